@@ -9,6 +9,7 @@
 //! [ACPI 6.3]: https://uefi.org/sites/default/files/resources/ACPI_6_3_May16.pdf
 
 use crate::error::{Error, Result};
+use crate::util::checksum;
 use byteorder::{ByteOrder, NativeEndian};
 use derive_try_from_primitive::TryFromPrimitive;
 use raw_cpuid::CpuId;
@@ -21,6 +22,8 @@ pub mod madt;
 pub mod rsdp;
 /// Support for the Root System Descriptor Table (RSDT).
 pub mod rsdt;
+/// Generation of the ACPI tables presented to a guest.
+pub mod tables;
 
 mod offsets {
     use core::ops::Range;
@@ -30,26 +33,52 @@ mod offsets {
     pub const GAS_BIT_OFFSET: usize = 2;
     pub const GAS_ACCESS_SIZE: usize = 3;
     pub const GAS_ADDRESS: Range<usize> = 4..12;
+    pub const SDT_CHECKSUM: usize = 9;
+    pub const SDT_HEADER_SIZE: usize = 36;
 }
 
 /// Verify a one byte checksum for a given slice and length.
-pub(self) fn verify_checksum(bytes: &[u8], cksum_idx: usize) -> Result<()> {
-    // Sum up the bytes in the buffer.
-    let result = bytes.iter().fold(0usize, |acc, val| acc + *val as usize);
-
+pub(self) fn verify_checksum_at(bytes: &[u8], cksum_idx: usize) -> Result<()> {
     // The result of the sum should be zero. See the ACPI § 5.2.5.3
     // in Table 5-27.
-    if (result & 0xff) == 0x00 {
+    if verify_checksum(bytes) {
         Ok(())
     } else {
         Err(Error::InvalidValue(format!(
             "Checksum mismatch checksum={:x} {:x} != 0x00",
             bytes[cksum_idx],
-            result & 0xff,
+            checksum::sum8(bytes),
         )))
     }
 }
 
+/// Returns true if the bytes of an ACPI table sum to zero.
+///
+/// This is valid for any table that carries a one byte checksum over its
+/// full length (every SDT, as well as the RSDP).
+pub fn verify_checksum(table: &[u8]) -> bool {
+    checksum::sum8(table) == 0x00
+}
+
+/// Rewrite the checksum byte of a System Descriptor Table so that the
+/// sum of all of its bytes is zero.
+///
+/// The checksum byte is located using the SDT header layout (see Table
+/// 5-28 in `ACPI § 5.2.6`), so this should be called after any edit to
+/// a table that is already laid out in memory. Fails if the table is too
+/// short to hold an SDT header.
+pub fn recompute_checksum(table: &mut [u8]) -> Result<()> {
+    if table.len() < offsets::SDT_HEADER_SIZE {
+        return Err(Error::InvalidValue(format!(
+            "ACPI table of {} bytes is shorter than an SDT header",
+            table.len()
+        )));
+    }
+    table[offsets::SDT_CHECKSUM] = 0;
+    table[offsets::SDT_CHECKSUM] = checksum::acpi_checksum(table);
+    Ok(())
+}
+
 /// The size of a Generic Address Structure in bytes.
 pub const GAS_SIZE: usize = 12;
 
@@ -182,3 +211,70 @@ impl GenericAddressStructure {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::acpi::madt::{IcsType, MADT};
+    use crate::acpi::rsdt::SDT;
+    use alloc::vec::Vec;
+
+    // Build a table with an SDT header for the given signature
+    fn build_table(signature: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut table = vec![0u8; 36];
+        table[0..4].copy_from_slice(signature);
+        NativeEndian::write_u32(&mut table[4..8], (36 + body.len()) as u32);
+        table[8] = 1;
+        table[10..16].copy_from_slice(b"MYTHRL");
+        table.extend_from_slice(body);
+        recompute_checksum(&mut table).unwrap();
+        table
+    }
+
+    fn build_fadt() -> Vec<u8> {
+        let mut body = vec![0u8; 244 - 36];
+        // PM1a control block at 0x604
+        NativeEndian::write_u32(&mut body[64 - 36..68 - 36], 0x604);
+        build_table(b"FACP", &body)
+    }
+
+    fn build_madt() -> Vec<u8> {
+        let mut body = vec![0u8; 8];
+        NativeEndian::write_u32(&mut body[0..4], 0xfee00000);
+        // A single enabled processor local APIC
+        body.extend_from_slice(&[0x00, 8, 0, 0, 1, 0, 0, 0]);
+        build_table(b"APIC", &body)
+    }
+
+    #[test]
+    fn test_corruption_invalidates_fadt() {
+        let mut fadt = build_fadt();
+        assert!(verify_checksum(&fadt));
+        fadt[64] = 0x05;
+        assert!(!verify_checksum(&fadt));
+        recompute_checksum(&mut fadt).unwrap();
+        assert!(verify_checksum(&fadt));
+        assert_eq!(NativeEndian::read_u32(&fadt[64..68]), 0x605);
+    }
+
+    #[test]
+    fn test_corruption_invalidates_madt() {
+        let mut madt = build_madt();
+        madt[36 + 8 + 3] = 1;
+        assert!(!verify_checksum(&madt));
+        assert!(unsafe { SDT::new(madt.as_ptr()) }.is_err());
+
+        recompute_checksum(&mut madt).unwrap();
+        let sdt = unsafe { SDT::new(madt.as_ptr()) }.unwrap();
+        let madt = MADT::new(&sdt);
+        let ics = madt.structures().next().unwrap().unwrap();
+        assert!(matches!(ics.ics_type(), IcsType::ProcessorLocalApic));
+    }
+
+    #[test]
+    fn test_recompute_checksum_short_table() {
+        let mut table = vec![0u8; 8];
+        assert!(recompute_checksum(&mut table).is_err());
+        assert_eq!(table, [0u8; 8]);
+    }
+}
@@ -1,5 +1,5 @@
 use super::rsdt::RSDT;
-use super::verify_checksum;
+use super::verify_checksum_at;
 use crate::error::{Error, Result};
 use byteorder::{ByteOrder, NativeEndian};
 use core::fmt;
@@ -170,13 +170,16 @@ impl RSDP {
     /// Checksum validation for the RSDP.
     fn verify_rsdp_checksum(bytes: &[u8]) -> Result<()> {
         // Verify the RSDT checksum regardless of the ACPI version.
-        verify_checksum(&bytes[..offsets::RSDT_ADDR.end], offsets::CHECKSUM)?;
+        verify_checksum_at(
+            &bytes[..offsets::RSDT_ADDR.end],
+            offsets::CHECKSUM,
+        )?;
 
         // We need to also validate the checksum of the extended data
         // for ACPI 2.0.
         match bytes[offsets::REVISION] {
             0 => Ok(()),
-            2 => verify_checksum(
+            2 => verify_checksum_at(
                 &bytes[..offsets::RESERVED.end],
                 offsets::EXT_CHECKSUM,
             ),
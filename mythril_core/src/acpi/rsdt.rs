@@ -1,4 +1,4 @@
-use super::verify_checksum;
+use super::verify_checksum_at;
 use crate::error::{Error, Result};
 use byteorder::{ByteOrder, NativeEndian};
 use core::fmt;
@@ -80,7 +80,7 @@ impl<'a> SDT<'a> {
 
         let bytes = slice::from_raw_parts(header.as_ptr(), length as usize);
 
-        verify_checksum(bytes, offsets::CHECKSUM)?;
+        verify_checksum_at(bytes, offsets::CHECKSUM)?;
 
         Ok(SDT {
             signature,
@@ -0,0 +1,533 @@
+use super::madt::{IcsType, LocalApicFlags, MultipleApicFlags};
+use super::recompute_checksum;
+use crate::error::{Error, Result};
+use crate::memory::{
+    GuestAccess, GuestAddressSpaceViewMut, GuestPhysAddr, GuestVirtAddr,
+    PrivilegeLevel,
+};
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, NativeEndian};
+use core::ops::Range;
+
+/// Offsets from `ACPI § 5.2.6`
+mod sdt_offsets {
+    use super::*;
+    /// Signature identifying the table.
+    pub const SIGNATURE: Range<usize> = 0..4;
+    /// Length of the table and header.
+    pub const LENGTH: Range<usize> = 4..8;
+    /// The revision of the structure corresponding to the signature.
+    pub const REVISION: usize = 8;
+    /// OEM supplied identifier.
+    pub const OEM_ID: Range<usize> = 10..16;
+    /// OEM supplied table identifier.
+    pub const OEM_TABLE_ID: Range<usize> = 16..24;
+    /// Vendor ID of the utility that created the table.
+    pub const CREATOR_ID: Range<usize> = 28..32;
+}
+
+/// Offsets from Table 5-33 in `ACPI § 5.2.9`
+mod fadt_offsets {
+    use super::*;
+    /// Physical address of the FACS.
+    pub const FIRMWARE_CTRL: Range<usize> = 36..40;
+    /// The SCI interrupt vector (a legacy IRQ).
+    pub const SCI_INT: Range<usize> = 46..48;
+    /// The port of the SMI command register.
+    pub const SMI_CMD: Range<usize> = 48..52;
+    /// The port of the PM1a event register block.
+    pub const PM1A_EVT_BLK: Range<usize> = 56..60;
+    /// The port of the PM1a control register block.
+    pub const PM1A_CNT_BLK: Range<usize> = 64..68;
+    /// The port of the PM timer.
+    pub const PM_TMR_BLK: Range<usize> = 76..80;
+    /// The length of the PM1 event register block.
+    pub const PM1_EVT_LEN: usize = 88;
+    /// The length of the PM1 control register block.
+    pub const PM1_CNT_LEN: usize = 89;
+    /// The length of the PM timer block.
+    pub const PM_TMR_LEN: usize = 91;
+    /// Fixed feature flags.
+    pub const FLAGS: Range<usize> = 112..116;
+    /// Minor version of the FADT.
+    pub const MINOR_VERSION: usize = 131;
+}
+
+/// Offsets from Table 5-43 in `ACPI § 5.2.12`
+mod madt_offsets {
+    use super::*;
+    /// Physical address of the local APICs.
+    pub const LOCAL_APIC_ADDR: Range<usize> = 36..40;
+    /// Multiple APIC flags.
+    pub const FLAGS: Range<usize> = 40..44;
+    /// The first Interrupt Controller Structure.
+    pub const INT_CTRL_STRUCTS: usize = 44;
+}
+
+/// Offsets from Table 5-37 in `ACPI § 5.2.10`
+mod facs_offsets {
+    use super::*;
+    /// Well known bytes, "FACS".
+    pub const SIGNATURE: Range<usize> = 0..4;
+    /// Length of the structure.
+    pub const LENGTH: Range<usize> = 4..8;
+    /// The hardware configuration signature.
+    pub const HARDWARE_SIGNATURE: Range<usize> = 8..12;
+    /// The 32-bit real mode waking vector.
+    pub const FIRMWARE_WAKING_VECTOR: Range<usize> = 12..16;
+    /// The 64-bit waking vector.
+    pub const X_FIRMWARE_WAKING_VECTOR: Range<usize> = 24..32;
+    /// Version of the structure.
+    pub const VERSION: usize = 32;
+}
+
+const FADT_SIZE: usize = 276;
+const FADT_REVISION: u8 = 6;
+const FADT_MINOR_REVISION: u8 = 3;
+// The PM timer is 32 bits (TMR_VAL_EXT).
+const FADT_FLAG_TMR_VAL_EXT: u32 = 1 << 8;
+
+const MADT_REVISION: u8 = 4;
+const LOCAL_APIC_ADDR: u32 = 0xfee00000;
+const IOAPIC_ADDR: u32 = 0xfec00000;
+
+const FACS_SIZE: usize = 64;
+const FACS_ALIGNMENT: u64 = 64;
+const FACS_VERSION: u8 = 2;
+
+const OEM_ID: &[u8; 6] = b"MYTHRL";
+const OEM_TABLE_ID: &[u8; 8] = b"MYTHRIL ";
+const CREATOR_ID: &[u8; 4] = b"MYTH";
+
+/// Builder for the ACPI tables presented to a guest.
+///
+/// The tables are laid out contiguously starting at a guest physical base
+/// address (which must be suitably aligned for the FACS).
+pub struct AcpiTablesBuilder {
+    base: GuestPhysAddr,
+    pm_base: u16,
+    smi_command: u16,
+    sci_irq: u16,
+    hardware_signature: u32,
+    processors: u8,
+    overrides: Vec<(u8, u32)>,
+}
+
+impl AcpiTablesBuilder {
+    /// Create a new builder for tables placed at `base`, describing a
+    /// PM register block at the I/O port `pm_base`.
+    pub fn new(base: GuestPhysAddr, pm_base: u16) -> Self {
+        Self {
+            base,
+            pm_base,
+            smi_command: 0xb2,
+            sci_irq: 9,
+            hardware_signature: 0,
+            processors: 1,
+            overrides: vec![],
+        }
+    }
+
+    /// Set the number of processors described in the MADT.
+    pub fn set_processor_count(&mut self, count: u8) {
+        self.processors = count;
+    }
+
+    /// Describe a legacy IRQ that is connected to I/O APIC input `gsi`
+    /// (an interrupt source override in the MADT).
+    pub fn add_interrupt_override(&mut self, irq: u8, gsi: u32) {
+        self.overrides.push((irq, gsi));
+    }
+
+    /// Set the I/O port of the SMI command register.
+    pub fn set_smi_command(&mut self, port: u16) {
+        self.smi_command = port;
+    }
+
+    /// Set the legacy IRQ used for the System Control Interrupt.
+    pub fn set_sci_irq(&mut self, irq: u16) {
+        self.sci_irq = irq;
+    }
+
+    /// Set the hardware signature reported in the FACS.
+    ///
+    /// The guest compares this across S4 sleep to detect hardware changes.
+    pub fn set_hardware_signature(&mut self, signature: u32) {
+        self.hardware_signature = signature;
+    }
+
+    /// Generate the tables.
+    pub fn build(&self) -> Result<AcpiTables> {
+        if self.base.as_u64() % FACS_ALIGNMENT != 0 {
+            return Err(Error::InvalidValue(format!(
+                "ACPI table base {:?} is not {} byte aligned",
+                self.base, FACS_ALIGNMENT
+            )));
+        }
+
+        let mut tables = AcpiTables {
+            base: self.base,
+            bytes: vec![],
+            entries: vec![],
+        };
+
+        // The FACS is first, as it has the strictest alignment
+        let facs = tables.push(b"FACS", self.facs());
+        let fadt = self.fadt(tables.base + facs.start)?;
+        tables.push(b"FACP", fadt);
+        tables.push(b"APIC", self.madt()?);
+        Ok(tables)
+    }
+
+    fn facs(&self) -> Vec<u8> {
+        let mut facs = vec![0u8; FACS_SIZE];
+        facs[facs_offsets::SIGNATURE].copy_from_slice(b"FACS");
+        NativeEndian::write_u32(
+            &mut facs[facs_offsets::LENGTH],
+            FACS_SIZE as u32,
+        );
+        NativeEndian::write_u32(
+            &mut facs[facs_offsets::HARDWARE_SIGNATURE],
+            self.hardware_signature,
+        );
+        facs[facs_offsets::VERSION] = FACS_VERSION;
+        facs
+    }
+
+    fn fadt(&self, facs_addr: GuestPhysAddr) -> Result<Vec<u8>> {
+        let mut fadt = sdt(b"FACP", FADT_REVISION, FADT_SIZE);
+
+        // FIRMWARE_CTRL is only 32 bits, so the FACS must be below 4GB
+        // (X_FIRMWARE_CTRL is left zero, so it is not used)
+        if facs_addr.as_u64() > u32::MAX as u64 {
+            return Err(Error::InvalidValue(format!(
+                "FACS address {:?} is above 4GB",
+                facs_addr
+            )));
+        }
+        NativeEndian::write_u32(
+            &mut fadt[fadt_offsets::FIRMWARE_CTRL],
+            facs_addr.as_u64() as u32,
+        );
+
+        NativeEndian::write_u16(&mut fadt[fadt_offsets::SCI_INT], self.sci_irq);
+        NativeEndian::write_u32(
+            &mut fadt[fadt_offsets::SMI_CMD],
+            self.smi_command as u32,
+        );
+        NativeEndian::write_u32(
+            &mut fadt[fadt_offsets::PM1A_EVT_BLK],
+            self.pm_base as u32,
+        );
+        NativeEndian::write_u32(
+            &mut fadt[fadt_offsets::PM1A_CNT_BLK],
+            self.pm_base as u32 + 0x04,
+        );
+        NativeEndian::write_u32(
+            &mut fadt[fadt_offsets::PM_TMR_BLK],
+            self.pm_base as u32 + 0x08,
+        );
+        fadt[fadt_offsets::PM1_EVT_LEN] = 4;
+        fadt[fadt_offsets::PM1_CNT_LEN] = 2;
+        fadt[fadt_offsets::PM_TMR_LEN] = 4;
+        NativeEndian::write_u32(
+            &mut fadt[fadt_offsets::FLAGS],
+            FADT_FLAG_TMR_VAL_EXT,
+        );
+        fadt[fadt_offsets::MINOR_VERSION] = FADT_MINOR_REVISION;
+
+        //TODO: the FADT should also point to a DSDT
+        recompute_checksum(&mut fadt)?;
+        Ok(fadt)
+    }
+
+    fn madt(&self) -> Result<Vec<u8>> {
+        let mut madt =
+            sdt(b"APIC", MADT_REVISION, madt_offsets::INT_CTRL_STRUCTS);
+        NativeEndian::write_u32(
+            &mut madt[madt_offsets::LOCAL_APIC_ADDR],
+            LOCAL_APIC_ADDR,
+        );
+        NativeEndian::write_u32(
+            &mut madt[madt_offsets::FLAGS],
+            MultipleApicFlags::PCAT_COMPAT.bits(),
+        );
+
+        for id in 0..self.processors {
+            let mut local_apic = ics(IcsType::ProcessorLocalApic);
+            local_apic[2] = id;
+            local_apic[3] = id;
+            NativeEndian::write_u32(
+                &mut local_apic[4..8],
+                LocalApicFlags::ENABLED.bits(),
+            );
+            madt.extend_from_slice(&local_apic);
+        }
+
+        let mut ioapic = ics(IcsType::IoApic);
+        NativeEndian::write_u32(&mut ioapic[4..8], IOAPIC_ADDR);
+        madt.extend_from_slice(&ioapic);
+
+        // ISA interrupts conform to the bus (edge triggered, active high),
+        // so the flags are left zero
+        for (irq, gsi) in self.overrides.iter() {
+            let mut source_override = ics(IcsType::InterruptSourceOverride);
+            source_override[3] = *irq;
+            NativeEndian::write_u32(&mut source_override[4..8], *gsi);
+            madt.extend_from_slice(&source_override);
+        }
+
+        let len = madt.len() as u32;
+        NativeEndian::write_u32(&mut madt[sdt_offsets::LENGTH], len);
+        recompute_checksum(&mut madt)?;
+        Ok(madt)
+    }
+}
+
+// Create a zeroed Interrupt Controller Structure of the given type
+fn ics(ty: IcsType) -> Vec<u8> {
+    let mut ics = vec![0u8; ty.expected_len()];
+    ics[0] = ty as u8;
+    ics[1] = ty.expected_len() as u8;
+    ics
+}
+
+// Create a zeroed table of `len` bytes with a System Descriptor Table header
+fn sdt(signature: &[u8; 4], revision: u8, len: usize) -> Vec<u8> {
+    let mut table = vec![0u8; len];
+    table[sdt_offsets::SIGNATURE].copy_from_slice(signature);
+    NativeEndian::write_u32(&mut table[sdt_offsets::LENGTH], len as u32);
+    table[sdt_offsets::REVISION] = revision;
+    table[sdt_offsets::OEM_ID].copy_from_slice(OEM_ID);
+    table[sdt_offsets::OEM_TABLE_ID].copy_from_slice(OEM_TABLE_ID);
+    table[sdt_offsets::CREATOR_ID].copy_from_slice(CREATOR_ID);
+    table
+}
+
+/// A table within the generated ACPI tables.
+struct TableEntry {
+    signature: [u8; 4],
+    range: Range<usize>,
+}
+
+/// The ACPI tables presented to a guest.
+pub struct AcpiTables {
+    base: GuestPhysAddr,
+    bytes: Vec<u8>,
+    entries: Vec<TableEntry>,
+}
+
+impl AcpiTables {
+    // Append a table (aligned to 16 bytes) and return its range
+    fn push(&mut self, signature: &[u8; 4], table: Vec<u8>) -> Range<usize> {
+        let start = (self.bytes.len() + 15) & !15;
+        self.bytes.resize(start, 0);
+        self.bytes.extend_from_slice(&table);
+        let range = start..self.bytes.len();
+        self.entries.push(TableEntry {
+            signature: *signature,
+            range: range.clone(),
+        });
+        range
+    }
+
+    /// The guest physical address of the first table.
+    pub fn base(&self) -> GuestPhysAddr {
+        self.base
+    }
+
+    /// The guest physical address of the table with the given signature.
+    pub fn address_of(&self, signature: &[u8; 4]) -> Option<GuestPhysAddr> {
+        self.entries
+            .iter()
+            .find(|entry| &entry.signature == signature)
+            .map(|entry| self.base + entry.range.start)
+    }
+
+    /// The bytes of the table with the given signature.
+    pub fn table(&self, signature: &[u8; 4]) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|entry| &entry.signature == signature)
+            .map(|entry| &self.bytes[entry.range.clone()])
+    }
+
+    /// The tables as they are laid out in guest memory, starting at
+    /// `base` (including the padding between them).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+
+    /// Copy the tables in to guest memory.
+    pub fn write_to_guest(
+        &self,
+        space: &mut GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        space.write_bytes(
+            GuestVirtAddr::NoPaging(self.base),
+            &self.bytes,
+            GuestAccess::Write(PrivilegeLevel(0)),
+        )
+    }
+
+    /// The waking vector the guest has stored in the FACS, if any.
+    ///
+    /// The 64-bit X_FIRMWARE_WAKING_VECTOR takes precedence over the
+    /// 32-bit (real mode) FIRMWARE_WAKING_VECTOR. See `ACPI § 5.2.10`.
+    pub fn waking_vector(
+        &self,
+        space: &GuestAddressSpaceViewMut,
+    ) -> Result<Option<u64>> {
+        let facs_addr = self.address_of(b"FACS").ok_or_else(|| {
+            Error::MissingDevice("No FACS in the ACPI tables".into())
+        })?;
+        let facs = space.read_bytes(
+            GuestVirtAddr::NoPaging(facs_addr),
+            FACS_SIZE,
+            GuestAccess::Read(PrivilegeLevel(0)),
+        )?;
+        let x_vector = NativeEndian::read_u64(
+            &facs[facs_offsets::X_FIRMWARE_WAKING_VECTOR],
+        );
+        let vector =
+            NativeEndian::read_u32(&facs[facs_offsets::FIRMWARE_WAKING_VECTOR]);
+        Ok(match (x_vector, vector) {
+            (0, 0) => None,
+            (0, vector) => Some(vector as u64),
+            (x_vector, _) => Some(x_vector),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::acpi::madt::{Ics, MADT};
+    use crate::acpi::rsdt::SDT;
+    use crate::acpi::verify_checksum;
+    use crate::memory::GuestAddressSpace;
+    use alloc::boxed::Box;
+
+    const TABLE_BASE: u64 = 0xe0000;
+
+    fn define_test_view() -> GuestAddressSpaceViewMut<'static> {
+        let space: &'static mut GuestAddressSpace =
+            Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
+        space
+            .map_new_frame(GuestPhysAddr::new(TABLE_BASE), false)
+            .unwrap();
+        GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space)
+    }
+
+    fn test_tables() -> AcpiTables {
+        let mut builder =
+            AcpiTablesBuilder::new(GuestPhysAddr::new(TABLE_BASE), 0xb000);
+        builder.set_hardware_signature(0x12345678);
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn test_fadt_points_to_facs() {
+        let tables = test_tables();
+        let fadt = tables.table(b"FACP").unwrap();
+        assert!(verify_checksum(fadt));
+
+        let facs_addr = tables.address_of(b"FACS").unwrap();
+        assert_eq!(facs_addr.as_u64() % FACS_ALIGNMENT, 0);
+        assert_eq!(
+            NativeEndian::read_u32(&fadt[fadt_offsets::FIRMWARE_CTRL]) as u64,
+            facs_addr.as_u64()
+        );
+        assert_eq!(
+            NativeEndian::read_u32(&fadt[fadt_offsets::PM1A_CNT_BLK]),
+            0xb004
+        );
+
+        let facs = tables.table(b"FACS").unwrap();
+        assert_eq!(&facs[facs_offsets::SIGNATURE], b"FACS");
+        assert_eq!(facs.len(), FACS_SIZE);
+        assert_eq!(
+            NativeEndian::read_u32(&facs[facs_offsets::HARDWARE_SIGNATURE]),
+            0x12345678
+        );
+    }
+
+    #[test]
+    fn test_madt_interrupt_override() {
+        let mut builder =
+            AcpiTablesBuilder::new(GuestPhysAddr::new(TABLE_BASE), 0xb000);
+        builder.set_processor_count(2);
+        builder.add_interrupt_override(0, 2);
+        let tables = builder.build().unwrap();
+
+        let madt = tables.table(b"APIC").unwrap();
+        let sdt = unsafe { SDT::new(madt.as_ptr()) }.unwrap();
+        let madt = MADT::new(&sdt);
+        assert!(madt.flags.contains(MultipleApicFlags::PCAT_COMPAT));
+        let structures =
+            madt.structures().collect::<Result<Vec<Ics>>>().unwrap();
+        assert_eq!(structures.len(), 4);
+        assert!(matches!(
+            structures[1],
+            Ics::LocalApic { apic_id: 1, .. }
+        ));
+        assert!(matches!(
+            structures[2],
+            Ics::IoApic { gsi_base: 0, .. }
+        ));
+        assert!(matches!(
+            structures[3],
+            Ics::InterruptSourceOverride {
+                source: 0,
+                gsi: 2,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_export_tables() {
+        let tables = test_tables();
+        let madt = tables.table(b"APIC").unwrap();
+        assert_eq!(&madt[sdt_offsets::SIGNATURE], b"APIC");
+        assert_eq!(
+            NativeEndian::read_u32(&madt[sdt_offsets::LENGTH]) as usize,
+            madt.len()
+        );
+        assert!(verify_checksum(madt));
+        assert!(tables.table(b"SSDT").is_none());
+
+        // Each table is placed in the blob at its guest physical offset
+        let bytes = tables.to_bytes();
+        let offset = (tables.address_of(b"APIC").unwrap().as_u64()
+            - tables.base().as_u64()) as usize;
+        assert_eq!(&bytes[offset..offset + madt.len()], madt);
+    }
+
+    #[test]
+    fn test_unaligned_base() {
+        let builder =
+            AcpiTablesBuilder::new(GuestPhysAddr::new(TABLE_BASE + 16), 0xb000);
+        assert!(builder.build().is_err());
+    }
+
+    #[test]
+    fn test_guest_waking_vector() {
+        let tables = test_tables();
+        let mut view = define_test_view();
+        tables.write_to_guest(&mut view).unwrap();
+        assert_eq!(tables.waking_vector(&view).unwrap(), None);
+
+        // The guest stores its real mode waking vector before sleeping
+        let facs_addr = tables.address_of(b"FACS").unwrap();
+        view.write_bytes(
+            GuestVirtAddr::NoPaging(
+                facs_addr + facs_offsets::FIRMWARE_WAKING_VECTOR.start,
+            ),
+            &0x9a000u32.to_ne_bytes(),
+            GuestAccess::Write(PrivilegeLevel(0)),
+        )
+        .unwrap();
+        assert_eq!(tables.waking_vector(&view).unwrap(), Some(0x9a000));
+    }
+}
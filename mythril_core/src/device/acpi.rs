@@ -1,16 +1,44 @@
+use crate::device::interrupt::{IrqLineState, IrqSink};
+use crate::device::pci::PciHotplug;
 use crate::device::{
-    DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
+    port_block, DeviceRegion, EmulatedDevice, Port, PortReadRequest,
+    PortWriteRequest, TriggerMode,
 };
 use crate::error::Result;
 use crate::memory::GuestAddressSpaceViewMut;
-use crate::time;
+use crate::time::{ClockSource, TimeDiagnostics};
 use alloc::boxed::Box;
+use alloc::rc::Rc;
 use alloc::vec::Vec;
+use core::cell::RefCell;
+use derive_try_from_primitive::TryFromPrimitive;
 
 const PMTIMER_HZ: u64 = 3579545;
 
+/// A sleep state requested by the guest through the PM1 control register
+///
+/// The value is the SLP_TYP written by the guest, which (by the ACPI
+/// tables we provide) is the number of the sleeping state.
+#[derive(Clone, Copy, Debug, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum SleepState {
+    S1 = 1,
+    S2 = 2,
+    S3 = 3,
+    S4 = 4,
+    S5 = 5,
+}
+
 pub struct AcpiRuntime {
     pm_base: Port,
+    pm1_status: u16,
+    pm1_enable: u16,
+    pm1_control: u16,
+    sleep_request: Option<SleepState>,
+    gpe_enable: u16,
+    pci_hotplug: Option<Rc<RefCell<PciHotplug>>>,
+    irq: Option<Rc<dyn IrqSink>>,
+    sci_raised: bool,
 }
 
 impl AcpiRuntime {
@@ -30,16 +58,169 @@ impl AcpiRuntime {
     const PCI_REMOVABILITY_STATUS_START: Port = 0xae0c;
     const PCI_REMOVABILITY_STATUS_END: Port = 0xae0f;
 
+    // The PM1 event (status and enable) and control registers
+    const PM1_STATUS: u16 = 0x00;
+    const PM1_ENABLE: u16 = 0x02;
+    const PM1_CONTROL: u16 = 0x04;
+    const PM1_BLOCK_LEN: u16 = 6;
+
+    const PM1_STATUS_WAK: u16 = 1 << 15;
+    const PM1_CONTROL_SLP_TYP_SHIFT: u16 = 10;
+    const PM1_CONTROL_SLP_TYP_MASK: u16 = 0b111;
+    const PM1_CONTROL_SLP_EN: u16 = 1 << 13;
+
+    // The GPE0 status bit used for PCI hot-plug notifications
+    const GPE_PCI_HOTPLUG: u16 = 1 << 1;
+
+    /// The (level triggered) IRQ used for the SCI, which matches the
+    /// default `SCI_INT` of the generated FADT
+    pub const SCI_IRQ: u8 = 9;
+
     pub fn new(pm_base: Port) -> Result<Box<Self>> {
-        Ok(Box::new(AcpiRuntime { pm_base }))
+        port_block(pm_base, Self::PM1_BLOCK_LEN)?;
+        Ok(Box::new(AcpiRuntime {
+            pm_base,
+            pm1_status: 0,
+            pm1_enable: 0,
+            pm1_control: 0,
+            sleep_request: None,
+            gpe_enable: 0,
+            pci_hotplug: None,
+            irq: None,
+            sci_raised: false,
+        }))
+    }
+
+    /// Report the PCI hot-plug events from `hotplug` to the guest
+    pub fn set_pci_hotplug(&mut self, hotplug: Rc<RefCell<PciHotplug>>) {
+        self.pci_hotplug = Some(hotplug);
+    }
+
+    /// Set the sink used to raise the SCI
+    pub fn set_irq_sink(&mut self, irq: Rc<dyn IrqSink>) {
+        self.irq = Some(irq);
+    }
+
+    /// Returns true if a GPE is pending and enabled (so the SCI should be
+    /// asserted)
+    pub fn gpe_pending(&self) -> bool {
+        self.gpe_status() & self.gpe_enable != 0
+    }
+
+    // Raise or lower the SCI to match the pending GPEs
+    fn update_sci(&mut self) {
+        let active = self.gpe_pending();
+        if active == self.sci_raised {
+            return;
+        }
+        if let Some(irq) = &self.irq {
+            if active {
+                irq.raise_irq(Self::SCI_IRQ);
+            } else {
+                irq.lower_irq(Self::SCI_IRQ);
+            }
+            self.sci_raised = active;
+        }
+    }
+
+    fn gpe_status(&self) -> u16 {
+        match &self.pci_hotplug {
+            Some(hotplug) if hotplug.borrow().is_pending() => {
+                Self::GPE_PCI_HOTPLUG
+            }
+            _ => 0,
+        }
     }
 
-    fn pm1a_cnt(&self) -> Port {
-        self.pm_base + 0x04
+    // The GPE0 block is a 16-bit status register followed by a 16-bit
+    // enable register
+    fn read_gpe_byte(&self, offset: u16) -> u8 {
+        let reg = if offset < 2 {
+            self.gpe_status()
+        } else {
+            self.gpe_enable
+        };
+        (reg >> ((offset & 1) * 8)) as u8
     }
 
-    fn pmtimer(&self) -> Port {
-        self.pm_base + 0x08
+    fn write_gpe_byte(&mut self, offset: u16, val: u8) {
+        // The status bits are derived from the pending events, so they
+        // are cleared once the guest has acknowledged them
+        if offset >= 2 {
+            let shift = (offset & 1) * 8;
+            self.gpe_enable =
+                (self.gpe_enable & !(0xff << shift)) | ((val as u16) << shift);
+        }
+    }
+
+    // Read a PCI hot-plug slot bitmap (which acknowledges the events)
+    fn read_hotplug_slots(&self, removed: bool) -> u32 {
+        match &self.pci_hotplug {
+            Some(hotplug) if removed => {
+                hotplug.borrow_mut().take_removed_slots()
+            }
+            Some(hotplug) => hotplug.borrow_mut().take_added_slots(),
+            None => 0,
+        }
+    }
+
+    /// Returns the sleep state most recently requested by the guest, if
+    /// it has not already been taken
+    ///
+    /// The supervisor should act on the request (e.g., pause the guest for
+    /// S3 or power it off for S5).
+    pub fn take_sleep_request(&mut self) -> Option<SleepState> {
+        self.sleep_request.take()
+    }
+
+    /// Indicate to the guest that it has woken from a sleep state
+    pub fn resume(&mut self) {
+        self.pm1_status |= Self::PM1_STATUS_WAK;
+    }
+
+    fn pm1_block_end(&self) -> Port {
+        self.pm_base + Self::PM1_BLOCK_LEN - 1
+    }
+
+    fn read_pm1_register(&self, offset: u16) -> u16 {
+        match offset {
+            Self::PM1_STATUS => self.pm1_status,
+            Self::PM1_ENABLE => self.pm1_enable,
+            // SLP_EN is write-only
+            Self::PM1_CONTROL => self.pm1_control & !Self::PM1_CONTROL_SLP_EN,
+            _ => unreachable!(),
+        }
+    }
+
+    // Write the bits of `val` selected by `mask` to a PM1 register
+    fn write_pm1_register(&mut self, offset: u16, val: u16, mask: u16) {
+        match offset {
+            // The status bits are cleared by writing a one
+            Self::PM1_STATUS => self.pm1_status &= !(val & mask),
+            Self::PM1_ENABLE => {
+                self.pm1_enable = (self.pm1_enable & !mask) | (val & mask)
+            }
+            Self::PM1_CONTROL => {
+                self.pm1_control = (self.pm1_control & !mask) | (val & mask);
+                if self.pm1_control & Self::PM1_CONTROL_SLP_EN != 0 {
+                    self.pm1_control &= !Self::PM1_CONTROL_SLP_EN;
+                    self.request_sleep();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn request_sleep(&mut self) {
+        let slp_typ = (self.pm1_control >> Self::PM1_CONTROL_SLP_TYP_SHIFT)
+            & Self::PM1_CONTROL_SLP_TYP_MASK;
+        match SleepState::try_from(slp_typ as u8) {
+            Some(state) => {
+                info!("Guest requested sleep state {:?}", state);
+                self.sleep_request = Some(state);
+            }
+            None => info!("Ignoring request for sleep type {}", slp_typ),
+        }
     }
 }
 
@@ -49,8 +230,7 @@ impl EmulatedDevice for AcpiRuntime {
             DeviceRegion::PortIo(
                 Self::FADT_SMI_COMMAND..=Self::FADT_SMI_COMMAND,
             ),
-            DeviceRegion::PortIo(self.pm1a_cnt()..=self.pm1a_cnt()),
-            DeviceRegion::PortIo(self.pmtimer()..=self.pmtimer()),
+            DeviceRegion::PortIo(self.pm_base..=self.pm1_block_end()),
             DeviceRegion::PortIo(Self::GPE_BLOCK_START..=Self::GPE_BLOCK_END),
             DeviceRegion::PortIo(
                 Self::PCI_SLOT_INJECTION_START..=Self::PCI_SLOT_INJECTION_END,
@@ -69,17 +249,64 @@ impl EmulatedDevice for AcpiRuntime {
         ]
     }
 
+    fn reset(&mut self) {
+        self.pm1_status = 0;
+        self.pm1_enable = 0;
+        self.pm1_control = 0;
+        self.sleep_request = None;
+        self.gpe_enable = 0;
+        self.update_sci();
+    }
+
+    fn irq_lines(&self) -> Vec<u8> {
+        vec![Self::SCI_IRQ]
+    }
+
+    fn irq_trigger_mode(&self, _irq: u8) -> TriggerMode {
+        TriggerMode::Level
+    }
+
+    // Hot-plug events are queued by the supervisor, outside of any guest
+    // access to this device
+    fn poll(&mut self, _now: u64) {
+        self.update_sci();
+    }
+
     fn on_port_read(
         &mut self,
         port: Port,
         mut val: PortReadRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
-        if port == self.pmtimer() {
-            let on_duration = time::now() - time::system_start_time();
-            let pm_time =
-                (on_duration.as_nanos() * PMTIMER_HZ as u128) / 1_000_000_000;
-            val.copy_from_u32(pm_time as u32);
+        if port >= self.pm_base && port <= self.pm1_block_end() {
+            // Accesses may cover part of a register, or more than one
+            let offset = port - self.pm_base;
+            let mut res = 0u32;
+            for (i, byte_offset) in (offset
+                ..offset + val.as_slice().len() as u16)
+                .enumerate()
+                .filter(|(_, off)| *off < Self::PM1_BLOCK_LEN)
+            {
+                let reg = self.read_pm1_register(byte_offset & !1);
+                let byte = (reg >> ((byte_offset & 1) * 8)) as u8;
+                res |= (byte as u32) << (i * 8);
+            }
+            val.copy_from_u32(res);
+        } else if port >= Self::GPE_BLOCK_START && port <= Self::GPE_BLOCK_END {
+            let offset = port - Self::GPE_BLOCK_START;
+            let mut res = 0u32;
+            for i in 0..val.as_slice().len() as u16 {
+                if offset + i <= Self::GPE_BLOCK_END - Self::GPE_BLOCK_START {
+                    res |= (self.read_gpe_byte(offset + i) as u32) << (i * 8);
+                }
+            }
+            val.copy_from_u32(res);
+        } else if port == Self::PCI_SLOT_INJECTION_START {
+            val.copy_from_u32(self.read_hotplug_slots(false));
+            self.update_sci();
+        } else if port == Self::PCI_SLOT_REMOVAL_NOTIFY_START {
+            val.copy_from_u32(self.read_hotplug_slots(true));
+            self.update_sci();
         }
         Ok(())
     }
@@ -90,6 +317,35 @@ impl EmulatedDevice for AcpiRuntime {
         val: PortWriteRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
+        if port >= self.pm_base && port <= self.pm1_block_end() {
+            let offset = port - self.pm_base;
+            let value = val.as_u32();
+            for (i, byte_offset) in (offset
+                ..offset + val.as_slice().len() as u16)
+                .enumerate()
+                .filter(|(_, off)| *off < Self::PM1_BLOCK_LEN)
+            {
+                let shift = (byte_offset & 1) * 8;
+                let byte = (value >> (i * 8)) as u8 as u16;
+                self.write_pm1_register(
+                    byte_offset & !1,
+                    byte << shift,
+                    0xff << shift,
+                );
+            }
+            return Ok(());
+        } else if port >= Self::GPE_BLOCK_START && port <= Self::GPE_BLOCK_END {
+            let offset = port - Self::GPE_BLOCK_START;
+            let value = val.as_u32();
+            for i in 0..val.as_slice().len() as u16 {
+                if offset + i <= Self::GPE_BLOCK_END - Self::GPE_BLOCK_START {
+                    self.write_gpe_byte(offset + i, (value >> (i * 8)) as u8);
+                }
+            }
+            self.update_sci();
+            return Ok(());
+        }
+
         info!(
             "Attempt to write to AcpiRuntime port=0x{:x}, val={}. Ignoring",
             port, val
@@ -97,3 +353,328 @@ impl EmulatedDevice for AcpiRuntime {
         Ok(())
     }
 }
+
+impl IrqLineState for AcpiRuntime {
+    fn line_asserted(&self, irq: u8) -> bool {
+        irq == Self::SCI_IRQ && self.sci_raised
+    }
+}
+
+/// The ACPI power management timer
+///
+/// This is a free running counter at 3.579545 MHz, derived from the
+/// platform clock. It is 24 bits wide, unless the FADT reports a 32-bit
+/// timer (the TMR_VAL_EXT flag).
+pub struct AcpiPmTimer {
+    port: Port,
+    clock: Rc<dyn ClockSource>,
+    extended: bool,
+}
+
+impl AcpiPmTimer {
+    /// Create a timer at the PM_TMR_BLK `port`, driven by `clock`
+    ///
+    /// `extended` selects a 32-bit (rather than 24-bit) counter.
+    pub fn new(
+        port: Port,
+        clock: Rc<dyn ClockSource>,
+        extended: bool,
+    ) -> Result<Box<Self>> {
+        port_block(port, 4)?;
+        Ok(Box::new(Self {
+            port,
+            clock,
+            extended,
+        }))
+    }
+
+    /// The current value of the counter
+    pub fn counter(&self) -> u32 {
+        self.counter_at(self.clock.now_ns())
+    }
+
+    /// The current value of the counter, and the clock time it was
+    /// derived from
+    pub fn time_diagnostics(&self) -> TimeDiagnostics {
+        let now = self.clock.now_ns();
+        TimeDiagnostics {
+            clock_ns: now,
+            device_time: self.counter_at(now) as u64,
+        }
+    }
+
+    fn counter_at(&self, now: u64) -> u32 {
+        let ticks = (now as u128 * PMTIMER_HZ as u128 / 1_000_000_000) as u32;
+        if self.extended {
+            ticks
+        } else {
+            ticks & 0x00ff_ffff
+        }
+    }
+}
+
+impl EmulatedDevice for AcpiPmTimer {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::PortIo(self.port..=self.port + 3)]
+    }
+
+    fn on_port_read(
+        &mut self,
+        port: Port,
+        mut val: PortReadRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let offset = port - self.port;
+        val.copy_from_u32(self.counter() >> (offset * 8));
+        Ok(())
+    }
+
+    fn on_port_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        // The timer is read-only
+        info!(
+            "Attempt to write to AcpiPmTimer port=0x{:x}, val={}. Ignoring",
+            port, val
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::{define_test_view, MockIrqs};
+    use crate::time::FixedClock;
+
+    const PM_BASE: Port = 0xb000;
+
+    fn write_pm1(acpi: &mut AcpiRuntime, offset: Port, val: u16) {
+        let arr = val.to_be_bytes();
+        acpi.on_port_write(
+            PM_BASE + offset,
+            PortWriteRequest::TwoBytes(&arr),
+            define_test_view(),
+        )
+        .unwrap();
+    }
+
+    fn read_pm1(acpi: &mut AcpiRuntime, offset: Port) -> u16 {
+        let mut arr = [0u8; 2];
+        acpi.on_port_read(
+            PM_BASE + offset,
+            PortReadRequest::TwoBytes(&mut arr),
+            define_test_view(),
+        )
+        .unwrap();
+        u16::from_be_bytes(arr)
+    }
+
+    fn sleep_command(slp_typ: u16) -> u16 {
+        (slp_typ << AcpiRuntime::PM1_CONTROL_SLP_TYP_SHIFT)
+            | AcpiRuntime::PM1_CONTROL_SLP_EN
+    }
+
+    #[test]
+    fn test_s5_request() {
+        let mut acpi = AcpiRuntime::new(PM_BASE).unwrap();
+        assert_eq!(acpi.take_sleep_request(), None);
+        write_pm1(&mut acpi, AcpiRuntime::PM1_CONTROL, sleep_command(5));
+        assert_eq!(acpi.take_sleep_request(), Some(SleepState::S5));
+        assert_eq!(acpi.take_sleep_request(), None);
+
+        // SLP_EN always reads as zero
+        assert_eq!(read_pm1(&mut acpi, AcpiRuntime::PM1_CONTROL), 5 << 10);
+    }
+
+    #[test]
+    fn test_s3_request_and_resume() {
+        let mut acpi = AcpiRuntime::new(PM_BASE).unwrap();
+        write_pm1(&mut acpi, AcpiRuntime::PM1_CONTROL, sleep_command(3));
+        assert_eq!(acpi.take_sleep_request(), Some(SleepState::S3));
+
+        acpi.resume();
+        let status = read_pm1(&mut acpi, AcpiRuntime::PM1_STATUS);
+        assert_eq!(status, AcpiRuntime::PM1_STATUS_WAK);
+
+        // The wake status is cleared by writing a one to it
+        write_pm1(&mut acpi, AcpiRuntime::PM1_STATUS, status);
+        assert_eq!(read_pm1(&mut acpi, AcpiRuntime::PM1_STATUS), 0);
+    }
+
+    #[test]
+    fn test_sleep_type_without_enable() {
+        let mut acpi = AcpiRuntime::new(PM_BASE).unwrap();
+        write_pm1(&mut acpi, AcpiRuntime::PM1_CONTROL, 5 << 10);
+        assert_eq!(acpi.take_sleep_request(), None);
+        write_pm1(&mut acpi, AcpiRuntime::PM1_ENABLE, 0x0121);
+        assert_eq!(read_pm1(&mut acpi, AcpiRuntime::PM1_ENABLE), 0x0121);
+    }
+
+    #[test]
+    fn test_pci_hotplug_gpe() {
+        use crate::device::pci::{PciBdf, PciHotplugEvent};
+
+        let hotplug = Rc::new(RefCell::new(PciHotplug::default()));
+        let mut acpi = AcpiRuntime::new(PM_BASE).unwrap();
+        acpi.set_pci_hotplug(hotplug.clone());
+        let gpe_status = |acpi: &mut AcpiRuntime| {
+            let mut arr = [0u8];
+            acpi.on_port_read(
+                AcpiRuntime::GPE_BLOCK_START,
+                PortReadRequest::OneByte(&mut arr),
+                define_test_view(),
+            )
+            .unwrap();
+            arr[0]
+        };
+        acpi.on_port_write(
+            AcpiRuntime::GPE_BLOCK_START + 2,
+            PortWriteRequest::OneByte(&[0x02]),
+            define_test_view(),
+        )
+        .unwrap();
+        assert_eq!(gpe_status(&mut acpi), 0);
+        assert!(!acpi.gpe_pending());
+
+        hotplug
+            .borrow_mut()
+            .push(PciHotplugEvent::Added(PciBdf::new(0, 4, 0)));
+        assert_eq!(gpe_status(&mut acpi), 0x02);
+        assert!(acpi.gpe_pending());
+
+        let mut arr = [0u8; 4];
+        acpi.on_port_read(
+            AcpiRuntime::PCI_SLOT_INJECTION_START,
+            PortReadRequest::FourBytes(&mut arr),
+            define_test_view(),
+        )
+        .unwrap();
+        assert_eq!(u32::from_be_bytes(arr), 1 << 4);
+        assert_eq!(gpe_status(&mut acpi), 0);
+    }
+
+    #[test]
+    fn test_pci_hotplug_sci() {
+        use crate::device::pci::{PciBdf, PciHotplugEvent};
+
+        let hotplug = Rc::new(RefCell::new(PciHotplug::default()));
+        let irqs = Rc::new(MockIrqs::default());
+        let mut acpi = AcpiRuntime::new(PM_BASE).unwrap();
+        acpi.set_pci_hotplug(hotplug.clone());
+        acpi.set_irq_sink(irqs.clone());
+        let write_gpe_enable = |acpi: &mut AcpiRuntime, val: u8| {
+            acpi.on_port_write(
+                AcpiRuntime::GPE_BLOCK_START + 2,
+                PortWriteRequest::OneByte(&[val]),
+                define_test_view(),
+            )
+            .unwrap();
+        };
+
+        // A pending event raises the SCI once the GPE is enabled
+        hotplug
+            .borrow_mut()
+            .push(PciHotplugEvent::Added(PciBdf::new(0, 4, 0)));
+        acpi.poll(0);
+        assert!(irqs.raised.borrow().is_empty());
+        write_gpe_enable(&mut acpi, 0x02);
+        assert_eq!(*irqs.raised.borrow(), [AcpiRuntime::SCI_IRQ]);
+        assert!(acpi.line_asserted(AcpiRuntime::SCI_IRQ));
+
+        // Disabling the GPE lowers it again
+        write_gpe_enable(&mut acpi, 0);
+        assert_eq!(*irqs.lowered.borrow(), [AcpiRuntime::SCI_IRQ]);
+        assert!(!acpi.line_asserted(AcpiRuntime::SCI_IRQ));
+
+        // So does acknowledging the event
+        write_gpe_enable(&mut acpi, 0x02);
+        assert_eq!(irqs.raised.borrow().len(), 2);
+        let mut arr = [0u8; 4];
+        acpi.on_port_read(
+            AcpiRuntime::PCI_SLOT_INJECTION_START,
+            PortReadRequest::FourBytes(&mut arr),
+            define_test_view(),
+        )
+        .unwrap();
+        assert_eq!(irqs.lowered.borrow().len(), 2);
+
+        // An event queued while the guest is not accessing the device is
+        // picked up when it is polled
+        hotplug
+            .borrow_mut()
+            .push(PciHotplugEvent::Added(PciBdf::new(0, 5, 0)));
+        acpi.poll(0);
+        assert_eq!(irqs.raised.borrow().len(), 3);
+    }
+
+    fn read_pm_timer(timer: &mut AcpiPmTimer) -> u32 {
+        let mut arr = [0u8; 4];
+        timer
+            .on_port_read(
+                PM_BASE + 8,
+                PortReadRequest::FourBytes(&mut arr),
+                define_test_view(),
+            )
+            .unwrap();
+        u32::from_be_bytes(arr)
+    }
+
+    #[test]
+    fn test_port_block_validation() {
+        let clock = Rc::new(FixedClock::new(0));
+        assert!(AcpiPmTimer::new(0xfffc, clock.clone(), false).is_ok());
+        assert!(AcpiPmTimer::new(0xfffd, clock.clone(), false).is_err());
+        assert!(AcpiRuntime::new(0xfffa).is_ok());
+        assert!(AcpiRuntime::new(0xfffb).is_err());
+    }
+
+    #[test]
+    fn test_pm_timer_advances() {
+        let clock = Rc::new(FixedClock::new(0));
+        let mut timer =
+            AcpiPmTimer::new(PM_BASE + 8, clock.clone(), false).unwrap();
+        assert_eq!(read_pm_timer(&mut timer), 0);
+
+        clock.advance(1_000_000);
+        assert_eq!(read_pm_timer(&mut timer), 3579);
+        clock.advance(1_000_000_000);
+        assert_eq!(read_pm_timer(&mut timer), 3583124);
+    }
+
+    #[test]
+    fn test_pm_timer_time_diagnostics() {
+        let clock = Rc::new(FixedClock::new(0));
+        let timer =
+            AcpiPmTimer::new(PM_BASE + 8, clock.clone(), false).unwrap();
+        let extended =
+            AcpiPmTimer::new(PM_BASE + 8, clock.clone(), true).unwrap();
+
+        // 10 seconds is 35795450 ticks, which wraps the 24-bit counter
+        clock.advance(10_000_000_000);
+        assert_eq!(
+            timer.time_diagnostics(),
+            TimeDiagnostics {
+                clock_ns: 10_000_000_000,
+                device_time: 35795450 % (1 << 24),
+            }
+        );
+        assert_eq!(extended.time_diagnostics().device_time, 35795450);
+    }
+
+    #[test]
+    fn test_pm_timer_wraps() {
+        // Just past the 24-bit boundary (2^24 ticks is ~4.687s)
+        let clock = Rc::new(FixedClock::new(4_687_500_000));
+        let mut timer =
+            AcpiPmTimer::new(PM_BASE + 8, clock.clone(), false).unwrap();
+        assert_eq!(read_pm_timer(&mut timer), 1901);
+
+        let mut timer =
+            AcpiPmTimer::new(PM_BASE + 8, clock.clone(), true).unwrap();
+        assert_eq!(read_pm_timer(&mut timer), 16779117);
+    }
+}
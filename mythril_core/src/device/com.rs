@@ -1,27 +1,172 @@
+use crate::device::fifo::DeviceFifo;
+use crate::device::input::{InputScript, ScriptEvent};
+use crate::device::interrupt::IrqSink;
+use crate::device::register::{masked_write, RegisterBlock, RegisterInfo};
 use crate::device::{
-    DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
+    check_isa_irq, port_block, DeviceKind, DeviceRegion, EmulatedDevice, Port,
+    PortReadRequest, PortWriteRequest,
 };
 use crate::error::Result;
 use crate::logger;
 use crate::memory::GuestAddressSpaceViewMut;
+use crate::time::ClockSource;
 use alloc::boxed::Box;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::rc::Rc;
 use alloc::string::String;
 use alloc::vec::Vec;
+use core::cell::RefCell;
 use core::convert::TryInto;
 
-#[derive(Default)]
+/// The host side of an emulated serial port
+pub trait SerialBackend {
+    /// Send a byte transmitted by the guest
+    fn transmit(&mut self, byte: u8);
+}
+
+/// A backend shared with the supervisor, so it can inspect what was sent
+impl<T: SerialBackend> SerialBackend for Rc<RefCell<T>> {
+    fn transmit(&mut self, byte: u8) {
+        self.borrow_mut().transmit(byte)
+    }
+}
+
+/// A backend that records each transmitted byte with the time it was sent
+///
+/// Only the most recent `capacity` bytes are kept: once the ring is full,
+/// each new byte replaces the oldest one.
+pub struct TimestampedBackend {
+    clock: Rc<dyn ClockSource>,
+    records: VecDeque<(u64, u8)>,
+    capacity: usize,
+}
+
+impl TimestampedBackend {
+    pub fn new(clock: Rc<dyn ClockSource>, capacity: usize) -> Self {
+        Self {
+            clock,
+            records: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Take the recorded `(timestamp_ns, byte)` pairs, oldest first
+    pub fn drain(&mut self) -> Vec<(u64, u8)> {
+        self.records.drain(..).collect()
+    }
+}
+
+impl SerialBackend for TimestampedBackend {
+    fn transmit(&mut self, byte: u8) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back((self.clock.now_ns(), byte));
+    }
+}
+
+/// A receive error reported in the line status register
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LineError {
+    /// A byte arrived while the receive FIFO was full
+    Overrun,
+    Parity,
+    Framing,
+
+    /// The line was held in the spacing state for longer than a character
+    Break,
+}
+
+/// The rewriting of line endings between the guest and the host
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NewlineTranslation {
+    None,
+
+    /// Rewrite each CR as LF
+    CrToLf,
+
+    /// Rewrite each LF as CR LF
+    LfToCrLf,
+}
+
+impl NewlineTranslation {
+    fn translate(self, byte: u8) -> impl Iterator<Item = u8> {
+        let (prefix, byte) = match (self, byte) {
+            (NewlineTranslation::CrToLf, b'\r') => (None, b'\n'),
+            (NewlineTranslation::LfToCrLf, b'\n') => (Some(b'\r'), b'\n'),
+            _ => (None, byte),
+        };
+        prefix.into_iter().chain(core::iter::once(byte))
+    }
+}
+
+/// Host side conveniences for an interactive console on a serial port
+///
+/// None of these change the registers seen by the guest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConsoleOptions {
+    /// Echo the bytes transmitted by the guest back into the receive FIFO
+    pub echo: bool,
+
+    /// The translation of the bytes transmitted by the guest
+    pub tx_newline: NewlineTranslation,
+
+    /// The translation of the bytes received from the host
+    pub rx_newline: NewlineTranslation,
+}
+
+impl Default for ConsoleOptions {
+    fn default() -> Self {
+        Self {
+            echo: false,
+            tx_newline: NewlineTranslation::None,
+            rx_newline: NewlineTranslation::None,
+        }
+    }
+}
+
 pub struct ComDevice {
     id: u64,
     base_port: Port,
     buff: Vec<u8>,
+    console: ConsoleOptions,
+
+    // Where transmitted bytes are sent instead of the log, if anywhere
+    backend: Option<Box<dyn SerialBackend>>,
+
+    // Bytes received from the host that the guest has not read yet, and
+    // the script they are fed from (if any)
+    rx: DeviceFifo<u8>,
+    script: Option<InputScript>,
+
+    // Whether the receive FIFO has been written or read since the last
+    // poll, the time of the last poll where that happened (while the FIFO
+    // was not empty), and whether the character timeout has expired
+    rx_activity: bool,
+    rx_idle_since: Option<u64>,
+    rx_timeout: bool,
+
+    // Bytes written by the guest that are held back by auto flow control
+    // until CTS is asserted
+    tx: DeviceFifo<u8>,
     divisor: u16,
+    irq: Option<Rc<dyn IrqSink>>,
+    irq_line: Option<u8>,
     interrupt_enable_register: u8,
-    interrupt_identification_register: u8,
-    _line_control_register: u8,
-    _modem_control_register: u8,
+    fifo_control_register: u8,
+    line_control_register: u8,
+    modem_control_register: u8,
     line_status_register: u8,
-    _modem_status_register: u8,
-    _scratch_register: u8,
+    modem_status_register: u8,
+    scratch_register: u8,
+
+    // The transmitter holding register is empty, and the guest has not
+    // yet been told about it (by reading the IIR)
+    thr_empty_pending: bool,
+    irq_raised: bool,
 }
 
 #[allow(non_snake_case)]
@@ -32,33 +177,550 @@ mod SerialOffset {
     pub const IER: u16 = 1;
     pub const DLH: u16 = 1;
     pub const IIR: u16 = 2;
+    pub const FCR: u16 = 2;
     pub const LCR: u16 = 3;
+    pub const MCR: u16 = 4;
     pub const LSR: u16 = 5;
     pub const MSR: u16 = 6;
+    pub const SCR: u16 = 7;
 }
 
 impl ComDevice {
-    pub fn new(vmid: u64, base_port: Port) -> Box<dyn EmulatedDevice> {
+    const IER_RX_AVAILABLE: u8 = 1 << 0;
+    const IER_THR_EMPTY: u8 = 1 << 1;
+    const IER_LINE_STATUS: u8 = 1 << 2;
+    const IIR_NO_INTERRUPT: u8 = 0x01;
+    const IIR_THR_EMPTY: u8 = 0x02;
+    const IIR_RX_AVAILABLE: u8 = 0x04;
+    const IIR_LINE_STATUS: u8 = 0x06;
+    const IIR_CHARACTER_TIMEOUT: u8 = 0x0c;
+    const LCR_WORD_LENGTH: u8 = 0b11;
+    const LCR_STOP_BITS: u8 = 1 << 2;
+    const LCR_PARITY: u8 = 1 << 3;
+    const LCR_BREAK: u8 = 1 << 6;
+    const LCR_DLAB: u8 = 1 << 7;
+    const FCR_CLEAR_RX: u8 = 1 << 1;
+    const FCR_CLEAR_TX: u8 = 1 << 2;
+    const FCR_TRIGGER_SHIFT: u8 = 6;
+    const MCR_RTS: u8 = 1 << 1;
+    const MCR_LOOPBACK: u8 = 1 << 4;
+    const MCR_AUTO_FLOW: u8 = 1 << 5;
+    const MSR_DELTA_CTS: u8 = 1 << 0;
+    const MSR_CTS: u8 = 1 << 4;
+
+    const PORT_COUNT: u16 = 8;
+
+    // The receive and transmit FIFOs of a 16550
+    const RX_FIFO_SIZE: usize = 16;
+    const TX_FIFO_SIZE: usize = 16;
+
+    // The receive FIFO trigger levels selected by FCR bits 7-6
+    const RX_TRIGGER_LEVELS: [usize; 4] = [1, 4, 8, 14];
+
+    // The clock of the baud rate generator divided by 16 (the baud rate
+    // with a divisor of 1)
+    const MAX_BAUD_RATE: u64 = 115_200;
+
+    // The number of character times without receive FIFO activity before
+    // the character timeout interrupt
+    const RX_TIMEOUT_CHARACTERS: u64 = 4;
+
+    const LSR_DATA_READY: u8 = 1 << 0;
+    const LSR_OVERRUN_ERROR: u8 = 1 << 1;
+    const LSR_PARITY_ERROR: u8 = 1 << 2;
+    const LSR_FRAMING_ERROR: u8 = 1 << 3;
+    const LSR_BREAK_INTERRUPT: u8 = 1 << 4;
+
+    // The error bits, which are cleared when the guest reads the LSR
+    const LSR_ERRORS: u8 = Self::LSR_OVERRUN_ERROR
+        | Self::LSR_PARITY_ERROR
+        | Self::LSR_FRAMING_ERROR
+        | Self::LSR_BREAK_INTERRUPT;
+
+    // The transmitter holding register and transmitter are empty
+    const LSR_THR_EMPTY: u8 = 1 << 5;
+    const LSR_TRANSMITTER_EMPTY: u8 = 1 << 6;
+
+    pub fn new(vmid: u64, base_port: Port) -> Box<Self> {
+        Self::with_console(vmid, base_port, ConsoleOptions::default())
+    }
+
+    /// Create a port, failing if its registers would extend past the end
+    /// of the port space
+    pub fn try_new(vmid: u64, base_port: Port) -> Result<Box<Self>> {
+        port_block(base_port, Self::PORT_COUNT)?;
+        Ok(Self::new(vmid, base_port))
+    }
+
+    /// Create a port with the given console options
+    pub fn with_console(
+        vmid: u64,
+        base_port: Port,
+        console: ConsoleOptions,
+    ) -> Box<Self> {
         Box::new(Self {
             id: vmid,
             base_port,
             buff: vec![],
+            console,
+            backend: None,
+            rx: DeviceFifo::new(Self::RX_FIFO_SIZE),
+            script: None,
+            rx_activity: false,
+            rx_idle_since: None,
+            rx_timeout: false,
+            tx: DeviceFifo::new(Self::TX_FIFO_SIZE),
+            divisor: 0,
+            irq: None,
+            irq_line: Self::default_irq_line(base_port),
+            interrupt_enable_register: 0,
+            fifo_control_register: 0,
+            line_control_register: 0,
+            modem_control_register: 0,
 
             // For now, transmitter holding register is always empty
-            interrupt_identification_register: 0x02,
+            line_status_register: Self::LSR_THR_EMPTY
+                | Self::LSR_TRANSMITTER_EMPTY,
 
-            ..Default::default()
+            // The host end of the line is always ready to receive
+            modem_status_register: Self::MSR_CTS,
+            scratch_register: 0,
+            thr_empty_pending: true,
+            irq_raised: false,
         })
     }
 
+    /// Send the bytes transmitted by the guest to `backend` instead of
+    /// writing them to the log
+    pub fn set_backend(&mut self, backend: Box<dyn SerialBackend>) {
+        self.backend = Some(backend);
+    }
+
+    /// Set the sink used to raise this port's IRQ
+    pub fn set_irq_sink(&mut self, irq: Rc<dyn IrqSink>) {
+        self.irq = Some(irq);
+    }
+
+    /// Use `line` as this port's IRQ instead of the conventional one for
+    /// its base port, or no IRQ at all (so the guest must poll the port)
+    pub fn set_irq_line(&mut self, line: Option<u8>) -> Result<()> {
+        if let Some(line) = line {
+            check_isa_irq(line)?;
+        }
+        self.irq_line = line;
+        Ok(())
+    }
+
+    // The conventional assignments for COM1-4
+    fn default_irq_line(base_port: Port) -> Option<u8> {
+        match base_port {
+            0x3f8 | 0x3e8 => Some(4),
+            0x2f8 | 0x2e8 => Some(3),
+            _ => None,
+        }
+    }
+
+    /// Queue a byte received from the host for the guest to read
+    ///
+    /// The byte is first translated according to the `rx_newline` console
+    /// option. If the receive FIFO is full, the byte is lost and an
+    /// overrun error is reported to the guest in the LSR.
+    pub fn receive(&mut self, byte: u8) -> Result<()> {
+        for byte in self.console.rx_newline.translate(byte) {
+            self.push_rx(byte)?;
+        }
+        Ok(())
+    }
+
+    fn push_rx(&mut self, byte: u8) -> Result<()> {
+        self.rx.push(byte).map_err(|err| {
+            self.inject_line_error(LineError::Overrun);
+            err
+        })?;
+        self.rx_activity = true;
+        self.update_irq();
+        Ok(())
+    }
+
+    /// Report a receive error to the guest in the LSR, raising the line
+    /// status interrupt if it is enabled
+    ///
+    /// As on a 16550, a break also receives a single zero byte.
+    pub fn inject_line_error(&mut self, error: LineError) {
+        let bit = match error {
+            LineError::Overrun => Self::LSR_OVERRUN_ERROR,
+            LineError::Parity => Self::LSR_PARITY_ERROR,
+            LineError::Framing => Self::LSR_FRAMING_ERROR,
+            LineError::Break => {
+                let _ = self.rx.push(0);
+                Self::LSR_BREAK_INTERRUPT
+            }
+        };
+        self.line_status_register |= bit;
+        self.update_irq();
+    }
+
+    /// Returns true while the guest is transmitting a break (LCR bit 6)
+    pub fn break_active(&self) -> bool {
+        self.line_control_register & Self::LCR_BREAK != 0
+    }
+
+    /// Feed the serial events of `script` to the guest as it reads them
+    ///
+    /// The receive FIFO is topped up from the script whenever the guest
+    /// reads the RBR or LSR (and when the device is polled), so scripted
+    /// input is never overrun. Other events in the script are skipped.
+    pub fn load_script(&mut self, script: InputScript) {
+        self.script = Some(script);
+        self.fill_from_script();
+    }
+
+    fn fill_from_script(&mut self) {
+        let script = match &mut self.script {
+            Some(script) => script,
+            None => return,
+        };
+        while !self.rx.is_full() {
+            match script.next_event() {
+                Some(ScriptEvent::Serial(byte)) => {
+                    let _ = self.rx.push(byte);
+                    self.rx_activity = true;
+                }
+                Some(event) => {
+                    info!("Skipping scripted {:?} for the serial port", event)
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// The number of bytes in the receive FIFO at which the received data
+    /// available interrupt is raised and auto flow control deasserts RTS
+    /// (the trigger level selected by FCR bits 7-6)
+    pub fn rx_trigger_level(&self) -> usize {
+        Self::RX_TRIGGER_LEVELS
+            [(self.fifo_control_register >> Self::FCR_TRIGGER_SHIFT) as usize]
+    }
+
+    /// The level of the RTS output
+    ///
+    /// RTS follows MCR bit 1, except that with auto flow control (MCR bit
+    /// 5) it is deasserted while the receive FIFO is at or above its
+    /// trigger level.
+    pub fn rts(&self) -> bool {
+        if self.modem_control_register & Self::MCR_RTS == 0 {
+            return false;
+        }
+        !self.auto_flow_control() || self.rx.len() < self.rx_trigger_level()
+    }
+
+    /// Set the level of the CTS input from the host end of the line
+    ///
+    /// With auto flow control, bytes written by the guest are held in the
+    /// transmit FIFO while CTS is deasserted, and sent when it is asserted
+    /// again.
+    pub fn set_cts(&mut self, asserted: bool) {
+        let cts = if asserted { Self::MSR_CTS } else { 0 };
+        if self.modem_status_register & Self::MSR_CTS != cts {
+            self.modem_status_register ^= Self::MSR_CTS;
+            self.modem_status_register |= Self::MSR_DELTA_CTS;
+        }
+        self.flush_tx();
+    }
+
+    /// The baud rate programmed by the guest through the divisor latch, or
+    /// 0 if the divisor has not been set
+    pub fn baud_rate(&self) -> u32 {
+        match self.divisor {
+            0 => 0,
+            divisor => (Self::MAX_BAUD_RATE / divisor as u64) as u32,
+        }
+    }
+
+    /// The time taken to send or receive one character at the current
+    /// baud rate and line settings (including the start, parity and stop
+    /// bits)
+    pub fn character_time_ns(&self) -> u64 {
+        let lcr = self.line_control_register;
+        let mut bits = 1 + 5 + (lcr & Self::LCR_WORD_LENGTH) as u64 + 1;
+        if lcr & Self::LCR_PARITY != 0 {
+            bits += 1;
+        }
+        if lcr & Self::LCR_STOP_BITS != 0 {
+            bits += 1;
+        }
+        // An unset divisor is treated like the slowest rate
+        let divisor = match self.divisor {
+            0 => 0x10000,
+            divisor => divisor as u64,
+        };
+        bits * divisor * 1_000_000_000 / Self::MAX_BAUD_RATE
+    }
+
+    // Track receive FIFO activity, expiring the character timeout once
+    // bytes have sat in the FIFO without being read (or added to) for four
+    // character times
+    fn update_rx_timeout(&mut self, now: u64) {
+        if self.rx.is_empty() {
+            self.rx_idle_since = None;
+            self.rx_timeout = false;
+            return;
+        }
+        let activity = core::mem::replace(&mut self.rx_activity, false);
+        match self.rx_idle_since {
+            Some(since) if !activity => {
+                let timeout =
+                    Self::RX_TIMEOUT_CHARACTERS * self.character_time_ns();
+                if now.saturating_sub(since) >= timeout {
+                    self.rx_timeout = true;
+                }
+            }
+            _ => self.rx_idle_since = Some(now),
+        }
+    }
+
+    // Read the RBR, which acknowledges the character timeout (and the
+    // received data available interrupt once the FIFO drops below its
+    // trigger level)
+    fn read_receive_buffer(&mut self) -> u8 {
+        self.fill_from_script();
+        let data = self.rx.pop().unwrap_or(0);
+        self.rx_activity = true;
+        self.rx_timeout = false;
+        self.fill_from_script();
+        if !self.rx_interrupt() {
+            self.irq_raised = false;
+        }
+        self.flush_tx();
+        self.update_irq();
+        data
+    }
+
+    fn auto_flow_control(&self) -> bool {
+        self.modem_control_register & Self::MCR_AUTO_FLOW != 0
+    }
+
+    // In loopback mode, CTS is connected to RTS
+    fn cts(&self) -> bool {
+        if self.modem_control_register & Self::MCR_LOOPBACK != 0 {
+            self.rts()
+        } else {
+            self.modem_status_register & Self::MSR_CTS != 0
+        }
+    }
+
+    fn modem_status(&self) -> u8 {
+        let msr = self.modem_status_register & !Self::MSR_CTS;
+        if self.cts() {
+            msr | Self::MSR_CTS
+        } else {
+            msr
+        }
+    }
+
+    // Reading the MSR clears the delta bits
+    fn read_modem_status(&mut self) -> u8 {
+        let msr = self.modem_status();
+        self.modem_status_register &= !Self::MSR_DELTA_CTS;
+        msr
+    }
+
+    fn write_modem_control(&mut self, val: u8) {
+        self.modem_control_register = val;
+        self.flush_tx();
+    }
+
+    fn line_status(&self) -> u8 {
+        let mut lsr = self.line_status_register;
+        if !self.rx.is_empty() {
+            lsr |= Self::LSR_DATA_READY;
+        }
+        if !self.tx.is_empty() {
+            lsr &= !(Self::LSR_THR_EMPTY | Self::LSR_TRANSMITTER_EMPTY);
+        }
+        lsr
+    }
+
+    // Reading the LSR clears the error bits (and so acknowledges the
+    // line status interrupt)
+    fn read_line_status(&mut self) -> u8 {
+        self.fill_from_script();
+        let lsr = self.line_status();
+        if lsr & Self::LSR_ERRORS != 0 {
+            self.line_status_register &= !Self::LSR_ERRORS;
+            self.irq_raised = false;
+        }
+        lsr
+    }
+
+    fn write_fifo_control(&mut self, val: u8) {
+        if val & Self::FCR_CLEAR_RX != 0 {
+            self.rx.clear();
+            self.rx_timeout = false;
+        }
+        if val & Self::FCR_CLEAR_TX != 0 && !self.tx.is_empty() {
+            self.tx.clear();
+            self.thr_empty_pending = true;
+            self.irq_raised = false;
+        }
+        self.fifo_control_register = val;
+    }
+
+    fn thr_empty_interrupt(&self) -> bool {
+        self.thr_empty_pending
+            && self.interrupt_enable_register & Self::IER_THR_EMPTY != 0
+    }
+
+    fn line_status_interrupt(&self) -> bool {
+        self.line_status_register & Self::LSR_ERRORS != 0
+            && self.interrupt_enable_register & Self::IER_LINE_STATUS != 0
+    }
+
+    fn rx_interrupt(&self) -> bool {
+        self.interrupt_enable_register & Self::IER_RX_AVAILABLE != 0
+            && (self.rx_timeout || self.rx.len() >= self.rx_trigger_level())
+    }
+
+    fn pending_interrupt(&self) -> u8 {
+        if self.line_status_interrupt() {
+            Self::IIR_LINE_STATUS
+        } else if self.rx_interrupt() {
+            if self.rx.len() >= self.rx_trigger_level() {
+                Self::IIR_RX_AVAILABLE
+            } else {
+                Self::IIR_CHARACTER_TIMEOUT
+            }
+        } else if self.thr_empty_interrupt() {
+            Self::IIR_THR_EMPTY
+        } else {
+            Self::IIR_NO_INTERRUPT
+        }
+    }
+
+    fn interrupt_identification(&mut self) -> u8 {
+        // Reading the IIR acknowledges the THR empty interrupt
+        let iir = self.pending_interrupt();
+        if iir == Self::IIR_THR_EMPTY {
+            self.thr_empty_pending = false;
+            self.irq_raised = false;
+        }
+        iir
+    }
+
     fn divisor_latch_bit_set(&self) -> bool {
-        self.line_status_register & (1 << 7) != 0
+        self.line_control_register & Self::LCR_DLAB != 0
+    }
+
+    fn write_interrupt_enable(&mut self, val: u8) {
+        // Enabling the THR empty interrupt while the THR is empty causes an
+        // interrupt
+        if val & !self.interrupt_enable_register & Self::IER_THR_EMPTY != 0 {
+            self.thr_empty_pending = true;
+            self.irq_raised = false;
+        }
+        self.interrupt_enable_register = val;
+    }
+
+    // Raise the IRQ if an interrupt is pending that has not been raised
+    fn update_irq(&mut self) {
+        if self.pending_interrupt() == Self::IIR_NO_INTERRUPT || self.irq_raised
+        {
+            return;
+        }
+        if let (Some(irq), Some(line)) = (&self.irq, self.irq_lines().first()) {
+            irq.raise_irq(*line);
+            self.irq_raised = true;
+        }
+    }
+
+    fn transmit(&mut self, val: u8) {
+        // While a break is being sent, the line is held in the spacing
+        // state and nothing else goes out
+        if self.break_active() {
+            info!("Dropping 0x{:x} sent during a break", val);
+        } else if self.auto_flow_control() && !self.cts() {
+            if self.tx.push(val).is_err() {
+                info!("Dropping 0x{:x} sent to a full transmit FIFO", val);
+            }
+            return;
+        } else {
+            self.send(val);
+        }
+
+        // Output is transmitted immediately, so the THR is empty again
+        self.thr_empty_pending = true;
+        self.irq_raised = false;
+    }
+
+    // Send the bytes held back by auto flow control, if CTS allows it
+    fn flush_tx(&mut self) {
+        if self.tx.is_empty() || (self.auto_flow_control() && !self.cts()) {
+            return;
+        }
+        while let Some(val) = self.tx.pop() {
+            self.send(val);
+        }
+        self.thr_empty_pending = true;
+        self.irq_raised = false;
+        self.update_irq();
+    }
+
+    fn send(&mut self, val: u8) {
+        if self.console.echo {
+            // An overrun is reported to the guest like any other
+            let _ = self.push_rx(val);
+        }
+        for val in self.console.tx_newline.translate(val) {
+            if let Some(backend) = &mut self.backend {
+                backend.transmit(val);
+                continue;
+            }
+            self.buff.push(val);
+            if val == 10 {
+                let s = String::from_utf8_lossy(&self.buff);
+                logger::write_console(&format!("GUEST{}: {}", self.id, s));
+                self.buff.clear();
+            }
+        }
     }
 }
 
 impl EmulatedDevice for ComDevice {
     fn services(&self) -> Vec<DeviceRegion> {
-        vec![DeviceRegion::PortIo(self.base_port..=self.base_port + 7)]
+        vec![DeviceRegion::PortIo(
+            self.base_port..=self.base_port + (Self::PORT_COUNT - 1),
+        )]
+    }
+
+    fn depends_on(&self) -> Vec<DeviceKind> {
+        vec![DeviceKind::Pic]
+    }
+
+    fn irq_lines(&self) -> Vec<u8> {
+        self.irq_line.into_iter().collect()
+    }
+
+    fn reset(&mut self) {
+        // Output that has not been flushed yet is still printed, and the
+        // rest of an input script is still fed to the guest
+        let irq = self.irq.take();
+        let script = self.script.take();
+        let buff = core::mem::take(&mut self.buff);
+        let backend = self.backend.take();
+        let irq_line = self.irq_line;
+        *self = *Self::with_console(self.id, self.base_port, self.console);
+        self.irq = irq;
+        self.irq_line = irq_line;
+        self.script = script;
+        self.buff = buff;
+        self.backend = backend;
+    }
+
+    fn poll(&mut self, now: u64) {
+        self.fill_from_script();
+        self.flush_tx();
+        self.update_rx_timeout(now);
+        self.update_irq();
     }
 
     fn on_port_read(
@@ -67,26 +729,20 @@ impl EmulatedDevice for ComDevice {
         mut val: PortReadRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
-        if port - self.base_port == SerialOffset::DATA
-            && self.divisor_latch_bit_set()
-        {
-            val.copy_from_u32((self.divisor & 0xff).into());
-        } else if port - self.base_port == SerialOffset::DLL
-            && self.divisor_latch_bit_set()
-        {
-            val.copy_from_u32((self.divisor >> 8).into());
-        }
-
-        if port - self.base_port == SerialOffset::IIR {
-            val.copy_from_u32(self.interrupt_identification_register as u32);
-        } else if port - self.base_port == SerialOffset::IER {
-            val.copy_from_u32(self.interrupt_enable_register as u32);
-        }
-
-        if port - self.base_port == SerialOffset::LSR {
-            val.copy_from_u32(0x20);
-        }
-
+        let data = match (port - self.base_port, self.divisor_latch_bit_set()) {
+            (SerialOffset::DLL, true) => self.divisor as u8,
+            (SerialOffset::DLH, true) => (self.divisor >> 8) as u8,
+            (SerialOffset::DATA, false) => self.read_receive_buffer(),
+            (SerialOffset::IER, false) => self.interrupt_enable_register,
+            (SerialOffset::IIR, _) => self.interrupt_identification(),
+            (SerialOffset::LCR, _) => self.line_control_register,
+            (SerialOffset::MCR, _) => self.modem_control_register,
+            (SerialOffset::LSR, _) => self.read_line_status(),
+            (SerialOffset::MSR, _) => self.read_modem_status(),
+            (SerialOffset::SCR, _) => self.scratch_register,
+            _ => return Ok(()),
+        };
+        val.copy_from_u32(data as u32);
         Ok(())
     }
 
@@ -97,29 +753,675 @@ impl EmulatedDevice for ComDevice {
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
         let val: u8 = val.try_into()?;
-        if port - self.base_port == SerialOffset::DATA {
-            if self.divisor_latch_bit_set() {
-                self.divisor &= 0xff00 | val as u16;
-            } else {
-                self.buff.push(val);
-                if val == 10 {
-                    let s = String::from_utf8_lossy(&self.buff);
-                    logger::write_console(&format!("GUEST{}: {}", self.id, s));
-                    self.buff.clear();
-                }
+        match (port - self.base_port, self.divisor_latch_bit_set()) {
+            (SerialOffset::DLL, true) => {
+                self.divisor = (self.divisor & 0xff00) | val as u16
             }
-        } else if port - self.base_port == SerialOffset::DLL
-            && self.divisor_latch_bit_set()
+            (SerialOffset::DLH, true) => {
+                self.divisor = (self.divisor & 0xff) | (val as u16) << 8
+            }
+            (SerialOffset::DATA, false) => self.transmit(val),
+            (SerialOffset::IER, false) => self.write_interrupt_enable(val),
+            (SerialOffset::FCR, _) => self.write_fifo_control(val),
+            (SerialOffset::LCR, _) => self.line_control_register = val,
+            (SerialOffset::MCR, _) => self.write_modem_control(val),
+            (SerialOffset::SCR, _) => self.scratch_register = val,
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+impl RegisterBlock for ComDevice {
+    fn registers(&self) -> &'static [RegisterInfo] {
+        const REGISTERS: &[RegisterInfo] = &[
+            RegisterInfo::new("IER", SerialOffset::IER, 1),
+            RegisterInfo::new("IIR", SerialOffset::IIR, 1),
+            RegisterInfo::new("LCR", SerialOffset::LCR, 1),
+            RegisterInfo::new("MCR", SerialOffset::MCR, 1),
+            RegisterInfo::new("LSR", SerialOffset::LSR, 1),
+            RegisterInfo::new("MSR", SerialOffset::MSR, 1),
+            RegisterInfo::new("SCR", SerialOffset::SCR, 1),
+        ];
+        REGISTERS
+    }
+
+    /// Read a register as the guest would see it with the current DLAB
+    /// setting (offset 0 is the divisor latch or, with DLAB clear, reads
+    /// as 0)
+    fn read_reg(&self, offset: u16) -> u32 {
+        let data = match (offset, self.divisor_latch_bit_set()) {
+            (SerialOffset::DLL, true) => self.divisor as u8,
+            (SerialOffset::DLH, true) => (self.divisor >> 8) as u8,
+            (SerialOffset::IER, false) => self.interrupt_enable_register,
+            (SerialOffset::IIR, _) => self.pending_interrupt(),
+            (SerialOffset::LCR, _) => self.line_control_register,
+            (SerialOffset::MCR, _) => self.modem_control_register,
+            (SerialOffset::LSR, _) => self.line_status(),
+            (SerialOffset::MSR, _) => self.modem_status(),
+            (SerialOffset::SCR, _) => self.scratch_register,
+            _ => 0,
+        };
+        data as u32
+    }
+
+    /// Write a register as the guest would with the current DLAB setting
+    /// (a write to offset 2 sets the FCR, and writes to the THR are
+    /// ignored)
+    fn write_reg(&mut self, offset: u16, val: u32, mask: u32) {
+        let old = self.read_reg(offset);
+        let val = masked_write(old, val, mask) as u8;
+        match (offset, self.divisor_latch_bit_set()) {
+            (SerialOffset::DLL, true) => {
+                self.divisor = (self.divisor & 0xff00) | val as u16
+            }
+            (SerialOffset::DLH, true) => {
+                self.divisor = (self.divisor & 0xff) | (val as u16) << 8
+            }
+            (SerialOffset::IER, false) => self.write_interrupt_enable(val),
+            (SerialOffset::FCR, _) => self.fifo_control_register = val,
+            (SerialOffset::LCR, _) => self.line_control_register = val,
+            (SerialOffset::MCR, _) => self.modem_control_register = val,
+            (SerialOffset::LSR, _) => {
+                self.line_status_register = val & !Self::LSR_DATA_READY
+            }
+            (SerialOffset::MSR, _) => self.modem_status_register = val,
+            (SerialOffset::SCR, _) => self.scratch_register = val,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::{define_test_view, MockIrqs};
+    use crate::error::Error;
+    use crate::ioapic::TriggerMode;
+    use crate::time::FixedClock;
+
+    #[test]
+    fn test_thr_empty_interrupt() {
+        let irqs = Rc::new(MockIrqs::default());
+        let mut com = ComDevice::new(0, 0x3f8);
+        com.set_irq_sink(irqs.clone());
+        com.poll(0);
+        assert!(irqs.raised.borrow().is_empty());
+
+        com.on_port_write(
+            0x3f8 + SerialOffset::IER,
+            PortWriteRequest::OneByte(&[ComDevice::IER_THR_EMPTY]),
+            define_test_view(),
+        )
+        .unwrap();
+        com.poll(0);
+        com.poll(0);
+        assert_eq!(*irqs.raised.borrow(), [4]);
+
+        let mut iir = || {
+            let mut arr = [0u8];
+            com.on_port_read(
+                0x3f8 + SerialOffset::IIR,
+                PortReadRequest::OneByte(&mut arr),
+                define_test_view(),
+            )
+            .unwrap();
+            arr[0]
+        };
+        assert_eq!(iir(), ComDevice::IIR_THR_EMPTY);
+        assert_eq!(iir(), ComDevice::IIR_NO_INTERRUPT);
+    }
+
+    fn write_com(com: &mut ComDevice, offset: u16, val: u8) {
+        com.on_port_write(
+            0x3f8 + offset,
+            PortWriteRequest::OneByte(&[val]),
+            define_test_view(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_divisor_latch() {
+        let mut com = ComDevice::new(0, 0x3f8);
+        write_com(&mut com, SerialOffset::IER, ComDevice::IER_THR_EMPTY);
+        write_com(&mut com, SerialOffset::LCR, ComDevice::LCR_DLAB | 0x03);
+        write_com(&mut com, SerialOffset::DLL, 0x0c);
+        write_com(&mut com, SerialOffset::DLH, 0x00);
+        assert_eq!(com.divisor, 12);
+        assert_eq!(com.read_reg(SerialOffset::DLL), 0x0c);
+
+        // The IER is unchanged by the divisor latch writes
+        write_com(&mut com, SerialOffset::LCR, 0x03);
+        assert_eq!(
+            com.read_reg(SerialOffset::IER),
+            ComDevice::IER_THR_EMPTY as u32
+        );
+    }
+
+    fn set_divisor(com: &mut ComDevice, divisor: u16) {
+        write_com(com, SerialOffset::LCR, ComDevice::LCR_DLAB | 0x03);
+        write_com(com, SerialOffset::DLL, divisor as u8);
+        write_com(com, SerialOffset::DLH, (divisor >> 8) as u8);
+        write_com(com, SerialOffset::LCR, 0x03);
+    }
+
+    #[test]
+    fn test_baud_rate() {
+        let mut com = ComDevice::new(0, 0x3f8);
+        assert_eq!(com.baud_rate(), 0);
+
+        for (divisor, baud) in
+            [(1, 115_200), (12, 9600), (0x180, 300), (0, 0)].iter()
         {
-            self.divisor = (self.divisor & 0xff) | (val as u16) << 8;
+            set_divisor(&mut com, *divisor);
+            assert_eq!(com.baud_rate(), *baud);
         }
+    }
+
+    fn read_com(com: &mut ComDevice, offset: u16) -> u8 {
+        let mut arr = [0u8];
+        com.on_port_read(
+            0x3f8 + offset,
+            PortReadRequest::OneByte(&mut arr),
+            define_test_view(),
+        )
+        .unwrap();
+        arr[0]
+    }
 
-        if port - self.base_port == SerialOffset::IIR {
-            self.interrupt_identification_register = val;
-        } else if port - self.base_port == SerialOffset::IER {
-            self.interrupt_enable_register = val;
+    #[test]
+    fn test_receive_fifo() {
+        let mut com = ComDevice::new(0, 0x3f8);
+        assert_eq!(read_com(&mut com, SerialOffset::LSR) & 0x01, 0);
+
+        for byte in b"hello".iter() {
+            com.receive(*byte).unwrap();
+        }
+        let mut received = vec![];
+        while read_com(&mut com, SerialOffset::LSR) & ComDevice::LSR_DATA_READY
+            != 0
+        {
+            received.push(read_com(&mut com, SerialOffset::DATA));
         }
+        assert_eq!(received, b"hello");
 
-        Ok(())
+        // Bytes received while the FIFO is full are lost, and the overrun
+        // is reported (once) in the LSR
+        for byte in 0..ComDevice::RX_FIFO_SIZE as u8 {
+            com.receive(byte).unwrap();
+        }
+        assert_eq!(
+            com.receive(0xff),
+            Err(Error::FifoOverrun {
+                capacity: ComDevice::RX_FIFO_SIZE
+            })
+        );
+        let lsr = read_com(&mut com, SerialOffset::LSR);
+        assert_eq!(
+            lsr & (ComDevice::LSR_DATA_READY | ComDevice::LSR_OVERRUN_ERROR),
+            ComDevice::LSR_DATA_READY | ComDevice::LSR_OVERRUN_ERROR
+        );
+        assert_eq!(
+            read_com(&mut com, SerialOffset::LSR)
+                & ComDevice::LSR_OVERRUN_ERROR,
+            0
+        );
+        assert_eq!(read_com(&mut com, SerialOffset::DATA), 0);
+
+        // The FCR can clear the FIFO
+        write_com(&mut com, SerialOffset::FCR, ComDevice::FCR_CLEAR_RX);
+        assert_eq!(
+            read_com(&mut com, SerialOffset::LSR) & ComDevice::LSR_DATA_READY,
+            0
+        );
+    }
+
+    #[test]
+    fn test_rx_trigger_level() {
+        let irqs = Rc::new(MockIrqs::default());
+        let mut com = ComDevice::new(0, 0x3f8);
+        com.set_irq_sink(irqs.clone());
+        write_com(&mut com, SerialOffset::IER, ComDevice::IER_RX_AVAILABLE);
+
+        // FIFO enabled with a trigger level of 4 bytes
+        write_com(&mut com, SerialOffset::FCR, 0x41);
+        assert_eq!(com.rx_trigger_level(), 4);
+        for byte in b"abc".iter() {
+            com.receive(*byte).unwrap();
+        }
+        assert!(irqs.raised.borrow().is_empty());
+        assert_eq!(
+            read_com(&mut com, SerialOffset::IIR),
+            ComDevice::IIR_NO_INTERRUPT
+        );
+        com.receive(b'd').unwrap();
+        assert_eq!(*irqs.raised.borrow(), [4]);
+        assert_eq!(
+            read_com(&mut com, SerialOffset::IIR),
+            ComDevice::IIR_RX_AVAILABLE
+        );
+
+        // Draining below the trigger level acknowledges the interrupt
+        assert_eq!(read_com(&mut com, SerialOffset::DATA), b'a');
+        assert_eq!(
+            read_com(&mut com, SerialOffset::IIR),
+            ComDevice::IIR_NO_INTERRUPT
+        );
+    }
+
+    #[test]
+    fn test_rx_character_timeout() {
+        let irqs = Rc::new(MockIrqs::default());
+        let mut com = ComDevice::new(0, 0x3f8);
+        com.set_irq_sink(irqs.clone());
+
+        // 9600 baud, 8N1: 10 bits per character
+        write_com(&mut com, SerialOffset::LCR, ComDevice::LCR_DLAB | 0x03);
+        write_com(&mut com, SerialOffset::DLL, 12);
+        write_com(&mut com, SerialOffset::LCR, 0x03);
+        assert_eq!(com.character_time_ns(), 1_041_666);
+        let timeout = 4 * com.character_time_ns();
+
+        write_com(&mut com, SerialOffset::IER, ComDevice::IER_RX_AVAILABLE);
+        write_com(&mut com, SerialOffset::FCR, 0x81);
+        com.receive(b'x').unwrap();
+        com.receive(b'y').unwrap();
+        com.poll(1_000);
+        com.poll(1_000 + timeout - 1);
+        assert!(irqs.raised.borrow().is_empty());
+
+        // A new byte restarts the timeout
+        com.receive(b'z').unwrap();
+        com.poll(1_000 + timeout);
+        com.poll(1_000 + 2 * timeout - 1);
+        assert!(irqs.raised.borrow().is_empty());
+        com.poll(1_000 + 2 * timeout);
+        assert_eq!(*irqs.raised.borrow(), [4]);
+        assert_eq!(
+            read_com(&mut com, SerialOffset::IIR),
+            ComDevice::IIR_CHARACTER_TIMEOUT
+        );
+
+        // Reading the RBR acknowledges it
+        assert_eq!(read_com(&mut com, SerialOffset::DATA), b'x');
+        assert_eq!(
+            read_com(&mut com, SerialOffset::IIR),
+            ComDevice::IIR_NO_INTERRUPT
+        );
+    }
+
+    #[test]
+    fn test_scripted_input() {
+        use crate::time::FixedClock;
+
+        let clock = Rc::new(FixedClock::new(0));
+        let mut script = InputScript::new(clock.clone());
+        script.push_serial(b"login: root and some more bytes\n");
+        script.push(ScriptEvent::Delay(1_000_000));
+        script.push_serial(b"password\n");
+
+        let mut com = ComDevice::new(0, 0x3f8);
+        com.load_script(script);
+
+        let read_available = |com: &mut ComDevice| {
+            let mut received = vec![];
+            while read_com(com, SerialOffset::LSR) & ComDevice::LSR_DATA_READY
+                != 0
+            {
+                received.push(read_com(com, SerialOffset::DATA));
+            }
+            received
+        };
+
+        // More than a FIFO's worth of input is delivered in order, and
+        // without overruns
+        assert_eq!(
+            read_available(&mut com),
+            b"login: root and some more bytes\n".to_vec()
+        );
+        assert_eq!(
+            read_com(&mut com, SerialOffset::LSR)
+                & ComDevice::LSR_OVERRUN_ERROR,
+            0
+        );
+
+        // The rest waits for the delay
+        clock.advance(999_999);
+        assert_eq!(read_available(&mut com), b"");
+        clock.advance(1);
+        assert_eq!(read_available(&mut com), b"password\n".to_vec());
+    }
+
+    #[test]
+    fn test_line_errors() {
+        let irqs = Rc::new(MockIrqs::default());
+        let mut com = ComDevice::new(0, 0x3f8);
+        com.set_irq_sink(irqs.clone());
+
+        // Without the line status interrupt, the error is only in the LSR
+        com.inject_line_error(LineError::Parity);
+        assert!(irqs.raised.borrow().is_empty());
+        let lsr = read_com(&mut com, SerialOffset::LSR);
+        assert_eq!(lsr & ComDevice::LSR_ERRORS, ComDevice::LSR_PARITY_ERROR);
+
+        write_com(&mut com, SerialOffset::IER, ComDevice::IER_LINE_STATUS);
+        com.inject_line_error(LineError::Framing);
+        assert_eq!(*irqs.raised.borrow(), [4]);
+        assert_eq!(
+            read_com(&mut com, SerialOffset::IIR),
+            ComDevice::IIR_LINE_STATUS
+        );
+
+        // Reading the LSR clears the error, and the interrupt with it
+        let lsr = read_com(&mut com, SerialOffset::LSR);
+        assert_eq!(lsr & ComDevice::LSR_ERRORS, ComDevice::LSR_FRAMING_ERROR);
+        assert_eq!(
+            read_com(&mut com, SerialOffset::LSR) & ComDevice::LSR_ERRORS,
+            0
+        );
+        assert_eq!(
+            read_com(&mut com, SerialOffset::IIR),
+            ComDevice::IIR_NO_INTERRUPT
+        );
+
+        // A break also receives a zero byte
+        com.inject_line_error(LineError::Break);
+        assert_eq!(*irqs.raised.borrow(), [4, 4]);
+        let lsr = read_com(&mut com, SerialOffset::LSR);
+        assert_eq!(
+            lsr & (ComDevice::LSR_BREAK_INTERRUPT | ComDevice::LSR_DATA_READY),
+            ComDevice::LSR_BREAK_INTERRUPT | ComDevice::LSR_DATA_READY
+        );
+        assert_eq!(read_com(&mut com, SerialOffset::DATA), 0);
+    }
+
+    #[test]
+    fn test_transmit_break() {
+        let mut com = ComDevice::new(0, 0x3f8);
+        write_com(&mut com, SerialOffset::LCR, ComDevice::LCR_BREAK | 0x03);
+        assert!(com.break_active());
+        write_com(&mut com, SerialOffset::DATA, b'x');
+        assert!(com.buff.is_empty());
+
+        write_com(&mut com, SerialOffset::LCR, 0x03);
+        assert!(!com.break_active());
+        write_com(&mut com, SerialOffset::DATA, b'x');
+        assert_eq!(com.buff, b"x");
+    }
+
+    #[test]
+    fn test_console_echo() {
+        let mut com = ComDevice::with_console(
+            0,
+            0x3f8,
+            ConsoleOptions {
+                echo: true,
+                ..ConsoleOptions::default()
+            },
+        );
+        write_com(&mut com, SerialOffset::DATA, b'x');
+        assert_eq!(com.buff, b"x");
+        assert_ne!(
+            read_com(&mut com, SerialOffset::LSR) & ComDevice::LSR_DATA_READY,
+            0
+        );
+        assert_eq!(read_com(&mut com, SerialOffset::DATA), b'x');
+
+        // Without echo, nothing is received
+        let mut com = ComDevice::new(0, 0x3f8);
+        write_com(&mut com, SerialOffset::DATA, b'x');
+        assert_eq!(
+            read_com(&mut com, SerialOffset::LSR) & ComDevice::LSR_DATA_READY,
+            0
+        );
+    }
+
+    #[test]
+    fn test_console_newline_translation() {
+        let mut com = ComDevice::with_console(
+            0,
+            0x3f8,
+            ConsoleOptions {
+                rx_newline: NewlineTranslation::LfToCrLf,
+                ..ConsoleOptions::default()
+            },
+        );
+        com.receive(b'a').unwrap();
+        com.receive(b'\n').unwrap();
+        let received: Vec<u8> = (0..3)
+            .map(|_| read_com(&mut com, SerialOffset::DATA))
+            .collect();
+        assert_eq!(received, b"a\r\n");
+
+        let mut com = ComDevice::with_console(
+            0,
+            0x3f8,
+            ConsoleOptions {
+                rx_newline: NewlineTranslation::CrToLf,
+                ..ConsoleOptions::default()
+            },
+        );
+        com.receive(b'\r').unwrap();
+        assert_eq!(read_com(&mut com, SerialOffset::DATA), b'\n');
+
+        // (Transmitted bytes go through the same translation, but a line
+        // ending flushes them to the host console)
+        let translate = |mode: NewlineTranslation, bytes: &[u8]| {
+            bytes
+                .iter()
+                .flat_map(|byte| mode.translate(*byte))
+                .collect::<Vec<u8>>()
+        };
+        assert_eq!(translate(NewlineTranslation::CrToLf, b"a\r\nb"), b"a\n\nb");
+        assert_eq!(
+            translate(NewlineTranslation::LfToCrLf, b"a\r\nb\n"),
+            b"a\r\r\nb\r\n"
+        );
+        assert_eq!(translate(NewlineTranslation::None, b"a\r\n"), b"a\r\n");
+
+        // The options survive a reset
+        com.reset();
+        com.receive(b'\r').unwrap();
+        assert_eq!(read_com(&mut com, SerialOffset::DATA), b'\n');
+    }
+
+    #[test]
+    fn test_auto_rts() {
+        let mut com = ComDevice::new(0, 0x3f8);
+        // A trigger level of 4 bytes, with RTS and auto flow control in
+        // loopback mode (so the MSR shows RTS as CTS)
+        write_com(&mut com, SerialOffset::FCR, 0x41);
+        write_com(
+            &mut com,
+            SerialOffset::MCR,
+            ComDevice::MCR_RTS
+                | ComDevice::MCR_AUTO_FLOW
+                | ComDevice::MCR_LOOPBACK,
+        );
+        assert_eq!(com.rx_trigger_level(), 4);
+
+        for byte in 0..3 {
+            com.receive(byte).unwrap();
+        }
+        assert!(com.rts());
+        assert_ne!(
+            read_com(&mut com, SerialOffset::MSR) & ComDevice::MSR_CTS,
+            0
+        );
+
+        com.receive(3).unwrap();
+        assert!(!com.rts());
+        assert_eq!(
+            read_com(&mut com, SerialOffset::MSR) & ComDevice::MSR_CTS,
+            0
+        );
+
+        // Draining the FIFO below the trigger level asserts RTS again
+        assert_eq!(read_com(&mut com, SerialOffset::DATA), 0);
+        assert!(com.rts());
+
+        // Without auto flow control, RTS simply follows the MCR
+        write_com(&mut com, SerialOffset::MCR, ComDevice::MCR_RTS);
+        com.receive(4).unwrap();
+        assert!(com.rts());
+    }
+
+    #[test]
+    fn test_auto_cts() {
+        let mut com = ComDevice::new(0, 0x3f8);
+        write_com(
+            &mut com,
+            SerialOffset::MCR,
+            ComDevice::MCR_RTS | ComDevice::MCR_AUTO_FLOW,
+        );
+        write_com(&mut com, SerialOffset::DATA, b'a');
+        assert_eq!(com.buff, b"a");
+
+        // Transmission stops while CTS is deasserted
+        com.set_cts(false);
+        let msr = read_com(&mut com, SerialOffset::MSR);
+        assert_eq!(
+            msr & (ComDevice::MSR_CTS | ComDevice::MSR_DELTA_CTS),
+            ComDevice::MSR_DELTA_CTS
+        );
+        assert_eq!(
+            read_com(&mut com, SerialOffset::MSR) & ComDevice::MSR_DELTA_CTS,
+            0
+        );
+        write_com(&mut com, SerialOffset::DATA, b'b');
+        write_com(&mut com, SerialOffset::DATA, b'c');
+        assert_eq!(com.buff, b"a");
+        assert_eq!(
+            read_com(&mut com, SerialOffset::LSR) & ComDevice::LSR_THR_EMPTY,
+            0
+        );
+
+        // and resumes, in order, when it is asserted
+        com.set_cts(true);
+        assert_eq!(com.buff, b"abc");
+        assert_ne!(
+            read_com(&mut com, SerialOffset::LSR) & ComDevice::LSR_THR_EMPTY,
+            0
+        );
+
+        // CTS is ignored without auto flow control
+        write_com(&mut com, SerialOffset::MCR, ComDevice::MCR_RTS);
+        com.set_cts(false);
+        write_com(&mut com, SerialOffset::DATA, b'd');
+        assert_eq!(com.buff, b"abcd");
+    }
+
+    #[test]
+    fn test_scratch_register() {
+        let mut com = ComDevice::new(0, 0x3f8);
+        assert_eq!(read_com(&mut com, SerialOffset::SCR), 0);
+
+        // The usual presence test
+        write_com(&mut com, SerialOffset::SCR, 0x55);
+        assert_eq!(read_com(&mut com, SerialOffset::SCR), 0x55);
+        write_com(&mut com, SerialOffset::SCR, 0xaa);
+        assert_eq!(read_com(&mut com, SerialOffset::SCR), 0xaa);
+
+        // Other register accesses leave it unchanged (including with the
+        // divisor latch selected)
+        for val in [0x00, 0x5a, 0xff, 0x12].iter() {
+            write_com(&mut com, SerialOffset::SCR, *val);
+            read_com(&mut com, SerialOffset::LSR);
+            read_com(&mut com, SerialOffset::MSR);
+            read_com(&mut com, SerialOffset::IIR);
+            write_com(&mut com, SerialOffset::LCR, ComDevice::LCR_DLAB);
+            write_com(&mut com, SerialOffset::DLL, 0x01);
+            assert_eq!(read_com(&mut com, SerialOffset::SCR), *val);
+            write_com(&mut com, SerialOffset::LCR, 0x03);
+            write_com(&mut com, SerialOffset::DATA, b'x');
+            write_com(&mut com, SerialOffset::IER, 0);
+            assert_eq!(read_com(&mut com, SerialOffset::SCR), *val);
+            assert_eq!(com.read_reg(SerialOffset::SCR), *val as u32);
+        }
+    }
+
+    #[test]
+    fn test_register_dump() {
+        let mut com = ComDevice::new(0, 0x3f8);
+        write_com(&mut com, SerialOffset::LCR, 0x03);
+        write_com(&mut com, SerialOffset::MCR, 0x0b);
+
+        let dump = format!("{}", com.dump());
+        assert!(dump.contains("LCR (0x03) = 0x03\n"));
+        assert!(dump.contains("LSR (0x05) = 0x60\n"));
+        assert!(dump.contains("MCR (0x04) = 0x0b\n"));
+        assert_eq!(dump.lines().count(), com.registers().len());
+        assert!(format!("{:?}", com.dump()).contains("\"LCR\": 3"));
+
+        // Inspecting the IIR does not acknowledge the interrupt
+        write_com(&mut com, SerialOffset::IER, ComDevice::IER_THR_EMPTY);
+        assert!(dump.contains("IIR (0x02) = 0x01\n"));
+        assert!(format!("{}", com.dump()).contains("IIR (0x02) = 0x02\n"));
+        assert!(format!("{}", com.dump()).contains("IIR (0x02) = 0x02\n"));
+
+        com.write_reg(SerialOffset::LCR, 0xff, 0x04);
+        assert_eq!(com.read_reg(SerialOffset::LCR), 0x07);
+    }
+
+    #[test]
+    fn test_construction_validation() {
+        assert!(ComDevice::try_new(0, 0x3f8).is_ok());
+        assert!(ComDevice::try_new(0, 0xfff8).is_ok());
+        assert!(ComDevice::try_new(0, 0xfff9).is_err());
+
+        let mut com = ComDevice::new(0, 0x3f8);
+        assert!(com.set_irq_line(Some(16)).is_err());
+        assert_eq!(com.irq_lines(), vec![4]);
+        com.set_irq_line(Some(15)).unwrap();
+        assert_eq!(com.irq_lines(), vec![15]);
+    }
+
+    #[test]
+    fn test_describe() {
+        let com = ComDevice::new(0, 0x3f8);
+        let description = com.describe();
+        assert_eq!(description.name, "ComDevice");
+        assert_eq!(
+            description.regions,
+            vec![DeviceRegion::PortIo(0x3f8..=0x3ff)]
+        );
+        assert_eq!(description.irqs, vec![(4, TriggerMode::Edge)]);
+        assert!(description.msr_ranges.is_empty());
+
+        let mut polled = ComDevice::new(0, 0x3e8);
+        polled.set_irq_line(None).unwrap();
+        assert!(polled.describe().irqs.is_empty());
+    }
+
+    #[test]
+    fn test_timestamped_backend() {
+        let clock = Rc::new(FixedClock::new(1000));
+        let backend =
+            Rc::new(RefCell::new(TimestampedBackend::new(clock.clone(), 16)));
+        let mut com = ComDevice::new(0, 0x3f8);
+        com.set_backend(Box::new(backend.clone()));
+
+        for (i, byte) in b"abc".iter().enumerate() {
+            clock.advance(i as u64 * 500);
+            write_com(&mut com, SerialOffset::DATA, *byte);
+        }
+        assert!(com.buff.is_empty());
+
+        let records = backend.borrow_mut().drain();
+        assert_eq!(records, [(1000, b'a'), (1500, b'b'), (2500, b'c')]);
+        assert!(records.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        assert!(backend.borrow_mut().drain().is_empty());
+    }
+
+    #[test]
+    fn test_timestamped_backend_wraps() {
+        let clock = Rc::new(FixedClock::new(0));
+        let mut backend = TimestampedBackend::new(clock.clone(), 3);
+        for byte in 0..5 {
+            clock.set(byte as u64 * 10);
+            backend.transmit(byte);
+        }
+        assert_eq!(backend.drain(), [(20, 2), (30, 3), (40, 4)]);
     }
 }
@@ -0,0 +1,210 @@
+use alloc::collections::vec_deque::VecDeque;
+use alloc::vec::Vec;
+
+/// The phase of a `CommandProtocol`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommandPhase {
+    /// Waiting for a command byte
+    Command,
+
+    /// Collecting the parameter bytes of a command
+    Parameters,
+
+    /// Returning the result bytes of a command
+    Result,
+}
+
+/// A command whose parameter bytes have all been written, ready for the
+/// device to execute
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompleteCommand<C> {
+    pub command: C,
+
+    /// The command byte followed by the parameter bytes
+    pub bytes: Vec<u8>,
+}
+
+impl<C> CompleteCommand<C> {
+    pub fn opcode(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    pub fn parameters(&self) -> &[u8] {
+        &self.bytes[1..]
+    }
+}
+
+/// The command, parameter and result phases shared by devices that are
+/// driven through a single command port (e.g., the floppy controller)
+///
+/// The device writes each byte it receives with `write`, supplying a
+/// decoder that maps a command byte to its command and number of parameter
+/// bytes. Once the last parameter arrives, `write` returns the complete
+/// command for the device to execute, and the device queues any result
+/// bytes with `push_result`. The protocol returns to the command phase
+/// when the result has been read, or when a new command is written during
+/// the result phase (which abandons the rest of the result).
+#[derive(Debug)]
+pub struct CommandProtocol<C> {
+    pending: Option<(C, usize)>,
+    bytes: Vec<u8>,
+    result: VecDeque<u8>,
+}
+
+impl<C: Copy> CommandProtocol<C> {
+    pub fn new() -> Self {
+        Self {
+            pending: None,
+            bytes: vec![],
+            result: VecDeque::new(),
+        }
+    }
+
+    pub fn phase(&self) -> CommandPhase {
+        if !self.result.is_empty() {
+            CommandPhase::Result
+        } else if self.pending.is_some() {
+            CommandPhase::Parameters
+        } else {
+            CommandPhase::Command
+        }
+    }
+
+    /// Returns true while a command is in progress (between its command
+    /// byte and the last byte of its result)
+    pub fn is_busy(&self) -> bool {
+        self.phase() != CommandPhase::Command
+    }
+
+    /// Write a command or parameter byte, returning the command once all
+    /// of its parameters have been written
+    pub fn write(
+        &mut self,
+        byte: u8,
+        decode: impl FnOnce(u8) -> (C, usize),
+    ) -> Option<CompleteCommand<C>> {
+        if !self.result.is_empty() {
+            info!("Abandoning {} unread result bytes", self.result.len());
+            self.result.clear();
+        }
+
+        self.bytes.push(byte);
+        let (command, count) = match self.pending {
+            Some(pending) => pending,
+            None => decode(byte),
+        };
+        if self.bytes.len() <= count {
+            self.pending = Some((command, count));
+            return None;
+        }
+
+        self.pending = None;
+        Some(CompleteCommand {
+            command,
+            bytes: core::mem::replace(&mut self.bytes, vec![]),
+        })
+    }
+
+    /// Queue a result byte of the command that was just executed
+    pub fn push_result(&mut self, byte: u8) {
+        self.result.push_back(byte);
+    }
+
+    /// Read the next result byte, if there is one
+    pub fn read_result(&mut self) -> Option<u8> {
+        self.result.pop_front()
+    }
+
+    /// Abandon any command in progress and its result
+    pub fn reset(&mut self) {
+        self.pending = None;
+        self.bytes.clear();
+        self.result.clear();
+    }
+}
+
+impl<C: Copy> Default for CommandProtocol<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum ToyCommand {
+        Add,
+        Nop,
+    }
+
+    fn decode(opcode: u8) -> (ToyCommand, usize) {
+        match opcode {
+            0x01 => (ToyCommand::Add, 2),
+            _ => (ToyCommand::Nop, 0),
+        }
+    }
+
+    // Execute a toy command, as a device would
+    fn execute(
+        protocol: &mut CommandProtocol<ToyCommand>,
+        cmd: CompleteCommand<ToyCommand>,
+    ) {
+        if cmd.command == ToyCommand::Add {
+            let params = cmd.parameters();
+            protocol.push_result(params[0].wrapping_add(params[1]));
+        }
+    }
+
+    #[test]
+    fn test_command_phases() {
+        let mut protocol = CommandProtocol::new();
+        assert_eq!(protocol.phase(), CommandPhase::Command);
+        assert!(!protocol.is_busy());
+
+        assert_eq!(protocol.write(0x01, decode), None);
+        assert_eq!(protocol.phase(), CommandPhase::Parameters);
+        assert!(protocol.is_busy());
+        assert_eq!(protocol.write(2, decode), None);
+        assert!(protocol.is_busy());
+
+        let cmd = protocol.write(3, decode).unwrap();
+        assert_eq!(cmd.command, ToyCommand::Add);
+        assert_eq!(cmd.opcode(), 0x01);
+        assert_eq!(cmd.parameters(), [2, 3]);
+        execute(&mut protocol, cmd);
+        assert_eq!(protocol.phase(), CommandPhase::Result);
+        assert!(protocol.is_busy());
+
+        assert_eq!(protocol.read_result(), Some(5));
+        assert_eq!(protocol.phase(), CommandPhase::Command);
+        assert!(!protocol.is_busy());
+        assert_eq!(protocol.read_result(), None);
+
+        // A command without parameters or a result completes immediately
+        let cmd = protocol.write(0xff, decode).unwrap();
+        assert_eq!(cmd.command, ToyCommand::Nop);
+        assert!(cmd.parameters().is_empty());
+        assert!(!protocol.is_busy());
+    }
+
+    #[test]
+    fn test_command_abandons_result() {
+        let mut protocol = CommandProtocol::new();
+        for byte in [0x01, 0x10, 0x20].iter() {
+            if let Some(cmd) = protocol.write(*byte, decode) {
+                execute(&mut protocol, cmd);
+            }
+        }
+        assert_eq!(protocol.phase(), CommandPhase::Result);
+
+        // A new command discards the unread result
+        assert_eq!(protocol.write(0x01, decode), None);
+        assert_eq!(protocol.phase(), CommandPhase::Parameters);
+        assert_eq!(protocol.read_result(), None);
+
+        protocol.reset();
+        assert_eq!(protocol.phase(), CommandPhase::Command);
+    }
+}
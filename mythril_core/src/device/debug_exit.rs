@@ -0,0 +1,114 @@
+use crate::device::{
+    port_block, DeviceRegion, EmulatedDevice, Port, PortReadRequest,
+    PortWriteRequest,
+};
+use crate::error::Result;
+use crate::memory::GuestAddressSpaceViewMut;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+/// A port a test guest can write to in order to exit with a status code
+///
+/// This follows QEMU's `isa-debug-exit` device: writing a value `v` of
+/// any width to the port requests an exit with the code `(v << 1) | 1`
+/// (so a guest can never report a successful exit of zero). The
+/// supervisor polls for the request with `take_exit_code`, so the device
+/// should be registered as an `Rc<RefCell<DebugExitDevice>>` to keep a
+/// handle to it.
+pub struct DebugExitDevice {
+    ports: RangeInclusive<Port>,
+    exit_code: Option<u32>,
+}
+
+impl DebugExitDevice {
+    /// The conventional base port of the device
+    pub const DEFAULT_IOBASE: Port = 0x501;
+
+    /// The conventional number of ports the device occupies
+    pub const DEFAULT_IOSIZE: u16 = 2;
+
+    pub fn new(iobase: Port, iosize: u16) -> Result<Box<Self>> {
+        Ok(Box::new(Self {
+            ports: port_block(iobase, iosize)?,
+            exit_code: None,
+        }))
+    }
+
+    /// Returns the exit code requested by the guest, if there is one
+    pub fn take_exit_code(&mut self) -> Option<u32> {
+        self.exit_code.take()
+    }
+}
+
+impl EmulatedDevice for DebugExitDevice {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::PortIo(self.ports.clone())]
+    }
+
+    fn on_port_read(
+        &mut self,
+        _port: Port,
+        mut val: PortReadRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        val.fill_bytes(|| 0xff);
+        Ok(())
+    }
+
+    fn on_port_write(
+        &mut self,
+        _port: Port,
+        val: PortWriteRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let code = (val.as_u32() << 1) | 1;
+        info!("Guest requested an exit with code 0x{:x}", code);
+        self.exit_code = Some(code);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::define_test_view;
+    use core::convert::TryFrom;
+
+    fn write(dev: &mut DebugExitDevice, bytes: &[u8]) {
+        dev.on_port_write(
+            DebugExitDevice::DEFAULT_IOBASE,
+            PortWriteRequest::try_from(bytes).unwrap(),
+            define_test_view(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let mut dev = DebugExitDevice::new(
+            DebugExitDevice::DEFAULT_IOBASE,
+            DebugExitDevice::DEFAULT_IOSIZE,
+        )
+        .unwrap();
+        assert_eq!(dev.take_exit_code(), None);
+
+        write(&mut dev, &[0x10]);
+        assert_eq!(dev.take_exit_code(), Some(0x21));
+        assert_eq!(dev.take_exit_code(), None);
+
+        write(&mut dev, &0u8.to_be_bytes());
+        assert_eq!(dev.take_exit_code(), Some(1));
+
+        write(&mut dev, &0x1234u16.to_be_bytes());
+        assert_eq!(dev.take_exit_code(), Some(0x2469));
+
+        write(&mut dev, &0x4000_0001u32.to_be_bytes());
+        assert_eq!(dev.take_exit_code(), Some(0x8000_0003));
+    }
+
+    #[test]
+    fn test_invalid_port_block() {
+        assert!(DebugExitDevice::new(0xffff, 2).is_err());
+    }
+}
@@ -1,65 +1,636 @@
 use crate::device::{
-    DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
+    validate_dma_range, DeviceKind, DeviceRegion, EmulatedDevice, Port,
+    PortReadRequest, PortWriteRequest,
+};
+use crate::error::{Error, Result};
+use crate::memory::{
+    GuestAccess, GuestAddressSpaceViewMut, GuestPhysAddr, GuestVirtAddr,
+    PrivilegeLevel,
 };
-use crate::error::Result;
-use crate::memory::GuestAddressSpaceViewMut;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::ops::Range;
+
+const MODE_TRANSFER_MASK: u8 = 0b1100;
+const MODE_TRANSFER_VERIFY: u8 = 0b0000;
+const MODE_TRANSFER_WRITE: u8 = 0b0100;
+const MODE_TRANSFER_READ: u8 = 0b1000;
+const MODE_AUTO_INIT: u8 = 1 << 4;
+const MODE_DECREMENT: u8 = 1 << 5;
+
+// Controller registers, by index (the primary controller's port number or
+// the secondary controller's port offset divided by two)
+const REG_STATUS_COMMAND: u8 = 0x8;
+const REG_REQUEST: u8 = 0x9;
+const REG_SINGLE_MASK: u8 = 0xa;
+const REG_MODE: u8 = 0xb;
+const REG_CLEAR_FLIP_FLOP: u8 = 0xc;
+const REG_MASTER_CLEAR: u8 = 0xd;
+const REG_CLEAR_MASK: u8 = 0xe;
+const REG_WRITE_MASK: u8 = 0xf;
+
+// The page register for each channel, as an offset from `DMA_PAGE_BASE`.
+// This order is correct (2-3-1 for the primary controller).
+const PAGE_REGISTERS: [usize; 8] = [0x7, 0x3, 0x1, 0x2, 0xf, 0xb, 0x9, 0xa];
+
+/// The secondary controller's channel 0 (channel 4 overall) is used to
+/// cascade the primary controller, so it cannot perform transfers
+const CASCADE_CHANNEL: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+struct DmaChannel {
+    base_address: u16,
+    current_address: u16,
+    base_count: u16,
+    current_count: u16,
+    mode: u8,
+    masked: bool,
+}
+
+impl Default for DmaChannel {
+    fn default() -> Self {
+        Self {
+            base_address: 0,
+            current_address: 0,
+            base_count: 0,
+            current_count: 0,
+            mode: 0,
+            masked: true,
+        }
+    }
+}
 
 #[derive(Default, Debug)]
-pub struct Dma8237;
+struct DmaController {
+    channels: [DmaChannel; 4],
+    flip_flop: bool,
+    command: u8,
+    status: u8,
+    request: u8,
+}
+
+impl DmaController {
+    // Access the low byte and then the high byte of a 16 bit register,
+    // depending on the state of the flip-flop
+    fn next_byte_shift(&mut self) -> u16 {
+        let shift = if self.flip_flop { 8 } else { 0 };
+        self.flip_flop = !self.flip_flop;
+        shift
+    }
+
+    fn read_register(&mut self, reg: u8) -> u8 {
+        match reg {
+            0x0..=0x7 => {
+                let shift = self.next_byte_shift();
+                let channel = &self.channels[(reg / 2) as usize];
+                let val = if reg % 2 == 0 {
+                    channel.current_address
+                } else {
+                    channel.current_count
+                };
+                (val >> shift) as u8
+            }
+            REG_STATUS_COMMAND => {
+                // Reading the status clears the terminal count bits
+                let status = self.status;
+                self.status &= 0xf0;
+                status
+            }
+            REG_WRITE_MASK => self
+                .channels
+                .iter()
+                .enumerate()
+                .fold(0, |mask, (i, channel)| {
+                    mask | ((channel.masked as u8) << i)
+                }),
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, reg: u8, val: u8) {
+        match reg {
+            0x0..=0x7 => {
+                let shift = self.next_byte_shift();
+                let channel = &mut self.channels[(reg / 2) as usize];
+                let mask = !(0xffu16 << shift);
+                if reg % 2 == 0 {
+                    channel.base_address =
+                        (channel.base_address & mask) | (val as u16) << shift;
+                    channel.current_address = channel.base_address;
+                } else {
+                    channel.base_count =
+                        (channel.base_count & mask) | (val as u16) << shift;
+                    channel.current_count = channel.base_count;
+                }
+            }
+            REG_STATUS_COMMAND => self.command = val,
+            REG_REQUEST => {
+                let bit = 1 << (val & 0b11);
+                if val & 0b100 != 0 {
+                    self.request |= bit;
+                } else {
+                    self.request &= !bit;
+                }
+            }
+            REG_SINGLE_MASK => {
+                self.channels[(val & 0b11) as usize].masked = val & 0b100 != 0;
+            }
+            REG_MODE => self.channels[(val & 0b11) as usize].mode = val,
+            REG_CLEAR_FLIP_FLOP => self.flip_flop = false,
+            REG_MASTER_CLEAR => *self = Self::default(),
+            REG_CLEAR_MASK => {
+                for channel in self.channels.iter_mut() {
+                    channel.masked = false;
+                }
+            }
+            REG_WRITE_MASK => {
+                for (i, channel) in self.channels.iter_mut().enumerate() {
+                    channel.masked = val & (1 << i) != 0;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// An emulated pair of 8237 DMA controllers
+///
+/// The primary controller provides the 8-bit channels 0-3 and the
+/// secondary controller provides the 16-bit channels 4-7 (with channel 4
+/// used to cascade the primary controller).
+#[derive(Default, Debug)]
+pub struct Dma8237 {
+    controllers: [DmaController; 2],
+    pages: [u8; 16],
+}
 
-#[allow(dead_code)]
 impl Dma8237 {
-    const DMA1_CHAN2_ADDR: Port = 0x0004;
-    const DMA1_CHAN2_COUNT: Port = 0x0005;
-    const DMA1_MASK: Port = 0x000a;
-    const DMA1_MODE: Port = 0x000b;
-    const DMA1_CLEAR_FF: Port = 0x000c;
-    const DMA1_MASTER_CLEAR: Port = 0x000d;
-
-    // This order is correct. 2-3-1
-    const DMA_CHAN2_PAGE_CHECK: Port = 0x0081;
-    const DMA_CHAN3_PAGE_CHECK: Port = 0x0082;
-    const DMA_CHAN1_PAGE_CHECK: Port = 0x0083;
-
-    const DMA2_MASK: Port = 0x00d4;
-    const DMA2_MODE: Port = 0x00d6;
-    const DMA2_MASTER_CLEAR: Port = 0x00da;
+    const DMA1_BASE: Port = 0x0000;
+    const DMA1_MAX: Port = 0x000f;
+    const DMA_PAGE_BASE: Port = 0x0080;
+    const DMA_PAGE_MIN: Port = 0x0081;
+    const DMA_PAGE_MAX: Port = 0x008f;
+    const DMA2_BASE: Port = 0x00c0;
+    const DMA2_MAX: Port = 0x00df;
 
     pub fn new() -> Box<Self> {
         Box::new(Dma8237::default())
     }
+
+    fn check_channel(channel: usize) -> Result<()> {
+        if channel >= 8 {
+            Err(Error::InvalidValue(format!(
+                "Invalid DMA channel: {}",
+                channel
+            )))
+        } else if channel == CASCADE_CHANNEL {
+            Err(Error::InvalidValue(
+                "DMA channel 4 is reserved for cascading".into(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn channel(&self, channel: usize) -> &DmaChannel {
+        &self.controllers[channel / 4].channels[channel % 4]
+    }
+
+    fn channel_mut(&mut self, channel: usize) -> &mut DmaChannel {
+        &mut self.controllers[channel / 4].channels[channel % 4]
+    }
+
+    /// The width (in bytes) of a single transfer on the given channel
+    pub fn transfer_width(channel: usize) -> usize {
+        if channel < 4 {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// The guest physical address the next transfer on `channel` will access
+    ///
+    /// The 16-bit channels address words, so the current address is shifted
+    /// by one and the low bit of the page register is ignored.
+    pub fn physical_address(&self, channel: usize) -> Result<GuestPhysAddr> {
+        Self::check_channel(channel)?;
+        Ok(self.address_for(channel, self.channel(channel).current_address))
+    }
+
+    fn address_for(&self, channel: usize, address: u16) -> GuestPhysAddr {
+        let page = self.pages[PAGE_REGISTERS[channel]] as u64;
+        let address = address as u64;
+        let addr = if channel < 4 {
+            (page << 16) | address
+        } else {
+            ((page & 0xfe) << 16) | (address << 1)
+        };
+        GuestPhysAddr::new(addr)
+    }
+
+    /// The number of bytes remaining before the terminal count of `channel`
+    pub fn remaining(&self, channel: usize) -> Result<usize> {
+        Self::check_channel(channel)?;
+        let count = self.channel(channel).current_count as usize + 1;
+        Ok(count * Self::transfer_width(channel))
+    }
+
+    // Returns false if `channel` is masked (so no transfer will happen), or
+    // an error if the channel is not programmed for `transfer_type`
+    fn check_transfer(
+        &self,
+        channel: usize,
+        transfer_type: u8,
+    ) -> Result<bool> {
+        Self::check_channel(channel)?;
+        let chan = self.channel(channel);
+        if chan.masked {
+            return Ok(false);
+        }
+        match chan.mode & MODE_TRANSFER_MASK {
+            MODE_TRANSFER_VERIFY => Ok(true),
+            ty if ty == transfer_type => Ok(true),
+            _ => Err(Error::InvalidValue(format!(
+                "DMA channel {} mode (0x{:x}) does not match transfer",
+                channel, chan.mode
+            ))),
+        }
+    }
+
+    // The guest memory that will be accessed by a transfer of up to `len`
+    // bytes on `channel`, as a list of (address, length) runs
+    fn pending_ranges(
+        &self,
+        channel: usize,
+        transfer_type: u8,
+        len: usize,
+    ) -> Result<Vec<(GuestPhysAddr, usize)>> {
+        let mut ranges: Vec<(GuestPhysAddr, usize)> = vec![];
+        if !self.check_transfer(channel, transfer_type)? {
+            return Ok(ranges);
+        }
+        let chan = self.channel(channel);
+        if chan.mode & MODE_TRANSFER_MASK == MODE_TRANSFER_VERIFY {
+            return Ok(ranges);
+        }
+
+        let width = Self::transfer_width(channel);
+        let units =
+            core::cmp::min(len / width, self.remaining(channel)? / width);
+        let mut address = chan.current_address;
+        for _ in 0..units {
+            let addr = self.address_for(channel, address);
+            match ranges.last_mut() {
+                Some((start, len)) if *start + *len == addr => *len += width,
+                Some((start, len)) if addr + width == *start => {
+                    *start = addr;
+                    *len += width;
+                }
+                _ => ranges.push((addr, width)),
+            }
+            address = if chan.mode & MODE_DECREMENT != 0 {
+                address.wrapping_sub(1)
+            } else {
+                address.wrapping_add(1)
+            };
+        }
+        Ok(ranges)
+    }
+
+    // Perform transfers on `channel` until either `len` bytes have been
+    // moved, or the channel reaches its terminal count. `copy` is called
+    // with the guest address and the range of the device buffer for each
+    // transfer.
+    fn transfer<F>(
+        &mut self,
+        channel: usize,
+        transfer_type: u8,
+        len: usize,
+        mut copy: F,
+    ) -> Result<usize>
+    where
+        F: FnMut(GuestPhysAddr, Range<usize>) -> Result<()>,
+    {
+        if !self.check_transfer(channel, transfer_type)? {
+            return Ok(0);
+        }
+        let mode = self.channel(channel).mode;
+
+        let width = Self::transfer_width(channel);
+        let mut offset = 0;
+        while offset + width <= len {
+            if mode & MODE_TRANSFER_MASK != MODE_TRANSFER_VERIFY {
+                copy(self.physical_address(channel)?, offset..offset + width)?;
+            }
+            offset += width;
+
+            let chan = self.channel_mut(channel);
+            chan.current_address = if mode & MODE_DECREMENT != 0 {
+                chan.current_address.wrapping_sub(1)
+            } else {
+                chan.current_address.wrapping_add(1)
+            };
+
+            let (count, terminal) = chan.current_count.overflowing_sub(1);
+            chan.current_count = count;
+            if terminal {
+                if mode & MODE_AUTO_INIT != 0 {
+                    chan.current_address = chan.base_address;
+                    chan.current_count = chan.base_count;
+                } else {
+                    chan.masked = true;
+                }
+                self.controllers[channel / 4].status |= 1 << (channel % 4);
+                break;
+            }
+        }
+        Ok(offset)
+    }
+
+    /// Transfer `data` from a device to guest memory using `channel`
+    ///
+    /// Returns the number of bytes transferred, which may be less than the
+    /// length of `data` if the channel reaches its terminal count.
+    pub fn write_to_guest(
+        &mut self,
+        channel: usize,
+        data: &[u8],
+        space: &mut GuestAddressSpaceViewMut,
+    ) -> Result<usize> {
+        let access = GuestAccess::Write(PrivilegeLevel(0));
+        for (addr, len) in
+            self.pending_ranges(channel, MODE_TRANSFER_WRITE, data.len())?
+        {
+            validate_dma_range(space, addr, len, access)?;
+        }
+
+        self.transfer(
+            channel,
+            MODE_TRANSFER_WRITE,
+            data.len(),
+            |addr, range| {
+                space.write_bytes(
+                    GuestVirtAddr::NoPaging(addr),
+                    &data[range],
+                    GuestAccess::Write(PrivilegeLevel(0)),
+                )
+            },
+        )
+    }
+
+    /// Transfer bytes from guest memory to a device buffer using `channel`
+    ///
+    /// Returns the number of bytes transferred, which may be less than the
+    /// length of `data` if the channel reaches its terminal count.
+    pub fn read_from_guest(
+        &mut self,
+        channel: usize,
+        data: &mut [u8],
+        space: &GuestAddressSpaceViewMut,
+    ) -> Result<usize> {
+        let len = data.len();
+        let access = GuestAccess::Read(PrivilegeLevel(0));
+        for (addr, len) in
+            self.pending_ranges(channel, MODE_TRANSFER_READ, len)?
+        {
+            validate_dma_range(space, addr, len, access)?;
+        }
+
+        self.transfer(channel, MODE_TRANSFER_READ, len, |addr, range| {
+            let bytes = space.read_bytes(
+                GuestVirtAddr::NoPaging(addr),
+                range.len(),
+                GuestAccess::Read(PrivilegeLevel(0)),
+            )?;
+            data[range].copy_from_slice(&bytes);
+            Ok(())
+        })
+    }
+
+    // Map a port to the controller and register index it accesses
+    fn register_for(port: Port) -> Option<(usize, u8)> {
+        match port {
+            Self::DMA1_BASE..=Self::DMA1_MAX => {
+                Some((0, (port - Self::DMA1_BASE) as u8))
+            }
+            Self::DMA2_BASE..=Self::DMA2_MAX if port % 2 == 0 => {
+                Some((1, ((port - Self::DMA2_BASE) / 2) as u8))
+            }
+            _ => None,
+        }
+    }
 }
 
 impl EmulatedDevice for Dma8237 {
     fn services(&self) -> Vec<DeviceRegion> {
         vec![
-            DeviceRegion::PortIo(
-                Self::DMA1_CHAN2_ADDR..=Self::DMA1_MASTER_CLEAR,
-            ),
-            DeviceRegion::PortIo(
-                Self::DMA_CHAN2_PAGE_CHECK..=Self::DMA_CHAN1_PAGE_CHECK,
-            ),
-            DeviceRegion::PortIo(Self::DMA2_MASK..=Self::DMA2_MASTER_CLEAR),
+            DeviceRegion::PortIo(Self::DMA1_BASE..=Self::DMA1_MAX),
+            DeviceRegion::PortIo(Self::DMA_PAGE_MIN..=Self::DMA_PAGE_MAX),
+            DeviceRegion::PortIo(Self::DMA2_BASE..=Self::DMA2_MAX),
         ]
     }
 
+    fn kind(&self) -> Option<DeviceKind> {
+        Some(DeviceKind::Dma)
+    }
+
+    fn reset(&mut self) {
+        *self = Self::default();
+    }
+
     fn on_port_read(
         &mut self,
-        _port: Port,
-        _val: PortReadRequest,
+        port: Port,
+        mut val: PortReadRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
+        let res = match port {
+            Self::DMA_PAGE_MIN..=Self::DMA_PAGE_MAX => {
+                self.pages[(port - Self::DMA_PAGE_BASE) as usize]
+            }
+            _ => match Self::register_for(port) {
+                Some((controller, reg)) => {
+                    self.controllers[controller].read_register(reg)
+                }
+                None => 0,
+            },
+        };
+        val.copy_from_u32(res as u32);
         Ok(())
     }
 
     fn on_port_write(
         &mut self,
-        _port: Port,
-        _val: PortWriteRequest,
+        port: Port,
+        val: PortWriteRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
+        let val: u8 = val.try_into()?;
+        match port {
+            Self::DMA_PAGE_MIN..=Self::DMA_PAGE_MAX => {
+                self.pages[(port - Self::DMA_PAGE_BASE) as usize] = val;
+            }
+            _ => {
+                if let Some((controller, reg)) = Self::register_for(port) {
+                    self.controllers[controller].write_register(reg, val);
+                }
+            }
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::define_test_view;
+    use crate::memory::GuestAddressSpace;
+
+    // A view with guest memory mapped at 0x20000-0x23fff
+    fn define_test_ram_view() -> GuestAddressSpaceViewMut<'static> {
+        let space: &'static mut GuestAddressSpace =
+            Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
+        for i in 0..4 {
+            space
+                .map_new_frame(GuestPhysAddr::new(0x20000 + i * 4096), false)
+                .unwrap();
+        }
+        GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space)
+    }
+
+    fn outb(dma: &mut Dma8237, port: Port, val: u8) {
+        let arr = [val];
+        let request = PortWriteRequest::OneByte(&arr);
+        dma.on_port_write(port, request, define_test_view())
+            .unwrap();
+    }
+
+    fn inb(dma: &mut Dma8237, port: Port) -> u8 {
+        let mut arr = [0u8];
+        let request = PortReadRequest::OneByte(&mut arr);
+        dma.on_port_read(port, request, define_test_view()).unwrap();
+        arr[0]
+    }
+
+    // Program channel 5 (the first 16-bit channel) for a write of `words`
+    // words to 0x20100
+    fn program_channel5(dma: &mut Dma8237, words: u16) {
+        outb(dma, 0xd4, 0b101); // mask channel 5
+        outb(dma, 0xd8, 0); // clear flip-flop
+        outb(dma, 0xd6, 0b0100_0101); // single mode, write, channel 5
+
+        // Word address 0x10080 = byte address 0x20100
+        outb(dma, 0x8b, 0x02);
+        outb(dma, 0xc4, 0x80);
+        outb(dma, 0xc4, 0x00);
+
+        let count = words - 1;
+        outb(dma, 0xc6, count as u8);
+        outb(dma, 0xc6, (count >> 8) as u8);
+        outb(dma, 0xd4, 0b001); // unmask channel 5
+    }
+
+    #[test]
+    fn test_8bit_physical_address() {
+        let mut dma = Dma8237::new();
+        outb(&mut dma, 0x0c, 0);
+        outb(&mut dma, 0x04, 0x34);
+        outb(&mut dma, 0x04, 0x12);
+        outb(&mut dma, 0x81, 0x05);
+        assert_eq!(dma.physical_address(2).unwrap().as_u64(), 0x51234);
+    }
+
+    #[test]
+    fn test_16bit_physical_address() {
+        let mut dma = Dma8237::new();
+        program_channel5(&mut dma, 4);
+
+        assert_eq!(dma.physical_address(5).unwrap().as_u64(), 0x20100);
+        assert_eq!(dma.remaining(5).unwrap(), 8);
+
+        // The low bit of the page is ignored for 16 bit channels
+        outb(&mut dma, 0x8b, 0x03);
+        assert_eq!(dma.physical_address(5).unwrap().as_u64(), 0x20100);
+    }
+
+    #[test]
+    fn test_address_readback() {
+        let mut dma = Dma8237::new();
+        program_channel5(&mut dma, 4);
+        outb(&mut dma, 0xd8, 0);
+        assert_eq!(inb(&mut dma, 0xc4), 0x80);
+        assert_eq!(inb(&mut dma, 0xc4), 0x00);
+        assert_eq!(inb(&mut dma, 0xc6), 0x03);
+        assert_eq!(inb(&mut dma, 0xc6), 0x00);
+    }
+
+    #[test]
+    fn test_16bit_transfer() {
+        let mut dma = Dma8237::new();
+        let mut space = define_test_ram_view();
+        program_channel5(&mut dma, 4);
+
+        let data = [1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        let moved = dma.write_to_guest(5, &data, &mut space).unwrap();
+        assert_eq!(moved, 8);
+
+        let bytes = space
+            .read_bytes(
+                GuestVirtAddr::NoPaging(GuestPhysAddr::new(0x20100)),
+                10,
+                GuestAccess::Read(PrivilegeLevel(0)),
+            )
+            .unwrap();
+        assert_eq!(bytes, [1, 2, 3, 4, 5, 6, 7, 8, 0, 0]);
+
+        // The channel reached terminal count
+        assert_eq!(inb(&mut dma, 0xd0) & 0b0010, 0b0010);
+        assert_eq!(inb(&mut dma, 0xd0) & 0b0010, 0);
+        assert_eq!(dma.write_to_guest(5, &data, &mut space).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_cascade_channel_reserved() {
+        let mut dma = Dma8237::new();
+        let mut space = define_test_view();
+        assert!(dma.physical_address(4).is_err());
+        assert!(dma.write_to_guest(4, &[0, 0], &mut space).is_err());
+    }
+
+    #[test]
+    fn test_transfer_to_unmapped_range() {
+        let mut dma = Dma8237::new();
+        let mut space = define_test_ram_view();
+
+        // 0x20100 + 16KiB extends past the end of the mapped memory
+        program_channel5(&mut dma, 0x2000);
+        let data = vec![0xffu8; 0x4000];
+        assert_eq!(
+            dma.write_to_guest(5, &data, &mut space),
+            Err(Error::InvalidDmaRange {
+                addr: GuestPhysAddr::new(0x20100),
+                len: 0x4000
+            })
+        );
+
+        // Nothing was transferred
+        assert_eq!(dma.remaining(5).unwrap(), 0x4000);
+        assert_eq!(dma.physical_address(5).unwrap().as_u64(), 0x20100);
+        let bytes = space
+            .read_bytes(
+                GuestVirtAddr::NoPaging(GuestPhysAddr::new(0x20100)),
+                4,
+                GuestAccess::Read(PrivilegeLevel(0)),
+            )
+            .unwrap();
+        assert_eq!(bytes, [0, 0, 0, 0]);
+
+        // A transfer that stays within the mapped memory is fine
+        assert_eq!(
+            dma.write_to_guest(5, &data[..0x100], &mut space),
+            Ok(0x100)
+        );
+    }
+}
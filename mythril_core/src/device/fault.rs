@@ -0,0 +1,374 @@
+use crate::device::interrupt::PendingInterrupt;
+use crate::device::{
+    AccessKind, DeviceDescription, DeviceKind, DeviceRegion, EmulatedDevice,
+    MemReadRequest, MemWriteRequest, Port, PortReadRequest, PortWriteRequest,
+    RegionAccess, RegionDelta,
+};
+use crate::error::{Error, Result};
+use crate::ioapic::TriggerMode;
+use crate::memory::{GuestAddressSpaceViewMut, GuestPhysAddr};
+use crate::time::FixedClock;
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+/// The fault injected into a matching access
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// Advance the (virtual) platform clock by this many nanoseconds
+    /// before the access is handled
+    Delay(u64),
+
+    /// Fail the access with `Error::InjectedFault` without handling it
+    Error,
+}
+
+/// Which of the matching accesses a fault is injected into
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultPattern {
+    /// Every matching access
+    Always,
+
+    /// Every `n`th matching access (the `n`th, `2n`th, etc)
+    EveryNth(u64),
+
+    /// Each matching access with a probability of `percent`, chosen by a
+    /// generator seeded with `seed` (so the accesses chosen are the same
+    /// on every run)
+    Random { percent: u8, seed: u64 },
+}
+
+/// A fault to inject into the accesses of a region
+#[derive(Clone, Debug, PartialEq)]
+pub struct FaultRule {
+    pub region: DeviceRegion,
+
+    /// The directions of access the rule applies to
+    pub access: RegionAccess,
+    pub fault: Fault,
+    pub pattern: FaultPattern,
+}
+
+struct RuleState {
+    rule: FaultRule,
+    matched: u64,
+    rng: u64,
+}
+
+impl RuleState {
+    fn matches(&self, kind: AccessKind, addr: u64) -> bool {
+        if !self.rule.access.allows(kind) {
+            return false;
+        }
+        match (&self.rule.region, kind) {
+            (DeviceRegion::PortIo(range), AccessKind::PortRead)
+            | (DeviceRegion::PortIo(range), AccessKind::PortWrite) => {
+                range.contains(&(addr as Port))
+            }
+            (DeviceRegion::MemIo(range), AccessKind::MemRead)
+            | (DeviceRegion::MemIo(range), AccessKind::MemWrite) => {
+                range.contains(&GuestPhysAddr::new(addr))
+            }
+            _ => false,
+        }
+    }
+
+    // Record a matching access, returning true if it should be faulted
+    fn trigger(&mut self) -> bool {
+        self.matched += 1;
+        match self.rule.pattern {
+            FaultPattern::Always => true,
+            FaultPattern::EveryNth(n) => n != 0 && self.matched % n == 0,
+            FaultPattern::Random { percent, .. } => {
+                // xorshift64
+                self.rng ^= self.rng << 13;
+                self.rng ^= self.rng >> 7;
+                self.rng ^= self.rng << 17;
+                self.rng % 100 < percent as u64
+            }
+        }
+    }
+}
+
+/// A wrapper that injects delays and errors into the accesses of a device,
+/// for testing how a guest handles slow or failing hardware
+///
+/// Faults are injected before the wrapped device sees the access, so a
+/// failed access has no effect on the device.
+pub struct FaultInjector {
+    inner: Box<dyn EmulatedDevice>,
+    clock: Option<Rc<FixedClock>>,
+    rules: Vec<RuleState>,
+}
+
+impl FaultInjector {
+    pub fn new(inner: Box<dyn EmulatedDevice>) -> Box<Self> {
+        Box::new(Self {
+            inner,
+            clock: None,
+            rules: vec![],
+        })
+    }
+
+    /// Set the platform clock that is advanced by `Fault::Delay`
+    pub fn set_clock(&mut self, clock: Rc<FixedClock>) {
+        self.clock = Some(clock);
+    }
+
+    /// Inject a fault into the accesses matched by `rule`
+    ///
+    /// Fails if the rule is a delay and no clock has been set.
+    pub fn add_rule(&mut self, rule: FaultRule) -> Result<()> {
+        if let (Fault::Delay(_), None) = (rule.fault, &self.clock) {
+            return Err(Error::InvalidValue(
+                "A delay fault requires a clock".into(),
+            ));
+        }
+        let seed = match rule.pattern {
+            FaultPattern::Random { seed, .. } => seed,
+            _ => 0,
+        };
+        self.rules.push(RuleState {
+            rule,
+            matched: 0,
+            // xorshift never leaves (or reaches) zero
+            rng: seed.max(1),
+        });
+        Ok(())
+    }
+
+    fn inject(&mut self, kind: AccessKind, addr: u64) -> Result<()> {
+        for state in self.rules.iter_mut() {
+            if !state.matches(kind, addr) || !state.trigger() {
+                continue;
+            }
+            match state.rule.fault {
+                Fault::Delay(ns) => {
+                    if let Some(clock) = &self.clock {
+                        clock.advance(ns);
+                    }
+                }
+                Fault::Error => {
+                    return Err(Error::InjectedFault { kind, addr });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl EmulatedDevice for FaultInjector {
+    fn services(&self) -> Vec<DeviceRegion> {
+        self.inner.services()
+    }
+
+    fn debug_name(&self) -> &'static str {
+        self.inner.debug_name()
+    }
+
+    fn kind(&self) -> Option<DeviceKind> {
+        self.inner.kind()
+    }
+
+    fn depends_on(&self) -> Vec<DeviceKind> {
+        self.inner.depends_on()
+    }
+
+    fn irq_lines(&self) -> Vec<u8> {
+        self.inner.irq_lines()
+    }
+
+    fn irq_trigger_mode(&self, irq: u8) -> TriggerMode {
+        self.inner.irq_trigger_mode(irq)
+    }
+
+    fn region_changed(&self) -> Option<RegionDelta> {
+        self.inner.region_changed()
+    }
+
+    fn region_access(&self, region: &DeviceRegion) -> RegionAccess {
+        self.inner.region_access(region)
+    }
+
+    fn msr_ranges(&self) -> Vec<RangeInclusive<u32>> {
+        self.inner.msr_ranges()
+    }
+
+    fn describe(&self) -> DeviceDescription {
+        self.inner.describe()
+    }
+
+    fn acknowledge_interrupt(&mut self) -> Option<PendingInterrupt> {
+        self.inner.acknowledge_interrupt()
+    }
+
+    fn on_msr_read(&mut self, msr: u32) -> Result<u64> {
+        self.inner.on_msr_read(msr)
+    }
+
+    fn on_msr_write(&mut self, msr: u32, val: u64) -> Result<()> {
+        self.inner.on_msr_write(msr, val)
+    }
+
+    fn poll(&mut self, now: u64) {
+        self.inner.poll(now)
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset()
+    }
+
+    fn on_mem_read(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemReadRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.inject(AccessKind::MemRead, addr.as_u64())?;
+        self.inner.on_mem_read(addr, data, space)
+    }
+
+    fn on_mem_write(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemWriteRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.inject(AccessKind::MemWrite, addr.as_u64())?;
+        self.inner.on_mem_write(addr, data, space)
+    }
+
+    fn on_port_read(
+        &mut self,
+        port: Port,
+        val: PortReadRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.inject(AccessKind::PortRead, port as u64)?;
+        self.inner.on_port_read(port, val, space)
+    }
+
+    fn on_port_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.inject(AccessKind::PortWrite, port as u64)?;
+        self.inner.on_port_write(port, val, space)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::com::ComDevice;
+    use crate::device::test_util::define_test_view;
+    use crate::device::DeviceMap;
+    use crate::memory::GuestAddressSpace;
+    use crate::time::ClockSource;
+
+    fn com_rule(
+        access: RegionAccess,
+        fault: Fault,
+        pattern: FaultPattern,
+    ) -> FaultRule {
+        FaultRule {
+            region: DeviceRegion::PortIo(0x3f8..=0x3f8),
+            access,
+            fault,
+            pattern,
+        }
+    }
+
+    fn read_lsr(map: &mut DeviceMap) -> Result<()> {
+        let mut arr = [0u8];
+        map.on_port_read(
+            0x3fd,
+            PortReadRequest::OneByte(&mut arr),
+            define_test_view(),
+        )
+    }
+
+    fn write_thr(map: &mut DeviceMap) -> Result<()> {
+        map.on_port_write(
+            0x3f8,
+            PortWriteRequest::OneByte(&[b'a']),
+            define_test_view(),
+        )
+    }
+
+    #[test]
+    fn test_every_nth_error() {
+        let mut com = FaultInjector::new(ComDevice::new(0, 0x3f8));
+        com.add_rule(com_rule(
+            RegionAccess::Write,
+            Fault::Error,
+            FaultPattern::EveryNth(3),
+        ))
+        .unwrap();
+        let mut map = DeviceMap::default();
+        map.register_device(com).unwrap();
+
+        let results: Vec<bool> =
+            (0..7).map(|_| write_thr(&mut map).is_ok()).collect();
+        assert_eq!(results, [true, true, false, true, true, false, true]);
+        assert_eq!(
+            write_thr(&mut map).and_then(|_| write_thr(&mut map)),
+            Err(Error::InjectedFault {
+                kind: AccessKind::PortWrite,
+                addr: 0x3f8,
+            })
+        );
+
+        // Other registers (and reads of the faulted one) are unaffected
+        assert!((0..10).all(|_| read_lsr(&mut map).is_ok()));
+    }
+
+    #[test]
+    fn test_random_errors_are_reproducible() {
+        let run = || -> Vec<bool> {
+            let mut com = FaultInjector::new(ComDevice::new(0, 0x3f8));
+            com.add_rule(com_rule(
+                RegionAccess::ReadWrite,
+                Fault::Error,
+                FaultPattern::Random {
+                    percent: 50,
+                    seed: 0x1234,
+                },
+            ))
+            .unwrap();
+            let mut map = DeviceMap::default();
+            map.register_device(com).unwrap();
+            (0..64).map(|_| write_thr(&mut map).is_ok()).collect()
+        };
+
+        let first = run();
+        assert_eq!(first, run());
+        assert!(first.iter().any(|ok| *ok));
+        assert!(first.iter().any(|ok| !*ok));
+    }
+
+    #[test]
+    fn test_delay() {
+        let clock = Rc::new(FixedClock::new(0));
+        let mut com = FaultInjector::new(ComDevice::new(0, 0x3f8));
+        let rule = com_rule(
+            RegionAccess::Write,
+            Fault::Delay(1000),
+            FaultPattern::Always,
+        );
+        assert!(com.add_rule(rule.clone()).is_err());
+
+        com.set_clock(clock.clone());
+        com.add_rule(rule).unwrap();
+        let mut map = DeviceMap::default();
+        map.register_device(com).unwrap();
+
+        write_thr(&mut map).unwrap();
+        write_thr(&mut map).unwrap();
+        read_lsr(&mut map).unwrap();
+        assert_eq!(clock.now_ns(), 2000);
+    }
+}
@@ -0,0 +1,125 @@
+use crate::error::{Error, Result};
+use alloc::collections::vec_deque::VecDeque;
+
+/// A bounded first-in first-out queue for device buffers (e.g., a UART
+/// receive FIFO or the keyboard controller output buffer)
+///
+/// A push to a full FIFO is an overrun: the new item is discarded (the
+/// items already queued are kept, as on real hardware), the overrun is
+/// counted and `Error::FifoOverrun` is returned so the device can report
+/// it to the guest.
+#[derive(Clone, Debug)]
+pub struct DeviceFifo<T> {
+    items: VecDeque<T>,
+    capacity: usize,
+    overruns: u64,
+}
+
+impl<T> DeviceFifo<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            items: VecDeque::with_capacity(capacity),
+            capacity,
+            overruns: 0,
+        }
+    }
+
+    /// Add an item to the back of the FIFO, failing if it is full
+    pub fn push(&mut self, item: T) -> Result<()> {
+        if self.is_full() {
+            self.overruns += 1;
+            return Err(Error::FifoOverrun {
+                capacity: self.capacity,
+            });
+        }
+        self.items.push_back(item);
+        Ok(())
+    }
+
+    /// Remove the item at the front of the FIFO
+    pub fn pop(&mut self) -> Option<T> {
+        self.items.pop_front()
+    }
+
+    /// The item at the front of the FIFO, without removing it
+    pub fn peek(&self) -> Option<&T> {
+        self.items.front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.items.len() >= self.capacity
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Discard every queued item (the overrun count is kept)
+    pub fn clear(&mut self) {
+        self.items.clear()
+    }
+
+    /// The number of items discarded because the FIFO was full
+    pub fn overruns(&self) -> u64 {
+        self.overruns
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_overrun_at_capacity() {
+        let mut fifo = DeviceFifo::new(4);
+        for i in 0..4u8 {
+            fifo.push(i).unwrap();
+        }
+        assert!(fifo.is_full());
+        assert_eq!(fifo.push(4), Err(Error::FifoOverrun { capacity: 4 }));
+        assert_eq!(fifo.push(5), Err(Error::FifoOverrun { capacity: 4 }));
+        assert_eq!(fifo.overruns(), 2);
+
+        // The overrunning items were dropped, not the queued ones
+        assert_eq!(fifo.len(), 4);
+        assert_eq!(fifo.pop(), Some(0));
+        fifo.push(6).unwrap();
+        assert_eq!(fifo.len(), 4);
+    }
+
+    #[test]
+    fn test_fifo_order() {
+        let mut fifo = DeviceFifo::new(16);
+        assert_eq!(fifo.pop(), None);
+        for i in 0..10u8 {
+            fifo.push(i).unwrap();
+        }
+        assert_eq!(fifo.peek(), Some(&0));
+        let items: Vec<u8> = core::iter::from_fn(|| fifo.pop()).collect();
+        assert_eq!(items, (0..10).collect::<Vec<u8>>());
+        assert!(fifo.is_empty());
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut fifo = DeviceFifo::new(2);
+        fifo.push('a').unwrap();
+        fifo.push('b').unwrap();
+        assert!(fifo.push('c').is_err());
+        fifo.clear();
+        assert!(fifo.is_empty());
+        assert_eq!(fifo.capacity(), 2);
+        assert_eq!(fifo.overruns(), 1);
+        fifo.push('d').unwrap();
+        assert_eq!(fifo.pop(), Some('d'));
+    }
+}
@@ -0,0 +1,594 @@
+use crate::device::command::{CommandPhase, CommandProtocol};
+use crate::device::dma::Dma8237;
+use crate::device::interrupt::IrqSink;
+use crate::device::{
+    DeviceKind, DeviceRegion, EmulatedDevice, Port, PortReadRequest,
+    PortWriteRequest,
+};
+use crate::error::{Error, Result};
+use crate::memory::GuestAddressSpaceViewMut;
+use alloc::boxed::Box;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::convert::TryInto;
+
+const SECTOR_SIZE: usize = 512;
+const FLOPPY_IRQ: u8 = 6;
+const FLOPPY_DMA_CHANNEL: usize = 2;
+
+const DOR_DRIVE_SELECT: u8 = 0b11;
+const DOR_NOT_RESET: u8 = 1 << 2;
+const DOR_IRQ_DMA: u8 = 1 << 3;
+
+const MSR_BUSY: u8 = 1 << 4;
+const MSR_DIO: u8 = 1 << 6;
+const MSR_RQM: u8 = 1 << 7;
+
+const DSR_RESET: u8 = 1 << 7;
+
+const ST0_ABNORMAL: u8 = 0x40;
+const ST0_INVALID: u8 = 0x80;
+const ST0_RESET: u8 = 0xc0;
+const ST0_SEEK_END: u8 = 0x20;
+const ST1_NO_DATA: u8 = 0x04;
+const ST1_END_OF_CYLINDER: u8 = 0x80;
+const ST3_READY: u8 = 0x20;
+const ST3_TRACK0: u8 = 0x10;
+const ST3_TWO_SIDE: u8 = 0x08;
+
+const CMD_MULTI_TRACK: u8 = 0x80;
+const CMD_MASK: u8 = 0x1f;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Command {
+    Specify,
+    SenseDriveStatus,
+    WriteData,
+    ReadData,
+    Recalibrate,
+    SenseInterrupt,
+    Seek,
+    Version,
+    Configure,
+    Invalid,
+}
+
+impl Command {
+    fn from_byte(byte: u8) -> Self {
+        match byte & CMD_MASK {
+            0x03 => Command::Specify,
+            0x04 => Command::SenseDriveStatus,
+            0x05 => Command::WriteData,
+            0x06 => Command::ReadData,
+            0x07 => Command::Recalibrate,
+            0x08 => Command::SenseInterrupt,
+            0x0f => Command::Seek,
+            0x10 => Command::Version,
+            0x13 => Command::Configure,
+            _ => Command::Invalid,
+        }
+    }
+
+    // The number of parameter bytes that follow the command byte
+    fn parameter_count(&self) -> usize {
+        match self {
+            Command::Specify => 2,
+            Command::SenseDriveStatus => 1,
+            Command::WriteData | Command::ReadData => 8,
+            Command::Recalibrate => 1,
+            Command::Seek => 2,
+            Command::Configure => 3,
+            Command::SenseInterrupt | Command::Version | Command::Invalid => 0,
+        }
+    }
+}
+
+/// The geometry of a floppy disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FloppyGeometry {
+    pub cylinders: u8,
+    pub heads: u8,
+    pub sectors: u8,
+}
+
+impl FloppyGeometry {
+    /// Determine the geometry of a standard 3.5" disk image by its size
+    pub fn from_image_size(size: usize) -> Result<Self> {
+        let sectors = match size {
+            737280 => 9,
+            1474560 => 18,
+            2949120 => 36,
+            _ => {
+                return Err(Error::InvalidValue(format!(
+                    "Unsupported floppy image size: {}",
+                    size
+                )))
+            }
+        };
+        Ok(Self {
+            cylinders: 80,
+            heads: 2,
+            sectors,
+        })
+    }
+
+    fn lba(&self, cylinder: u8, head: u8, sector: u8) -> Option<usize> {
+        if cylinder >= self.cylinders
+            || head >= self.heads
+            || sector == 0
+            || sector > self.sectors
+        {
+            return None;
+        }
+        Some(
+            (cylinder as usize * self.heads as usize + head as usize)
+                * self.sectors as usize
+                + (sector as usize - 1),
+        )
+    }
+}
+
+/// An emulated 82077 floppy disk controller with a single drive
+///
+/// Sector data is transferred to and from guest memory using channel 2 of
+/// the (shared) DMA controller, and completion is signaled on IRQ6.
+pub struct FloppyController {
+    image: Vec<u8>,
+    geometry: FloppyGeometry,
+    dma: Rc<RefCell<Dma8237>>,
+    irq: Rc<dyn IrqSink>,
+
+    dor: u8,
+    protocol: CommandProtocol<Command>,
+    cylinder: [u8; 4],
+    pending_sense: VecDeque<(u8, u8)>,
+}
+
+impl FloppyController {
+    const FDC_BASE: Port = 0x3f0;
+    const FDC_DOR: Port = 0x3f2;
+    const FDC_MSR_DSR: Port = 0x3f4;
+    const FDC_FIFO: Port = 0x3f5;
+    const FDC_DIR_CCR: Port = 0x3f7;
+
+    pub fn new(
+        image: Vec<u8>,
+        dma: Rc<RefCell<Dma8237>>,
+        irq: Rc<dyn IrqSink>,
+    ) -> Result<Box<Self>> {
+        let geometry = FloppyGeometry::from_image_size(image.len())?;
+        Ok(Box::new(Self {
+            image,
+            geometry,
+            dma,
+            irq,
+            dor: DOR_NOT_RESET | DOR_IRQ_DMA,
+            protocol: CommandProtocol::new(),
+            cylinder: [0; 4],
+            pending_sense: VecDeque::new(),
+        }))
+    }
+
+    pub fn geometry(&self) -> FloppyGeometry {
+        self.geometry
+    }
+
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    fn raise_irq(&self) {
+        if self.dor & DOR_IRQ_DMA != 0 {
+            self.irq.raise_irq(FLOPPY_IRQ);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.protocol.reset();
+        self.pending_sense.clear();
+        for drive in 0..4 {
+            self.pending_sense.push_back((ST0_RESET | drive, 0));
+        }
+        self.raise_irq();
+    }
+
+    fn main_status(&self) -> u8 {
+        match self.protocol.phase() {
+            CommandPhase::Result => MSR_RQM | MSR_DIO | MSR_BUSY,
+            CommandPhase::Parameters => MSR_RQM | MSR_BUSY,
+            CommandPhase::Command => MSR_RQM,
+        }
+    }
+
+    fn write_fifo(
+        &mut self,
+        byte: u8,
+        space: &mut GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let decode = |byte| {
+            let command = Command::from_byte(byte);
+            (command, command.parameter_count())
+        };
+        // Writing while in the result phase aborts the command
+        match self.protocol.write(byte, decode) {
+            Some(cmd) => self.execute(cmd.command, &cmd.bytes, space),
+            None => Ok(()),
+        }
+    }
+
+    fn execute(
+        &mut self,
+        command: Command,
+        bytes: &[u8],
+        space: &mut GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        match command {
+            Command::Specify | Command::Configure => (),
+            Command::SenseDriveStatus => {
+                let drive = bytes[1] & 0b111;
+                let mut st3 = drive | ST3_READY | ST3_TWO_SIDE;
+                if self.cylinder[(drive & 0b11) as usize] == 0 {
+                    st3 |= ST3_TRACK0;
+                }
+                self.protocol.push_result(st3);
+            }
+            Command::Recalibrate => {
+                let drive = bytes[1] & 0b11;
+                self.cylinder[drive as usize] = 0;
+                self.pending_sense.push_back((ST0_SEEK_END | drive, 0));
+                self.raise_irq();
+            }
+            Command::Seek => {
+                let drive = bytes[1] & 0b11;
+                let head = (bytes[1] >> 2) & 1;
+                self.cylinder[drive as usize] = bytes[2];
+                self.pending_sense
+                    .push_back((ST0_SEEK_END | (head << 2) | drive, bytes[2]));
+                self.raise_irq();
+            }
+            Command::SenseInterrupt => match self.pending_sense.pop_front() {
+                Some((st0, cylinder)) => {
+                    self.protocol.push_result(st0);
+                    self.protocol.push_result(cylinder);
+                }
+                None => self.protocol.push_result(ST0_INVALID),
+            },
+            Command::Version => self.protocol.push_result(0x90),
+            Command::ReadData | Command::WriteData => {
+                self.transfer(command, bytes, space)?;
+                self.raise_irq();
+            }
+            Command::Invalid => {
+                info!("Invalid floppy command 0x{:x}", bytes[0]);
+                self.protocol.push_result(ST0_INVALID);
+            }
+        }
+        Ok(())
+    }
+
+    // Perform a read or write command, transferring sectors until either
+    // the DMA channel reaches its terminal count or the end of the track
+    fn transfer(
+        &mut self,
+        command: Command,
+        bytes: &[u8],
+        space: &mut GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let multi_track = bytes[0] & CMD_MULTI_TRACK != 0;
+        let drive = bytes[1] & 0b11;
+        let (mut cylinder, mut head, mut sector) =
+            (bytes[2], bytes[3], bytes[4]);
+        let size_code = bytes[5];
+        let end_of_track = bytes[6];
+
+        let mut st0 = (head << 2) | drive;
+        let mut st1 = 0;
+        loop {
+            let lba = match self.geometry.lba(cylinder, head, sector) {
+                Some(lba) if drive == 0 => lba,
+                _ => {
+                    st0 |= ST0_ABNORMAL;
+                    st1 |= ST1_NO_DATA;
+                    break;
+                }
+            };
+            let data = &mut self.image[lba * SECTOR_SIZE..][..SECTOR_SIZE];
+
+            let mut dma = self.dma.borrow_mut();
+            let remaining = dma.remaining(FLOPPY_DMA_CHANNEL)?;
+            let moved = if command == Command::ReadData {
+                dma.write_to_guest(FLOPPY_DMA_CHANNEL, data, space)?
+            } else {
+                dma.read_from_guest(FLOPPY_DMA_CHANNEL, data, space)?
+            };
+            if moved == 0 {
+                break;
+            }
+            let terminal_count = moved >= remaining;
+
+            sector += 1;
+            if sector > end_of_track {
+                sector = 1;
+                if multi_track && head == 0 {
+                    head = 1;
+                } else {
+                    head = 0;
+                    cylinder = cylinder.wrapping_add(1);
+                    if !terminal_count {
+                        st0 |= ST0_ABNORMAL;
+                        st1 |= ST1_END_OF_CYLINDER;
+                        break;
+                    }
+                }
+            }
+
+            if terminal_count {
+                break;
+            }
+        }
+
+        self.cylinder[drive as usize] = cylinder;
+        for byte in [st0, st1, 0, cylinder, head, sector, size_code].iter() {
+            self.protocol.push_result(*byte);
+        }
+        Ok(())
+    }
+}
+
+impl EmulatedDevice for FloppyController {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![
+            DeviceRegion::PortIo(Self::FDC_BASE..=Self::FDC_FIFO),
+            DeviceRegion::PortIo(Self::FDC_DIR_CCR..=Self::FDC_DIR_CCR),
+        ]
+    }
+
+    fn depends_on(&self) -> Vec<DeviceKind> {
+        vec![DeviceKind::Pic, DeviceKind::Dma]
+    }
+
+    fn irq_lines(&self) -> Vec<u8> {
+        vec![FLOPPY_IRQ]
+    }
+
+    fn on_port_read(
+        &mut self,
+        port: Port,
+        mut val: PortReadRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let res = match port {
+            Self::FDC_DOR => self.dor,
+            Self::FDC_MSR_DSR => self.main_status(),
+            Self::FDC_FIFO => self.protocol.read_result().unwrap_or(0),
+            _ => 0,
+        };
+        val.copy_from_u32(res as u32);
+        Ok(())
+    }
+
+    fn on_port_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+        mut space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let val: u8 = val.try_into()?;
+        match port {
+            Self::FDC_DOR => {
+                let old = self.dor;
+                self.dor = val;
+                if val & DOR_DRIVE_SELECT != 0 {
+                    info!("Floppy drive {} selected", val & DOR_DRIVE_SELECT);
+                }
+                if old & DOR_NOT_RESET == 0 && val & DOR_NOT_RESET != 0 {
+                    self.reset();
+                }
+            }
+            Self::FDC_MSR_DSR => {
+                if val & DSR_RESET != 0 {
+                    self.reset();
+                }
+            }
+            Self::FDC_FIFO => self.write_fifo(val, &mut space)?,
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::{define_test_view, MockIrqs};
+    use crate::memory::{
+        GuestAccess, GuestAddressSpace, GuestPhysAddr, GuestVirtAddr,
+        PrivilegeLevel,
+    };
+
+    struct TestSetup {
+        fdc: Box<FloppyController>,
+        dma: Rc<RefCell<Dma8237>>,
+        irqs: Rc<MockIrqs>,
+        space: &'static mut GuestAddressSpace,
+    }
+
+    // A 1.44MB image where every byte of a sector is its LBA (truncated)
+    fn test_setup() -> TestSetup {
+        let mut image = vec![0u8; 1474560];
+        for (i, sector) in image.chunks_mut(SECTOR_SIZE).enumerate() {
+            for byte in sector.iter_mut() {
+                *byte = i as u8;
+            }
+        }
+
+        let space: &'static mut GuestAddressSpace =
+            Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
+        space
+            .map_new_frame(GuestPhysAddr::new(0x20000), false)
+            .unwrap();
+
+        let dma = Rc::new(RefCell::new(*Dma8237::new()));
+        let irqs = Rc::new(MockIrqs::default());
+        let fdc =
+            FloppyController::new(image, dma.clone(), irqs.clone()).unwrap();
+        TestSetup {
+            fdc,
+            dma,
+            irqs,
+            space,
+        }
+    }
+
+    fn outb(dev: &mut dyn EmulatedDevice, port: Port, val: u8) {
+        let arr = [val];
+        let request = PortWriteRequest::OneByte(&arr);
+        dev.on_port_write(port, request, define_test_view())
+            .unwrap();
+    }
+
+    fn inb(dev: &mut dyn EmulatedDevice, port: Port) -> u8 {
+        let mut arr = [0u8];
+        let request = PortReadRequest::OneByte(&mut arr);
+        dev.on_port_read(port, request, define_test_view()).unwrap();
+        arr[0]
+    }
+
+    // Program DMA channel 2 for `len` bytes at 0x20000 using `mode`
+    fn program_dma(dma: &mut Rc<RefCell<Dma8237>>, mode: u8, len: u16) {
+        outb(dma, 0x0a, 0b110);
+        outb(dma, 0x0c, 0);
+        outb(dma, 0x0b, mode);
+        outb(dma, 0x04, 0x00);
+        outb(dma, 0x04, 0x00);
+        outb(dma, 0x81, 0x02);
+        outb(dma, 0x05, (len - 1) as u8);
+        outb(dma, 0x05, ((len - 1) >> 8) as u8);
+        outb(dma, 0x0a, 0b010);
+    }
+
+    fn send_command(setup: &mut TestSetup, bytes: &[u8]) {
+        for byte in bytes {
+            let arr = [*byte];
+            let request = PortWriteRequest::OneByte(&arr);
+            let space = GuestAddressSpaceViewMut::new(
+                GuestPhysAddr::new(0),
+                &mut *setup.space,
+            );
+            setup
+                .fdc
+                .on_port_write(FloppyController::FDC_FIFO, request, space)
+                .unwrap();
+        }
+    }
+
+    fn read_result(setup: &mut TestSetup, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| inb(&mut *setup.fdc, FloppyController::FDC_FIFO))
+            .collect()
+    }
+
+    fn read_guest(setup: &TestSetup, len: usize) -> Vec<u8> {
+        setup
+            .space
+            .read_bytes(
+                GuestPhysAddr::new(0),
+                GuestVirtAddr::NoPaging(GuestPhysAddr::new(0x20000)),
+                len,
+                GuestAccess::Read(PrivilegeLevel(0)),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_read_sector() {
+        let mut setup = test_setup();
+        program_dma(&mut setup.dma, 0x46, SECTOR_SIZE as u16);
+
+        // Read C=0 H=1 R=3 (LBA 20)
+        send_command(&mut setup, &[0x46, 0x04, 0, 1, 3, 2, 18, 0x1b, 0xff]);
+
+        assert_eq!(read_guest(&setup, SECTOR_SIZE), vec![20u8; SECTOR_SIZE]);
+        assert_eq!(*setup.irqs.raised.borrow(), [FLOPPY_IRQ]);
+
+        assert_eq!(
+            inb(&mut *setup.fdc, FloppyController::FDC_MSR_DSR),
+            MSR_RQM | MSR_DIO | MSR_BUSY
+        );
+        assert_eq!(read_result(&mut setup, 7), [0x04, 0, 0, 0, 1, 4, 2]);
+        assert_eq!(
+            inb(&mut *setup.fdc, FloppyController::FDC_MSR_DSR),
+            MSR_RQM
+        );
+    }
+
+    #[test]
+    fn test_multi_sector_read() {
+        let mut setup = test_setup();
+        program_dma(&mut setup.dma, 0x46, 2 * SECTOR_SIZE as u16);
+
+        // Read the last sector of head 0 and the first of head 1
+        send_command(&mut setup, &[0xc6, 0x00, 0, 0, 18, 2, 18, 0x1b, 0xff]);
+
+        let mut expected = vec![17u8; SECTOR_SIZE];
+        expected.extend_from_slice(&[18u8; SECTOR_SIZE]);
+        assert_eq!(read_guest(&setup, 2 * SECTOR_SIZE), expected);
+        assert_eq!(read_result(&mut setup, 7), [0x00, 0, 0, 0, 1, 2, 2]);
+    }
+
+    #[test]
+    fn test_write_sector() {
+        let mut setup = test_setup();
+        program_dma(&mut setup.dma, 0x4a, SECTOR_SIZE as u16);
+
+        // Write guest memory (zeros) to C=1 H=0 R=1 (LBA 36)
+        send_command(&mut setup, &[0x45, 0x00, 1, 0, 1, 2, 18, 0x1b, 0xff]);
+        assert_eq!(read_result(&mut setup, 7), [0x00, 0, 0, 1, 0, 2, 2]);
+
+        let lba = 36 * SECTOR_SIZE;
+        assert!(setup.fdc.image()[lba..lba + SECTOR_SIZE]
+            .iter()
+            .all(|b| *b == 0));
+        assert_eq!(setup.fdc.image()[lba + SECTOR_SIZE], 37);
+    }
+
+    #[test]
+    fn test_seek_and_sense_interrupt() {
+        let mut setup = test_setup();
+        send_command(&mut setup, &[0x0f, 0x00, 40]);
+        assert_eq!(*setup.irqs.raised.borrow(), [FLOPPY_IRQ]);
+
+        send_command(&mut setup, &[0x08]);
+        assert_eq!(read_result(&mut setup, 2), [ST0_SEEK_END, 40]);
+
+        send_command(&mut setup, &[0x07, 0x00]);
+        send_command(&mut setup, &[0x08]);
+        assert_eq!(read_result(&mut setup, 2), [ST0_SEEK_END, 0]);
+
+        // No interrupt is pending anymore
+        send_command(&mut setup, &[0x08]);
+        assert_eq!(read_result(&mut setup, 1), [ST0_INVALID]);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut setup = test_setup();
+        outb(&mut *setup.fdc, FloppyController::FDC_DOR, 0x00);
+        outb(&mut *setup.fdc, FloppyController::FDC_DOR, 0x0c);
+        assert_eq!(*setup.irqs.raised.borrow(), [FLOPPY_IRQ]);
+
+        for drive in 0..4 {
+            send_command(&mut setup, &[0x08]);
+            assert_eq!(read_result(&mut setup, 2), [ST0_RESET | drive, 0]);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_image() {
+        let dma = Rc::new(RefCell::new(*Dma8237::new()));
+        let irqs = Rc::new(MockIrqs::default());
+        assert!(FloppyController::new(vec![0; 1000], dma, irqs).is_err());
+    }
+}
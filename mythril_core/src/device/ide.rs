@@ -0,0 +1,704 @@
+use crate::device::identity::DeviceIdentity;
+use crate::device::interrupt::IrqSink;
+use crate::device::{
+    DeviceKind, DeviceRegion, EmulatedDevice, Port, PortReadRequest,
+    PortWriteRequest,
+};
+use crate::error::{Error, Result};
+use crate::memory::GuestAddressSpaceViewMut;
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+const SECTOR_SIZE: usize = 512;
+const ATA_IRQ: u8 = 14;
+
+// The serial number reported when none is given
+const DEFAULT_SERIAL: &[u8] = b"MYTHRIL0001";
+
+// The geometry reported by IDENTIFY (and used for CHS addressing)
+const ATA_HEADS: u64 = 16;
+const ATA_SECTORS_PER_TRACK: u64 = 63;
+const ATA_MAX_CYLINDERS: u64 = 16383;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DSC: u8 = 1 << 4;
+const STATUS_DRDY: u8 = 1 << 6;
+
+const ERROR_ABRT: u8 = 1 << 2;
+const ERROR_IDNF: u8 = 1 << 4;
+
+const DEVICE_LBA: u8 = 1 << 6;
+const DEVICE_SLAVE: u8 = 1 << 4;
+
+const CONTROL_NIEN: u8 = 1 << 1;
+const CONTROL_SRST: u8 = 1 << 2;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_READ_SECTORS_NO_RETRY: u8 = 0x21;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_WRITE_SECTORS_NO_RETRY: u8 = 0x31;
+const CMD_INITIALIZE_DEVICE_PARAMETERS: u8 = 0x91;
+const CMD_FLUSH_CACHE: u8 = 0xe7;
+const CMD_IDENTIFY: u8 = 0xec;
+const CMD_SET_FEATURES: u8 = 0xef;
+
+/// The data transfer (if any) in progress through the data port
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Transfer {
+    None,
+    Identify,
+    Read { lba: u64, remaining: u32 },
+    Write { lba: u64, remaining: u32 },
+}
+
+/// An emulated ATA controller with a single (master) disk on the primary
+/// channel
+///
+/// Only PIO transfers are supported. Each block of a command is moved
+/// through the data port, and the completion of a block is signaled on
+/// IRQ14 (unless disabled with nIEN).
+pub struct AtaController {
+    image: Vec<u8>,
+    irq: Rc<dyn IrqSink>,
+    serial: Vec<u8>,
+
+    error: u8,
+    features: u8,
+    sector_count: u8,
+    lba_low: u8,
+    lba_mid: u8,
+    lba_high: u8,
+    device: u8,
+    status: u8,
+    control: u8,
+
+    transfer: Transfer,
+    buffer: [u8; SECTOR_SIZE],
+    buffer_pos: usize,
+}
+
+impl AtaController {
+    const ATA_DATA: Port = 0x1f0;
+    const ATA_ERROR_FEATURES: Port = 0x1f1;
+    const ATA_SECTOR_COUNT: Port = 0x1f2;
+    const ATA_LBA_LOW: Port = 0x1f3;
+    const ATA_LBA_MID: Port = 0x1f4;
+    const ATA_LBA_HIGH: Port = 0x1f5;
+    const ATA_DEVICE: Port = 0x1f6;
+    const ATA_STATUS_COMMAND: Port = 0x1f7;
+    const ATA_ALT_STATUS_CONTROL: Port = 0x3f6;
+
+    /// Create a controller whose disk is backed by `image`, which must be
+    /// a whole number of sectors
+    pub fn new(image: Vec<u8>, irq: Rc<dyn IrqSink>) -> Result<Box<Self>> {
+        if image.is_empty() || image.len() % SECTOR_SIZE != 0 {
+            return Err(Error::InvalidValue(format!(
+                "Unsupported disk image size: {}",
+                image.len()
+            )));
+        }
+        let mut ata = Box::new(Self {
+            image,
+            irq,
+            serial: DEFAULT_SERIAL.to_vec(),
+            error: 0,
+            features: 0,
+            sector_count: 0,
+            lba_low: 0,
+            lba_mid: 0,
+            lba_high: 0,
+            device: 0,
+            status: 0,
+            control: 0,
+            transfer: Transfer::None,
+            buffer: [0; SECTOR_SIZE],
+            buffer_pos: 0,
+        });
+        ata.reset_registers();
+        Ok(ata)
+    }
+
+    /// Create a controller whose disk serial number is derived from
+    /// `identity`, rather than the fixed default
+    pub fn with_identity(
+        image: Vec<u8>,
+        irq: Rc<dyn IrqSink>,
+        identity: &DeviceIdentity,
+    ) -> Result<Box<Self>> {
+        let mut ata = Self::new(image, irq)?;
+        ata.set_serial(&identity.serial_number(Self::ATA_DATA as u64))?;
+        Ok(ata)
+    }
+
+    /// Set the serial number reported by IDENTIFY, which must be at most
+    /// 20 ASCII characters
+    pub fn set_serial(&mut self, serial: &str) -> Result<()> {
+        if serial.len() > DeviceIdentity::MAX_SERIAL_LEN || !serial.is_ascii() {
+            return Err(Error::InvalidValue(format!(
+                "Invalid ATA serial number: {}",
+                serial
+            )));
+        }
+        self.serial = serial.as_bytes().to_vec();
+        Ok(())
+    }
+
+    pub fn serial(&self) -> &[u8] {
+        &self.serial
+    }
+
+    /// The capacity of the disk in sectors
+    pub fn sectors(&self) -> u64 {
+        (self.image.len() / SECTOR_SIZE) as u64
+    }
+
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    // Put the registers in their state after a reset (with the device
+    // signature of a disk)
+    fn reset_registers(&mut self) {
+        self.error = 0x01;
+        self.features = 0;
+        self.sector_count = 1;
+        self.lba_low = 1;
+        self.lba_mid = 0;
+        self.lba_high = 0;
+        self.device = 0;
+        self.status = STATUS_DRDY | STATUS_DSC;
+        self.transfer = Transfer::None;
+        self.buffer_pos = 0;
+    }
+
+    fn slave_selected(&self) -> bool {
+        self.device & DEVICE_SLAVE != 0
+    }
+
+    fn raise_irq(&self) {
+        if self.control & CONTROL_NIEN == 0 {
+            self.irq.raise_irq(ATA_IRQ);
+        }
+    }
+
+    // The number of sectors selected by the sector count register (where
+    // zero means 256)
+    fn command_sectors(&self) -> u32 {
+        match self.sector_count {
+            0 => 256,
+            count => count as u32,
+        }
+    }
+
+    // The first sector selected by the LBA (or CHS) registers
+    fn command_lba(&self) -> Option<u64> {
+        if self.device & DEVICE_LBA != 0 {
+            return Some(
+                ((self.device & 0x0f) as u64) << 24
+                    | (self.lba_high as u64) << 16
+                    | (self.lba_mid as u64) << 8
+                    | self.lba_low as u64,
+            );
+        }
+        let cylinder = (self.lba_high as u64) << 8 | self.lba_mid as u64;
+        let head = (self.device & 0x0f) as u64;
+        let sector = self.lba_low as u64;
+        if sector == 0 || sector > ATA_SECTORS_PER_TRACK {
+            return None;
+        }
+        Some(
+            (cylinder * ATA_HEADS + head) * ATA_SECTORS_PER_TRACK
+                + (sector - 1),
+        )
+    }
+
+    fn abort(&mut self, error: u8) {
+        self.error = error;
+        self.status = STATUS_DRDY | STATUS_DSC | STATUS_ERR;
+        self.transfer = Transfer::None;
+        self.raise_irq();
+    }
+
+    fn complete(&mut self) {
+        self.error = 0;
+        self.status = STATUS_DRDY | STATUS_DSC;
+        self.transfer = Transfer::None;
+    }
+
+    // Make the next block of a command available through the data port
+    fn start_block(&mut self, transfer: Transfer) {
+        match transfer {
+            Transfer::Identify => self.buffer = self.identify_data(),
+            Transfer::Read { lba, .. } => {
+                let start = lba as usize * SECTOR_SIZE;
+                self.buffer
+                    .copy_from_slice(&self.image[start..start + SECTOR_SIZE]);
+            }
+            Transfer::Write { .. } | Transfer::None => (),
+        }
+        self.transfer = transfer;
+        self.buffer_pos = 0;
+        self.status = STATUS_DRDY | STATUS_DSC | STATUS_DRQ;
+    }
+
+    fn execute(&mut self, command: u8) {
+        if self.slave_selected() {
+            return;
+        }
+
+        match command {
+            CMD_IDENTIFY => {
+                self.start_block(Transfer::Identify);
+                self.raise_irq();
+            }
+            CMD_READ_SECTORS
+            | CMD_READ_SECTORS_NO_RETRY
+            | CMD_WRITE_SECTORS
+            | CMD_WRITE_SECTORS_NO_RETRY => {
+                let count = self.command_sectors();
+                let lba = match self.command_lba() {
+                    Some(lba) if lba + count as u64 <= self.sectors() => lba,
+                    _ => return self.abort(ERROR_IDNF | ERROR_ABRT),
+                };
+                if command == CMD_READ_SECTORS
+                    || command == CMD_READ_SECTORS_NO_RETRY
+                {
+                    self.start_block(Transfer::Read {
+                        lba,
+                        remaining: count,
+                    });
+                    self.raise_irq();
+                } else {
+                    // The first block of a write is requested without an
+                    // interrupt
+                    self.start_block(Transfer::Write {
+                        lba,
+                        remaining: count,
+                    });
+                }
+            }
+            CMD_INITIALIZE_DEVICE_PARAMETERS
+            | CMD_FLUSH_CACHE
+            | CMD_SET_FEATURES => {
+                self.complete();
+                self.raise_irq();
+            }
+            command => {
+                info!("Unsupported ATA command 0x{:x}", command);
+                self.abort(ERROR_ABRT);
+            }
+        }
+    }
+
+    // Called when the guest has read or written the whole buffer
+    fn finish_block(&mut self) {
+        match self.transfer {
+            Transfer::Read { lba, remaining } if remaining > 1 => {
+                self.start_block(Transfer::Read {
+                    lba: lba + 1,
+                    remaining: remaining - 1,
+                });
+                self.raise_irq();
+            }
+            Transfer::Write { lba, remaining } => {
+                let start = lba as usize * SECTOR_SIZE;
+                self.image[start..start + SECTOR_SIZE]
+                    .copy_from_slice(&self.buffer);
+                if remaining > 1 {
+                    self.start_block(Transfer::Write {
+                        lba: lba + 1,
+                        remaining: remaining - 1,
+                    });
+                } else {
+                    self.complete();
+                }
+                self.raise_irq();
+            }
+            _ => self.complete(),
+        }
+    }
+
+    // Read `len` bytes from the data port (as a little endian value)
+    fn read_data(&mut self, len: usize) -> u32 {
+        if self.status & STATUS_DRQ == 0 {
+            return 0xffff_ffff;
+        }
+        let mut res = 0u32;
+        for i in 0..len {
+            res |= (self.buffer[self.buffer_pos] as u32) << (i * 8);
+            self.buffer_pos += 1;
+            if self.buffer_pos == SECTOR_SIZE {
+                self.finish_block();
+                break;
+            }
+        }
+        res
+    }
+
+    fn write_data(&mut self, val: u32, len: usize) {
+        match self.transfer {
+            Transfer::Write { .. } if self.status & STATUS_DRQ != 0 => (),
+            _ => return,
+        }
+        for i in 0..len {
+            self.buffer[self.buffer_pos] = (val >> (i * 8)) as u8;
+            self.buffer_pos += 1;
+            if self.buffer_pos == SECTOR_SIZE {
+                self.finish_block();
+                break;
+            }
+        }
+    }
+
+    fn identify_data(&self) -> [u8; SECTOR_SIZE] {
+        let mut words = [0u16; SECTOR_SIZE / 2];
+        let sectors = self.sectors().min(0x0fff_ffff);
+        let cylinders = (sectors / (ATA_HEADS * ATA_SECTORS_PER_TRACK))
+            .max(1)
+            .min(ATA_MAX_CYLINDERS) as u16;
+
+        // A fixed (non-removable) disk
+        words[0] = 0x0040;
+        words[1] = cylinders;
+        words[3] = ATA_HEADS as u16;
+        words[6] = ATA_SECTORS_PER_TRACK as u16;
+        Self::identify_string(&mut words[10..20], &self.serial);
+        Self::identify_string(&mut words[23..27], b"1.0");
+        Self::identify_string(&mut words[27..47], b"MYTHRIL HARDDISK");
+        // LBA is supported
+        words[49] = 1 << 9;
+        // Words 54-58 are valid
+        words[53] = 1 << 0;
+        words[54] = cylinders;
+        words[55] = ATA_HEADS as u16;
+        words[56] = ATA_SECTORS_PER_TRACK as u16;
+        let chs_sectors =
+            cylinders as u32 * (ATA_HEADS * ATA_SECTORS_PER_TRACK) as u32;
+        words[57] = chs_sectors as u16;
+        words[58] = (chs_sectors >> 16) as u16;
+        words[60] = sectors as u16;
+        words[61] = (sectors >> 16) as u16;
+
+        let mut data = [0u8; SECTOR_SIZE];
+        for (bytes, word) in data.chunks_mut(2).zip(words.iter()) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        data
+    }
+
+    // IDENTIFY strings are space padded, with two characters per word
+    // (the first in the high byte)
+    fn identify_string(words: &mut [u16], s: &[u8]) {
+        for (i, word) in words.iter_mut().enumerate() {
+            let c = |idx: usize| *s.get(idx).unwrap_or(&b' ') as u16;
+            *word = c(i * 2) << 8 | c(i * 2 + 1);
+        }
+    }
+}
+
+impl EmulatedDevice for AtaController {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![
+            DeviceRegion::PortIo(Self::ATA_DATA..=Self::ATA_STATUS_COMMAND),
+            DeviceRegion::PortIo(
+                Self::ATA_ALT_STATUS_CONTROL..=Self::ATA_ALT_STATUS_CONTROL,
+            ),
+        ]
+    }
+
+    fn depends_on(&self) -> Vec<DeviceKind> {
+        vec![DeviceKind::Pic]
+    }
+
+    fn irq_lines(&self) -> Vec<u8> {
+        vec![ATA_IRQ]
+    }
+
+    fn reset(&mut self) {
+        self.control = 0;
+        self.reset_registers();
+    }
+
+    fn on_port_read(
+        &mut self,
+        port: Port,
+        mut val: PortReadRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let len = val.as_slice().len();
+        let res = match port {
+            Self::ATA_DATA => self.read_data(len),
+            // There is no slave device
+            _ if self.slave_selected() && port != Self::ATA_DEVICE => 0,
+            Self::ATA_ERROR_FEATURES => self.error as u32,
+            Self::ATA_SECTOR_COUNT => self.sector_count as u32,
+            Self::ATA_LBA_LOW => self.lba_low as u32,
+            Self::ATA_LBA_MID => self.lba_mid as u32,
+            Self::ATA_LBA_HIGH => self.lba_high as u32,
+            Self::ATA_DEVICE => self.device as u32,
+            Self::ATA_STATUS_COMMAND | Self::ATA_ALT_STATUS_CONTROL => {
+                self.status as u32
+            }
+            _ => 0,
+        };
+        val.copy_from_u32(res);
+        Ok(())
+    }
+
+    fn on_port_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        if port == Self::ATA_DATA {
+            let len = val.as_slice().len();
+            self.write_data(val.as_u32(), len);
+            return Ok(());
+        }
+
+        let val: u8 = val.try_into()?;
+        match port {
+            Self::ATA_ERROR_FEATURES => self.features = val,
+            Self::ATA_SECTOR_COUNT => self.sector_count = val,
+            Self::ATA_LBA_LOW => self.lba_low = val,
+            Self::ATA_LBA_MID => self.lba_mid = val,
+            Self::ATA_LBA_HIGH => self.lba_high = val,
+            Self::ATA_DEVICE => self.device = val,
+            Self::ATA_STATUS_COMMAND => self.execute(val),
+            Self::ATA_ALT_STATUS_CONTROL => {
+                // The reset completes when SRST is cleared again
+                if self.control & CONTROL_SRST != 0 && val & CONTROL_SRST == 0 {
+                    self.reset_registers();
+                }
+                self.control = val;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::{define_test_view, MockIrqs};
+
+    // A 1MB disk where every byte of a sector is its LBA (truncated)
+    fn test_setup() -> (Box<AtaController>, Rc<MockIrqs>) {
+        let mut image = vec![0u8; 2048 * SECTOR_SIZE];
+        for (i, sector) in image.chunks_mut(SECTOR_SIZE).enumerate() {
+            for byte in sector.iter_mut() {
+                *byte = i as u8;
+            }
+        }
+        let irqs = Rc::new(MockIrqs::default());
+        (AtaController::new(image, irqs.clone()).unwrap(), irqs)
+    }
+
+    fn outb(dev: &mut AtaController, port: Port, val: u8) {
+        let arr = [val];
+        let request = PortWriteRequest::OneByte(&arr);
+        dev.on_port_write(port, request, define_test_view())
+            .unwrap();
+    }
+
+    fn inb(dev: &mut AtaController, port: Port) -> u8 {
+        let mut arr = [0u8];
+        let request = PortReadRequest::OneByte(&mut arr);
+        dev.on_port_read(port, request, define_test_view()).unwrap();
+        arr[0]
+    }
+
+    fn inw(dev: &mut AtaController, port: Port) -> u16 {
+        let mut arr = [0u8; 2];
+        let request = PortReadRequest::TwoBytes(&mut arr);
+        dev.on_port_read(port, request, define_test_view()).unwrap();
+        u16::from_be_bytes(arr)
+    }
+
+    fn outw(dev: &mut AtaController, port: Port, val: u16) {
+        let arr = val.to_be_bytes();
+        let request = PortWriteRequest::TwoBytes(&arr);
+        dev.on_port_write(port, request, define_test_view())
+            .unwrap();
+    }
+
+    // Read a block from the data port, one word at a time
+    fn read_block(dev: &mut AtaController) -> Vec<u8> {
+        let mut data = vec![];
+        for _ in 0..SECTOR_SIZE / 2 {
+            data.extend_from_slice(
+                &inw(dev, AtaController::ATA_DATA).to_le_bytes(),
+            );
+        }
+        data
+    }
+
+    fn select_lba(dev: &mut AtaController, lba: u32, count: u8) {
+        outb(dev, AtaController::ATA_SECTOR_COUNT, count);
+        outb(dev, AtaController::ATA_LBA_LOW, lba as u8);
+        outb(dev, AtaController::ATA_LBA_MID, (lba >> 8) as u8);
+        outb(dev, AtaController::ATA_LBA_HIGH, (lba >> 16) as u8);
+        outb(
+            dev,
+            AtaController::ATA_DEVICE,
+            0xe0 | ((lba >> 24) as u8 & 0x0f),
+        );
+    }
+
+    #[test]
+    fn test_identify() {
+        let (mut ata, irqs) = test_setup();
+        outb(&mut ata, AtaController::ATA_DEVICE, 0xa0);
+        outb(&mut ata, AtaController::ATA_STATUS_COMMAND, CMD_IDENTIFY);
+        assert_eq!(*irqs.raised.borrow(), [ATA_IRQ]);
+        assert_eq!(
+            inb(&mut ata, AtaController::ATA_STATUS_COMMAND),
+            STATUS_DRDY | STATUS_DSC | STATUS_DRQ
+        );
+
+        let data = read_block(&mut ata);
+        let word =
+            |i: usize| u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]);
+        assert_eq!(word(0), 0x0040);
+        assert_eq!((word(1), word(3), word(6)), (2, 16, 63));
+        assert_ne!(word(49) & (1 << 9), 0);
+        assert_eq!(word(60) as u32 | (word(61) as u32) << 16, 2048);
+        assert_eq!(&data[54..58], b"YMHT");
+
+        assert_eq!(
+            inb(&mut ata, AtaController::ATA_STATUS_COMMAND),
+            STATUS_DRDY | STATUS_DSC
+        );
+    }
+
+    #[test]
+    fn test_identify_serial() {
+        let irqs = Rc::new(MockIrqs::default());
+        let identity = DeviceIdentity::new(7);
+        let mut ata =
+            AtaController::with_identity(vec![0; SECTOR_SIZE], irqs, &identity)
+                .unwrap();
+        assert_eq!(ata.serial(), identity.serial_number(0x1f0).as_bytes());
+
+        // An explicit serial number overrides the derived one
+        ata.set_serial("DISK 42").unwrap();
+        outb(&mut ata, AtaController::ATA_DEVICE, 0xa0);
+        outb(&mut ata, AtaController::ATA_STATUS_COMMAND, CMD_IDENTIFY);
+        let data = read_block(&mut ata);
+        assert_eq!(&data[20..28], b"IDKS4  2");
+        assert!(ata.set_serial("012345678901234567890").is_err());
+    }
+
+    #[test]
+    fn test_read_sectors() {
+        let (mut ata, irqs) = test_setup();
+        select_lba(&mut ata, 0x123, 2);
+        outb(
+            &mut ata,
+            AtaController::ATA_STATUS_COMMAND,
+            CMD_READ_SECTORS,
+        );
+        assert_eq!(*irqs.raised.borrow(), [ATA_IRQ]);
+
+        assert_eq!(read_block(&mut ata), vec![0x23u8; SECTOR_SIZE]);
+        assert_eq!(*irqs.raised.borrow(), [ATA_IRQ; 2]);
+        assert_ne!(
+            inb(&mut ata, AtaController::ATA_STATUS_COMMAND) & STATUS_DRQ,
+            0
+        );
+        assert_eq!(read_block(&mut ata), vec![0x24u8; SECTOR_SIZE]);
+        assert_eq!(
+            inb(&mut ata, AtaController::ATA_STATUS_COMMAND),
+            STATUS_DRDY | STATUS_DSC
+        );
+    }
+
+    #[test]
+    fn test_chs_read() {
+        let (mut ata, _irqs) = test_setup();
+        // C=1 H=2 S=3 is LBA (1 * 16 + 2) * 63 + 2 = 1136
+        outb(&mut ata, AtaController::ATA_SECTOR_COUNT, 1);
+        outb(&mut ata, AtaController::ATA_LBA_LOW, 3);
+        outb(&mut ata, AtaController::ATA_LBA_MID, 1);
+        outb(&mut ata, AtaController::ATA_LBA_HIGH, 0);
+        outb(&mut ata, AtaController::ATA_DEVICE, 0xa2);
+        outb(
+            &mut ata,
+            AtaController::ATA_STATUS_COMMAND,
+            CMD_READ_SECTORS,
+        );
+        assert_eq!(read_block(&mut ata), vec![1136u16 as u8; SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn test_write_sectors() {
+        let (mut ata, irqs) = test_setup();
+        select_lba(&mut ata, 7, 1);
+        outb(
+            &mut ata,
+            AtaController::ATA_STATUS_COMMAND,
+            CMD_WRITE_SECTORS,
+        );
+        assert!(irqs.raised.borrow().is_empty());
+
+        for _ in 0..SECTOR_SIZE / 2 {
+            outw(&mut ata, AtaController::ATA_DATA, 0xaa55);
+        }
+        assert_eq!(*irqs.raised.borrow(), [ATA_IRQ]);
+        assert_eq!(
+            inb(&mut ata, AtaController::ATA_STATUS_COMMAND),
+            STATUS_DRDY | STATUS_DSC
+        );
+
+        let sector = &ata.image()[7 * SECTOR_SIZE..8 * SECTOR_SIZE];
+        assert!(sector.chunks(2).all(|word| word == [0x55, 0xaa]));
+        assert_eq!(ata.image()[8 * SECTOR_SIZE], 8);
+    }
+
+    #[test]
+    fn test_out_of_range_read() {
+        let (mut ata, irqs) = test_setup();
+        select_lba(&mut ata, 2047, 2);
+        outb(
+            &mut ata,
+            AtaController::ATA_STATUS_COMMAND,
+            CMD_READ_SECTORS,
+        );
+        assert_eq!(*irqs.raised.borrow(), [ATA_IRQ]);
+        assert_eq!(
+            inb(&mut ata, AtaController::ATA_STATUS_COMMAND),
+            STATUS_DRDY | STATUS_DSC | STATUS_ERR
+        );
+        assert_eq!(
+            inb(&mut ata, AtaController::ATA_ERROR_FEATURES),
+            ERROR_IDNF | ERROR_ABRT
+        );
+    }
+
+    #[test]
+    fn test_no_slave_and_disabled_irq() {
+        let (mut ata, irqs) = test_setup();
+        outb(&mut ata, AtaController::ATA_DEVICE, 0xb0);
+        assert_eq!(inb(&mut ata, AtaController::ATA_STATUS_COMMAND), 0);
+        outb(&mut ata, AtaController::ATA_STATUS_COMMAND, CMD_IDENTIFY);
+        assert!(irqs.raised.borrow().is_empty());
+
+        outb(&mut ata, AtaController::ATA_DEVICE, 0xa0);
+        outb(
+            &mut ata,
+            AtaController::ATA_ALT_STATUS_CONTROL,
+            CONTROL_NIEN,
+        );
+        outb(&mut ata, AtaController::ATA_STATUS_COMMAND, CMD_IDENTIFY);
+        assert!(irqs.raised.borrow().is_empty());
+        assert_ne!(
+            inb(&mut ata, AtaController::ATA_ALT_STATUS_CONTROL) & STATUS_DRQ,
+            0
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use alloc::string::String;
+
+/// A source of identifiers for emulated devices (e.g., MAC addresses and
+/// disk serial numbers) derived from a per-guest seed
+///
+/// The same seed always produces the same identifiers, so they are stable
+/// across boots of a guest, while guests with different seeds get
+/// different identifiers. Devices of the same kind within a guest are
+/// distinguished by an `index` (such as their base port).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeviceIdentity {
+    seed: u64,
+}
+
+impl DeviceIdentity {
+    // Distinguishes the identifiers of different kinds from one index
+    const MAC_SALT: u64 = 0x6d61_6300;
+    const SERIAL_SALT: u64 = 0x7365_7200;
+
+    // The flags in the first octet of a MAC address
+    const MAC_MULTICAST: u8 = 1 << 0;
+    const MAC_LOCALLY_ADMINISTERED: u8 = 1 << 1;
+
+    /// The maximum length of an ATA serial number
+    pub const MAX_SERIAL_LEN: usize = 20;
+
+    pub fn new(seed: u64) -> Self {
+        Self { seed }
+    }
+
+    /// A locally administered, unicast MAC address
+    pub fn mac_address(&self, index: u64) -> [u8; 6] {
+        let bytes = self.derive(Self::MAC_SALT, index).to_le_bytes();
+        let mut mac = [0u8; 6];
+        mac.copy_from_slice(&bytes[..6]);
+        mac[0] =
+            (mac[0] & !Self::MAC_MULTICAST) | Self::MAC_LOCALLY_ADMINISTERED;
+        mac
+    }
+
+    /// A serial number of up to `MAX_SERIAL_LEN` upper case ASCII
+    /// characters, suitable for ATA IDENTIFY data
+    pub fn serial_number(&self, index: u64) -> String {
+        let value = self.derive(Self::SERIAL_SALT, index);
+        format!("MYTHRIL{:012X}", value & 0xffff_ffff_ffff)
+    }
+
+    // The splitmix64 mix of the seed, salt and index
+    fn derive(&self, salt: u64, index: u64) -> u64 {
+        let mut z = self
+            .seed
+            .wrapping_add(salt.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+            .wrapping_add(index.wrapping_mul(0xbf58_476d_1ce4_e5b9));
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_identity_is_deterministic() {
+        let identity = DeviceIdentity::new(42);
+        assert_eq!(
+            identity.mac_address(0),
+            DeviceIdentity::new(42).mac_address(0)
+        );
+        assert_eq!(
+            identity.serial_number(0),
+            DeviceIdentity::new(42).serial_number(0)
+        );
+
+        // Different seeds (and different devices) get different identifiers
+        let other = DeviceIdentity::new(43);
+        assert_ne!(identity.mac_address(0), other.mac_address(0));
+        assert_ne!(identity.serial_number(0), other.serial_number(0));
+        assert_ne!(identity.mac_address(0), identity.mac_address(1));
+        assert_ne!(identity.serial_number(0), identity.serial_number(1));
+    }
+
+    #[test]
+    fn test_identity_format() {
+        for seed in 0..64 {
+            let identity = DeviceIdentity::new(seed);
+            let mac = identity.mac_address(seed);
+            assert_eq!(mac[0] & DeviceIdentity::MAC_MULTICAST, 0);
+            assert_ne!(mac[0] & DeviceIdentity::MAC_LOCALLY_ADMINISTERED, 0);
+
+            let serial = identity.serial_number(seed);
+            assert!(serial.len() <= DeviceIdentity::MAX_SERIAL_LEN);
+            assert!(serial
+                .bytes()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()));
+        }
+    }
+}
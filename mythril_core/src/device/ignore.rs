@@ -1,21 +1,83 @@
 use crate::device::{
-    DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
+    AccessKind, DeviceRegion, EmulatedDevice, Port, PortReadRequest,
+    PortWriteRequest, UnhandledAccess,
 };
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::memory::GuestAddressSpaceViewMut;
 use alloc::boxed::Box;
+use alloc::collections::btree_set::BTreeSet;
 use alloc::vec::Vec;
 
+/// How an `IgnoredDevice` responds to accesses of the ports it services
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IgnoreMode {
+    /// Reads return all 0xFFs (like an empty bus) and writes are dropped
+    Absorb,
+
+    /// Like `Absorb`, but log the first access to each port
+    Warn,
+
+    /// Return `Error::UnhandledAccess` so the unhandled access is reported
+    Fault,
+}
+
+impl Default for IgnoreMode {
+    fn default() -> Self {
+        IgnoreMode::Absorb
+    }
+}
+
 // In the future, we will just ignore all ports not associated with mapped devices,
 // but for now, it is useful to explicitly ignore devices we don't need to emulate
 // and fail when an unknown port is used.
 #[derive(Default, Debug)]
-pub struct IgnoredDevice;
+pub struct IgnoredDevice {
+    mode: IgnoreMode,
+    warned: BTreeSet<Port>,
+}
 
 impl IgnoredDevice {
     pub fn new() -> Box<Self> {
         Box::new(Self::default())
     }
+
+    pub fn with_mode(mode: IgnoreMode) -> Box<Self> {
+        Box::new(Self {
+            mode,
+            ..Self::default()
+        })
+    }
+
+    pub fn mode(&self) -> IgnoreMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: IgnoreMode) {
+        self.mode = mode;
+    }
+
+    fn handle_access(
+        &mut self,
+        port: Port,
+        kind: AccessKind,
+        width: usize,
+    ) -> Result<()> {
+        match self.mode {
+            IgnoreMode::Absorb => Ok(()),
+            IgnoreMode::Warn => {
+                if self.warned.insert(port) {
+                    warn!("Ignoring {:?} of port 0x{:x}", kind, port);
+                }
+                Ok(())
+            }
+            IgnoreMode::Fault => Err(Error::UnhandledAccess(UnhandledAccess {
+                device: self.debug_name(),
+                kind,
+                addr: port as u64,
+                width,
+            })),
+        }
+    }
 }
 
 impl EmulatedDevice for IgnoredDevice {
@@ -31,19 +93,84 @@ impl EmulatedDevice for IgnoredDevice {
 
     fn on_port_read(
         &mut self,
-        _port: Port,
-        _val: PortReadRequest,
+        port: Port,
+        mut val: PortReadRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
+        self.handle_access(port, AccessKind::PortRead, val.as_slice().len())?;
+        val.copy_from_u32(0xffffffff);
         Ok(())
     }
 
     fn on_port_write(
         &mut self,
-        _port: Port,
-        _val: PortWriteRequest,
+        port: Port,
+        val: PortWriteRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
-        Ok(())
+        self.handle_access(port, AccessKind::PortWrite, val.as_slice().len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::define_test_view;
+
+    const PORT: Port = 128;
+
+    fn read_port(dev: &mut IgnoredDevice) -> Result<u8> {
+        let mut buff = [0u8; 1];
+        let val = PortReadRequest::OneByte(&mut buff);
+        dev.on_port_read(PORT, val, define_test_view())?;
+        Ok(buff[0])
+    }
+
+    fn write_port(dev: &mut IgnoredDevice) -> Result<()> {
+        let buff = [0x12u8; 1];
+        let val = PortWriteRequest::OneByte(&buff);
+        dev.on_port_write(PORT, val, define_test_view())
+    }
+
+    #[test]
+    fn test_absorb_mode() {
+        let mut dev = IgnoredDevice::with_mode(IgnoreMode::Absorb);
+        assert_eq!(read_port(&mut dev), Ok(0xff));
+        assert_eq!(write_port(&mut dev), Ok(()));
+    }
+
+    #[test]
+    fn test_default_mode_absorbs() {
+        let mut dev = IgnoredDevice::new();
+        assert_eq!(dev.mode(), IgnoreMode::Absorb);
+        assert_eq!(read_port(&mut dev), Ok(0xff));
+    }
+
+    #[test]
+    fn test_warn_mode() {
+        let mut dev = IgnoredDevice::with_mode(IgnoreMode::Warn);
+        assert_eq!(read_port(&mut dev), Ok(0xff));
+        assert_eq!(read_port(&mut dev), Ok(0xff));
+        assert_eq!(write_port(&mut dev), Ok(()));
+        assert!(dev.warned.contains(&PORT));
+        assert_eq!(dev.warned.len(), 1);
+    }
+
+    #[test]
+    fn test_fault_mode() {
+        let mut dev = IgnoredDevice::with_mode(IgnoreMode::Fault);
+        assert_eq!(
+            read_port(&mut dev),
+            Err(Error::UnhandledAccess(UnhandledAccess {
+                device: "IgnoredDevice",
+                kind: AccessKind::PortRead,
+                addr: PORT as u64,
+                width: 1,
+            }))
+        );
+        assert!(write_port(&mut dev).is_err());
+
+        dev.set_mode(IgnoreMode::Absorb);
+        assert_eq!(read_port(&mut dev), Ok(0xff));
     }
 }
@@ -0,0 +1,145 @@
+use crate::device::rom::RomDevice;
+use crate::device::{
+    DeviceRegion, EmulatedDevice, MemReadRequest, MemWriteRequest,
+};
+use crate::error::Result;
+use crate::memory::{GuestAddressSpaceViewMut, GuestPhysAddr};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// The platform parameters reported by an `InfoRom`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PlatformInfo {
+    /// The size of guest RAM in bytes
+    pub memory: u64,
+    pub cpus: u32,
+
+    /// The address of the ACPI RSDP, if the guest has ACPI tables
+    pub rsdp: Option<GuestPhysAddr>,
+}
+
+/// A read-only page describing the platform, for guests without firmware
+///
+/// The page has the following layout (all fields are little endian):
+///
+/// | Offset | Size | Field                                         |
+/// |--------|------|-----------------------------------------------|
+/// | 0x00   | 8    | Signature, "MYTHINFO"                         |
+/// | 0x08   | 4    | Layout version (currently 1)                  |
+/// | 0x0c   | 4    | Length of the populated fields, in bytes      |
+/// | 0x10   | 8    | Size of guest RAM, in bytes                   |
+/// | 0x18   | 4    | Number of CPUs                                |
+/// | 0x1c   | 4    | Reserved (zero)                               |
+/// | 0x20   | 8    | Address of the ACPI RSDP (zero if none)       |
+///
+/// The rest of the page reads as zero. Fields are only ever appended, so
+/// a guest should check the length before reading a newer field.
+pub struct InfoRom {
+    rom: RomDevice,
+}
+
+impl InfoRom {
+    /// The conventional address of the page (in the MMIO hole, between
+    /// the local APIC and the BIOS)
+    pub const DEFAULT_BASE: u64 = 0xfef0_0000;
+
+    pub const SIGNATURE: [u8; 8] = *b"MYTHINFO";
+    pub const VERSION: u32 = 1;
+
+    pub const MEMORY_OFFSET: usize = 0x10;
+    pub const CPUS_OFFSET: usize = 0x18;
+    pub const RSDP_OFFSET: usize = 0x20;
+    const LENGTH: usize = 0x28;
+
+    const PAGE_SIZE: u64 = 0x1000;
+
+    pub fn new(base: GuestPhysAddr, info: &PlatformInfo) -> Box<Self> {
+        let mut image = Vec::with_capacity(Self::LENGTH);
+        image.extend_from_slice(&Self::SIGNATURE);
+        image.extend_from_slice(&Self::VERSION.to_le_bytes());
+        image.extend_from_slice(&(Self::LENGTH as u32).to_le_bytes());
+        image.extend_from_slice(&info.memory.to_le_bytes());
+        image.extend_from_slice(&info.cpus.to_le_bytes());
+        image.extend_from_slice(&0u32.to_le_bytes());
+        let rsdp = info.rsdp.map(|addr| addr.as_u64()).unwrap_or(0);
+        image.extend_from_slice(&rsdp.to_le_bytes());
+
+        let end = GuestPhysAddr::new(base.as_u64() + Self::PAGE_SIZE - 1);
+        Box::new(Self {
+            rom: *RomDevice::new(base..=end, image),
+        })
+    }
+
+    pub fn image(&self) -> &[u8] {
+        self.rom.image()
+    }
+}
+
+impl EmulatedDevice for InfoRom {
+    fn services(&self) -> Vec<DeviceRegion> {
+        self.rom.services()
+    }
+
+    fn on_mem_read(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemReadRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.rom.on_mem_read(addr, data, space)
+    }
+
+    fn on_mem_write(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemWriteRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.rom.on_mem_write(addr, data, space)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::define_test_view;
+    use core::convert::TryInto;
+
+    fn read_info(rom: &mut InfoRom, offset: usize, len: usize) -> Vec<u8> {
+        let mut buff = vec![0xffu8; len];
+        rom.on_mem_read(
+            GuestPhysAddr::new(InfoRom::DEFAULT_BASE + offset as u64),
+            MemReadRequest::new(&mut buff),
+            define_test_view(),
+        )
+        .unwrap();
+        buff
+    }
+
+    #[test]
+    fn test_info_layout() {
+        let mut rom = InfoRom::new(
+            GuestPhysAddr::new(InfoRom::DEFAULT_BASE),
+            &PlatformInfo {
+                memory: 256 << 20,
+                cpus: 4,
+                rsdp: Some(GuestPhysAddr::new(0xe0000)),
+            },
+        );
+        assert_eq!(read_info(&mut rom, 0, 8), b"MYTHINFO");
+        assert_eq!(read_info(&mut rom, 0x08, 4), 1u32.to_le_bytes());
+        assert_eq!(read_info(&mut rom, 0x0c, 4), 0x28u32.to_le_bytes());
+
+        let memory = read_info(&mut rom, InfoRom::MEMORY_OFFSET, 8);
+        assert_eq!(
+            u64::from_le_bytes(memory[..].try_into().unwrap()),
+            256 << 20
+        );
+        let cpus = read_info(&mut rom, InfoRom::CPUS_OFFSET, 4);
+        assert_eq!(u32::from_le_bytes(cpus[..].try_into().unwrap()), 4);
+        let rsdp = read_info(&mut rom, InfoRom::RSDP_OFFSET, 8);
+        assert_eq!(u64::from_le_bytes(rsdp[..].try_into().unwrap()), 0xe0000);
+
+        assert_eq!(read_info(&mut rom, 0xffc, 4), [0; 4]);
+    }
+}
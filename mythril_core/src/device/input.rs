@@ -0,0 +1,273 @@
+use crate::time::ClockSource;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::rc::Rc;
+
+/// A host input event destined for an emulated input device
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputEvent {
+    /// A single keyboard scancode byte
+    Key(u8),
+
+    /// Relative mouse motion with the current state of the buttons (bit 0
+    /// is the left button, bit 1 the right and bit 2 the middle)
+    MouseMotion { dx: i32, dy: i32, buttons: u8 },
+}
+
+impl InputEvent {
+    // Merge `other` in to this event, if both are relative motion with
+    // the same button state
+    fn coalesce(&mut self, other: &InputEvent) -> bool {
+        match (self, other) {
+            (
+                InputEvent::MouseMotion { dx, dy, buttons },
+                InputEvent::MouseMotion {
+                    dx: other_dx,
+                    dy: other_dy,
+                    buttons: other_buttons,
+                },
+            ) if buttons == other_buttons => {
+                *dx = dx.saturating_add(*other_dx);
+                *dy = dy.saturating_add(*other_dy);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A bounded queue of input events waiting to be consumed by the guest
+///
+/// Relative mouse motion events are summed with any motion event at the
+/// back of the queue (with the same button state), so a guest that drains
+/// slowly sees fewer, larger movements instead of an overrun controller.
+/// Key events are never coalesced. When the queue is full, new events are
+/// dropped and counted.
+#[derive(Debug)]
+pub struct InputQueue {
+    events: VecDeque<InputEvent>,
+    capacity: usize,
+    dropped: usize,
+}
+
+impl InputQueue {
+    pub const DEFAULT_CAPACITY: usize = 64;
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+            dropped: 0,
+        }
+    }
+
+    /// Add an event to the queue
+    ///
+    /// Returns false if the event had to be dropped because the queue is
+    /// full.
+    pub fn push(&mut self, event: InputEvent) -> bool {
+        if let Some(last) = self.events.back_mut() {
+            if last.coalesce(&event) {
+                return true;
+            }
+        }
+
+        if self.events.len() >= self.capacity {
+            self.dropped += 1;
+            return false;
+        }
+        self.events.push_back(event);
+        true
+    }
+
+    /// Remove the next event from the queue
+    pub fn pop(&mut self) -> Option<InputEvent> {
+        self.events.pop_front()
+    }
+
+    /// Return an event to the front of the queue (e.g., the remainder of
+    /// a motion event that was too large for a single packet)
+    pub fn push_front(&mut self, event: InputEvent) {
+        self.events.push_front(event)
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Returns true if the queue is full, so new events will be dropped
+    pub fn backpressure(&self) -> bool {
+        self.events.len() >= self.capacity
+    }
+
+    /// The number of events dropped because the queue was full
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+impl Default for InputQueue {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY)
+    }
+}
+
+/// A step of an `InputScript`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScriptEvent {
+    /// A byte received by a serial port
+    Serial(u8),
+
+    /// An event for an input device (e.g., a key press)
+    Input(InputEvent),
+
+    /// Hold back the rest of the script for this many nanoseconds
+    Delay(u64),
+}
+
+/// A script of input that is fed to a device as the guest consumes it, for
+/// automated and reproducible guest interaction
+///
+/// Devices only take the next event from the script when they have room
+/// for it, so a script is never overrun. A delay starts once the events
+/// before it have been taken, and is measured by the (virtual) `clock`.
+pub struct InputScript {
+    events: VecDeque<ScriptEvent>,
+    clock: Rc<dyn ClockSource>,
+
+    // The clock time when the delay at the front of the script ends
+    delay_end: Option<u64>,
+}
+
+impl InputScript {
+    pub fn new(clock: Rc<dyn ClockSource>) -> Self {
+        Self {
+            events: VecDeque::new(),
+            clock,
+            delay_end: None,
+        }
+    }
+
+    /// Add an event to the end of the script
+    pub fn push(&mut self, event: ScriptEvent) {
+        self.events.push_back(event);
+    }
+
+    /// Add a serial event for each of `bytes` to the end of the script
+    pub fn push_serial(&mut self, bytes: &[u8]) {
+        self.events
+            .extend(bytes.iter().map(|byte| ScriptEvent::Serial(*byte)));
+    }
+
+    /// Take the next event, unless the script is waiting for a delay to
+    /// pass (delays are never returned)
+    pub fn next_event(&mut self) -> Option<ScriptEvent> {
+        loop {
+            match *self.events.front()? {
+                ScriptEvent::Delay(ns) => {
+                    let now = self.clock.now_ns();
+                    let end =
+                        *self.delay_end.get_or_insert(now.saturating_add(ns));
+                    if now < end {
+                        return None;
+                    }
+                    self.delay_end = None;
+                    self.events.pop_front();
+                }
+                _ => return self.events.pop_front(),
+            }
+        }
+    }
+
+    /// Returns true once every event has been taken
+    pub fn is_finished(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::time::FixedClock;
+
+    fn motion(dx: i32, dy: i32) -> InputEvent {
+        InputEvent::MouseMotion { dx, dy, buttons: 0 }
+    }
+
+    #[test]
+    fn test_motion_coalesces() {
+        let mut queue = InputQueue::default();
+        assert!(queue.push(motion(1, 2)));
+        assert!(queue.push(motion(3, -4)));
+        assert!(queue.push(motion(5, 6)));
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.pop(), Some(motion(9, 4)));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn test_button_change_not_coalesced() {
+        let mut queue = InputQueue::default();
+        queue.push(motion(1, 1));
+        queue.push(InputEvent::MouseMotion {
+            dx: 1,
+            dy: 1,
+            buttons: 1,
+        });
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn test_keys_not_coalesced() {
+        let mut queue = InputQueue::default();
+        queue.push(InputEvent::Key(0x1e));
+        queue.push(InputEvent::Key(0x1e));
+        queue.push(motion(1, 0));
+        queue.push(InputEvent::Key(0x9e));
+        queue.push(motion(1, 0));
+        assert_eq!(queue.len(), 5);
+    }
+
+    #[test]
+    fn test_backpressure() {
+        let mut queue = InputQueue::new(2);
+        assert!(queue.push(InputEvent::Key(0x1e)));
+        assert!(!queue.backpressure());
+        assert!(queue.push(InputEvent::Key(0x9e)));
+        assert!(queue.backpressure());
+        assert!(!queue.push(InputEvent::Key(0x1f)));
+        assert_eq!(queue.dropped(), 1);
+
+        assert_eq!(queue.pop(), Some(InputEvent::Key(0x1e)));
+        assert!(!queue.backpressure());
+    }
+
+    #[test]
+    fn test_script_delay() {
+        let clock = Rc::new(FixedClock::new(1000));
+        let mut script = InputScript::new(clock.clone());
+        script.push(ScriptEvent::Input(InputEvent::Key(0x1e)));
+        script.push(ScriptEvent::Delay(500));
+        script.push(ScriptEvent::Delay(0));
+        script.push_serial(b"ab");
+
+        assert_eq!(
+            script.next_event(),
+            Some(ScriptEvent::Input(InputEvent::Key(0x1e)))
+        );
+
+        // The delay starts when it reaches the front of the script
+        clock.advance(2000);
+        assert_eq!(script.next_event(), None);
+        clock.advance(499);
+        assert_eq!(script.next_event(), None);
+        clock.advance(1);
+        assert_eq!(script.next_event(), Some(ScriptEvent::Serial(b'a')));
+        assert_eq!(script.next_event(), Some(ScriptEvent::Serial(b'b')));
+        assert_eq!(script.next_event(), None);
+        assert!(script.is_finished());
+    }
+}
@@ -0,0 +1,371 @@
+use crate::ioapic::{DeliveryMode, DestinationMode};
+use alloc::collections::vec_deque::VecDeque;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt;
+
+/// The set of vCPUs targeted by an interrupt and how it should be delivered
+///
+/// This is the common currency between the interrupt controllers (the
+/// I/O APIC, local APIC, MSI, etc) and the VM. Each controller decodes its
+/// own register format in to an `InterruptDestination` and hands it to an
+/// `InterruptSink`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InterruptDestination {
+    /// Whether `destination` is an APIC ID or a logical address
+    pub mode: DestinationMode,
+
+    /// How the targeted vCPUs should act upon the interrupt
+    pub delivery: DeliveryMode,
+
+    /// An APIC ID (physical mode) or a set of logical APIC IDs (logical
+    /// mode, using the flat model)
+    pub destination: u8,
+}
+
+impl InterruptDestination {
+    /// The physical destination that targets every vCPU
+    pub const BROADCAST: u8 = 0xff;
+
+    /// A fixed interrupt targeting the vCPU with the given APIC ID
+    pub fn physical(apic_id: u8) -> Self {
+        Self {
+            mode: DestinationMode::Physical,
+            delivery: DeliveryMode::Fixed,
+            destination: apic_id,
+        }
+    }
+
+    /// A fixed interrupt targeting every vCPU with a logical APIC ID in
+    /// the given set
+    pub fn logical(logical_ids: u8) -> Self {
+        Self {
+            mode: DestinationMode::Logical,
+            delivery: DeliveryMode::Fixed,
+            destination: logical_ids,
+        }
+    }
+
+    /// Use the given delivery mode for this destination
+    pub fn with_delivery(self, delivery: DeliveryMode) -> Self {
+        Self { delivery, ..self }
+    }
+
+    /// Returns true if the given vCPU is part of this destination
+    pub fn includes(&self, vcpu: &VcpuApic) -> bool {
+        match self.mode {
+            DestinationMode::Physical => {
+                self.destination == Self::BROADCAST
+                    || self.destination == vcpu.apic_id
+            }
+            DestinationMode::Logical => self.destination & vcpu.logical_id != 0,
+        }
+    }
+
+    /// Resolve this destination to the indices of the targeted vCPUs
+    ///
+    /// For `LowestPriority` delivery, at most one vCPU is selected (the
+    /// first of those with the lowest task priority).
+    pub fn resolve(&self, vcpus: &[VcpuApic]) -> Vec<usize> {
+        let targets = vcpus
+            .iter()
+            .enumerate()
+            .filter(|(_, vcpu)| self.includes(vcpu));
+        match self.delivery {
+            DeliveryMode::LowestPriority => targets
+                .min_by_key(|(i, vcpu)| (vcpu.priority, *i))
+                .map(|(i, _)| vec![i])
+                .unwrap_or_default(),
+            _ => targets.map(|(i, _)| i).collect(),
+        }
+    }
+}
+
+/// The APIC addressing state of a single vCPU
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VcpuApic {
+    /// The (physical) local APIC ID
+    pub apic_id: u8,
+
+    /// The logical APIC ID (from the LDR)
+    pub logical_id: u8,
+
+    /// The task priority, used for lowest priority delivery
+    pub priority: u8,
+}
+
+impl VcpuApic {
+    /// Create the addressing state for a vCPU with the given APIC ID
+    ///
+    /// The logical ID defaults to the flat model bit for the APIC ID,
+    /// if there is one.
+    pub fn new(apic_id: u8) -> Self {
+        Self {
+            apic_id,
+            logical_id: if apic_id < 8 { 1 << apic_id } else { 0 },
+            priority: 0,
+        }
+    }
+}
+
+/// The interface used by the interrupt controllers to deliver an
+/// interrupt to the guest
+pub trait InterruptSink {
+    /// Deliver the interrupt `vector` to the vCPU(s) in `dest`
+    fn deliver(&self, dest: InterruptDestination, vector: u8);
+
+    /// End the highest priority interrupt in service on the vCPU with the
+    /// given APIC ID, returning its vector
+    ///
+    /// This is called when the guest writes the local APIC EOI register.
+    /// Sinks that do not track which interrupts are in service return
+    /// `None`.
+    fn end_of_interrupt(&self, _apic_id: u8) -> Option<u8> {
+        None
+    }
+}
+
+/// The interface used by a local APIC to broadcast the end of an
+/// interrupt to the I/O APIC(s), so level triggered inputs can interrupt
+/// again
+pub trait EoiSink {
+    fn end_of_interrupt(&self, vector: u8);
+}
+
+/// An interrupt acknowledged from an interrupt controller, ready to be
+/// injected in to a vCPU
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PendingInterrupt {
+    pub vector: u8,
+
+    /// The IRQ line the interrupt was requested on
+    pub irq: u8,
+}
+
+/// The interface used by devices to signal their interrupt request lines
+pub trait IrqSink {
+    /// Assert the given IRQ line
+    fn raise_irq(&self, irq: u8);
+
+    /// Deassert the given IRQ line (this only matters for level triggered
+    /// lines)
+    fn lower_irq(&self, _irq: u8) {}
+}
+
+/// The interface used by interrupt controllers to check the current level
+/// of a device's IRQ line
+///
+/// A level triggered line stays asserted until the condition in the
+/// device is cleared, so after the guest signals the end of an interrupt
+/// (EOI) on such a line, the controller checks the line again and raises
+/// the interrupt again if it is still asserted.
+pub trait IrqLineState {
+    fn line_asserted(&self, irq: u8) -> bool;
+}
+
+impl<T: IrqLineState> IrqLineState for RefCell<T> {
+    fn line_asserted(&self, irq: u8) -> bool {
+        // A device that is busy (i.e., the EOI is a side effect of one of
+        // its own accesses) updates its line itself when it is done
+        self.try_borrow()
+            .map_or(false, |dev| dev.line_asserted(irq))
+    }
+}
+
+/// The devices that drive each level triggered IRQ line
+///
+/// This is shared by the interrupt controllers, so whichever one the
+/// guest sends an EOI to can check the lines it serves.
+#[derive(Default)]
+pub struct LevelIrqLines {
+    sources: RefCell<Vec<(u8, Rc<dyn IrqLineState>)>>,
+}
+
+impl LevelIrqLines {
+    pub fn new() -> Rc<Self> {
+        Rc::new(Self::default())
+    }
+
+    /// Register a device that drives `irq` (a level triggered line may be
+    /// shared by several devices)
+    pub fn attach(&self, irq: u8, source: Rc<dyn IrqLineState>) {
+        self.sources.borrow_mut().push((irq, source));
+    }
+
+    /// Returns true if `irq` is driven by a registered device
+    pub fn is_level(&self, irq: u8) -> bool {
+        self.sources.borrow().iter().any(|(line, _)| *line == irq)
+    }
+
+    /// Returns true if any device driving `irq` is asserting it
+    pub fn asserted(&self, irq: u8) -> bool {
+        self.sources
+            .borrow()
+            .iter()
+            .any(|(line, source)| *line == irq && source.line_asserted(irq))
+    }
+}
+
+impl fmt::Debug for LevelIrqLines {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.sources.borrow().iter().map(|(line, _)| line))
+            .finish()
+    }
+}
+
+/// An `InterruptSink` that queues delivered interrupts for each vCPU
+/// until they can be injected
+///
+/// Fixed and lowest priority interrupts are considered in service from
+/// the time they are taken from the queue until the guest ends them with
+/// an EOI.
+pub struct PendingInterrupts {
+    vcpus: Vec<VcpuApic>,
+    pending: RefCell<Vec<VecDeque<(DeliveryMode, u8)>>>,
+    in_service: RefCell<Vec<Vec<u8>>>,
+}
+
+impl PendingInterrupts {
+    /// Create a new queue for the given vCPUs
+    pub fn new(vcpus: &[VcpuApic]) -> Self {
+        Self {
+            vcpus: vcpus.to_vec(),
+            pending: RefCell::new(vec![VecDeque::new(); vcpus.len()]),
+            in_service: RefCell::new(vec![vec![]; vcpus.len()]),
+        }
+    }
+
+    /// The addressing state for the vCPUs serviced by this queue
+    pub fn vcpus(&self) -> &[VcpuApic] {
+        &self.vcpus
+    }
+
+    /// Remove and return the next interrupt pending for the vCPU at `index`
+    pub fn pop(&self, index: usize) -> Option<(DeliveryMode, u8)> {
+        let next = self
+            .pending
+            .borrow_mut()
+            .get_mut(index)
+            .and_then(|queue| queue.pop_front());
+        match next {
+            Some((DeliveryMode::Fixed, vector))
+            | Some((DeliveryMode::LowestPriority, vector)) => {
+                self.in_service.borrow_mut()[index].push(vector)
+            }
+            _ => (),
+        }
+        next
+    }
+
+    /// Drop every pending and in service interrupt (e.g., on a reset)
+    pub fn clear(&self) {
+        for queue in self.pending.borrow_mut().iter_mut() {
+            queue.clear();
+        }
+        for vectors in self.in_service.borrow_mut().iter_mut() {
+            vectors.clear();
+        }
+    }
+}
+
+impl InterruptSink for PendingInterrupts {
+    fn deliver(&self, dest: InterruptDestination, vector: u8) {
+        let targets = dest.resolve(&self.vcpus);
+        if targets.is_empty() {
+            warn!(
+                "Dropping interrupt 0x{:x} with no target vcpu: {:?}",
+                vector, dest
+            );
+        }
+        let mut pending = self.pending.borrow_mut();
+        for target in targets {
+            pending[target].push_back((dest.delivery, vector));
+        }
+    }
+
+    fn end_of_interrupt(&self, apic_id: u8) -> Option<u8> {
+        let index = self.vcpus.iter().position(|v| v.apic_id == apic_id)?;
+        let mut in_service = self.in_service.borrow_mut();
+        let vectors = &mut in_service[index];
+
+        // The highest vector has the highest priority
+        let (highest, _) =
+            vectors.iter().enumerate().max_by_key(|(_, v)| **v)?;
+        Some(vectors.swap_remove(highest))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_vcpus() -> Vec<VcpuApic> {
+        (0..4).map(VcpuApic::new).collect()
+    }
+
+    #[test]
+    fn test_physical_destination() {
+        let vcpus = test_vcpus();
+        assert_eq!(InterruptDestination::physical(2).resolve(&vcpus), [2]);
+        assert!(InterruptDestination::physical(7).resolve(&vcpus).is_empty());
+        assert_eq!(
+            InterruptDestination::physical(InterruptDestination::BROADCAST)
+                .resolve(&vcpus),
+            [0, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_logical_destination() {
+        let vcpus = test_vcpus();
+        assert_eq!(
+            InterruptDestination::logical(0b1010).resolve(&vcpus),
+            [1, 3]
+        );
+        assert!(InterruptDestination::logical(0).resolve(&vcpus).is_empty());
+    }
+
+    #[test]
+    fn test_lowest_priority_destination() {
+        let mut vcpus = test_vcpus();
+        vcpus[0].priority = 0x20;
+        vcpus[1].priority = 0x10;
+        vcpus[2].priority = 0x10;
+        let dest = InterruptDestination::logical(0b0111)
+            .with_delivery(DeliveryMode::LowestPriority);
+        assert_eq!(dest.resolve(&vcpus), [1]);
+    }
+
+    #[test]
+    fn test_pending_interrupts() {
+        let sink = PendingInterrupts::new(&test_vcpus());
+        sink.deliver(InterruptDestination::logical(0b0011), 0x30);
+        sink.deliver(InterruptDestination::physical(1), 0x31);
+
+        assert_eq!(sink.pop(0), Some((DeliveryMode::Fixed, 0x30)));
+        assert_eq!(sink.pop(0), None);
+        assert_eq!(sink.pop(1), Some((DeliveryMode::Fixed, 0x30)));
+        assert_eq!(sink.pop(1), Some((DeliveryMode::Fixed, 0x31)));
+        assert_eq!(sink.pop(2), None);
+    }
+
+    #[test]
+    fn test_pending_interrupts_eoi() {
+        let sink = PendingInterrupts::new(&test_vcpus());
+        sink.deliver(InterruptDestination::physical(1), 0x30);
+        sink.deliver(InterruptDestination::physical(1), 0x50);
+        sink.deliver(InterruptDestination::physical(1), 0x40);
+
+        // Nothing is in service until it is taken from the queue
+        assert_eq!(sink.end_of_interrupt(1), None);
+        while sink.pop(1).is_some() {}
+
+        assert_eq!(sink.end_of_interrupt(0), None);
+        assert_eq!(sink.end_of_interrupt(1), Some(0x50));
+        assert_eq!(sink.end_of_interrupt(1), Some(0x40));
+        assert_eq!(sink.end_of_interrupt(1), Some(0x30));
+        assert_eq!(sink.end_of_interrupt(1), None);
+    }
+}
@@ -0,0 +1,444 @@
+use crate::device::interrupt::{
+    EoiSink, InterruptDestination, InterruptSink, LevelIrqLines,
+};
+use crate::device::irq_router::IrqOverride;
+use crate::device::{
+    DeviceKind, DeviceRegion, EmulatedDevice, MemReadRequest, MemWriteRequest,
+};
+use crate::error::{Error, Result};
+use crate::ioapic::{IoRedTblEntry, TriggerMode, IOREDTBL_RW_MASK};
+use crate::memory::{GuestAddressSpaceViewMut, GuestPhysAddr};
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::convert::{TryFrom, TryInto};
+
+const IOAPIC_VERSION: u32 = 0x11;
+const IOAPIC_PINS: usize = 24;
+
+const IOREGSEL_OFFSET: u64 = 0x00;
+const IOWIN_OFFSET: u64 = 0x10;
+
+const IOAPICID: u8 = 0x00;
+const IOAPICVER: u8 = 0x01;
+const IOAPICARB: u8 = 0x02;
+const IOREDTBL_OFFSET: u8 = 0x10;
+
+const IOREDTBL_REMOTE_IRR: u64 = 1 << 14;
+const IOREDTBL_MASKED: u64 = 1 << 16;
+
+/// An emulated I/O APIC
+///
+/// Interrupts raised on the I/O APIC's input pins are routed according to
+/// the (guest programmed) redirection table and delivered through the
+/// `InterruptSink` provided at construction.
+pub struct IoApic {
+    id: u8,
+    base: GuestPhysAddr,
+    selected: u8,
+    redirection: [u64; IOAPIC_PINS],
+    sink: Rc<dyn InterruptSink>,
+    level_lines: Option<Rc<LevelIrqLines>>,
+    irq_overrides: Option<Rc<RefCell<Vec<IrqOverride>>>>,
+
+    // The level triggered pins whose interrupt has been delivered, but
+    // not yet ended with an EOI (one bit per pin)
+    remote_irr: u32,
+}
+
+impl IoApic {
+    pub const DEFAULT_BASE: u64 = 0xfec00000;
+
+    /// The number of interrupt inputs (GSIs 0 to 23)
+    pub const PIN_COUNT: usize = IOAPIC_PINS;
+
+    pub fn new(sink: Rc<dyn InterruptSink>) -> Box<Self> {
+        Box::new(Self {
+            id: 0,
+            base: GuestPhysAddr::new(Self::DEFAULT_BASE),
+            selected: 0,
+            redirection: [IOREDTBL_MASKED; IOAPIC_PINS],
+            sink,
+            level_lines: None,
+            irq_overrides: None,
+            remote_irr: 0,
+        })
+    }
+
+    /// Set the level triggered lines that are checked again when the guest
+    /// ends an interrupt on them
+    pub fn set_level_lines(&mut self, lines: Rc<LevelIrqLines>) {
+        self.level_lines = Some(lines);
+    }
+
+    /// Set the IRQs that are connected to a different input pin than
+    /// their own number
+    ///
+    /// The level lines are keyed by IRQ, so this is needed to find the
+    /// lines behind a pin. This is shared with the `IrqRouter`, which
+    /// sets it up.
+    pub fn set_irq_overrides(
+        &mut self,
+        overrides: Rc<RefCell<Vec<IrqOverride>>>,
+    ) {
+        self.irq_overrides = Some(overrides);
+    }
+
+    // Returns true if any level line connected to `pin` is asserted
+    fn pin_asserted(&self, pin: u8) -> bool {
+        let lines = match &self.level_lines {
+            Some(lines) => lines,
+            None => return false,
+        };
+        let overrides = match &self.irq_overrides {
+            Some(overrides) => overrides.borrow(),
+            None => return lines.asserted(pin),
+        };
+
+        // The IRQ of the same number is connected unless it is overridden
+        let identity = overrides.iter().all(|o| o.irq != pin);
+        (identity && lines.asserted(pin))
+            || overrides
+                .iter()
+                .any(|o| o.gsi == pin as u32 && lines.asserted(o.irq))
+    }
+
+    /// Handle the end of interrupt `vector` (broadcast by a local APIC)
+    ///
+    /// Every level triggered pin using the vector may interrupt again, and
+    /// any whose line is still asserted does so immediately.
+    pub fn end_of_interrupt(&mut self, vector: u8) {
+        for pin in 0..IOAPIC_PINS as u8 {
+            if self.remote_irr & (1 << pin) == 0 {
+                continue;
+            }
+            match self.redirection_entry(pin) {
+                Ok(entry) if entry.vector() == vector => (),
+                _ => continue,
+            }
+            self.remote_irr &= !(1 << pin);
+
+            if self.pin_asserted(pin) {
+                if let Err(e) = self.raise_irq(pin) {
+                    warn!("Failed to raise I/O APIC irq: {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// The redirection table entry for the given input pin
+    pub fn redirection_entry(&self, pin: u8) -> Result<IoRedTblEntry> {
+        let bits = self.redirection.get(pin as usize).ok_or_else(|| {
+            Error::InvalidValue(format!("Invalid I/O APIC pin: {}", pin))
+        })?;
+        IoRedTblEntry::try_from(*bits)
+    }
+
+    /// Signal an interrupt on the given input pin
+    pub fn raise_irq(&mut self, pin: u8) -> Result<()> {
+        let entry = self.redirection_entry(pin)?;
+        if entry.masked() || self.remote_irr & (1 << pin) != 0 {
+            return Ok(());
+        }
+        if entry.trigger_mode() == TriggerMode::Level {
+            self.remote_irr |= 1 << pin;
+        }
+
+        let dest = InterruptDestination {
+            mode: entry.destination_mode(),
+            delivery: entry.delivery_mode(),
+            destination: entry.destination(),
+        };
+        self.sink.deliver(dest, entry.vector());
+        Ok(())
+    }
+
+    fn read_register(&self, reg: u8) -> u32 {
+        match reg {
+            IOAPICID | IOAPICARB => (self.id as u32) << 24,
+            IOAPICVER => ((IOAPIC_PINS as u32 - 1) << 16) | IOAPIC_VERSION,
+            reg if reg >= IOREDTBL_OFFSET => {
+                let index = ((reg - IOREDTBL_OFFSET) / 2) as usize;
+                let remote_irr = if index < IOAPIC_PINS
+                    && self.remote_irr & (1 << index) != 0
+                {
+                    IOREDTBL_REMOTE_IRR
+                } else {
+                    0
+                };
+                match self.redirection.get(index) {
+                    Some(entry) if reg % 2 == 0 => (*entry | remote_irr) as u32,
+                    Some(entry) => (*entry >> 32) as u32,
+                    None => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, reg: u8, val: u32) {
+        match reg {
+            IOAPICID => self.id = ((val >> 24) & 0x0f) as u8,
+            reg if reg >= IOREDTBL_OFFSET => {
+                let index = ((reg - IOREDTBL_OFFSET) / 2) as usize;
+                let entry = match self.redirection.get_mut(index) {
+                    Some(entry) => entry,
+                    None => return,
+                };
+                let updated = if reg % 2 == 0 {
+                    (*entry & !0xffffffff) | val as u64
+                } else {
+                    (*entry & 0xffffffff) | (val as u64) << 32
+                };
+                *entry = updated & IOREDTBL_RW_MASK;
+            }
+            _ => (),
+        }
+    }
+}
+
+impl EoiSink for RefCell<IoApic> {
+    fn end_of_interrupt(&self, vector: u8) {
+        match self.try_borrow_mut() {
+            Ok(mut ioapic) => ioapic.end_of_interrupt(vector),
+            Err(_) => warn!("Dropping EOI 0x{:x} for a busy I/O APIC", vector),
+        }
+    }
+}
+
+impl EmulatedDevice for IoApic {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::MemIo(self.base..=(self.base + 0xfff))]
+    }
+
+    fn kind(&self) -> Option<DeviceKind> {
+        Some(DeviceKind::IoApic)
+    }
+
+    fn depends_on(&self) -> Vec<DeviceKind> {
+        vec![DeviceKind::LocalApic]
+    }
+
+    fn reset(&mut self) {
+        self.id = 0;
+        self.selected = 0;
+        self.redirection = [IOREDTBL_MASKED; IOAPIC_PINS];
+        self.remote_irr = 0;
+    }
+
+    fn on_mem_read(
+        &mut self,
+        addr: GuestPhysAddr,
+        mut data: MemReadRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let val = match addr.as_u64() - self.base.as_u64() {
+            IOREGSEL_OFFSET => self.selected as u32,
+            IOWIN_OFFSET => self.read_register(self.selected),
+            offset => {
+                info!("Read of unknown I/O APIC offset 0x{:x}", offset);
+                0
+            }
+        };
+        data.copy_from_u32(val);
+        Ok(())
+    }
+
+    fn on_mem_write(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemWriteRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        match addr.as_u64() - self.base.as_u64() {
+            IOREGSEL_OFFSET => {
+                self.selected = *data.as_slice().last().ok_or_else(|| {
+                    Error::InvalidValue("Empty IOREGSEL write".into())
+                })?;
+            }
+            IOWIN_OFFSET => {
+                let val: u32 = data.try_into()?;
+                self.write_register(self.selected, val);
+            }
+            offset => {
+                info!("Write to unknown I/O APIC offset 0x{:x}", offset);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::interrupt::{IrqLineState, PendingInterrupts, VcpuApic};
+    use crate::device::test_util::define_test_view;
+    use crate::ioapic::{DeliveryMode, DestinationMode};
+    use core::cell::{Cell, RefCell};
+
+    #[derive(Default)]
+    struct MockSink {
+        delivered: RefCell<Vec<(InterruptDestination, u8)>>,
+    }
+
+    impl InterruptSink for MockSink {
+        fn deliver(&self, dest: InterruptDestination, vector: u8) {
+            self.delivered.borrow_mut().push((dest, vector));
+        }
+    }
+
+    fn write_reg(ioapic: &mut IoApic, reg: u8, val: u32) {
+        let base = IoApic::DEFAULT_BASE;
+        let sel = [reg];
+        let request = MemWriteRequest::new(&sel);
+        ioapic
+            .on_mem_write(GuestPhysAddr::new(base), request, define_test_view())
+            .unwrap();
+        let win = val.to_be_bytes();
+        let request = MemWriteRequest::new(&win);
+        ioapic
+            .on_mem_write(
+                GuestPhysAddr::new(base + IOWIN_OFFSET),
+                request,
+                define_test_view(),
+            )
+            .unwrap();
+    }
+
+    fn read_reg(ioapic: &mut IoApic, reg: u8) -> u32 {
+        let base = IoApic::DEFAULT_BASE;
+        let sel = [reg];
+        let request = MemWriteRequest::new(&sel);
+        ioapic
+            .on_mem_write(GuestPhysAddr::new(base), request, define_test_view())
+            .unwrap();
+        let mut win = [0u8; 4];
+        let request = MemReadRequest::new(&mut win);
+        ioapic
+            .on_mem_read(
+                GuestPhysAddr::new(base + IOWIN_OFFSET),
+                request,
+                define_test_view(),
+            )
+            .unwrap();
+        u32::from_be_bytes(win)
+    }
+
+    fn program_pin(ioapic: &mut IoApic, pin: u8, entry: u64) {
+        let reg = IOREDTBL_OFFSET + pin * 2;
+        write_reg(ioapic, reg + 1, (entry >> 32) as u32);
+        write_reg(ioapic, reg, entry as u32);
+    }
+
+    // Fixed delivery, logical destination 0b0110, vector 0x41
+    const LOGICAL_ENTRY: u64 = 0x06000000_00000841;
+
+    #[test]
+    fn test_ioapic_version() {
+        let mut ioapic = IoApic::new(Rc::new(MockSink::default()));
+        assert_eq!(read_reg(&mut ioapic, IOAPICVER), 0x00170011);
+    }
+
+    #[test]
+    fn test_ioapic_redirection_readback() {
+        let mut ioapic = IoApic::new(Rc::new(MockSink::default()));
+        assert_eq!(read_reg(&mut ioapic, IOREDTBL_OFFSET + 2), 0x10000);
+
+        program_pin(&mut ioapic, 1, LOGICAL_ENTRY);
+        assert_eq!(read_reg(&mut ioapic, IOREDTBL_OFFSET + 2), 0x841);
+        assert_eq!(read_reg(&mut ioapic, IOREDTBL_OFFSET + 3), 0x06000000);
+    }
+
+    #[test]
+    fn test_ioapic_masked_pin() {
+        let sink = Rc::new(MockSink::default());
+        let mut ioapic = IoApic::new(sink.clone());
+        ioapic.raise_irq(1).unwrap();
+        assert!(sink.delivered.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_ioapic_logical_delivery() {
+        let sink = Rc::new(MockSink::default());
+        let mut ioapic = IoApic::new(sink.clone());
+        program_pin(&mut ioapic, 1, LOGICAL_ENTRY);
+        ioapic.raise_irq(1).unwrap();
+
+        let delivered = sink.delivered.borrow();
+        assert_eq!(delivered.len(), 1);
+
+        let (dest, vector) = delivered[0];
+        assert_eq!(vector, 0x41);
+        assert_eq!(dest.mode, DestinationMode::Logical);
+        assert_eq!(dest.delivery, DeliveryMode::Fixed);
+
+        let vcpus: Vec<_> = (0..4).map(VcpuApic::new).collect();
+        assert_eq!(dest.resolve(&vcpus), [1, 2]);
+    }
+
+    #[test]
+    fn test_ioapic_lowest_priority_delivery() {
+        let vcpus: Vec<_> = (0..4).map(VcpuApic::new).collect();
+        let sink = Rc::new(PendingInterrupts::new(&vcpus));
+        let mut ioapic = IoApic::new(sink.clone());
+
+        // Lowest priority delivery to logical destination 0b1100
+        program_pin(&mut ioapic, 3, 0x0c000000_00000952);
+        ioapic.raise_irq(3).unwrap();
+
+        assert_eq!(sink.pop(2), Some((DeliveryMode::LowestPriority, 0x52)));
+        assert_eq!(sink.pop(3), None);
+    }
+
+    // A device holding a level triggered line
+    #[derive(Default)]
+    struct MockLevelDevice {
+        asserted: Cell<bool>,
+    }
+
+    impl IrqLineState for MockLevelDevice {
+        fn line_asserted(&self, _irq: u8) -> bool {
+            self.asserted.get()
+        }
+    }
+
+    // Fixed delivery, level triggered, physical destination 0, vector 0x51
+    const LEVEL_ENTRY: u64 = 0x00000000_00008051;
+
+    #[test]
+    fn test_ioapic_level_eoi() {
+        let sink = Rc::new(MockSink::default());
+        let lines = LevelIrqLines::new();
+        let dev = Rc::new(MockLevelDevice::default());
+        lines.attach(11, dev.clone());
+        let mut ioapic = IoApic::new(sink.clone());
+        ioapic.set_level_lines(lines);
+        program_pin(&mut ioapic, 11, LEVEL_ENTRY);
+
+        dev.asserted.set(true);
+        ioapic.raise_irq(11).unwrap();
+        assert_eq!(sink.delivered.borrow().len(), 1);
+
+        // Not delivered again until the EOI (the remote IRR is set)
+        ioapic.raise_irq(11).unwrap();
+        assert_eq!(sink.delivered.borrow().len(), 1);
+        let entry = IOREDTBL_OFFSET + 22;
+        assert_ne!(read_reg(&mut ioapic, entry) & 1 << 14, 0);
+
+        // An EOI of another vector has no effect
+        ioapic.end_of_interrupt(0x41);
+        assert_eq!(sink.delivered.borrow().len(), 1);
+
+        // The line is still asserted, so the EOI delivers it again
+        ioapic.end_of_interrupt(0x51);
+        assert_eq!(sink.delivered.borrow().len(), 2);
+        assert_eq!(sink.delivered.borrow()[1].1, 0x51);
+
+        // Once the device clears the line, the EOI has no effect
+        dev.asserted.set(false);
+        ioapic.end_of_interrupt(0x51);
+        assert_eq!(sink.delivered.borrow().len(), 2);
+        assert_eq!(read_reg(&mut ioapic, entry) & 1 << 14, 0);
+    }
+}
@@ -0,0 +1,422 @@
+use crate::device::interrupt::IrqSink;
+use crate::device::ioapic::IoApic;
+use crate::device::pic::Pic8259;
+use crate::device::{
+    DeviceKind, DeviceRegion, EmulatedDevice, Port, PortReadRequest,
+    PortWriteRequest,
+};
+use crate::error::{Error, Result};
+use crate::memory::GuestAddressSpaceViewMut;
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::convert::TryInto;
+
+/// The interrupt controller that device IRQs are delivered to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InterruptMode {
+    /// IRQs go to the 8259 PIC (the state at reset)
+    Pic,
+    /// IRQs go to the I/O APIC ("symmetric I/O mode")
+    Apic,
+}
+
+/// A legacy (ISA) IRQ that is connected to a different I/O APIC input
+/// than its own number, as described to the guest by an interrupt source
+/// override in the MADT
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IrqOverride {
+    pub irq: u8,
+    pub gsi: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum IrqEvent {
+    Raise(u8),
+    Lower(u8),
+}
+
+/// An `IrqSink` that delivers device IRQs to whichever interrupt
+/// controller is selected by the IMCR
+///
+/// In APIC mode, IRQs are delivered to the I/O APIC input of the same
+/// number unless there is an `IrqOverride` for them. As on a PC, the PIT
+/// (IRQ0) is connected to GSI2 by default.
+///
+/// Every IRQ is delivered to exactly one controller. If that controller
+/// is busy (for instance, because it is the device raising the IRQ),
+/// the IRQ is queued and delivered on the next call into the router,
+/// rather than failing to borrow the controller.
+pub struct IrqRouter {
+    pic: Rc<RefCell<Pic8259>>,
+    ioapic: Rc<RefCell<IoApic>>,
+    mode: Cell<InterruptMode>,
+    overrides: Rc<RefCell<Vec<IrqOverride>>>,
+    deferred: RefCell<Vec<IrqEvent>>,
+}
+
+impl IrqRouter {
+    const PIT_IRQ: u8 = 0;
+    const PIT_GSI: u32 = 2;
+
+    /// Create a router for the given controllers
+    ///
+    /// The overrides are shared with the I/O APIC, so it can find the
+    /// level triggered IRQs behind each of its pins.
+    pub fn new(
+        pic: Rc<RefCell<Pic8259>>,
+        ioapic: Rc<RefCell<IoApic>>,
+    ) -> Rc<Self> {
+        let overrides = Rc::new(RefCell::new(vec![IrqOverride {
+            irq: Self::PIT_IRQ,
+            gsi: Self::PIT_GSI,
+        }]));
+        ioapic.borrow_mut().set_irq_overrides(overrides.clone());
+        Rc::new(Self {
+            pic,
+            ioapic,
+            mode: Cell::new(InterruptMode::Pic),
+            overrides,
+            deferred: RefCell::new(vec![]),
+        })
+    }
+
+    /// Connect `irq` to the I/O APIC input `gsi` (replacing any existing
+    /// override of `irq`)
+    pub fn set_override(&self, irq: u8, gsi: u32) -> Result<()> {
+        if gsi >= IoApic::PIN_COUNT as u32 {
+            return Err(Error::InvalidValue(format!(
+                "Invalid GSI {} for IRQ {}",
+                gsi, irq
+            )));
+        }
+        let mut overrides = self.overrides.borrow_mut();
+        overrides.retain(|o| o.irq != irq);
+        if gsi != irq as u32 {
+            overrides.push(IrqOverride { irq, gsi });
+        }
+        Ok(())
+    }
+
+    /// The IRQs that are not connected to the I/O APIC input of the same
+    /// number, for the interrupt source overrides in the MADT
+    pub fn overrides(&self) -> Vec<IrqOverride> {
+        self.overrides.borrow().clone()
+    }
+
+    // The I/O APIC input that `irq` is connected to
+    fn gsi(&self, irq: u8) -> u8 {
+        self.overrides
+            .borrow()
+            .iter()
+            .find(|o| o.irq == irq)
+            .map_or(irq, |o| o.gsi as u8)
+    }
+
+    pub fn mode(&self) -> InterruptMode {
+        self.mode.get()
+    }
+
+    /// Select the controller that receives IRQs
+    ///
+    /// Any queued IRQs are delivered to the old controller first, so none
+    /// are lost (or delivered twice) across the switch.
+    pub fn set_mode(&self, mode: InterruptMode) {
+        self.flush();
+        if mode != self.mode.get() {
+            info!("Switching interrupt mode to {:?}", mode);
+        }
+        self.mode.set(mode);
+    }
+
+    /// Deliver any IRQs that were queued while their controller was busy
+    pub fn flush(&self) {
+        let pending: Vec<IrqEvent> = {
+            let mut deferred = self.deferred.borrow_mut();
+            if deferred.is_empty() {
+                return;
+            }
+            deferred.drain(..).collect()
+        };
+        for event in pending {
+            self.route(event);
+        }
+    }
+
+    fn route(&self, event: IrqEvent) {
+        let delivered = match self.mode.get() {
+            InterruptMode::Pic => match self.pic.try_borrow_mut() {
+                Ok(mut pic) => {
+                    match event {
+                        IrqEvent::Raise(irq) => pic.raise_irq(irq),
+                        IrqEvent::Lower(irq) => pic.lower_irq(irq),
+                    }
+                    true
+                }
+                Err(_) => false,
+            },
+            InterruptMode::Apic => match self.ioapic.try_borrow_mut() {
+                Ok(mut ioapic) => {
+                    // The I/O APIC does not latch the line level, so
+                    // only the assertion matters
+                    if let IrqEvent::Raise(irq) = event {
+                        if let Err(e) = ioapic.raise_irq(self.gsi(irq)) {
+                            warn!("Failed to raise I/O APIC irq: {:?}", e);
+                        }
+                    }
+                    true
+                }
+                Err(_) => false,
+            },
+        };
+        if !delivered {
+            self.deferred.borrow_mut().push(event);
+        }
+    }
+}
+
+impl IrqSink for IrqRouter {
+    fn raise_irq(&self, irq: u8) {
+        self.flush();
+        self.route(IrqEvent::Raise(irq));
+    }
+
+    fn lower_irq(&self, irq: u8) {
+        self.flush();
+        self.route(IrqEvent::Lower(irq));
+    }
+}
+
+/// The Interrupt Mode Configuration Register
+///
+/// Writing 0x70 to port 0x22 selects the IMCR, then bit 0 of port 0x23
+/// selects PIC (0) or APIC (1) mode. See the MultiProcessor
+/// Specification, Section 3.6.2.1.
+pub struct Imcr {
+    router: Rc<IrqRouter>,
+    index: u8,
+}
+
+impl Imcr {
+    const IMCR_INDEX: Port = 0x22;
+    const IMCR_DATA: Port = 0x23;
+
+    const IMCR_SELECT: u8 = 0x70;
+    const IMCR_APIC_MODE: u8 = 1 << 0;
+
+    pub fn new(router: Rc<IrqRouter>) -> Box<Self> {
+        Box::new(Self { router, index: 0 })
+    }
+}
+
+impl EmulatedDevice for Imcr {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::PortIo(Self::IMCR_INDEX..=Self::IMCR_DATA)]
+    }
+
+    fn depends_on(&self) -> Vec<DeviceKind> {
+        vec![DeviceKind::Pic, DeviceKind::IoApic]
+    }
+
+    fn reset(&mut self) {
+        self.index = 0;
+        self.router.set_mode(InterruptMode::Pic);
+    }
+
+    fn on_port_read(
+        &mut self,
+        port: Port,
+        mut val: PortReadRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let data = match port {
+            Self::IMCR_INDEX => self.index,
+            _ if self.index != Self::IMCR_SELECT => 0xff,
+            _ => match self.router.mode() {
+                InterruptMode::Pic => 0,
+                InterruptMode::Apic => Self::IMCR_APIC_MODE,
+            },
+        };
+        val.copy_from_u32(data as u32);
+        Ok(())
+    }
+
+    fn on_port_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let data: u8 = val.try_into()?;
+        match port {
+            Self::IMCR_INDEX => self.index = data,
+            _ if self.index != Self::IMCR_SELECT => {
+                info!("Write to unknown IMCR register 0x{:x}", self.index)
+            }
+            _ => self.router.set_mode(if data & Self::IMCR_APIC_MODE != 0 {
+                InterruptMode::Apic
+            } else {
+                InterruptMode::Pic
+            }),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::interrupt::{PendingInterrupts, VcpuApic};
+    use crate::device::test_util::define_test_view;
+    use crate::device::MemWriteRequest;
+    use crate::ioapic::DeliveryMode;
+    use crate::memory::GuestPhysAddr;
+
+    struct TestRouter {
+        pic: Rc<RefCell<Pic8259>>,
+        ioapic: Rc<RefCell<IoApic>>,
+        sink: Rc<PendingInterrupts>,
+        router: Rc<IrqRouter>,
+    }
+
+    fn test_router() -> TestRouter {
+        let sink = Rc::new(PendingInterrupts::new(&[VcpuApic::new(0)]));
+        let pic = Rc::new(RefCell::new(*Pic8259::new()));
+        let ioapic = Rc::new(RefCell::new(*IoApic::new(sink.clone())));
+        let router = IrqRouter::new(pic.clone(), ioapic.clone());
+        TestRouter {
+            pic,
+            ioapic,
+            sink,
+            router,
+        }
+    }
+
+    // Route I/O APIC pin `pin` to `vector` on APIC 0
+    fn program_pin(ioapic: &Rc<RefCell<IoApic>>, pin: u8, vector: u8) {
+        let write_reg = |reg: u8, val: u32| {
+            let base = IoApic::DEFAULT_BASE;
+            ioapic
+                .borrow_mut()
+                .on_mem_write(
+                    GuestPhysAddr::new(base),
+                    MemWriteRequest::new(&[reg]),
+                    define_test_view(),
+                )
+                .unwrap();
+            ioapic
+                .borrow_mut()
+                .on_mem_write(
+                    GuestPhysAddr::new(base + 0x10),
+                    MemWriteRequest::new(&val.to_be_bytes()),
+                    define_test_view(),
+                )
+                .unwrap();
+        };
+        write_reg(0x10 + pin * 2 + 1, 0);
+        write_reg(0x10 + pin * 2, vector as u32);
+    }
+
+    fn write_imcr(imcr: &mut Imcr, port: Port, val: u8) {
+        imcr.on_port_write(
+            port,
+            PortWriteRequest::OneByte(&[val]),
+            define_test_view(),
+        )
+        .unwrap();
+    }
+
+    fn read_imcr(imcr: &mut Imcr) -> u8 {
+        let mut arr = [0u8];
+        imcr.on_port_read(
+            Imcr::IMCR_DATA,
+            PortReadRequest::OneByte(&mut arr),
+            define_test_view(),
+        )
+        .unwrap();
+        arr[0]
+    }
+
+    #[test]
+    fn test_pic_mode_routing() {
+        let test = test_router();
+        program_pin(&test.ioapic, 4, 0x34);
+        assert_eq!(test.router.mode(), InterruptMode::Pic);
+
+        test.router.raise_irq(4);
+        test.router.raise_irq(12);
+        assert_eq!(
+            test.pic.borrow().requested_irqs(),
+            1 << 12 | 1 << 4 | 1 << 2
+        );
+        assert_eq!(test.sink.pop(0), None);
+
+        test.router.lower_irq(12);
+        assert_eq!(test.pic.borrow().requested_irqs(), 1 << 4);
+    }
+
+    #[test]
+    fn test_apic_mode_routing() {
+        let test = test_router();
+        program_pin(&test.ioapic, 4, 0x34);
+        let mut imcr = Imcr::new(test.router.clone());
+
+        assert_eq!(read_imcr(&mut imcr), 0xff);
+        write_imcr(&mut imcr, Imcr::IMCR_INDEX, Imcr::IMCR_SELECT);
+        assert_eq!(read_imcr(&mut imcr), 0);
+        write_imcr(&mut imcr, Imcr::IMCR_DATA, Imcr::IMCR_APIC_MODE);
+        assert_eq!(test.router.mode(), InterruptMode::Apic);
+        assert_eq!(read_imcr(&mut imcr), Imcr::IMCR_APIC_MODE);
+
+        test.router.raise_irq(4);
+        assert_eq!(test.sink.pop(0), Some((DeliveryMode::Fixed, 0x34)));
+        assert_eq!(test.pic.borrow().requested_irqs(), 0);
+
+        // And back to PIC mode
+        write_imcr(&mut imcr, Imcr::IMCR_DATA, 0);
+        assert_eq!(test.router.mode(), InterruptMode::Pic);
+        test.router.raise_irq(4);
+        assert_eq!(test.sink.pop(0), None);
+        assert_eq!(test.pic.borrow().requested_irqs(), 1 << 4);
+    }
+
+    #[test]
+    fn test_pit_override() {
+        let test = test_router();
+        program_pin(&test.ioapic, 0, 0x30);
+        program_pin(&test.ioapic, 2, 0x32);
+        assert_eq!(test.router.overrides(), [IrqOverride { irq: 0, gsi: 2 }]);
+
+        // The PIT is IRQ0 on the PIC, but GSI2 on the I/O APIC
+        test.router.raise_irq(0);
+        assert_eq!(test.pic.borrow().requested_irqs(), 1 << 0);
+        test.router.set_mode(InterruptMode::Apic);
+        test.router.raise_irq(0);
+        assert_eq!(test.sink.pop(0), Some((DeliveryMode::Fixed, 0x32)));
+        assert_eq!(test.sink.pop(0), None);
+
+        // Removing the override connects IRQ0 to GSI0
+        test.router.set_override(0, 0).unwrap();
+        assert!(test.router.overrides().is_empty());
+        test.router.raise_irq(0);
+        assert_eq!(test.sink.pop(0), Some((DeliveryMode::Fixed, 0x30)));
+        assert!(test.router.set_override(0, 24).is_err());
+    }
+
+    #[test]
+    fn test_busy_controller_is_deferred() {
+        let test = test_router();
+        {
+            let _busy = test.pic.borrow_mut();
+            test.router.raise_irq(1);
+        }
+        assert_eq!(test.pic.borrow().requested_irqs(), 0);
+
+        // Queued IRQs are delivered to the controller that was selected
+        // when they were raised, even across a mode switch
+        test.router.set_mode(InterruptMode::Apic);
+        assert_eq!(test.pic.borrow().requested_irqs(), 1 << 1);
+        assert_eq!(test.sink.pop(0), None);
+    }
+}
@@ -1,20 +1,346 @@
+use crate::device::fifo::DeviceFifo;
+use crate::device::input::{InputEvent, InputQueue, InputScript, ScriptEvent};
+use crate::device::reset::{ResetSignal, ResetSource};
 use crate::device::{
-    DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
+    DeviceKind, DeviceRegion, EmulatedDevice, Port, PortReadRequest,
+    PortWriteRequest,
 };
 use crate::error::Result;
 use crate::memory::GuestAddressSpaceViewMut;
+use crate::time::{ClockSource, SystemClock};
 use alloc::boxed::Box;
+use alloc::rc::Rc;
 use alloc::vec::Vec;
+use core::convert::TryInto;
 
-#[derive(Default, Debug)]
-pub struct Keyboard8042;
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_SYSTEM: u8 = 1 << 2;
+const STATUS_AUX_DATA: u8 = 1 << 5;
+
+const MOUSE_PACKET_ALWAYS_ONE: u8 = 1 << 3;
+const MOUSE_PACKET_X_SIGN: u8 = 1 << 4;
+const MOUSE_PACKET_Y_SIGN: u8 = 1 << 5;
+const MOUSE_MIN_DELTA: i32 = -256;
+const MOUSE_MAX_DELTA: i32 = 255;
+
+// Controller commands (written to the status port)
+const CTRL_CMD_READ_COMMAND_BYTE: u8 = 0x20;
+const CTRL_CMD_WRITE_COMMAND_BYTE: u8 = 0x60;
+const CTRL_CMD_DISABLE_AUX: u8 = 0xa7;
+const CTRL_CMD_ENABLE_AUX: u8 = 0xa8;
+const CTRL_CMD_TEST_AUX: u8 = 0xa9;
+const CTRL_CMD_WRITE_OUTPUT_PORT: u8 = 0xd1;
+const CTRL_CMD_PULSE_OUTPUT_PORT: u8 = 0xf0;
+
+// Bit 0 of the output port is the (active low) system reset line
+const OUTPUT_PORT_RESET: u8 = 1 << 0;
+
+// The command byte bit that disables the aux port clock
+const COMMAND_BYTE_AUX_DISABLE: u8 = 1 << 5;
+
+// Both interrupts enabled, the system flag set, and translation on
+const DEFAULT_COMMAND_BYTE: u8 = 0x47;
+
+// The result of an aux interface test with no errors
+const AUX_TEST_PASSED: u8 = 0x00;
+
+const KBD_CMD_SET_TYPEMATIC: u8 = 0xf3;
+const KBD_ACK: u8 = 0xfa;
+const SCANCODE_EXTENDED: u8 = 0xe0;
+const SCANCODE_BREAK: u8 = 1 << 7;
+
+// 10.9 characters per second after a 500ms delay
+const DEFAULT_TYPEMATIC: u8 = 0x2b;
+
+// The number of bytes the keyboard can buffer for the controller
+const OUTPUT_BUFFER_SIZE: usize = 16;
+
+/// A single byte in the output buffer and whether it is from the aux
+/// (mouse) device
+#[derive(Clone, Copy, Debug)]
+struct OutputByte {
+    value: u8,
+    aux: bool,
+}
+
+/// A key that is held down, and will repeat at the typematic rate
+#[derive(Clone, Copy, Debug)]
+struct HeldKey {
+    code: u8,
+    extended: bool,
+    next_repeat: u64,
+}
+
+pub struct Keyboard8042 {
+    input: InputQueue,
+    script: Option<InputScript>,
+    output: DeviceFifo<OutputByte>,
+    clock: Rc<dyn ClockSource>,
+    reset: Option<Rc<ResetSignal>>,
+    command_byte: u8,
+
+    // A controller command that is waiting for its parameter byte
+    pending_controller_command: Option<u8>,
+
+    // A keyboard command that is waiting for its parameter byte
+    pending_command: Option<u8>,
+    typematic: u8,
+    held: Option<HeldKey>,
+    last_key: Option<u8>,
+}
 
 impl Keyboard8042 {
     const PS2_DATA: Port = 0x0060;
     const PS2_STATUS: Port = 0x0064;
 
     pub fn new() -> Box<Self> {
-        Box::new(Self::default())
+        Self::with_clock(Rc::new(SystemClock))
+    }
+
+    /// Create a keyboard whose key repeat is driven by `clock`
+    pub fn with_clock(clock: Rc<dyn ClockSource>) -> Box<Self> {
+        Box::new(Self {
+            input: InputQueue::default(),
+            script: None,
+            output: DeviceFifo::new(OUTPUT_BUFFER_SIZE),
+            clock,
+            reset: None,
+            command_byte: DEFAULT_COMMAND_BYTE,
+            pending_controller_command: None,
+            pending_command: None,
+            typematic: DEFAULT_TYPEMATIC,
+            held: None,
+            last_key: None,
+        })
+    }
+
+    /// Set the signal raised when the guest resets the system through the
+    /// controller output port
+    pub fn set_reset_signal(&mut self, reset: Rc<ResetSignal>) {
+        self.reset = Some(reset);
+    }
+
+    /// Queue a host input event for the guest
+    ///
+    /// A key that is pressed (but not released) repeats at the typematic
+    /// rate programmed by the guest. Returns false if the event was
+    /// dropped because the guest is not draining input fast enough.
+    pub fn push_input(&mut self, event: InputEvent) -> bool {
+        if let InputEvent::Key(code) = event {
+            self.track_held_key(code);
+        }
+        self.input.push(event)
+    }
+
+    /// Feed the input events of `script` to the guest as it reads them
+    ///
+    /// The next event is taken from the script when the guest has drained
+    /// all of the earlier input. Other events in the script are skipped.
+    pub fn load_script(&mut self, script: InputScript) {
+        self.script = Some(script);
+    }
+
+    fn fill_from_script(&mut self) {
+        if !self.input.is_empty() {
+            return;
+        }
+        while let Some(event) =
+            self.script.as_mut().and_then(|s| s.next_event())
+        {
+            match event {
+                ScriptEvent::Input(event) => {
+                    self.push_input(event);
+                    return;
+                }
+                event => {
+                    info!("Skipping scripted {:?} for the keyboard", event)
+                }
+            }
+        }
+    }
+
+    pub fn input_queue(&self) -> &InputQueue {
+        &self.input
+    }
+
+    /// The delay before a held key starts repeating (bits 5-6 of the
+    /// typematic byte select 250ms to 1s)
+    fn typematic_delay_ns(&self) -> u64 {
+        (1 + ((self.typematic >> 5) & 0b11) as u64) * 250_000_000
+    }
+
+    /// The interval between repeats of a held key
+    ///
+    /// The period is (8 + A) * 2^B * 4.17ms, where A is bits 0-2 and B is
+    /// bits 3-4 of the typematic byte (from 30 down to 2 characters per
+    /// second).
+    fn typematic_period_ns(&self) -> u64 {
+        let a = (self.typematic & 0b111) as u64;
+        let b = (self.typematic >> 3) & 0b11;
+        ((8 + a) << b) * 4_170_000
+    }
+
+    fn track_held_key(&mut self, code: u8) {
+        let extended = self.last_key == Some(SCANCODE_EXTENDED);
+        self.last_key = Some(code);
+        match code {
+            SCANCODE_EXTENDED => (),
+            code if code & SCANCODE_BREAK == 0 => {
+                self.held = Some(HeldKey {
+                    code,
+                    extended,
+                    next_repeat: self.clock.now_ns()
+                        + self.typematic_delay_ns(),
+                })
+            }
+            code => {
+                let released = self.held.map_or(false, |held| {
+                    held.code == code & !SCANCODE_BREAK
+                        && held.extended == extended
+                });
+                if released {
+                    self.held = None;
+                }
+            }
+        }
+    }
+
+    fn push_response(&mut self, value: u8) {
+        if self.output.push(OutputByte { value, aux: false }).is_err() {
+            info!("Keyboard output buffer overrun, dropping 0x{:x}", value);
+        }
+    }
+
+    fn request_reset(&self, source: ResetSource) {
+        match &self.reset {
+            Some(reset) => reset.request(source),
+            None => info!("Ignoring keyboard controller reset"),
+        }
+    }
+
+    /// Returns true if the aux (mouse) port is enabled in the command byte
+    ///
+    /// While the aux port is disabled, mouse input is discarded rather
+    /// than delivered to the guest.
+    pub fn aux_enabled(&self) -> bool {
+        self.command_byte & COMMAND_BYTE_AUX_DISABLE == 0
+    }
+
+    fn write_command(&mut self, cmd: u8) {
+        match cmd {
+            CTRL_CMD_READ_COMMAND_BYTE => self.push_response(self.command_byte),
+            CTRL_CMD_WRITE_COMMAND_BYTE | CTRL_CMD_WRITE_OUTPUT_PORT => {
+                self.pending_controller_command = Some(cmd)
+            }
+            CTRL_CMD_DISABLE_AUX => {
+                self.command_byte |= COMMAND_BYTE_AUX_DISABLE
+            }
+            CTRL_CMD_ENABLE_AUX => {
+                self.command_byte &= !COMMAND_BYTE_AUX_DISABLE
+            }
+            CTRL_CMD_TEST_AUX => self.push_response(AUX_TEST_PASSED),
+            // Commands 0xf0-0xff pulse the output port bits that are clear
+            // in the low nibble (so 0xfe pulses the reset line)
+            cmd if cmd >= CTRL_CMD_PULSE_OUTPUT_PORT => {
+                if cmd & OUTPUT_PORT_RESET == 0 {
+                    self.request_reset(ResetSource::KeyboardController);
+                }
+            }
+            cmd => info!("Ignoring keyboard controller command 0x{:x}", cmd),
+        }
+    }
+
+    fn write_data(&mut self, val: u8) {
+        match self.pending_controller_command.take() {
+            Some(CTRL_CMD_WRITE_COMMAND_BYTE) => {
+                self.command_byte = val;
+                return;
+            }
+            Some(CTRL_CMD_WRITE_OUTPUT_PORT) => {
+                if val & OUTPUT_PORT_RESET == 0 {
+                    self.request_reset(ResetSource::KeyboardOutputPort);
+                }
+                return;
+            }
+            _ => (),
+        }
+
+        match self.pending_command.take() {
+            Some(KBD_CMD_SET_TYPEMATIC) => {
+                self.typematic = val & 0x7f;
+                self.push_response(KBD_ACK);
+            }
+            _ if val == KBD_CMD_SET_TYPEMATIC => {
+                self.pending_command = Some(val);
+                self.push_response(KBD_ACK);
+            }
+            _ => info!("Ignoring keyboard command 0x{:x}", val),
+        }
+    }
+
+    // Make sure the output buffer has the next byte available, pulling
+    // the next event from the input queue if necessary
+    fn fill_output(&mut self) {
+        if !self.output.is_empty() {
+            return;
+        }
+
+        self.fill_from_script();
+        match self.input.pop() {
+            Some(InputEvent::Key(code)) => self.push_response(code),
+            Some(event @ InputEvent::MouseMotion { .. })
+                if !self.aux_enabled() =>
+            {
+                info!("Discarding {:?} while the aux port is disabled", event);
+                self.fill_output();
+            }
+            Some(InputEvent::MouseMotion { dx, dy, buttons }) => {
+                // Movements too large for a single packet are split, with
+                // the remainder left at the front of the queue
+                let packet_dx = dx.max(MOUSE_MIN_DELTA).min(MOUSE_MAX_DELTA);
+                let packet_dy = dy.max(MOUSE_MIN_DELTA).min(MOUSE_MAX_DELTA);
+                if packet_dx != dx || packet_dy != dy {
+                    self.input.push_front(InputEvent::MouseMotion {
+                        dx: dx - packet_dx,
+                        dy: dy - packet_dy,
+                        buttons,
+                    });
+                }
+
+                let mut flags = MOUSE_PACKET_ALWAYS_ONE | (buttons & 0b111);
+                if packet_dx < 0 {
+                    flags |= MOUSE_PACKET_X_SIGN;
+                }
+                if packet_dy < 0 {
+                    flags |= MOUSE_PACKET_Y_SIGN;
+                }
+                // The output buffer is empty, so the whole packet fits
+                for value in [flags, packet_dx as u8, packet_dy as u8].iter() {
+                    let _ = self.output.push(OutputByte {
+                        value: *value,
+                        aux: true,
+                    });
+                }
+            }
+            None => (),
+        }
+    }
+
+    fn status(&mut self) -> u8 {
+        self.fill_output();
+        match self.output.peek() {
+            Some(byte) if byte.aux => {
+                STATUS_SYSTEM | STATUS_OUTPUT_FULL | STATUS_AUX_DATA
+            }
+            Some(_) => STATUS_SYSTEM | STATUS_OUTPUT_FULL,
+            None => STATUS_SYSTEM,
+        }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        self.fill_output();
+        //FIXME: For now just return 0xff when there is no data
+        self.output.pop().map(|byte| byte.value).unwrap_or(0xff)
     }
 }
 
@@ -26,23 +352,268 @@ impl EmulatedDevice for Keyboard8042 {
         ]
     }
 
+    fn depends_on(&self) -> Vec<DeviceKind> {
+        vec![DeviceKind::Pic]
+    }
+
+    fn irq_lines(&self) -> Vec<u8> {
+        // IRQ1 for the keyboard and IRQ12 for the aux (mouse) device
+        vec![1, 12]
+    }
+
+    fn reset(&mut self) {
+        self.input = InputQueue::default();
+        self.output.clear();
+        self.command_byte = DEFAULT_COMMAND_BYTE;
+        self.pending_controller_command = None;
+        self.pending_command = None;
+        self.typematic = DEFAULT_TYPEMATIC;
+        self.held = None;
+        self.last_key = None;
+    }
+
+    fn poll(&mut self, now: u64) {
+        let period = self.typematic_period_ns();
+        let held = match &mut self.held {
+            Some(held) if held.next_repeat <= now => held,
+            _ => return,
+        };
+
+        // Don't generate more repeats than the guest could see
+        let repeats = ((now - held.next_repeat) / period + 1)
+            .min(InputQueue::DEFAULT_CAPACITY as u64);
+        held.next_repeat += ((now - held.next_repeat) / period + 1) * period;
+        let held = *held;
+        for _ in 0..repeats {
+            if held.extended {
+                self.input.push(InputEvent::Key(SCANCODE_EXTENDED));
+            }
+            self.input.push(InputEvent::Key(held.code));
+        }
+    }
+
     fn on_port_read(
         &mut self,
-        _port: Port,
+        port: Port,
         mut val: PortReadRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
-        //FIXME: For now just return 0xff for everything
-        val.copy_from_u32(0xff);
+        let res = match port {
+            Self::PS2_STATUS => self.status(),
+            _ => self.read_data(),
+        };
+        val.copy_from_u32(res as u32);
         Ok(())
     }
 
     fn on_port_write(
         &mut self,
-        _port: Port,
-        _val: PortWriteRequest,
+        port: Port,
+        val: PortWriteRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
+        match port {
+            Self::PS2_DATA => self.write_data(val.try_into()?),
+            _ => self.write_command(val.try_into()?),
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::define_test_view;
+    use crate::time::FixedClock;
+
+    fn inb(kbd: &mut Keyboard8042, port: Port) -> u8 {
+        let mut arr = [0u8];
+        let request = PortReadRequest::OneByte(&mut arr);
+        kbd.on_port_read(port, request, define_test_view()).unwrap();
+        arr[0]
+    }
+
+    fn outb(kbd: &mut Keyboard8042, port: Port, val: u8) {
+        let arr = [val];
+        let request = PortWriteRequest::OneByte(&arr);
+        kbd.on_port_write(port, request, define_test_view())
+            .unwrap();
+    }
+
+    // Read all of the available output bytes
+    fn drain(kbd: &mut Keyboard8042) -> Vec<u8> {
+        let mut bytes = vec![];
+        while inb(kbd, Keyboard8042::PS2_STATUS) & STATUS_OUTPUT_FULL != 0 {
+            bytes.push(inb(kbd, Keyboard8042::PS2_DATA));
+        }
+        bytes
+    }
+
+    fn test_keyboard() -> Box<Keyboard8042> {
+        Keyboard8042::with_clock(Rc::new(FixedClock::new(0)))
+    }
+
+    fn motion(dx: i32, dy: i32) -> InputEvent {
+        InputEvent::MouseMotion { dx, dy, buttons: 0 }
+    }
+
+    #[test]
+    fn test_scripted_keys() {
+        let clock = Rc::new(FixedClock::new(0));
+        let mut script = InputScript::new(clock.clone());
+        script.push(ScriptEvent::Input(InputEvent::Key(0x1e)));
+        script.push(ScriptEvent::Input(InputEvent::Key(0x9e)));
+        script.push(ScriptEvent::Delay(1000));
+        script.push(ScriptEvent::Input(InputEvent::Key(0x1c)));
+
+        let mut kbd = Keyboard8042::with_clock(clock.clone());
+        kbd.load_script(script);
+        assert_eq!(drain(&mut kbd), [0x1e, 0x9e]);
+        assert!(kbd.input_queue().is_empty());
+
+        clock.advance(1000);
+        assert_eq!(drain(&mut kbd), [0x1c]);
+    }
+
+    #[test]
+    fn test_aux_port_commands() {
+        let mut kbd = test_keyboard();
+        assert!(kbd.aux_enabled());
+
+        // The interface test passes
+        outb(&mut kbd, Keyboard8042::PS2_STATUS, CTRL_CMD_TEST_AUX);
+        assert_eq!(drain(&mut kbd), [AUX_TEST_PASSED]);
+
+        // Disabling the aux port sets the disable bit of the command byte,
+        // and mouse input is discarded
+        outb(&mut kbd, Keyboard8042::PS2_STATUS, CTRL_CMD_DISABLE_AUX);
+        assert!(!kbd.aux_enabled());
+        outb(
+            &mut kbd,
+            Keyboard8042::PS2_STATUS,
+            CTRL_CMD_READ_COMMAND_BYTE,
+        );
+        assert_eq!(
+            drain(&mut kbd),
+            [DEFAULT_COMMAND_BYTE | COMMAND_BYTE_AUX_DISABLE]
+        );
+        kbd.push_input(motion(1, 1));
+        kbd.push_input(InputEvent::Key(0x1e));
+        assert_eq!(drain(&mut kbd), [0x1e]);
+
+        // Mouse input flows again once it is enabled
+        outb(&mut kbd, Keyboard8042::PS2_STATUS, CTRL_CMD_ENABLE_AUX);
+        assert!(kbd.aux_enabled());
+        kbd.push_input(motion(1, 1));
+        assert_eq!(drain(&mut kbd), [0x08, 1, 1]);
+
+        // The command byte can also be written directly
+        outb(
+            &mut kbd,
+            Keyboard8042::PS2_STATUS,
+            CTRL_CMD_WRITE_COMMAND_BYTE,
+        );
+        outb(&mut kbd, Keyboard8042::PS2_DATA, 0x65);
+        assert!(!kbd.aux_enabled());
+    }
+
+    #[test]
+    fn test_key_bytes() {
+        let mut kbd = test_keyboard();
+        assert_eq!(inb(&mut kbd, Keyboard8042::PS2_STATUS), STATUS_SYSTEM);
+
+        kbd.push_input(InputEvent::Key(0x1e));
+        kbd.push_input(InputEvent::Key(0x9e));
+        assert_eq!(
+            inb(&mut kbd, Keyboard8042::PS2_STATUS),
+            STATUS_SYSTEM | STATUS_OUTPUT_FULL
+        );
+        assert_eq!(inb(&mut kbd, Keyboard8042::PS2_DATA), 0x1e);
+        assert_eq!(inb(&mut kbd, Keyboard8042::PS2_DATA), 0x9e);
+        assert_eq!(inb(&mut kbd, Keyboard8042::PS2_STATUS), STATUS_SYSTEM);
+    }
+
+    #[test]
+    fn test_coalesced_mouse_packet() {
+        let mut kbd = test_keyboard();
+        kbd.push_input(motion(2, 1));
+        kbd.push_input(motion(3, -4));
+        kbd.push_input(motion(-1, -2));
+        assert_eq!(kbd.input_queue().len(), 1);
+
+        assert_eq!(
+            inb(&mut kbd, Keyboard8042::PS2_STATUS),
+            STATUS_SYSTEM | STATUS_OUTPUT_FULL | STATUS_AUX_DATA
+        );
+        let packet: Vec<u8> = (0..3)
+            .map(|_| inb(&mut kbd, Keyboard8042::PS2_DATA))
+            .collect();
+        assert_eq!(packet, [0x28, 4, 0xfb]);
+        assert_eq!(inb(&mut kbd, Keyboard8042::PS2_STATUS), STATUS_SYSTEM);
+    }
+
+    #[test]
+    fn test_large_motion_split() {
+        let mut kbd = test_keyboard();
+        kbd.push_input(motion(300, 0));
+        let packet: Vec<u8> = (0..6)
+            .map(|_| inb(&mut kbd, Keyboard8042::PS2_DATA))
+            .collect();
+        assert_eq!(packet, [0x08, 255, 0, 0x08, 45, 0]);
+    }
+
+    #[test]
+    fn test_typematic_repeat() {
+        let clock = Rc::new(FixedClock::new(0));
+        let mut kbd = Keyboard8042::with_clock(clock.clone());
+
+        // 30 characters per second (a 33.36ms period) after 250ms
+        outb(&mut kbd, Keyboard8042::PS2_DATA, KBD_CMD_SET_TYPEMATIC);
+        outb(&mut kbd, Keyboard8042::PS2_DATA, 0x00);
+        assert_eq!(drain(&mut kbd), [KBD_ACK, KBD_ACK]);
+
+        kbd.push_input(InputEvent::Key(0x1e));
+        clock.set(249_000_000);
+        kbd.poll(clock.now_ns());
+        assert_eq!(drain(&mut kbd), [0x1e]);
+
+        clock.set(250_000_000);
+        kbd.poll(clock.now_ns());
+        assert_eq!(drain(&mut kbd), [0x1e]);
+
+        // Three more periods
+        clock.set(250_000_000 + 3 * 33_360_000);
+        kbd.poll(clock.now_ns());
+        assert_eq!(drain(&mut kbd), [0x1e; 3]);
+
+        kbd.push_input(InputEvent::Key(0x9e));
+        clock.advance(1_000_000_000);
+        kbd.poll(clock.now_ns());
+        assert_eq!(drain(&mut kbd), [0x9e]);
+    }
+
+    #[test]
+    fn test_typematic_extended_key() {
+        let clock = Rc::new(FixedClock::new(0));
+        let mut kbd = Keyboard8042::with_clock(clock.clone());
+
+        // The default is a 500ms delay, then a 91.74ms period
+        kbd.push_input(InputEvent::Key(SCANCODE_EXTENDED));
+        kbd.push_input(InputEvent::Key(0x48));
+        clock.set(500_000_000 + 91_740_000);
+        kbd.poll(clock.now_ns());
+        assert_eq!(drain(&mut kbd), [0xe0, 0x48, 0xe0, 0x48, 0xe0, 0x48]);
+
+        // Releasing the non-extended key with the same code has no effect
+        kbd.push_input(InputEvent::Key(0xc8));
+        clock.advance(91_740_000);
+        kbd.poll(clock.now_ns());
+        assert_eq!(drain(&mut kbd), [0xc8, 0xe0, 0x48]);
+
+        kbd.push_input(InputEvent::Key(SCANCODE_EXTENDED));
+        kbd.push_input(InputEvent::Key(0xc8));
+        clock.advance(1_000_000_000);
+        kbd.poll(clock.now_ns());
+        assert_eq!(drain(&mut kbd), [0xe0, 0xc8]);
+    }
+}
@@ -1,17 +1,251 @@
+use crate::device::interrupt::{EoiSink, InterruptDestination, InterruptSink};
 use crate::device::{
-    DeviceRegion, EmulatedDevice, MemReadRequest, MemWriteRequest,
+    DeviceKind, DeviceRegion, EmulatedDevice, MemReadRequest, MemWriteRequest,
 };
-use crate::error::Result;
+use crate::error::{Error, Result};
+use crate::ioapic::{DeliveryMode, DestinationMode};
 use crate::memory::{GuestAddressSpaceViewMut, GuestPhysAddr};
+use crate::mmio_regs;
+use crate::time::{ClockSource, SystemClock};
 use alloc::boxed::Box;
+use alloc::rc::Rc;
 use alloc::vec::Vec;
+use core::convert::{TryFrom, TryInto};
+use core::ops::RangeInclusive;
 
-#[derive(Default)]
-pub struct LocalApic;
+const LAPIC_BASE: u64 = 0xfee00000;
+const EOI_OFFSET: u64 = 0x0b0;
+const SPURIOUS_OFFSET: u64 = 0x0f0;
+const ICR_LOW_OFFSET: u64 = 0x300;
+const ICR_HIGH_OFFSET: u64 = 0x310;
+const LVT_TIMER_OFFSET: u64 = 0x320;
+const TIMER_INITIAL_COUNT_OFFSET: u64 = 0x380;
+const TIMER_CURRENT_COUNT_OFFSET: u64 = 0x390;
+const TIMER_DIVIDE_CONFIG_OFFSET: u64 = 0x3e0;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_MODE_MASK: u32 = 0b11 << 17;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const LVT_TIMER_TSC_DEADLINE: u32 = 1 << 18;
+
+const IA32_TIME_STAMP_COUNTER: u32 = 0x10;
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+// The frequency of the (virtual) bus clock that drives the timer
+const TIMER_FREQUENCY_HZ: u64 = 1_000_000_000;
+
+/// The frequency of the guest TSC used by the TSC-deadline timer, unless
+/// configured otherwise
+pub const DEFAULT_TSC_FREQUENCY_HZ: u64 = 1_000_000_000;
+
+mmio_regs! {
+    /// The memory mapped registers of the local APIC that hold state
+    struct LapicRegisters {
+        SPURIOUS_OFFSET => spurious: u32,
+        ICR_LOW_OFFSET => icr_low: u32,
+        ICR_HIGH_OFFSET => icr_high: u32,
+        LVT_TIMER_OFFSET => lvt_timer: u32,
+        TIMER_INITIAL_COUNT_OFFSET => timer_initial_count: u32,
+        TIMER_CURRENT_COUNT_OFFSET => timer_current_count: u32,
+        TIMER_DIVIDE_CONFIG_OFFSET => timer_divide_config: u32,
+    }
+}
+
+pub struct LocalApic {
+    id: u8,
+    regs: LapicRegisters,
+    sink: Rc<dyn InterruptSink>,
+    eoi_sink: Option<Rc<dyn EoiSink>>,
+    clock: Rc<dyn ClockSource>,
+
+    // The clock time (in nanoseconds) when the timer was started and the
+    // number of expirations that have been delivered since then
+    timer_started: Option<u64>,
+    timer_expirations: u64,
+
+    // The guest TSC value at which the TSC-deadline timer fires (or 0 if
+    // it is disarmed), the frequency of the guest TSC and the value added
+    // to it by guest writes of IA32_TIME_STAMP_COUNTER
+    tsc_deadline: u64,
+    tsc_frequency: u64,
+    tsc_offset: u64,
+}
 
 impl LocalApic {
-    pub fn new() -> Box<Self> {
-        Box::new(LocalApic::default())
+    pub fn new(sink: Rc<dyn InterruptSink>) -> Box<Self> {
+        Self::with_clock(sink, Rc::new(SystemClock))
+    }
+
+    /// Create a local APIC whose timer is driven by `clock`
+    pub fn with_clock(
+        sink: Rc<dyn InterruptSink>,
+        clock: Rc<dyn ClockSource>,
+    ) -> Box<Self> {
+        Box::new(LocalApic {
+            id: 0,
+            regs: Self::initial_registers(),
+            sink,
+            eoi_sink: None,
+            clock,
+            timer_started: None,
+            timer_expirations: 0,
+            tsc_deadline: 0,
+            tsc_frequency: DEFAULT_TSC_FREQUENCY_HZ,
+            tsc_offset: 0,
+        })
+    }
+
+    // The registers at power-on (with the timer masked)
+    fn initial_registers() -> LapicRegisters {
+        LapicRegisters {
+            lvt_timer: LVT_MASKED,
+            ..LapicRegisters::default()
+        }
+    }
+
+    /// Set where EOIs are broadcast to (normally the I/O APIC)
+    pub fn set_eoi_sink(&mut self, eoi_sink: Rc<dyn EoiSink>) {
+        self.eoi_sink = Some(eoi_sink);
+    }
+
+    // End the highest priority interrupt in service, and let the I/O APIC
+    // know about it
+    fn end_of_interrupt(&self) {
+        let vector = match self.sink.end_of_interrupt(self.id) {
+            Some(vector) => vector,
+            None => return,
+        };
+        if let Some(eoi_sink) = &self.eoi_sink {
+            eoi_sink.end_of_interrupt(vector);
+        }
+    }
+
+    /// Set the frequency of the guest TSC, which is derived from the clock
+    pub fn set_tsc_frequency(&mut self, hz: u64) {
+        self.tsc_frequency = hz;
+    }
+
+    // The guest TSC at the clock time `now`. This is also what the guest
+    // reads with RDTSC (which exits, and is emulated as a read of
+    // IA32_TIME_STAMP_COUNTER), so deadlines are in the guest's units.
+    fn guest_tsc(&self, now: u64) -> u64 {
+        let ticks =
+            (now as u128 * self.tsc_frequency as u128 / 1_000_000_000) as u64;
+        ticks.wrapping_add(self.tsc_offset)
+    }
+
+    fn timer_divisor(&self) -> u64 {
+        // The divisor is encoded in bits 0, 1 and 3
+        let encoded = (self.regs.timer_divide_config & 0b11)
+            | ((self.regs.timer_divide_config >> 1) & 0b100);
+        match encoded {
+            0b111 => 1,
+            n => 2 << n,
+        }
+    }
+
+    fn timer_ticks(&self, now: u64) -> Option<u64> {
+        self.timer_started.map(|started| {
+            let elapsed = now.saturating_sub(started) as u128;
+            (elapsed * TIMER_FREQUENCY_HZ as u128
+                / 1_000_000_000
+                / self.timer_divisor() as u128) as u64
+        })
+    }
+
+    fn timer_is_periodic(&self) -> bool {
+        self.regs.lvt_timer & LVT_TIMER_MODE_MASK == LVT_TIMER_PERIODIC
+    }
+
+    fn timer_is_tsc_deadline(&self) -> bool {
+        self.regs.lvt_timer & LVT_TIMER_MODE_MASK == LVT_TIMER_TSC_DEADLINE
+    }
+
+    fn write_lvt_timer(&mut self, val: u32) {
+        // Changing the timer mode disarms the timer
+        if (val ^ self.regs.lvt_timer) & LVT_TIMER_MODE_MASK != 0 {
+            self.regs.timer_initial_count = 0;
+            self.timer_started = None;
+            self.tsc_deadline = 0;
+        }
+        self.regs.lvt_timer = val;
+    }
+
+    fn deliver_timer_interrupt(&mut self) {
+        // Expirations while the timer is masked are lost
+        if self.regs.lvt_timer & LVT_MASKED == 0 {
+            let vector = (self.regs.lvt_timer & 0xff) as u8;
+            self.sink
+                .deliver(InterruptDestination::physical(self.id), vector);
+        }
+    }
+
+    fn timer_current_count(&self, now: u64) -> u32 {
+        let initial = self.regs.timer_initial_count as u64;
+        match self.timer_ticks(now) {
+            Some(ticks) if self.timer_is_periodic() => {
+                (initial - ticks % initial) as u32
+            }
+            Some(ticks) if ticks < initial => (initial - ticks) as u32,
+            _ => 0,
+        }
+    }
+
+    fn start_timer(&mut self, initial_count: u32) {
+        // The initial count is ignored in TSC-deadline mode
+        if self.timer_is_tsc_deadline() {
+            return;
+        }
+        self.regs.timer_initial_count = initial_count;
+        self.timer_expirations = 0;
+        self.timer_started = if initial_count == 0 {
+            None
+        } else {
+            Some(self.clock.now_ns())
+        };
+    }
+
+    fn send_ipi(&mut self, icr_low: u32) {
+        self.regs.icr_low = icr_low;
+        let vector = (icr_low & 0xff) as u8;
+        let delivery =
+            match DeliveryMode::try_from(((icr_low >> 8) & 0x7) as u8) {
+                Ok(mode) => mode,
+                Err(_) => {
+                    info!(
+                        "Unsupported IPI delivery mode (icr=0x{:x})",
+                        icr_low
+                    );
+                    return;
+                }
+            };
+        let mode = if icr_low & (1 << 11) != 0 {
+            DestinationMode::Logical
+        } else {
+            DestinationMode::Physical
+        };
+
+        let dest = match (icr_low >> 18) & 0b11 {
+            0b00 => InterruptDestination {
+                mode,
+                delivery,
+                destination: (self.regs.icr_high >> 24) as u8,
+            },
+            0b01 => {
+                InterruptDestination::physical(self.id).with_delivery(delivery)
+            }
+            0b10 => {
+                InterruptDestination::physical(InterruptDestination::BROADCAST)
+                    .with_delivery(delivery)
+            }
+            _ => {
+                // There is currently only a single vcpu per vm, so there
+                // is no one else to send to
+                info!("Dropping 'all excluding self' IPI 0x{:x}", vector);
+                return;
+            }
+        };
+        self.sink.deliver(dest, vector);
     }
 }
 
@@ -19,30 +253,115 @@ impl EmulatedDevice for LocalApic {
     fn services(&self) -> Vec<DeviceRegion> {
         vec![
             DeviceRegion::MemIo(
-                GuestPhysAddr::new(0xfee00000)..=GuestPhysAddr::new(0xfee010f0),
+                GuestPhysAddr::new(LAPIC_BASE)..=GuestPhysAddr::new(0xfee010f0),
             ),
             //FIXME: this is actually the 1st HPET
             DeviceRegion::MemIo(
                 GuestPhysAddr::new(0xfed00000)..=GuestPhysAddr::new(0xfed010f0),
             ),
-            //FIXME: this is actually the io apic
-            DeviceRegion::MemIo(
-                GuestPhysAddr::new(0xfec00000)..=GuestPhysAddr::new(0xfec010f0),
-            ),
         ]
     }
 
+    fn kind(&self) -> Option<DeviceKind> {
+        Some(DeviceKind::LocalApic)
+    }
+
+    fn reset(&mut self) {
+        self.regs = Self::initial_registers();
+        self.timer_started = None;
+        self.timer_expirations = 0;
+        self.tsc_deadline = 0;
+    }
+
+    fn msr_ranges(&self) -> Vec<RangeInclusive<u32>> {
+        vec![
+            IA32_TIME_STAMP_COUNTER..=IA32_TIME_STAMP_COUNTER,
+            IA32_TSC_DEADLINE..=IA32_TSC_DEADLINE,
+        ]
+    }
+
+    fn on_msr_read(&mut self, msr: u32) -> Result<u64> {
+        match msr {
+            IA32_TIME_STAMP_COUNTER => Ok(self.guest_tsc(self.clock.now_ns())),
+            // The deadline reads as 0 in the other timer modes
+            IA32_TSC_DEADLINE if self.timer_is_tsc_deadline() => {
+                Ok(self.tsc_deadline)
+            }
+            IA32_TSC_DEADLINE => Ok(0),
+            _ => Err(Error::NotImplemented(format!(
+                "local apic read of MSR 0x{:x}",
+                msr
+            ))),
+        }
+    }
+
+    fn on_msr_write(&mut self, msr: u32, val: u64) -> Result<()> {
+        match msr {
+            IA32_TIME_STAMP_COUNTER => {
+                let now = self.guest_tsc(self.clock.now_ns());
+                self.tsc_offset =
+                    self.tsc_offset.wrapping_add(val.wrapping_sub(now));
+                Ok(())
+            }
+            // Writing a deadline (re)arms the timer, and writing 0 disarms
+            // it. Writes are ignored in the other timer modes.
+            IA32_TSC_DEADLINE if self.timer_is_tsc_deadline() => {
+                self.tsc_deadline = val;
+                Ok(())
+            }
+            IA32_TSC_DEADLINE => Ok(()),
+            _ => Err(Error::NotImplemented(format!(
+                "local apic write of MSR 0x{:x}",
+                msr
+            ))),
+        }
+    }
+
+    fn poll(&mut self, now: u64) {
+        if self.timer_is_tsc_deadline() {
+            if self.tsc_deadline != 0
+                && self.guest_tsc(now) >= self.tsc_deadline
+            {
+                self.tsc_deadline = 0;
+                self.deliver_timer_interrupt();
+            }
+            return;
+        }
+
+        let total = match self.timer_ticks(now) {
+            Some(ticks) if self.timer_is_periodic() => {
+                ticks / self.regs.timer_initial_count as u64
+            }
+            Some(ticks) if ticks >= self.regs.timer_initial_count as u64 => 1,
+            _ => return,
+        };
+
+        // Expirations missed since the last poll are coalesced into a
+        // single interrupt, like a real timer whose IRR bit is still set
+        if total > self.timer_expirations {
+            self.deliver_timer_interrupt();
+        }
+        self.timer_expirations = total;
+    }
+
     fn on_mem_read(
         &mut self,
         addr: GuestPhysAddr,
-        data: MemReadRequest,
+        mut data: MemReadRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
-        info!(
-            "local apic read of addr = {:?} (len=0x{:x})",
-            addr,
-            data.as_slice().len()
-        );
+        let offset = addr.as_u64().wrapping_sub(LAPIC_BASE);
+        if offset == TIMER_CURRENT_COUNT_OFFSET {
+            self.regs.timer_current_count =
+                self.timer_current_count(self.clock.now_ns());
+        }
+        if !self.regs.read(offset, &mut data)? {
+            info!(
+                "local apic read of addr = {:?} (len=0x{:x})",
+                addr,
+                data.as_slice().len()
+            );
+        }
         Ok(())
     }
 
@@ -52,7 +371,273 @@ impl EmulatedDevice for LocalApic {
         data: MemWriteRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
-        info!("local apic write of addr = {:?} (data={:?})", addr, data);
+        // Registers with side effects are handled here, and the rest are
+        // simply stored
+        let offset = addr.as_u64().wrapping_sub(LAPIC_BASE);
+        match offset {
+            EOI_OFFSET => self.end_of_interrupt(),
+            ICR_LOW_OFFSET => self.send_ipi(data.try_into()?),
+            LVT_TIMER_OFFSET => self.write_lvt_timer(data.try_into()?),
+            TIMER_INITIAL_COUNT_OFFSET => self.start_timer(data.try_into()?),
+            TIMER_CURRENT_COUNT_OFFSET => {
+                info!("Ignoring write to the local apic current count")
+            }
+            _ => {
+                if !self.regs.write(offset, &data)? {
+                    info!(
+                        "local apic write of addr = {:?} (data={:?})",
+                        addr, data
+                    )
+                }
+            }
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::interrupt::{PendingInterrupts, VcpuApic};
+    use crate::device::test_util::define_test_view;
+    use crate::time::FixedClock;
+    use core::cell::RefCell;
+
+    fn write_icr(lapic: &mut LocalApic, high: u32, low: u32) {
+        for (offset, val) in
+            [(ICR_HIGH_OFFSET, high), (ICR_LOW_OFFSET, low)].iter()
+        {
+            let arr = val.to_be_bytes();
+            let request = MemWriteRequest::new(&arr);
+            lapic
+                .on_mem_write(
+                    GuestPhysAddr::new(LAPIC_BASE + offset),
+                    request,
+                    define_test_view(),
+                )
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_lapic_ipi_delivery() {
+        let vcpus: Vec<_> = (0..4).map(VcpuApic::new).collect();
+        let sink = Rc::new(PendingInterrupts::new(&vcpus));
+        let mut lapic = LocalApic::new(sink.clone());
+
+        // Fixed IPI to logical destination 0b1001
+        write_icr(&mut lapic, 0x09000000, 0x00000830);
+        assert_eq!(sink.pop(0), Some((DeliveryMode::Fixed, 0x30)));
+        assert_eq!(sink.pop(3), Some((DeliveryMode::Fixed, 0x30)));
+        assert_eq!(sink.pop(1), None);
+
+        // Self IPI
+        write_icr(&mut lapic, 0, 0x00040031);
+        assert_eq!(sink.pop(0), Some((DeliveryMode::Fixed, 0x31)));
+        assert_eq!(sink.pop(1), None);
+    }
+
+    fn write_reg(lapic: &mut LocalApic, offset: u64, val: u32) {
+        let arr = val.to_be_bytes();
+        lapic
+            .on_mem_write(
+                GuestPhysAddr::new(LAPIC_BASE + offset),
+                MemWriteRequest::new(&arr),
+                define_test_view(),
+            )
+            .unwrap();
+    }
+
+    fn read_reg(lapic: &mut LocalApic, offset: u64) -> u32 {
+        let mut arr = [0u8; 4];
+        lapic
+            .on_mem_read(
+                GuestPhysAddr::new(LAPIC_BASE + offset),
+                MemReadRequest::new(&mut arr),
+                define_test_view(),
+            )
+            .unwrap();
+        u32::from_be_bytes(arr)
+    }
+
+    #[test]
+    fn test_lapic_register_access() {
+        let sink = Rc::new(PendingInterrupts::new(&[VcpuApic::new(0)]));
+        let mut lapic = LocalApic::new(sink);
+        assert_eq!(read_reg(&mut lapic, LVT_TIMER_OFFSET), LVT_MASKED);
+        write_reg(&mut lapic, SPURIOUS_OFFSET, 0x1ff);
+        assert_eq!(read_reg(&mut lapic, SPURIOUS_OFFSET), 0x1ff);
+
+        // Registers must be accessed as whole (aligned) dwords
+        let mut arr = [0u8; 2];
+        assert!(lapic
+            .on_mem_read(
+                GuestPhysAddr::new(LAPIC_BASE + SPURIOUS_OFFSET + 2),
+                MemReadRequest::new(&mut arr),
+                define_test_view(),
+            )
+            .is_err());
+        assert!(lapic
+            .on_mem_write(
+                GuestPhysAddr::new(LAPIC_BASE + ICR_HIGH_OFFSET),
+                MemWriteRequest::new(&[0; 2]),
+                define_test_view(),
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_lapic_periodic_timer() {
+        let sink = Rc::new(PendingInterrupts::new(&[VcpuApic::new(0)]));
+        let clock = Rc::new(FixedClock::new(0));
+        let mut lapic = LocalApic::with_clock(sink.clone(), clock.clone());
+
+        // Periodic timer on vector 0x40, divide by 16, 1000 tick period
+        write_reg(&mut lapic, TIMER_DIVIDE_CONFIG_OFFSET, 0b0011);
+        write_reg(&mut lapic, LVT_TIMER_OFFSET, LVT_TIMER_PERIODIC | 0x40);
+        write_reg(&mut lapic, TIMER_INITIAL_COUNT_OFFSET, 1000);
+
+        clock.advance(16 * 250);
+        lapic.poll(clock.now_ns());
+        assert_eq!(read_reg(&mut lapic, TIMER_CURRENT_COUNT_OFFSET), 750);
+        assert_eq!(sink.pop(0), None);
+
+        // The two expirations since the last poll deliver one interrupt
+        clock.advance(16 * 2000);
+        lapic.poll(clock.now_ns());
+        assert_eq!(sink.pop(0), Some((DeliveryMode::Fixed, 0x40)));
+        assert_eq!(sink.pop(0), None);
+
+        // A masked timer keeps counting, but nothing is delivered
+        write_reg(&mut lapic, LVT_TIMER_OFFSET, LVT_MASKED | 0x40);
+        clock.advance(16 * 1000);
+        lapic.poll(clock.now_ns());
+        assert_eq!(sink.pop(0), None);
+    }
+
+    #[test]
+    fn test_lapic_tsc_deadline() {
+        let sink = Rc::new(PendingInterrupts::new(&[VcpuApic::new(0)]));
+        let clock = Rc::new(FixedClock::new(0));
+        let mut lapic = LocalApic::with_clock(sink.clone(), clock.clone());
+        lapic.set_tsc_frequency(2_000_000_000);
+
+        // The deadline is ignored outside of TSC-deadline mode
+        lapic.on_msr_write(IA32_TSC_DEADLINE, 1000).unwrap();
+        assert_eq!(lapic.on_msr_read(IA32_TSC_DEADLINE), Ok(0));
+
+        write_reg(&mut lapic, LVT_TIMER_OFFSET, LVT_TIMER_TSC_DEADLINE | 0x41);
+        lapic.on_msr_write(IA32_TSC_DEADLINE, 10_000).unwrap();
+        assert_eq!(lapic.on_msr_read(IA32_TSC_DEADLINE), Ok(10_000));
+
+        clock.set(4_999);
+        lapic.poll(clock.now_ns());
+        assert_eq!(sink.pop(0), None);
+
+        // The timer fires once, and the deadline is cleared
+        clock.set(5_000);
+        lapic.poll(clock.now_ns());
+        clock.advance(100_000);
+        lapic.poll(clock.now_ns());
+        assert_eq!(sink.pop(0), Some((DeliveryMode::Fixed, 0x41)));
+        assert_eq!(sink.pop(0), None);
+        assert_eq!(lapic.on_msr_read(IA32_TSC_DEADLINE), Ok(0));
+    }
+
+    #[test]
+    fn test_lapic_tsc_deadline_rearm() {
+        let sink = Rc::new(PendingInterrupts::new(&[VcpuApic::new(0)]));
+        let clock = Rc::new(FixedClock::new(0));
+        let mut lapic = LocalApic::with_clock(sink.clone(), clock.clone());
+        write_reg(&mut lapic, LVT_TIMER_OFFSET, LVT_TIMER_TSC_DEADLINE | 0x41);
+
+        // Rewriting the deadline before it passes reschedules the timer
+        lapic.on_msr_write(IA32_TSC_DEADLINE, 1_000).unwrap();
+        lapic.on_msr_write(IA32_TSC_DEADLINE, 3_000).unwrap();
+        clock.set(2_000);
+        lapic.poll(clock.now_ns());
+        assert_eq!(sink.pop(0), None);
+        clock.set(3_000);
+        lapic.poll(clock.now_ns());
+        assert_eq!(sink.pop(0), Some((DeliveryMode::Fixed, 0x41)));
+
+        // Writing zero disarms it
+        lapic.on_msr_write(IA32_TSC_DEADLINE, 4_000).unwrap();
+        lapic.on_msr_write(IA32_TSC_DEADLINE, 0).unwrap();
+        clock.set(5_000);
+        lapic.poll(clock.now_ns());
+        assert_eq!(sink.pop(0), None);
+
+        // As does leaving TSC-deadline mode
+        lapic.on_msr_write(IA32_TSC_DEADLINE, 6_000).unwrap();
+        write_reg(&mut lapic, LVT_TIMER_OFFSET, 0x41);
+        write_reg(&mut lapic, LVT_TIMER_OFFSET, LVT_TIMER_TSC_DEADLINE | 0x41);
+        clock.set(7_000);
+        lapic.poll(clock.now_ns());
+        assert_eq!(sink.pop(0), None);
+    }
+
+    #[test]
+    fn test_lapic_tsc_deadline_from_guest_tsc() {
+        let sink = Rc::new(PendingInterrupts::new(&[VcpuApic::new(0)]));
+        let clock = Rc::new(FixedClock::new(1_000_000));
+        let mut lapic = LocalApic::with_clock(sink.clone(), clock.clone());
+        lapic.set_tsc_frequency(2_000_000_000);
+        write_reg(&mut lapic, LVT_TIMER_OFFSET, LVT_TIMER_TSC_DEADLINE | 0x41);
+
+        // Arm the timer 1000 ticks after the TSC the guest reads
+        let tsc = lapic.on_msr_read(IA32_TIME_STAMP_COUNTER).unwrap();
+        assert_eq!(tsc, 2_000_000);
+        lapic.on_msr_write(IA32_TSC_DEADLINE, tsc + 1000).unwrap();
+        clock.advance(499);
+        lapic.poll(clock.now_ns());
+        assert_eq!(sink.pop(0), None);
+        clock.advance(1);
+        lapic.poll(clock.now_ns());
+        assert_eq!(sink.pop(0), Some((DeliveryMode::Fixed, 0x41)));
+
+        // The same holds after the guest sets its TSC
+        lapic.on_msr_write(IA32_TIME_STAMP_COUNTER, 5).unwrap();
+        let tsc = lapic.on_msr_read(IA32_TIME_STAMP_COUNTER).unwrap();
+        assert_eq!(tsc, 5);
+        lapic.on_msr_write(IA32_TSC_DEADLINE, tsc + 1000).unwrap();
+        clock.advance(499);
+        lapic.poll(clock.now_ns());
+        assert_eq!(sink.pop(0), None);
+        clock.advance(1);
+        lapic.poll(clock.now_ns());
+        assert_eq!(sink.pop(0), Some((DeliveryMode::Fixed, 0x41)));
+    }
+
+    #[derive(Default)]
+    struct MockEoiSink {
+        vectors: RefCell<Vec<u8>>,
+    }
+
+    impl EoiSink for MockEoiSink {
+        fn end_of_interrupt(&self, vector: u8) {
+            self.vectors.borrow_mut().push(vector);
+        }
+    }
+
+    #[test]
+    fn test_lapic_eoi_broadcast() {
+        let sink = Rc::new(PendingInterrupts::new(&[VcpuApic::new(0)]));
+        let eoi_sink = Rc::new(MockEoiSink::default());
+        let mut lapic = LocalApic::new(sink.clone());
+        lapic.set_eoi_sink(eoi_sink.clone());
+
+        // An EOI with nothing in service is ignored
+        write_reg(&mut lapic, EOI_OFFSET, 0);
+        assert!(eoi_sink.vectors.borrow().is_empty());
+
+        write_icr(&mut lapic, 0, 0x00040031);
+        write_icr(&mut lapic, 0, 0x00040032);
+        while sink.pop(0).is_some() {}
+
+        // EOIs end the highest priority vector first
+        write_reg(&mut lapic, EOI_OFFSET, 0);
+        write_reg(&mut lapic, EOI_OFFSET, 0);
+        assert_eq!(*eoi_sink.vectors.borrow(), [0x32, 0x31]);
+    }
+}
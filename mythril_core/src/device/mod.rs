@@ -1,9 +1,17 @@
+use crate::device::interrupt::PendingInterrupt;
 use crate::error::{Error, Result};
-use crate::memory::{GuestAddressSpaceViewMut, GuestPhysAddr};
+use crate::ioapic::TriggerMode;
+use crate::memory::{
+    EptTableFlags, GuestAccess, GuestAddressSpace, GuestAddressSpaceViewMut,
+    GuestPhysAddr, MemoryLayout,
+};
 use alloc::boxed::Box;
 use alloc::collections::btree_map::BTreeMap;
 use alloc::rc::Rc;
+use alloc::string::String;
 use alloc::vec::Vec;
+use core::any::Any;
+use core::cell::RefCell;
 use core::cmp::Ordering;
 use core::convert::{TryFrom, TryInto};
 use core::fmt;
@@ -11,18 +19,43 @@ use core::ops::RangeInclusive;
 
 pub mod acpi;
 pub mod com;
+pub mod command;
 pub mod debug;
+pub mod debug_exit;
 pub mod dma;
+#[cfg(feature = "fault-injection")]
+pub mod fault;
+pub mod fifo;
+pub mod floppy;
+pub mod ide;
+pub mod identity;
 pub mod ignore;
+pub mod info_rom;
+pub mod input;
+pub mod interrupt;
+pub mod ioapic;
+pub mod irq_router;
 pub mod keyboard;
 pub mod lapic;
+pub mod msi;
 pub mod pci;
 pub mod pic;
 pub mod pit;
+pub mod platform;
+pub mod port_proxy;
 pub mod pos;
 pub mod qemu_fw_cfg;
+pub mod register;
+pub mod reset;
+pub mod rom;
 pub mod rtc;
+pub mod rtl8139;
+pub mod scatter_gather;
+pub mod sync;
+pub mod tpm;
+pub mod trace;
 pub mod vga;
+pub mod watchdog;
 
 pub type Port = u16;
 
@@ -32,6 +65,9 @@ struct PortIoRegion(RangeInclusive<Port>);
 #[derive(Eq, PartialEq)]
 struct MemIoRegion(RangeInclusive<GuestPhysAddr>);
 
+#[derive(Eq, PartialEq)]
+struct MsrRegion(RangeInclusive<u32>);
+
 impl PartialOrd for PortIoRegion {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
@@ -50,6 +86,24 @@ impl Ord for PortIoRegion {
     }
 }
 
+impl PartialOrd for MsrRegion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MsrRegion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self.0.end() < other.0.start() {
+            Ordering::Less
+        } else if other.0.end() < self.0.start() {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
+    }
+}
+
 impl PartialOrd for MemIoRegion {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
@@ -68,9 +122,85 @@ impl Ord for MemIoRegion {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum DeviceRegion {
     PortIo(RangeInclusive<Port>),
     MemIo(RangeInclusive<GuestPhysAddr>),
+
+    /// A range of model specific registers (as declared through
+    /// `EmulatedDevice::msr_ranges`)
+    Msr(RangeInclusive<u32>),
+}
+
+impl DeviceRegion {
+    /// The memory region of `size` bytes starting at `base`
+    ///
+    /// Fails with `Error::InvalidValue` if `size` is zero or the region
+    /// extends beyond the end of the guest physical address space.
+    pub fn mem_from_base_size(base: GuestPhysAddr, size: u64) -> Result<Self> {
+        let end = size
+            .checked_sub(1)
+            .and_then(|last| base.as_u64().checked_add(last))
+            .ok_or_else(|| {
+                Error::InvalidValue(format!(
+                    "Invalid memory region of size 0x{:x} at {:?}",
+                    size, base
+                ))
+            })?;
+        Ok(DeviceRegion::MemIo(base..=GuestPhysAddr::new(end)))
+    }
+
+    /// The port region of `size` ports starting at `base`
+    ///
+    /// Fails with `Error::InvalidValue` if `size` is zero or the region
+    /// extends beyond the last port.
+    pub fn port_from_base_size(base: Port, size: u16) -> Result<Self> {
+        let end = size
+            .checked_sub(1)
+            .and_then(|last| base.checked_add(last))
+            .ok_or_else(|| {
+                Error::InvalidValue(format!(
+                    "Invalid port region of size 0x{:x} at 0x{:x}",
+                    size, base
+                ))
+            })?;
+        Ok(DeviceRegion::PortIo(base..=end))
+    }
+
+    fn overlaps(&self, other: &DeviceRegion) -> bool {
+        match (self, other) {
+            (DeviceRegion::PortIo(a), DeviceRegion::PortIo(b)) => {
+                a.start() <= b.end() && b.start() <= a.end()
+            }
+            (DeviceRegion::MemIo(a), DeviceRegion::MemIo(b)) => {
+                a.start() <= b.end() && b.start() <= a.end()
+            }
+            (DeviceRegion::Msr(a), DeviceRegion::Msr(b)) => {
+                a.start() <= b.end() && b.start() <= a.end()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A change to the set of regions serviced by an `EmulatedDevice`
+///
+/// Devices report these through `EmulatedDevice::region_changed` when the
+/// regions they service change after registration (e.g., a resizable
+/// framebuffer).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegionDelta {
+    /// Begin servicing a new region
+    Add(DeviceRegion),
+
+    /// Stop servicing a previously registered region
+    Remove(DeviceRegion),
+
+    /// Replace a previously registered region with a new one
+    Resize {
+        old: DeviceRegion,
+        new: DeviceRegion,
+    },
 }
 
 pub trait DeviceInteraction {
@@ -79,6 +209,19 @@ pub trait DeviceInteraction {
         self,
         map: &mut DeviceMap,
     ) -> Option<&mut Box<dyn EmulatedDevice>>;
+
+    /// The distance from this port or address to `region` (zero if the
+    /// region contains it), or None if `region` is in another address space
+    fn distance_to(&self, region: &DeviceRegion) -> Option<u64>;
+}
+
+// The distance from `val` to the nearest end of `range`
+fn distance_to_range(val: u64, range: RangeInclusive<u64>) -> u64 {
+    if val < *range.start() {
+        range.start() - val
+    } else {
+        val.saturating_sub(*range.end())
+    }
 }
 
 impl DeviceInteraction for u16 {
@@ -97,6 +240,15 @@ impl DeviceInteraction for u16 {
             .get_mut(&range)
             .map(|v| unsafe { Rc::get_mut_unchecked(v) })
     }
+    fn distance_to(&self, region: &DeviceRegion) -> Option<u64> {
+        match region {
+            DeviceRegion::PortIo(range) => Some(distance_to_range(
+                *self as u64,
+                *range.start() as u64..=*range.end() as u64,
+            )),
+            DeviceRegion::MemIo(_) | DeviceRegion::Msr(_) => None,
+        }
+    }
 }
 
 impl DeviceInteraction for GuestPhysAddr {
@@ -113,6 +265,314 @@ impl DeviceInteraction for GuestPhysAddr {
             .get_mut(&range)
             .map(|v| unsafe { Rc::get_mut_unchecked(v) })
     }
+    fn distance_to(&self, region: &DeviceRegion) -> Option<u64> {
+        match region {
+            DeviceRegion::MemIo(range) => Some(distance_to_range(
+                self.as_u64(),
+                range.start().as_u64()..=range.end().as_u64(),
+            )),
+            DeviceRegion::PortIo(_) | DeviceRegion::Msr(_) => None,
+        }
+    }
+}
+
+/// A device that uses an IRQ line
+#[derive(Clone, Debug, PartialEq)]
+pub struct IrqUser {
+    pub device: &'static str,
+    pub trigger_mode: TriggerMode,
+}
+
+/// The devices using a single IRQ line
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IrqAssignment {
+    pub users: Vec<IrqUser>,
+}
+
+impl IrqAssignment {
+    /// Returns true if more than one device uses this line
+    pub fn is_shared(&self) -> bool {
+        self.users.len() > 1
+    }
+
+    /// Returns true if this line is shared and any of the users are edge
+    /// triggered (edge triggered interrupts cannot be reliably shared)
+    pub fn has_edge_conflict(&self) -> bool {
+        self.is_shared()
+            && self
+                .users
+                .iter()
+                .any(|user| user.trigger_mode == TriggerMode::Edge)
+    }
+}
+
+/// Check that a DMA transfer of `len` bytes at `addr` only accesses mapped
+/// guest memory (that is writable, if `access` is a write)
+///
+/// DMA engines must call this before moving any bytes, as the guest
+/// controls the addresses they use. Fails with `Error::InvalidDmaRange`.
+pub fn validate_dma_range(
+    space: &GuestAddressSpace,
+    addr: GuestPhysAddr,
+    len: usize,
+    access: GuestAccess,
+) -> Result<()> {
+    let invalid = || Error::InvalidDmaRange { addr, len };
+    if len == 0 {
+        return Ok(());
+    }
+    let end = addr
+        .as_u64()
+        .checked_add(len as u64 - 1)
+        .ok_or_else(invalid)?;
+
+    let required = match access {
+        GuestAccess::Write(_) => EptTableFlags::WRITE_ACCESS,
+        _ => EptTableFlags::READ_ACCESS,
+    };
+    let mut frame = addr.as_u64() & !0xfff;
+    while frame <= end {
+        let flags = space
+            .frame_flags(GuestPhysAddr::new(frame))
+            .map_err(|_| invalid())?;
+        if !flags.contains(required) {
+            return Err(invalid());
+        }
+        frame = match frame.checked_add(0x1000) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    Ok(())
+}
+
+/// The kinds of device that other devices may depend on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeviceKind {
+    /// The legacy 8259 interrupt controllers
+    Pic,
+    /// The local APIC
+    LocalApic,
+    /// The I/O APIC
+    IoApic,
+    /// The 8237 DMA controllers
+    Dma,
+}
+
+/// A dependency declared by a device that is not present in a platform
+#[derive(Clone, Debug, PartialEq)]
+pub struct MissingDependency {
+    pub device: &'static str,
+    pub kind: DeviceKind,
+}
+
+/// An IRQ line that could not be assigned to a device because it is
+/// already used by another device, and one of them is edge triggered
+#[derive(Clone, Debug, PartialEq)]
+pub struct IrqConflict {
+    pub irq: u8,
+    pub device: &'static str,
+    pub existing_device: &'static str,
+}
+
+/// A snapshot of the resources used by a device, as reported by
+/// `EmulatedDevice::describe`
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceDescription {
+    pub name: &'static str,
+    pub kind: Option<DeviceKind>,
+    pub depends_on: Vec<DeviceKind>,
+    pub regions: Vec<DeviceRegion>,
+
+    /// The IRQ lines the device may raise, with their trigger modes
+    pub irqs: Vec<(u8, TriggerMode)>,
+    pub msr_ranges: Vec<RangeInclusive<u32>>,
+}
+
+/// A region registered in a `DeviceMap`, with its access mode and the
+/// device that services it
+pub type RegisteredRegion<'a> =
+    (DeviceRegion, RegionAccess, &'a Box<dyn EmulatedDevice>);
+
+/// A region that could not be registered because it overlaps a region
+/// serviced by another device
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegionConflict {
+    pub device: &'static str,
+    pub region: DeviceRegion,
+    pub existing_device: &'static str,
+    pub existing_region: DeviceRegion,
+}
+
+/// The kind of a guest access to a device
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    PortRead,
+    PortWrite,
+    MemRead,
+    MemWrite,
+}
+
+/// The directions in which the guest may access a device region
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegionAccess {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl RegionAccess {
+    /// Returns true if an access of the given kind is permitted
+    pub fn allows(&self, kind: AccessKind) -> bool {
+        match (self, kind) {
+            (RegionAccess::ReadWrite, _) => true,
+            (RegionAccess::Read, AccessKind::PortRead)
+            | (RegionAccess::Read, AccessKind::MemRead) => true,
+            (RegionAccess::Write, AccessKind::PortWrite)
+            | (RegionAccess::Write, AccessKind::MemWrite) => true,
+            _ => false,
+        }
+    }
+}
+
+/// A guest access in a direction that its region does not permit
+#[derive(Clone, Debug, PartialEq)]
+pub struct DisallowedAccess {
+    pub device: &'static str,
+    pub region: DeviceRegion,
+    pub kind: AccessKind,
+
+    /// The port or guest physical address that was accessed
+    pub addr: u64,
+}
+
+/// A guest access that a device does not implement
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnhandledAccess {
+    pub device: &'static str,
+    pub kind: AccessKind,
+
+    /// The port or guest physical address that was accessed
+    pub addr: u64,
+
+    /// The width of the access in bytes
+    pub width: usize,
+}
+
+impl fmt::Display for UnhandledAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            AccessKind::PortRead => "port read",
+            AccessKind::PortWrite => "port write",
+            AccessKind::MemRead => "memory read",
+            AccessKind::MemWrite => "memory write",
+        };
+        write!(
+            f,
+            "Unhandled {} byte {} of 0x{:x} by {}",
+            self.width, kind, self.addr, self.device
+        )
+    }
+}
+
+/// A memory mapped region that is not within an MMIO hole of the guest's
+/// `MemoryLayout`
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidMmioRegion {
+    pub device: &'static str,
+    pub region: RangeInclusive<GuestPhysAddr>,
+
+    /// The guest RAM overlapped by the region (if any)
+    pub ram: Option<RangeInclusive<GuestPhysAddr>>,
+}
+
+/// Check that the dependencies of each device are satisfied by the others
+///
+/// Returns `Error::MissingDependencies` listing every unsatisfied
+/// dependency (in device order).
+pub fn check_dependencies(devices: &[Box<dyn EmulatedDevice>]) -> Result<()> {
+    let present: Vec<DeviceKind> =
+        devices.iter().filter_map(|dev| dev.kind()).collect();
+    let missing: Vec<MissingDependency> = devices
+        .iter()
+        .flat_map(|dev| {
+            dev.depends_on()
+                .into_iter()
+                .filter(|kind| !present.contains(kind))
+                .map(|kind| MissingDependency {
+                    device: dev.debug_name(),
+                    kind,
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::MissingDependencies(missing))
+    }
+}
+
+/// Check that no edge triggered IRQ line is used by more than one device
+///
+/// Level triggered lines (e.g., PCI interrupts) may be shared. Returns
+/// `Error::IrqConflict` naming the first device to use a conflicting line
+/// and the device that conflicts with it.
+pub fn check_irq_conflicts(devices: &[Box<dyn EmulatedDevice>]) -> Result<()> {
+    let mut users = BTreeMap::<u8, Vec<(&'static str, TriggerMode)>>::new();
+    for dev in devices.iter() {
+        for irq in dev.irq_lines() {
+            let trigger_mode = dev.irq_trigger_mode(irq);
+            let existing = users.entry(irq).or_default();
+            let conflict = existing.iter().find(|(_, mode)| {
+                *mode == TriggerMode::Edge || trigger_mode == TriggerMode::Edge
+            });
+            if let Some((existing_device, _)) = conflict {
+                return Err(Error::IrqConflict(IrqConflict {
+                    irq,
+                    device: dev.debug_name(),
+                    existing_device,
+                }));
+            }
+            existing.push((dev.debug_name(), trigger_mode));
+        }
+    }
+    Ok(())
+}
+
+/// How a `DeviceMap` responds to guest accesses of physical memory that
+/// is neither backed by RAM nor serviced by a device
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnassignedMemoryPolicy {
+    /// Reads return all 0xFFs (like an empty bus) and writes are dropped
+    ReturnFF,
+
+    /// Reads return zeros and writes are dropped
+    ReturnZero,
+
+    /// Return `Error::MissingDevice` for both reads and writes
+    Fault,
+}
+
+impl Default for UnassignedMemoryPolicy {
+    fn default() -> Self {
+        UnassignedMemoryPolicy::Fault
+    }
+}
+
+/// The number of guest reads and writes of a single region
+#[derive(Clone, Copy, Debug, Default)]
+struct AccessCount {
+    reads: u64,
+    writes: u64,
+}
+
+/// The access counts of each region of a `DeviceMap` (which are only
+/// collected with the `access-stats` feature)
+#[derive(Default)]
+struct AccessCounters {
+    ports: BTreeMap<PortIoRegion, AccessCount>,
+    mem: BTreeMap<MemIoRegion, AccessCount>,
 }
 
 /// A structure for looking up `EmulatedDevice`s by port or address
@@ -120,6 +580,18 @@ impl DeviceInteraction for GuestPhysAddr {
 pub struct DeviceMap {
     portio_map: BTreeMap<PortIoRegion, Rc<Box<dyn EmulatedDevice>>>,
     memio_map: BTreeMap<MemIoRegion, Rc<Box<dyn EmulatedDevice>>>,
+    msr_map: BTreeMap<MsrRegion, Rc<Box<dyn EmulatedDevice>>>,
+
+    // Every registered device, once each (in registration order)
+    devices: Vec<Rc<Box<dyn EmulatedDevice>>>,
+
+    // The regions that may only be accessed in one direction
+    port_access: BTreeMap<PortIoRegion, RegionAccess>,
+    mem_access: BTreeMap<MemIoRegion, RegionAccess>,
+
+    unassigned_memory: UnassignedMemoryPolicy,
+    memory_layout: Option<MemoryLayout>,
+    access_counts: AccessCounters,
 }
 
 impl DeviceMap {
@@ -138,142 +610,1242 @@ impl DeviceMap {
         op.find_device_mut(self)
     }
 
-    pub fn register_device(
+    /// Find the device that is responsible for an MSR
+    pub fn device_for_msr(&self, msr: u32) -> Option<&Box<dyn EmulatedDevice>> {
+        self.msr_map.get(&MsrRegion(msr..=msr)).map(|v| &**v)
+    }
+
+    pub fn device_for_msr_mut(
         &mut self,
-        dev: Box<dyn EmulatedDevice>,
-    ) -> Result<()> {
-        let services = dev.services();
-        let dev = Rc::new(dev);
-        for region in services.into_iter() {
+        msr: u32,
+    ) -> Option<&mut Box<dyn EmulatedDevice>> {
+        self.msr_map
+            .get_mut(&MsrRegion(msr..=msr))
+            .map(|v| unsafe { Rc::get_mut_unchecked(v) })
+    }
+
+    /// Dispatch a guest `rdmsr` to the device responsible for `msr`
+    pub fn on_msr_read(&mut self, msr: u32) -> Result<u64> {
+        let dev = self.device_for_msr_mut(msr).ok_or_else(|| {
+            Error::MissingDevice(format!("No device for MSR 0x{:x}", msr))
+        })?;
+        dev.on_msr_read(msr)
+    }
+
+    /// Dispatch a guest `wrmsr` to the device responsible for `msr`
+    pub fn on_msr_write(&mut self, msr: u32, val: u64) -> Result<()> {
+        let dev = self.device_for_msr_mut(msr).ok_or_else(|| {
+            Error::MissingDevice(format!("No device for MSR 0x{:x}", msr))
+        })?;
+        dev.on_msr_write(msr, val)
+    }
+
+    /// The number of guest reads and writes of each registered region
+    ///
+    /// Accesses are only counted with the `access-stats` feature, to keep
+    /// the cost off of the dispatch path otherwise (so without it, every
+    /// count is zero). The counts of a region start over if it is moved
+    /// or resized.
+    pub fn access_stats(&self) -> Vec<(DeviceRegion, u64, u64)> {
+        let counters = &self.access_counts;
+        let ports = self.portio_map.keys().map(|key| {
+            let count = counters.ports.get(key).copied().unwrap_or_default();
+            (
+                DeviceRegion::PortIo(key.0.clone()),
+                count.reads,
+                count.writes,
+            )
+        });
+        let mem = self.memio_map.keys().map(|key| {
+            let count = counters.mem.get(key).copied().unwrap_or_default();
+            (
+                DeviceRegion::MemIo(key.0.clone()),
+                count.reads,
+                count.writes,
+            )
+        });
+        ports.chain(mem).collect()
+    }
+
+    /// Every registered port I/O, MMIO and MSR region, with its access
+    /// mode and the device that services it
+    ///
+    /// The order is fixed: all port I/O regions sorted by their first
+    /// port, then all MMIO regions sorted by their first address, then
+    /// all MSR ranges sorted by their first MSR. As the regions of each
+    /// kind never overlap, this does not depend on the order the devices
+    /// were registered in.
+    pub fn iter_regions(&self) -> impl Iterator<Item = RegisteredRegion> {
+        let ports = self.portio_map.iter().map(move |(key, dev)| {
+            let access = self
+                .port_access
+                .get(key)
+                .copied()
+                .unwrap_or(RegionAccess::ReadWrite);
+            (DeviceRegion::PortIo(key.0.clone()), access, &**dev)
+        });
+        let mem = self.memio_map.iter().map(move |(key, dev)| {
+            let access = self
+                .mem_access
+                .get(key)
+                .copied()
+                .unwrap_or(RegionAccess::ReadWrite);
+            (DeviceRegion::MemIo(key.0.clone()), access, &**dev)
+        });
+        let msrs = self.msr_map.iter().map(|(key, dev)| {
+            (
+                DeviceRegion::Msr(key.0.clone()),
+                RegionAccess::ReadWrite,
+                &**dev,
+            )
+        });
+        ports.chain(mem).chain(msrs)
+    }
+
+    /// The registered region closest to the port or address of `op` (which
+    /// is the region containing it, if there is one), with its device
+    ///
+    /// This is useful to diagnose an access that no device services, such
+    /// as one just past the end of a misplaced device. If two regions are
+    /// equally close, the lower one is returned.
+    pub fn nearest_region(
+        &self,
+        op: impl DeviceInteraction,
+    ) -> Option<(DeviceRegion, &Box<dyn EmulatedDevice>)> {
+        self.iter_regions()
+            .filter_map(|(region, _, dev)| {
+                op.distance_to(&region)
+                    .map(|distance| (distance, region, dev))
+            })
+            .min_by_key(|(distance, _, _)| *distance)
+            .map(|(_, region, dev)| (region, dev))
+    }
+
+    // The error for an access at `op` that no device services, naming
+    // the nearest device
+    fn missing_device(
+        &self,
+        description: String,
+        op: impl DeviceInteraction,
+    ) -> Error {
+        match self.nearest_region(op) {
+            Some((region, dev)) => Error::MissingDevice(format!(
+                "{} (the nearest device is '{}' at {:?})",
+                description,
+                dev.debug_name(),
+                region
+            )),
+            None => Error::MissingDevice(description),
+        }
+    }
+
+    /// A table of every registered region in the order of `iter_regions`
+    /// (followed by the MSR ranges, in order), with the name, access mode
+    /// and IRQ lines of the device that services it
+    pub fn layout_report(&self) -> String {
+        use core::fmt::Write;
+
+        // Writing to a String can't fail, so the results are ignored
+        let mut report = String::new();
+        let _ = writeln!(
+            report,
+            "{:<4} {:<23} {:<6} {:<20} {}",
+            "TYPE", "RANGE", "ACCESS", "DEVICE", "IRQS"
+        );
+        let mut row = |kind: &str,
+                       range: String,
+                       access: RegionAccess,
+                       dev: &Box<dyn EmulatedDevice>| {
+            let access = match access {
+                RegionAccess::Read => "r",
+                RegionAccess::Write => "w",
+                RegionAccess::ReadWrite => "rw",
+            };
+            let irqs = dev
+                .irq_lines()
+                .iter()
+                .map(|irq| format!("{}", irq))
+                .collect::<Vec<_>>();
+            let irqs = if irqs.is_empty() {
+                "-".into()
+            } else {
+                irqs.join(",")
+            };
+            let _ = writeln!(
+                report,
+                "{:<4} {:<23} {:<6} {:<20} {}",
+                kind,
+                range,
+                access,
+                dev.debug_name(),
+                irqs
+            );
+        };
+
+        for (region, access, dev) in self.iter_regions() {
             match region {
-                DeviceRegion::PortIo(val) => {
-                    let key = PortIoRegion(val);
-                    if self.portio_map.contains_key(&key) {
-                        let conflict = self
-                            .portio_map
-                            .get_key_value(&key)
-                            .expect("Could not get conflicting device")
-                            .0;
+                DeviceRegion::PortIo(range) => {
+                    let range = format!(
+                        "0x{:04x}-0x{:04x}",
+                        range.start(),
+                        range.end()
+                    );
+                    row("PIO", range, access, dev);
+                }
+                DeviceRegion::MemIo(range) => {
+                    let range = format!(
+                        "0x{:08x}-0x{:08x}",
+                        range.start().as_u64(),
+                        range.end().as_u64()
+                    );
+                    row("MMIO", range, access, dev);
+                }
+                DeviceRegion::Msr(range) => {
+                    let range = format!(
+                        "0x{:08x}-0x{:08x}",
+                        range.start(),
+                        range.end()
+                    );
+                    row("MSR", range, access, dev);
+                }
+            }
+        }
+        report
+    }
 
-                        return Err(Error::InvalidDevice(format!(
-                            "I/O Port already registered: 0x{:x}-0x{:x} conflicts with existing map of 0x{:x}-0x{:x}",
-                            key.0.start(), key.0.end(), conflict.0.start(), conflict.0.end()
-                        )));
+    // Count a guest access of the region containing `addr`
+    #[cfg(feature = "access-stats")]
+    fn count_access(&mut self, kind: AccessKind, addr: u64) {
+        let count = match kind {
+            AccessKind::PortRead | AccessKind::PortWrite => {
+                let port = addr as Port;
+                let key = PortIoRegion(port..=port);
+                let region = match self.portio_map.get_key_value(&key) {
+                    Some((region, _)) => PortIoRegion(region.0.clone()),
+                    None => return,
+                };
+                self.access_counts.ports.entry(region).or_default()
+            }
+            AccessKind::MemRead | AccessKind::MemWrite => {
+                let addr = GuestPhysAddr::new(addr);
+                let key = MemIoRegion(addr..=addr);
+                let region = match self.memio_map.get_key_value(&key) {
+                    Some((region, _)) => MemIoRegion(region.0.clone()),
+                    None => return,
+                };
+                self.access_counts.mem.entry(region).or_default()
+            }
+        };
+        match kind {
+            AccessKind::PortRead | AccessKind::MemRead => count.reads += 1,
+            AccessKind::PortWrite | AccessKind::MemWrite => count.writes += 1,
+        }
+    }
+
+    #[cfg(not(feature = "access-stats"))]
+    fn count_access(&mut self, _kind: AccessKind, _addr: u64) {}
+
+    // Fail with `Error::DisallowedAccess` if the region containing `addr`
+    // does not permit this kind of access
+    fn check_access(&self, kind: AccessKind, addr: u64) -> Result<()> {
+        let (region, access) = match kind {
+            AccessKind::PortRead | AccessKind::PortWrite => {
+                let port = addr as Port;
+                match self.port_access.get_key_value(&PortIoRegion(port..=port))
+                {
+                    Some((key, access)) => {
+                        (DeviceRegion::PortIo(key.0.clone()), *access)
                     }
-                    self.portio_map.insert(key, Rc::clone(&dev));
+                    None => return Ok(()),
                 }
-                DeviceRegion::MemIo(val) => {
-                    let key = MemIoRegion(val);
-                    if self.memio_map.contains_key(&key) {
-                        let conflict = self
-                            .memio_map
-                            .get_key_value(&key)
-                            .expect("Could not get conflicting device")
-                            .0;
-                        return Err(Error::InvalidDevice(format!(
-                            "Memory region already registered: 0x{:x}-0x{:x} conflicts with existing map of 0x{:x}-0x{:x}",
-                            key.0.start().as_u64(), key.0.end().as_u64(), conflict.0.start().as_u64(), conflict.0.end().as_u64()
-                        )));
+            }
+            AccessKind::MemRead | AccessKind::MemWrite => {
+                let addr = GuestPhysAddr::new(addr);
+                match self.mem_access.get_key_value(&MemIoRegion(addr..=addr)) {
+                    Some((key, access)) => {
+                        (DeviceRegion::MemIo(key.0.clone()), *access)
                     }
-                    self.memio_map.insert(key, Rc::clone(&dev));
+                    None => return Ok(()),
                 }
             }
+        };
+        if access.allows(kind) {
+            return Ok(());
         }
-        Ok(())
+        let device = self
+            .region_owner(&region)
+            .map(|(device, _)| device)
+            .unwrap_or("unknown");
+        Err(Error::DisallowedAccess(DisallowedAccess {
+            device,
+            region,
+            kind,
+            addr,
+        }))
     }
-}
-
-pub trait EmulatedDevice {
-    fn services(&self) -> Vec<DeviceRegion>;
 
-    fn on_mem_read(
+    /// Dispatch a port read to the device responsible for `port`
+    pub fn on_port_read(
         &mut self,
-        _addr: GuestPhysAddr,
-        _data: MemReadRequest,
-        _space: GuestAddressSpaceViewMut,
+        port: Port,
+        val: PortReadRequest,
+        space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
-        Err(Error::NotImplemented(
-            "MemoryMapped device does not support reading".into(),
-        ))
+        self.count_access(AccessKind::PortRead, port as u64);
+        self.check_access(AccessKind::PortRead, port as u64)?;
+        if self.device_for(port).is_none() {
+            return Err(self
+                .missing_device(format!("No device for port {}", port), port));
+        }
+        let dev = self.device_for_mut(port).expect("Missing port device");
+        dev.on_port_read(port, val, space)
     }
-    fn on_mem_write(
+
+    /// Dispatch a port write to the device responsible for `port`
+    pub fn on_port_write(
         &mut self,
-        _addr: GuestPhysAddr,
-        _data: MemWriteRequest,
-        _space: GuestAddressSpaceViewMut,
+        port: Port,
+        val: PortWriteRequest,
+        space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
-        Err(Error::NotImplemented(
-            "MemoryMapped device does not support writing".into(),
-        ))
+        self.count_access(AccessKind::PortWrite, port as u64);
+        self.check_access(AccessKind::PortWrite, port as u64)?;
+        if self.device_for(port).is_none() {
+            return Err(self
+                .missing_device(format!("No device for port {}", port), port));
+        }
+        let mut dev = Rc::clone(
+            self.portio_map
+                .get(&PortIoRegion(port..=port))
+                .expect("Missing port device"),
+        );
+        //NOTE: This is safe because all of the clones exist in this
+        //      DeviceMap, so there are no outstanding references
+        unsafe { Rc::get_mut_unchecked(&mut dev) }
+            .on_port_write(port, val, space)?;
+        self.apply_device_changes(&dev)
     }
-    fn on_port_read(
+
+    /// Set how accesses to memory that is not serviced by any device
+    /// are handled (by default, they fault)
+    pub fn set_unassigned_memory_policy(
         &mut self,
-        _port: Port,
-        _val: PortReadRequest,
-        _space: GuestAddressSpaceViewMut,
+        policy: UnassignedMemoryPolicy,
+    ) {
+        self.unassigned_memory = policy;
+    }
+
+    /// Dispatch a memory read to the device responsible for `addr`
+    pub fn on_mem_read(
+        &mut self,
+        addr: GuestPhysAddr,
+        mut val: MemReadRequest,
+        space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
-        Err(Error::NotImplemented(
-            "PortIo device does not support reading".into(),
-        ))
+        self.count_access(AccessKind::MemRead, addr.as_u64());
+        self.check_access(AccessKind::MemRead, addr.as_u64())?;
+        let policy = self.unassigned_memory;
+        let dev = match self.device_for_mut(addr) {
+            Some(dev) => dev,
+            None => {
+                let fill = match policy {
+                    UnassignedMemoryPolicy::ReturnFF => 0xff,
+                    UnassignedMemoryPolicy::ReturnZero => 0x00,
+                    UnassignedMemoryPolicy::Fault => {
+                        return Err(self.missing_device(
+                            format!("No device for address {:?}", addr),
+                            addr,
+                        ))
+                    }
+                };
+                for byte in val.as_mut_slice().iter_mut() {
+                    *byte = fill;
+                }
+                return Ok(());
+            }
+        };
+        dev.on_mem_read(addr, val, space)
     }
-    fn on_port_write(
+
+    /// Dispatch a memory write to the device responsible for `addr`
+    pub fn on_mem_write(
         &mut self,
-        _port: Port,
-        _val: PortWriteRequest,
-        _space: GuestAddressSpaceViewMut,
+        addr: GuestPhysAddr,
+        val: MemWriteRequest,
+        space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
-        Err(Error::NotImplemented(
-            "PortIo device does not support writing".into(),
-        ))
+        self.count_access(AccessKind::MemWrite, addr.as_u64());
+        self.check_access(AccessKind::MemWrite, addr.as_u64())?;
+        let policy = self.unassigned_memory;
+        let mut dev = match self.memio_map.get(&MemIoRegion(addr..=addr)) {
+            Some(dev) => Rc::clone(dev),
+            None if policy == UnassignedMemoryPolicy::Fault => {
+                return Err(self.missing_device(
+                    format!("No device for address {:?}", addr),
+                    addr,
+                ))
+            }
+            None => return Ok(()),
+        };
+        //NOTE: This is safe because all of the clones exist in this
+        //      DeviceMap, so there are no outstanding references
+        unsafe { Rc::get_mut_unchecked(&mut dev) }
+            .on_mem_write(addr, val, space)?;
+        self.apply_device_changes(&dev)
     }
-}
 
-#[derive(Debug)]
-pub enum PortReadRequest<'a> {
-    OneByte(&'a mut [u8; 1]),
-    TwoBytes(&'a mut [u8; 2]),
-    FourBytes(&'a mut [u8; 4]),
-}
+    /// Require that memory mapped regions are placed in the MMIO holes of
+    /// `layout`
+    ///
+    /// Registering a device with a region outside of the holes (or that
+    /// overlaps RAM) will fail with `Error::InvalidMmioRegion`.
+    pub fn set_memory_layout(&mut self, layout: MemoryLayout) {
+        self.memory_layout = Some(layout);
+    }
 
-#[derive(Debug)]
-pub enum PortWriteRequest<'a> {
-    OneByte(&'a [u8; 1]),
-    TwoBytes(&'a [u8; 2]),
-    FourBytes(&'a [u8; 4]),
-}
+    pub fn memory_layout(&self) -> Option<&MemoryLayout> {
+        self.memory_layout.as_ref()
+    }
 
-impl<'a> PortReadRequest<'a> {
-    fn len(&self) -> usize {
-        self.as_slice().len()
+    // Check that a region is within an MMIO hole of the memory layout
+    fn check_mmio_placement(
+        &self,
+        device: &'static str,
+        region: &DeviceRegion,
+    ) -> Result<()> {
+        let (layout, range) = match (&self.memory_layout, region) {
+            (Some(layout), DeviceRegion::MemIo(range)) => (layout, range),
+            _ => return Ok(()),
+        };
+        let ram = layout.overlapping_ram(range);
+        if ram.is_some() || !layout.in_mmio_hole(range) {
+            return Err(Error::InvalidMmioRegion(InvalidMmioRegion {
+                device,
+                region: range.clone(),
+                ram: ram.cloned(),
+            }));
+        }
+        Ok(())
     }
 
-    pub fn as_slice(&self) -> &[u8] {
-        match self {
-            &Self::OneByte(ref val) => *val,
-            &Self::TwoBytes(ref val) => *val,
-            &Self::FourBytes(ref val) => *val,
+    /// Register a device at all of its regions and MSR ranges, or none of
+    /// them
+    ///
+    /// If any of the device's regions overlaps a registered region (or
+    /// another of its own), the map is left unchanged.
+    pub fn register_device(
+        &mut self,
+        dev: Box<dyn EmulatedDevice>,
+    ) -> Result<()> {
+        self.check_devices(core::slice::from_ref(&dev))?;
+        self.insert_device(dev)
+    }
+
+    fn insert_device(&mut self, dev: Box<dyn EmulatedDevice>) -> Result<()> {
+        let services = dev.services();
+        let msrs = dev.msr_ranges();
+        let dev = Rc::new(dev);
+        for region in services.into_iter() {
+            self.insert_region(region, &dev)?;
+        }
+        for range in msrs.into_iter() {
+            self.insert_msr_range(range, &dev)?;
         }
+        self.devices.push(dev);
+        Ok(())
     }
 
-    pub fn as_mut_slice(&mut self) -> &mut [u8] {
-        match self {
-            &mut Self::OneByte(ref mut val) => *val,
-            &mut Self::TwoBytes(ref mut val) => *val,
-            &mut Self::FourBytes(ref mut val) => *val,
+    /// Register a set of devices, or none of them
+    ///
+    /// The regions of every device are checked against the existing map
+    /// and each other before anything is registered, so if any two
+    /// overlap, the map is left unchanged and the conflicting pair is
+    /// returned as an `Error::RegionConflict`.
+    pub fn register_all(
+        &mut self,
+        devices: Vec<Box<dyn EmulatedDevice>>,
+    ) -> Result<()> {
+        self.check_devices(&devices)?;
+        for dev in devices {
+            self.insert_device(dev)?;
         }
+        Ok(())
     }
 
-    pub fn copy_from_u32(&mut self, val: u32) {
-        let arr = val.to_be_bytes();
-        let len = self.len();
-        self.as_mut_slice().copy_from_slice(&arr[4 - len..]);
+    // Check that the regions and MSR ranges of `devices` overlap neither
+    // the registered ones nor each other
+    fn check_devices(&self, devices: &[Box<dyn EmulatedDevice>]) -> Result<()> {
+        let mut batch: Vec<(&'static str, DeviceRegion)> = vec![];
+        for dev in devices.iter() {
+            let msrs = dev.msr_ranges().into_iter().map(DeviceRegion::Msr);
+            for region in dev.services().into_iter().chain(msrs) {
+                self.check_mmio_placement(dev.debug_name(), &region)?;
+                let existing = self.region_owner(&region).or_else(|| {
+                    batch
+                        .iter()
+                        .find(|(_, other)| other.overlaps(&region))
+                        .cloned()
+                });
+                if let Some((existing_device, existing_region)) = existing {
+                    return Err(Error::RegionConflict(RegionConflict {
+                        device: dev.debug_name(),
+                        region,
+                        existing_device,
+                        existing_region,
+                    }));
+                }
+                batch.push((dev.debug_name(), region));
+            }
+        }
+        Ok(())
     }
-}
 
-impl<'a> TryFrom<&'a mut [u8]> for PortReadRequest<'a> {
-    type Error = Error;
+    // The name of the device (and its region) that services any part of
+    // `region`
+    fn region_owner(
+        &self,
+        region: &DeviceRegion,
+    ) -> Option<(&'static str, DeviceRegion)> {
+        match region {
+            DeviceRegion::PortIo(range) => self
+                .portio_map
+                .get_key_value(&PortIoRegion(range.clone()))
+                .map(|(key, dev)| {
+                    (dev.debug_name(), DeviceRegion::PortIo(key.0.clone()))
+                }),
+            DeviceRegion::MemIo(range) => self
+                .memio_map
+                .get_key_value(&MemIoRegion(range.clone()))
+                .map(|(key, dev)| {
+                    (dev.debug_name(), DeviceRegion::MemIo(key.0.clone()))
+                }),
+            DeviceRegion::Msr(range) => self
+                .msr_map
+                .get_key_value(&MsrRegion(range.clone()))
+                .map(|(key, dev)| {
+                    (dev.debug_name(), DeviceRegion::Msr(key.0.clone()))
+                }),
+        }
+    }
 
-    fn try_from(buff: &'a mut [u8]) -> Result<Self> {
+    /// Collect the IRQ lines used by all of the registered devices
+    ///
+    /// A warning is logged for any line that is shared by edge triggered
+    /// devices.
+    pub fn irq_map(&self) -> BTreeMap<u8, IrqAssignment> {
+        let mut map = BTreeMap::<u8, IrqAssignment>::new();
+        for dev in self.devices.iter() {
+            for irq in dev.irq_lines() {
+                map.entry(irq).or_default().users.push(IrqUser {
+                    device: dev.debug_name(),
+                    trigger_mode: dev.irq_trigger_mode(irq),
+                });
+            }
+        }
+
+        for (irq, assignment) in map.iter() {
+            if assignment.has_edge_conflict() {
+                warn!(
+                    "IRQ{} is shared by edge triggered devices: {:?}",
+                    irq, assignment.users
+                );
+            }
+        }
+        map
+    }
+
+    /// Apply any pending region changes reported by the registered devices
+    ///
+    /// Each `RegionDelta` is applied atomically. If it cannot be applied
+    /// (e.g., a resized region would overlap another device), the map is
+    /// left as it was before that delta and an error is returned.
+    ///
+    /// The changes caused by a guest write are applied as part of the
+    /// write, so this is only needed after changing a device outside of a
+    /// guest access (e.g., hot-plugging a PCI device).
+    pub fn apply_region_changes(&mut self) -> Result<()> {
+        for i in 0..self.devices.len() {
+            let dev = Rc::clone(&self.devices[i]);
+            self.apply_device_changes(&dev)?;
+        }
+        Ok(())
+    }
+
+    // Apply the pending region changes of a single device. Only the device
+    // that handled a write can have changed its regions as a result, so
+    // this is all that is needed on the dispatch path.
+    fn apply_device_changes(
+        &mut self,
+        dev: &Rc<Box<dyn EmulatedDevice>>,
+    ) -> Result<()> {
+        while let Some(delta) = dev.region_changed() {
+            self.apply_region_delta(delta, dev)?;
+        }
+        Ok(())
+    }
+
+    /// Give every registered device a chance to perform periodic work
+    ///
+    /// `now` is the current time of the platform clock in nanoseconds.
+    pub fn poll_all(&mut self, now: u64) {
+        for dev in self.devices.iter_mut() {
+            //NOTE: This is safe because all of the clones exist in this
+            //      DeviceMap, so there are no outstanding references
+            unsafe { Rc::get_mut_unchecked(dev) }.poll(now);
+        }
+    }
+
+    /// Acknowledge the highest priority interrupt that is ready to be
+    /// injected in to the vCPU
+    ///
+    /// This is the interrupt the vCPU would receive from the INTA cycle,
+    /// so it is no longer pending once it is returned (until the guest
+    /// ends it). Interrupts delivered through the I/O APIC or local APIC
+    /// are queued by the platform's `InterruptSink` instead.
+    pub fn next_pending_interrupt(&mut self) -> Option<PendingInterrupt> {
+        for dev in self.devices.iter_mut() {
+            //NOTE: This is safe because all of the clones exist in this
+            //      DeviceMap, so there are no outstanding references
+            let dev = unsafe { Rc::get_mut_unchecked(dev) };
+            if let Some(interrupt) = dev.acknowledge_interrupt() {
+                return Some(interrupt);
+            }
+        }
+        None
+    }
+
+    /// Return every registered device to its power-on state
+    ///
+    /// Any regions that move as the devices reset (e.g., an option ROM
+    /// that is no longer decoded) are updated in the map.
+    pub fn reset_all(&mut self) -> Result<()> {
+        for dev in self.devices.iter_mut() {
+            //NOTE: This is safe because all of the clones exist in this
+            //      DeviceMap, so there are no outstanding references
+            unsafe { Rc::get_mut_unchecked(dev) }.reset();
+        }
+        self.apply_region_changes()
+    }
+
+    fn apply_region_delta(
+        &mut self,
+        delta: RegionDelta,
+        dev: &Rc<Box<dyn EmulatedDevice>>,
+    ) -> Result<()> {
+        match delta {
+            RegionDelta::Add(region) => self.insert_region(region, dev),
+            RegionDelta::Remove(region) => self.remove_region(region, dev),
+            RegionDelta::Resize { old, new } => {
+                self.remove_region(old.clone(), dev)?;
+                if let Err(e) = self.insert_region(new, dev) {
+                    // The old region was just removed, so this should not
+                    // fail, but the guest must not be able to panic the
+                    // host if it does
+                    if let Err(restore) = self.insert_region(old.clone(), dev) {
+                        return Err(Error::InvalidDevice(format!(
+                            "Failed to restore {:?} of {} after a failed resize ({:?}): {:?}",
+                            old,
+                            dev.debug_name(),
+                            e,
+                            restore
+                        )));
+                    }
+                    return Err(e);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn insert_region(
+        &mut self,
+        region: DeviceRegion,
+        dev: &Rc<Box<dyn EmulatedDevice>>,
+    ) -> Result<()> {
+        let access = dev.region_access(&region);
+        match region {
+            DeviceRegion::PortIo(val) => {
+                let key = PortIoRegion(val);
+                if self.portio_map.contains_key(&key) {
+                    let conflict = self
+                        .portio_map
+                        .get_key_value(&key)
+                        .expect("Could not get conflicting device")
+                        .0;
+
+                    return Err(Error::InvalidDevice(format!(
+                        "I/O Port already registered: 0x{:x}-0x{:x} conflicts with existing map of 0x{:x}-0x{:x}",
+                        key.0.start(), key.0.end(), conflict.0.start(), conflict.0.end()
+                    )));
+                }
+                if access != RegionAccess::ReadWrite {
+                    self.port_access
+                        .insert(PortIoRegion(key.0.clone()), access);
+                }
+                self.portio_map.insert(key, Rc::clone(dev));
+            }
+            DeviceRegion::MemIo(val) => {
+                self.check_mmio_placement(
+                    dev.debug_name(),
+                    &DeviceRegion::MemIo(val.clone()),
+                )?;
+                let key = MemIoRegion(val);
+                if self.memio_map.contains_key(&key) {
+                    let conflict = self
+                        .memio_map
+                        .get_key_value(&key)
+                        .expect("Could not get conflicting device")
+                        .0;
+                    return Err(Error::InvalidDevice(format!(
+                        "Memory region already registered: 0x{:x}-0x{:x} conflicts with existing map of 0x{:x}-0x{:x}",
+                        key.0.start().as_u64(), key.0.end().as_u64(), conflict.0.start().as_u64(), conflict.0.end().as_u64()
+                    )));
+                }
+                if access != RegionAccess::ReadWrite {
+                    self.mem_access.insert(MemIoRegion(key.0.clone()), access);
+                }
+                self.memio_map.insert(key, Rc::clone(dev));
+            }
+            DeviceRegion::Msr(val) => self.insert_msr_range(val, dev)?,
+        }
+        Ok(())
+    }
+
+    fn insert_msr_range(
+        &mut self,
+        range: RangeInclusive<u32>,
+        dev: &Rc<Box<dyn EmulatedDevice>>,
+    ) -> Result<()> {
+        let key = MsrRegion(range);
+        if let Some((conflict, existing)) = self.msr_map.get_key_value(&key) {
+            return Err(Error::RegionConflict(RegionConflict {
+                device: dev.debug_name(),
+                region: DeviceRegion::Msr(key.0),
+                existing_device: existing.debug_name(),
+                existing_region: DeviceRegion::Msr(conflict.0.clone()),
+            }));
+        }
+        self.msr_map.insert(key, Rc::clone(dev));
+        Ok(())
+    }
+
+    fn remove_region(
+        &mut self,
+        region: DeviceRegion,
+        dev: &Rc<Box<dyn EmulatedDevice>>,
+    ) -> Result<()> {
+        let removed = match region {
+            DeviceRegion::PortIo(ref val) => {
+                let key = PortIoRegion(val.clone());
+                match self.portio_map.get_key_value(&key) {
+                    Some((existing, owner))
+                        if existing.0 == key.0 && Rc::ptr_eq(owner, dev) =>
+                    {
+                        self.access_counts.ports.remove(&key);
+                        self.port_access.remove(&key);
+                        self.portio_map.remove(&key)
+                    }
+                    _ => None,
+                }
+            }
+            DeviceRegion::MemIo(ref val) => {
+                let key = MemIoRegion(val.clone());
+                match self.memio_map.get_key_value(&key) {
+                    Some((existing, owner))
+                        if existing.0 == key.0 && Rc::ptr_eq(owner, dev) =>
+                    {
+                        self.access_counts.mem.remove(&key);
+                        self.mem_access.remove(&key);
+                        self.memio_map.remove(&key)
+                    }
+                    _ => None,
+                }
+            }
+            DeviceRegion::Msr(ref val) => {
+                let key = MsrRegion(val.clone());
+                match self.msr_map.get_key_value(&key) {
+                    Some((existing, owner))
+                        if existing.0 == key.0 && Rc::ptr_eq(owner, dev) =>
+                    {
+                        self.msr_map.remove(&key)
+                    }
+                    _ => None,
+                }
+            }
+        };
+
+        removed.map(|_| ()).ok_or_else(|| {
+            Error::InvalidDevice(format!(
+                "Region {:?} is not registered to this device",
+                region
+            ))
+        })
+    }
+}
+
+/// Conversion of a device to `Any`, so a `dyn EmulatedDevice` can be
+/// downcast to its concrete type
+///
+/// This is implemented for every `EmulatedDevice`, so devices do not need
+/// to implement it themselves. For example:
+///
+/// ```ignore
+/// let com = map
+///     .device_for(0x3f8u16)
+///     .and_then(|dev| dev.as_any().downcast_ref::<ComDevice>());
+/// ```
+///
+/// Note that a device registered as an `Rc<RefCell<T>>` must be downcast
+/// to that type (not `T`).
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: EmulatedDevice + 'static> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+pub trait EmulatedDevice: AsAny {
+    fn services(&self) -> Vec<DeviceRegion>;
+
+    /// A short, human readable name for this device
+    fn debug_name(&self) -> &'static str {
+        let name = core::any::type_name::<Self>();
+        name.rsplit("::").next().unwrap_or(name)
+    }
+
+    /// The kind of this device, if it is one that others may depend on
+    fn kind(&self) -> Option<DeviceKind> {
+        None
+    }
+
+    /// The kinds of device that must also be present for this device to
+    /// function (e.g., an interrupt controller to deliver its IRQs)
+    fn depends_on(&self) -> Vec<DeviceKind> {
+        vec![]
+    }
+
+    /// The IRQ lines this device may raise
+    fn irq_lines(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    /// The trigger mode of one of this device's IRQ lines
+    ///
+    /// Legacy (ISA) devices are edge triggered, which is the default.
+    fn irq_trigger_mode(&self, _irq: u8) -> TriggerMode {
+        TriggerMode::Edge
+    }
+
+    /// Report a change to the regions serviced by this device
+    ///
+    /// This is polled by `DeviceMap::apply_region_changes`. A device should
+    /// return a given delta only once.
+    fn region_changed(&self) -> Option<RegionDelta> {
+        None
+    }
+
+    /// The directions in which the guest may access `region` (one of the
+    /// regions returned by `services`)
+    ///
+    /// The `DeviceMap` rejects accesses in any other direction with
+    /// `Error::DisallowedAccess`, without calling the device.
+    fn region_access(&self, _region: &DeviceRegion) -> RegionAccess {
+        RegionAccess::ReadWrite
+    }
+
+    /// Perform any internal work that depends on the passage of time
+    /// (e.g., timer expiry or draining a FIFO)
+    ///
+    /// This is called periodically by `DeviceMap::poll_all`, with `now` as
+    /// the current time of the platform clock in nanoseconds.
+    fn poll(&mut self, _now: u64) {}
+
+    /// Return the device to its power-on state
+    ///
+    /// This is called by `DeviceMap::reset_all` when the guest is reset.
+    /// Configuration provided by the host (e.g., backends and IRQ sinks)
+    /// is retained.
+    fn reset(&mut self) {}
+
+    /// The model specific registers (by index) serviced by this device
+    fn msr_ranges(&self) -> Vec<RangeInclusive<u32>> {
+        vec![]
+    }
+
+    /// Describe the resources used by this device in a single snapshot
+    ///
+    /// The default combines `debug_name`, `kind`, `depends_on`,
+    /// `services`, `irq_lines` (with `irq_trigger_mode`) and `msr_ranges`.
+    fn describe(&self) -> DeviceDescription {
+        DeviceDescription {
+            name: self.debug_name(),
+            kind: self.kind(),
+            depends_on: self.depends_on(),
+            regions: self.services(),
+            irqs: self
+                .irq_lines()
+                .into_iter()
+                .map(|irq| (irq, self.irq_trigger_mode(irq)))
+                .collect(),
+            msr_ranges: self.msr_ranges(),
+        }
+    }
+
+    /// Acknowledge the highest priority interrupt that is ready to be
+    /// injected, if this device is an interrupt controller that is
+    /// consulted by the vCPU (e.g., the 8259 PIC)
+    fn acknowledge_interrupt(&mut self) -> Option<PendingInterrupt> {
+        None
+    }
+
+    /// Handle a guest `rdmsr` of one of the MSRs in `msr_ranges`
+    fn on_msr_read(&mut self, msr: u32) -> Result<u64> {
+        Err(Error::NotImplemented(format!(
+            "MSR read of 0x{:x} is not implemented by {}",
+            msr,
+            self.debug_name()
+        )))
+    }
+
+    /// Handle a guest `wrmsr` of one of the MSRs in `msr_ranges`
+    fn on_msr_write(&mut self, msr: u32, _val: u64) -> Result<()> {
+        Err(Error::NotImplemented(format!(
+            "MSR write of 0x{:x} is not implemented by {}",
+            msr,
+            self.debug_name()
+        )))
+    }
+
+    fn on_mem_read(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemReadRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        Err(Error::UnhandledAccess(UnhandledAccess {
+            device: self.debug_name(),
+            kind: AccessKind::MemRead,
+            addr: addr.as_u64(),
+            width: data.as_slice().len(),
+        }))
+    }
+    fn on_mem_write(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemWriteRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        Err(Error::UnhandledAccess(UnhandledAccess {
+            device: self.debug_name(),
+            kind: AccessKind::MemWrite,
+            addr: addr.as_u64(),
+            width: data.as_slice().len(),
+        }))
+    }
+    /// Handle a guest read of one of the ports in `services`
+    ///
+    /// Like `on_mem_read`, this takes `&mut self` so that reads can have
+    /// side effects, as they do for many real registers (e.g., a latched
+    /// count that advances by a byte per read, or status bits that clear or
+    /// toggle when read). Such a device fills the request from its current
+    /// state and then updates that state, so successive reads of the same
+    /// port can return different values. Side effects must not be
+    /// performed by `RegisterBlock::read_reg`, which is used for debugging.
+    fn on_port_read(
+        &mut self,
+        port: Port,
+        val: PortReadRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        Err(Error::UnhandledAccess(UnhandledAccess {
+            device: self.debug_name(),
+            kind: AccessKind::PortRead,
+            addr: port as u64,
+            width: val.len(),
+        }))
+    }
+    fn on_port_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        Err(Error::UnhandledAccess(UnhandledAccess {
+            device: self.debug_name(),
+            kind: AccessKind::PortWrite,
+            addr: port as u64,
+            width: val.as_slice().len(),
+        }))
+    }
+}
+
+/// A device that is shared with other devices (for example, the DMA
+/// controller used by the floppy controller) can be registered in a
+/// `DeviceMap` as an `Rc<RefCell<T>>`
+impl<T: EmulatedDevice + 'static> EmulatedDevice for Rc<RefCell<T>> {
+    fn services(&self) -> Vec<DeviceRegion> {
+        self.borrow().services()
+    }
+
+    fn debug_name(&self) -> &'static str {
+        self.borrow().debug_name()
+    }
+
+    fn kind(&self) -> Option<DeviceKind> {
+        self.borrow().kind()
+    }
+
+    fn depends_on(&self) -> Vec<DeviceKind> {
+        self.borrow().depends_on()
+    }
+
+    fn irq_lines(&self) -> Vec<u8> {
+        self.borrow().irq_lines()
+    }
+
+    fn irq_trigger_mode(&self, irq: u8) -> TriggerMode {
+        self.borrow().irq_trigger_mode(irq)
+    }
+
+    fn region_changed(&self) -> Option<RegionDelta> {
+        self.borrow().region_changed()
+    }
+
+    fn region_access(&self, region: &DeviceRegion) -> RegionAccess {
+        self.borrow().region_access(region)
+    }
+
+    fn msr_ranges(&self) -> Vec<RangeInclusive<u32>> {
+        self.borrow().msr_ranges()
+    }
+
+    fn describe(&self) -> DeviceDescription {
+        self.borrow().describe()
+    }
+
+    fn acknowledge_interrupt(&mut self) -> Option<PendingInterrupt> {
+        self.borrow_mut().acknowledge_interrupt()
+    }
+
+    fn on_msr_read(&mut self, msr: u32) -> Result<u64> {
+        self.borrow_mut().on_msr_read(msr)
+    }
+
+    fn on_msr_write(&mut self, msr: u32, val: u64) -> Result<()> {
+        self.borrow_mut().on_msr_write(msr, val)
+    }
+
+    fn poll(&mut self, now: u64) {
+        self.borrow_mut().poll(now)
+    }
+
+    fn reset(&mut self) {
+        self.borrow_mut().reset()
+    }
+
+    fn on_mem_read(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemReadRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.borrow_mut().on_mem_read(addr, data, space)
+    }
+
+    fn on_mem_write(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemWriteRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.borrow_mut().on_mem_write(addr, data, space)
+    }
+
+    fn on_port_read(
+        &mut self,
+        port: Port,
+        val: PortReadRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.borrow_mut().on_port_read(port, val, space)
+    }
+
+    fn on_port_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.borrow_mut().on_port_write(port, val, space)
+    }
+}
+
+/// The widths (in bytes) of a port access
+///
+/// `IN` and `OUT` (and their string forms) move a byte, word or dword, so
+/// no guest port access is wider than EAX, and 3 byte accesses do not
+/// exist. MMIO is reached with ordinary memory operands instead, which
+/// can be a qword (or wider), so memory requests accept any length.
+pub const PORT_ACCESS_WIDTHS: &[usize] = &[1, 2, 4];
+
+/// Fail with `Error::AccessWidth` unless `len` is a valid port access width
+pub fn require_port_width(len: usize) -> Result<()> {
+    require_len(len, PORT_ACCESS_WIDTHS)
+}
+
+/// The highest IRQ line of the legacy (ISA) interrupt controllers
+pub const MAX_ISA_IRQ: u8 = 15;
+
+/// The `len` ports starting at `base`, failing if the block would extend
+/// past the end of the port space
+pub fn port_block(base: Port, len: u16) -> Result<RangeInclusive<Port>> {
+    match len.checked_sub(1).and_then(|last| base.checked_add(last)) {
+        Some(end) => Ok(base..=end),
+        None => Err(Error::InvalidValue(format!(
+            "Invalid block of {} ports at 0x{:x}",
+            len, base
+        ))),
+    }
+}
+
+/// Fail unless `irq` is one of the lines of the legacy interrupt
+/// controllers
+pub fn check_isa_irq(irq: u8) -> Result<()> {
+    if irq > MAX_ISA_IRQ {
+        return Err(Error::InvalidValue(format!("Invalid ISA IRQ: {}", irq)));
+    }
+    Ok(())
+}
+
+/// Fail with `Error::AccessWidth` unless `len` is one of `expected`
+pub fn require_len(len: usize, expected: &'static [usize]) -> Result<()> {
+    if expected.contains(&len) {
+        Ok(())
+    } else {
+        Err(Error::AccessWidth {
+            expected,
+            actual: len,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum PortReadRequest<'a> {
+    OneByte(&'a mut [u8; 1]),
+    TwoBytes(&'a mut [u8; 2]),
+    FourBytes(&'a mut [u8; 4]),
+}
+
+#[derive(Debug)]
+pub enum PortWriteRequest<'a> {
+    OneByte(&'a [u8; 1]),
+    TwoBytes(&'a [u8; 2]),
+    FourBytes(&'a [u8; 4]),
+}
+
+/// The width of a port access
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortWidth {
+    Byte,
+    Word,
+    DWord,
+}
+
+/// The value of a port write, decoded according to the access width
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortValue {
+    U8(u8),
+    U16(u16),
+    U32(u32),
+}
+
+impl PortValue {
+    /// The width of the access that produced this value
+    pub fn width(&self) -> PortWidth {
+        match self {
+            Self::U8(_) => PortWidth::Byte,
+            Self::U16(_) => PortWidth::Word,
+            Self::U32(_) => PortWidth::DWord,
+        }
+    }
+
+    /// The value, zero extended to 32 bits
+    pub fn as_u32(&self) -> u32 {
+        match *self {
+            Self::U8(val) => val as u32,
+            Self::U16(val) => val as u32,
+            Self::U32(val) => val,
+        }
+    }
+}
+
+impl<'a> PortReadRequest<'a> {
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    /// The width of this read
+    pub fn width(&self) -> PortWidth {
+        match self {
+            Self::OneByte(_) => PortWidth::Byte,
+            Self::TwoBytes(_) => PortWidth::Word,
+            Self::FourBytes(_) => PortWidth::DWord,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            &Self::OneByte(ref val) => *val,
+            &Self::TwoBytes(ref val) => *val,
+            &Self::FourBytes(ref val) => *val,
+        }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        match self {
+            &mut Self::OneByte(ref mut val) => *val,
+            &mut Self::TwoBytes(ref mut val) => *val,
+            &mut Self::FourBytes(ref mut val) => *val,
+        }
+    }
+
+    pub fn copy_from_u32(&mut self, val: u32) {
+        let arr = val.to_be_bytes();
+        let len = self.len();
+        self.as_mut_slice().copy_from_slice(&arr[4 - len..]);
+    }
+
+    /// Fill the request one byte at a time, in the order the bytes appear
+    /// on the bus (least significant first)
+    ///
+    /// This is used to split a wide read of a byte wide register into a
+    /// sequence of byte reads.
+    pub fn fill_bytes(&mut self, mut next: impl FnMut() -> u8) {
+        for byte in self.as_mut_slice().iter_mut().rev() {
+            *byte = next();
+        }
+    }
+
+    /// Copy `src` into the request, failing if it is not exactly as wide
+    /// as the request
+    pub fn fill_from_slice(&mut self, src: &[u8]) -> Result<()> {
+        fill_from_slice(self.as_mut_slice(), src)
+    }
+}
+
+impl<'a> TryFrom<&'a mut [u8]> for PortReadRequest<'a> {
+    type Error = Error;
+
+    fn try_from(buff: &'a mut [u8]) -> Result<Self> {
+        require_port_width(buff.len())?;
         let res = match buff.len() {
             1 => Self::OneByte(unsafe {
                 &mut *(buff.as_mut_ptr() as *mut [u8; 1])
@@ -284,12 +1856,7 @@ impl<'a> TryFrom<&'a mut [u8]> for PortReadRequest<'a> {
             4 => Self::FourBytes(unsafe {
                 &mut *(buff.as_mut_ptr() as *mut [u8; 4])
             }),
-            len => {
-                return Err(Error::InvalidValue(format!(
-                    "Invalid slice length: {}",
-                    len
-                )))
-            }
+            _ => unreachable!(),
         };
         Ok(res)
     }
@@ -322,6 +1889,8 @@ impl<'a> PortWriteRequest<'a> {
         }
     }
 
+    /// The written value, zero extended to 32 bits (see `value` for the
+    /// byte order)
     pub fn as_u32(&self) -> u32 {
         let arr = match self {
             Self::OneByte(val) => [0, 0, 0, val[0]],
@@ -330,24 +1899,41 @@ impl<'a> PortWriteRequest<'a> {
         };
         u32::from_be_bytes(arr)
     }
+
+    /// The written bytes, in the order they appear on the bus (least
+    /// significant first)
+    pub fn bytes(&self) -> impl Iterator<Item = u8> + 'a {
+        self.as_slice().iter().rev().copied()
+    }
+
+    /// The written value, decoded according to the width of the write
+    ///
+    /// The request buffer holds the value most significant byte first
+    /// (the port I/O emulation stores the guest's register big endian),
+    /// so it is decoded as big endian, the same as `as_u32`. This is the
+    /// value the guest wrote, i.e., the little endian decoding of the
+    /// bytes in bus order (see `bytes`).
+    pub fn value(&self) -> PortValue {
+        match *self {
+            Self::OneByte(val) => PortValue::U8(val[0]),
+            Self::TwoBytes(val) => PortValue::U16(u16::from_be_bytes(*val)),
+            Self::FourBytes(val) => PortValue::U32(u32::from_be_bytes(*val)),
+        }
+    }
 }
 
 impl<'a> TryFrom<&'a [u8]> for PortWriteRequest<'a> {
     type Error = Error;
 
     fn try_from(buff: &'a [u8]) -> Result<Self> {
+        require_port_width(buff.len())?;
         let res = match buff.len() {
             1 => Self::OneByte(unsafe { &*(buff.as_ptr() as *const [u8; 1]) }),
             2 => Self::TwoBytes(unsafe { &*(buff.as_ptr() as *const [u8; 2]) }),
             4 => {
                 Self::FourBytes(unsafe { &*(buff.as_ptr() as *const [u8; 4]) })
             }
-            len => {
-                return Err(Error::InvalidValue(format!(
-                    "Invalid slice length: {}",
-                    len
-                )))
-            }
+            _ => unreachable!(),
         };
         Ok(res)
     }
@@ -359,10 +1945,10 @@ impl<'a> TryInto<u8> for PortWriteRequest<'a> {
     fn try_into(self) -> Result<u8> {
         match self {
             Self::OneByte(val) => Ok(val[0]),
-            val => Err(Error::InvalidValue(format!(
-                "Value {} cannot be converted to u8",
-                val
-            ))),
+            val => Err(Error::AccessWidth {
+                expected: &[1],
+                actual: val.as_slice().len(),
+            }),
         }
     }
 }
@@ -373,10 +1959,10 @@ impl<'a> TryInto<u16> for PortWriteRequest<'a> {
     fn try_into(self) -> Result<u16> {
         match self {
             Self::TwoBytes(val) => Ok(u16::from_be_bytes(*val)),
-            val => Err(Error::InvalidValue(format!(
-                "Value {} cannot be converted to u16",
-                val
-            ))),
+            val => Err(Error::AccessWidth {
+                expected: &[2],
+                actual: val.as_slice().len(),
+            }),
         }
     }
 }
@@ -387,10 +1973,10 @@ impl<'a> TryInto<u32> for PortWriteRequest<'a> {
     fn try_into(self) -> Result<u32> {
         match self {
             Self::FourBytes(val) => Ok(u32::from_be_bytes(*val)),
-            val => Err(Error::InvalidValue(format!(
-                "Value {} cannot be converted to u32",
-                val
-            ))),
+            val => Err(Error::AccessWidth {
+                expected: &[4],
+                actual: val.as_slice().len(),
+            }),
         }
     }
 }
@@ -413,6 +1999,117 @@ impl<'a> fmt::Display for PortWriteRequest<'a> {
     }
 }
 
+/// An owned copy of a `PortWriteRequest`, which can be stored (e.g., for
+/// deferred processing or replay) and converted back into a request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OwnedPortWrite {
+    OneByte([u8; 1]),
+    TwoBytes([u8; 2]),
+    FourBytes([u8; 4]),
+}
+
+impl OwnedPortWrite {
+    /// A write of the value stored in `bytes` in little endian order (as
+    /// in guest memory, e.g., the source of an `outs`)
+    pub fn from_le_bytes(bytes: &[u8]) -> Result<Self> {
+        match *bytes {
+            [a] => Ok(Self::OneByte([a])),
+            [a, b] => Ok(Self::TwoBytes([b, a])),
+            [a, b, c, d] => Ok(Self::FourBytes([d, c, b, a])),
+            _ => Err(Error::AccessWidth {
+                expected: &[1, 2, 4],
+                actual: bytes.len(),
+            }),
+        }
+    }
+
+    /// A request that writes the stored bytes
+    pub fn as_request(&self) -> PortWriteRequest {
+        match self {
+            Self::OneByte(val) => PortWriteRequest::OneByte(val),
+            Self::TwoBytes(val) => PortWriteRequest::TwoBytes(val),
+            Self::FourBytes(val) => PortWriteRequest::FourBytes(val),
+        }
+    }
+}
+
+impl<'a> From<&PortWriteRequest<'a>> for OwnedPortWrite {
+    fn from(request: &PortWriteRequest<'a>) -> Self {
+        match *request {
+            PortWriteRequest::OneByte(val) => Self::OneByte(*val),
+            PortWriteRequest::TwoBytes(val) => Self::TwoBytes(*val),
+            PortWriteRequest::FourBytes(val) => Self::FourBytes(*val),
+        }
+    }
+}
+
+impl<'a> From<&'a OwnedPortWrite> for PortWriteRequest<'a> {
+    fn from(owned: &'a OwnedPortWrite) -> Self {
+        owned.as_request()
+    }
+}
+
+/// An owned buffer for a port read of a given width, which can be stored
+/// and later passed to a device as a `PortReadRequest`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OwnedPortRead {
+    OneByte([u8; 1]),
+    TwoBytes([u8; 2]),
+    FourBytes([u8; 4]),
+}
+
+impl OwnedPortRead {
+    /// A zeroed buffer for a read of the given width
+    pub fn new(width: PortWidth) -> Self {
+        match width {
+            PortWidth::Byte => Self::OneByte([0]),
+            PortWidth::Word => Self::TwoBytes([0; 2]),
+            PortWidth::DWord => Self::FourBytes([0; 4]),
+        }
+    }
+
+    /// A request that reads into this buffer
+    pub fn as_request(&mut self) -> PortReadRequest {
+        match self {
+            Self::OneByte(val) => PortReadRequest::OneByte(val),
+            Self::TwoBytes(val) => PortReadRequest::TwoBytes(val),
+            Self::FourBytes(val) => PortReadRequest::FourBytes(val),
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            Self::OneByte(val) => val,
+            Self::TwoBytes(val) => val,
+            Self::FourBytes(val) => val,
+        }
+    }
+
+    /// The bytes read, zero extended to 32 bits
+    pub fn as_u32(&self) -> u32 {
+        let mut arr = [0u8; 4];
+        let len = self.as_slice().len();
+        arr[4 - len..].copy_from_slice(self.as_slice());
+        u32::from_be_bytes(arr)
+    }
+}
+
+impl<'a> From<&PortReadRequest<'a>> for OwnedPortRead {
+    fn from(request: &PortReadRequest<'a>) -> Self {
+        match request {
+            PortReadRequest::OneByte(val) => Self::OneByte(**val),
+            PortReadRequest::TwoBytes(val) => Self::TwoBytes(**val),
+            PortReadRequest::FourBytes(val) => Self::FourBytes(**val),
+        }
+    }
+}
+
+impl<'a> From<&'a mut OwnedPortRead> for PortReadRequest<'a> {
+    fn from(owned: &'a mut OwnedPortRead) -> Self {
+        owned.as_request()
+    }
+}
+
 pub struct MemWriteRequest<'a> {
     data: &'a [u8],
 }
@@ -445,14 +2142,19 @@ impl<'a> TryInto<u8> for MemWriteRequest<'a> {
     type Error = Error;
 
     fn try_into(self) -> Result<u8> {
-        if self.data.len() == 1 {
-            Ok(self.data[0])
-        } else {
-            Err(Error::InvalidValue(format!(
-                "Value {} cannot be converted to u8",
-                self
-            )))
-        }
+        require_len(self.data.len(), &[1])?;
+        Ok(self.data[0])
+    }
+}
+
+impl<'a> TryInto<u32> for MemWriteRequest<'a> {
+    type Error = Error;
+
+    fn try_into(self) -> Result<u32> {
+        require_len(self.data.len(), &[4])?;
+        let mut arr = [0u8; 4];
+        arr.copy_from_slice(self.data);
+        Ok(u32::from_be_bytes(arr))
     }
 }
 
@@ -469,11 +2171,85 @@ impl<'a> MemReadRequest<'a> {
     pub fn as_slice(&self) -> &[u8] {
         self.data
     }
-}
 
-impl<'a> fmt::Display for MemReadRequest<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "MemReadRequest({:?})", self.data)
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        self.data
+    }
+
+    /// Copy the (big-endian) value into the request, zero extending it if
+    /// the request is wider than four bytes
+    pub fn copy_from_u32(&mut self, val: u32) {
+        let arr = val.to_be_bytes();
+        let len = self.data.len();
+        for byte in self.data.iter_mut() {
+            *byte = 0;
+        }
+        if len >= 4 {
+            self.data[len - 4..].copy_from_slice(&arr);
+        } else {
+            self.data.copy_from_slice(&arr[4 - len..]);
+        }
+    }
+
+    /// Copy `src` into the request, failing if it is not exactly as wide
+    /// as the request
+    pub fn fill_from_slice(&mut self, src: &[u8]) -> Result<()> {
+        fill_from_slice(self.data, src)
+    }
+}
+
+fn fill_from_slice(dest: &mut [u8], src: &[u8]) -> Result<()> {
+    if dest.len() != src.len() {
+        return Err(Error::InvalidValue(format!(
+            "Cannot fill a {} byte read with {} bytes",
+            dest.len(),
+            src.len()
+        )));
+    }
+    dest.copy_from_slice(src);
+    Ok(())
+}
+
+impl<'a> fmt::Display for MemReadRequest<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MemReadRequest({:?})", self.data)
+    }
+}
+
+/// Fixtures shared by the tests of the device modules
+#[cfg(test)]
+pub(crate) mod test_util {
+    use crate::device::interrupt::IrqSink;
+    use crate::memory::{
+        GuestAddressSpace, GuestAddressSpaceViewMut, GuestPhysAddr,
+    };
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    /// A view of a new (empty) guest address space, which is leaked so it
+    /// lives as long as the test
+    pub fn define_test_view() -> GuestAddressSpaceViewMut<'static> {
+        let space: &'static mut GuestAddressSpace =
+            Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
+        GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space)
+    }
+
+    /// An `IrqSink` that records every IRQ raised (or lowered) through it
+    #[derive(Default)]
+    pub struct MockIrqs {
+        pub raised: RefCell<Vec<u8>>,
+        pub lowered: RefCell<Vec<u8>>,
+    }
+
+    impl IrqSink for MockIrqs {
+        fn raise_irq(&self, irq: u8) {
+            self.raised.borrow_mut().push(irq);
+        }
+
+        fn lower_irq(&self, irq: u8) {
+            self.lowered.borrow_mut().push(irq);
+        }
     }
 }
 
@@ -481,17 +2257,14 @@ impl<'a> fmt::Display for MemReadRequest<'a> {
 mod test {
     use super::*;
     use crate::device::com::*;
+    use crate::device::test_util::define_test_view;
     use crate::memory::{
         GuestAddressSpace, GuestAddressSpaceViewMut, GuestPhysAddr,
+        PrivilegeLevel,
     };
+    use core::cell::Cell;
     use core::convert::TryInto;
 
-    fn define_test_view() -> GuestAddressSpaceViewMut<'static> {
-        let space: &'static mut GuestAddressSpace =
-            Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
-        GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space)
-    }
-
     // This is just a dummy device so we can have arbitrary port ranges
     // for testing.
     struct DummyDevice {
@@ -513,6 +2286,304 @@ mod test {
         }
     }
 
+    // A device that reports a single pending region change
+    struct ResizingDevice {
+        services: Vec<RangeInclusive<Port>>,
+        pending: Cell<Option<RegionDelta>>,
+    }
+
+    impl ResizingDevice {
+        fn new(
+            services: Vec<RangeInclusive<Port>>,
+            delta: RegionDelta,
+        ) -> Box<dyn EmulatedDevice> {
+            Box::new(Self {
+                services,
+                pending: Cell::new(Some(delta)),
+            })
+        }
+    }
+
+    impl EmulatedDevice for ResizingDevice {
+        fn services(&self) -> Vec<DeviceRegion> {
+            self.services
+                .iter()
+                .map(|x| DeviceRegion::PortIo(x.clone()))
+                .collect()
+        }
+
+        fn region_changed(&self) -> Option<RegionDelta> {
+            self.pending.take()
+        }
+
+        fn on_port_write(
+            &mut self,
+            _port: Port,
+            _val: PortWriteRequest,
+            _space: GuestAddressSpaceViewMut,
+        ) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_region_grow() {
+        let mut map = DeviceMap::default();
+        let dev = ResizingDevice::new(
+            vec![0..=3],
+            RegionDelta::Resize {
+                old: DeviceRegion::PortIo(0..=3),
+                new: DeviceRegion::PortIo(0..=7),
+            },
+        );
+        map.register_device(dev).unwrap();
+        assert!(map.device_for(6u16).is_none());
+
+        map.apply_region_changes().unwrap();
+        assert!(map.device_for(0u16).is_some());
+        assert!(map.device_for(6u16).is_some());
+        assert!(map.device_for(8u16).is_none());
+    }
+
+    #[test]
+    fn test_region_add_and_remove() {
+        let mut map = DeviceMap::default();
+        let dev = ResizingDevice::new(
+            vec![0..=3],
+            RegionDelta::Add(DeviceRegion::PortIo(10..=11)),
+        );
+        map.register_device(dev).unwrap();
+        map.apply_region_changes().unwrap();
+        assert!(map.device_for(11u16).is_some());
+
+        let dev = ResizingDevice::new(
+            vec![20..=21],
+            RegionDelta::Remove(DeviceRegion::PortIo(20..=21)),
+        );
+        map.register_device(dev).unwrap();
+        map.apply_region_changes().unwrap();
+        assert!(map.device_for(20u16).is_none());
+        assert!(map.device_for(11u16).is_some());
+    }
+
+    #[test]
+    fn test_write_applies_region_changes() {
+        let mut map = DeviceMap::default();
+        let dev = ResizingDevice::new(
+            vec![0..=3],
+            RegionDelta::Add(DeviceRegion::PortIo(10..=11)),
+        );
+        map.register_device(dev).unwrap();
+        let dev = ResizingDevice::new(
+            vec![20..=21],
+            RegionDelta::Add(DeviceRegion::PortIo(30..=31)),
+        );
+        map.register_device(dev).unwrap();
+
+        // Only the device that handled the write is checked for changes
+        map.on_port_write(
+            0,
+            PortWriteRequest::OneByte(&[0]),
+            define_test_view(),
+        )
+        .unwrap();
+        assert!(map.device_for(10u16).is_some());
+        assert!(map.device_for(30u16).is_none());
+
+        map.apply_region_changes().unwrap();
+        assert!(map.device_for(30u16).is_some());
+    }
+
+    #[test]
+    fn test_conflicting_region_grow_rolls_back() {
+        let mut map = DeviceMap::default();
+        let dev = ResizingDevice::new(
+            vec![0..=3],
+            RegionDelta::Resize {
+                old: DeviceRegion::PortIo(0..=3),
+                new: DeviceRegion::PortIo(0..=7),
+            },
+        );
+        map.register_device(dev).unwrap();
+        map.register_device(DummyDevice::new(vec![4..=5])).unwrap();
+
+        assert!(map.apply_region_changes().is_err());
+
+        // The original region is still serviced by the resizing device
+        let key = PortIoRegion(0..=0);
+        let (region, _) = map.portio_map.get_key_value(&key).unwrap();
+        assert_eq!(region.0, 0..=3);
+
+        // And the conflicting device still owns its region
+        let key = PortIoRegion(4..=4);
+        let (region, _) = map.portio_map.get_key_value(&key).unwrap();
+        assert_eq!(region.0, 4..=5);
+        assert!(map.device_for(6u16).is_none());
+    }
+
+    #[test]
+    fn test_register_all_with_internal_conflict() {
+        let mut map = DeviceMap::default();
+        let devices = vec![
+            DummyDevice::new(vec![0..=3]),
+            DummyDevice::new(vec![8..=9, 10..=11]),
+            DummyDevice::new(vec![16..=17, 2..=4]),
+        ];
+        assert_eq!(
+            map.register_all(devices),
+            Err(Error::RegionConflict(RegionConflict {
+                device: "DummyDevice",
+                region: DeviceRegion::PortIo(2..=4),
+                existing_device: "DummyDevice",
+                existing_region: DeviceRegion::PortIo(0..=3),
+            }))
+        );
+        assert!(map.device_for(0u16).is_none());
+        assert!(map.device_for(8u16).is_none());
+        assert!(map.device_for(16u16).is_none());
+    }
+
+    #[test]
+    fn test_register_all() {
+        let mut map = DeviceMap::default();
+        map.register_device(DummyDevice::new(vec![0..=3])).unwrap();
+
+        let devices = vec![
+            DummyDevice::new(vec![8..=9]),
+            ComDevice::new(0, 0x3f8),
+            DummyDevice::new(vec![0x3fc..=0x400]),
+        ];
+        match map.register_all(devices) {
+            Err(Error::RegionConflict(conflict)) => {
+                assert_eq!(conflict.device, "DummyDevice");
+                assert_eq!(conflict.existing_device, "ComDevice");
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert!(map.device_for(8u16).is_none());
+
+        let devices =
+            vec![DummyDevice::new(vec![8..=9]), ComDevice::new(0, 0x3f8)];
+        map.register_all(devices).unwrap();
+        assert!(map.device_for(0u16).is_some());
+        assert!(map.device_for(9u16).is_some());
+        assert!(map.device_for(0x3ff_u16).is_some());
+    }
+
+    #[test]
+    fn test_mmio_placement() {
+        let region = |start: u64, end: u64| {
+            GuestPhysAddr::new(start)..=GuestPhysAddr::new(end)
+        };
+        let mut map = DeviceMap::default();
+        map.set_memory_layout(MemoryLayout::for_ram_size(256));
+
+        // A region in RAM is rejected
+        let res = map.register_device(rom::RomDevice::new(
+            region(0x200000, 0x200fff),
+            vec![],
+        ));
+        assert_eq!(
+            res,
+            Err(Error::InvalidMmioRegion(InvalidMmioRegion {
+                device: "RomDevice",
+                region: region(0x200000, 0x200fff),
+                ram: Some(region(0x100000, 0xfffffff)),
+            }))
+        );
+        assert!(map.device_for(GuestPhysAddr::new(0x200000)).is_none());
+
+        // As is one that is not in RAM, but not in a hole either
+        let devices: Vec<Box<dyn EmulatedDevice>> = vec![rom::RomDevice::new(
+            region(0x8000_0000, 0x8000_0fff),
+            vec![],
+        )];
+        assert!(matches!(
+            map.register_all(devices),
+            Err(Error::InvalidMmioRegion(InvalidMmioRegion { ram: None, .. }))
+        ));
+
+        // Regions in the holes are fine
+        map.register_device(rom::RomDevice::new(
+            region(0xc0000, 0xc7fff),
+            vec![],
+        ))
+        .unwrap();
+        map.register_device(rom::RomDevice::new(
+            region(0xfed0_0000, 0xfed0_03ff),
+            vec![],
+        ))
+        .unwrap();
+        assert!(map.device_for(GuestPhysAddr::new(0xc0000)).is_some());
+        assert!(map.device_for(GuestPhysAddr::new(0xfed0_0000)).is_some());
+    }
+
+    #[test]
+    fn test_region_from_base_size() {
+        assert_eq!(
+            DeviceRegion::mem_from_base_size(
+                GuestPhysAddr::new(0x1000),
+                0x1000
+            ),
+            Ok(DeviceRegion::MemIo(
+                GuestPhysAddr::new(0x1000)..=GuestPhysAddr::new(0x1fff)
+            ))
+        );
+        assert!(DeviceRegion::mem_from_base_size(
+            GuestPhysAddr::new(0x1000),
+            0
+        )
+        .is_err());
+        assert!(DeviceRegion::mem_from_base_size(
+            GuestPhysAddr::new(!0 - 0xfff),
+            0x2000
+        )
+        .is_err());
+
+        assert_eq!(
+            DeviceRegion::port_from_base_size(0x3f8, 8),
+            Ok(DeviceRegion::PortIo(0x3f8..=0x3ff))
+        );
+        assert_eq!(
+            DeviceRegion::port_from_base_size(0xffff, 1),
+            Ok(DeviceRegion::PortIo(0xffff..=0xffff))
+        );
+        assert!(DeviceRegion::port_from_base_size(0x3f8, 0).is_err());
+        assert!(DeviceRegion::port_from_base_size(0xfff0, 0x11).is_err());
+    }
+
+    #[test]
+    fn test_memory_layout_for_ram_size() {
+        let layout = MemoryLayout::for_ram_size(4096);
+        let ram: Vec<(u64, u64)> = layout
+            .ram()
+            .iter()
+            .map(|r| (r.start().as_u64(), r.end().as_u64()))
+            .collect();
+        assert_eq!(
+            ram,
+            [
+                (0, 0x9ffff),
+                (0x100000, 0xbfff_ffff),
+                (0x1_0000_0000, 0x1_3fff_ffff)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remove_unowned_region_fails() {
+        let mut map = DeviceMap::default();
+        map.register_device(DummyDevice::new(vec![4..=5])).unwrap();
+        let dev = ResizingDevice::new(
+            vec![0..=3],
+            RegionDelta::Remove(DeviceRegion::PortIo(4..=5)),
+        );
+        map.register_device(dev).unwrap();
+
+        assert!(map.apply_region_changes().is_err());
+        assert!(map.device_for(4u16).is_some());
+    }
+
     #[test]
     fn test_memmap_write_to_portio_fails() {
         let view = define_test_view();
@@ -533,6 +2604,149 @@ mod test {
         assert_eq!(map.device_for(10u16).is_none(), true);
     }
 
+    #[test]
+    fn test_unassigned_memory_policy() {
+        let mut map = DeviceMap::default();
+        let addr = GuestPhysAddr::new(0xd000_0000);
+        let read = |map: &mut DeviceMap| {
+            let mut arr = [0x5au8; 4];
+            map.on_mem_read(
+                addr,
+                MemReadRequest::new(&mut arr),
+                define_test_view(),
+            )
+            .map(|_| arr)
+        };
+        let write = |map: &mut DeviceMap| {
+            map.on_mem_write(
+                addr,
+                MemWriteRequest::new(&[1, 2]),
+                define_test_view(),
+            )
+        };
+
+        assert!(matches!(read(&mut map), Err(Error::MissingDevice(_))));
+        assert!(matches!(write(&mut map), Err(Error::MissingDevice(_))));
+
+        map.set_unassigned_memory_policy(UnassignedMemoryPolicy::ReturnFF);
+        assert_eq!(read(&mut map), Ok([0xff; 4]));
+        assert_eq!(write(&mut map), Ok(()));
+
+        map.set_unassigned_memory_policy(UnassignedMemoryPolicy::ReturnZero);
+        assert_eq!(read(&mut map), Ok([0x00; 4]));
+        assert_eq!(write(&mut map), Ok(()));
+    }
+
+    #[test]
+    fn test_device_downcast() {
+        struct TestIrqs(Cell<Option<u8>>);
+
+        impl interrupt::IrqSink for TestIrqs {
+            fn raise_irq(&self, irq: u8) {
+                self.0.set(Some(irq));
+            }
+        }
+
+        let mut map = DeviceMap::default();
+        map.register_device(ComDevice::new(0, 0x3f8)).unwrap();
+        let dev = map.device_for(0x3f8u16).unwrap();
+        assert!(dev.as_any().downcast_ref::<ComDevice>().is_some());
+        assert!(dev.as_any().downcast_ref::<pit::Pit8254>().is_none());
+
+        let irqs = Rc::new(TestIrqs(Cell::new(None)));
+        map.device_for_mut(0x3f8u16)
+            .and_then(|dev| dev.as_any_mut().downcast_mut::<ComDevice>())
+            .unwrap()
+            .set_irq_sink(irqs.clone());
+
+        // Enable the THR empty interrupt
+        map.on_port_write(
+            0x3f9,
+            PortWriteRequest::OneByte(&[0x02]),
+            define_test_view(),
+        )
+        .unwrap();
+        map.poll_all(0);
+        assert_eq!(irqs.0.get(), Some(4));
+    }
+
+    // A device that raises an IRQ through the PIC when its port is written
+    struct PicIrqDevice {
+        pic: Rc<RefCell<pic::Pic8259>>,
+    }
+
+    impl EmulatedDevice for PicIrqDevice {
+        fn services(&self) -> Vec<DeviceRegion> {
+            vec![DeviceRegion::PortIo(0x100..=0x100)]
+        }
+
+        fn on_port_write(
+            &mut self,
+            _port: Port,
+            val: PortWriteRequest,
+            _space: GuestAddressSpaceViewMut,
+        ) -> Result<()> {
+            self.pic.borrow_mut().raise_irq(val.try_into()?);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_next_pending_interrupt() {
+        let pic = Rc::new(RefCell::new(*pic::Pic8259::new()));
+        let mut map = DeviceMap::default();
+        map.register_device(Box::new(pic.clone())).unwrap();
+        map.register_device(Box::new(PicIrqDevice { pic })).unwrap();
+        let out = |map: &mut DeviceMap, port: Port, val: u8| {
+            map.on_port_write(
+                port,
+                PortWriteRequest::OneByte(&[val]),
+                define_test_view(),
+            )
+            .unwrap()
+        };
+        assert_eq!(map.next_pending_interrupt(), None);
+
+        // Before the guest programs the PICs, the BIOS vectors are used
+        out(&mut map, 0x100, 1);
+        assert_eq!(
+            map.next_pending_interrupt(),
+            Some(PendingInterrupt {
+                vector: 0x09,
+                irq: 1
+            })
+        );
+        assert_eq!(map.next_pending_interrupt(), None);
+
+        // Remap the master to 0x20 (ICW1-4) and mask IRQ 3
+        out(&mut map, 0x20, 0x11);
+        out(&mut map, 0x21, 0x20);
+        out(&mut map, 0x21, 0x04);
+        out(&mut map, 0x21, 0x01);
+        out(&mut map, 0x21, 1 << 3);
+        out(&mut map, 0x100, 3);
+        out(&mut map, 0x100, 5);
+        assert_eq!(
+            map.next_pending_interrupt(),
+            Some(PendingInterrupt {
+                vector: 0x25,
+                irq: 5
+            })
+        );
+        assert_eq!(map.next_pending_interrupt(), None);
+
+        // Once IRQ 5 ends and IRQ 3 is unmasked, it is delivered
+        out(&mut map, 0x20, 0x20);
+        out(&mut map, 0x21, 0);
+        assert_eq!(
+            map.next_pending_interrupt(),
+            Some(PendingInterrupt {
+                vector: 0x23,
+                irq: 3
+            })
+        );
+    }
+
     #[test]
     fn test_write_request_try_from() {
         let val: Result<PortWriteRequest> =
@@ -550,6 +2764,144 @@ mod test {
         assert_eq!(val.as_u32(), 0x1234);
     }
 
+    #[test]
+    fn test_validate_dma_range() {
+        let mut space = GuestAddressSpace::new().unwrap();
+        space
+            .map_new_frame(GuestPhysAddr::new(0x1000), false)
+            .unwrap();
+        space
+            .map_new_frame(GuestPhysAddr::new(0x2000), true)
+            .unwrap();
+        let read = GuestAccess::Read(PrivilegeLevel(0));
+        let write = GuestAccess::Write(PrivilegeLevel(0));
+
+        let addr = GuestPhysAddr::new(0x1800);
+        assert!(validate_dma_range(&space, addr, 0x800, write).is_ok());
+        assert!(validate_dma_range(&space, addr, 0x1000, read).is_ok());
+
+        // The second frame is read only, and the third is not mapped
+        assert_eq!(
+            validate_dma_range(&space, addr, 0x1000, write),
+            Err(Error::InvalidDmaRange { addr, len: 0x1000 })
+        );
+        assert!(validate_dma_range(&space, addr, 0x1801, read).is_err());
+        assert!(validate_dma_range(
+            &space,
+            GuestPhysAddr::new(u64::MAX),
+            2,
+            read
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_write_request_value() {
+        let val = PortWriteRequest::OneByte(&[0x12]);
+        assert_eq!(val.value(), PortValue::U8(0x12));
+
+        let val = PortWriteRequest::TwoBytes(&[0x12, 0x34]);
+        assert_eq!(val.value(), PortValue::U16(0x1234));
+        assert_eq!(val.value().width(), PortWidth::Word);
+
+        let val = PortWriteRequest::FourBytes(&[0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(val.value(), PortValue::U32(0x12345678));
+        assert_eq!(val.value().as_u32(), val.as_u32());
+
+        // The bytes on the bus are the little endian encoding of the value
+        let bus: Vec<u8> = val.bytes().collect();
+        assert_eq!(bus, 0x12345678u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_read_request_width() {
+        let mut one = [0u8];
+        let mut two = [0u8; 2];
+        let mut four = [0u8; 4];
+        assert_eq!(PortReadRequest::OneByte(&mut one).width(), PortWidth::Byte);
+        assert_eq!(
+            PortReadRequest::TwoBytes(&mut two).width(),
+            PortWidth::Word
+        );
+        assert_eq!(
+            PortReadRequest::FourBytes(&mut four).width(),
+            PortWidth::DWord
+        );
+    }
+
+    #[test]
+    fn test_access_width_error() {
+        let err =
+            PortWriteRequest::try_from(&[0x12, 0x34, 0x56][..]).unwrap_err();
+        assert_eq!(
+            err,
+            Error::AccessWidth {
+                expected: PORT_ACCESS_WIDTHS,
+                actual: 3
+            }
+        );
+        assert_eq!(
+            format!("{}", err),
+            "Invalid access width: 3 bytes (expected [1, 2, 4])"
+        );
+
+        let mut arr = [0u8; 3];
+        assert!(matches!(
+            PortReadRequest::try_from(&mut arr[..]),
+            Err(Error::AccessWidth { actual: 3, .. })
+        ));
+
+        let res: Result<u32> =
+            MemWriteRequest::new(&[0x12, 0x34, 0x56]).try_into();
+        assert_eq!(
+            res,
+            Err(Error::AccessWidth {
+                expected: &[4],
+                actual: 3
+            })
+        );
+
+        let res: Result<u8> =
+            PortWriteRequest::TwoBytes(&[0x12, 0x34]).try_into();
+        assert!(matches!(res, Err(Error::AccessWidth { actual: 2, .. })));
+    }
+
+    #[test]
+    fn test_invalid_port_widths() {
+        for len in [0, 3, 5, 8].iter() {
+            let expected = Err(Error::AccessWidth {
+                expected: PORT_ACCESS_WIDTHS,
+                actual: *len,
+            });
+            let mut buff = vec![0u8; *len];
+            assert_eq!(
+                PortWriteRequest::try_from(&buff[..]).map(|_| ()),
+                expected
+            );
+            assert_eq!(
+                PortReadRequest::try_from(&mut buff[..]).map(|_| ()),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_fill_from_slice() {
+        let mut arr = [0u8; 2];
+        let mut val = PortReadRequest::TwoBytes(&mut arr);
+        val.fill_from_slice(&[0x12, 0x34]).unwrap();
+        assert_eq!(val.as_slice(), [0x12, 0x34]);
+        assert!(val.fill_from_slice(&[0x56]).is_err());
+        assert!(val.fill_from_slice(&[0x56, 0x78, 0x9a, 0xbc]).is_err());
+        assert_eq!(val.as_slice(), [0x12, 0x34]);
+
+        let mut arr = [0u8; 8];
+        let mut val = MemReadRequest::new(&mut arr);
+        val.fill_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert_eq!(val.as_slice(), [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(val.fill_from_slice(&[1, 2, 3, 4]).is_err());
+    }
+
     #[test]
     fn test_portio_value_read() {
         let mut arr = [0x00, 0x00];
@@ -569,6 +2921,27 @@ mod test {
         assert!(map.register_device(com).is_err());
     }
 
+    #[test]
+    fn test_conflicting_second_region() {
+        let mut map = DeviceMap::default();
+        map.register_device(DummyDevice::new(vec![0x60..=0x64]))
+            .unwrap();
+
+        // The first region is free, but the second conflicts
+        let dummy = DummyDevice::new(vec![0x20..=0x21, 0x64..=0x64]);
+        assert!(matches!(
+            map.register_device(dummy),
+            Err(Error::RegionConflict(_))
+        ));
+        assert!(map.device_for(0x20u16).is_none());
+        assert!(map.device_for(0x21u16).is_none());
+        assert_eq!(map.iter_regions().count(), 1);
+
+        // So the first region can still be used
+        map.register_device(DummyDevice::new(vec![0x20..=0x21]))
+            .unwrap();
+    }
+
     #[test]
     fn test_fully_overlapping_portio_device() {
         // region 2 fully inside region 1
@@ -620,4 +2993,545 @@ mod test {
 
         assert!(map.register_device(dummy).is_ok());
     }
+
+    // A PCI-style device with a level triggered interrupt
+    struct LevelIrqDevice {
+        port: Port,
+    }
+
+    impl EmulatedDevice for LevelIrqDevice {
+        fn services(&self) -> Vec<DeviceRegion> {
+            vec![DeviceRegion::PortIo(self.port..=self.port)]
+        }
+
+        fn irq_lines(&self) -> Vec<u8> {
+            vec![11]
+        }
+
+        fn irq_trigger_mode(&self, _irq: u8) -> TriggerMode {
+            TriggerMode::Level
+        }
+    }
+
+    #[test]
+    fn test_irq_map_shared_edge_line() {
+        let mut map = DeviceMap::default();
+        map.register_device(ComDevice::new(0, 0x3f8)).unwrap();
+        map.register_device(ComDevice::new(0, 0x3e8)).unwrap();
+        map.register_device(ComDevice::new(0, 0x2f8)).unwrap();
+
+        let irqs = map.irq_map();
+        assert_eq!(irqs.keys().copied().collect::<Vec<_>>(), [3, 4]);
+        assert!(irqs[&4].is_shared());
+        assert!(irqs[&4].has_edge_conflict());
+        assert!(!irqs[&3].is_shared());
+        assert!(!irqs[&3].has_edge_conflict());
+        assert_eq!(irqs[&3].users[0].device, "ComDevice");
+    }
+
+    #[test]
+    fn test_irq_map_shared_level_line() {
+        let mut map = DeviceMap::default();
+        map.register_device(Box::new(LevelIrqDevice { port: 0x10 }))
+            .unwrap();
+        map.register_device(Box::new(LevelIrqDevice { port: 0x20 }))
+            .unwrap();
+
+        let irqs = map.irq_map();
+        assert!(irqs[&11].is_shared());
+        assert!(!irqs[&11].has_edge_conflict());
+    }
+
+    #[test]
+    fn test_owned_port_write_replay() {
+        use crate::device::register::RegisterBlock;
+
+        // Program a divisor of 12 and 8N1 framing, recording the writes
+        let writes: &[(u16, &[u8])] =
+            &[(3, &[0x80]), (0, &[0x0c]), (1, &[0x00]), (3, &[0x03])];
+        let mut original = ComDevice::new(0, 0x3f8);
+        let mut log = vec![];
+        for (offset, bytes) in writes {
+            let request = PortWriteRequest::try_from(*bytes).unwrap();
+            log.push((0x3f8 + offset, OwnedPortWrite::from(&request)));
+            original
+                .on_port_write(0x3f8 + offset, request, define_test_view())
+                .unwrap();
+        }
+        assert_eq!(log[0].1, OwnedPortWrite::OneByte([0x80]));
+        assert_eq!(log[0].1.as_request().as_u32(), 0x80);
+
+        let mut replayed = ComDevice::new(0, 0x3f8);
+        for (port, write) in log.iter() {
+            replayed
+                .on_port_write(*port, write.into(), define_test_view())
+                .unwrap();
+        }
+        assert_eq!(
+            format!("{}", replayed.dump()),
+            format!("{}", original.dump())
+        );
+
+        let mut read = OwnedPortRead::new(PortWidth::Byte);
+        replayed
+            .on_port_read(0x3f8 + 3, read.as_request(), define_test_view())
+            .unwrap();
+        assert_eq!(read, OwnedPortRead::OneByte([0x03]));
+        assert_eq!(read.as_u32(), 0x03);
+    }
+
+    #[test]
+    fn test_owned_port_read_conversion() {
+        let mut arr = [0x12, 0x34];
+        let request = PortReadRequest::TwoBytes(&mut arr);
+        let mut owned = OwnedPortRead::from(&request);
+        assert_eq!(owned.as_u32(), 0x1234);
+
+        let mut request: PortReadRequest = (&mut owned).into();
+        request.copy_from_u32(0xabcd);
+        assert_eq!(owned, OwnedPortRead::TwoBytes([0xab, 0xcd]));
+        assert_eq!(OwnedPortRead::new(PortWidth::DWord).as_slice().len(), 4);
+    }
+
+    fn stats_for(map: &DeviceMap, port: Port) -> (u64, u64) {
+        map.access_stats()
+            .into_iter()
+            .find_map(|(region, reads, writes)| match region {
+                DeviceRegion::PortIo(range) if range.contains(&port) => {
+                    Some((reads, writes))
+                }
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    fn exercise_com_ports(map: &mut DeviceMap) {
+        for _ in 0..3 {
+            let mut arr = [0u8];
+            map.on_port_read(
+                0x3fd,
+                PortReadRequest::OneByte(&mut arr),
+                define_test_view(),
+            )
+            .unwrap();
+        }
+        for _ in 0..5 {
+            map.on_port_write(
+                0x3f8,
+                PortWriteRequest::OneByte(&[b'a']),
+                define_test_view(),
+            )
+            .unwrap();
+        }
+    }
+
+    #[cfg(feature = "access-stats")]
+    #[test]
+    fn test_access_stats() {
+        let mut map = DeviceMap::default();
+        map.register_device(ComDevice::new(0, 0x3f8)).unwrap();
+        map.register_device(ComDevice::new(0, 0x2f8)).unwrap();
+        exercise_com_ports(&mut map);
+
+        assert_eq!(stats_for(&map, 0x3f8), (3, 5));
+        assert_eq!(stats_for(&map, 0x2f8), (0, 0));
+    }
+
+    #[cfg(not(feature = "access-stats"))]
+    #[test]
+    fn test_access_stats_disabled() {
+        let mut map = DeviceMap::default();
+        map.register_device(ComDevice::new(0, 0x3f8)).unwrap();
+        exercise_com_ports(&mut map);
+
+        assert_eq!(stats_for(&map, 0x3f8), (0, 0));
+    }
+
+    // A device with a read-only ROM and a write-only command port
+    struct DirectionalDevice;
+
+    impl DirectionalDevice {
+        const ROM_START: u64 = 0xfffe_0000;
+        const COMMAND_PORT: Port = 0x500;
+
+        fn rom() -> RangeInclusive<GuestPhysAddr> {
+            GuestPhysAddr::new(Self::ROM_START)
+                ..=GuestPhysAddr::new(Self::ROM_START + 0xfff)
+        }
+    }
+
+    impl EmulatedDevice for DirectionalDevice {
+        fn services(&self) -> Vec<DeviceRegion> {
+            vec![
+                DeviceRegion::MemIo(Self::rom()),
+                DeviceRegion::PortIo(Self::COMMAND_PORT..=Self::COMMAND_PORT),
+            ]
+        }
+
+        fn region_access(&self, region: &DeviceRegion) -> RegionAccess {
+            match region {
+                DeviceRegion::MemIo(_) => RegionAccess::Read,
+                _ => RegionAccess::Write,
+            }
+        }
+
+        fn on_mem_read(
+            &mut self,
+            _addr: GuestPhysAddr,
+            mut data: MemReadRequest,
+            _space: GuestAddressSpaceViewMut,
+        ) -> Result<()> {
+            data.copy_from_u32(0x1234);
+            Ok(())
+        }
+
+        fn on_port_write(
+            &mut self,
+            _port: Port,
+            _val: PortWriteRequest,
+            _space: GuestAddressSpaceViewMut,
+        ) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_region_access_directions() {
+        let mut map = DeviceMap::default();
+        map.register_device(Box::new(DirectionalDevice)).unwrap();
+        let rom = GuestPhysAddr::new(DirectionalDevice::ROM_START + 0x10);
+        let port = DirectionalDevice::COMMAND_PORT;
+
+        let mut arr = [0u8; 4];
+        map.on_mem_read(rom, MemReadRequest::new(&mut arr), define_test_view())
+            .unwrap();
+        assert_eq!(arr, 0x1234u32.to_be_bytes());
+        map.on_port_write(
+            port,
+            PortWriteRequest::OneByte(&[1]),
+            define_test_view(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            map.on_mem_write(
+                rom,
+                MemWriteRequest::new(&[0; 4]),
+                define_test_view()
+            ),
+            Err(Error::DisallowedAccess(DisallowedAccess {
+                device: "DirectionalDevice",
+                region: DeviceRegion::MemIo(DirectionalDevice::rom()),
+                kind: AccessKind::MemWrite,
+                addr: rom.as_u64(),
+            }))
+        );
+        let mut arr = [0u8];
+        assert_eq!(
+            map.on_port_read(
+                port,
+                PortReadRequest::OneByte(&mut arr),
+                define_test_view()
+            ),
+            Err(Error::DisallowedAccess(DisallowedAccess {
+                device: "DirectionalDevice",
+                region: DeviceRegion::PortIo(port..=port),
+                kind: AccessKind::PortRead,
+                addr: port as u64,
+            }))
+        );
+    }
+
+    // A device with a block of scratch MSRs and a read-only counter
+    #[derive(Default)]
+    struct MsrDevice {
+        scratch: [u64; 4],
+        counter: u64,
+    }
+
+    impl MsrDevice {
+        const SCRATCH_BASE: u32 = 0x4000_0100;
+        const COUNTER: u32 = 0x4000_0200;
+    }
+
+    impl EmulatedDevice for MsrDevice {
+        fn services(&self) -> Vec<DeviceRegion> {
+            vec![]
+        }
+
+        fn msr_ranges(&self) -> Vec<RangeInclusive<u32>> {
+            vec![
+                Self::SCRATCH_BASE..=Self::SCRATCH_BASE + 3,
+                Self::COUNTER..=Self::COUNTER,
+            ]
+        }
+
+        fn on_msr_read(&mut self, msr: u32) -> Result<u64> {
+            if msr == Self::COUNTER {
+                self.counter += 1;
+                Ok(self.counter)
+            } else {
+                Ok(self.scratch[(msr - Self::SCRATCH_BASE) as usize])
+            }
+        }
+
+        fn on_msr_write(&mut self, msr: u32, val: u64) -> Result<()> {
+            if msr == Self::COUNTER {
+                return Err(Error::InvalidValue(format!(
+                    "MSR 0x{:x} is read-only",
+                    msr
+                )));
+            }
+            self.scratch[(msr - Self::SCRATCH_BASE) as usize] = val;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_msr_dispatch() {
+        let mut map = DeviceMap::default();
+        map.register_device(Box::new(MsrDevice::default())).unwrap();
+
+        let dev = map.device_for_msr(MsrDevice::SCRATCH_BASE + 2).unwrap();
+        assert_eq!(dev.debug_name(), "MsrDevice");
+        assert!(map.device_for_msr(MsrDevice::COUNTER).is_some());
+        assert!(map.device_for_msr(MsrDevice::SCRATCH_BASE + 4).is_none());
+
+        map.on_msr_write(MsrDevice::SCRATCH_BASE + 1, 0xdead_beef_0000_0001)
+            .unwrap();
+        assert_eq!(
+            map.on_msr_read(MsrDevice::SCRATCH_BASE + 1),
+            Ok(0xdead_beef_0000_0001)
+        );
+        assert_eq!(map.on_msr_read(MsrDevice::SCRATCH_BASE), Ok(0));
+        assert_eq!(map.on_msr_read(MsrDevice::COUNTER), Ok(1));
+        assert_eq!(map.on_msr_read(MsrDevice::COUNTER), Ok(2));
+        assert!(map.on_msr_write(MsrDevice::COUNTER, 0).is_err());
+
+        assert!(matches!(
+            map.on_msr_read(0x10),
+            Err(Error::MissingDevice(_))
+        ));
+        assert!(matches!(
+            map.on_msr_write(0x10, 0),
+            Err(Error::MissingDevice(_))
+        ));
+    }
+
+    #[test]
+    fn test_conflicting_msr_device() {
+        let mut map = DeviceMap::default();
+        map.register_device(Box::new(MsrDevice::default())).unwrap();
+        let scratch = MsrDevice::SCRATCH_BASE..=MsrDevice::SCRATCH_BASE + 3;
+        match map.register_device(Box::new(MsrDevice::default())) {
+            Err(Error::RegionConflict(conflict)) => assert_eq!(
+                conflict,
+                RegionConflict {
+                    device: "MsrDevice",
+                    region: DeviceRegion::Msr(scratch.clone()),
+                    existing_device: "MsrDevice",
+                    existing_region: DeviceRegion::Msr(scratch),
+                }
+            ),
+            res => panic!("Unexpected result {:?}", res),
+        }
+
+        // A conflict within a batch leaves the map unchanged
+        let mut map = DeviceMap::default();
+        let devices: Vec<Box<dyn EmulatedDevice>> = vec![
+            ComDevice::new(0, 0x3f8),
+            Box::new(MsrDevice::default()),
+            Box::new(MsrDevice::default()),
+        ];
+        assert!(map.register_all(devices).is_err());
+        assert!(map.device_for(0x3f8u16).is_none());
+        assert!(map.device_for_msr(MsrDevice::COUNTER).is_none());
+    }
+
+    #[test]
+    fn test_msr_default_not_implemented() {
+        let mut dev = DummyDevice::new(vec![0x80..=0x80]);
+        assert!(dev.msr_ranges().is_empty());
+        assert!(matches!(
+            dev.on_msr_read(0x1b),
+            Err(Error::NotImplemented(_))
+        ));
+        assert!(matches!(
+            dev.on_msr_write(0x1b, 0),
+            Err(Error::NotImplemented(_))
+        ));
+    }
+
+    #[test]
+    fn test_nearest_region() {
+        use crate::device::rom::RomDevice;
+
+        let mut map = DeviceMap::default();
+        assert!(map.nearest_region(0x3f8u16).is_none());
+        map.register_all(vec![
+            DummyDevice::new(vec![0x3f8..=0x3f8, 0x400..=0x40f]),
+            RomDevice::new(
+                GuestPhysAddr::new(0xc0000)..=GuestPhysAddr::new(0xc0fff),
+                vec![],
+            ),
+        ])
+        .unwrap();
+
+        let nearest_port = |port: u16| {
+            map.nearest_region(port).map(|(region, _)| region).unwrap()
+        };
+        assert_eq!(nearest_port(0x3f9), DeviceRegion::PortIo(0x3f8..=0x3f8));
+        assert_eq!(nearest_port(0x3f8), DeviceRegion::PortIo(0x3f8..=0x3f8));
+        assert_eq!(nearest_port(0x3fe), DeviceRegion::PortIo(0x400..=0x40f));
+        assert_eq!(nearest_port(0xffff), DeviceRegion::PortIo(0x400..=0x40f));
+
+        // Ports and memory are searched separately
+        let (region, dev) =
+            map.nearest_region(GuestPhysAddr::new(0xc1000)).unwrap();
+        assert_eq!(
+            region,
+            DeviceRegion::MemIo(
+                GuestPhysAddr::new(0xc0000)..=GuestPhysAddr::new(0xc0fff)
+            )
+        );
+        assert_eq!(dev.debug_name(), "RomDevice");
+
+        // The nearest device is named in the error for an unserviced access
+        let mut arr = [0u8];
+        match map.on_port_read(
+            0x3f9,
+            PortReadRequest::OneByte(&mut arr),
+            define_test_view(),
+        ) {
+            Err(Error::MissingDevice(msg)) => {
+                assert!(msg.contains("'DummyDevice' at PortIo(1016..=1016)"))
+            }
+            res => panic!("Unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_iter_regions_order() {
+        use crate::device::rom::RomDevice;
+
+        let regions = |devices: Vec<Box<dyn EmulatedDevice>>| {
+            let mut map = DeviceMap::default();
+            map.register_all(devices).unwrap();
+            map.iter_regions()
+                .map(|(region, _, dev)| (region, dev.debug_name()))
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![
+            (DeviceRegion::PortIo(0x80..=0x83), "DummyDevice"),
+            (DeviceRegion::PortIo(0x3f8..=0x3ff), "ComDevice"),
+            (DeviceRegion::PortIo(0x500..=0x500), "DirectionalDevice"),
+            (DeviceRegion::PortIo(0x510..=0x511), "DummyDevice"),
+            (
+                DeviceRegion::MemIo(
+                    GuestPhysAddr::new(0xfec0_0000)
+                        ..=GuestPhysAddr::new(0xfec0_0fff),
+                ),
+                "RomDevice",
+            ),
+            (
+                DeviceRegion::MemIo(
+                    GuestPhysAddr::new(0xfffe_0000)
+                        ..=GuestPhysAddr::new(0xfffe_0fff),
+                ),
+                "DirectionalDevice",
+            ),
+        ];
+
+        // The same order, whatever order the devices are registered in
+        assert_eq!(
+            regions(vec![
+                DummyDevice::new(vec![0x510..=0x511, 0x80..=0x83]),
+                ComDevice::new(4, 0x3f8),
+                Box::new(DirectionalDevice),
+                RomDevice::new(
+                    GuestPhysAddr::new(0xfec0_0000)
+                        ..=GuestPhysAddr::new(0xfec0_0fff),
+                    vec![],
+                ),
+            ]),
+            expected
+        );
+        assert_eq!(
+            regions(vec![
+                RomDevice::new(
+                    GuestPhysAddr::new(0xfec0_0000)
+                        ..=GuestPhysAddr::new(0xfec0_0fff),
+                    vec![],
+                ),
+                Box::new(DirectionalDevice),
+                ComDevice::new(4, 0x3f8),
+                DummyDevice::new(vec![0x80..=0x83, 0x510..=0x511]),
+            ]),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_layout_report() {
+        let mut map = DeviceMap::default();
+        let devices: Vec<Box<dyn EmulatedDevice>> = vec![
+            ComDevice::new(4, 0x3f8),
+            Box::new(DirectionalDevice),
+            DummyDevice::new(vec![0x80..=0x83]),
+            Box::new(MsrDevice::default()),
+        ];
+        map.register_all(devices).unwrap();
+
+        let report = map.layout_report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "TYPE RANGE                   ACCESS DEVICE               IRQS",
+                "PIO  0x0080-0x0083           rw     DummyDevice          -",
+                "PIO  0x03f8-0x03ff           rw     ComDevice            4",
+                "PIO  0x0500-0x0500           w      DirectionalDevice    -",
+                "MMIO 0xfffe0000-0xfffe0fff   r      DirectionalDevice    -",
+                "MSR  0x40000100-0x40000103   rw     MsrDevice            -",
+                "MSR  0x40000200-0x40000200   rw     MsrDevice            -",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unhandled_access() {
+        let mut dev = DummyDevice::new(vec![0x80..=0x83]);
+        let mut arr = [0u8; 2];
+        let res = dev.on_port_read(
+            0x82,
+            PortReadRequest::TwoBytes(&mut arr),
+            define_test_view(),
+        );
+        let expected = UnhandledAccess {
+            device: "DummyDevice",
+            kind: AccessKind::PortRead,
+            addr: 0x82,
+            width: 2,
+        };
+        assert_eq!(
+            format!("{}", expected),
+            "Unhandled 2 byte port read of 0x82 by DummyDevice"
+        );
+        assert_eq!(res, Err(Error::UnhandledAccess(expected)));
+
+        let res = dev.on_mem_write(
+            GuestPhysAddr::new(0xfee0_0000),
+            MemWriteRequest::new(&[0; 4]),
+            define_test_view(),
+        );
+        assert_eq!(
+            res,
+            Err(Error::UnhandledAccess(UnhandledAccess {
+                device: "DummyDevice",
+                kind: AccessKind::MemWrite,
+                addr: 0xfee0_0000,
+                width: 4,
+            }))
+        );
+    }
 }
@@ -0,0 +1,348 @@
+use crate::device::interrupt::{InterruptDestination, InterruptSink};
+use crate::ioapic::{DeliveryMode, DestinationMode};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+/// A message signaled interrupt, as written by a device to the local
+/// APIC address range
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+impl MsiMessage {
+    const ADDRESS_MASK: u64 = 0xfff0_0000;
+    const ADDRESS_BASE: u64 = 0xfee0_0000;
+    const ADDRESS_LOGICAL: u64 = 1 << 2;
+
+    /// Decode the message in to a destination and vector
+    ///
+    /// The address holds the destination APIC ID (bits 19-12) and the
+    /// destination mode (bit 2). The data holds the vector (bits 7-0) and
+    /// delivery mode (bits 10-8). Returns None if the address is not in
+    /// the local APIC range, or the delivery mode is invalid.
+    pub fn decode(&self) -> Option<(InterruptDestination, u8)> {
+        if self.address & Self::ADDRESS_MASK != Self::ADDRESS_BASE {
+            return None;
+        }
+        let mode = if self.address & Self::ADDRESS_LOGICAL != 0 {
+            DestinationMode::Logical
+        } else {
+            DestinationMode::Physical
+        };
+        let delivery =
+            DeliveryMode::try_from(((self.data >> 8) & 0b111) as u8).ok()?;
+        let dest = InterruptDestination {
+            mode,
+            delivery,
+            destination: ((self.address >> 12) & 0xff) as u8,
+        };
+        Some((dest, self.data as u8))
+    }
+
+    /// Deliver this message to `sink`
+    ///
+    /// Returns false (and logs a warning) if the message is invalid.
+    pub fn deliver(&self, sink: &dyn InterruptSink) -> bool {
+        match self.decode() {
+            Some((dest, vector)) => {
+                sink.deliver(dest, vector);
+                true
+            }
+            None => {
+                warn!("Dropping invalid MSI: {:?}", self);
+                false
+            }
+        }
+    }
+}
+
+/// The MSI capability of a PCI function
+///
+/// The capability registers are accessed by their byte offset from the
+/// start of the capability, in 32-bit units (as they appear in the
+/// function's configuration space).
+#[derive(Clone, Debug)]
+pub struct MsiCapability {
+    next: u8,
+    control: u16,
+    address: u64,
+    data: u16,
+    mask: u32,
+    pending: u32,
+}
+
+impl MsiCapability {
+    /// The PCI capability ID for MSI
+    pub const CAP_ID: u8 = 0x05;
+
+    const CONTROL_ENABLE: u16 = 1 << 0;
+    const CONTROL_64BIT: u16 = 1 << 7;
+    const CONTROL_PER_VECTOR_MASKING: u16 = 1 << 8;
+
+    // The read-only bits of the message control register
+    const CONTROL_RO_MASK: u16 =
+        0b1110 | Self::CONTROL_64BIT | Self::CONTROL_PER_VECTOR_MASKING;
+
+    /// Create a capability that supports `2^vectors_log2` vectors
+    pub fn new(vectors_log2: u8, is_64bit: bool, masking: bool) -> Self {
+        let mut control = ((vectors_log2.min(5) as u16) & 0b111) << 1;
+        if is_64bit {
+            control |= Self::CONTROL_64BIT;
+        }
+        if masking {
+            control |= Self::CONTROL_PER_VECTOR_MASKING;
+        }
+        Self {
+            next: 0,
+            control,
+            address: 0,
+            data: 0,
+            mask: 0,
+            pending: 0,
+        }
+    }
+
+    /// Return the capability to its power-on state (disabled, with no
+    /// message programmed)
+    pub fn reset(&mut self) {
+        self.control &= Self::CONTROL_RO_MASK;
+        self.address = 0;
+        self.data = 0;
+        self.mask = 0;
+        self.pending = 0;
+    }
+
+    /// Set the offset of the next capability in the list
+    pub fn set_next(&mut self, next: u8) {
+        self.next = next;
+    }
+
+    /// The size of this capability in bytes
+    pub fn size(&self) -> u8 {
+        match (self.is_64bit(), self.has_masking()) {
+            (false, false) => 12,
+            (true, false) => 16,
+            (false, true) => 20,
+            (true, true) => 24,
+        }
+    }
+
+    /// The content of the capability following the ID and next pointer
+    /// (as passed to `PciDevice::add_capability`)
+    pub fn body(&self) -> Vec<u8> {
+        (0..self.size())
+            .step_by(4)
+            .flat_map(|offset| {
+                self.read_register(offset).to_le_bytes().to_vec()
+            })
+            .skip(2)
+            .collect()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.control & Self::CONTROL_ENABLE != 0
+    }
+
+    fn is_64bit(&self) -> bool {
+        self.control & Self::CONTROL_64BIT != 0
+    }
+
+    fn has_masking(&self) -> bool {
+        self.control & Self::CONTROL_PER_VECTOR_MASKING != 0
+    }
+
+    // The number of vectors enabled by the guest
+    fn enabled_vectors(&self) -> u32 {
+        let capable = (self.control >> 1) & 0b111;
+        let enabled = (self.control >> 4) & 0b111;
+        1 << enabled.min(capable)
+    }
+
+    // The offset of the data register (the mask and pending registers
+    // follow it)
+    fn data_offset(&self) -> u8 {
+        if self.is_64bit() {
+            12
+        } else {
+            8
+        }
+    }
+
+    /// Read the capability register at byte `offset`
+    pub fn read_register(&self, offset: u8) -> u32 {
+        let data = self.data_offset();
+        match offset & !0b11 {
+            0 => {
+                Self::CAP_ID as u32
+                    | (self.next as u32) << 8
+                    | (self.control as u32) << 16
+            }
+            4 => self.address as u32,
+            8 if self.is_64bit() => (self.address >> 32) as u32,
+            o if o == data => self.data as u32,
+            o if self.has_masking() && o == data + 4 => self.mask,
+            o if self.has_masking() && o == data + 8 => self.pending,
+            _ => 0,
+        }
+    }
+
+    /// Write the capability register at byte `offset`
+    pub fn write_register(&mut self, offset: u8, val: u32) {
+        let data = self.data_offset();
+        match offset & !0b11 {
+            0 => {
+                let control = (val >> 16) as u16;
+                self.control = (self.control & Self::CONTROL_RO_MASK)
+                    | (control & !Self::CONTROL_RO_MASK);
+            }
+            // The address must be dword aligned
+            4 => {
+                self.address =
+                    (self.address & !0xffff_ffff) | (val & !0b11) as u64
+            }
+            8 if self.is_64bit() => {
+                self.address = (self.address & 0xffff_ffff) | (val as u64) << 32
+            }
+            o if o == data => self.data = val as u16,
+            o if self.has_masking() && o == data + 4 => self.mask = val,
+            _ => (),
+        }
+    }
+
+    /// The message that is sent for the given vector
+    pub fn message(&self, vector: u8) -> MsiMessage {
+        // With multiple vectors, the low bits of the data are the vector
+        let low_bits = self.enabled_vectors() - 1;
+        MsiMessage {
+            address: self.address,
+            data: (self.data as u32 & !low_bits) | (vector as u32 & low_bits),
+        }
+    }
+
+    /// Signal the given vector
+    ///
+    /// Nothing is delivered if MSI is disabled. If the vector is masked,
+    /// it is marked as pending instead. Returns true if an interrupt was
+    /// delivered.
+    pub fn fire(&mut self, vector: u8, sink: &dyn InterruptSink) -> bool {
+        if !self.is_enabled() || vector as u32 >= self.enabled_vectors() {
+            return false;
+        }
+        let bit = 1 << vector;
+        if self.has_masking() && self.mask & bit != 0 {
+            self.pending |= bit;
+            return false;
+        }
+        self.message(vector).deliver(sink)
+    }
+
+    /// Deliver any pending vectors that are no longer masked
+    pub fn deliver_pending(&mut self, sink: &dyn InterruptSink) {
+        if !self.is_enabled() {
+            return;
+        }
+        let ready = self.pending & !self.mask;
+        self.pending &= !ready;
+        for vector in 0..self.enabled_vectors() {
+            if ready & (1 << vector) != 0 {
+                self.message(vector as u8).deliver(sink);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::interrupt::{PendingInterrupts, VcpuApic};
+    use alloc::vec::Vec;
+
+    fn test_sink() -> PendingInterrupts {
+        let vcpus: Vec<_> = (0..4).map(VcpuApic::new).collect();
+        PendingInterrupts::new(&vcpus)
+    }
+
+    #[test]
+    fn test_msi_message_decode() {
+        let msg = MsiMessage {
+            address: 0xfee0_2000,
+            data: 0x0041,
+        };
+        assert_eq!(
+            msg.decode(),
+            Some((InterruptDestination::physical(2), 0x41))
+        );
+
+        let msg = MsiMessage {
+            address: 0xfee0_3004,
+            data: 0x0142,
+        };
+        assert_eq!(
+            msg.decode(),
+            Some((
+                InterruptDestination::logical(3)
+                    .with_delivery(DeliveryMode::LowestPriority),
+                0x42
+            ))
+        );
+
+        let msg = MsiMessage {
+            address: 0xfec0_0000,
+            data: 0x0041,
+        };
+        assert_eq!(msg.decode(), None);
+    }
+
+    #[test]
+    fn test_msi_capability_delivery() {
+        let sink = test_sink();
+        let mut msi = MsiCapability::new(0, true, false);
+
+        // Program the address and data the way the guest would
+        msi.write_register(4, 0xfee0_1000);
+        msi.write_register(8, 0);
+        msi.write_register(12, 0x0051);
+
+        // MSI is disabled, so nothing is delivered
+        assert!(!msi.fire(0, &sink));
+        assert_eq!(sink.pop(1), None);
+
+        msi.write_register(0, 1 << 16);
+        assert!(msi.is_enabled());
+        assert_eq!(msi.read_register(0) & 0xff, MsiCapability::CAP_ID as u32);
+        assert!(msi.fire(0, &sink));
+        assert_eq!(sink.pop(1), Some((DeliveryMode::Fixed, 0x51)));
+        assert_eq!(sink.pop(0), None);
+
+        msi.write_register(0, 0);
+        assert!(!msi.fire(0, &sink));
+        assert_eq!(sink.pop(1), None);
+    }
+
+    #[test]
+    fn test_msi_capability_masking() {
+        let sink = test_sink();
+
+        // Four vectors, 32-bit addresses and per-vector masking
+        let mut msi = MsiCapability::new(2, false, true);
+        assert_eq!(msi.size(), 20);
+        msi.write_register(4, 0xfee0_0000);
+        msi.write_register(8, 0x0060);
+        msi.write_register(12, 1 << 2);
+        msi.write_register(0, (2 << 4 | 1) << 16);
+
+        assert!(msi.fire(1, &sink));
+        assert_eq!(sink.pop(0), Some((DeliveryMode::Fixed, 0x61)));
+
+        assert!(!msi.fire(2, &sink));
+        assert_eq!(sink.pop(0), None);
+        assert_eq!(msi.read_register(16), 1 << 2);
+
+        msi.write_register(12, 0);
+        msi.deliver_pending(&sink);
+        assert_eq!(sink.pop(0), Some((DeliveryMode::Fixed, 0x62)));
+        assert_eq!(msi.read_register(16), 0);
+    }
+}
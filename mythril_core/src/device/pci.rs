@@ -1,12 +1,21 @@
+use crate::device::msi::MsiCapability;
+use crate::device::register::{masked_write, RegisterBlock, RegisterInfo};
+use crate::device::rom::RomDevice;
 use crate::device::{
-    DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
+    DeviceRegion, EmulatedDevice, MemReadRequest, MemWriteRequest,
+    OwnedPortRead, Port, PortReadRequest, PortWidth, PortWriteRequest,
+    RegionDelta,
 };
 use crate::error::{Error, Result};
-use crate::memory::GuestAddressSpaceViewMut;
+use crate::memory::{GuestAddressSpaceViewMut, GuestPhysAddr};
 use alloc::boxed::Box;
 use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::rc::Rc;
 use alloc::vec::Vec;
-use core::convert::TryInto;
+use core::cell::RefCell;
+use core::convert::{TryFrom, TryInto};
+use core::ops::RangeInclusive;
 use derive_try_from_primitive::TryFromPrimitive;
 use ux;
 
@@ -60,6 +69,59 @@ struct PciNonBridgeHeader {
     max_latency: u8,
 }
 
+impl PciNonBridgeHeader {
+    const HEADER_TYPE_MULTI_FUNCTION: u8 = 1 << 7;
+    const BIST_CAPABLE: u8 = 1 << 7;
+    const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+    const EXPANSION_ROM_ENABLE: u32 = 1;
+    const EXPANSION_ROM_ADDRESS_MASK: u32 = 0xfffff800;
+
+    /// Set whether this is a function of a multi-function device
+    fn set_multi_function(&mut self, multi_function: bool) {
+        if multi_function {
+            self.header_type |= Self::HEADER_TYPE_MULTI_FUNCTION;
+        } else {
+            self.header_type &= !Self::HEADER_TYPE_MULTI_FUNCTION;
+        }
+    }
+
+    /// Set whether the function supports a built-in self test
+    fn set_bist_capable(&mut self, capable: bool) {
+        if capable {
+            self.bist |= Self::BIST_CAPABLE;
+        } else {
+            self.bist &= !Self::BIST_CAPABLE;
+        }
+    }
+
+    /// Write the expansion ROM BAR of a `size` byte ROM
+    ///
+    /// The address bits below the ROM size are hardwired to zero, so
+    /// writing all ones reads back the size mask.
+    fn write_expansion_rom(&mut self, val: u32, size: u32) {
+        let mask = Self::EXPANSION_ROM_ADDRESS_MASK & !(size - 1);
+        self.expansion_rom_addr =
+            (val & mask) | (val & Self::EXPANSION_ROM_ENABLE);
+    }
+
+    /// The window decoded by the expansion ROM BAR of a `size` byte ROM,
+    /// if decoding is enabled
+    fn expansion_rom_window(
+        &self,
+        size: u32,
+    ) -> Option<RangeInclusive<GuestPhysAddr>> {
+        let addr = self.expansion_rom_addr;
+        if addr & Self::EXPANSION_ROM_ENABLE == 0 {
+            return None;
+        }
+        let base = (addr & Self::EXPANSION_ROM_ADDRESS_MASK) as u64;
+        Some(
+            GuestPhysAddr::new(base)
+                ..=GuestPhysAddr::new(base + size as u64 - 1),
+        )
+    }
+}
+
 #[repr(C)]
 #[repr(packed)]
 struct PciNonBridgeSpace {
@@ -110,18 +172,116 @@ impl PciConfigSpace {
         }
     }
 
+    fn as_bytes_mut(&mut self) -> &mut [u8; 256] {
+        match self {
+            PciConfigSpace::Type0(space) => unsafe {
+                core::mem::transmute(space)
+            },
+            PciConfigSpace::Type1(space) => unsafe {
+                core::mem::transmute(space)
+            },
+            PciConfigSpace::Type2(space) => unsafe {
+                core::mem::transmute(space)
+            },
+        }
+    }
+
     fn read_register(&self, register: u8) -> u32 {
         self.as_registers()[register as usize]
     }
+
+    fn write_register(&mut self, register: u8, val: u32) {
+        let offset = register as usize * 4;
+        self.as_bytes_mut()[offset..offset + 4]
+            .copy_from_slice(&val.to_ne_bytes());
+    }
+
+    fn header(&self) -> Option<&PciNonBridgeHeader> {
+        match self {
+            PciConfigSpace::Type0(space) => Some(&space.header),
+            _ => None,
+        }
+    }
+
+    fn header_mut(&mut self) -> Option<&mut PciNonBridgeHeader> {
+        match self {
+            PciConfigSpace::Type0(space) => Some(&mut space.header),
+            _ => None,
+        }
+    }
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug)]
 pub struct PciBdf {
     bus: u8,
     device: ux::u5,
     function: ux::u3,
 }
 
+impl PciBdf {
+    const CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
+
+    /// Create a BDF from its fields
+    ///
+    /// As when decoding a CONFIG_ADDRESS, only the low 5 bits of `device`
+    /// and the low 3 bits of `function` are used.
+    pub fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            bus,
+            device: ux::u5::new(device & 0x1f),
+            function: ux::u3::new(function & 0b111),
+        }
+    }
+
+    pub fn bus(&self) -> u8 {
+        self.bus
+    }
+
+    pub fn device(&self) -> u8 {
+        u8::from(self.device)
+    }
+
+    pub fn function(&self) -> u8 {
+        u8::from(self.function)
+    }
+
+    /// Encode this BDF and a register number as a CONFIG_ADDRESS value
+    ///
+    /// The layout is the standard configuration mechanism #1 format:
+    ///
+    /// ```text
+    /// 31     | 30-24    | 23-16 | 15-11  | 10-8     | 7-2      | 1-0
+    /// enable | reserved | bus   | device | function | register | 00
+    /// ```
+    ///
+    /// `register` is the index of a 32-bit register in the configuration
+    /// space (i.e., the byte offset divided by 4), so it must be below 64.
+    pub fn to_config_address(&self, register: u8) -> u32 {
+        let bdf: u16 = (*self).into();
+        Self::CONFIG_ADDRESS_ENABLE
+            | (bdf as u32) << 8
+            | ((register as u32) & 0x3f) << 2
+    }
+
+    /// Decode a CONFIG_ADDRESS value into the BDF and register number
+    ///
+    /// The enable bit, the reserved bits and the low two bits are ignored.
+    pub fn from_config_address(addr: u32) -> (PciBdf, u8) {
+        let bdf = PciBdf::from(((addr & 0x00ffff00) >> 8) as u16);
+        let register = ((addr & 0xfc) >> 2) as u8;
+        (bdf, register)
+    }
+
+    pub fn to_le_bytes(&self) -> [u8; 2] {
+        let bdf: u16 = (*self).into();
+        bdf.to_le_bytes()
+    }
+
+    pub fn from_le_bytes(bytes: [u8; 2]) -> Self {
+        PciBdf::from(u16::from_le_bytes(bytes))
+    }
+}
+
 impl From<u16> for PciBdf {
     fn from(bytes: u16) -> Self {
         Self {
@@ -140,223 +300,2889 @@ impl Into<u16> for PciBdf {
     }
 }
 
-pub struct PciDevice {
-    config_space: PciConfigSpace,
-    bdf: PciBdf,
+// The option ROM of a device, exposed through the expansion ROM BAR
+struct PciOptionRom {
+    size: u32,
+    image: Vec<u8>,
+
+    // The ROM at the window decoded by the expansion ROM BAR, if enabled
+    mapped: Option<Box<RomDevice>>,
 }
 
-pub struct PciRootComplex {
-    current_address: u32,
-    devices: BTreeMap<u16, PciDevice>,
+impl PciOptionRom {
+    // Move the ROM to `window`, returning the change to the serviced region
+    fn map(
+        &mut self,
+        window: Option<RangeInclusive<GuestPhysAddr>>,
+    ) -> Option<RegionDelta> {
+        let old = self.mapped.as_ref().map(|rom| rom.region().clone());
+        if old == window {
+            return None;
+        }
+        let image = &self.image;
+        self.mapped = window
+            .clone()
+            .map(|window| RomDevice::new(window, image.clone()));
+        match (old, window) {
+            (None, Some(new)) => {
+                Some(RegionDelta::Add(DeviceRegion::MemIo(new)))
+            }
+            (Some(old), Some(new)) => Some(RegionDelta::Resize {
+                old: DeviceRegion::MemIo(old),
+                new: DeviceRegion::MemIo(new),
+            }),
+            (Some(old), None) => {
+                Some(RegionDelta::Remove(DeviceRegion::MemIo(old)))
+            }
+            (None, None) => None,
+        }
+    }
 }
 
-impl PciRootComplex {
-    const PCI_CONFIG_ADDRESS: Port = 0xcf8;
-    const PCI_CONFIG_DATA: Port = 0xcfc;
-    const PCI_CONFIG_DATA_MAX: Port = Self::PCI_CONFIG_DATA + 3;
+/// The size of a function's configuration space
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PciConfigSize {
+    /// The 256 bytes of a conventional PCI function
+    Legacy,
 
-    pub fn new() -> Box<Self> {
-        let mut devices = BTreeMap::new();
+    /// The 4KB of a PCI Express function, of which only the first 256
+    /// bytes are reachable through the I/O port mechanisms
+    Extended,
+}
 
-        let host_bridge = PciDevice {
-            bdf: PciBdf::from(0x0000),
-            config_space: PciConfigSpace::Type0(PciNonBridgeSpace::new(
-                PciNonBridgeHeader {
-                    vendor_id: VendorId::Intel as u16,
-                    device_id: DeviceId::P35Mch as u16,
-                    ..PciNonBridgeHeader::default()
-                },
-            )),
-        };
-        devices.insert(host_bridge.bdf.into(), host_bridge);
+impl PciConfigSize {
+    /// The size in bytes
+    pub fn bytes(&self) -> usize {
+        match self {
+            Self::Legacy => 256,
+            Self::Extended => 4096,
+        }
+    }
 
-        let ich9 = PciDevice {
-            bdf: PciBdf::from(0b1000),
-            config_space: PciConfigSpace::Type0(PciNonBridgeSpace::new(
-                PciNonBridgeHeader {
-                    vendor_id: VendorId::Intel as u16,
-                    device_id: DeviceId::Ich9 as u16,
-                    ..PciNonBridgeHeader::default()
-                },
-            )),
+    /// The number of 32-bit registers
+    pub fn registers(&self) -> u16 {
+        (self.bytes() / 4) as u16
+    }
+}
+
+/// The device/port type field of the PCI Express capability
+#[derive(Clone, Copy, Debug, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum PciExpressDeviceType {
+    Endpoint = 0b0000,
+    LegacyEndpoint = 0b0001,
+    RootPort = 0b0100,
+    UpstreamSwitchPort = 0b0101,
+    DownstreamSwitchPort = 0b0110,
+    RootComplexIntegratedEndpoint = 0b1001,
+}
+
+/// The PCI Express capability, which identifies a function as PCI Express
+///
+/// Only the fields needed to describe a single, trained link are
+/// configurable. The device, link and slot control registers read as 0.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PciExpressCap {
+    pub device_type: PciExpressDeviceType,
+
+    /// The maximum (and current) link speed (1 = 2.5 GT/s, 2 = 5 GT/s,
+    /// etc)
+    pub link_speed: u8,
+
+    /// The maximum (and negotiated) link width (number of lanes)
+    pub link_width: u8,
+}
+
+impl PciExpressCap {
+    pub const ID: u8 = 0x10;
+
+    // The capability version, and the size of a version 2 capability
+    // (following the ID and next pointer)
+    const VERSION: u16 = 2;
+    const BODY_SIZE: usize = 0x3a;
+
+    // Offsets within the capability
+    const CAPABILITIES_OFFSET: usize = 0x02;
+    const LINK_CAPABILITIES_OFFSET: usize = 0x0c;
+    const LINK_STATUS_OFFSET: usize = 0x12;
+
+    pub fn new(device_type: PciExpressDeviceType) -> Self {
+        Self {
+            device_type,
+            link_speed: 1,
+            link_width: 1,
+        }
+    }
+
+    // The content of the capability following the ID and next pointer
+    fn body(&self) -> Vec<u8> {
+        let mut body = vec![0u8; Self::BODY_SIZE];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            body[offset - 2..offset - 2 + bytes.len()].copy_from_slice(bytes)
         };
-        devices.insert(ich9.bdf.into(), ich9);
+        let capabilities = Self::VERSION | (self.device_type as u16) << 4;
+        put(Self::CAPABILITIES_OFFSET, &capabilities.to_le_bytes());
 
-        Box::new(Self {
-            current_address: 0,
-            devices: devices,
-        })
+        let link = (self.link_speed as u32 & 0xf)
+            | (self.link_width as u32 & 0x3f) << 4;
+        put(Self::LINK_CAPABILITIES_OFFSET, &link.to_le_bytes());
+        put(Self::LINK_STATUS_OFFSET, &(link as u16).to_le_bytes());
+        body
     }
 }
 
-impl EmulatedDevice for PciRootComplex {
-    fn services(&self) -> Vec<DeviceRegion> {
-        vec![
-            DeviceRegion::PortIo(
-                Self::PCI_CONFIG_ADDRESS..=Self::PCI_CONFIG_ADDRESS,
-            ),
-            DeviceRegion::PortIo(
-                Self::PCI_CONFIG_DATA..=Self::PCI_CONFIG_DATA_MAX,
-            ),
-        ]
-    }
-    fn on_port_read(
-        &mut self,
-        port: Port,
-        mut val: PortReadRequest,
-        _space: GuestAddressSpaceViewMut,
-    ) -> Result<()> {
-        match port {
-            Self::PCI_CONFIG_ADDRESS => {
-                // For now, always set the enable bit
-                let addr = 0x80000000 | self.current_address;
-                val.copy_from_u32(addr);
-            }
-            Self::PCI_CONFIG_DATA..=Self::PCI_CONFIG_DATA_MAX => {
-                let bdf = ((self.current_address & 0xffff00) >> 8) as u16;
-                let register = (self.current_address & 0xff >> 2) as u8;
-                let offset = (port - Self::PCI_CONFIG_DATA) as u8;
+/// The kind of address space decoded by a base address register
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PciBarKind {
+    Io,
+    Memory32 {
+        prefetchable: bool,
+    },
 
-                match self.devices.get(&bdf) {
-                    Some(device) => {
-                        let res = device.config_space.read_register(register)
-                            >> (offset * 8);
-                        val.copy_from_u32(res);
-                        info!(
-                            "port=0x{:x}, register=0x{:x}, offset=0x{:x}, val={}",
-                            port, register, offset, val
-                        );
-                    }
-                    None => {
-                        // If no device is present, just return all 0xFFs
-                        let res = 0xffffffffu32;
-                        val.copy_from_u32(res);
-                    }
+    /// A memory BAR that also occupies the following BAR register (the
+    /// upper half of the address)
+    Memory64 {
+        prefetchable: bool,
+    },
+}
+
+/// A base address register declared by a function
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PciBar {
+    pub kind: PciBarKind,
+
+    /// The size of the decoded window (a power of two)
+    pub size: u64,
+}
+
+impl PciBar {
+    const IO_SPACE: u32 = 1;
+    const MEMORY_64: u32 = 0b10 << 1;
+    const PREFETCHABLE: u32 = 1 << 3;
+
+    const IO_ADDRESS_MASK: u32 = !0b11;
+    const MEMORY_ADDRESS_MASK: u32 = !0b1111;
+
+    // The smallest windows allowed by the spec, and the largest I/O window
+    const IO_MIN_SIZE: u64 = 4;
+    const IO_MAX_SIZE: u64 = 256;
+    const MEMORY_MIN_SIZE: u64 = 16;
+
+    /// The read-only type bits in the low bits of the (lower) register
+    fn type_bits(&self) -> u32 {
+        match self.kind {
+            PciBarKind::Io => Self::IO_SPACE,
+            PciBarKind::Memory32 { prefetchable } => {
+                if prefetchable {
+                    Self::PREFETCHABLE
+                } else {
+                    0
                 }
             }
-            _ => {
-                return Err(Error::InvalidValue(format!(
-                    "Invalid PCI port read 0x{:x}",
-                    port
-                )))
+            PciBarKind::Memory64 { prefetchable } => {
+                Self::MEMORY_64
+                    | if prefetchable { Self::PREFETCHABLE } else { 0 }
             }
         }
-        Ok(())
     }
 
-    fn on_port_write(
-        &mut self,
-        port: Port,
-        val: PortWriteRequest,
-        _space: GuestAddressSpaceViewMut,
-    ) -> Result<()> {
-        match port {
-            Self::PCI_CONFIG_ADDRESS => {
-                let addr: u32 = val.try_into()?;
-                self.current_address = addr & 0x7fffffffu32;
-            }
-            _ => {
-                info!(
-                    "Attempt to write to port=0x{:x} (addr=0x{:x}). Ignoring.",
-                    port, self.current_address
-                );
-            }
+    /// The writable address bits of the (lower) register
+    ///
+    /// The address bits below the BAR size are hardwired to zero, so
+    /// writing all ones reads back the size mask.
+    fn address_mask(&self) -> u32 {
+        let size_mask = !(self.size - 1) as u32;
+        match self.kind {
+            PciBarKind::Io => size_mask & Self::IO_ADDRESS_MASK,
+            _ => size_mask & Self::MEMORY_ADDRESS_MASK,
+        }
+    }
+
+    /// The writable bits of the upper register of a 64-bit BAR
+    fn upper_address_mask(&self) -> u32 {
+        (!(self.size - 1) >> 32) as u32
+    }
+
+    fn register_count(&self) -> usize {
+        match self.kind {
+            PciBarKind::Memory64 { .. } => 2,
+            _ => 1,
         }
-        Ok(())
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::memory::{
-        GuestAddressSpace, GuestAddressSpaceViewMut, GuestPhysAddr,
-    };
+/// The registers decoded by the BARs of a `PciDevice`
+///
+/// Accesses are given as the BAR index and the offset within it, with the
+/// data in the same byte order as the `DeviceMap` requests.
+pub trait PciBarHandler {
+    fn on_bar_read(
+        &mut self,
+        index: u8,
+        offset: u64,
+        data: &mut [u8],
+    ) -> Result<()>;
+
+    fn on_bar_write(
+        &mut self,
+        index: u8,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()>;
+}
+
+pub struct PciDevice {
+    config_space: PciConfigSpace,
+    bdf: PciBdf,
+
+    // The registers following the first 256 bytes of configuration space
+    // (empty for a legacy function)
+    extended_space: Vec<u32>,
 
-    fn define_test_view() -> GuestAddressSpaceViewMut<'static> {
-        let space: &'static mut GuestAddressSpace =
-            Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
-        GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space)
+    // The offset of the last capability in the capability list, and the
+    // offset where the next capability will be placed
+    last_capability: Option<u8>,
+    capability_end: u8,
+
+    option_rom: Option<PciOptionRom>,
+
+    // The MSI capability and its offset (the capability's registers are
+    // serviced by it, rather than the configuration space)
+    msi: Option<(u8, MsiCapability)>,
+
+    // The declared BARs, indexed by their (lower) register, the windows
+    // they currently decode and the handler of the accesses to them
+    bars: [Option<PciBar>; PciBarWindows::BAR_COUNT],
+    bar_windows: PciBarWindows,
+    bar_handler: Option<Box<dyn PciBarHandler>>,
+}
+
+impl PciDevice {
+    const BAR_REGISTER: u16 = 0x10 / 4;
+    // The register at byte offset 0x04
+    const COMMAND_REGISTER: u16 = 1;
+    const CAPABILITIES_POINTER: usize = 0x34;
+    const CAPABILITIES_START: u8 = 0x40;
+    const EXPANSION_ROM_REGISTER: u16 = 0x30 / 4;
+    const INTERRUPT_REGISTER: u16 = 0x3c / 4;
+    const LEGACY_REGISTERS: u16 = 256 / 4;
+
+    // Only the interrupt line of the interrupt register is writable
+    const INTERRUPT_LINE_MASK: u32 = 0xff;
+
+    // The writable command bits: I/O space, memory space, bus master,
+    // parity error response, SERR# enable and interrupt disable
+    const COMMAND_MASK: u32 = 0x0547;
+    const COMMAND_IO_SPACE: u16 = 1 << 0;
+    const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+
+    /// The largest interrupt pin (INTD#)
+    pub const MAX_INTERRUPT_PIN: u8 = 4;
+
+    /// The smallest option ROM window (the granularity of the expansion
+    /// ROM BAR)
+    pub const OPTION_ROM_MIN_SIZE: u32 = 0x800;
+
+    /// Create a (non-bridge) device with the given identity
+    pub fn new(bdf: PciBdf, vendor_id: u16, device_id: u16) -> Self {
+        Self {
+            bdf,
+            config_space: PciConfigSpace::Type0(PciNonBridgeSpace::new(
+                PciNonBridgeHeader {
+                    vendor_id,
+                    device_id,
+                    ..PciNonBridgeHeader::default()
+                },
+            )),
+            extended_space: vec![],
+            last_capability: None,
+            capability_end: Self::CAPABILITIES_START,
+            option_rom: None,
+            msi: None,
+            bars: [None; PciBarWindows::BAR_COUNT],
+            bar_windows: PciBarWindows::new(),
+            bar_handler: None,
+        }
     }
 
-    fn complex_ready_for_reg_read(reg: u8) -> Box<PciRootComplex> {
-        use core::convert::TryFrom;
+    pub fn bdf(&self) -> PciBdf {
+        self.bdf
+    }
 
-        let view = define_test_view();
-        let mut complex = PciRootComplex::new();
-        let addr = ((reg << 2) as u32).to_be_bytes();
-        let request = PortWriteRequest::try_from(&addr[..]).unwrap();
-        complex
-            .on_port_write(PciRootComplex::PCI_CONFIG_ADDRESS, request, view)
-            .unwrap();
-        complex
+    /// Set the size of this function's configuration space (a PCI Express
+    /// function has 4KB). Registers beyond the end read as 0.
+    pub fn set_config_size(&mut self, size: PciConfigSize) {
+        let extended = size.registers() - Self::LEGACY_REGISTERS;
+        self.extended_space.resize(extended as usize, 0);
     }
 
-    #[test]
-    fn test_full_register_read() {
-        let view = define_test_view();
-        let mut complex = complex_ready_for_reg_read(0);
-        let mut buff = [0u8; 4];
-        let val = PortReadRequest::FourBytes(&mut buff);
-        complex
-            .on_port_read(PciRootComplex::PCI_CONFIG_DATA, val, view)
-            .unwrap();
+    pub fn config_size(&self) -> PciConfigSize {
+        if self.extended_space.is_empty() {
+            PciConfigSize::Legacy
+        } else {
+            PciConfigSize::Extended
+        }
+    }
 
-        assert_eq!(u32::from_be_bytes(buff), 0x29c08086);
+    // The value of the 32-bit `register` (0 beyond the configuration space)
+    fn read_config_register(&self, register: u16) -> u32 {
+        if let Some((msi, offset)) = self.msi_register(register) {
+            return msi.read_register(offset);
+        }
+        if register < Self::LEGACY_REGISTERS {
+            return self.config_space.read_register(register as u8);
+        }
+        self.extended_space
+            .get((register - Self::LEGACY_REGISTERS) as usize)
+            .copied()
+            .unwrap_or(0)
     }
 
-    #[test]
-    fn test_half_register_read() {
-        let view = define_test_view();
-        let mut complex = complex_ready_for_reg_read(0);
-        let mut buff = [0u8; 2];
-        let val = PortReadRequest::TwoBytes(&mut buff);
+    fn write_config_register(&mut self, register: u16, val: u32) {
+        if let Some((_, offset)) = self.msi_register(register) {
+            if let Some((_, msi)) = self.msi.as_mut() {
+                msi.write_register(offset, val);
+            }
+        } else if register < Self::LEGACY_REGISTERS {
+            self.config_space.write_register(register as u8, val);
+        } else if let Some(reg) = self
+            .extended_space
+            .get_mut((register - Self::LEGACY_REGISTERS) as usize)
+        {
+            *reg = val;
+        }
+    }
 
-        complex
-            .on_port_read(PciRootComplex::PCI_CONFIG_DATA, val, view)
-            .unwrap();
-        assert_eq!(u16::from_be_bytes(buff), 0x8086);
+    /// Report this function as part of a multi-function device (bit 7 of
+    /// the header type)
+    pub fn set_multi_function(&mut self, multi_function: bool) {
+        if let Some(header) = self.config_space.header_mut() {
+            header.set_multi_function(multi_function);
+        }
+    }
 
-        let view = define_test_view();
-        let val = PortReadRequest::TwoBytes(&mut buff);
-        complex
-            .on_port_read(PciRootComplex::PCI_CONFIG_DATA + 2, val, view)
-            .unwrap();
-        assert_eq!(u16::from_be_bytes(buff), 0x29c0);
+    /// Set the class code of this function
+    pub fn set_class(&mut self, class: u8, subclass: u8, prog_if: u8) {
+        if let Some(header) = self.config_space.header_mut() {
+            header.class = class;
+            header.subclass = subclass;
+            header.prog_if = prog_if;
+        }
     }
 
-    #[test]
-    fn test_register_byte_read() {
-        let mut complex = complex_ready_for_reg_read(0);
-        let mut buff = [0u8; 1];
+    /// Report that this function supports a built-in self test
+    pub fn set_bist_capable(&mut self, capable: bool) {
+        if let Some(header) = self.config_space.header_mut() {
+            header.set_bist_capable(capable);
+        }
+    }
 
-        let view = define_test_view();
-        let val = PortReadRequest::OneByte(&mut buff);
+    /// Declare the interrupt pin used by this function (1-4 for INTA# to
+    /// INTD#, or 0 if it does not use an interrupt pin)
+    ///
+    /// The pin is read-only to the guest.
+    pub fn set_interrupt_pin(&mut self, pin: u8) -> Result<()> {
+        if pin > Self::MAX_INTERRUPT_PIN {
+            return Err(Error::InvalidValue(format!(
+                "Invalid PCI interrupt pin: {}",
+                pin
+            )));
+        }
+        let header = self
+            .config_space
+            .header_mut()
+            .ok_or_else(|| Error::NotSupported)?;
+        header.interrupt_pin = pin;
+        Ok(())
+    }
 
-        complex
-            .on_port_read(PciRootComplex::PCI_CONFIG_DATA, val, view)
-            .unwrap();
-        assert_eq!(u8::from_be_bytes(buff), 0x86);
+    /// The interrupt pin used by this function (0 if none)
+    pub fn interrupt_pin(&self) -> u8 {
+        self.config_space
+            .header()
+            .map(|header| header.interrupt_pin)
+            .unwrap_or(0)
+    }
 
-        let view = define_test_view();
-        let val = PortReadRequest::OneByte(&mut buff);
+    /// The interrupt line recorded by the guest (the IRQ the function's
+    /// pin is routed to)
+    pub fn interrupt_line(&self) -> u8 {
+        self.config_space
+            .header()
+            .map(|header| header.interrupt_line)
+            .unwrap_or(0)
+    }
+
+    /// Append a capability with the given ID to the capability list
+    ///
+    /// `body` is the content of the capability following the ID and next
+    /// pointer. Adding a capability sets the capabilities list bit in the
+    /// status register. Returns the offset of the new capability.
+    pub fn add_capability(&mut self, id: u8, body: &[u8]) -> Result<u8> {
+        let offset = self.capability_end;
+        let end = offset as usize + 2 + body.len();
+        if end > 256 {
+            return Err(Error::InvalidValue(format!(
+                "No space for a {} byte capability in {:?}",
+                body.len() + 2,
+                self.bdf
+            )));
+        }
+
+        let header = self
+            .config_space
+            .header_mut()
+            .ok_or_else(|| Error::NotSupported)?;
+        header.status |= PciNonBridgeHeader::STATUS_CAPABILITIES_LIST;
+
+        let bytes = self.config_space.as_bytes_mut();
+        match self.last_capability {
+            Some(last) => bytes[last as usize + 1] = offset,
+            None => bytes[Self::CAPABILITIES_POINTER] = offset,
+        }
+        bytes[offset as usize] = id;
+        bytes[offset as usize + 1] = 0;
+        bytes[offset as usize + 2..end].copy_from_slice(body);
+
+        // The MSI capability holds its own next pointer
+        if let Some((msi_offset, msi)) = self.msi.as_mut() {
+            if Some(*msi_offset) == self.last_capability {
+                msi.set_next(offset);
+            }
+        }
+
+        // Capabilities must be dword aligned
+        self.last_capability = Some(offset);
+        self.capability_end = ((end + 3) & !3).min(0xff) as u8;
+        Ok(offset)
+    }
+
+    /// The offset of the first capability with the given ID, if present
+    pub fn find_capability(&self, id: u8) -> Option<u8> {
+        let mut offset = self.read_config_byte(Self::CAPABILITIES_POINTER);
+        // Bound the walk, in case the list has been corrupted into a loop
+        for _ in 0..(256 / 4) {
+            if offset == 0 {
+                return None;
+            }
+            if self.read_config_byte(offset as usize) == id {
+                return Some(offset);
+            }
+            offset = self.read_config_byte(offset as usize + 1);
+        }
+        None
+    }
+
+    /// Add the MSI capability, returning its offset
+    ///
+    /// The guest's accesses to the capability are serviced by `msi`, so
+    /// the address and data it programs are used by `msi_mut().fire(..)`.
+    pub fn add_msi_capability(&mut self, msi: MsiCapability) -> Result<u8> {
+        if self.msi.is_some() {
+            return Err(Error::InvalidValue(format!(
+                "{:?} already has an MSI capability",
+                self.bdf
+            )));
+        }
+        let offset = self.add_capability(MsiCapability::CAP_ID, &msi.body())?;
+        self.msi = Some((offset, msi));
+        Ok(offset)
+    }
+
+    /// The MSI capability of this function, if present
+    pub fn msi(&self) -> Option<&MsiCapability> {
+        self.msi.as_ref().map(|(_, msi)| msi)
+    }
+
+    pub fn msi_mut(&mut self) -> Option<&mut MsiCapability> {
+        self.msi.as_mut().map(|(_, msi)| msi)
+    }
+
+    // The MSI capability and the byte offset within it of `register`, if
+    // the register is part of the capability
+    fn msi_register(&self, register: u16) -> Option<(&MsiCapability, u8)> {
+        let (start, msi) = self.msi.as_ref()?;
+        let offset = (register * 4).checked_sub(*start as u16)?;
+        if offset < msi.size() as u16 {
+            Some((msi, offset as u8))
+        } else {
+            None
+        }
+    }
+
+    /// Add the PCI Express capability, marking this as a PCI Express
+    /// function (with 4KB of configuration space). Returns the offset of
+    /// the capability.
+    pub fn add_express_capability(&mut self, cap: PciExpressCap) -> Result<u8> {
+        if self.find_capability(PciExpressCap::ID).is_some() {
+            return Err(Error::InvalidValue(format!(
+                "{:?} already has a PCI Express capability",
+                self.bdf
+            )));
+        }
+        let offset = self.add_capability(PciExpressCap::ID, &cap.body())?;
+        self.set_config_size(PciConfigSize::Extended);
+        Ok(offset)
+    }
+
+    /// The device/port type in the PCI Express capability, if present
+    pub fn express_device_type(&self) -> Option<PciExpressDeviceType> {
+        let offset = self.find_capability(PciExpressCap::ID)? as usize;
+        let capabilities =
+            self.read_config_byte(offset + PciExpressCap::CAPABILITIES_OFFSET);
+        PciExpressDeviceType::try_from(capabilities >> 4)
+    }
+
+    fn read_config_byte(&self, offset: usize) -> u8 {
+        (self.read_config_register((offset / 4) as u16) >> ((offset % 4) * 8))
+            as u8
+    }
+
+    /// Expose `image` as the option ROM of this function
+    ///
+    /// `size` is the size of the window decoded by the expansion ROM BAR,
+    /// which must be a power of two of at least `OPTION_ROM_MIN_SIZE` and
+    /// large enough for the image (the rest of the window reads as 0).
+    /// Without an option ROM, the expansion ROM BAR is hardwired to 0.
+    pub fn set_option_rom(&mut self, image: Vec<u8>, size: u32) -> Result<()> {
+        if !size.is_power_of_two()
+            || size < Self::OPTION_ROM_MIN_SIZE
+            || image.len() > size as usize
+        {
+            return Err(Error::InvalidValue(format!(
+                "Invalid option ROM size 0x{:x} for a {} byte image",
+                size,
+                image.len()
+            )));
+        }
+        let header = self
+            .config_space
+            .header_mut()
+            .ok_or_else(|| Error::NotSupported)?;
+        header.expansion_rom_addr = 0;
+        self.option_rom = Some(PciOptionRom {
+            size,
+            image,
+            mapped: None,
+        });
+        Ok(())
+    }
+
+    /// Declare BAR `index` of this function
+    ///
+    /// The size must be a power of two of at least 16 bytes for a memory
+    /// BAR, or between 4 and 256 bytes for an I/O BAR. A 64-bit BAR also
+    /// uses register `index + 1`. Guest writes to the BAR keep the type
+    /// bits and align the address down to the size, and undeclared BARs
+    /// are hardwired to 0.
+    pub fn set_bar(&mut self, index: u8, bar: PciBar) -> Result<()> {
+        let min_size = match bar.kind {
+            PciBarKind::Io => PciBar::IO_MIN_SIZE,
+            _ => PciBar::MEMORY_MIN_SIZE,
+        };
+        let too_large = match bar.kind {
+            PciBarKind::Io => bar.size > PciBar::IO_MAX_SIZE,
+            PciBarKind::Memory32 { .. } => bar.size > 1 << 32,
+            PciBarKind::Memory64 { .. } => false,
+        };
+        if !bar.size.is_power_of_two() || bar.size < min_size || too_large {
+            return Err(Error::InvalidValue(format!(
+                "Invalid size 0x{:x} for a {:?} BAR",
+                bar.size, bar.kind
+            )));
+        }
+
+        let index = index as usize;
+        let end = index + bar.register_count();
+        let overlaps = (index..end).any(|i| self.bar_register(i).is_some());
+        if end > PciBarWindows::BAR_COUNT || overlaps {
+            return Err(Error::InvalidValue(format!(
+                "BAR {} of {:?} is not available for a {:?} BAR",
+                index, self.bdf, bar.kind
+            )));
+        }
+
+        self.bars[index] = Some(bar);
+        self.clear_bar(index, bar);
+        Ok(())
+    }
+
+    // Return the registers of BAR `index` to an unassigned address
+    fn clear_bar(&mut self, index: usize, bar: PciBar) {
+        let register = Self::BAR_REGISTER + index as u16;
+        self.write_config_register(register, bar.type_bits());
+        if bar.register_count() == 2 {
+            self.write_config_register(register + 1, 0);
+        }
+    }
+
+    /// The BAR occupying register `index`, and whether the register is
+    /// the upper half of a 64-bit BAR
+    fn bar_register(&self, index: usize) -> Option<(PciBar, bool)> {
+        if let Some(bar) = self.bars.get(index).copied().flatten() {
+            return Some((bar, false));
+        }
+        let lower = index.checked_sub(1)?;
+        self.bars
+            .get(lower)
+            .copied()
+            .flatten()
+            .filter(|bar| bar.register_count() == 2)
+            .map(|bar| (bar, true))
+    }
+
+    /// The address currently programmed into BAR `index`
+    pub fn bar_address(&self, index: u8) -> Option<u64> {
+        let bar = self.bars.get(index as usize).copied().flatten()?;
+        let register = Self::BAR_REGISTER + index as u16;
+        let lower = self.read_config_register(register) & bar.address_mask();
+        let upper = if bar.register_count() == 2 {
+            self.read_config_register(register + 1)
+        } else {
+            0
+        };
+        Some((upper as u64) << 32 | lower as u64)
+    }
+
+    fn write_bar(&mut self, register: u16, val: u32) {
+        let index = (register - Self::BAR_REGISTER) as usize;
+        let new = match self.bar_register(index) {
+            Some((bar, true)) => val & bar.upper_address_mask(),
+            Some((bar, false)) => (val & bar.address_mask()) | bar.type_bits(),
+            None => return,
+        };
+        self.write_config_register(register, new);
+        self.update_bar_windows();
+    }
+
+    /// Service the accesses to this function's BARs with `handler`
+    pub fn set_bar_handler(&mut self, handler: Box<dyn PciBarHandler>) {
+        self.bar_handler = Some(handler);
+    }
+
+    /// The windows currently decoded by this function's BARs
+    ///
+    /// A BAR is decoded while it has a (non-zero) address and its kind of
+    /// decoding is enabled in the command register.
+    pub fn bar_windows(&self) -> &PciBarWindows {
+        &self.bar_windows
+    }
+
+    // Move the BAR windows to match the BARs and command register
+    fn update_bar_windows(&mut self) {
+        let command = self
+            .config_space
+            .header()
+            .map(|header| header.command)
+            .unwrap_or(0);
+        for index in 0..PciBarWindows::BAR_COUNT as u8 {
+            let bar = match self.bars[index as usize] {
+                Some(bar) => bar,
+                None => continue,
+            };
+            let address = self.bar_address(index).unwrap_or(0);
+            let res = match bar.kind {
+                PciBarKind::Io
+                    if command & Self::COMMAND_IO_SPACE != 0
+                        && address != 0 =>
+                {
+                    match Port::try_from(address) {
+                        Ok(port) => self.bar_windows.assign_io(
+                            index,
+                            port,
+                            bar.size as u16,
+                        ),
+                        Err(_) => self.bar_windows.unassign(index),
+                    }
+                }
+                PciBarKind::Memory32 { .. } | PciBarKind::Memory64 { .. }
+                    if command & Self::COMMAND_MEMORY_SPACE != 0
+                        && address != 0 =>
+                {
+                    self.bar_windows.assign(
+                        index,
+                        GuestPhysAddr::new(address),
+                        bar.size,
+                    )
+                }
+                _ => self.bar_windows.unassign(index),
+            };
+            if let Err(e) = res {
+                warn!(
+                    "Failed to update BAR {} of {:?}: {:?}",
+                    index, self.bdf, e
+                );
+                let _ = self.bar_windows.unassign(index);
+            }
+        }
+    }
+
+    /// The window where the option ROM is currently decoded, if enabled
+    pub fn option_rom_window(&self) -> Option<RangeInclusive<GuestPhysAddr>> {
+        self.option_rom
+            .as_ref()
+            .and_then(|rom| rom.mapped.as_ref())
+            .map(|rom| rom.region().clone())
+    }
+
+    // Handle a guest write of `val` at byte `offset` of `register`,
+    // returning the change to the regions serviced for this device
+    fn write_config(
+        &mut self,
+        register: u16,
+        offset: u8,
+        val: PortWriteRequest,
+    ) -> Option<RegionDelta> {
+        let shift = offset as u32 * 8;
+        let width = val.as_slice().len() as u32 * 8;
+        let mask = (!0u32 >> (32 - width)) << shift;
+        let old = self.read_config_register(register);
+        let new = masked_write(old, val.as_u32() << shift, mask);
+
+        match register {
+            r if (Self::BAR_REGISTER
+                ..Self::BAR_REGISTER + PciBarWindows::BAR_COUNT as u16)
+                .contains(&r) =>
+            {
+                self.write_bar(register, new);
+                None
+            }
+            Self::COMMAND_REGISTER => {
+                self.write_config_register(
+                    register,
+                    masked_write(old, new, Self::COMMAND_MASK),
+                );
+                self.update_bar_windows();
+                None
+            }
+            Self::EXPANSION_ROM_REGISTER => self.write_expansion_rom(new),
+            r if self.msi_register(r).is_some() => {
+                self.write_config_register(register, new);
+                None
+            }
+            Self::INTERRUPT_REGISTER => {
+                self.write_config_register(
+                    register,
+                    masked_write(old, new, Self::INTERRUPT_LINE_MASK),
+                );
+                None
+            }
+            _ => {
+                info!(
+                    "Ignoring write of {} to bdf={:?}, register=0x{:x}",
+                    val, self.bdf, register
+                );
+                None
+            }
+        }
+    }
+
+    // Return the guest programmed state (the command register, BARs,
+    // interrupt line and MSI capability) to its power-on state, returning
+    // the change to the option ROM window (the BAR windows report their
+    // own changes)
+    fn reset(&mut self) -> Option<RegionDelta> {
+        if let Some(header) = self.config_space.header_mut() {
+            header.command = 0;
+            header.interrupt_line = 0;
+        }
+        for index in 0..PciBarWindows::BAR_COUNT {
+            if let Some(bar) = self.bars[index] {
+                self.clear_bar(index, bar);
+            }
+        }
+        if let Some(msi) = self.msi_mut() {
+            msi.reset();
+        }
+        self.update_bar_windows();
+        self.write_expansion_rom(0)
+    }
+
+    fn write_expansion_rom(&mut self, val: u32) -> Option<RegionDelta> {
+        let rom = self.option_rom.as_mut()?;
+        let header = self.config_space.header_mut()?;
+        header.write_expansion_rom(val, rom.size);
+        rom.map(header.expansion_rom_window(rom.size))
+    }
+
+    // The mapped option ROM containing `addr`
+    fn option_rom_at(&mut self, addr: GuestPhysAddr) -> Option<&mut RomDevice> {
+        self.option_rom
+            .as_mut()
+            .and_then(|rom| rom.mapped.as_mut())
+            .filter(|rom| rom.region().contains(&addr))
+            .map(|rom| &mut **rom)
+    }
+}
+
+/// A builder for a synthetic PCI function, for testing the PCI emulation
+///
+/// ```ignore
+/// let device = TestPciDevice::new(PciBdf::new(0, 3, 0), 0x1af4, 0x1000)
+///     .class(0x02, 0x00, 0x00)
+///     .bar(0, PciBar { kind: PciBarKind::Io, size: 0x20 })
+///     .interrupt_pin(1)
+///     .build()?;
+/// complex.add_device(device)?;
+/// ```
+pub struct TestPciDevice {
+    bdf: PciBdf,
+    vendor_id: u16,
+    device_id: u16,
+    class: (u8, u8, u8),
+    bars: Vec<(u8, PciBar)>,
+    capabilities: Vec<(u8, Vec<u8>)>,
+    interrupt_pin: u8,
+}
+
+impl TestPciDevice {
+    pub fn new(bdf: PciBdf, vendor_id: u16, device_id: u16) -> Self {
+        Self {
+            bdf,
+            vendor_id,
+            device_id,
+            class: (0, 0, 0),
+            bars: vec![],
+            capabilities: vec![],
+            interrupt_pin: 0,
+        }
+    }
+
+    pub fn class(mut self, class: u8, subclass: u8, prog_if: u8) -> Self {
+        self.class = (class, subclass, prog_if);
+        self
+    }
+
+    /// Declare a BAR at (lower) register `index`
+    pub fn bar(mut self, index: u8, bar: PciBar) -> Self {
+        self.bars.push((index, bar));
+        self
+    }
+
+    /// Append a capability (see `PciDevice::add_capability`)
+    pub fn capability(mut self, id: u8, body: &[u8]) -> Self {
+        self.capabilities.push((id, body.to_vec()));
+        self
+    }
+
+    pub fn interrupt_pin(mut self, pin: u8) -> Self {
+        self.interrupt_pin = pin;
+        self
+    }
+
+    /// Create the device, failing if any BAR, capability or the interrupt
+    /// pin is invalid
+    pub fn build(self) -> Result<PciDevice> {
+        let mut device =
+            PciDevice::new(self.bdf, self.vendor_id, self.device_id);
+        let (class, subclass, prog_if) = self.class;
+        device.set_class(class, subclass, prog_if);
+        for (index, bar) in self.bars {
+            device.set_bar(index, bar)?;
+        }
+        for (id, body) in self.capabilities {
+            device.add_capability(id, &body)?;
+        }
+        device.set_interrupt_pin(self.interrupt_pin)?;
+        Ok(device)
+    }
+}
+
+/// The guest physical (or I/O port) windows assigned to a PCI device's
+/// BARs
+///
+/// A device that services its BARs keeps one of these up to date as the
+/// BARs are assigned, and uses `decode` (or `decode_port`) to turn an
+/// access into a BAR relative offset. Each assignment is reported once
+/// through `region_changed`, which the device should return from its own
+/// `EmulatedDevice::region_changed`, so the `DeviceMap` follows the BARs.
+/// The `PciDevice`s of a `PciRootComplex` keep theirs in sync with the
+/// guest programmed BARs and command register.
+#[derive(Debug, Default)]
+pub struct PciBarWindows {
+    windows: [Option<DeviceRegion>; PciBarWindows::BAR_COUNT],
+    changes: RefCell<VecDeque<RegionDelta>>,
+}
+
+impl PciBarWindows {
+    /// The number of BARs in a (non-bridge) configuration space header
+    pub const BAR_COUNT: usize = 6;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Place memory BAR `index` at `base`, moving it if it was already
+    /// assigned
+    pub fn assign(
+        &mut self,
+        index: u8,
+        base: GuestPhysAddr,
+        size: u64,
+    ) -> Result<()> {
+        let end = size
+            .checked_sub(1)
+            .and_then(|len| base.as_u64().checked_add(len))
+            .ok_or_else(|| {
+                Error::InvalidValue(format!(
+                    "Invalid BAR window: base={:?}, size=0x{:x}",
+                    base, size
+                ))
+            })?;
+        self.set_window(
+            index,
+            DeviceRegion::MemIo(base..=GuestPhysAddr::new(end)),
+        )
+    }
+
+    /// Place I/O BAR `index` at port `base`, moving it if it was already
+    /// assigned
+    pub fn assign_io(
+        &mut self,
+        index: u8,
+        base: Port,
+        size: u16,
+    ) -> Result<()> {
+        let end = size
+            .checked_sub(1)
+            .and_then(|len| base.checked_add(len))
+            .ok_or_else(|| {
+                Error::InvalidValue(format!(
+                    "Invalid BAR window: base=0x{:x}, size=0x{:x}",
+                    base, size
+                ))
+            })?;
+        self.set_window(index, DeviceRegion::PortIo(base..=end))
+    }
+
+    fn set_window(&mut self, index: u8, new: DeviceRegion) -> Result<()> {
+        let slot = self.slot(index)?;
+        let delta = match slot.replace(new.clone()) {
+            Some(old) if old == new => return Ok(()),
+            Some(old) => RegionDelta::Resize { old, new },
+            None => RegionDelta::Add(new),
+        };
+        self.changes.borrow_mut().push_back(delta);
+        Ok(())
+    }
+
+    /// Stop decoding BAR `index` (e.g., because memory decode is disabled)
+    pub fn unassign(&mut self, index: u8) -> Result<()> {
+        if let Some(old) = self.slot(index)?.take() {
+            self.changes
+                .borrow_mut()
+                .push_back(RegionDelta::Remove(old));
+        }
+        Ok(())
+    }
+
+    /// The memory BAR containing `addr` and the offset of `addr` within it
+    pub fn decode(&self, addr: GuestPhysAddr) -> Option<(u8, u64)> {
+        self.windows
+            .iter()
+            .enumerate()
+            .find_map(|(index, window)| match window {
+                Some(DeviceRegion::MemIo(window)) if window.contains(&addr) => {
+                    Some((index as u8, addr.as_u64() - window.start().as_u64()))
+                }
+                _ => None,
+            })
+    }
+
+    /// The I/O BAR containing `port` and the offset of `port` within it
+    pub fn decode_port(&self, port: Port) -> Option<(u8, u64)> {
+        self.windows
+            .iter()
+            .enumerate()
+            .find_map(|(index, window)| match window {
+                Some(DeviceRegion::PortIo(window))
+                    if window.contains(&port) =>
+                {
+                    Some((index as u8, (port - window.start()) as u64))
+                }
+                _ => None,
+            })
+    }
+
+    /// The regions of the assigned BARs, for `EmulatedDevice::services`
+    pub fn regions(&self) -> Vec<DeviceRegion> {
+        self.windows.iter().filter_map(Clone::clone).collect()
+    }
+
+    /// The next unreported change to the assigned windows
+    pub fn region_changed(&self) -> Option<RegionDelta> {
+        self.changes.borrow_mut().pop_front()
+    }
+
+    fn slot(&mut self, index: u8) -> Result<&mut Option<DeviceRegion>> {
+        self.windows.get_mut(index as usize).ok_or_else(|| {
+            Error::InvalidValue(format!("Invalid BAR index: {}", index))
+        })
+    }
+}
+
+/// The registers of the configuration space header, as dwords
+impl RegisterBlock for PciDevice {
+    fn registers(&self) -> &'static [RegisterInfo] {
+        const REGISTERS: &[RegisterInfo] = &[
+            RegisterInfo::new("ID", 0x00, 4),
+            RegisterInfo::new("STATUS/COMMAND", 0x04, 4),
+            RegisterInfo::new("CLASS/REVISION", 0x08, 4),
+            RegisterInfo::new("BIST/HEADER", 0x0c, 4),
+            RegisterInfo::new("BAR0", 0x10, 4),
+            RegisterInfo::new("BAR1", 0x14, 4),
+            RegisterInfo::new("BAR2", 0x18, 4),
+            RegisterInfo::new("BAR3", 0x1c, 4),
+            RegisterInfo::new("BAR4", 0x20, 4),
+            RegisterInfo::new("BAR5", 0x24, 4),
+            RegisterInfo::new("SUBSYSTEM", 0x2c, 4),
+            RegisterInfo::new("ROM", 0x30, 4),
+            RegisterInfo::new("CAPABILITIES", 0x34, 4),
+            RegisterInfo::new("INTERRUPT", 0x3c, 4),
+        ];
+        REGISTERS
+    }
+
+    /// Read the 32 bits at `offset`, which need not be dword aligned (the
+    /// bytes beyond the containing dword read as 0)
+    fn read_reg(&self, offset: u16) -> u32 {
+        self.read_config_register(offset / 4) >> ((offset % 4) * 8)
+    }
+
+    fn write_reg(&mut self, offset: u16, val: u32, mask: u32) {
+        let register = offset / 4;
+        let shift = (offset % 4) * 8;
+        let old = self.read_config_register(register);
+        self.write_config_register(
+            register,
+            masked_write(old, val << shift, mask << shift),
+        );
+    }
+}
+
+/// A change to the set of PCI devices that the guest has not yet seen
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PciHotplugEvent {
+    Added(PciBdf),
+    Removed(PciBdf),
+}
+
+/// The PCI hot-plug state shared by the root complex (which records the
+/// events) and the ACPI GPE block (which reports them to the guest)
+///
+/// The guest learns of events through bitmaps of the bus 0 slots with
+/// added or removed devices. Reading a bitmap acknowledges those events.
+#[derive(Debug, Default)]
+pub struct PciHotplug {
+    events: Vec<PciHotplugEvent>,
+}
+
+impl PciHotplug {
+    pub fn push(&mut self, event: PciHotplugEvent) {
+        self.events.push(event);
+    }
+
+    /// The events that have not been acknowledged by the guest
+    pub fn pending(&self) -> &[PciHotplugEvent] {
+        &self.events
+    }
+
+    /// Returns true if there are events the guest can be notified of
+    pub fn is_pending(&self) -> bool {
+        self.events.iter().any(|event| Self::slot(event).is_some())
+    }
+
+    /// The bitmap of bus 0 slots with added devices (acknowledging them)
+    pub fn take_added_slots(&mut self) -> u32 {
+        self.take_slots(|event| matches!(event, PciHotplugEvent::Added(_)))
+    }
+
+    /// The bitmap of bus 0 slots with removed devices (acknowledging them)
+    pub fn take_removed_slots(&mut self) -> u32 {
+        self.take_slots(|event| matches!(event, PciHotplugEvent::Removed(_)))
+    }
+
+    fn slot(event: &PciHotplugEvent) -> Option<u8> {
+        let bdf = match event {
+            PciHotplugEvent::Added(bdf) | PciHotplugEvent::Removed(bdf) => bdf,
+        };
+        if bdf.bus() == 0 {
+            Some(bdf.device())
+        } else {
+            None
+        }
+    }
+
+    fn take_slots(&mut self, filter: impl Fn(&PciHotplugEvent) -> bool) -> u32 {
+        let mut slots = 0;
+        self.events.retain(|event| match Self::slot(event) {
+            Some(slot) if filter(event) => {
+                slots |= 1 << slot;
+                false
+            }
+            _ => true,
+        });
+        slots
+    }
+}
+
+/// The method used by the guest to access PCI configuration space
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PciConfigMechanism {
+    /// CONFIG_ADDRESS (0xCF8) and CONFIG_DATA (0xCFC) registers
+    Mechanism1,
+
+    /// The configuration space enable register (0xCF8) and forward
+    /// register (0xCFA), with per-device I/O windows at 0xC000-0xCEFF
+    Mechanism2,
+}
+
+pub struct PciRootComplex {
+    mechanism: PciConfigMechanism,
+    current_address: u32,
+
+    // The decoded form of `current_address`, updated whenever it changes
+    // so the (very frequent) CONFIG_DATA accesses don't need to decode it
+    current_target: (PciBdf, u8),
+    devices: BTreeMap<u16, PciDevice>,
+    hotplug: Rc<RefCell<PciHotplug>>,
+
+    // Changes to the option ROM and BAR windows not yet seen by the
+    // `DeviceMap`
+    region_changes: RefCell<VecDeque<RegionDelta>>,
+
+    // The value read from the configuration space of an absent function,
+    // and the callback invoked when the guest probes one
+    absent_fill: u32,
+    absent_probe: Option<Box<dyn Fn(PciBdf)>>,
+
+    // The mechanism #2 configuration space enable and forward registers
+    cse: u8,
+    forward: u8,
+
+    // The base of the memory mapped (ECAM) configuration space window
+    ecam_base: Option<GuestPhysAddr>,
+}
+
+impl PciRootComplex {
+    const PCI_CONFIG_ADDRESS: Port = 0xcf8;
+    const PCI_CONFIG_DATA: Port = 0xcfc;
+    const PCI_CONFIG_DATA_MAX: Port = Self::PCI_CONFIG_DATA + 3;
+
+    // The enable bit, bus, device, function and register of CONFIG_ADDRESS
+    // (the reserved bits and low two bits are hardwired to zero)
+    const CONFIG_ADDRESS_MASK: u32 = 0x80ff_fffc;
+
+    const PCI_CSE: Port = 0xcf8;
+    const PCI_FORWARD: Port = 0xcfa;
+    const PCI_CONFIG_WINDOW: Port = 0xc000;
+    const PCI_CONFIG_WINDOW_MAX: Port = 0xceff;
+
+    /// The value read from the configuration space of an absent function
+    /// unless configured otherwise
+    pub const DEFAULT_ABSENT_FILL: u32 = 0xffffffff;
+
+    /// The size of the ECAM window (4KB for each function of 256 buses)
+    pub const ECAM_SIZE: u64 = 256 << 20;
+
+    pub fn new() -> Box<Self> {
+        Self::with_config_mechanism(PciConfigMechanism::Mechanism1)
+    }
+
+    /// Create a root complex that is accessed using the given mechanism
+    pub fn with_config_mechanism(mechanism: PciConfigMechanism) -> Box<Self> {
+        let mut devices = BTreeMap::new();
+
+        let host_bridge = PciDevice::new(
+            PciBdf::from(0x0000),
+            VendorId::Intel as u16,
+            DeviceId::P35Mch as u16,
+        );
+        devices.insert(host_bridge.bdf.into(), host_bridge);
+
+        let ich9 = PciDevice::new(
+            PciBdf::from(0b1000),
+            VendorId::Intel as u16,
+            DeviceId::Ich9 as u16,
+        );
+        devices.insert(ich9.bdf.into(), ich9);
+
+        Box::new(Self {
+            mechanism,
+            current_address: 0,
+            current_target: PciBdf::from_config_address(0),
+            devices: devices,
+            hotplug: Rc::new(RefCell::new(PciHotplug::default())),
+            region_changes: RefCell::new(VecDeque::new()),
+            absent_fill: Self::DEFAULT_ABSENT_FILL,
+            absent_probe: None,
+            cse: 0,
+            forward: 0,
+            ecam_base: None,
+        })
+    }
+
+    /// Set the value read from the configuration space of absent functions
+    pub fn set_absent_fill(&mut self, fill: u32) {
+        self.absent_fill = fill;
+    }
+
+    /// Invoke `probe` with the BDF of each configuration space read of an
+    /// absent function (e.g., to observe how the guest enumerates the bus)
+    pub fn set_absent_probe(&mut self, probe: impl Fn(PciBdf) + 'static) {
+        self.absent_probe = Some(Box::new(probe));
+    }
+
+    /// Expose the configuration space of every function through the
+    /// memory mapped (ECAM) window at `base`, which is the only way to
+    /// reach the extended configuration space of PCI Express functions
+    ///
+    /// This must be called before the root complex is registered.
+    pub fn set_ecam_base(&mut self, base: GuestPhysAddr) -> Result<()> {
+        if base.as_u64() % Self::ECAM_SIZE != 0 {
+            return Err(Error::InvalidValue(format!(
+                "Misaligned ECAM window at {:?}",
+                base
+            )));
+        }
+        self.ecam_base = Some(base);
+        Ok(())
+    }
+
+    /// The device at `bdf`, if present
+    pub fn device(&self, bdf: PciBdf) -> Option<&PciDevice> {
+        self.devices.get(&bdf.into())
+    }
+
+    pub fn device_mut(&mut self, bdf: PciBdf) -> Option<&mut PciDevice> {
+        self.devices.get_mut(&bdf.into())
+    }
+
+    /// The hot-plug state of this root complex
+    pub fn hotplug(&self) -> Rc<RefCell<PciHotplug>> {
+        self.hotplug.clone()
+    }
+
+    /// Add a device (generating a hot-plug event for the guest)
+    pub fn add_device(&mut self, device: PciDevice) -> Result<()> {
+        let bdf: u16 = device.bdf.into();
+        if self.devices.contains_key(&bdf) {
+            return Err(Error::InvalidDevice(format!(
+                "A PCI device already exists at {:?}",
+                device.bdf
+            )));
+        }
+        self.hotplug
+            .borrow_mut()
+            .push(PciHotplugEvent::Added(device.bdf));
+        self.devices.insert(bdf, device);
+        Ok(())
+    }
+
+    /// Remove a device (generating a hot-plug event for the guest)
+    pub fn remove_device(&mut self, bdf: PciBdf) -> Result<PciDevice> {
+        let device = self.devices.remove(&bdf.into()).ok_or_else(|| {
+            Error::MissingDevice(format!("No PCI device at {:?}", bdf))
+        })?;
+        self.hotplug
+            .borrow_mut()
+            .push(PciHotplugEvent::Removed(bdf));
+        let mut changes = self.region_changes.borrow_mut();
+        if let Some(window) = device.option_rom_window() {
+            changes.push_back(RegionDelta::Remove(DeviceRegion::MemIo(window)));
+        }
+        for region in device.bar_windows.regions() {
+            changes.push_back(RegionDelta::Remove(region));
+        }
+        drop(changes);
+        Ok(device)
+    }
+
+    /// The hot-plug events that have not yet been acknowledged by the guest
+    pub fn pending_hotplug_events(&self) -> Vec<PciHotplugEvent> {
+        self.hotplug.borrow().pending().to_vec()
+    }
+
+    fn set_current_address(&mut self, addr: u32) {
+        self.current_address = addr & Self::CONFIG_ADDRESS_MASK;
+        self.current_target = PciBdf::from_config_address(self.current_address);
+    }
+
+    // Whether CONFIG_DATA accesses reach configuration space (the enable
+    // bit of CONFIG_ADDRESS is set)
+    fn config_enabled(&self) -> bool {
+        self.current_address & PciBdf::CONFIG_ADDRESS_ENABLE != 0
+    }
+
+    // Decode a mechanism #2 configuration window access. Returns None if
+    // configuration space is not enabled in the CSE register.
+    fn window_target(&self, port: Port) -> Option<(PciBdf, u8, u8)> {
+        // The upper nibble of the CSE is a key that must be non-zero
+        if self.cse & 0xf0 == 0 {
+            return None;
+        }
+        let bdf = PciBdf::new(
+            self.forward,
+            ((port >> 8) & 0xf) as u8,
+            (self.cse >> 1) & 0b111,
+        );
+        Some((bdf, ((port & 0xfc) >> 2) as u8, (port & 0b11) as u8))
+    }
+
+    // Decode an ECAM window access into the BDF, register and byte offset
+    fn ecam_target(&self, addr: GuestPhysAddr) -> Option<(PciBdf, u16, u8)> {
+        let base = self.ecam_base?.as_u64();
+        let offset = addr.as_u64().checked_sub(base)?;
+        if offset >= Self::ECAM_SIZE {
+            return None;
+        }
+        let bdf = PciBdf::new(
+            (offset >> 20) as u8,
+            ((offset >> 15) & 0x1f) as u8,
+            ((offset >> 12) & 0b111) as u8,
+        );
+        Some((bdf, ((offset & 0xfff) >> 2) as u16, (offset & 0b11) as u8))
+    }
+
+    fn read_config(
+        &self,
+        bdf: PciBdf,
+        register: u16,
+        offset: u8,
+        val: &mut PortReadRequest,
+    ) {
+        match self.devices.get(&bdf.into()) {
+            Some(device) => {
+                let res = device.read_config_register(register) >> (offset * 8);
+                val.copy_from_u32(res);
+                info!(
+                    "bdf={:?}, register=0x{:x}, offset=0x{:x}, val={}",
+                    bdf, register, offset, val
+                );
+            }
+            None => {
+                if let Some(probe) = &self.absent_probe {
+                    probe(bdf);
+                }
+                val.copy_from_u32(self.absent_fill >> (offset * 8));
+            }
+        }
+    }
+
+    fn write_config(
+        &mut self,
+        bdf: PciBdf,
+        register: u16,
+        offset: u8,
+        val: PortWriteRequest,
+    ) {
+        // Writes to absent devices are dropped
+        if let Some(device) = self.devices.get_mut(&bdf.into()) {
+            let mut changes = self.region_changes.borrow_mut();
+            if let Some(delta) = device.write_config(register, offset, val) {
+                changes.push_back(delta);
+            }
+            while let Some(delta) = device.bar_windows.region_changed() {
+                changes.push_back(delta);
+            }
+        }
+    }
+
+    // Forward an access to the handler of the BAR that `decode` finds it
+    // in, or return `None` if no BAR decodes the access
+    fn bar_access(
+        &mut self,
+        decode: impl Fn(&PciBarWindows) -> Option<(u8, u64)>,
+        access: impl FnOnce(&mut dyn PciBarHandler, u8, u64) -> Result<()>,
+    ) -> Option<Result<()>> {
+        let device = self
+            .devices
+            .values_mut()
+            .find(|device| decode(&device.bar_windows).is_some())?;
+        let (index, offset) = decode(&device.bar_windows)?;
+        match device.bar_handler.as_mut() {
+            Some(handler) => Some(access(&mut **handler, index, offset)),
+            None => {
+                info!(
+                    "Ignoring access to BAR {} of {:?} without a handler",
+                    index, device.bdf
+                );
+                Some(Ok(()))
+            }
+        }
+    }
+
+    // The option ROM decoded at `addr`
+    fn option_rom_at(&mut self, addr: GuestPhysAddr) -> Result<&mut RomDevice> {
+        self.devices
+            .values_mut()
+            .find_map(|device| device.option_rom_at(addr))
+            .ok_or_else(|| {
+                Error::MissingDevice(format!("No option ROM at {:?}", addr))
+            })
+    }
+
+    fn on_mechanism2_read(
+        &mut self,
+        port: Port,
+        mut val: PortReadRequest,
+    ) -> Result<()> {
+        match port {
+            Self::PCI_CSE => val.copy_from_u32(self.cse as u32),
+            Self::PCI_FORWARD => val.copy_from_u32(self.forward as u32),
+            Self::PCI_CONFIG_WINDOW..=Self::PCI_CONFIG_WINDOW_MAX => {
+                match self.window_target(port) {
+                    Some((bdf, register, offset)) => {
+                        self.read_config(bdf, register.into(), offset, &mut val)
+                    }
+                    None => val.copy_from_u32(0xffffffff),
+                }
+            }
+            _ => {
+                return Err(Error::InvalidValue(format!(
+                    "Invalid PCI port read 0x{:x}",
+                    port
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    fn on_mechanism2_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+    ) -> Result<()> {
+        match port {
+            Self::PCI_CSE => self.cse = val.try_into()?,
+            Self::PCI_FORWARD => self.forward = val.try_into()?,
+            Self::PCI_CONFIG_WINDOW..=Self::PCI_CONFIG_WINDOW_MAX
+                if self.window_target(port).is_some() =>
+            {
+                let (bdf, register, offset) = self.window_target(port).unwrap();
+                self.write_config(bdf, register.into(), offset, val);
+            }
+            _ => {
+                info!(
+                    "Attempt to write to port=0x{:x} (cse=0x{:x}). Ignoring.",
+                    port, self.cse
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl EmulatedDevice for PciRootComplex {
+    fn services(&self) -> Vec<DeviceRegion> {
+        let mut regions = if self.mechanism == PciConfigMechanism::Mechanism2 {
+            vec![
+                DeviceRegion::PortIo(
+                    Self::PCI_CONFIG_WINDOW..=Self::PCI_CONFIG_WINDOW_MAX,
+                ),
+                DeviceRegion::PortIo(Self::PCI_CSE..=Self::PCI_CSE),
+                DeviceRegion::PortIo(Self::PCI_FORWARD..=Self::PCI_FORWARD),
+            ]
+        } else {
+            vec![
+                DeviceRegion::PortIo(
+                    Self::PCI_CONFIG_ADDRESS..=Self::PCI_CONFIG_ADDRESS,
+                ),
+                DeviceRegion::PortIo(
+                    Self::PCI_CONFIG_DATA..=Self::PCI_CONFIG_DATA_MAX,
+                ),
+            ]
+        };
+
+        if let Some(base) = self.ecam_base {
+            regions.push(DeviceRegion::MemIo(
+                base..=base + (Self::ECAM_SIZE - 1) as usize,
+            ));
+        }
+
+        // The enabled option ROMs and the decoded BARs
+        regions.extend(
+            self.devices
+                .values()
+                .filter_map(|device| device.option_rom_window())
+                .map(DeviceRegion::MemIo),
+        );
+        regions.extend(
+            self.devices
+                .values()
+                .flat_map(|device| device.bar_windows.regions()),
+        );
+        regions
+    }
+
+    fn region_changed(&self) -> Option<RegionDelta> {
+        self.region_changes.borrow_mut().pop_front()
+    }
+
+    fn reset(&mut self) {
+        self.current_address = 0;
+        self.current_target = PciBdf::from_config_address(0);
+        self.cse = 0;
+        self.forward = 0;
+        let mut changes = self.region_changes.borrow_mut();
+        for device in self.devices.values_mut() {
+            if let Some(delta) = device.reset() {
+                changes.push_back(delta);
+            }
+            while let Some(delta) = device.bar_windows.region_changed() {
+                changes.push_back(delta);
+            }
+        }
+    }
+
+    fn on_mem_read(
+        &mut self,
+        addr: GuestPhysAddr,
+        mut data: MemReadRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        if let Some((bdf, register, offset)) = self.ecam_target(addr) {
+            let mut val = OwnedPortRead::new(match data.as_slice().len() {
+                1 => PortWidth::Byte,
+                2 => PortWidth::Word,
+                4 => PortWidth::DWord,
+                len => {
+                    return Err(Error::AccessWidth {
+                        expected: &[1, 2, 4],
+                        actual: len,
+                    })
+                }
+            });
+            self.read_config(bdf, register, offset, &mut val.as_request());
+            return data.fill_from_slice(val.as_slice());
+        }
+        if let Some(res) = self.bar_access(
+            |bars| bars.decode(addr),
+            |handler, index, offset| {
+                handler.on_bar_read(index, offset, data.as_mut_slice())
+            },
+        ) {
+            return res;
+        }
+        self.option_rom_at(addr)?.on_mem_read(addr, data, space)
+    }
+
+    fn on_mem_write(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemWriteRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        if let Some((bdf, register, offset)) = self.ecam_target(addr) {
+            let val = PortWriteRequest::try_from(data.as_slice())?;
+            self.write_config(bdf, register, offset, val);
+            return Ok(());
+        }
+        if let Some(res) = self.bar_access(
+            |bars| bars.decode(addr),
+            |handler, index, offset| {
+                handler.on_bar_write(index, offset, data.as_slice())
+            },
+        ) {
+            return res;
+        }
+        self.option_rom_at(addr)?.on_mem_write(addr, data, space)
+    }
+    fn on_port_read(
+        &mut self,
+        port: Port,
+        mut val: PortReadRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        if let Some(res) = self.bar_access(
+            |bars| bars.decode_port(port),
+            |handler, index, offset| {
+                handler.on_bar_read(index, offset, val.as_mut_slice())
+            },
+        ) {
+            return res;
+        }
+        if self.mechanism == PciConfigMechanism::Mechanism2 {
+            return self.on_mechanism2_read(port, val);
+        }
+
+        match port {
+            Self::PCI_CONFIG_ADDRESS => {
+                val.copy_from_u32(self.current_address);
+            }
+            Self::PCI_CONFIG_DATA..=Self::PCI_CONFIG_DATA_MAX => {
+                if !self.config_enabled() {
+                    val.copy_from_u32(0xffffffff);
+                    return Ok(());
+                }
+                let (bdf, register) = self.current_target;
+                let offset = (port - Self::PCI_CONFIG_DATA) as u8;
+                self.read_config(bdf, register.into(), offset, &mut val);
+            }
+            _ => {
+                return Err(Error::InvalidValue(format!(
+                    "Invalid PCI port read 0x{:x}",
+                    port
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    fn on_port_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        if let Some(res) = self.bar_access(
+            |bars| bars.decode_port(port),
+            |handler, index, offset| {
+                handler.on_bar_write(index, offset, val.as_slice())
+            },
+        ) {
+            return res;
+        }
+        if self.mechanism == PciConfigMechanism::Mechanism2 {
+            return self.on_mechanism2_write(port, val);
+        }
+
+        match port {
+            Self::PCI_CONFIG_ADDRESS => {
+                let addr: u32 = val.try_into()?;
+                self.set_current_address(addr);
+            }
+            // Writes while configuration space is disabled are dropped
+            Self::PCI_CONFIG_DATA..=Self::PCI_CONFIG_DATA_MAX
+                if self.config_enabled() =>
+            {
+                let (bdf, register) = self.current_target;
+                let offset = (port - Self::PCI_CONFIG_DATA) as u8;
+                self.write_config(bdf, register.into(), offset, val);
+            }
+            _ => {
+                info!(
+                    "Attempt to write to port=0x{:x} (addr=0x{:x}). Ignoring.",
+                    port, self.current_address
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::define_test_view;
+    use crate::memory::GuestPhysAddr;
+
+    fn complex_ready_for_reg_read(reg: u8) -> Box<PciRootComplex> {
+        let view = define_test_view();
+        let mut complex = PciRootComplex::new();
+        let addr = PciBdf::new(0, 0, 0).to_config_address(reg).to_be_bytes();
+        let request = PortWriteRequest::try_from(&addr[..]).unwrap();
+        complex
+            .on_port_write(PciRootComplex::PCI_CONFIG_ADDRESS, request, view)
+            .unwrap();
+        complex
+    }
+
+    #[test]
+    fn test_full_register_read() {
+        let view = define_test_view();
+        let mut complex = complex_ready_for_reg_read(0);
+        let mut buff = [0u8; 4];
+        let val = PortReadRequest::FourBytes(&mut buff);
+        complex
+            .on_port_read(PciRootComplex::PCI_CONFIG_DATA, val, view)
+            .unwrap();
+
+        assert_eq!(u32::from_be_bytes(buff), 0x29c08086);
+    }
+
+    #[test]
+    fn test_half_register_read() {
+        let view = define_test_view();
+        let mut complex = complex_ready_for_reg_read(0);
+        let mut buff = [0u8; 2];
+        let val = PortReadRequest::TwoBytes(&mut buff);
+
+        complex
+            .on_port_read(PciRootComplex::PCI_CONFIG_DATA, val, view)
+            .unwrap();
+        assert_eq!(u16::from_be_bytes(buff), 0x8086);
+
+        let view = define_test_view();
+        let val = PortReadRequest::TwoBytes(&mut buff);
+        complex
+            .on_port_read(PciRootComplex::PCI_CONFIG_DATA + 2, val, view)
+            .unwrap();
+        assert_eq!(u16::from_be_bytes(buff), 0x29c0);
+    }
+
+    #[test]
+    fn test_register_byte_read() {
+        let mut complex = complex_ready_for_reg_read(0);
+        let mut buff = [0u8; 1];
+
+        let view = define_test_view();
+        let val = PortReadRequest::OneByte(&mut buff);
+
+        complex
+            .on_port_read(PciRootComplex::PCI_CONFIG_DATA, val, view)
+            .unwrap();
+        assert_eq!(u8::from_be_bytes(buff), 0x86);
+
+        let view = define_test_view();
+        let val = PortReadRequest::OneByte(&mut buff);
+        complex
+            .on_port_read(PciRootComplex::PCI_CONFIG_DATA + 1, val, view)
+            .unwrap();
+        assert_eq!(u8::from_be_bytes(buff), 0x80);
+
+        let view = define_test_view();
+        let val = PortReadRequest::OneByte(&mut buff);
+        complex
+            .on_port_read(PciRootComplex::PCI_CONFIG_DATA + 2, val, view)
+            .unwrap();
+        assert_eq!(u8::from_be_bytes(buff), 0xc0);
+
+        let view = define_test_view();
+        let val = PortReadRequest::OneByte(&mut buff);
+        complex
+            .on_port_read(PciRootComplex::PCI_CONFIG_DATA + 3, val, view)
+            .unwrap();
+        assert_eq!(u8::from_be_bytes(buff), 0x29);
+    }
+
+    #[test]
+    fn test_config_address_encoding() {
+        let bdf = PciBdf::new(0x12, 0x3, 0x5);
+        assert_eq!(bdf.to_config_address(0x0f), 0x8012_1d3c);
+    }
+
+    #[test]
+    fn test_config_address_roundtrip() {
+        let bdfs = [
+            PciBdf::new(0, 0, 0),
+            PciBdf::new(0, 1, 0),
+            PciBdf::new(0, 31, 7),
+            PciBdf::new(0xff, 0, 7),
+            PciBdf::new(0x80, 0x10, 0x3),
+        ];
+        for bdf in bdfs.iter() {
+            for register in [0u8, 1, 0x0f, 0x10, 0x3f].iter() {
+                let addr = bdf.to_config_address(*register);
+                assert_eq!(addr & 0x80000000, 0x80000000);
+                assert_eq!(addr & 0b11, 0);
+                assert_eq!(
+                    PciBdf::from_config_address(addr),
+                    (*bdf, *register)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_config_address_ignores_low_bits() {
+        let (bdf, register) = PciBdf::from_config_address(0x8000_0813);
+        assert_eq!(bdf, PciBdf::new(0, 1, 0));
+        assert_eq!(register, 0x04);
+    }
+
+    #[test]
+    fn test_bdf_masks_out_of_range_fields() {
+        assert_eq!(PciBdf::new(0, 0x21, 0x9), PciBdf::new(0, 1, 1));
+    }
+
+    #[test]
+    fn test_bdf_le_bytes_roundtrip() {
+        let bdf = PciBdf::new(0x12, 0x3, 0x5);
+        assert_eq!(bdf.to_le_bytes(), [0x1d, 0x12]);
+        assert_eq!(PciBdf::from_le_bytes(bdf.to_le_bytes()), bdf);
+    }
+
+    fn write_config_address(complex: &mut PciRootComplex, addr: u32) {
+        let arr = addr.to_be_bytes();
+        let request = PortWriteRequest::try_from(&arr[..]).unwrap();
+        complex
+            .on_port_write(
+                PciRootComplex::PCI_CONFIG_ADDRESS,
+                request,
+                define_test_view(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_cached_config_address_decode() {
+        let mut complex = PciRootComplex::new();
+        let addrs = [
+            PciBdf::new(0, 1, 0).to_config_address(0),
+            PciBdf::new(3, 0x1f, 7).to_config_address(0x3f),
+            0x80001234,
+            0x00ffff00,
+            PciBdf::new(0, 1, 0).to_config_address(0),
+        ];
+        for addr in addrs.iter() {
+            write_config_address(&mut complex, *addr);
+            let (bdf, reg) = complex.current_target;
+            let (expected_bdf, expected_reg) =
+                PciBdf::from_config_address(complex.current_address);
+            assert_eq!(bdf, expected_bdf);
+            assert_eq!(reg, expected_reg);
+        }
+    }
+
+    #[test]
+    fn test_config_address_enable_bit() {
+        let mut complex = PciRootComplex::new();
+        let bdf = PciBdf::new(0, 1, 0);
+        let read_port = |complex: &mut PciRootComplex, port: Port| {
+            let mut buff = [0u8; 4];
+            complex
+                .on_port_read(
+                    port,
+                    PortReadRequest::FourBytes(&mut buff),
+                    define_test_view(),
+                )
+                .unwrap();
+            u32::from_be_bytes(buff)
+        };
+
+        // The address reads back exactly as written (less the reserved
+        // and low bits), including a clear enable bit
+        let disabled = bdf.to_config_address(0x3c / 4) & !(1 << 31);
+        write_config_address(&mut complex, disabled | 0b11);
+        assert_eq!(
+            read_port(&mut complex, PciRootComplex::PCI_CONFIG_ADDRESS),
+            disabled
+        );
+
+        // CONFIG_DATA reads all ones, and writes are dropped
+        assert_eq!(
+            read_port(&mut complex, PciRootComplex::PCI_CONFIG_DATA),
+            0xffffffff
+        );
+        let data = 0x0bu32.to_be_bytes();
+        complex
+            .on_port_write(
+                PciRootComplex::PCI_CONFIG_DATA,
+                PortWriteRequest::try_from(&data[..]).unwrap(),
+                define_test_view(),
+            )
+            .unwrap();
+
+        write_config_address(&mut complex, bdf.to_config_address(0x3c / 4));
+        assert_eq!(
+            read_port(&mut complex, PciRootComplex::PCI_CONFIG_ADDRESS),
+            bdf.to_config_address(0x3c / 4)
+        );
+        assert_eq!(read_port(&mut complex, PciRootComplex::PCI_CONFIG_DATA), 0);
+    }
+
+    #[test]
+    fn test_repeated_config_data_reads() {
+        let mut complex = PciRootComplex::new();
+        write_config_address(
+            &mut complex,
+            PciBdf::new(0, 1, 0).to_config_address(0),
+        );
+        for _ in 0..10000 {
+            let mut buff = [0u8; 4];
+            let val = PortReadRequest::FourBytes(&mut buff);
+            complex
+                .on_port_read(
+                    PciRootComplex::PCI_CONFIG_DATA,
+                    val,
+                    define_test_view(),
+                )
+                .unwrap();
+            assert_eq!(u32::from_be_bytes(buff), 0x29188086);
+        }
+    }
+
+    fn read_config(complex: &mut PciRootComplex, bdf: PciBdf, reg: u8) -> u32 {
+        write_config_address(complex, bdf.to_config_address(reg));
+        let mut buff = [0u8; 4];
+        let val = PortReadRequest::FourBytes(&mut buff);
         complex
-            .on_port_read(PciRootComplex::PCI_CONFIG_DATA + 1, val, view)
+            .on_port_read(
+                PciRootComplex::PCI_CONFIG_DATA,
+                val,
+                define_test_view(),
+            )
             .unwrap();
-        assert_eq!(u8::from_be_bytes(buff), 0x80);
+        u32::from_be_bytes(buff)
+    }
 
-        let view = define_test_view();
-        let val = PortReadRequest::OneByte(&mut buff);
+    #[test]
+    fn test_absent_fill() {
+        let mut complex = PciRootComplex::new();
+        let absent = PciBdf::new(0, 5, 0);
+        assert_eq!(read_config(&mut complex, absent, 0), 0xffffffff);
+
+        complex.set_absent_fill(0x1234_5678);
+        assert_eq!(read_config(&mut complex, absent, 0), 0x1234_5678);
+        assert_eq!(read_config(&mut complex, absent, 0x10), 0x1234_5678);
+
+        // Present functions are unaffected
+        let ich9 = PciBdf::new(0, 1, 0);
+        assert_eq!(read_config(&mut complex, ich9, 0), 0x29188086);
+    }
+
+    #[test]
+    fn test_absent_probe() {
+        let probes = Rc::new(RefCell::new(vec![]));
+        let mut complex = PciRootComplex::new();
+        let seen = probes.clone();
+        complex.set_absent_probe(move |bdf| seen.borrow_mut().push(bdf));
+
+        read_config(&mut complex, PciBdf::new(0, 0, 0), 0);
+        read_config(&mut complex, PciBdf::new(0, 2, 0), 0);
+        read_config(&mut complex, PciBdf::new(1, 0x1f, 7), 0);
+        read_config(&mut complex, PciBdf::new(0, 1, 0), 0);
+        assert_eq!(
+            *probes.borrow(),
+            [PciBdf::new(0, 2, 0), PciBdf::new(1, 0x1f, 7)]
+        );
+    }
+
+    fn write_config_byte(
+        complex: &mut PciRootComplex,
+        bdf: PciBdf,
+        offset: u8,
+        val: u8,
+    ) {
+        write_config_address(complex, bdf.to_config_address(offset / 4));
+        let port = PciRootComplex::PCI_CONFIG_DATA + (offset % 4) as Port;
         complex
-            .on_port_read(PciRootComplex::PCI_CONFIG_DATA + 2, val, view)
+            .on_port_write(
+                port,
+                PortWriteRequest::OneByte(&[val]),
+                define_test_view(),
+            )
             .unwrap();
-        assert_eq!(u8::from_be_bytes(buff), 0xc0);
+    }
 
-        let view = define_test_view();
-        let val = PortReadRequest::OneByte(&mut buff);
+    #[test]
+    fn test_interrupt_line() {
+        let bdf = PciBdf::new(0, 3, 0);
+        let mut complex = PciRootComplex::new();
         complex
-            .on_port_read(PciRootComplex::PCI_CONFIG_DATA + 3, val, view)
+            .add_device(PciDevice::new(bdf, 0x8086, 0x100e))
             .unwrap();
-        assert_eq!(u8::from_be_bytes(buff), 0x29);
+
+        write_config_byte(&mut complex, bdf, 0x3c, 11);
+        assert_eq!(read_config(&mut complex, bdf, 0x3c / 4), 11);
+        assert_eq!(complex.device(bdf).unwrap().interrupt_line(), 11);
+
+        write_config_byte(&mut complex, bdf, 0x3c, 0xff);
+        assert_eq!(complex.device(bdf).unwrap().interrupt_line(), 0xff);
+    }
+
+    #[test]
+    fn test_interrupt_pin() {
+        let bdf = PciBdf::new(0, 3, 0);
+        let mut device = PciDevice::new(bdf, 0x8086, 0x100e);
+        assert!(device.set_interrupt_pin(5).is_err());
+        device.set_interrupt_pin(2).unwrap();
+        let mut complex = PciRootComplex::new();
+        complex.add_device(device).unwrap();
+
+        assert_eq!(read_config(&mut complex, bdf, 0x3c / 4), 0x0200);
+        assert_eq!(complex.device(bdf).unwrap().interrupt_pin(), 2);
+
+        // The pin is read-only
+        write_config_byte(&mut complex, bdf, 0x3d, 4);
+        assert_eq!(read_config(&mut complex, bdf, 0x3c / 4), 0x0200);
+        assert_eq!(complex.device(bdf).unwrap().interrupt_pin(), 2);
+
+        // As are the rest of the bytes in a dword write
+        write_config_address(&mut complex, bdf.to_config_address(0x3c / 4));
+        let data = 0xffff_ff0au32.to_be_bytes();
+        complex
+            .on_port_write(
+                PciRootComplex::PCI_CONFIG_DATA,
+                PortWriteRequest::FourBytes(&data),
+                define_test_view(),
+            )
+            .unwrap();
+        assert_eq!(read_config(&mut complex, bdf, 0x3c / 4), 0x020a);
+    }
+
+    #[test]
+    fn test_hotplug_add_device() {
+        let mut complex = PciRootComplex::new();
+        let bdf = PciBdf::new(0, 3, 0);
+        assert_eq!(read_config(&mut complex, bdf, 0), 0xffffffff);
+        assert!(complex.pending_hotplug_events().is_empty());
+
+        complex
+            .add_device(PciDevice::new(bdf, 0x1af4, 0x1000))
+            .unwrap();
+        assert_eq!(
+            complex.pending_hotplug_events(),
+            [PciHotplugEvent::Added(bdf)]
+        );
+        assert_eq!(read_config(&mut complex, bdf, 0), 0x10001af4);
+
+        // Reading the slot bitmap acknowledges the event
+        let hotplug = complex.hotplug();
+        assert!(hotplug.borrow().is_pending());
+        assert_eq!(hotplug.borrow_mut().take_added_slots(), 1 << 3);
+        assert!(complex.pending_hotplug_events().is_empty());
+    }
+
+    #[test]
+    fn test_hotplug_remove_device() {
+        let mut complex = PciRootComplex::new();
+        let bdf = PciBdf::new(0, 3, 0);
+        assert!(complex.remove_device(bdf).is_err());
+        complex
+            .add_device(PciDevice::new(bdf, 0x1af4, 0x1000))
+            .unwrap();
+        assert!(complex
+            .add_device(PciDevice::new(bdf, 0x1af4, 0x1000))
+            .is_err());
+
+        complex.remove_device(bdf).unwrap();
+        assert_eq!(read_config(&mut complex, bdf, 0), 0xffffffff);
+        let hotplug = complex.hotplug();
+        assert_eq!(hotplug.borrow_mut().take_removed_slots(), 1 << 3);
+        assert_eq!(hotplug.borrow_mut().take_added_slots(), 1 << 3);
+        assert!(!hotplug.borrow().is_pending());
+    }
+
+    #[test]
+    fn test_multi_function_and_bist() {
+        let mut complex = PciRootComplex::new();
+        let bdf = PciBdf::new(0, 3, 0);
+        let mut device = PciDevice::new(bdf, 0x1af4, 0x1000);
+        device.set_multi_function(true);
+        device.set_bist_capable(true);
+        complex.add_device(device).unwrap();
+
+        // Register 3 holds the header type (bits 23-16) and BIST (31-24)
+        let reg = read_config(&mut complex, bdf, 3);
+        assert_ne!(reg & (1 << 23), 0);
+        assert_eq!(reg >> 24, 0x80);
+
+        // The host bridge is a single function device without BIST
+        let reg = read_config(&mut complex, PciBdf::new(0, 0, 0), 3);
+        assert_eq!(reg & 0xffff_0000, 0);
+    }
+
+    #[test]
+    fn test_capability_list() {
+        let mut complex = PciRootComplex::new();
+        let bdf = PciBdf::new(0, 3, 0);
+        let mut device = PciDevice::new(bdf, 0x1af4, 0x1000);
+        assert_eq!(device.add_capability(0x05, &[0x80, 0x00]).unwrap(), 0x40);
+        assert_eq!(device.add_capability(0x09, &[0x0c, 0xaa]).unwrap(), 0x44);
+        complex.add_device(device).unwrap();
+
+        // The status register (bits 31-16 of register 1) reports the list
+        assert_ne!(read_config(&mut complex, bdf, 1) & (1 << 20), 0);
+        assert_eq!(read_config(&mut complex, bdf, 0x34 >> 2) & 0xff, 0x40);
+        assert_eq!(read_config(&mut complex, bdf, 0x40 >> 2), 0x0080_4405);
+        assert_eq!(read_config(&mut complex, bdf, 0x44 >> 2), 0xaa0c_0009);
+
+        // A device without capabilities does not
+        let other = PciBdf::new(0, 4, 0);
+        complex
+            .add_device(PciDevice::new(other, 0x1af4, 0x1000))
+            .unwrap();
+        assert_eq!(read_config(&mut complex, other, 1) & (1 << 20), 0);
+
+        let mut full = PciDevice::new(other, 0x1af4, 0x1000);
+        assert!(full.add_capability(0x09, &[0u8; 0xc0]).is_err());
+    }
+
+    #[test]
+    fn test_express_capability() {
+        let mut complex = PciRootComplex::new();
+        let bdf = PciBdf::new(0, 3, 0);
+        let mut device = PciDevice::new(bdf, 0x1af4, 0x1000);
+        device.add_capability(0x05, &[0x80, 0x00]).unwrap();
+        assert_eq!(device.express_device_type(), None);
+
+        let mut cap = PciExpressCap::new(PciExpressDeviceType::RootPort);
+        cap.link_width = 4;
+        let offset = device.add_express_capability(cap).unwrap();
+        assert_eq!(offset, 0x44);
+        assert_eq!(device.find_capability(PciExpressCap::ID), Some(0x44));
+        assert_eq!(
+            device.express_device_type(),
+            Some(PciExpressDeviceType::RootPort)
+        );
+        assert_eq!(device.config_size(), PciConfigSize::Extended);
+        assert!(device.add_express_capability(cap).is_err());
+        complex.add_device(device).unwrap();
+
+        // The guest finds the capability by walking the list
+        let first = read_config(&mut complex, bdf, 0x34 >> 2) & 0xff;
+        let next =
+            (read_config(&mut complex, bdf, first as u8 >> 2) >> 8) & 0xff;
+        assert_eq!(next, 0x44);
+        let header = read_config(&mut complex, bdf, 0x44 >> 2);
+        assert_eq!(header & 0xff, PciExpressCap::ID as u32);
+        assert_eq!(header >> 16, 0x0042);
+
+        // Link capabilities and status report a trained x4 link
+        assert_eq!(read_config(&mut complex, bdf, (0x44 + 0x0c) >> 2), 0x41);
+        assert_eq!(
+            read_config(&mut complex, bdf, (0x44 + 0x10) >> 2) >> 16,
+            0x41
+        );
+    }
+
+    const TEST_ECAM_BASE: u64 = 0xb000_0000;
+
+    fn ecam_addr(bdf: PciBdf, offset: u64) -> GuestPhysAddr {
+        let bdf: u16 = bdf.into();
+        GuestPhysAddr::new(TEST_ECAM_BASE + ((bdf as u64) << 12) + offset)
+    }
+
+    fn ecam_read(
+        complex: &mut PciRootComplex,
+        bdf: PciBdf,
+        offset: u64,
+    ) -> u32 {
+        let mut buff = [0u8; 4];
+        complex
+            .on_mem_read(
+                ecam_addr(bdf, offset),
+                MemReadRequest::new(&mut buff),
+                define_test_view(),
+            )
+            .unwrap();
+        u32::from_be_bytes(buff)
+    }
+
+    #[test]
+    fn test_extended_config_space() {
+        let mut complex = PciRootComplex::new();
+        assert!(complex
+            .set_ecam_base(GuestPhysAddr::new(TEST_ECAM_BASE + 0x1000))
+            .is_err());
+        complex
+            .set_ecam_base(GuestPhysAddr::new(TEST_ECAM_BASE))
+            .unwrap();
+
+        let express = PciBdf::new(0, 3, 0);
+        let mut device = PciDevice::new(express, 0x1af4, 0x1000);
+        device.set_config_size(PciConfigSize::Extended);
+        assert_eq!(device.config_size(), PciConfigSize::Extended);
+        device.write_reg(0x100, 0x1234_5678, !0);
+        complex.add_device(device).unwrap();
+
+        let legacy = PciBdf::new(0, 4, 0);
+        let mut device = PciDevice::new(legacy, 0x1af4, 0x1000);
+        device.write_reg(0x100, 0x1234_5678, !0);
+        assert_eq!(device.read_reg(0x100), 0);
+        complex.add_device(device).unwrap();
+
+        // ECAM reaches both the legacy and extended registers
+        assert_eq!(ecam_read(&mut complex, express, 0), 0x1000_1af4);
+        assert_eq!(ecam_read(&mut complex, express, 0x100), 0x1234_5678);
+        assert_eq!(ecam_read(&mut complex, legacy, 0x100), 0);
+        assert_eq!(ecam_read(&mut complex, legacy, 0xffc), 0);
+
+        // CONFIG_ADDRESS can only select registers in the first 256 bytes
+        for bdf in [express, legacy].iter() {
+            for reg in 0..64 {
+                assert_ne!(read_config(&mut complex, *bdf, reg), 0x1234_5678);
+            }
+        }
+
+        // Absent functions read as the absent fill through ECAM too
+        let absent = PciBdf::new(2, 0, 0);
+        assert_eq!(ecam_read(&mut complex, absent, 0x100), 0xffffffff);
+
+        let mut buff = [0u8; 8];
+        assert!(complex
+            .on_mem_read(
+                ecam_addr(express, 0x100),
+                MemReadRequest::new(&mut buff),
+                define_test_view(),
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_config_mechanism2() {
+        let mut complex = PciRootComplex::with_config_mechanism(
+            PciConfigMechanism::Mechanism2,
+        );
+        complex
+            .add_device(PciDevice::new(PciBdf::new(0, 3, 2), 0x1af4, 0x1000))
+            .unwrap();
+
+        let read_window = |complex: &mut PciRootComplex, port: Port| {
+            let mut buff = [0u8; 4];
+            complex
+                .on_port_read(
+                    port,
+                    PortReadRequest::FourBytes(&mut buff),
+                    define_test_view(),
+                )
+                .unwrap();
+            u32::from_be_bytes(buff)
+        };
+        let write_byte = |complex: &mut PciRootComplex, port: Port, val: u8| {
+            complex
+                .on_port_write(
+                    port,
+                    PortWriteRequest::OneByte(&[val]),
+                    define_test_view(),
+                )
+                .unwrap();
+        };
+
+        // Configuration space is disabled until a key is written to the CSE
+        assert_eq!(read_window(&mut complex, 0xc300), 0xffffffff);
+
+        // Enable configuration space and select function 2 (on bus 0)
+        write_byte(&mut complex, PciRootComplex::PCI_CSE, 0xf4);
+        write_byte(&mut complex, PciRootComplex::PCI_FORWARD, 0);
+        assert_eq!(read_window(&mut complex, 0xc300), 0x10001af4);
+        assert_eq!(read_window(&mut complex, 0xc400), 0xffffffff);
+
+        // Function 0 of device 1 is the ICH9
+        write_byte(&mut complex, PciRootComplex::PCI_CSE, 0xf0);
+        assert_eq!(read_window(&mut complex, 0xc100), 0x29188086);
+
+        write_byte(&mut complex, PciRootComplex::PCI_CSE, 0x00);
+        assert_eq!(read_window(&mut complex, 0xc100), 0xffffffff);
+    }
+
+    #[test]
+    fn test_register_dump() {
+        let mut device = PciDevice::new(PciBdf::new(0, 3, 0), 0x8086, 0x100e);
+        device.write_reg(0x04, 0x0007, 0xffff);
+        device.write_reg(0x3d, 0x01, 0xff);
+        assert_eq!(device.read_reg(0x3c), 0x0100);
+        assert_eq!(device.read_reg(0x02), 0x100e);
+
+        let dump = format!("{}", device.dump());
+        assert!(dump.contains("ID (0x00) = 0x100e8086\n"));
+        assert!(dump.contains("STATUS/COMMAND (0x04) = 0x00000007\n"));
+        assert!(dump.contains("INTERRUPT (0x3c) = 0x00000100\n"));
+    }
+
+    #[test]
+    fn test_bar_decode() {
+        let mut bars = PciBarWindows::new();
+        assert_eq!(bars.decode(GuestPhysAddr::new(0xfebf_0000)), None);
+
+        bars.assign(0, GuestPhysAddr::new(0xfebf_0000), 0x1000)
+            .unwrap();
+        bars.assign(2, GuestPhysAddr::new(0xfebe_0000), 0x100)
+            .unwrap();
+        assert_eq!(bars.decode(GuestPhysAddr::new(0xfebf_0000)), Some((0, 0)));
+        assert_eq!(
+            bars.decode(GuestPhysAddr::new(0xfebf_0ffc)),
+            Some((0, 0xffc))
+        );
+        assert_eq!(
+            bars.decode(GuestPhysAddr::new(0xfebe_0010)),
+            Some((2, 0x10))
+        );
+        assert_eq!(bars.decode(GuestPhysAddr::new(0xfebf_1000)), None);
+        assert_eq!(bars.decode(GuestPhysAddr::new(0xfebe_0100)), None);
+
+        assert!(bars.assign(6, GuestPhysAddr::new(0), 0x1000).is_err());
+        assert!(bars.assign(1, GuestPhysAddr::new(0x1000), 0).is_err());
+        assert!(bars.assign(1, GuestPhysAddr::new(!0), 2).is_err());
+    }
+
+    #[test]
+    fn test_bar_reassignment() {
+        use crate::device::DeviceMap;
+
+        struct BarDevice {
+            bars: PciBarWindows,
+        }
+
+        impl EmulatedDevice for BarDevice {
+            fn services(&self) -> Vec<DeviceRegion> {
+                self.bars.regions()
+            }
+
+            fn region_changed(&self) -> Option<RegionDelta> {
+                self.bars.region_changed()
+            }
+        }
+
+        let mut bars = PciBarWindows::new();
+        bars.assign(0, GuestPhysAddr::new(0xe000_0000), 0x1000)
+            .unwrap();
+        assert!(matches!(bars.region_changed(), Some(RegionDelta::Add(_))));
+        assert_eq!(bars.region_changed(), None);
+
+        let device = Rc::new(RefCell::new(BarDevice { bars }));
+        let mut map = DeviceMap::default();
+        map.register_device(Box::new(device.clone())).unwrap();
+        assert!(map.device_for(GuestPhysAddr::new(0xe000_0800)).is_some());
+
+        // Moving the BAR moves the region serviced by the device
+        device
+            .borrow_mut()
+            .bars
+            .assign(0, GuestPhysAddr::new(0xe010_0000), 0x1000)
+            .unwrap();
+        map.apply_region_changes().unwrap();
+        assert!(map.device_for(GuestPhysAddr::new(0xe000_0800)).is_none());
+        assert!(map.device_for(GuestPhysAddr::new(0xe010_0800)).is_some());
+        assert_eq!(
+            device.borrow().bars.decode(GuestPhysAddr::new(0xe010_0800)),
+            Some((0, 0x800))
+        );
+
+        device.borrow_mut().bars.unassign(0).unwrap();
+        map.apply_region_changes().unwrap();
+        assert!(map.device_for(GuestPhysAddr::new(0xe010_0800)).is_none());
+    }
+
+    fn map_write_config(
+        map: &mut crate::device::DeviceMap,
+        bdf: PciBdf,
+        reg: u8,
+        val: u32,
+    ) {
+        let addr = bdf.to_config_address(reg).to_be_bytes();
+        let request = PortWriteRequest::try_from(&addr[..]).unwrap();
+        map.on_port_write(
+            PciRootComplex::PCI_CONFIG_ADDRESS,
+            request,
+            define_test_view(),
+        )
+        .unwrap();
+        let data = val.to_be_bytes();
+        let request = PortWriteRequest::try_from(&data[..]).unwrap();
+        map.on_port_write(
+            PciRootComplex::PCI_CONFIG_DATA,
+            request,
+            define_test_view(),
+        )
+        .unwrap();
+    }
+
+    fn map_read_config(
+        map: &mut crate::device::DeviceMap,
+        bdf: PciBdf,
+        reg: u8,
+    ) -> u32 {
+        let addr = bdf.to_config_address(reg).to_be_bytes();
+        let request = PortWriteRequest::try_from(&addr[..]).unwrap();
+        map.on_port_write(
+            PciRootComplex::PCI_CONFIG_ADDRESS,
+            request,
+            define_test_view(),
+        )
+        .unwrap();
+        let mut buff = [0u8; 4];
+        map.on_port_read(
+            PciRootComplex::PCI_CONFIG_DATA,
+            PortReadRequest::FourBytes(&mut buff),
+            define_test_view(),
+        )
+        .unwrap();
+        u32::from_be_bytes(buff)
+    }
+
+    #[test]
+    fn test_bar_alignment() {
+        let bdf = PciBdf::new(0, 3, 0);
+        let mut device = PciDevice::new(bdf, 0x8086, 0x100e);
+        let mmio = PciBar {
+            kind: PciBarKind::Memory32 { prefetchable: true },
+            size: 0x1000,
+        };
+        let io = PciBar {
+            kind: PciBarKind::Io,
+            size: 0x20,
+        };
+        let wide = PciBar {
+            kind: PciBarKind::Memory64 {
+                prefetchable: false,
+            },
+            size: 0x10_0000,
+        };
+        assert!(device
+            .set_bar(
+                0,
+                PciBar {
+                    size: 0x1800,
+                    ..mmio
+                }
+            )
+            .is_err());
+        assert!(device.set_bar(0, PciBar { size: 8, ..mmio }).is_err());
+        assert!(device.set_bar(1, PciBar { size: 0x200, ..io }).is_err());
+        assert!(device.set_bar(5, wide).is_err());
+        device.set_bar(0, mmio).unwrap();
+        device.set_bar(1, io).unwrap();
+        device.set_bar(2, wide).unwrap();
+        assert!(device.set_bar(3, mmio).is_err());
+
+        let mut complex = PciRootComplex::new();
+        complex.add_device(device).unwrap();
+        let mut map = crate::device::DeviceMap::default();
+        map.register_device(complex).unwrap();
+
+        // An unaligned base is aligned down, keeping the type bits
+        assert_eq!(map_read_config(&mut map, bdf, 0x10 / 4), 0x8);
+        map_write_config(&mut map, bdf, 0x10 / 4, 0xfebf_1234);
+        assert_eq!(map_read_config(&mut map, bdf, 0x10 / 4), 0xfebf_1008);
+
+        // Writing all ones reads back the size mask
+        map_write_config(&mut map, bdf, 0x10 / 4, 0xffff_ffff);
+        assert_eq!(map_read_config(&mut map, bdf, 0x10 / 4), 0xffff_f008);
+
+        map_write_config(&mut map, bdf, 0x14 / 4, 0xc0ff);
+        assert_eq!(map_read_config(&mut map, bdf, 0x14 / 4), 0xc0e1);
+
+        // The upper half of a 64-bit BAR is fully writable
+        map_write_config(&mut map, bdf, 0x18 / 4, 0xffff_ffff);
+        map_write_config(&mut map, bdf, 0x1c / 4, 0xffff_ffff);
+        assert_eq!(map_read_config(&mut map, bdf, 0x18 / 4), 0xfff0_0004);
+        assert_eq!(map_read_config(&mut map, bdf, 0x1c / 4), 0xffff_ffff);
+        map_write_config(&mut map, bdf, 0x18 / 4, 0xe012_3456);
+        map_write_config(&mut map, bdf, 0x1c / 4, 0x1);
+        assert_eq!(map_read_config(&mut map, bdf, 0x18 / 4), 0xe010_0004);
+
+        // Undeclared BARs are hardwired to 0
+        map_write_config(&mut map, bdf, 0x24 / 4, 0xffff_ffff);
+        assert_eq!(map_read_config(&mut map, bdf, 0x24 / 4), 0);
+    }
+
+    // Records the BAR accesses, reading 0xab
+    struct RecordingBarHandler(Rc<RefCell<Vec<(u8, u64, bool)>>>);
+
+    impl PciBarHandler for RecordingBarHandler {
+        fn on_bar_read(
+            &mut self,
+            index: u8,
+            offset: u64,
+            data: &mut [u8],
+        ) -> Result<()> {
+            self.0.borrow_mut().push((index, offset, false));
+            for byte in data.iter_mut() {
+                *byte = 0xab;
+            }
+            Ok(())
+        }
+
+        fn on_bar_write(
+            &mut self,
+            index: u8,
+            offset: u64,
+            _data: &[u8],
+        ) -> Result<()> {
+            self.0.borrow_mut().push((index, offset, true));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_bar_windows_follow_config() {
+        let bdf = PciBdf::new(0, 3, 0);
+        let mut device = TestPciDevice::new(bdf, 0x8086, 0x100e)
+            .bar(
+                0,
+                PciBar {
+                    kind: PciBarKind::Memory32 {
+                        prefetchable: false,
+                    },
+                    size: 0x1000,
+                },
+            )
+            .bar(
+                1,
+                PciBar {
+                    kind: PciBarKind::Io,
+                    size: 0x20,
+                },
+            )
+            .build()
+            .unwrap();
+        let accesses = Rc::new(RefCell::new(vec![]));
+        device.set_bar_handler(Box::new(RecordingBarHandler(accesses.clone())));
+
+        let mut complex = PciRootComplex::new();
+        complex.add_device(device).unwrap();
+        let mut map = crate::device::DeviceMap::default();
+        map.register_device(complex).unwrap();
+
+        // The BARs are not decoded until enabled in the command register
+        map_write_config(&mut map, bdf, 0x10 / 4, 0xe000_0000);
+        map_write_config(&mut map, bdf, 0x14 / 4, 0xc100);
+        assert!(map.device_for(GuestPhysAddr::new(0xe000_0800)).is_none());
+        assert!(map.device_for(0xc110u16).is_none());
+
+        map_write_config(&mut map, bdf, 0x04 / 4, 0b11);
+        assert_eq!(map_read_config(&mut map, bdf, 0x04 / 4) & 0xffff, 0b11);
+        let mut data = [0u8; 4];
+        map.on_mem_read(
+            GuestPhysAddr::new(0xe000_0800),
+            MemReadRequest::new(&mut data),
+            define_test_view(),
+        )
+        .unwrap();
+        assert_eq!(data, [0xab; 4]);
+        map.on_port_write(
+            0xc110,
+            PortWriteRequest::OneByte(&[1]),
+            define_test_view(),
+        )
+        .unwrap();
+        assert_eq!(*accesses.borrow(), [(0, 0x800, false), (1, 0x10, true)]);
+
+        // Moving a BAR moves its region
+        map_write_config(&mut map, bdf, 0x10 / 4, 0xe010_0000);
+        assert!(map.device_for(GuestPhysAddr::new(0xe000_0800)).is_none());
+        assert!(map.device_for(GuestPhysAddr::new(0xe010_0800)).is_some());
+
+        // Disabling memory decode removes only the memory BAR
+        map_write_config(&mut map, bdf, 0x04 / 4, 0b01);
+        assert!(map.device_for(GuestPhysAddr::new(0xe010_0800)).is_none());
+        assert!(map.device_for(0xc110u16).is_some());
+
+        // As does removing the device
+        map.device_for_mut(0xcf8u16)
+            .and_then(|dev| dev.as_any_mut().downcast_mut::<PciRootComplex>())
+            .unwrap()
+            .remove_device(bdf)
+            .unwrap();
+        map.apply_region_changes().unwrap();
+        assert!(map.device_for(0xc110u16).is_none());
+    }
+
+    #[test]
+    fn test_enumerate_test_device() {
+        use crate::device::msi::MsiCapability;
+
+        let bdf = PciBdf::new(0, 5, 0);
+        let msi = MsiCapability::new(2, true, false);
+        let device = TestPciDevice::new(bdf, 0x1b36, 0x0005)
+            .class(0x00, 0xff, 0x00)
+            .bar(
+                0,
+                PciBar {
+                    kind: PciBarKind::Io,
+                    size: 0x40,
+                },
+            )
+            .bar(
+                2,
+                PciBar {
+                    kind: PciBarKind::Memory64 { prefetchable: true },
+                    size: 0x4000_0000,
+                },
+            )
+            .capability(MsiCapability::CAP_ID, &msi.body())
+            .interrupt_pin(1)
+            .build()
+            .unwrap();
+
+        let mut complex = PciRootComplex::new();
+        complex.add_device(device).unwrap();
+        let mut map = crate::device::DeviceMap::default();
+        map.register_device(complex).unwrap();
+
+        assert_eq!(map_read_config(&mut map, bdf, 0), 0x0005_1b36);
+        assert_eq!(map_read_config(&mut map, bdf, 2) >> 8, 0x00_ff_00);
+
+        // Size the 64-bit BAR the way firmware does
+        map_write_config(&mut map, bdf, 0x18 / 4, 0xffff_ffff);
+        map_write_config(&mut map, bdf, 0x1c / 4, 0xffff_ffff);
+        let low = map_read_config(&mut map, bdf, 0x18 / 4);
+        let high = map_read_config(&mut map, bdf, 0x1c / 4);
+        assert_eq!(low & 0b1111, 0b1100);
+        let mask = (high as u64) << 32 | (low & !0b1111) as u64;
+        assert_eq!(!mask + 1, 0x4000_0000);
+        map_write_config(&mut map, bdf, 0x14 / 4, 0xffff_ffff);
+        assert_eq!(map_read_config(&mut map, bdf, 0x14 / 4), 0);
+
+        map_write_config(&mut map, bdf, 0x10 / 4, 0xffff_ffff);
+        assert_eq!(map_read_config(&mut map, bdf, 0x10 / 4), 0xffff_ffc1);
+
+        // Walk the capability list to the MSI capability
+        assert_ne!(map_read_config(&mut map, bdf, 1) & (1 << 20), 0);
+        let cap = map_read_config(&mut map, bdf, 0x34 / 4) as u8;
+        let header = map_read_config(&mut map, bdf, cap / 4);
+        assert_eq!(header & 0xffff, MsiCapability::CAP_ID as u32);
+        assert_eq!(header >> 16, msi.read_register(0) >> 16);
+
+        assert_eq!((map_read_config(&mut map, bdf, 0x3c / 4) >> 8) & 0xff, 1);
+
+        assert!(TestPciDevice::new(bdf, 0x1b36, 0x0005)
+            .interrupt_pin(5)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_msi_capability_config_access() {
+        use crate::device::interrupt::{PendingInterrupts, VcpuApic};
+        use crate::ioapic::DeliveryMode;
+
+        let bdf = PciBdf::new(0, 6, 0);
+        let mut device = PciDevice::new(bdf, 0x1b36, 0x0005);
+        let cap = device
+            .add_msi_capability(MsiCapability::new(0, false, true))
+            .unwrap();
+        assert!(device
+            .add_msi_capability(MsiCapability::new(0, false, false))
+            .is_err());
+        let next = device.add_capability(0x09, &[0; 2]).unwrap();
+        assert_eq!(next, cap + 20);
+
+        let complex = Rc::new(RefCell::new(*PciRootComplex::new()));
+        complex.borrow_mut().add_device(device).unwrap();
+        let mut map = crate::device::DeviceMap::default();
+        map.register_device(Box::new(complex.clone())).unwrap();
+
+        // The capability list continues past the MSI capability
+        let header = map_read_config(&mut map, bdf, cap / 4);
+        assert_eq!(header & 0xff, MsiCapability::CAP_ID as u32);
+        assert_eq!((header >> 8) & 0xff, next as u32);
+
+        // Program and enable MSI the way the guest would
+        map_write_config(&mut map, bdf, cap / 4 + 1, 0xfee0_2000);
+        map_write_config(&mut map, bdf, cap / 4 + 2, 0x0071);
+        map_write_config(&mut map, bdf, cap / 4, header | 1 << 16);
+        assert_eq!(map_read_config(&mut map, bdf, cap / 4 + 1), 0xfee0_2000);
+
+        let sink = PendingInterrupts::new(&[
+            VcpuApic::new(0),
+            VcpuApic::new(1),
+            VcpuApic::new(2),
+        ]);
+        let mut complex = complex.borrow_mut();
+        let msi = complex.device_mut(bdf).unwrap().msi_mut().unwrap();
+        assert!(msi.is_enabled());
+        assert!(msi.fire(0, &sink));
+        assert_eq!(sink.pop(2), Some((DeliveryMode::Fixed, 0x71)));
+    }
+
+    #[test]
+    fn test_option_rom_sizing() {
+        let mut device = PciDevice::new(PciBdf::new(0, 3, 0), 0x8086, 0x100e);
+        assert!(device.set_option_rom(vec![0; 0x100], 0x400).is_err());
+        assert!(device.set_option_rom(vec![0; 0x100], 0x3000).is_err());
+        assert!(device.set_option_rom(vec![0; 0x2000], 0x1000).is_err());
+        device.set_option_rom(vec![0x55, 0xaa], 0x10000).unwrap();
+
+        let mut complex = PciRootComplex::new();
+        complex.add_device(device).unwrap();
+        let mut map = crate::device::DeviceMap::default();
+        map.register_device(complex).unwrap();
+
+        // Writing ones to the address bits reads back the size mask
+        let bdf = PciBdf::new(0, 3, 0);
+        map_write_config(&mut map, bdf, 0x30 / 4, 0xfffff800);
+        assert_eq!(map_read_config(&mut map, bdf, 0x30 / 4), 0xffff0000);
+
+        // A device without an option ROM hardwires the BAR to 0
+        let ich9 = PciBdf::new(0, 1, 0);
+        map_write_config(&mut map, ich9, 0x30 / 4, 0xfffff800);
+        assert_eq!(map_read_config(&mut map, ich9, 0x30 / 4), 0);
+    }
+
+    #[test]
+    fn test_option_rom_enable() {
+        let bdf = PciBdf::new(0, 3, 0);
+        let mut device = PciDevice::new(bdf, 0x8086, 0x100e);
+        device
+            .set_option_rom(vec![0x55, 0xaa, 0x08, 0xcb], 0x800)
+            .unwrap();
+        let mut complex = PciRootComplex::new();
+        complex.add_device(device).unwrap();
+        let mut map = crate::device::DeviceMap::default();
+        map.register_device(complex).unwrap();
+
+        let read_rom = |map: &mut crate::device::DeviceMap, addr: u64| {
+            let mut buff = [0u8; 4];
+            map.on_mem_read(
+                GuestPhysAddr::new(addr),
+                MemReadRequest::new(&mut buff),
+                define_test_view(),
+            )
+            .map(|_| buff)
+        };
+
+        // Assigning the address does not decode the ROM until enabled
+        map_write_config(&mut map, bdf, 0x30 / 4, 0xfebc_0000);
+        map.apply_region_changes().unwrap();
+        assert!(map.device_for(GuestPhysAddr::new(0xfebc_0000)).is_none());
+
+        map_write_config(&mut map, bdf, 0x30 / 4, 0xfebc_0001);
+        map.apply_region_changes().unwrap();
+        assert_eq!(map_read_config(&mut map, bdf, 0x30 / 4), 0xfebc_0001);
+        assert_eq!(
+            read_rom(&mut map, 0xfebc_0000).unwrap(),
+            [0x55, 0xaa, 0x08, 0xcb]
+        );
+        assert_eq!(read_rom(&mut map, 0xfebc_07fc).unwrap(), [0; 4]);
+        assert!(map.device_for(GuestPhysAddr::new(0xfebc_0800)).is_none());
+
+        // Moving the BAR moves the window
+        map_write_config(&mut map, bdf, 0x30 / 4, 0xfebd_0001);
+        map.apply_region_changes().unwrap();
+        assert!(map.device_for(GuestPhysAddr::new(0xfebc_0000)).is_none());
+        assert_eq!(
+            read_rom(&mut map, 0xfebd_0000).unwrap(),
+            [0x55, 0xaa, 0x08, 0xcb]
+        );
+
+        // Clearing the enable bit stops decoding but keeps the address
+        map_write_config(&mut map, bdf, 0x30 / 4, 0xfebd_0000);
+        map.apply_region_changes().unwrap();
+        assert!(map.device_for(GuestPhysAddr::new(0xfebd_0000)).is_none());
+        assert_eq!(map_read_config(&mut map, bdf, 0x30 / 4), 0xfebd_0000);
+    }
+
+    // Accesses built from the bytes a guest puts on the bus, which are
+    // little endian (unlike the big endian requests)
+    mod byte_order {
+        use super::*;
+        use crate::device::{OwnedPortRead, OwnedPortWrite, PortWidth};
+
+        fn guest_out(complex: &mut PciRootComplex, port: Port, bytes: &[u8]) {
+            let request = OwnedPortWrite::from_le_bytes(bytes).unwrap();
+            complex
+                .on_port_write(port, request.as_request(), define_test_view())
+                .unwrap();
+        }
+
+        fn guest_in(
+            complex: &mut PciRootComplex,
+            port: Port,
+            width: PortWidth,
+        ) -> Vec<u8> {
+            let mut read = OwnedPortRead::new(width);
+            complex
+                .on_port_read(port, read.as_request(), define_test_view())
+                .unwrap();
+            let len = read.as_slice().len();
+            read.as_u32().to_le_bytes()[..len].to_vec()
+        }
+
+        // CONFIG_ADDRESS in bus order: register, device/function, bus and
+        // then the enable bit
+        fn config_address_bytes(bdf: PciBdf, offset: u8) -> [u8; 4] {
+            [
+                offset & 0xfc,
+                bdf.device() << 3 | bdf.function(),
+                bdf.bus(),
+                0x80,
+            ]
+        }
+
+        #[test]
+        fn test_outl_matches_register_value() {
+            // `outl` of EAX=0x8012_1d3c is emulated as the big endian
+            // bytes of EAX, which must match the bus order bytes
+            let eax = 0x8012_1d3cu32;
+            let from_register = OwnedPortWrite::FourBytes(eax.to_be_bytes());
+            assert_eq!(
+                OwnedPortWrite::from_le_bytes(&[0x3c, 0x1d, 0x12, 0x80]),
+                Ok(from_register)
+            );
+            assert_eq!(from_register.as_request().as_u32(), eax);
+        }
+
+        #[test]
+        fn test_config_address_decode() {
+            let targets = [
+                (PciBdf::new(0, 0, 0), 0x00),
+                (PciBdf::new(0, 1, 0), 0x04),
+                (PciBdf::new(0, 0, 1), 0x3c),
+                (PciBdf::new(0, 0x1f, 7), 0xfc),
+                (PciBdf::new(1, 0, 0), 0x10),
+                (PciBdf::new(0x80, 0x10, 4), 0x40),
+                (PciBdf::new(0xff, 0x1f, 7), 0x80),
+            ];
+            let mut complex = PciRootComplex::new();
+            for (bdf, offset) in targets.iter() {
+                let bytes = config_address_bytes(*bdf, *offset);
+                assert_eq!(
+                    u32::from_le_bytes(bytes),
+                    bdf.to_config_address(offset >> 2)
+                );
+
+                guest_out(
+                    &mut complex,
+                    PciRootComplex::PCI_CONFIG_ADDRESS,
+                    &bytes,
+                );
+                assert_eq!(complex.current_target, (*bdf, offset >> 2));
+                assert_eq!(
+                    guest_in(
+                        &mut complex,
+                        PciRootComplex::PCI_CONFIG_ADDRESS,
+                        PortWidth::DWord
+                    ),
+                    bytes
+                );
+            }
+        }
+
+        #[test]
+        fn test_config_data_bus_order() {
+            let mut complex = PciRootComplex::new();
+            let bytes = config_address_bytes(PciBdf::new(0, 0, 0), 0);
+            guest_out(&mut complex, PciRootComplex::PCI_CONFIG_ADDRESS, &bytes);
+
+            // Vendor 0x8086, device 0x29c0
+            let expected = [0x86, 0x80, 0xc0, 0x29];
+            let data = PciRootComplex::PCI_CONFIG_DATA;
+            assert_eq!(
+                guest_in(&mut complex, data, PortWidth::DWord),
+                expected
+            );
+            for i in 0..2 {
+                assert_eq!(
+                    guest_in(&mut complex, data + i * 2, PortWidth::Word),
+                    &expected[i as usize * 2..][..2]
+                );
+            }
+            for i in 0..4 {
+                assert_eq!(
+                    guest_in(&mut complex, data + i, PortWidth::Byte),
+                    [expected[i as usize]]
+                );
+            }
+        }
+
+        #[test]
+        fn test_config_data_absent_device() {
+            let mut complex = PciRootComplex::new();
+            let bytes = config_address_bytes(PciBdf::new(2, 3, 0), 0);
+            guest_out(&mut complex, PciRootComplex::PCI_CONFIG_ADDRESS, &bytes);
+            assert_eq!(
+                guest_in(
+                    &mut complex,
+                    PciRootComplex::PCI_CONFIG_DATA + 2,
+                    PortWidth::Word
+                ),
+                [0xff, 0xff]
+            );
+        }
+
+        #[test]
+        fn test_invalid_le_width() {
+            assert_eq!(
+                OwnedPortWrite::from_le_bytes(&[1, 2, 3]),
+                Err(Error::AccessWidth {
+                    expected: &[1, 2, 4],
+                    actual: 3,
+                })
+            );
+        }
     }
 }
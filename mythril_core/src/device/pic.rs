@@ -1,21 +1,100 @@
+use crate::device::interrupt::{LevelIrqLines, PendingInterrupt};
 use crate::device::{
-    DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
+    require_len, DeviceKind, DeviceRegion, EmulatedDevice, Port,
+    PortReadRequest, PortWriteRequest,
 };
 use crate::error::Result;
 use crate::memory::GuestAddressSpaceViewMut;
 use alloc::boxed::Box;
+use alloc::rc::Rc;
 use alloc::vec::Vec;
 use core::convert::TryInto;
 
 #[derive(Default, Debug)]
 pub struct PicState {
     imr: u8,
+    irr: u8,
+    isr: u8,
+
+    // The vector of input 0 (set by ICW2)
+    vector_base: u8,
+
+    // The initialization command word expected next on the data port (2-4,
+    // or 0 when not initializing), and whether ICW4 will be sent
+    next_icw: u8,
+    icw4_needed: bool,
 }
 
-#[derive(Default, Debug)]
+impl PicState {
+    fn new(vector_base: u8) -> Self {
+        Self {
+            vector_base,
+            ..Self::default()
+        }
+    }
+
+    // Handle an ICW1, which starts the initialization sequence (ICW2 and,
+    // as the PICs are cascaded, ICW3 always follow)
+    fn start_init(&mut self, icw1: u8) {
+        self.imr = 0;
+        self.isr = 0;
+        self.next_icw = 2;
+        self.icw4_needed = icw1 & Pic8259::ICW1_IC4 != 0;
+    }
+
+    // Handle a write to the data port during initialization (only the
+    // vector base in ICW2 is used)
+    fn write_icw(&mut self, val: u8) {
+        self.next_icw = match self.next_icw {
+            2 => {
+                self.vector_base = val & !0b111;
+                3
+            }
+            3 if self.icw4_needed => 4,
+            _ => 0,
+        };
+    }
+
+    // The highest priority (lowest numbered) input that is requested,
+    // unmasked and not blocked by an input of equal or higher priority
+    // that is in service
+    fn pending(&self) -> Option<u8> {
+        let mut blocked = 0u8;
+        if self.isr != 0 {
+            blocked = !((1 << self.isr.trailing_zeros()) - 1);
+        }
+        let eligible = self.irr & !self.imr & !blocked;
+        if eligible == 0 {
+            None
+        } else {
+            Some(eligible.trailing_zeros() as u8)
+        }
+    }
+
+    // Handle an OCW2 EOI command, returning the input that is no longer
+    // in service
+    fn end_of_interrupt(&mut self, command: u8) -> Option<u8> {
+        let input = if command & Pic8259::OCW2_SPECIFIC != 0 {
+            command & 0b111
+        } else if self.isr != 0 {
+            self.isr.trailing_zeros() as u8
+        } else {
+            return None;
+        };
+        self.isr &= !(1 << input);
+        Some(input)
+    }
+}
+
+/// The master and slave 8259 programmable interrupt controllers
+///
+/// The PIC's registers are byte wide, and any wider access fails with
+/// `Error::AccessWidth`.
+#[derive(Debug)]
 pub struct Pic8259 {
     master_state: PicState,
     slave_state: PicState,
+    level_lines: Option<Rc<LevelIrqLines>>,
 }
 
 impl Pic8259 {
@@ -26,8 +105,149 @@ impl Pic8259 {
     const PIC_ECLR_COMMAND: Port = 0x4d0;
     const PIC_ECLR_DATA: Port = Self::PIC_ECLR_COMMAND + 1;
 
+    // The master input the slave is cascaded through
+    const CASCADE_IRQ: u8 = 2;
+
+    // The vectors used by the BIOS, until the guest initializes the PICs
+    const MASTER_VECTOR_BASE: u8 = 0x08;
+    const SLAVE_VECTOR_BASE: u8 = 0x70;
+
+    // ICW1 (written to a command port with bit 4 set)
+    const ICW1_INIT: u8 = 1 << 4;
+    const ICW1_IC4: u8 = 1 << 0;
+
+    // OCW2 (written to a command port with bits 3 and 4 clear)
+    const OCW_SELECT_MASK: u8 = 0b0001_1000;
+    const OCW2_EOI: u8 = 1 << 5;
+    const OCW2_SPECIFIC: u8 = 1 << 6;
+
     pub fn new() -> Box<Self> {
-        Box::new(Pic8259::default())
+        Box::new(Pic8259 {
+            master_state: PicState::new(Self::MASTER_VECTOR_BASE),
+            slave_state: PicState::new(Self::SLAVE_VECTOR_BASE),
+            level_lines: None,
+        })
+    }
+
+    /// Set the level triggered lines that are checked again when the guest
+    /// ends an interrupt on them
+    pub fn set_level_lines(&mut self, lines: Rc<LevelIrqLines>) {
+        self.level_lines = Some(lines);
+    }
+
+    /// Acknowledge the highest priority pending IRQ (the INTA cycle),
+    /// moving it from requested to in service
+    ///
+    /// Returns the IRQ line (0-15), or None if no IRQ is pending.
+    pub fn acknowledge(&mut self) -> Option<u8> {
+        let input = self.master_state.pending()?;
+        self.master_state.isr |= 1 << input;
+        if input != Self::CASCADE_IRQ {
+            self.master_state.irr &= !(1 << input);
+            return Some(input);
+        }
+
+        let slave_input = match self.slave_state.pending() {
+            Some(input) => input,
+            None => {
+                // Nothing left for the cascade input to deliver
+                self.master_state.isr &= !(1 << input);
+                self.master_state.irr &= !(1 << input);
+                return None;
+            }
+        };
+        self.slave_state.isr |= 1 << slave_input;
+        self.slave_state.irr &= !(1 << slave_input);
+        if self.slave_state.irr == 0 {
+            self.master_state.irr &= !(1 << Self::CASCADE_IRQ);
+        }
+        Some(slave_input + 8)
+    }
+
+    /// The vector delivered for the given IRQ line (0-15)
+    pub fn vector(&self, irq: u8) -> u8 {
+        if irq < 8 {
+            self.master_state.vector_base + irq
+        } else {
+            self.slave_state.vector_base + (irq - 8)
+        }
+    }
+
+    /// The IRQ lines that are in service, with the slave lines in bits 8-15
+    pub fn in_service_irqs(&self) -> u16 {
+        (self.slave_state.isr as u16) << 8 | self.master_state.isr as u16
+    }
+
+    // Handle a write to a command port (only ICW1 and OCW2 EOI commands
+    // are supported)
+    fn write_command(&mut self, slave: bool, command: u8) {
+        if command & Self::ICW1_INIT != 0 {
+            let state = if slave {
+                &mut self.slave_state
+            } else {
+                &mut self.master_state
+            };
+            state.start_init(command);
+            return;
+        }
+        if command & Self::OCW_SELECT_MASK != 0 || command & Self::OCW2_EOI == 0
+        {
+            info!(
+                "Write to PIC command port not yet supported (0x{:x})",
+                command
+            );
+            return;
+        }
+
+        let (state, first_irq) = if slave {
+            (&mut self.slave_state, 8)
+        } else {
+            (&mut self.master_state, 0)
+        };
+        let irq = match state.end_of_interrupt(command) {
+            Some(input) => first_irq + input,
+            None => return,
+        };
+
+        // A level triggered line that is still asserted interrupts again
+        let asserted = match &self.level_lines {
+            Some(lines) => lines.is_level(irq) && lines.asserted(irq),
+            None => false,
+        };
+        if asserted {
+            self.raise_irq(irq);
+        }
+    }
+
+    /// Assert the given IRQ line (0-15)
+    pub fn raise_irq(&mut self, irq: u8) {
+        match irq {
+            0..=7 => self.master_state.irr |= 1 << irq,
+            8..=15 => {
+                self.slave_state.irr |= 1 << (irq - 8);
+                self.master_state.irr |= 1 << Self::CASCADE_IRQ;
+            }
+            _ => warn!("Attempt to raise invalid PIC irq {}", irq),
+        }
+    }
+
+    /// Deassert the given IRQ line (0-15)
+    pub fn lower_irq(&mut self, irq: u8) {
+        match irq {
+            0..=7 => self.master_state.irr &= !(1 << irq),
+            8..=15 => {
+                self.slave_state.irr &= !(1 << (irq - 8));
+                if self.slave_state.irr == 0 {
+                    self.master_state.irr &= !(1 << Self::CASCADE_IRQ);
+                }
+            }
+            _ => warn!("Attempt to lower invalid PIC irq {}", irq),
+        }
+    }
+
+    /// The requested IRQ lines, with the slave lines in bits 8-15
+    pub fn requested_irqs(&self) -> u16 {
+        (self.slave_state.irr as u16) << 8 | self.master_state.irr as u16
     }
 }
 
@@ -44,15 +264,33 @@ impl EmulatedDevice for Pic8259 {
         ]
     }
 
+    fn kind(&self) -> Option<DeviceKind> {
+        Some(DeviceKind::Pic)
+    }
+
+    fn reset(&mut self) {
+        self.master_state = PicState::new(Self::MASTER_VECTOR_BASE);
+        self.slave_state = PicState::new(Self::SLAVE_VECTOR_BASE);
+    }
+
+    fn acknowledge_interrupt(&mut self) -> Option<PendingInterrupt> {
+        let irq = self.acknowledge()?;
+        Some(PendingInterrupt {
+            vector: self.vector(irq),
+            irq,
+        })
+    }
+
     fn on_port_read(
         &mut self,
         port: Port,
         mut val: PortReadRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
+        require_len(val.as_slice().len(), &[1])?;
         let data = match port {
             Self::PIC_MASTER_DATA => self.master_state.imr,
-            Self::PIC_SLAVE_DATA => self.master_state.imr,
+            Self::PIC_SLAVE_DATA => self.slave_state.imr,
             _ => {
                 info!("Read of PIC command port not yet supported");
                 return Ok(());
@@ -68,14 +306,27 @@ impl EmulatedDevice for Pic8259 {
         val: PortWriteRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
+        require_len(val.as_slice().len(), &[1])?;
         match port {
+            Self::PIC_MASTER_DATA if self.master_state.next_icw != 0 => {
+                self.master_state.write_icw(val.try_into()?)
+            }
+            Self::PIC_SLAVE_DATA if self.slave_state.next_icw != 0 => {
+                self.slave_state.write_icw(val.try_into()?)
+            }
             Self::PIC_MASTER_DATA => {
                 info!("Set master PIC data: {}", val);
                 self.master_state.imr = val.try_into()?;
             }
             Self::PIC_SLAVE_DATA => {
                 info!("Set slave PIC data: {}", val);
-                self.master_state.imr = val.try_into()?;
+                self.slave_state.imr = val.try_into()?;
+            }
+            Self::PIC_MASTER_COMMAND => {
+                self.write_command(false, val.try_into()?)
+            }
+            Self::PIC_SLAVE_COMMAND => {
+                self.write_command(true, val.try_into()?)
             }
             port => {
                 info!(
@@ -87,3 +338,139 @@ impl EmulatedDevice for Pic8259 {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::interrupt::IrqLineState;
+    use crate::device::test_util::define_test_view;
+    use core::cell::Cell;
+
+    // A device holding a level triggered line
+    #[derive(Default)]
+    struct MockLevelDevice {
+        asserted: Cell<bool>,
+    }
+
+    impl IrqLineState for MockLevelDevice {
+        fn line_asserted(&self, _irq: u8) -> bool {
+            self.asserted.get()
+        }
+    }
+
+    fn write_port(pic: &mut Pic8259, port: Port, val: u8) {
+        pic.on_port_write(
+            port,
+            PortWriteRequest::OneByte(&[val]),
+            define_test_view(),
+        )
+        .unwrap();
+    }
+
+    // Non-specific EOI to the slave and then the master
+    fn slave_eoi(pic: &mut Pic8259) {
+        write_port(pic, Pic8259::PIC_SLAVE_COMMAND, Pic8259::OCW2_EOI);
+        write_port(pic, Pic8259::PIC_MASTER_COMMAND, Pic8259::OCW2_EOI);
+    }
+
+    fn read_imr(pic: &mut Pic8259, port: Port) -> u8 {
+        let mut arr = [0u8];
+        pic.on_port_read(
+            port,
+            PortReadRequest::OneByte(&mut arr),
+            define_test_view(),
+        )
+        .unwrap();
+        arr[0]
+    }
+
+    #[test]
+    fn test_separate_masks() {
+        let mut pic = Pic8259::new();
+        write_port(&mut pic, Pic8259::PIC_MASTER_DATA, 0xfb);
+        write_port(&mut pic, Pic8259::PIC_SLAVE_DATA, 0xff);
+        assert_eq!(read_imr(&mut pic, Pic8259::PIC_MASTER_DATA), 0xfb);
+        assert_eq!(read_imr(&mut pic, Pic8259::PIC_SLAVE_DATA), 0xff);
+    }
+
+    #[test]
+    fn test_acknowledge_priority() {
+        let mut pic = Pic8259::new();
+        pic.raise_irq(4);
+        pic.raise_irq(1);
+        pic.raise_irq(9);
+        assert_eq!(pic.acknowledge(), Some(1));
+
+        // IRQ 1 blocks the lower priority IRQs until it ends
+        assert_eq!(pic.acknowledge(), None);
+        write_port(&mut pic, Pic8259::PIC_MASTER_COMMAND, Pic8259::OCW2_EOI);
+        assert_eq!(pic.acknowledge(), Some(9));
+        assert_eq!(pic.in_service_irqs(), 1 << 9 | 1 << 2);
+        slave_eoi(&mut pic);
+        assert_eq!(pic.acknowledge(), Some(4));
+
+        // Specific EOI of IRQ 4, then a masked IRQ is not delivered
+        write_port(
+            &mut pic,
+            Pic8259::PIC_MASTER_COMMAND,
+            Pic8259::OCW2_EOI | Pic8259::OCW2_SPECIFIC | 4,
+        );
+        assert_eq!(pic.in_service_irqs(), 0);
+        write_port(&mut pic, Pic8259::PIC_MASTER_DATA, 1 << 3);
+        pic.raise_irq(3);
+        assert_eq!(pic.acknowledge(), None);
+    }
+
+    #[test]
+    fn test_level_eoi_reraises() {
+        let lines = LevelIrqLines::new();
+        let dev = Rc::new(MockLevelDevice::default());
+        lines.attach(10, dev.clone());
+        let mut pic = Pic8259::new();
+        pic.set_level_lines(lines);
+
+        dev.asserted.set(true);
+        pic.raise_irq(10);
+        assert_eq!(pic.acknowledge(), Some(10));
+        assert_eq!(pic.requested_irqs(), 0);
+
+        // The line is still asserted, so the EOI raises it again
+        slave_eoi(&mut pic);
+        assert_eq!(pic.requested_irqs(), 1 << 10 | 1 << 2);
+        assert_eq!(pic.acknowledge(), Some(10));
+
+        // Once the device clears the line, the EOI has no effect
+        dev.asserted.set(false);
+        slave_eoi(&mut pic);
+        assert_eq!(pic.requested_irqs(), 0);
+        assert_eq!(pic.in_service_irqs(), 0);
+
+        // Edge triggered lines are never raised again
+        pic.raise_irq(4);
+        assert_eq!(pic.acknowledge(), Some(4));
+        write_port(&mut pic, Pic8259::PIC_MASTER_COMMAND, Pic8259::OCW2_EOI);
+        assert_eq!(pic.requested_irqs(), 0);
+    }
+
+    #[test]
+    fn test_wide_access_rejected() {
+        use crate::error::Error;
+
+        let mut pic = Pic8259::new();
+        write_port(&mut pic, Pic8259::PIC_MASTER_DATA, 0xfb);
+        let res = pic.on_port_write(
+            Pic8259::PIC_MASTER_DATA,
+            PortWriteRequest::TwoBytes(&[0xff, 0x00]),
+            define_test_view(),
+        );
+        assert!(matches!(res, Err(Error::AccessWidth { actual: 2, .. })));
+        assert_eq!(pic.master_state.imr, 0xfb);
+
+        let res = pic.on_port_read(
+            Pic8259::PIC_MASTER_DATA,
+            PortReadRequest::FourBytes(&mut [0u8; 4]),
+            define_test_view(),
+        );
+        assert!(matches!(res, Err(Error::AccessWidth { actual: 4, .. })));
+    }
+}
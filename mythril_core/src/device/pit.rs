@@ -1,11 +1,16 @@
+use crate::device::interrupt::IrqSink;
+use crate::device::register::{masked_write, RegisterBlock, RegisterInfo};
 use crate::device::{
-    DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
+    require_len, DeviceKind, DeviceRegion, EmulatedDevice, Port,
+    PortReadRequest, PortWriteRequest,
 };
 use crate::error::{Error, Result};
 use crate::memory::GuestAddressSpaceViewMut;
+use crate::time::{ClockSource, SystemClock};
 use alloc::boxed::Box;
+use alloc::rc::Rc;
 use alloc::vec::Vec;
-use core::convert::TryFrom;
+use core::convert::{TryFrom, TryInto};
 use derive_try_from_primitive::TryFromPrimitive;
 
 #[derive(Clone, Copy, Debug, TryFromPrimitive)]
@@ -64,8 +69,188 @@ pub enum BinaryMode {
     Bcd = 0b1,
 }
 
-#[derive(Default, Debug)]
-pub struct Pit8254;
+// The frequency of the PIT input clock
+const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+#[derive(Debug)]
+struct PitChannel {
+    mode: OperatingMode,
+    access: AccessMode,
+    reload: u16,
+
+    // The time (in nanoseconds) when the reload value was loaded
+    loaded_at: Option<u64>,
+
+    // The number of times the counter has expired that have already
+    // been reported
+    expirations: u64,
+
+    latch: Option<u16>,
+    write_high: bool,
+    read_high: bool,
+}
+
+impl Default for PitChannel {
+    fn default() -> Self {
+        Self {
+            mode: OperatingMode::Mode0,
+            access: AccessMode::Word,
+            reload: 0,
+            loaded_at: None,
+            expirations: 0,
+            latch: None,
+            write_high: false,
+            read_high: false,
+        }
+    }
+}
+
+impl PitChannel {
+    // A reload value of zero is treated as 65536
+    fn period(&self) -> u64 {
+        if self.reload == 0 {
+            0x10000
+        } else {
+            self.reload as u64
+        }
+    }
+
+    fn ticks(&self, now: u64) -> Option<u64> {
+        self.loaded_at.map(|loaded_at| {
+            (now.saturating_sub(loaded_at) as u128 * PIT_FREQUENCY_HZ as u128
+                / NS_PER_SEC as u128) as u64
+        })
+    }
+
+    fn is_periodic(&self) -> bool {
+        match self.mode {
+            OperatingMode::Mode2 | OperatingMode::Mode3 => true,
+            _ => false,
+        }
+    }
+
+    // The total number of times the counter has reached zero since it
+    // was loaded
+    fn total_expirations(&self, now: u64) -> u64 {
+        match self.ticks(now) {
+            Some(ticks) if self.is_periodic() => ticks / self.period(),
+            Some(ticks) if ticks >= self.period() => 1,
+            _ => 0,
+        }
+    }
+
+    fn count(&self, now: u64) -> u16 {
+        match self.ticks(now) {
+            Some(ticks) if self.is_periodic() => {
+                (self.period() - ticks % self.period()) as u16
+            }
+            Some(ticks) => self.period().wrapping_sub(ticks) as u16,
+            None => self.reload,
+        }
+    }
+
+    /// The level of this channel's OUT pin
+    ///
+    /// Modes 1 and 5 are started by a rising edge on the gate input, which
+    /// is not modeled, so their output stays high.
+    fn output(&self, now: u64) -> bool {
+        let ticks = match self.ticks(now) {
+            Some(ticks) => ticks,
+            // OUT goes low when mode 0 is programmed, and high otherwise
+            None => {
+                return match self.mode {
+                    OperatingMode::Mode0 => false,
+                    _ => true,
+                }
+            }
+        };
+        let period = self.period();
+        match self.mode {
+            // Low until the counter reaches zero, then high
+            OperatingMode::Mode0 => ticks >= period,
+            // Low for one clock when the counter reaches one, every period
+            OperatingMode::Mode2 => ticks % period != period - 1,
+            // High for the first half of each period and low for the
+            // second (an odd count is high for the extra clock)
+            OperatingMode::Mode3 => ticks % period < (period + 1) / 2,
+            // Low for one clock when the counter reaches zero
+            OperatingMode::Mode4 => ticks != period,
+            OperatingMode::Mode1 | OperatingMode::Mode5 => true,
+        }
+    }
+
+    fn program(&mut self, access: AccessMode, mode: OperatingMode) {
+        self.access = access;
+        self.mode = mode;
+        self.loaded_at = None;
+        self.expirations = 0;
+        self.latch = None;
+        self.write_high = false;
+        self.read_high = false;
+    }
+
+    fn write(&mut self, val: u8, now: u64) {
+        match self.access {
+            AccessMode::LoByte => self.reload = val as u16,
+            AccessMode::HiByte => self.reload = (val as u16) << 8,
+            AccessMode::Word | AccessMode::LatchCount => {
+                if self.write_high {
+                    self.reload = (self.reload & 0xff) | (val as u16) << 8;
+                } else {
+                    self.reload = (self.reload & 0xff00) | val as u16;
+                }
+                self.write_high = !self.write_high;
+
+                // The counter is only loaded once both bytes are written
+                if self.write_high {
+                    return;
+                }
+            }
+        }
+        self.loaded_at = Some(now);
+        self.expirations = 0;
+    }
+
+    fn read(&mut self, now: u64) -> u8 {
+        let count = self.latch.unwrap_or_else(|| self.count(now));
+        let (val, done) = match self.access {
+            AccessMode::LoByte => (count as u8, true),
+            AccessMode::HiByte => ((count >> 8) as u8, true),
+            AccessMode::Word | AccessMode::LatchCount => {
+                self.read_high = !self.read_high;
+                if self.read_high {
+                    (count as u8, false)
+                } else {
+                    ((count >> 8) as u8, true)
+                }
+            }
+        };
+        if done {
+            self.latch = None;
+        }
+        val
+    }
+}
+
+/// An emulated 8254 programmable interval timer (and the PC's port 0x61)
+///
+/// All of the PIT's registers are byte wide. A wide access to a counter
+/// port is split into a sequence of byte accesses to that port (least
+/// significant byte first), so a 16-bit write in lo/hi byte access mode
+/// loads the whole count. Wide accesses to the mode control port and port
+/// 0x61 fail with `Error::AccessWidth`.
+pub struct Pit8254 {
+    channels: [PitChannel; 3],
+    clock: Rc<dyn ClockSource>,
+    irq: Option<Rc<dyn IrqSink>>,
+
+    // The writable bits of port 0x61
+    ctrl_b: u8,
+
+    // The refresh request bit of port 0x61, which toggles on every read
+    refresh: bool,
+}
 
 impl Pit8254 {
     pub const PIT_COUNTER_0: Port = 0x0040;
@@ -75,8 +260,158 @@ impl Pit8254 {
 
     pub const PIT_PS2_CTRL_B: Port = 0x0061;
 
+    const PIT_IRQ: u8 = 0;
+
+    // Port 0x61 bits
+    pub const CTRL_B_GATE2: u8 = 1 << 0;
+    pub const CTRL_B_SPEAKER_DATA: u8 = 1 << 1;
+    pub const CTRL_B_REFRESH: u8 = 1 << 4;
+    pub const CTRL_B_OUT2: u8 = 1 << 5;
+
+    // The level of a speaker sample when it is driven high (or the
+    // negation when low)
+    const SPEAKER_AMPLITUDE: i16 = i16::MAX / 2;
+
     pub fn new() -> Box<Self> {
-        Box::new(Pit8254::default())
+        Self::with_clock(Rc::new(SystemClock))
+    }
+
+    /// Create a PIT whose counters are driven by `clock`
+    pub fn with_clock(clock: Rc<dyn ClockSource>) -> Box<Self> {
+        Box::new(Self {
+            channels: Default::default(),
+            clock,
+            irq: None,
+            ctrl_b: 0,
+            refresh: false,
+        })
+    }
+
+    /// Set the sink used to raise IRQ0 when channel 0 expires
+    pub fn set_irq_sink(&mut self, irq: Rc<dyn IrqSink>) {
+        self.irq = Some(irq);
+    }
+
+    /// The level of channel 2's output, as seen at bit 5 of port 0x61
+    pub fn channel2_output(&self) -> bool {
+        self.channels[2].output(self.clock.now_ns())
+    }
+
+    /// Render the speaker's output between `from_ns` and `to_ns` (times
+    /// of the PIT's clock) as signed 16-bit samples at `sample_rate` Hz
+    ///
+    /// The speaker follows channel 2's output while both the gate
+    /// (bit 0) and speaker data (bit 1) of port 0x61 are set, and is
+    /// silent (all zero samples) otherwise. The current channel 2
+    /// programming and port 0x61 bits are used for the whole interval.
+    pub fn speaker_samples(
+        &self,
+        from_ns: u64,
+        to_ns: u64,
+        sample_rate: u32,
+    ) -> Vec<i16> {
+        let count = (to_ns.saturating_sub(from_ns) as u128
+            * sample_rate as u128
+            / NS_PER_SEC as u128) as usize;
+        let enabled = Self::CTRL_B_GATE2 | Self::CTRL_B_SPEAKER_DATA;
+        if self.ctrl_b & enabled != enabled {
+            return vec![0; count];
+        }
+
+        let channel = &self.channels[2];
+        (0..count)
+            .map(|sample| {
+                let offset =
+                    sample as u128 * NS_PER_SEC as u128 / sample_rate as u128;
+                if channel.output(from_ns + offset as u64) {
+                    Self::SPEAKER_AMPLITUDE
+                } else {
+                    -Self::SPEAKER_AMPLITUDE
+                }
+            })
+            .collect()
+    }
+
+    fn write_mode_control(&mut self, val: u8) -> Result<()> {
+        let channel = Channel::try_from((val >> 6) & 0b11).unwrap();
+        let access = AccessMode::try_from((val >> 4) & 0b11).unwrap();
+        let mode = OperatingMode::try_from((val >> 1) & 0b111)?;
+
+        let channel = match channel {
+            Channel::ReadBack => {
+                info!("Ignoring PIT read-back command: 0x{:x}", val);
+                return Ok(());
+            }
+            channel => &mut self.channels[channel as usize],
+        };
+        match access {
+            AccessMode::LatchCount => {
+                if channel.latch.is_none() {
+                    channel.latch = Some(channel.count(self.clock.now_ns()));
+                }
+            }
+            access => channel.program(access, mode),
+        }
+        Ok(())
+    }
+}
+
+impl RegisterBlock for Pit8254 {
+    fn registers(&self) -> &'static [RegisterInfo] {
+        const REGISTERS: &[RegisterInfo] = &[
+            RegisterInfo::new("COUNT0", 0, 2),
+            RegisterInfo::new("COUNT1", 1, 2),
+            RegisterInfo::new("COUNT2", 2, 2),
+            RegisterInfo::new(
+                "CTRL_B",
+                Pit8254::PIT_PS2_CTRL_B - Pit8254::PIT_COUNTER_0,
+                1,
+            ),
+        ];
+        REGISTERS
+    }
+
+    /// Offsets are relative to port 0x40. The counters read as their
+    /// current count (ignoring any latched value).
+    fn read_reg(&self, offset: u16) -> u32 {
+        let port = Self::PIT_COUNTER_0 + offset;
+        match port {
+            Self::PIT_COUNTER_0..=Self::PIT_COUNTER_2 => {
+                let channel = &self.channels[offset as usize];
+                channel.count(self.clock.now_ns()) as u32
+            }
+            Self::PIT_PS2_CTRL_B => {
+                let mut data = self.ctrl_b;
+                if self.refresh {
+                    data |= Self::CTRL_B_REFRESH;
+                }
+                if self.channel2_output() {
+                    data |= Self::CTRL_B_OUT2;
+                }
+                data as u32
+            }
+            _ => 0,
+        }
+    }
+
+    /// Writing a counter loads a new reload value, restarting the count
+    fn write_reg(&mut self, offset: u16, val: u32, mask: u32) {
+        let port = Self::PIT_COUNTER_0 + offset;
+        match port {
+            Self::PIT_COUNTER_0..=Self::PIT_COUNTER_2 => {
+                let now = self.clock.now_ns();
+                let channel = &mut self.channels[offset as usize];
+                channel.reload =
+                    masked_write(channel.reload as u32, val, mask) as u16;
+                channel.loaded_at = Some(now);
+                channel.expirations = 0;
+            }
+            Self::PIT_PS2_CTRL_B => {
+                self.ctrl_b = masked_write(self.ctrl_b as u32, val, mask) as u8
+                    & (Self::CTRL_B_GATE2 | Self::CTRL_B_SPEAKER_DATA);
+            }
+            _ => (),
+        }
     }
 }
 
@@ -88,21 +423,414 @@ impl EmulatedDevice for Pit8254 {
         ]
     }
 
+    fn depends_on(&self) -> Vec<DeviceKind> {
+        vec![DeviceKind::Pic]
+    }
+
+    fn irq_lines(&self) -> Vec<u8> {
+        vec![Self::PIT_IRQ]
+    }
+
+    fn reset(&mut self) {
+        self.channels = Default::default();
+        self.ctrl_b = 0;
+        self.refresh = false;
+    }
+
+    fn poll(&mut self, now: u64) {
+        // Only channel 0 is connected to an interrupt line
+        let channel = &mut self.channels[0];
+        let total = channel.total_expirations(now);
+        // Expirations missed since the last poll are coalesced into a
+        // single edge on IRQ0
+        if total > channel.expirations {
+            if let Some(irq) = &self.irq {
+                irq.raise_irq(Self::PIT_IRQ);
+            }
+        }
+        channel.expirations = total;
+    }
+
     fn on_port_read(
         &mut self,
-        _port: Port,
-        _val: PortReadRequest,
+        port: Port,
+        mut val: PortReadRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
+        match port {
+            Self::PIT_COUNTER_0..=Self::PIT_COUNTER_2 => {
+                let now = self.clock.now_ns();
+                let channel =
+                    &mut self.channels[(port - Self::PIT_COUNTER_0) as usize];
+                val.fill_bytes(|| channel.read(now));
+            }
+            Self::PIT_PS2_CTRL_B => {
+                require_len(val.as_slice().len(), &[1])?;
+                val.copy_from_u32(self.read_reg(port - Self::PIT_COUNTER_0));
+
+                // Software (e.g., a BIOS delay loop) waits for this bit to
+                // change, so it toggles after every read rather than with
+                // the real ~15us refresh period
+                self.refresh = !self.refresh;
+            }
+            _ => (),
+        }
         Ok(())
     }
 
     fn on_port_write(
         &mut self,
-        _port: Port,
-        _val: PortWriteRequest,
+        port: Port,
+        val: PortWriteRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
+        match port {
+            Self::PIT_COUNTER_0..=Self::PIT_COUNTER_2 => {
+                let now = self.clock.now_ns();
+                let channel =
+                    &mut self.channels[(port - Self::PIT_COUNTER_0) as usize];
+                for byte in val.bytes() {
+                    channel.write(byte, now);
+                }
+            }
+            Self::PIT_MODE_CONTROL => {
+                self.write_mode_control(val.try_into()?)?
+            }
+            Self::PIT_PS2_CTRL_B => {
+                let data: u8 = val.try_into()?;
+                self.ctrl_b =
+                    data & (Self::CTRL_B_GATE2 | Self::CTRL_B_SPEAKER_DATA);
+            }
+            _ => (),
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::{define_test_view, MockIrqs};
+    use crate::time::FixedClock;
+
+    fn write_port(pit: &mut Pit8254, port: Port, val: u8) {
+        pit.on_port_write(
+            port,
+            PortWriteRequest::OneByte(&[val]),
+            define_test_view(),
+        )
+        .unwrap();
+    }
+
+    fn read_port(pit: &mut Pit8254, port: Port) -> u8 {
+        let mut arr = [0u8];
+        pit.on_port_read(
+            port,
+            PortReadRequest::OneByte(&mut arr),
+            define_test_view(),
+        )
+        .unwrap();
+        arr[0]
+    }
+
+    fn test_pit() -> (Box<Pit8254>, Rc<FixedClock>, Rc<MockIrqs>) {
+        let clock = Rc::new(FixedClock::new(0));
+        let irqs = Rc::new(MockIrqs::default());
+        let mut pit = Pit8254::with_clock(clock.clone());
+        pit.set_irq_sink(irqs.clone());
+        (pit, clock, irqs)
+    }
+
+    // The time taken for the PIT to count `ticks`
+    fn ticks_ns(ticks: u64) -> u64 {
+        (ticks * NS_PER_SEC + PIT_FREQUENCY_HZ - 1) / PIT_FREQUENCY_HZ
+    }
+
+    #[test]
+    fn test_periodic_irq0() {
+        let (mut pit, clock, irqs) = test_pit();
+
+        // Channel 0, rate generator, lo/hi byte access, 1000 tick period
+        write_port(&mut pit, Pit8254::PIT_MODE_CONTROL, 0x34);
+        write_port(&mut pit, Pit8254::PIT_COUNTER_0, 0xe8);
+        write_port(&mut pit, Pit8254::PIT_COUNTER_0, 0x03);
+
+        for _ in 0..20 {
+            clock.advance(ticks_ns(250));
+            pit.poll(clock.now_ns());
+        }
+        assert_eq!(*irqs.raised.borrow(), [0; 5]);
+
+        // A late poll reports the missed expirations as a single IRQ
+        clock.advance(ticks_ns(2000));
+        pit.poll(clock.now_ns());
+        assert_eq!(irqs.raised.borrow().len(), 6);
+    }
+
+    #[test]
+    fn test_wide_counter_access() {
+        let (mut pit, clock, irqs) = test_pit();
+
+        // Channel 0, rate generator, lo/hi byte access. A single 16-bit
+        // write loads both bytes of the 1000 tick period
+        write_port(&mut pit, Pit8254::PIT_MODE_CONTROL, 0x34);
+        pit.on_port_write(
+            Pit8254::PIT_COUNTER_0,
+            PortWriteRequest::TwoBytes(&1000u16.to_be_bytes()),
+            define_test_view(),
+        )
+        .unwrap();
+        assert_eq!(pit.channels[0].reload, 1000);
+
+        // A 16-bit read returns the low and then high byte of the count
+        clock.advance(ticks_ns(250));
+        let mut arr = [0u8; 2];
+        pit.on_port_read(
+            Pit8254::PIT_COUNTER_0,
+            PortReadRequest::TwoBytes(&mut arr),
+            define_test_view(),
+        )
+        .unwrap();
+        assert_eq!(u16::from_be_bytes(arr), 750);
+
+        clock.advance(ticks_ns(750));
+        pit.poll(clock.now_ns());
+        assert_eq!(*irqs.raised.borrow(), [0]);
+
+        // The mode control register only accepts byte writes
+        let res = pit.on_port_write(
+            Pit8254::PIT_MODE_CONTROL,
+            PortWriteRequest::TwoBytes(&[0x00, 0x34]),
+            define_test_view(),
+        );
+        assert!(matches!(res, Err(Error::AccessWidth { actual: 2, .. })));
+        let res = pit.on_port_read(
+            Pit8254::PIT_PS2_CTRL_B,
+            PortReadRequest::FourBytes(&mut [0u8; 4]),
+            define_test_view(),
+        );
+        assert!(matches!(res, Err(Error::AccessWidth { actual: 4, .. })));
+    }
+
+    #[test]
+    fn test_one_shot_irq0() {
+        let (mut pit, clock, irqs) = test_pit();
+
+        // Channel 0, interrupt on terminal count, lo byte access
+        write_port(&mut pit, Pit8254::PIT_MODE_CONTROL, 0x10);
+        write_port(&mut pit, Pit8254::PIT_COUNTER_0, 100);
+        clock.advance(ticks_ns(40));
+        pit.poll(clock.now_ns());
+        assert!(irqs.raised.borrow().is_empty());
+        assert_eq!(read_port(&mut pit, Pit8254::PIT_COUNTER_0), 60);
+
+        clock.advance(ticks_ns(1000));
+        pit.poll(clock.now_ns());
+        assert_eq!(*irqs.raised.borrow(), [0]);
+    }
+
+    #[test]
+    fn test_latched_count() {
+        let (mut pit, clock, _) = test_pit();
+
+        // Channel 2, square wave, lo/hi byte access
+        write_port(&mut pit, Pit8254::PIT_MODE_CONTROL, 0xb6);
+        write_port(&mut pit, Pit8254::PIT_COUNTER_2, 0x00);
+        write_port(&mut pit, Pit8254::PIT_COUNTER_2, 0x10);
+        clock.advance(ticks_ns(0x100));
+
+        // Latch channel 2, then keep counting
+        write_port(&mut pit, Pit8254::PIT_MODE_CONTROL, 0x80);
+        clock.advance(ticks_ns(0x100));
+        assert_eq!(read_port(&mut pit, Pit8254::PIT_COUNTER_2), 0x00);
+        assert_eq!(read_port(&mut pit, Pit8254::PIT_COUNTER_2), 0x0f);
+        assert_eq!(read_port(&mut pit, Pit8254::PIT_COUNTER_2), 0x00);
+        assert_eq!(read_port(&mut pit, Pit8254::PIT_COUNTER_2), 0x0e);
+    }
+
+    // Sample channel 2's output through port 0x61 once per clock over one
+    // period, returning the number of clocks the output was high
+    fn sample_channel2_period(
+        pit: &mut Pit8254,
+        clock: &FixedClock,
+        period: u64,
+    ) -> u64 {
+        let start = clock.now_ns();
+        (0..period)
+            .filter(|tick| {
+                clock.set(start + ticks_ns(*tick));
+                read_port(pit, Pit8254::PIT_PS2_CTRL_B) & Pit8254::CTRL_B_OUT2
+                    != 0
+            })
+            .count() as u64
+    }
+
+    #[test]
+    fn test_mode3_square_wave() {
+        let (mut pit, clock, _) = test_pit();
+
+        // Channel 2, square wave, lo/hi byte access, 1000 tick period
+        write_port(&mut pit, Pit8254::PIT_MODE_CONTROL, 0xb6);
+        write_port(&mut pit, Pit8254::PIT_COUNTER_2, 0xe8);
+        write_port(&mut pit, Pit8254::PIT_COUNTER_2, 0x03);
+        assert_eq!(sample_channel2_period(&mut pit, &clock, 1000), 500);
+
+        // The next period is the same
+        assert_eq!(sample_channel2_period(&mut pit, &clock, 1000), 500);
+
+        // An odd count is high for one extra clock
+        write_port(&mut pit, Pit8254::PIT_COUNTER_2, 0xe9);
+        write_port(&mut pit, Pit8254::PIT_COUNTER_2, 0x03);
+        assert_eq!(sample_channel2_period(&mut pit, &clock, 1001), 501);
+    }
+
+    #[test]
+    fn test_mode2_strobe() {
+        let (mut pit, clock, _) = test_pit();
+
+        // Channel 2, rate generator, lo/hi byte access, 1000 tick period
+        write_port(&mut pit, Pit8254::PIT_MODE_CONTROL, 0xb4);
+        assert!(pit.channel2_output());
+        write_port(&mut pit, Pit8254::PIT_COUNTER_2, 0xe8);
+        write_port(&mut pit, Pit8254::PIT_COUNTER_2, 0x03);
+        assert_eq!(sample_channel2_period(&mut pit, &clock, 1000), 999);
+        assert_eq!(sample_channel2_period(&mut pit, &clock, 1000), 999);
+    }
+
+    #[test]
+    fn test_one_shot_outputs() {
+        let (mut pit, clock, _) = test_pit();
+
+        // Channel 2, interrupt on terminal count, lo byte access
+        write_port(&mut pit, Pit8254::PIT_MODE_CONTROL, 0x90);
+        assert!(!pit.channel2_output());
+        write_port(&mut pit, Pit8254::PIT_COUNTER_2, 100);
+        clock.advance(ticks_ns(99));
+        assert!(!pit.channel2_output());
+        clock.advance(ticks_ns(1));
+        assert!(pit.channel2_output());
+
+        // Channel 2, software triggered strobe, lo byte access
+        write_port(&mut pit, Pit8254::PIT_MODE_CONTROL, 0x98);
+        assert!(pit.channel2_output());
+        let start = clock.now_ns();
+        write_port(&mut pit, Pit8254::PIT_COUNTER_2, 100);
+        clock.set(start + ticks_ns(99));
+        assert!(pit.channel2_output());
+        clock.set(start + ticks_ns(100));
+        assert!(!pit.channel2_output());
+        clock.set(start + ticks_ns(101));
+        assert!(pit.channel2_output());
+    }
+
+    #[test]
+    fn test_port_61_control_bits() {
+        let (mut pit, _, _) = test_pit();
+        write_port(&mut pit, Pit8254::PIT_PS2_CTRL_B, 0xff);
+        assert_eq!(
+            read_port(&mut pit, Pit8254::PIT_PS2_CTRL_B)
+                & !Pit8254::CTRL_B_OUT2,
+            Pit8254::CTRL_B_GATE2 | Pit8254::CTRL_B_SPEAKER_DATA
+        );
+    }
+
+    #[test]
+    fn test_port_61_refresh_toggles() {
+        let (mut pit, _, _) = test_pit();
+        let reads: Vec<u8> = (0..4)
+            .map(|_| {
+                read_port(&mut pit, Pit8254::PIT_PS2_CTRL_B)
+                    & Pit8254::CTRL_B_REFRESH
+            })
+            .collect();
+        assert_eq!(
+            reads,
+            [0, Pit8254::CTRL_B_REFRESH, 0, Pit8254::CTRL_B_REFRESH]
+        );
+
+        // Dumping the registers has no side effects
+        let ctrl_b = Pit8254::PIT_PS2_CTRL_B - Pit8254::PIT_COUNTER_0;
+        assert_eq!(pit.read_reg(ctrl_b), pit.read_reg(ctrl_b));
+        assert_eq!(pit.read_reg(ctrl_b) as u8 & Pit8254::CTRL_B_REFRESH, 0);
+
+        pit.reset();
+        assert_eq!(
+            read_port(&mut pit, Pit8254::PIT_PS2_CTRL_B)
+                & Pit8254::CTRL_B_REFRESH,
+            0
+        );
+    }
+
+    // The number of low to high transitions in a run of samples
+    fn rising_edges(samples: &[i16]) -> usize {
+        samples.windows(2).filter(|w| w[0] < 0 && w[1] > 0).count()
+    }
+
+    #[test]
+    fn test_speaker_samples() {
+        let (mut pit, _, _) = test_pit();
+
+        // Channel 2, square wave, lo/hi byte access, ~1kHz (1193 ticks)
+        write_port(&mut pit, Pit8254::PIT_MODE_CONTROL, 0xb6);
+        write_port(&mut pit, Pit8254::PIT_COUNTER_2, 0xa9);
+        write_port(&mut pit, Pit8254::PIT_COUNTER_2, 0x04);
+        write_port(
+            &mut pit,
+            Pit8254::PIT_PS2_CTRL_B,
+            Pit8254::CTRL_B_GATE2 | Pit8254::CTRL_B_SPEAKER_DATA,
+        );
+
+        // 100ms at 48kHz
+        let samples = pit.speaker_samples(0, 100_000_000, 48_000);
+        assert_eq!(samples.len(), 4800);
+        assert!(samples
+            .iter()
+            .all(|s| s.abs() == Pit8254::SPEAKER_AMPLITUDE));
+        let edges = rising_edges(&samples);
+        assert!(edges >= 99 && edges <= 100, "{} rising edges", edges);
+
+        // Each half of a cycle is ~24 samples
+        let first_low = samples.iter().position(|s| *s < 0).unwrap();
+        assert!(first_low >= 23 && first_low <= 25);
+
+        // Halving the reload value doubles the frequency
+        write_port(&mut pit, Pit8254::PIT_COUNTER_2, 0x54);
+        write_port(&mut pit, Pit8254::PIT_COUNTER_2, 0x02);
+        let edges = rising_edges(&pit.speaker_samples(0, 100_000_000, 48_000));
+        assert!(edges >= 199 && edges <= 200, "{} rising edges", edges);
+    }
+
+    #[test]
+    fn test_speaker_disabled_is_silent() {
+        let (mut pit, _, _) = test_pit();
+        write_port(&mut pit, Pit8254::PIT_MODE_CONTROL, 0xb6);
+        write_port(&mut pit, Pit8254::PIT_COUNTER_2, 0xa9);
+        write_port(&mut pit, Pit8254::PIT_COUNTER_2, 0x04);
+
+        for ctrl_b in
+            [0, Pit8254::CTRL_B_GATE2, Pit8254::CTRL_B_SPEAKER_DATA].iter()
+        {
+            write_port(&mut pit, Pit8254::PIT_PS2_CTRL_B, *ctrl_b);
+            let samples = pit.speaker_samples(0, 10_000_000, 44_100);
+            assert_eq!(samples.len(), 441);
+            assert!(samples.iter().all(|s| *s == 0));
+        }
+        assert!(pit.speaker_samples(10, 0, 44_100).is_empty());
+    }
+
+    #[test]
+    fn test_register_dump() {
+        let (mut pit, clock, _) = test_pit();
+
+        // Channel 2, square wave, lo/hi byte access
+        write_port(&mut pit, Pit8254::PIT_MODE_CONTROL, 0xb6);
+        write_port(&mut pit, Pit8254::PIT_COUNTER_2, 0x00);
+        write_port(&mut pit, Pit8254::PIT_COUNTER_2, 0x10);
+        write_port(&mut pit, Pit8254::PIT_PS2_CTRL_B, 0x03);
+        clock.advance(ticks_ns(0x100));
+
+        let dump = format!("{}", pit.dump());
+        assert!(dump.contains("COUNT2 (0x02) = 0x0f00\n"));
+        assert!(dump.contains("CTRL_B (0x21) = 0x23\n"));
+    }
+}
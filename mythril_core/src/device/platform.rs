@@ -0,0 +1,971 @@
+use crate::device::interrupt::{
+    InterruptSink, IrqSink, LevelIrqLines, PendingInterrupts, VcpuApic,
+};
+use crate::device::{
+    acpi, check_dependencies, check_irq_conflicts, com, debug, dma, ignore,
+    info_rom, ioapic, irq_router, keyboard, lapic, pci, pic, pit, pos, reset,
+    rtc, vga, DeviceMap, EmulatedDevice,
+};
+use crate::error::{Error, Result};
+use crate::memory::{GuestPhysAddr, MemoryLayout};
+use crate::time::{self, ClockSource, SystemClock};
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+/// A builder for the set of emulated devices that make up a guest platform
+///
+/// By default, the platform is empty. `enable_legacy_devices` adds the
+/// standard PC devices (PIC, PIT, CMOS, UARTs, etc.) and `add_device` can
+/// be used to add any others.
+pub struct PlatformBuilder {
+    vmid: u64,
+    memory: u64,
+    clock: Rc<dyn ClockSource>,
+    rtc_time: u64,
+    cmos_nvram: Option<rtc::CmosNvram>,
+    acpi_runtime: Option<Rc<RefCell<acpi::AcpiRuntime>>>,
+    pci_root: Option<Rc<RefCell<pci::PciRootComplex>>>,
+    reset: Rc<reset::ResetSignal>,
+    level_irqs: Rc<LevelIrqLines>,
+    interrupt_sink: Rc<dyn InterruptSink>,
+    irq_sink: Option<Rc<dyn IrqSink>>,
+    com_irqs: [Option<u8>; 4],
+    memory_layout: Option<MemoryLayout>,
+    legacy_devices: bool,
+
+    // The CPU count and RSDP reported by the info ROM, if enabled
+    info_rom: Option<(u32, Option<GuestPhysAddr>)>,
+    devices: Vec<Box<dyn EmulatedDevice>>,
+}
+
+impl PlatformBuilder {
+    const ACPI_PM_BASE: u16 = 0xb000;
+    const COM_PORTS: [u16; 4] = [0x3f8, 0x2f8, 0x3e8, 0x2e8];
+    // COM3 and COM4 conventionally share the (edge triggered) IRQs of COM1
+    // and COM2, which cannot be shared here, so they are polled by default
+    const COM_IRQS: [Option<u8>; 4] = [Some(4), Some(3), None, None];
+    const DEBUG_PORT: u16 = 0x402;
+
+    /// Create a new builder for a guest with the given id and memory (in MB)
+    pub fn new(vmid: u64, memory: u64) -> Self {
+        Self {
+            vmid,
+            memory,
+            clock: Rc::new(SystemClock),
+            rtc_time: time::system_start_unix_time().unwrap_or(0),
+            cmos_nvram: None,
+            acpi_runtime: None,
+            pci_root: None,
+            reset: reset::ResetSignal::new(),
+            level_irqs: LevelIrqLines::new(),
+            interrupt_sink: Rc::new(PendingInterrupts::new(&[VcpuApic::new(
+                0,
+            )])),
+            irq_sink: None,
+            com_irqs: Self::COM_IRQS,
+            memory_layout: None,
+            legacy_devices: false,
+            info_rom: None,
+            devices: vec![],
+        }
+    }
+
+    /// Set the clock used by the time-keeping devices
+    pub fn set_clock(&mut self, clock: Rc<dyn ClockSource>) {
+        self.clock = clock;
+    }
+
+    /// Set the wall clock time (in seconds since the unix epoch) reported
+    /// by the CMOS when the platform clock reads zero
+    ///
+    /// By default, this is the host's wall clock time when the system
+    /// started (which is when the default `SystemClock` reads zero), so
+    /// the guest reads the host's current time from the CMOS.
+    pub fn set_rtc_time(&mut self, unix_time: u64) {
+        self.rtc_time = unix_time;
+    }
+
+    /// Back the CMOS NVRAM with a host provided buffer, so it persists
+    /// across guest reboots
+    pub fn set_cmos_nvram(&mut self, nvram: rtc::CmosNvram) {
+        self.cmos_nvram = Some(nvram);
+    }
+
+    /// Set the sink used by the interrupt controllers to deliver
+    /// interrupts to the guest
+    ///
+    /// By default, interrupts are queued for a single vcpu with APIC ID 0.
+    pub fn set_interrupt_sink(&mut self, sink: Rc<dyn InterruptSink>) {
+        self.interrupt_sink = sink;
+    }
+
+    /// Set the sink used by the legacy devices to raise their IRQs
+    ///
+    /// By default, IRQs are routed to the PIC or I/O APIC as selected by
+    /// the guest through the IMCR.
+    pub fn set_irq_sink(&mut self, sink: Rc<dyn IrqSink>) {
+        self.irq_sink = Some(sink);
+    }
+
+    /// Set the IRQ of the legacy UART `index` (0 for COM1 to 3 for COM4),
+    /// or `None` for the guest to poll it
+    ///
+    /// By default, COM1 uses IRQ4, COM2 uses IRQ3, and COM3 and COM4 have
+    /// no IRQ. The conventional IRQs of COM3 and COM4 are those of COM1
+    /// and COM2, and edge triggered IRQs cannot be shared, so moving
+    /// either to IRQ4 or IRQ3 requires moving (or disabling) the IRQ of
+    /// the other port first. Otherwise, `build` fails with
+    /// `Error::IrqConflict`.
+    pub fn set_com_irq_line(
+        &mut self,
+        index: usize,
+        line: Option<u8>,
+    ) -> Result<()> {
+        let irq = self.com_irqs.get_mut(index).ok_or_else(|| {
+            Error::InvalidValue(format!("Invalid COM port index: {}", index))
+        })?;
+        *irq = line;
+        Ok(())
+    }
+
+    /// The ACPI power management device of the legacy platform
+    ///
+    /// The returned device is shared with the built platform, so the
+    /// supervisor can use it to poll for guest sleep requests.
+    pub fn acpi_runtime(&mut self) -> Result<Rc<RefCell<acpi::AcpiRuntime>>> {
+        if let Some(acpi) = &self.acpi_runtime {
+            return Ok(acpi.clone());
+        }
+        let acpi =
+            Rc::new(RefCell::new(*acpi::AcpiRuntime::new(Self::ACPI_PM_BASE)?));
+        self.acpi_runtime = Some(acpi.clone());
+        Ok(acpi)
+    }
+
+    /// The PCI root complex of the legacy platform
+    ///
+    /// The returned device is shared with the built platform, so the
+    /// supervisor can use it to hot-plug PCI devices after boot.
+    pub fn pci_root_complex(&mut self) -> Rc<RefCell<pci::PciRootComplex>> {
+        if let Some(root) = &self.pci_root {
+            return root.clone();
+        }
+        let root = Rc::new(RefCell::new(*pci::PciRootComplex::new()));
+        self.pci_root = Some(root.clone());
+        root
+    }
+
+    /// The signal raised when the guest requests a reset
+    ///
+    /// Every reset mechanism of the legacy platform (the keyboard
+    /// controller, port 0x92 and the reset control register) raises this
+    /// same signal, so the supervisor only has to poll it with
+    /// `take_reset_request`.
+    pub fn reset_signal(&self) -> Rc<reset::ResetSignal> {
+        self.reset.clone()
+    }
+
+    /// The level triggered IRQ lines of the platform
+    ///
+    /// A device added with a level triggered IRQ (e.g., a PCI device)
+    /// should be attached here, so the PIC and I/O APIC raise its
+    /// interrupt again when the guest ends it while the line is still
+    /// asserted.
+    pub fn level_irq_lines(&self) -> Rc<LevelIrqLines> {
+        self.level_irqs.clone()
+    }
+
+    /// Set the guest memory layout that memory mapped devices are
+    /// validated against
+    ///
+    /// By default, this is the standard PC layout for the guest's memory
+    /// size (see `MemoryLayout::for_ram_size`).
+    pub fn set_memory_layout(&mut self, layout: MemoryLayout) {
+        self.memory_layout = Some(layout);
+    }
+
+    /// Include the standard legacy PC devices in the platform
+    pub fn enable_legacy_devices(&mut self) {
+        self.legacy_devices = true;
+    }
+
+    /// Include an `InfoRom` at its default address, reporting the guest's
+    /// memory, `cpus` and the address of the ACPI RSDP (if any)
+    pub fn enable_info_rom(&mut self, cpus: u32, rsdp: Option<GuestPhysAddr>) {
+        self.info_rom = Some((cpus, rsdp));
+    }
+
+    /// Include an additional device in the platform
+    pub fn add_device(&mut self, dev: Box<dyn EmulatedDevice>) {
+        self.devices.push(dev);
+    }
+
+    fn legacy_device_list(&mut self) -> Result<Vec<Box<dyn EmulatedDevice>>> {
+        let acpi = self.acpi_runtime()?;
+        let pci_root = self.pci_root_complex();
+        acpi.borrow_mut()
+            .set_pci_hotplug(pci_root.borrow().hotplug());
+
+        let pic = Rc::new(RefCell::new(*pic::Pic8259::new()));
+        pic.borrow_mut().set_level_lines(self.level_irqs.clone());
+        let ioapic = Rc::new(RefCell::new(*ioapic::IoApic::new(
+            self.interrupt_sink.clone(),
+        )));
+        ioapic.borrow_mut().set_level_lines(self.level_irqs.clone());
+        let router = irq_router::IrqRouter::new(pic.clone(), ioapic.clone());
+        let irq_sink: Rc<dyn IrqSink> = match &self.irq_sink {
+            Some(irq) => irq.clone(),
+            None => router.clone(),
+        };
+        acpi.borrow_mut().set_irq_sink(irq_sink.clone());
+        self.level_irqs
+            .attach(acpi::AcpiRuntime::SCI_IRQ, acpi.clone());
+
+        let mut devices: Vec<Box<dyn EmulatedDevice>> = vec![Box::new(acpi)];
+        // The generated FADT reports a 32-bit PM timer (TMR_VAL_EXT)
+        devices.push(acpi::AcpiPmTimer::new(
+            Self::ACPI_PM_BASE + 8,
+            self.clock.clone(),
+            true,
+        )?);
+        for (port, line) in Self::COM_PORTS.iter().zip(self.com_irqs.iter()) {
+            let mut com = com::ComDevice::new(self.vmid, *port);
+            com.set_irq_sink(irq_sink.clone());
+            com.set_irq_line(*line)?;
+            devices.push(com);
+        }
+        devices.push(debug::DebugPort::new(self.vmid, Self::DEBUG_PORT));
+        devices.push(vga::VgaController::new());
+        devices.push(dma::Dma8237::new());
+        devices.push(ignore::IgnoredDevice::new());
+        devices.push(Box::new(pci_root));
+        devices.push(Box::new(pic));
+        devices.push(irq_router::Imcr::new(router));
+        let mut keyboard =
+            keyboard::Keyboard8042::with_clock(self.clock.clone());
+        keyboard.set_reset_signal(self.reset.clone());
+        devices.push(keyboard);
+        let mut pit = pit::Pit8254::with_clock(self.clock.clone());
+        pit.set_irq_sink(irq_sink.clone());
+        devices.push(pit);
+        let mut pos = pos::ProgrammableOptionSelect::new();
+        pos.set_reset_signal(self.reset.clone());
+        devices.push(pos);
+        devices.push(reset::ResetControl::new(self.reset.clone()));
+        let mut cmos = rtc::CmosRtc::with_clock(
+            self.memory,
+            self.clock.clone(),
+            self.rtc_time,
+        );
+        if let Some(nvram) = &self.cmos_nvram {
+            cmos.set_nvram(nvram.clone());
+        }
+        cmos.set_irq_sink(irq_sink);
+        devices.push(cmos);
+
+        //TODO: this should actually be per-vcpu
+        let mut lapic = lapic::LocalApic::with_clock(
+            self.interrupt_sink.clone(),
+            self.clock.clone(),
+        );
+        lapic.set_eoi_sink(ioapic.clone());
+        devices.push(lapic);
+        devices.push(Box::new(ioapic));
+        Ok(devices)
+    }
+
+    /// Register all of the platform devices in a new `DeviceMap`
+    ///
+    /// Fails with `Error::MissingDependencies` if any device depends on
+    /// a kind of device that is not part of the platform,
+    /// `Error::IrqConflict` if two devices use the same IRQ line and
+    /// either is edge triggered, or `Error::InvalidMmioRegion` if a device
+    /// is memory mapped outside of the MMIO holes of the memory layout.
+    pub fn build(mut self) -> Result<DeviceMap> {
+        let mut devices = if self.legacy_devices {
+            self.legacy_device_list()?
+        } else {
+            vec![]
+        };
+        if let Some((cpus, rsdp)) = self.info_rom {
+            let info = info_rom::PlatformInfo {
+                memory: self.memory << 20,
+                cpus,
+                rsdp,
+            };
+            devices.push(info_rom::InfoRom::new(
+                GuestPhysAddr::new(info_rom::InfoRom::DEFAULT_BASE),
+                &info,
+            ));
+        }
+        devices.extend(self.devices);
+        check_dependencies(&devices)?;
+        check_irq_conflicts(&devices)?;
+
+        let mut map = DeviceMap::default();
+        let memory = self.memory;
+        map.set_memory_layout(
+            self.memory_layout
+                .unwrap_or_else(|| MemoryLayout::for_ram_size(memory)),
+        );
+        map.register_all(devices)?;
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::interrupt::IrqLineState;
+    use crate::device::test_util::{define_test_view, MockIrqs};
+    use crate::device::{
+        msi, DeviceKind, DeviceRegion, IrqConflict, MemReadRequest,
+        MemWriteRequest, MissingDependency, Port, PortReadRequest,
+        PortWriteRequest,
+    };
+    use crate::error::Error;
+    use crate::ioapic::TriggerMode;
+    use crate::time::{ClockSource, FixedClock};
+    use core::cell::Cell;
+    use core::convert::TryFrom;
+
+    // 2020-05-14 13:45:30 UTC
+    const RTC_TIME: u64 = 1589463930;
+
+    /// A single guest I/O access and (for reads) the expected response
+    enum Access {
+        Out(Port, usize, u32),
+        In(Port, usize, u32),
+    }
+
+    use Access::*;
+
+    // The accesses performed by the BIOS during early platform init
+    const EARLY_INIT: &[Access] = &[
+        // PIC init (ICW1-4), then mask everything but the cascade
+        Out(0x20, 1, 0x11),
+        Out(0xa0, 1, 0x11),
+        Out(0x21, 1, 0x08),
+        Out(0xa1, 1, 0x70),
+        Out(0x21, 1, 0x04),
+        Out(0xa1, 1, 0x02),
+        Out(0x21, 1, 0x01),
+        Out(0xa1, 1, 0x01),
+        Out(0x21, 1, 0xfb),
+        Out(0xa1, 1, 0xff),
+        In(0x21, 1, 0xfb),
+        In(0xa1, 1, 0xff),
+        // PIT channel 0, rate generator, lo/hi byte access
+        Out(0x43, 1, 0x34),
+        Out(0x40, 1, 0x00),
+        Out(0x40, 1, 0x00),
+        // CMOS status registers (with NMI disabled)
+        Out(0x70, 1, 0x8d),
+        In(0x71, 1, 0x80),
+        Out(0x70, 1, 0x8b),
+        In(0x71, 1, 0x02),
+        // CMOS time
+        Out(0x70, 1, 0x00),
+        In(0x71, 1, 0x30),
+        Out(0x70, 1, 0x02),
+        In(0x71, 1, 0x45),
+        Out(0x70, 1, 0x04),
+        In(0x71, 1, 0x13),
+        Out(0x70, 1, 0x07),
+        In(0x71, 1, 0x14),
+        Out(0x70, 1, 0x08),
+        In(0x71, 1, 0x05),
+        Out(0x70, 1, 0x09),
+        In(0x71, 1, 0x20),
+        Out(0x70, 1, 0x32),
+        In(0x71, 1, 0x20),
+        // PCI enumeration of bus 0: the host bridge, the ICH9 and nothing
+        Out(0xcf8, 4, 0x80000000),
+        In(0xcfc, 4, 0x29c08086),
+        In(0xcfc, 2, 0x8086),
+        In(0xcfe, 2, 0x29c0),
+        Out(0xcf8, 4, 0x80000800),
+        In(0xcfc, 4, 0x29188086),
+        Out(0xcf8, 4, 0x80001000),
+        In(0xcfc, 4, 0xffffffff),
+    ];
+
+    fn test_platform() -> DeviceMap {
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.set_clock(Rc::new(FixedClock::new(0)));
+        builder.set_rtc_time(RTC_TIME);
+        builder.enable_legacy_devices();
+        builder.build().unwrap()
+    }
+
+    // Perform an access the same way the portio emulation does
+    fn replay(map: &mut DeviceMap, access: &Access) -> Result<Option<u32>> {
+        match *access {
+            Out(port, size, val) => {
+                let arr = val.to_be_bytes();
+                let request = PortWriteRequest::try_from(&arr[4 - size..])?;
+                map.on_port_write(port, request, define_test_view())?;
+                Ok(None)
+            }
+            In(port, size, _) => {
+                let mut arr = [0u8; 4];
+                let request = PortReadRequest::try_from(&mut arr[4 - size..])?;
+                map.on_port_read(port, request, define_test_view())?;
+                Ok(Some(u32::from_be_bytes(arr)))
+            }
+        }
+    }
+
+    fn mem_write(map: &mut DeviceMap, addr: u64, val: u32) {
+        map.on_mem_write(
+            GuestPhysAddr::new(addr),
+            MemWriteRequest::new(&val.to_be_bytes()),
+            define_test_view(),
+        )
+        .unwrap();
+    }
+
+    fn mem_read(map: &mut DeviceMap, addr: u64) -> u32 {
+        let mut arr = [0u8; 4];
+        map.on_mem_read(
+            GuestPhysAddr::new(addr),
+            MemReadRequest::new(&mut arr),
+            define_test_view(),
+        )
+        .unwrap();
+        u32::from_be_bytes(arr)
+    }
+
+    #[test]
+    fn test_golden_early_init() {
+        let mut map = test_platform();
+        for (i, access) in EARLY_INIT.iter().enumerate() {
+            let res = replay(&mut map, access).unwrap_or_else(|e| {
+                panic!("Access {} failed: {:?}", i, e);
+            });
+            if let In(port, _, expected) = *access {
+                assert_eq!(
+                    res,
+                    Some(expected),
+                    "Unexpected read of port 0x{:x} (access {})",
+                    port,
+                    i
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_empty_platform() {
+        let mut map = PlatformBuilder::new(0, 256).build().unwrap();
+        assert!(map.device_for(0x3f8u16).is_none());
+        assert!(replay(&mut map, &In(0x21, 1, 0)).is_err());
+    }
+
+    #[test]
+    fn test_conflicting_platform_device() {
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.enable_legacy_devices();
+        builder.add_device(com::ComDevice::new(0, 0x3f8));
+        assert!(builder.build().is_err());
+    }
+
+    #[test]
+    fn test_platform_irq_assignments() {
+        let map = test_platform();
+        let irqs = map.irq_map();
+        let users = |irq: u8| -> Vec<&str> {
+            irqs[&irq].users.iter().map(|user| user.device).collect()
+        };
+
+        assert_eq!(users(0), ["Pit8254"]);
+        assert_eq!(users(1), ["Keyboard8042"]);
+        assert_eq!(users(8), ["CmosRtc"]);
+        assert!(users(4).iter().all(|name| *name == "ComDevice"));
+    }
+
+    // A PCI-style device with a level triggered interrupt
+    struct LevelIrqDevice {
+        port: Port,
+    }
+
+    impl EmulatedDevice for LevelIrqDevice {
+        fn services(&self) -> Vec<DeviceRegion> {
+            vec![DeviceRegion::PortIo(self.port..=self.port)]
+        }
+
+        fn irq_lines(&self) -> Vec<u8> {
+            vec![11]
+        }
+
+        fn irq_trigger_mode(&self, _irq: u8) -> TriggerMode {
+            TriggerMode::Level
+        }
+    }
+
+    #[test]
+    fn test_irq_conflicts() {
+        // Two UARTs on (edge triggered) IRQ4
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.add_device(pic::Pic8259::new());
+        builder.add_device(com::ComDevice::new(0, 0x3f8));
+        builder.add_device(com::ComDevice::new(0, 0x3e8));
+        match builder.build() {
+            Err(Error::IrqConflict(conflict)) => assert_eq!(
+                conflict,
+                IrqConflict {
+                    irq: 4,
+                    device: "ComDevice",
+                    existing_device: "ComDevice",
+                }
+            ),
+            res => panic!("Unexpected build result: {:?}", res.err()),
+        }
+
+        // Either can be moved to another line (or polled)
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.add_device(pic::Pic8259::new());
+        builder.add_device(com::ComDevice::new(0, 0x3f8));
+        let mut com3 = com::ComDevice::new(0, 0x3e8);
+        com3.set_irq_line(Some(5)).unwrap();
+        builder.add_device(com3);
+        let map = builder.build().unwrap();
+        assert_eq!(map.irq_map()[&5].users[0].device, "ComDevice");
+
+        // Level triggered lines can be shared
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.add_device(Box::new(LevelIrqDevice { port: 0x10 }));
+        builder.add_device(Box::new(LevelIrqDevice { port: 0x20 }));
+        let map = builder.build().unwrap();
+        assert!(map.irq_map()[&11].is_shared());
+    }
+
+    #[test]
+    fn test_platform_com_irqs() {
+        // By default, only COM1 and COM2 have an IRQ
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.enable_legacy_devices();
+        let map = builder.build().unwrap();
+        assert_eq!(map.irq_map()[&4].users.len(), 1);
+        assert_eq!(map.irq_map()[&3].users.len(), 1);
+
+        // COM3 can be given a free IRQ
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.enable_legacy_devices();
+        builder.set_com_irq_line(2, Some(5)).unwrap();
+        let map = builder.build().unwrap();
+        assert_eq!(map.irq_map()[&5].users[0].device, "ComDevice");
+
+        // Its conventional IRQ conflicts with COM1, unless COM1 is moved
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.enable_legacy_devices();
+        builder.set_com_irq_line(2, Some(4)).unwrap();
+        assert!(matches!(builder.build(), Err(Error::IrqConflict(_))));
+
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.enable_legacy_devices();
+        builder.set_com_irq_line(0, None).unwrap();
+        builder.set_com_irq_line(2, Some(4)).unwrap();
+        let map = builder.build().unwrap();
+        assert_eq!(map.irq_map()[&4].users.len(), 1);
+
+        let mut builder = PlatformBuilder::new(0, 256);
+        assert!(builder.set_com_irq_line(4, Some(4)).is_err());
+    }
+
+    #[test]
+    fn test_uart_without_interrupt_controller() {
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.add_device(com::ComDevice::new(0, 0x3f8));
+        builder.add_device(ioapic::IoApic::new(Rc::new(
+            PendingInterrupts::new(&[VcpuApic::new(0)]),
+        )));
+        match builder.build() {
+            Err(Error::MissingDependencies(missing)) => assert_eq!(
+                missing,
+                [
+                    MissingDependency {
+                        device: "ComDevice",
+                        kind: DeviceKind::Pic,
+                    },
+                    MissingDependency {
+                        device: "IoApic",
+                        kind: DeviceKind::LocalApic,
+                    },
+                ]
+            ),
+            res => panic!("Unexpected build result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_complete_platform_dependencies() {
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.add_device(pic::Pic8259::new());
+        builder.add_device(com::ComDevice::new(0, 0x3f8));
+        assert!(builder.build().is_ok());
+
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.enable_legacy_devices();
+        assert_eq!(
+            check_dependencies(&builder.legacy_device_list().unwrap()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_platform_sleep_request() {
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.enable_legacy_devices();
+        let acpi = builder.acpi_runtime().unwrap();
+        let mut map = builder.build().unwrap();
+
+        let pm1_control = PlatformBuilder::ACPI_PM_BASE + 4;
+        replay(&mut map, &Out(pm1_control, 2, (5 << 10) | (1 << 13))).unwrap();
+        assert_eq!(
+            acpi.borrow_mut().take_sleep_request(),
+            Some(acpi::SleepState::S5)
+        );
+    }
+
+    #[test]
+    fn test_platform_pci_hotplug() {
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.enable_legacy_devices();
+        let root = builder.pci_root_complex();
+        let mut map = builder.build().unwrap();
+
+        let gpe_status = 0xafe0;
+        replay(&mut map, &Out(gpe_status + 2, 1, 0x02)).unwrap();
+        assert_eq!(replay(&mut map, &In(gpe_status, 1, 0)).unwrap(), Some(0));
+
+        let bdf = pci::PciBdf::new(0, 3, 0);
+        root.borrow_mut()
+            .add_device(pci::PciDevice::new(bdf, 0x1af4, 0x1000))
+            .unwrap();
+        assert_eq!(
+            replay(&mut map, &In(gpe_status, 1, 0)).unwrap(),
+            Some(0x02)
+        );
+
+        replay(&mut map, &Out(0xcf8, 4, 0x8000_1800)).unwrap();
+        assert_eq!(
+            replay(&mut map, &In(0xcfc, 4, 0)).unwrap(),
+            Some(0x1000_1af4)
+        );
+    }
+
+    #[test]
+    fn test_platform_reset_triggers() {
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.set_clock(Rc::new(FixedClock::new(0)));
+        builder.enable_legacy_devices();
+        let reset = builder.reset_signal();
+        let bdf = pci::PciBdf::new(0, 3, 0);
+        let mut device = pci::PciDevice::new(bdf, 0x1af4, 0x1000);
+        device
+            .set_bar(
+                0,
+                pci::PciBar {
+                    kind: pci::PciBarKind::Memory32 {
+                        prefetchable: false,
+                    },
+                    size: 0x1000,
+                },
+            )
+            .unwrap();
+        let msi = device
+            .add_msi_capability(msi::MsiCapability::new(0, false, false))
+            .unwrap();
+        builder
+            .pci_root_complex()
+            .borrow_mut()
+            .add_device(device)
+            .unwrap();
+        let mut map = builder.build().unwrap();
+
+        let triggers: &[(&[Access], reset::ResetSource)] = &[
+            (
+                &[Out(0x64, 1, 0xfe)],
+                reset::ResetSource::KeyboardController,
+            ),
+            (
+                &[Out(0x64, 1, 0xd1), Out(0x60, 1, 0xfe)],
+                reset::ResetSource::KeyboardOutputPort,
+            ),
+            (&[Out(0x92, 1, 0x03)], reset::ResetSource::FastReset),
+            (&[Out(0xcf9, 1, 0x06)], reset::ResetSource::ResetControl),
+        ];
+        for (accesses, source) in triggers.iter() {
+            assert_eq!(reset.take_reset_request(), None);
+            for access in accesses.iter() {
+                replay(&mut map, access).unwrap();
+            }
+            assert_eq!(reset.take_reset_request(), Some(*source));
+            assert_eq!(reset.take_reset_request(), None);
+        }
+
+        // Writes that don't request a reset
+        replay(&mut map, &Out(0x64, 1, 0xff)).unwrap();
+        replay(&mut map, &Out(0x64, 1, 0xd1)).unwrap();
+        replay(&mut map, &Out(0x60, 1, 0x03)).unwrap();
+        replay(&mut map, &Out(0x92, 1, 0x02)).unwrap();
+        replay(&mut map, &Out(0xcf9, 1, 0x02)).unwrap();
+        assert_eq!(reset.take_reset_request(), None);
+
+        // Put some devices in a non-default state, then reset them
+        replay(&mut map, &Out(0x21, 1, 0xfb)).unwrap();
+        replay(&mut map, &Out(0x3fb, 1, 0x03)).unwrap();
+        replay(&mut map, &Out(0x43, 1, 0x34)).unwrap();
+        replay(&mut map, &Out(0x40, 1, 0x34)).unwrap();
+        replay(&mut map, &Out(0x40, 1, 0x12)).unwrap();
+        assert_eq!(replay(&mut map, &In(0x92, 1, 0)).unwrap(), Some(0x02));
+
+        // The local APIC timer and an I/O APIC redirection entry
+        mem_write(&mut map, 0xfee0_0320, 0x40);
+        mem_write(&mut map, ioapic::IoApic::DEFAULT_BASE, 0x14);
+        mem_write(&mut map, ioapic::IoApic::DEFAULT_BASE + 0x10, 0x30);
+
+        // The VGA cursor and a DMA page register
+        replay(&mut map, &Out(0x3d4, 1, 0x0e)).unwrap();
+        replay(&mut map, &Out(0x3d5, 1, 0x12)).unwrap();
+        replay(&mut map, &Out(0x81, 1, 0x12)).unwrap();
+
+        // A PCI BAR and MSI capability
+        let config =
+            |register: u8| Out(0xcf8, 4, bdf.to_config_address(register));
+        replay(&mut map, &config(0x10 / 4)).unwrap();
+        replay(&mut map, &Out(0xcfc, 4, 0xfebf_0000)).unwrap();
+        replay(&mut map, &config(msi / 4 + 1)).unwrap();
+        replay(&mut map, &Out(0xcfc, 4, 0xfee0_0000)).unwrap();
+        replay(&mut map, &config(msi / 4)).unwrap();
+        replay(&mut map, &Out(0xcfc, 4, 1 << 16)).unwrap();
+        assert_eq!(
+            replay(&mut map, &In(0xcfc, 4, 0)).unwrap().unwrap() >> 16,
+            1
+        );
+
+        map.reset_all().unwrap();
+        assert_eq!(replay(&mut map, &In(0x21, 1, 0)).unwrap(), Some(0));
+        assert_eq!(replay(&mut map, &In(0x3fb, 1, 0)).unwrap(), Some(0));
+        assert_eq!(replay(&mut map, &In(0x40, 1, 0)).unwrap(), Some(0));
+        assert_eq!(replay(&mut map, &In(0x92, 1, 0)).unwrap(), Some(0));
+        assert_eq!(reset.take_reset_request(), None);
+
+        assert_eq!(mem_read(&mut map, 0xfee0_0320), 1 << 16);
+        assert_eq!(mem_read(&mut map, ioapic::IoApic::DEFAULT_BASE), 0);
+        mem_write(&mut map, ioapic::IoApic::DEFAULT_BASE, 0x14);
+        assert_eq!(
+            mem_read(&mut map, ioapic::IoApic::DEFAULT_BASE + 0x10),
+            1 << 16
+        );
+
+        replay(&mut map, &Out(0x3d4, 1, 0x0e)).unwrap();
+        assert_eq!(replay(&mut map, &In(0x3d5, 1, 0)).unwrap(), Some(0));
+        assert_eq!(replay(&mut map, &In(0x81, 1, 0)).unwrap(), Some(0));
+
+        assert_eq!(replay(&mut map, &In(0xcf8, 4, 0)).unwrap(), Some(0));
+        replay(&mut map, &config(0x10 / 4)).unwrap();
+        assert_eq!(replay(&mut map, &In(0xcfc, 4, 0)).unwrap(), Some(0));
+        replay(&mut map, &config(msi / 4 + 1)).unwrap();
+        assert_eq!(replay(&mut map, &In(0xcfc, 4, 0)).unwrap(), Some(0));
+        replay(&mut map, &config(msi / 4)).unwrap();
+        assert_eq!(
+            replay(&mut map, &In(0xcfc, 4, 0)).unwrap().unwrap() >> 16,
+            0
+        );
+    }
+
+    #[test]
+    fn test_platform_poll_timers() {
+        let clock = Rc::new(FixedClock::new(0));
+        let irqs = Rc::new(MockIrqs::default());
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.set_clock(clock.clone());
+        builder.set_irq_sink(irqs.clone());
+        builder.enable_legacy_devices();
+        let mut map = builder.build().unwrap();
+
+        // Program PIT channel 0 for a ~1ms period
+        replay(&mut map, &Out(0x43, 1, 0x34)).unwrap();
+        replay(&mut map, &Out(0x40, 1, 0xa9)).unwrap();
+        replay(&mut map, &Out(0x40, 1, 0x04)).unwrap();
+
+        for _ in 0..10 {
+            clock.advance(500_000);
+            map.poll_all(clock.now_ns());
+        }
+        assert_eq!(*irqs.raised.borrow(), [0; 5]);
+    }
+
+    #[test]
+    fn test_platform_mmio_in_ram() {
+        let rom = |start: u64| {
+            crate::device::rom::RomDevice::new(
+                GuestPhysAddr::new(start)..=GuestPhysAddr::new(start + 0xfff),
+                vec![],
+            )
+        };
+
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.enable_legacy_devices();
+        builder.add_device(rom(0x0800_0000));
+        assert!(matches!(builder.build(), Err(Error::InvalidMmioRegion(_))));
+
+        // With less memory, the same address is in the hole
+        let mut builder = PlatformBuilder::new(0, 64);
+        builder.set_memory_layout(MemoryLayout::new(
+            vec![GuestPhysAddr::new(0)..=GuestPhysAddr::new(0x03ff_ffff)],
+            vec![
+                GuestPhysAddr::new(0x0400_0000)
+                    ..=GuestPhysAddr::new(0xffff_ffff),
+            ],
+        ));
+        builder.enable_legacy_devices();
+        builder.add_device(rom(0x0800_0000));
+        assert!(builder.build().is_ok());
+    }
+
+    #[test]
+    fn test_platform_info_rom() {
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.enable_legacy_devices();
+        builder.enable_info_rom(2, None);
+        let mut map = builder.build().unwrap();
+
+        let base = info_rom::InfoRom::DEFAULT_BASE;
+        let mut read = |offset: usize| {
+            let mut buff = [0u8; 8];
+            map.on_mem_read(
+                GuestPhysAddr::new(base + offset as u64),
+                MemReadRequest::new(&mut buff),
+                define_test_view(),
+            )
+            .unwrap();
+            u64::from_le_bytes(buff)
+        };
+        assert_eq!(read(info_rom::InfoRom::MEMORY_OFFSET), 256 << 20);
+        assert_eq!(read(info_rom::InfoRom::CPUS_OFFSET) as u32, 2);
+        assert_eq!(read(info_rom::InfoRom::RSDP_OFFSET), 0);
+    }
+
+    #[test]
+    fn test_platform_imcr_routing() {
+        let clock = Rc::new(FixedClock::new(0));
+        let interrupts = Rc::new(PendingInterrupts::new(&[VcpuApic::new(0)]));
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.set_clock(clock.clone());
+        builder.set_interrupt_sink(interrupts.clone());
+        builder.enable_legacy_devices();
+        let mut map = builder.build().unwrap();
+        let pic_irqs = |map: &DeviceMap| {
+            map.device_for(0x20u16)
+                .and_then(|dev| {
+                    dev.as_any().downcast_ref::<Rc<RefCell<pic::Pic8259>>>()
+                })
+                .unwrap()
+                .borrow()
+                .requested_irqs()
+        };
+
+        // Route I/O APIC pin 2 (where the PIT is connected in APIC mode)
+        // to vector 0x30
+        let ioapic = ioapic::IoApic::DEFAULT_BASE;
+        for (reg, val) in [(0x15u8, 0u32), (0x14, 0x30)].iter() {
+            map.on_mem_write(
+                GuestPhysAddr::new(ioapic),
+                MemWriteRequest::new(&[*reg]),
+                define_test_view(),
+            )
+            .unwrap();
+            map.on_mem_write(
+                GuestPhysAddr::new(ioapic + 0x10),
+                MemWriteRequest::new(&val.to_be_bytes()),
+                define_test_view(),
+            )
+            .unwrap();
+        }
+
+        // Program PIT channel 0 for a ~1ms period
+        replay(&mut map, &Out(0x43, 1, 0x34)).unwrap();
+        replay(&mut map, &Out(0x40, 1, 0xa9)).unwrap();
+        replay(&mut map, &Out(0x40, 1, 0x04)).unwrap();
+
+        // At reset, the timer interrupt goes to the PIC
+        clock.advance(1_000_000);
+        map.poll_all(clock.now_ns());
+        assert_eq!(pic_irqs(&map), 1);
+        assert_eq!(interrupts.pop(0), None);
+
+        // After switching to APIC mode, it goes to the I/O APIC
+        replay(&mut map, &Out(0x22, 1, 0x70)).unwrap();
+        replay(&mut map, &Out(0x23, 1, 0x01)).unwrap();
+        assert_eq!(replay(&mut map, &In(0x23, 1, 0)).unwrap(), Some(1));
+        clock.advance(1_000_000);
+        map.poll_all(clock.now_ns());
+        assert_eq!(
+            interrupts.pop(0),
+            Some((crate::ioapic::DeliveryMode::Fixed, 0x30))
+        );
+    }
+
+    // A device holding a level triggered line
+    #[derive(Default)]
+    struct MockLevelDevice {
+        asserted: Cell<bool>,
+    }
+
+    impl IrqLineState for MockLevelDevice {
+        fn line_asserted(&self, _irq: u8) -> bool {
+            self.asserted.get()
+        }
+    }
+
+    #[test]
+    fn test_platform_level_eoi() {
+        let interrupts = Rc::new(PendingInterrupts::new(&[VcpuApic::new(0)]));
+        let mut builder = PlatformBuilder::new(0, 256);
+        builder.set_interrupt_sink(interrupts.clone());
+        builder.enable_legacy_devices();
+
+        // IRQ0 is connected to GSI2 by default, so the I/O APIC must find
+        // the line behind pin 2 through the override
+        let dev = Rc::new(MockLevelDevice::default());
+        builder.level_irq_lines().attach(0, dev.clone());
+        let mut map = builder.build().unwrap();
+
+        // Pin 2: level triggered, vector 0x51
+        let ioapic = ioapic::IoApic::DEFAULT_BASE;
+        mem_write(&mut map, ioapic, 0x15);
+        mem_write(&mut map, ioapic + 0x10, 0);
+        mem_write(&mut map, ioapic, 0x14);
+        mem_write(&mut map, ioapic + 0x10, 0x8051);
+
+        dev.asserted.set(true);
+        map.device_for(GuestPhysAddr::new(ioapic))
+            .and_then(|dev| {
+                dev.as_any().downcast_ref::<Rc<RefCell<ioapic::IoApic>>>()
+            })
+            .unwrap()
+            .borrow_mut()
+            .raise_irq(2)
+            .unwrap();
+        let fixed = crate::ioapic::DeliveryMode::Fixed;
+        assert_eq!(interrupts.pop(0), Some((fixed, 0x51)));
+
+        // The line is still asserted, so the EOI written by the guest to
+        // the local APIC delivers it again
+        let eoi = 0xfee000b0;
+        mem_write(&mut map, eoi, 0);
+        assert_eq!(interrupts.pop(0), Some((fixed, 0x51)));
+
+        // Once the line is deasserted, the EOI just clears the remote IRR
+        dev.asserted.set(false);
+        mem_write(&mut map, eoi, 0);
+        assert_eq!(interrupts.pop(0), None);
+        mem_write(&mut map, ioapic, 0x14);
+        assert_eq!(mem_read(&mut map, ioapic + 0x10) & 1 << 14, 0);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::device::{
+    DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortValue, PortWidth,
+    PortWriteRequest,
+};
+use crate::error::{Error, Result};
+use crate::memory::GuestAddressSpaceViewMut;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+/// Access to the host's I/O ports
+pub trait HostPortIo {
+    fn read(&self, port: Port, width: PortWidth) -> u32;
+    fn write(&self, port: Port, val: PortValue);
+}
+
+/// The real host I/O ports, accessed with `in` and `out`
+///
+/// Passing host ports through to a guest lets it drive that hardware
+/// directly, so this is only available with the `port-passthrough`
+/// feature.
+#[cfg(feature = "port-passthrough")]
+pub struct HardwarePortIo;
+
+#[cfg(feature = "port-passthrough")]
+impl HostPortIo for HardwarePortIo {
+    fn read(&self, port: Port, width: PortWidth) -> u32 {
+        use x86::io::{inb, inl, inw};
+        unsafe {
+            match width {
+                PortWidth::Byte => inb(port) as u32,
+                PortWidth::Word => inw(port) as u32,
+                PortWidth::DWord => inl(port),
+            }
+        }
+    }
+
+    fn write(&self, port: Port, val: PortValue) {
+        use x86::io::{outb, outl, outw};
+        unsafe {
+            match val {
+                PortValue::U8(val) => outb(port, val),
+                PortValue::U16(val) => outw(port, val),
+                PortValue::U32(val) => outl(port, val),
+            }
+        }
+    }
+}
+
+/// A device that forwards guest accesses to a range of host I/O ports
+pub struct PortProxyDevice {
+    range: RangeInclusive<Port>,
+    io: Box<dyn HostPortIo>,
+}
+
+impl PortProxyDevice {
+    /// Pass the host ports in `range` through to the guest
+    #[cfg(feature = "port-passthrough")]
+    pub fn new(range: RangeInclusive<Port>) -> Result<Box<Self>> {
+        Self::with_io(range, Box::new(HardwarePortIo))
+    }
+
+    /// Forward accesses to the ports in `range` to `io`
+    pub fn with_io(
+        range: RangeInclusive<Port>,
+        io: Box<dyn HostPortIo>,
+    ) -> Result<Box<Self>> {
+        if range.start() > range.end() {
+            return Err(Error::InvalidValue(format!(
+                "Empty port proxy range: {:?}",
+                range
+            )));
+        }
+        Ok(Box::new(Self { range, io }))
+    }
+
+    // Check that every port touched by an access is in the proxied range
+    fn check_access(&self, port: Port, len: usize) -> Result<()> {
+        let last = port as usize + len - 1;
+        if !self.range.contains(&port) || last > *self.range.end() as usize {
+            return Err(Error::InvalidValue(format!(
+                "Port access 0x{:x}-0x{:x} is outside the proxied range {:?}",
+                port, last, self.range
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl EmulatedDevice for PortProxyDevice {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::PortIo(self.range.clone())]
+    }
+
+    fn on_port_read(
+        &mut self,
+        port: Port,
+        mut val: PortReadRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.check_access(port, val.as_slice().len())?;
+        val.copy_from_u32(self.io.read(port, val.width()));
+        Ok(())
+    }
+
+    fn on_port_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.check_access(port, val.as_slice().len())?;
+        self.io.write(port, val.value());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::define_test_view;
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    #[derive(Default)]
+    struct MockPortIo {
+        reads: RefCell<Vec<(Port, PortWidth)>>,
+        writes: RefCell<Vec<(Port, PortValue)>>,
+    }
+
+    impl HostPortIo for Rc<MockPortIo> {
+        fn read(&self, port: Port, width: PortWidth) -> u32 {
+            self.reads.borrow_mut().push((port, width));
+            0x1234_5678 + port as u32
+        }
+
+        fn write(&self, port: Port, val: PortValue) {
+            self.writes.borrow_mut().push((port, val));
+        }
+    }
+
+    #[test]
+    fn test_port_proxy_read() {
+        let io = Rc::new(MockPortIo::default());
+        let mut proxy =
+            PortProxyDevice::with_io(0x378..=0x37a, Box::new(io.clone()))
+                .unwrap();
+
+        let mut arr = [0u8; 2];
+        proxy
+            .on_port_read(
+                0x378,
+                PortReadRequest::TwoBytes(&mut arr),
+                define_test_view(),
+            )
+            .unwrap();
+        assert_eq!(u16::from_be_bytes(arr), 0x59f0);
+        assert_eq!(*io.reads.borrow(), [(0x378, PortWidth::Word)]);
+    }
+
+    #[test]
+    fn test_port_proxy_write() {
+        let io = Rc::new(MockPortIo::default());
+        let mut proxy =
+            PortProxyDevice::with_io(0x378..=0x37a, Box::new(io.clone()))
+                .unwrap();
+
+        proxy
+            .on_port_write(
+                0x37a,
+                PortWriteRequest::OneByte(&[0x0c]),
+                define_test_view(),
+            )
+            .unwrap();
+        assert_eq!(*io.writes.borrow(), [(0x37a, PortValue::U8(0x0c))]);
+    }
+
+    #[test]
+    fn test_port_proxy_validates_range() {
+        let io = Rc::new(MockPortIo::default());
+        assert!(
+            PortProxyDevice::with_io(0x37a..=0x378, Box::new(io.clone()))
+                .is_err()
+        );
+
+        // A dword access at 0x379 would touch 0x37b and 0x37c
+        let mut proxy =
+            PortProxyDevice::with_io(0x378..=0x37a, Box::new(io.clone()))
+                .unwrap();
+        let mut arr = [0u8; 4];
+        assert!(proxy
+            .on_port_read(
+                0x379,
+                PortReadRequest::FourBytes(&mut arr),
+                define_test_view(),
+            )
+            .is_err());
+        assert!(proxy
+            .on_port_write(
+                0x3f8,
+                PortWriteRequest::OneByte(&[0]),
+                define_test_view(),
+            )
+            .is_err());
+        assert!(io.reads.borrow().is_empty());
+        assert!(io.writes.borrow().is_empty());
+    }
+}
@@ -1,30 +1,46 @@
+use crate::device::reset::{ResetSignal, ResetSource};
 use crate::device::{
     DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
 };
 use crate::error::Result;
 use crate::memory::GuestAddressSpaceViewMut;
 use alloc::boxed::Box;
+use alloc::rc::Rc;
 use alloc::vec::Vec;
+use core::convert::TryInto;
 
 #[derive(Default, Debug)]
-pub struct ProgrammableOptionSelect;
+pub struct ProgrammableOptionSelect {
+    reset: Option<Rc<ResetSignal>>,
+
+    // System control port A (without the self clearing reset bit)
+    control_a: u8,
+}
 
 impl ProgrammableOptionSelect {
     const POS_ARBITRATION_CLOCK: Port = 0x90;
     const _POS_CARD_SELECT_FEEDBACK: Port = 0x91;
-    const _POS_CONTROL_AND_STATUS: Port = 0x92;
+    const POS_CONTROL_AND_STATUS: Port = 0x92;
     const _POS_RESERVED_1: Port = 0x93;
     const _POS_BOARD_ENABLE_SETUP: Port = 0x94;
     const _POS_RESERVED_2: Port = 0x95;
     const POS_ADAPTER_ENABLE_SETUP: Port = 0x96;
 
+    // Bit 0 of port 0x92 is the "fast reset" and bit 1 is the A20 gate
+    const CONTROL_A_RESET: u8 = 1 << 0;
+
     pub fn new() -> Box<Self> {
         Box::new(ProgrammableOptionSelect::default())
     }
+
+    /// Set the signal raised when the guest requests a fast reset
+    pub fn set_reset_signal(&mut self, reset: Rc<ResetSignal>) {
+        self.reset = Some(reset);
+    }
 }
 
-// Currently we don't actually implement any of this, but I don't think we
-// need to either (kvm doesn't seem to)
+// Other than system control port A, we don't actually implement any of
+// this, but I don't think we need to either (kvm doesn't seem to)
 impl EmulatedDevice for ProgrammableOptionSelect {
     fn services(&self) -> Vec<DeviceRegion> {
         vec![DeviceRegion::PortIo(
@@ -32,22 +48,43 @@ impl EmulatedDevice for ProgrammableOptionSelect {
         )]
     }
 
+    fn reset(&mut self) {
+        self.control_a = 0;
+    }
+
     fn on_port_read(
         &mut self,
-        _port: Port,
+        port: Port,
         mut val: PortReadRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
-        val.copy_from_u32(0);
+        match port {
+            Self::POS_CONTROL_AND_STATUS => {
+                val.copy_from_u32(self.control_a as u32)
+            }
+            _ => val.copy_from_u32(0),
+        }
         Ok(())
     }
 
     fn on_port_write(
         &mut self,
-        _port: Port,
-        _val: PortWriteRequest,
+        port: Port,
+        val: PortWriteRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
+        if port != Self::POS_CONTROL_AND_STATUS {
+            return Ok(());
+        }
+
+        let val: u8 = val.try_into()?;
+        if val & Self::CONTROL_A_RESET != 0 {
+            match &self.reset {
+                Some(reset) => reset.request(ResetSource::FastReset),
+                None => info!("Ignoring fast reset request"),
+            }
+        }
+        self.control_a = val & !Self::CONTROL_A_RESET;
         Ok(())
     }
 }
@@ -1,5 +1,6 @@
 use crate::device::{
-    DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
+    validate_dma_range, DeviceRegion, EmulatedDevice, Port, PortReadRequest,
+    PortWriteRequest,
 };
 use crate::error::{Error, Result};
 use crate::memory::{
@@ -54,6 +55,15 @@ pub mod FwCfgSelector {
 
 const FW_CFG_MAX_FILE_NAME: usize = 55;
 
+/// The fw_cfg file holding the SMBIOS entry point structure
+pub const SMBIOS_ANCHOR_FILE: &str = "etc/smbios/smbios-anchor";
+
+/// The fw_cfg file holding the SMBIOS structure table
+pub const SMBIOS_TABLES_FILE: &str = "etc/smbios/smbios-tables";
+
+/// The fw_cfg file holding the firmware boot order
+pub const BOOT_ORDER_FILE: &str = "bootorder";
+
 #[repr(C)]
 struct FWCfgFile {
     size: u32,
@@ -174,13 +184,11 @@ impl QemuFwCfgBuilder {
     }
 
     fn next_file_selector(&self) -> u16 {
-        //TODO: this should only consider keys below 0x8000
         self.data
-            .keys()
-            .copied()
-            .max()
+            .range(FwCfgSelector::FILE_FIRST..=FwCfgSelector::FILE_LAST)
+            .next_back()
+            .map(|(selector, _)| selector + 1)
             .unwrap_or(FwCfgSelector::FILE_FIRST)
-            + 1
     }
 
     pub fn add_file(
@@ -216,6 +224,51 @@ impl QemuFwCfgBuilder {
         Ok(())
     }
 
+    /// Provide SMBIOS tables to the guest firmware
+    ///
+    /// `entry_point` is either a 32-bit ("_SM_") or 64-bit ("_SM3_")
+    /// entry point structure, and `tables` the structure table it
+    /// describes. The firmware fixes up the table address when it
+    /// installs them.
+    pub fn add_smbios_table(
+        &mut self,
+        entry_point: &[u8],
+        tables: &[u8],
+    ) -> Result<()> {
+        let valid = (entry_point.starts_with(b"_SM_")
+            && entry_point.len() == 0x1f)
+            || (entry_point.starts_with(b"_SM3_") && entry_point.len() == 0x18);
+        if !valid {
+            return Err(Error::InvalidValue(format!(
+                "qemu_fw_cfg: invalid SMBIOS entry point ({} bytes)",
+                entry_point.len()
+            )));
+        }
+        if tables.is_empty() {
+            return Err(Error::InvalidValue(
+                "qemu_fw_cfg: empty SMBIOS structure table".into(),
+            ));
+        }
+        self.add_file(SMBIOS_ANCHOR_FILE, entry_point)?;
+        self.add_file(SMBIOS_TABLES_FILE, tables)
+    }
+
+    /// Set the order in which the firmware should try boot devices
+    ///
+    /// Each entry is a firmware device path (e.g., "/pci@i0cf8/ide@1,1/
+    /// drive@0/disk@0"). The paths are stored newline separated, with a
+    /// NUL terminator.
+    pub fn set_boot_order(&mut self, devices: &[&str]) -> Result<()> {
+        if devices.iter().any(|dev| dev.contains('\n')) {
+            return Err(Error::InvalidValue(
+                "qemu_fw_cfg: boot device paths cannot contain newlines".into(),
+            ));
+        }
+        let mut data = devices.join("\n").into_bytes();
+        data.push(0);
+        self.add_file(BOOT_ORDER_FILE, &data)
+    }
+
     pub fn add_i32(&mut self, selector: u16, data: i32) {
         self.data.insert(selector, data.to_le_bytes().to_vec());
     }
@@ -242,6 +295,12 @@ impl QemuFwCfg {
         &mut self,
         mut space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
+        validate_dma_range(
+            &space,
+            GuestPhysAddr::new(self.dma_addr),
+            core::mem::size_of::<RawFWCfgDmaAccess>(),
+            GuestAccess::Write(PrivilegeLevel(0)),
+        )?;
         let bytes = space.read_bytes(
             GuestVirtAddr::NoPaging(GuestPhysAddr::new(self.dma_addr)),
             core::mem::size_of::<RawFWCfgDmaAccess>(),
@@ -265,6 +324,21 @@ impl QemuFwCfg {
 
         if request.control.contains(DmaControlFlags::READ) {
             match self.read_selector(request.length as usize) {
+                Some(data)
+                    if validate_dma_range(
+                        &space,
+                        GuestPhysAddr::new(request.address),
+                        data.len(),
+                        GuestAccess::Write(PrivilegeLevel(0)),
+                    )
+                    .is_err() =>
+                {
+                    warn!(
+                        "qemu_fw_cfg: invalid DMA target 0x{:x}",
+                        request.address
+                    );
+                    request.control = DmaControlFlags::ERROR.into();
+                }
                 Some(data) => {
                     space.write_bytes(
                         GuestVirtAddr::NoPaging(GuestPhysAddr::new(
@@ -339,9 +413,7 @@ impl EmulatedDevice for QemuFwCfg {
             Self::FW_CFG_PORT_DATA => {
                 let data = self.read_selector(len);
                 match data {
-                    Some(data) => {
-                        val.as_mut_slice().copy_from_slice(data);
-                    }
+                    Some(data) => val.fill_from_slice(data)?,
                     None => {
                         info!(
                             "Attempt to read from selector: 0x{:x}",
@@ -396,3 +468,101 @@ impl EmulatedDevice for QemuFwCfg {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::define_test_view;
+
+    fn read_item(fw_cfg: &mut QemuFwCfg, selector: u16, len: usize) -> Vec<u8> {
+        fw_cfg
+            .on_port_write(
+                QemuFwCfg::FW_CFG_PORT_SEL,
+                PortWriteRequest::TwoBytes(&selector.to_be_bytes()),
+                define_test_view(),
+            )
+            .unwrap();
+        (0..len)
+            .map(|_| {
+                let mut arr = [0u8];
+                fw_cfg
+                    .on_port_read(
+                        QemuFwCfg::FW_CFG_PORT_DATA,
+                        PortReadRequest::OneByte(&mut arr),
+                        define_test_view(),
+                    )
+                    .unwrap();
+                arr[0]
+            })
+            .collect()
+    }
+
+    // Find a file in the directory, returning its selector and size
+    fn find_file(fw_cfg: &mut QemuFwCfg, name: &str) -> Option<(u16, usize)> {
+        let count = read_item(fw_cfg, FwCfgSelector::FILE_DIR, 4);
+        let count = u32::from_be_bytes(count[..].try_into().unwrap()) as usize;
+        let entry_len = core::mem::size_of::<FWCfgFile>();
+        let dir =
+            read_item(fw_cfg, FwCfgSelector::FILE_DIR, 4 + count * entry_len);
+        dir[4..].chunks(entry_len).find_map(|entry| {
+            let entry_name = &entry[8..8 + name.len() + 1];
+            if &entry_name[..name.len()] != name.as_bytes()
+                || entry_name[name.len()] != 0
+            {
+                return None;
+            }
+            let size = u32::from_be_bytes(entry[..4].try_into().unwrap());
+            let select = u16::from_be_bytes(entry[4..6].try_into().unwrap());
+            Some((select, size as usize))
+        })
+    }
+
+    fn smbios_entry_point() -> Vec<u8> {
+        let mut entry = vec![0u8; 0x1f];
+        entry[..4].copy_from_slice(b"_SM_");
+        entry[0x10..0x15].copy_from_slice(b"_DMI_");
+        entry
+    }
+
+    #[test]
+    fn test_smbios_files() {
+        let entry = smbios_entry_point();
+        let tables = [0x7f, 0x04, 0x00, 0x00, 0x00, 0x00];
+        let mut builder = QemuFwCfgBuilder::new();
+        builder.add_smbios_table(&entry, &tables).unwrap();
+        let mut fw_cfg = builder.build();
+
+        let (selector, size) =
+            find_file(&mut fw_cfg, SMBIOS_ANCHOR_FILE).unwrap();
+        assert_eq!(size, 0x1f);
+        assert_eq!(read_item(&mut fw_cfg, selector, size), entry);
+
+        let (selector, size) =
+            find_file(&mut fw_cfg, SMBIOS_TABLES_FILE).unwrap();
+        assert_eq!(size, tables.len());
+        assert_eq!(read_item(&mut fw_cfg, selector, size), tables);
+
+        let mut builder = QemuFwCfgBuilder::new();
+        assert!(builder.add_smbios_table(&entry[..0x18], &tables).is_err());
+        assert!(builder.add_smbios_table(&entry, &[]).is_err());
+    }
+
+    #[test]
+    fn test_boot_order_file() {
+        let mut builder = QemuFwCfgBuilder::new();
+        builder
+            .set_boot_order(&["/pci@i0cf8/ide@1,1/drive@0/disk@0", "HALT"])
+            .unwrap();
+        let mut fw_cfg = builder.build();
+
+        let expected = b"/pci@i0cf8/ide@1,1/drive@0/disk@0\nHALT\0";
+        let (selector, size) = find_file(&mut fw_cfg, BOOT_ORDER_FILE).unwrap();
+        assert!(selector >= FwCfgSelector::FILE_FIRST);
+        assert_eq!(size, expected.len());
+        assert_eq!(read_item(&mut fw_cfg, selector, size), &expected[..]);
+        assert_eq!(find_file(&mut fw_cfg, SMBIOS_ANCHOR_FILE), None);
+
+        let mut builder = QemuFwCfgBuilder::new();
+        assert!(builder.set_boot_order(&["bad\npath"]).is_err());
+    }
+}
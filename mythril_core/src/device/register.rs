@@ -0,0 +1,301 @@
+use crate::device::require_len;
+use crate::error::{Error, Result};
+use core::fmt;
+
+/// A named register in a `RegisterBlock`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RegisterInfo {
+    pub name: &'static str,
+
+    /// The offset of the register from the start of the block
+    pub offset: u16,
+
+    /// The width of the register in bytes
+    pub width: u8,
+}
+
+impl RegisterInfo {
+    pub const fn new(name: &'static str, offset: u16, width: u8) -> Self {
+        Self {
+            name,
+            offset,
+            width,
+        }
+    }
+}
+
+/// The registers of a device, for inspection and debugging
+///
+/// Unlike a guest access, reading a register through this trait has no
+/// side effects (e.g., reading an interrupt status register does not
+/// acknowledge the interrupt), and writing one simply updates the stored
+/// value.
+pub trait RegisterBlock {
+    /// The registers in this block, in the order they should be displayed
+    fn registers(&self) -> &'static [RegisterInfo];
+
+    /// The current value of the register at `offset`
+    fn read_reg(&self, offset: u16) -> u32;
+
+    /// Set the bits of the register at `offset` that are set in `mask` to
+    /// the corresponding bits of `val`
+    fn write_reg(&mut self, offset: u16, val: u32, mask: u32);
+
+    /// A view of this block that formats as the value of each register
+    fn dump(&self) -> RegisterDump<Self>
+    where
+        Self: Sized,
+    {
+        RegisterDump(self)
+    }
+}
+
+/// Check that an access of `len` bytes at `offset` is to exactly one of
+/// `registers`
+///
+/// Returns false if there is no register at `offset`. An access that starts
+/// within a register, or is not as wide as the register, is an error.
+pub fn check_register_access(
+    registers: &[RegisterInfo],
+    offset: u64,
+    len: usize,
+) -> Result<bool> {
+    let reg = match registers.iter().find(|reg| {
+        offset >= reg.offset as u64
+            && offset < reg.offset as u64 + reg.width as u64
+    }) {
+        Some(reg) => reg,
+        None => return Ok(false),
+    };
+    if offset != reg.offset as u64 {
+        return Err(Error::InvalidValue(format!(
+            "Misaligned access to {} at offset 0x{:x}",
+            reg.name, offset
+        )));
+    }
+    let expected: &'static [usize] = match reg.width {
+        1 => &[1],
+        2 => &[2],
+        4 => &[4],
+        _ => &[8],
+    };
+    require_len(len, expected)?;
+    Ok(true)
+}
+
+/// Define a file of memory mapped registers, each at a fixed offset with
+/// a fixed width
+///
+/// ```ignore
+/// mmio_regs! {
+///     struct TimerRegisters {
+///         0x00 => control: u32,
+///         0x08 => counter: u64,
+///     }
+/// }
+/// ```
+///
+/// The offsets may be literals or constants. This generates a struct with
+/// a field for each register, with `read`
+/// and `write` methods that dispatch a guest access by its offset (checking
+/// the alignment and width with `check_register_access`). Values are
+/// transferred in the same (big-endian) byte order as the other request
+/// helpers. `Debug` shows the value of each register in hex.
+#[macro_export]
+macro_rules! mmio_regs {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($offset:expr => $field:ident: $ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Default, PartialEq)]
+        $vis struct $name {
+            $(pub $field: $ty,)*
+        }
+
+        impl $name {
+            /// The registers, in the order they were declared
+            pub const REGISTERS: &'static [
+                $crate::device::register::RegisterInfo
+            ] = &[
+                $($crate::device::register::RegisterInfo::new(
+                    stringify!($field),
+                    $offset as u16,
+                    core::mem::size_of::<$ty>() as u8,
+                ),)*
+            ];
+
+            /// Read the register at `offset` into `data`, returning false
+            /// if there is no register at `offset`
+            pub fn read(
+                &self,
+                offset: u64,
+                data: &mut $crate::device::MemReadRequest,
+            ) -> $crate::error::Result<bool> {
+                if !$crate::device::register::check_register_access(
+                    Self::REGISTERS,
+                    offset,
+                    data.as_slice().len(),
+                )? {
+                    return Ok(false);
+                }
+                $(if offset == $offset as u64 {
+                    data.fill_from_slice(&self.$field.to_be_bytes())?;
+                    return Ok(true);
+                })*
+                Ok(false)
+            }
+
+            /// Write `data` to the register at `offset`, returning false if
+            /// there is no register at `offset`
+            pub fn write(
+                &mut self,
+                offset: u64,
+                data: &$crate::device::MemWriteRequest,
+            ) -> $crate::error::Result<bool> {
+                if !$crate::device::register::check_register_access(
+                    Self::REGISTERS,
+                    offset,
+                    data.as_slice().len(),
+                )? {
+                    return Ok(false);
+                }
+                $(if offset == $offset as u64 {
+                    let mut arr = <$ty>::default().to_be_bytes();
+                    arr.copy_from_slice(data.as_slice());
+                    self.$field = <$ty>::from_be_bytes(arr);
+                    return Ok(true);
+                })*
+                Ok(false)
+            }
+        }
+
+        impl core::fmt::Debug for $name {
+            fn fmt(
+                &self,
+                f: &mut core::fmt::Formatter<'_>,
+            ) -> core::fmt::Result {
+                f.debug_struct(stringify!($name))
+                    $(.field(
+                        stringify!($field),
+                        &format_args!("{:#x}", self.$field),
+                    ))*
+                    .finish()
+            }
+        }
+    };
+}
+
+/// Apply a masked write of `val` to the register value `old`
+pub fn masked_write(old: u32, val: u32, mask: u32) -> u32 {
+    (old & !mask) | (val & mask)
+}
+
+/// The named register values of a `RegisterBlock`
+///
+/// `Display` shows one register per line (`LCR (0x03) = 0x03`), and `Debug`
+/// shows a map from register name to value.
+pub struct RegisterDump<'a, T: ?Sized>(&'a T);
+
+impl<'a, T: RegisterBlock + ?Sized> fmt::Display for RegisterDump<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for reg in self.0.registers() {
+            writeln!(
+                f,
+                "{} (0x{:02x}) = 0x{:0width$x}",
+                reg.name,
+                reg.offset,
+                self.0.read_reg(reg.offset),
+                width = reg.width as usize * 2
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, T: RegisterBlock + ?Sized> fmt::Debug for RegisterDump<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(
+                self.0
+                    .registers()
+                    .iter()
+                    .map(|reg| (reg.name, self.0.read_reg(reg.offset))),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::{MemReadRequest, MemWriteRequest};
+    use alloc::vec::Vec;
+
+    mmio_regs! {
+        struct TestRegisters {
+            0x00 => control: u32,
+            0x04 => status: u8,
+            0x06 => index: u16,
+            0x08 => counter: u64,
+        }
+    }
+
+    fn read(regs: &TestRegisters, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut buff = vec![0xaa; len];
+        regs.read(offset, &mut MemReadRequest::new(&mut buff))
+            .map(|found| if found { buff } else { vec![] })
+    }
+
+    #[test]
+    fn test_mmio_regs_dispatch() {
+        let mut regs = TestRegisters::default();
+        assert!(regs
+            .write(0x00, &MemWriteRequest::new(&0x12345678u32.to_be_bytes()))
+            .unwrap());
+        assert!(regs.write(0x04, &MemWriteRequest::new(&[0x9a])).unwrap());
+        assert!(regs
+            .write(0x06, &MemWriteRequest::new(&0xbcdeu16.to_be_bytes()))
+            .unwrap());
+        assert!(regs
+            .write(0x08, &MemWriteRequest::new(&u64::MAX.to_be_bytes()))
+            .unwrap());
+        assert_eq!(regs.control, 0x12345678);
+        assert_eq!(regs.status, 0x9a);
+        assert_eq!(regs.index, 0xbcde);
+        assert_eq!(regs.counter, u64::MAX);
+
+        assert_eq!(read(&regs, 0x00, 4).unwrap(), 0x12345678u32.to_be_bytes());
+        assert_eq!(read(&regs, 0x04, 1).unwrap(), [0x9a]);
+        assert_eq!(read(&regs, 0x06, 2).unwrap(), [0xbc, 0xde]);
+        assert_eq!(read(&regs, 0x08, 8).unwrap(), [0xff; 8]);
+
+        // There is no register at offset 5
+        assert_eq!(read(&regs, 0x05, 1).unwrap(), vec![]);
+        assert!(!regs.write(0x10, &MemWriteRequest::new(&[0; 4])).unwrap());
+
+        assert_eq!(
+            format!("{:?}", regs),
+            "TestRegisters { control: 0x12345678, status: 0x9a, \
+             index: 0xbcde, counter: 0xffffffffffffffff }"
+        );
+    }
+
+    #[test]
+    fn test_mmio_regs_access_checks() {
+        let mut regs = TestRegisters::default();
+        assert!(matches!(read(&regs, 0x02, 2), Err(Error::InvalidValue(_))));
+        assert!(regs.write(0x09, &MemWriteRequest::new(&[0; 4])).is_err());
+        assert_eq!(
+            read(&regs, 0x08, 4),
+            Err(Error::AccessWidth {
+                expected: &[8],
+                actual: 4
+            })
+        );
+        assert!(regs.write(0x00, &MemWriteRequest::new(&[0; 2])).is_err());
+        assert_eq!(regs, TestRegisters::default());
+    }
+}
@@ -0,0 +1,292 @@
+use crate::device::rtc::CmosNvram;
+use crate::device::{
+    DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
+};
+use crate::error::Result;
+use crate::memory::GuestAddressSpaceViewMut;
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::convert::TryInto;
+
+/// The way in which the guest requested a reset
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResetSource {
+    /// The 8042 "pulse output port" command (0xFE)
+    KeyboardController,
+
+    /// Bit 0 of the 8042 output port cleared with command 0xD1
+    KeyboardOutputPort,
+
+    /// Bit 0 of system control port A (0x92)
+    FastReset,
+
+    /// The reset control register (0xCF9), which is also the ACPI reset
+    /// register
+    ResetControl,
+
+    /// The expiry of a watchdog timer that was not pet in time
+    Watchdog,
+}
+
+/// The reset line shared by every device that can reset the guest
+///
+/// Devices signal a reset with `request`, and the supervisor polls for it
+/// with `take_reset_request` (and should then reset the vcpus and call
+/// `DeviceMap::reset_all`). A request that has not been taken yet is not
+/// replaced by later ones.
+#[derive(Debug, Default)]
+pub struct ResetSignal {
+    request: Cell<Option<ResetSource>>,
+}
+
+impl ResetSignal {
+    pub fn new() -> Rc<Self> {
+        Rc::new(Self::default())
+    }
+
+    /// Signal that the guest should be reset
+    pub fn request(&self, source: ResetSource) {
+        info!("Guest requested a reset ({:?})", source);
+        if self.request.get().is_none() {
+            self.request.set(Some(source));
+        }
+    }
+
+    /// Returns the pending reset request, if there is one
+    pub fn take_reset_request(&self) -> Option<ResetSource> {
+        self.request.take()
+    }
+}
+
+/// The cause of the most recent reset, as recorded by `ResetReasonLog`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResetReason {
+    PowerOn,
+    Requested(ResetSource),
+}
+
+impl ResetReason {
+    // The value stored in CMOS for each reason
+    fn code(self) -> u8 {
+        match self {
+            ResetReason::PowerOn => 0,
+            ResetReason::Requested(ResetSource::KeyboardController) => 1,
+            ResetReason::Requested(ResetSource::KeyboardOutputPort) => 2,
+            ResetReason::Requested(ResetSource::FastReset) => 3,
+            ResetReason::Requested(ResetSource::ResetControl) => 4,
+            ResetReason::Requested(ResetSource::Watchdog) => 5,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        Some(match code {
+            0 => ResetReason::PowerOn,
+            1 => ResetReason::Requested(ResetSource::KeyboardController),
+            2 => ResetReason::Requested(ResetSource::KeyboardOutputPort),
+            3 => ResetReason::Requested(ResetSource::FastReset),
+            4 => ResetReason::Requested(ResetSource::ResetControl),
+            5 => ResetReason::Requested(ResetSource::Watchdog),
+            _ => return None,
+        })
+    }
+}
+
+/// A record of why the guest was last reset, and how many times it has
+/// booted, kept in CMOS NVRAM for the guest's firmware to read
+///
+/// The reason is stored at CMOS index `REASON_INDEX` (0 for power on,
+/// then 1-5 for the `ResetSource` variants in declaration order) and the
+/// boot count, which wraps at 255, at `BOOT_COUNT_INDEX`. The supervisor
+/// should call `record` with each reset request before calling
+/// `DeviceMap::reset_all`, so the CMOS loads the new values as it resets.
+pub struct ResetReasonLog {
+    nvram: CmosNvram,
+}
+
+impl ResetReasonLog {
+    pub const REASON_INDEX: u8 = 0x7e;
+    pub const BOOT_COUNT_INDEX: u8 = 0x7f;
+
+    pub fn new(nvram: CmosNvram) -> Self {
+        Self { nvram }
+    }
+
+    /// Record a boot caused by `reason`
+    pub fn record(&self, reason: ResetReason) {
+        let mut nvram = self.nvram.borrow_mut();
+        nvram[Self::REASON_INDEX as usize] = reason.code();
+        let count = &mut nvram[Self::BOOT_COUNT_INDEX as usize];
+        *count = count.wrapping_add(1);
+    }
+
+    /// The reason for the last boot, if one has been recorded
+    pub fn last_reason(&self) -> Option<ResetReason> {
+        ResetReason::from_code(self.nvram.borrow()[Self::REASON_INDEX as usize])
+    }
+
+    pub fn boot_count(&self) -> u8 {
+        self.nvram.borrow()[Self::BOOT_COUNT_INDEX as usize]
+    }
+}
+
+/// The reset control register (port 0xCF9)
+///
+/// Writing a value with the RST_CPU bit set resets the guest. The ACPI
+/// tables report this register (with the value 0x06) as the FADT reset
+/// register. The type of reset (SYS_RST and FULL_RST) is not modeled.
+pub struct ResetControl {
+    reset: Rc<ResetSignal>,
+    value: u8,
+}
+
+impl ResetControl {
+    const RESET_CONTROL: Port = 0xcf9;
+
+    const RST_CPU: u8 = 1 << 2;
+
+    pub fn new(reset: Rc<ResetSignal>) -> Box<Self> {
+        Box::new(Self { reset, value: 0 })
+    }
+}
+
+impl EmulatedDevice for ResetControl {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::PortIo(
+            Self::RESET_CONTROL..=Self::RESET_CONTROL,
+        )]
+    }
+
+    fn reset(&mut self) {
+        self.value = 0;
+    }
+
+    fn on_port_read(
+        &mut self,
+        _port: Port,
+        mut val: PortReadRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        val.copy_from_u32(self.value as u32);
+        Ok(())
+    }
+
+    fn on_port_write(
+        &mut self,
+        _port: Port,
+        val: PortWriteRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let val: u8 = val.try_into()?;
+        if val & Self::RST_CPU != 0 {
+            self.reset.request(ResetSource::ResetControl);
+        }
+        // RST_CPU is self clearing
+        self.value = val & !Self::RST_CPU;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::define_test_view;
+
+    #[test]
+    fn test_first_request_is_kept() {
+        let reset = ResetSignal::new();
+        reset.request(ResetSource::FastReset);
+        reset.request(ResetSource::ResetControl);
+        assert_eq!(reset.take_reset_request(), Some(ResetSource::FastReset));
+        assert_eq!(reset.take_reset_request(), None);
+    }
+
+    #[test]
+    fn test_reset_control_register() {
+        let reset = ResetSignal::new();
+        let mut dev = ResetControl::new(reset.clone());
+        for val in [0x02u8, 0x06].iter() {
+            let arr = [*val];
+            dev.on_port_write(
+                ResetControl::RESET_CONTROL,
+                PortWriteRequest::OneByte(&arr),
+                define_test_view(),
+            )
+            .unwrap();
+        }
+        assert_eq!(reset.take_reset_request(), Some(ResetSource::ResetControl));
+
+        // RST_CPU reads back as clear
+        let mut arr = [0u8];
+        dev.on_port_read(
+            ResetControl::RESET_CONTROL,
+            PortReadRequest::OneByte(&mut arr),
+            define_test_view(),
+        )
+        .unwrap();
+        assert_eq!(arr[0], 0x02);
+    }
+
+    #[test]
+    fn test_watchdog_reset_reason() {
+        use crate::device::rtc::CmosRtc;
+        use crate::device::watchdog::Watchdog;
+        use crate::time::{ClockSource, FixedClock};
+        use core::cell::RefCell;
+
+        let clock = Rc::new(FixedClock::new(0));
+        let nvram: CmosNvram = Rc::new(RefCell::new([0u8; CmosRtc::CMOS_SIZE]));
+        let log = ResetReasonLog::new(nvram.clone());
+        log.record(ResetReason::PowerOn);
+        let mut rtc = CmosRtc::with_clock(256, clock.clone(), 0);
+        rtc.set_nvram(nvram);
+
+        let read_cmos = |rtc: &mut CmosRtc, index: u8| {
+            rtc.on_port_write(
+                0x70,
+                PortWriteRequest::OneByte(&[index]),
+                define_test_view(),
+            )
+            .unwrap();
+            let mut arr = [0u8];
+            rtc.on_port_read(
+                0x71,
+                PortReadRequest::OneByte(&mut arr),
+                define_test_view(),
+            )
+            .unwrap();
+            arr[0]
+        };
+        assert_eq!(read_cmos(&mut rtc, ResetReasonLog::REASON_INDEX), 0);
+        assert_eq!(read_cmos(&mut rtc, ResetReasonLog::BOOT_COUNT_INDEX), 1);
+
+        // Let the watchdog expire
+        let reset = ResetSignal::new();
+        let mut dog = Watchdog::with_clock(
+            Watchdog::DEFAULT_BASE,
+            reset.clone(),
+            clock.clone(),
+        );
+        dog.on_port_write(
+            Watchdog::DEFAULT_BASE + 4,
+            PortWriteRequest::OneByte(&[Watchdog::CONTROL_ENABLE]),
+            define_test_view(),
+        )
+        .unwrap();
+        clock.advance(2_000_000_000);
+        dog.poll(clock.now_ns());
+
+        let source = reset.take_reset_request().unwrap();
+        log.record(ResetReason::Requested(source));
+        rtc.reset();
+
+        assert_eq!(
+            log.last_reason(),
+            Some(ResetReason::Requested(ResetSource::Watchdog))
+        );
+        assert_eq!(log.boot_count(), 2);
+        assert_eq!(read_cmos(&mut rtc, ResetReasonLog::REASON_INDEX), 5);
+        assert_eq!(read_cmos(&mut rtc, ResetReasonLog::BOOT_COUNT_INDEX), 2);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::device::{
+    DeviceRegion, EmulatedDevice, MemReadRequest, MemWriteRequest,
+};
+use crate::error::{Error, Result};
+use crate::memory::{GuestAddressSpaceViewMut, GuestPhysAddr};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+/// A read-only memory region backed by a fixed image (e.g., an option ROM)
+///
+/// Reads beyond the end of the image return zeros. By default, writes are
+/// silently dropped, but the device can be configured to fail them instead.
+pub struct RomDevice {
+    region: RangeInclusive<GuestPhysAddr>,
+    image: Vec<u8>,
+    fault_on_write: bool,
+}
+
+impl RomDevice {
+    pub fn new(
+        region: RangeInclusive<GuestPhysAddr>,
+        image: Vec<u8>,
+    ) -> Box<Self> {
+        Box::new(Self {
+            region,
+            image,
+            fault_on_write: false,
+        })
+    }
+
+    /// Configure whether writes to the ROM return an error
+    pub fn set_fault_on_write(&mut self, fault: bool) {
+        self.fault_on_write = fault;
+    }
+
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    pub fn region(&self) -> &RangeInclusive<GuestPhysAddr> {
+        &self.region
+    }
+}
+
+impl EmulatedDevice for RomDevice {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::MemIo(self.region.clone())]
+    }
+
+    fn on_mem_read(
+        &mut self,
+        addr: GuestPhysAddr,
+        mut data: MemReadRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let offset = (addr.as_u64() - self.region.start().as_u64()) as usize;
+        for (i, byte) in data.as_mut_slice().iter_mut().enumerate() {
+            *byte = self.image.get(offset + i).copied().unwrap_or(0);
+        }
+        Ok(())
+    }
+
+    fn on_mem_write(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemWriteRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        if self.fault_on_write {
+            return Err(Error::InvalidValue(format!(
+                "Attempt to write {:?} to ROM at {:?}",
+                data, addr
+            )));
+        }
+        info!("Ignoring write of {:?} to ROM at {:?}", data, addr);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::define_test_view;
+
+    fn test_rom() -> Box<RomDevice> {
+        let region = GuestPhysAddr::new(0xc0000)..=GuestPhysAddr::new(0xc0fff);
+        RomDevice::new(region, vec![0x55, 0xaa, 0x10, 0x20, 0x30, 0x40])
+    }
+
+    fn read_rom(rom: &mut RomDevice, addr: u64, len: usize) -> Vec<u8> {
+        let mut buff = vec![0xffu8; len];
+        let request = MemReadRequest::new(&mut buff);
+        rom.on_mem_read(GuestPhysAddr::new(addr), request, define_test_view())
+            .unwrap();
+        buff
+    }
+
+    #[test]
+    fn test_rom_read() {
+        let mut rom = test_rom();
+        assert_eq!(read_rom(&mut rom, 0xc0000, 2), vec![0x55, 0xaa]);
+        assert_eq!(
+            read_rom(&mut rom, 0xc0002, 4),
+            vec![0x10, 0x20, 0x30, 0x40]
+        );
+    }
+
+    #[test]
+    fn test_rom_read_past_image() {
+        let mut rom = test_rom();
+        assert_eq!(read_rom(&mut rom, 0xc0004, 4), vec![0x30, 0x40, 0, 0]);
+        assert_eq!(read_rom(&mut rom, 0xc0800, 4), vec![0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_rom_write() {
+        let mut rom = test_rom();
+        let data = [0u8; 2];
+        let addr = GuestPhysAddr::new(0xc0000);
+
+        let request = MemWriteRequest::new(&data);
+        assert!(rom.on_mem_write(addr, request, define_test_view()).is_ok());
+        assert_eq!(read_rom(&mut rom, 0xc0000, 2), vec![0x55, 0xaa]);
+
+        rom.set_fault_on_write(true);
+        let request = MemWriteRequest::new(&data);
+        assert!(rom.on_mem_write(addr, request, define_test_view()).is_err());
+        assert_eq!(read_rom(&mut rom, 0xc0000, 2), vec![0x55, 0xaa]);
+    }
+}
@@ -1,10 +1,15 @@
+use crate::device::interrupt::IrqSink;
 use crate::device::{
-    DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
+    require_len, DeviceKind, DeviceRegion, EmulatedDevice, Port,
+    PortReadRequest, PortWriteRequest,
 };
 use crate::error::Result;
 use crate::memory::GuestAddressSpaceViewMut;
+use crate::time::{ClockSource, SystemClock, TimeDiagnostics};
 use alloc::boxed::Box;
+use alloc::rc::Rc;
 use alloc::vec::Vec;
+use core::cell::RefCell;
 use core::convert::TryInto;
 use derive_try_from_primitive::TryFromPrimitive;
 
@@ -66,65 +71,443 @@ enum CmosRegister {
     Unknown = 0xff,
 }
 
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// A broken down (UTC) calendar time, as stored in the CMOS registers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct RtcTime {
+    pub(crate) second: u8,
+    pub(crate) minute: u8,
+    pub(crate) hour: u8,
+    pub(crate) day_of_week: u8, // 1 = Sunday
+    pub(crate) day_of_month: u8,
+    pub(crate) month: u8,
+    pub(crate) year: u16,
+}
+
+impl RtcTime {
+    // The conversions between days and civil dates are based on the
+    // algorithms from http://howardhinnant.github.io/date_algorithms.html
+    fn from_unix(secs: i64) -> Self {
+        let days = secs.div_euclid(SECS_PER_DAY);
+        let rem = secs.rem_euclid(SECS_PER_DAY);
+
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            second: (rem % 60) as u8,
+            minute: ((rem / 60) % 60) as u8,
+            hour: (rem / 3600) as u8,
+            // January 1st 1970 was a Thursday
+            day_of_week: ((days + 4).rem_euclid(7) + 1) as u8,
+            day_of_month: day as u8,
+            month: month as u8,
+            year: year as u16,
+        }
+    }
+
+    pub(crate) fn to_unix(&self) -> i64 {
+        let month = self.month as i64;
+        let year = self.year as i64 - if month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let mp = if month > 2 { month - 3 } else { month + 9 };
+        let doy = (153 * mp + 2) / 5 + self.day_of_month as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+
+        days * SECS_PER_DAY
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64
+    }
+}
+
+/// A host provided buffer backing the battery-backed CMOS RAM
+///
+/// The buffer covers the full 128 byte CMOS, but only the NVRAM bytes
+/// (`CmosRtc::NVRAM_START` onwards) are stored in it.
+pub type CmosNvram = Rc<RefCell<[u8; CmosRtc::CMOS_SIZE]>>;
+
+/// The CMOS real time clock and NVRAM
+///
+/// The index and data ports are byte wide, and any wider access fails
+/// with `Error::AccessWidth`.
 pub struct CmosRtc {
-    addr: CmosRegister,
+    addr: u8,
     data: [u8; 256],
+    clock: Rc<dyn ClockSource>,
+    mem: u64,
+    nvram: Option<CmosNvram>,
+    irq: Option<Rc<dyn IrqSink>>,
+
+    // The unix time (in seconds) when `clock` reads zero
+    base_time: i64,
+
+    // The clock times (in nanoseconds) of the next periodic interrupt and
+    // update cycle
+    next_periodic: Option<u64>,
+    next_update: Option<u64>,
 }
 
 impl CmosRtc {
     const RTC_ADDRESS: Port = 0x0070;
     const RTC_DATA: Port = 0x0071;
 
+    const STATUS_B_24_HOUR: u8 = 1 << 1;
+    const STATUS_B_BINARY: u8 = 1 << 2;
+    const HOUR_PM: u8 = 1 << 7;
+    const NMI_DISABLE: u8 = 1 << 7;
+
+    const STATUS_A_RATE_MASK: u8 = 0x0f;
+    const STATUS_B_UIE: u8 = 1 << 4;
+    const STATUS_B_PIE: u8 = 1 << 6;
+    const STATUS_C_UF: u8 = 1 << 4;
+    const STATUS_C_PF: u8 = 1 << 6;
+    const STATUS_C_IRQF: u8 = 1 << 7;
+
+    const RTC_IRQ: u8 = 8;
+    const NS_PER_SEC: u64 = 1_000_000_000;
+
+    /// The number of bytes of CMOS RAM (including the clock registers)
+    pub const CMOS_SIZE: usize = 128;
+
+    /// The first CMOS index that is general purpose NVRAM (the registers
+    /// before this are the clock and status registers)
+    pub const NVRAM_START: u8 = 0x0e;
+
     pub fn new(mem: u64) -> Box<Self> {
+        Self::with_clock(mem, Rc::new(SystemClock), 0)
+    }
+
+    /// Create a CMOS whose time registers are derived from `clock`
+    ///
+    /// `unix_time` is the wall clock time (in seconds since the unix epoch)
+    /// that the CMOS should report when `clock` reads zero.
+    pub fn with_clock(
+        mem: u64,
+        clock: Rc<dyn ClockSource>,
+        unix_time: u64,
+    ) -> Box<Self> {
         Box::new(Self {
-            addr: CmosRegister::Seconds, // For now, just set the default reg as seconds
+            addr: CmosRegister::Seconds as u8, // For now, just set the default reg as seconds
             data: Self::default_register_values(mem),
+            clock,
+            mem,
+            nvram: None,
+            irq: None,
+            base_time: unix_time as i64,
+            next_periodic: None,
+            next_update: None,
         })
     }
 
-    fn default_register_values(mem: u64) -> [u8; 256] {
-        //TODO: support memory above 4GB
+    /// Set the sink used to raise IRQ8 for the periodic and update-ended
+    /// interrupts
+    pub fn set_irq_sink(&mut self, irq: Rc<dyn IrqSink>) {
+        self.irq = Some(irq);
+    }
 
-        let mut data = [0u8; 256];
+    /// Back the CMOS NVRAM with a host provided buffer
+    ///
+    /// The NVRAM contents are loaded from the buffer, and any guest writes
+    /// to the NVRAM are stored to it, so they survive a `reset` (or can be
+    /// saved by the host across guest boots).
+    pub fn set_nvram(&mut self, nvram: CmosNvram) {
+        self.nvram = Some(nvram);
+        self.load_nvram();
+    }
 
-        let megs_under_4gb = mem & 0xfff;
-        // Subtrack 16 because it's really 'blocks_under_4gb_over_16mb'
-        // Shift by 4 because each 'block' is 64KiB
-        let blocks_under_4gb: u16 = ((megs_under_4gb - 16) << 4) as u16;
+    /// Reset the CMOS to its power-on state
+    ///
+    /// The clock is unaffected and, if the CMOS is backed by NVRAM, the
+    /// NVRAM contents are retained.
+    pub fn reset(&mut self) {
+        self.addr = CmosRegister::Seconds as u8;
+        self.data = Self::default_register_values(self.mem);
+        self.next_periodic = None;
+        self.next_update = None;
+        self.load_nvram();
+    }
+
+    fn load_nvram(&mut self) {
+        if let Some(nvram) = &self.nvram {
+            let start = Self::NVRAM_START as usize;
+            self.data[start..Self::CMOS_SIZE]
+                .copy_from_slice(&nvram.borrow()[start..]);
+        }
+
+        // The memory size is determined by the host, regardless of
+        // what was previously stored
+        Self::set_memory_registers(&mut self.data, self.mem);
+    }
+
+    fn default_register_values(mem: u64) -> [u8; 256] {
+        let mut data = [0u8; 256];
 
         let defaults = [
+            // Use 24 hour, BCD mode by default
+            (CmosRegister::StatusRegisterB, Self::STATUS_B_24_HOUR),
             // The MSB of register D indicates the CMOS battery is working
             (CmosRegister::StatusRegisterD, 0b10000000),
-            (CmosRegister::QemuMemAbove16MbLsb, blocks_under_4gb as u8),
-            (
-                CmosRegister::QemuMemAbove16MbMsb,
-                (blocks_under_4gb >> 8) as u8,
-            ),
         ];
         for &(reg, val) in &defaults {
             data[reg as usize] = val
         }
+        Self::set_memory_registers(&mut data, mem);
         data
     }
+
+    fn set_memory_registers(data: &mut [u8; 256], mem: u64) {
+        //TODO: support memory above 4GB
+
+        let megs_under_4gb = mem & 0xfff;
+        // Subtrack 16 because it's really 'blocks_under_4gb_over_16mb'
+        // Shift by 4 because each 'block' is 64KiB
+        let blocks_under_4gb: u16 = ((megs_under_4gb - 16) << 4) as u16;
+
+        data[CmosRegister::QemuMemAbove16MbLsb as usize] =
+            blocks_under_4gb as u8;
+        data[CmosRegister::QemuMemAbove16MbMsb as usize] =
+            (blocks_under_4gb >> 8) as u8;
+    }
+
+    fn write_data(&mut self, index: u8, val: u8) {
+        self.data[index as usize] = val;
+        if index >= Self::NVRAM_START && (index as usize) < Self::CMOS_SIZE {
+            if let Some(nvram) = &self.nvram {
+                nvram.borrow_mut()[index as usize] = val;
+            }
+        }
+    }
+
+    // The interval of the periodic interrupt selected by status register A
+    fn periodic_interval(&self) -> Option<u64> {
+        let rate = match self.data[CmosRegister::StatusRegisterA as usize]
+            & Self::STATUS_A_RATE_MASK
+        {
+            0 => return None,
+            // Rates 1 and 2 are the same as 8 and 9
+            rate @ 1..=2 => rate + 7,
+            rate => rate,
+        };
+        Some((Self::NS_PER_SEC << (rate - 1)) / 32768)
+    }
+
+    // Set status register C flags, raising IRQ8 if one of them is enabled
+    fn set_interrupt_flags(&mut self, flags: u8) {
+        let status_b = self.data[CmosRegister::StatusRegisterB as usize];
+        let status_c = &mut self.data[CmosRegister::StatusRegisterC as usize];
+        *status_c |= flags;
+
+        // The IRQ line remains asserted until register C is read
+        let enabled = Self::STATUS_B_PIE | Self::STATUS_B_UIE;
+        if *status_c & status_b & enabled != 0
+            && *status_c & Self::STATUS_C_IRQF == 0
+        {
+            *status_c |= Self::STATUS_C_IRQF;
+            if let Some(irq) = &self.irq {
+                irq.raise_irq(Self::RTC_IRQ);
+            }
+        }
+    }
+
+    fn current_unix_time(&self) -> i64 {
+        self.unix_time_at(self.clock.now_ns())
+    }
+
+    fn unix_time_at(&self, now: u64) -> i64 {
+        self.base_time + (now / Self::NS_PER_SEC) as i64
+    }
+
+    /// The time reported by the clock registers (in seconds since the
+    /// unix epoch), and the clock time it was derived from
+    ///
+    /// The guest may set the time registers, so the reported time does
+    /// not necessarily match the time given at construction plus the
+    /// clock time.
+    pub fn time_diagnostics(&self) -> TimeDiagnostics {
+        let now = self.clock.now_ns();
+        TimeDiagnostics {
+            clock_ns: now,
+            device_time: self.unix_time_at(now).max(0) as u64,
+        }
+    }
+
+    fn is_binary_mode(&self) -> bool {
+        self.data[CmosRegister::StatusRegisterB as usize]
+            & Self::STATUS_B_BINARY
+            != 0
+    }
+
+    fn is_24_hour_mode(&self) -> bool {
+        self.data[CmosRegister::StatusRegisterB as usize]
+            & Self::STATUS_B_24_HOUR
+            != 0
+    }
+
+    fn encode(&self, val: u8) -> u8 {
+        if self.is_binary_mode() {
+            val
+        } else {
+            ((val / 10) << 4) | (val % 10)
+        }
+    }
+
+    fn decode(&self, val: u8) -> u8 {
+        if self.is_binary_mode() {
+            val
+        } else {
+            (val >> 4) * 10 + (val & 0x0f)
+        }
+    }
+
+    fn encode_hour(&self, hour: u8) -> u8 {
+        if self.is_24_hour_mode() {
+            return self.encode(hour);
+        }
+        let pm = if hour >= 12 { Self::HOUR_PM } else { 0 };
+        let hour = match hour % 12 {
+            0 => 12,
+            h => h,
+        };
+        self.encode(hour) | pm
+    }
+
+    fn decode_hour(&self, val: u8) -> u8 {
+        if self.is_24_hour_mode() {
+            return self.decode(val);
+        }
+        let hour = self.decode(val & !Self::HOUR_PM) % 12;
+        if val & Self::HOUR_PM != 0 {
+            hour + 12
+        } else {
+            hour
+        }
+    }
+
+    /// Returns the value of a clock-derived register, if `reg` is one
+    fn read_time_register(&self, reg: CmosRegister) -> Option<u8> {
+        let time = RtcTime::from_unix(self.current_unix_time());
+        let val = match reg {
+            CmosRegister::Seconds => self.encode(time.second),
+            CmosRegister::Minutes => self.encode(time.minute),
+            CmosRegister::Hours => self.encode_hour(time.hour),
+            CmosRegister::DayOfWeek => self.encode(time.day_of_week),
+            CmosRegister::DayOfMonth => self.encode(time.day_of_month),
+            CmosRegister::Month => self.encode(time.month),
+            CmosRegister::Year => self.encode((time.year % 100) as u8),
+            CmosRegister::BcdCenturyDate => {
+                self.encode((time.year / 100) as u8)
+            }
+            _ => return None,
+        };
+        Some(val)
+    }
+
+    /// Update the clock-derived time if `reg` is a time register
+    ///
+    /// Returns false if `reg` is not a time register.
+    fn write_time_register(&mut self, reg: CmosRegister, val: u8) -> bool {
+        let mut time = RtcTime::from_unix(self.current_unix_time());
+        match reg {
+            CmosRegister::Seconds => time.second = self.decode(val),
+            CmosRegister::Minutes => time.minute = self.decode(val),
+            CmosRegister::Hours => time.hour = self.decode_hour(val),
+            CmosRegister::DayOfMonth => time.day_of_month = self.decode(val),
+            CmosRegister::Month => time.month = self.decode(val),
+            CmosRegister::Year => {
+                let year = self.decode(val) as u16;
+                time.year = time.year - time.year % 100 + year;
+            }
+            CmosRegister::BcdCenturyDate => {
+                let century = self.decode(val) as u16;
+                time.year = century * 100 + time.year % 100;
+            }
+            // The day of the week is derived from the date
+            CmosRegister::DayOfWeek => (),
+            _ => return false,
+        }
+        self.base_time += time.to_unix() - self.current_unix_time();
+        true
+    }
 }
 
 //TODO: support the NMI masking stuff
 impl EmulatedDevice for CmosRtc {
+    fn reset(&mut self) {
+        CmosRtc::reset(self)
+    }
+
     fn services(&self) -> Vec<DeviceRegion> {
         vec![DeviceRegion::PortIo(Self::RTC_ADDRESS..=Self::RTC_DATA)]
     }
 
+    fn depends_on(&self) -> Vec<DeviceKind> {
+        vec![DeviceKind::Pic]
+    }
+
+    fn irq_lines(&self) -> Vec<u8> {
+        vec![Self::RTC_IRQ]
+    }
+
+    fn poll(&mut self, now: u64) {
+        let mut flags = 0;
+        match self.periodic_interval() {
+            Some(interval) => {
+                let next = self.next_periodic.get_or_insert(now + interval);
+                if now >= *next {
+                    flags |= Self::STATUS_C_PF;
+                    *next += ((now - *next) / interval + 1) * interval;
+                }
+            }
+            None => self.next_periodic = None,
+        }
+
+        // An update cycle completes at every second boundary
+        let next_second = (now / Self::NS_PER_SEC + 1) * Self::NS_PER_SEC;
+        let next = self.next_update.get_or_insert(next_second);
+        if now >= *next {
+            flags |= Self::STATUS_C_UF;
+            *next = next_second;
+        }
+
+        if flags != 0 {
+            self.set_interrupt_flags(flags);
+        }
+    }
+
     fn on_port_read(
         &mut self,
         port: Port,
         mut val: PortReadRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
+        require_len(val.as_slice().len(), &[1])?;
         match port {
-            Self::RTC_ADDRESS => val.copy_from_u32(self.addr as u8 as u32),
-            Self::RTC_DATA => match self.addr {
-                addr => {
-                    val.copy_from_u32(self.data[addr as usize] as u32);
+            Self::RTC_ADDRESS => val.copy_from_u32(self.addr as u32),
+            // Reading status register C acknowledges the interrupt
+            Self::RTC_DATA
+                if self.addr == CmosRegister::StatusRegisterC as u8 =>
+            {
+                let status_c = CmosRegister::StatusRegisterC as usize;
+                val.copy_from_u32(self.data[status_c] as u32);
+                self.data[status_c] = 0;
+            }
+            Self::RTC_DATA => match CmosRegister::try_from(self.addr)
+                .and_then(|reg| self.read_time_register(reg))
+            {
+                Some(time) => val.copy_from_u32(time as u32),
+                None => {
+                    val.copy_from_u32(self.data[self.addr as usize] as u32);
                 }
             },
             _ => unreachable!(),
@@ -139,31 +522,38 @@ impl EmulatedDevice for CmosRtc {
         val: PortWriteRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
-        // For now, just ignore the NMI masking
         let val: u8 = val.try_into()?;
-        let val = val & 0x7f;
 
         match port {
             Self::RTC_ADDRESS => {
-                // OVMF expects to be able to read pretty much any address
-                // (and just get zeros for meaningless ones)
-                self.addr = CmosRegister::try_from(val)
-                    .unwrap_or(CmosRegister::Unknown);
+                // For now, just ignore the NMI masking. OVMF expects to be
+                // able to read pretty much any address (and just get zeros
+                // for meaningless ones)
+                self.addr = val & !Self::NMI_DISABLE;
             }
             Self::RTC_DATA => {
-                match self.addr {
+                let reg = CmosRegister::try_from(self.addr)
+                    .unwrap_or(CmosRegister::Unknown);
+                match reg {
                     CmosRegister::ShutdownStatus => {
                         // It's not clear what's supposed to happen here, just ignore
                         // it for now
                     }
+                    CmosRegister::StatusRegisterA => {
+                        // Restart the periodic interrupt at the new rate
+                        self.write_data(self.addr, val);
+                        self.next_periodic = None;
+                    }
                     CmosRegister::StatusRegisterD
                     | CmosRegister::StatusRegisterC => {
                         // Status register C and D are read-only (but OVMF will attempt
                         // to write to them, so we must explicitly ignore the writes)
                     }
-                    addr => {
+                    reg => {
                         // For now, any other register write is just directly performed
-                        self.data[addr as usize] = val;
+                        if !self.write_time_register(reg, val) {
+                            self.write_data(self.addr, val);
+                        }
                     }
                 }
             }
@@ -172,3 +562,216 @@ impl EmulatedDevice for CmosRtc {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::{define_test_view, MockIrqs};
+    use crate::time::FixedClock;
+
+    // 2020-05-14 13:45:30 UTC (a Thursday)
+    const TEST_TIME: u64 = 1589463930;
+
+    #[test]
+    fn test_rtc_time_from_unix() {
+        let time = RtcTime::from_unix(TEST_TIME as i64);
+        assert_eq!(
+            time,
+            RtcTime {
+                second: 30,
+                minute: 45,
+                hour: 13,
+                day_of_week: 5,
+                day_of_month: 14,
+                month: 5,
+                year: 2020,
+            }
+        );
+        assert_eq!(time.to_unix(), TEST_TIME as i64);
+
+        let epoch = RtcTime::from_unix(0);
+        assert_eq!((epoch.year, epoch.month, epoch.day_of_month), (1970, 1, 1));
+        assert_eq!(epoch.day_of_week, 5);
+    }
+
+    #[test]
+    fn test_time_registers_follow_clock() {
+        let clock = Rc::new(FixedClock::new(0));
+        let rtc = CmosRtc::with_clock(256, clock.clone(), TEST_TIME);
+
+        assert_eq!(rtc.read_time_register(CmosRegister::Seconds), Some(0x30));
+        assert_eq!(rtc.read_time_register(CmosRegister::Minutes), Some(0x45));
+        assert_eq!(rtc.read_time_register(CmosRegister::Hours), Some(0x13));
+        assert_eq!(rtc.read_time_register(CmosRegister::Year), Some(0x20));
+        assert_eq!(
+            rtc.read_time_register(CmosRegister::BcdCenturyDate),
+            Some(0x20)
+        );
+
+        clock.advance(31_000_000_000);
+        assert_eq!(rtc.read_time_register(CmosRegister::Seconds), Some(0x01));
+        assert_eq!(rtc.read_time_register(CmosRegister::Minutes), Some(0x46));
+    }
+
+    #[test]
+    fn test_binary_12_hour_mode() {
+        let clock = Rc::new(FixedClock::new(0));
+        let mut rtc = CmosRtc::with_clock(256, clock, TEST_TIME);
+        rtc.data[CmosRegister::StatusRegisterB as usize] =
+            CmosRtc::STATUS_B_BINARY;
+
+        assert_eq!(
+            rtc.read_time_register(CmosRegister::Hours),
+            Some(1 | CmosRtc::HOUR_PM)
+        );
+        assert_eq!(rtc.read_time_register(CmosRegister::Minutes), Some(45));
+    }
+
+    #[test]
+    fn test_time_register_write() {
+        let clock = Rc::new(FixedClock::new(0));
+        let mut rtc = CmosRtc::with_clock(256, clock.clone(), TEST_TIME);
+
+        assert!(rtc.write_time_register(CmosRegister::Hours, 0x08));
+        assert_eq!(rtc.read_time_register(CmosRegister::Hours), Some(0x08));
+        assert_eq!(rtc.read_time_register(CmosRegister::Minutes), Some(0x45));
+
+        clock.advance(60_000_000_000);
+        assert_eq!(rtc.read_time_register(CmosRegister::Minutes), Some(0x46));
+        assert!(!rtc.write_time_register(CmosRegister::Equipment, 0x08));
+    }
+
+    fn write_cmos(rtc: &mut CmosRtc, index: u8, val: u8) {
+        rtc.on_port_write(
+            CmosRtc::RTC_ADDRESS,
+            PortWriteRequest::OneByte(&[index]),
+            define_test_view(),
+        )
+        .unwrap();
+        rtc.on_port_write(
+            CmosRtc::RTC_DATA,
+            PortWriteRequest::OneByte(&[val]),
+            define_test_view(),
+        )
+        .unwrap();
+    }
+
+    fn read_cmos(rtc: &mut CmosRtc, index: u8) -> u8 {
+        let mut buf = [0u8];
+        rtc.on_port_write(
+            CmosRtc::RTC_ADDRESS,
+            PortWriteRequest::OneByte(&[index]),
+            define_test_view(),
+        )
+        .unwrap();
+        rtc.on_port_read(
+            CmosRtc::RTC_DATA,
+            PortReadRequest::OneByte(&mut buf),
+            define_test_view(),
+        )
+        .unwrap();
+        buf[0]
+    }
+
+    #[test]
+    fn test_nvram_persists_across_reset() {
+        let clock = Rc::new(FixedClock::new(0));
+        let nvram: CmosNvram = Rc::new(RefCell::new([0u8; CmosRtc::CMOS_SIZE]));
+        let mut rtc = CmosRtc::with_clock(256, clock, TEST_TIME);
+        rtc.set_nvram(nvram.clone());
+
+        write_cmos(&mut rtc, 0x10, 0xa5);
+        write_cmos(&mut rtc, 0x50, 0x5a);
+        assert_eq!(nvram.borrow()[0x10], 0xa5);
+
+        rtc.reset();
+        assert_eq!(read_cmos(&mut rtc, 0x10), 0xa5);
+        assert_eq!(read_cmos(&mut rtc, 0x50), 0x5a);
+        assert_eq!(read_cmos(&mut rtc, 0x00), 0x30);
+    }
+
+    #[test]
+    fn test_reset_without_nvram() {
+        let clock = Rc::new(FixedClock::new(0));
+        let mut rtc = CmosRtc::with_clock(256, clock, TEST_TIME);
+        write_cmos(&mut rtc, 0x10, 0xa5);
+        assert_eq!(read_cmos(&mut rtc, 0x10), 0xa5);
+        rtc.reset();
+        assert_eq!(read_cmos(&mut rtc, 0x10), 0x00);
+    }
+
+    #[test]
+    fn test_nvram_seeded_by_host() {
+        let clock = Rc::new(FixedClock::new(0));
+        let nvram: CmosNvram = Rc::new(RefCell::new([0u8; CmosRtc::CMOS_SIZE]));
+        nvram.borrow_mut()[0x10] = 0x40;
+        // Stale memory size and clock registers are not restored
+        nvram.borrow_mut()[CmosRegister::QemuMemAbove16MbLsb as usize] = 0xff;
+        nvram.borrow_mut()[0x00] = 0x11;
+
+        let mut rtc = CmosRtc::with_clock(256, clock, TEST_TIME);
+        rtc.set_nvram(nvram);
+        assert_eq!(read_cmos(&mut rtc, 0x10), 0x40);
+        assert_eq!(read_cmos(&mut rtc, 0x34), 0x00);
+        assert_eq!(read_cmos(&mut rtc, 0x35), 0x0f);
+        assert_eq!(read_cmos(&mut rtc, 0x00), 0x30);
+    }
+
+    #[test]
+    fn test_periodic_interrupt() {
+        let clock = Rc::new(FixedClock::new(0));
+        let irqs = Rc::new(MockIrqs::default());
+        let mut rtc = CmosRtc::with_clock(256, clock.clone(), TEST_TIME);
+        rtc.set_irq_sink(irqs.clone());
+
+        // 1024Hz periodic interrupt
+        write_cmos(&mut rtc, 0x0a, 0x26);
+        write_cmos(&mut rtc, 0x0b, 0x42);
+        rtc.poll(clock.now_ns());
+
+        clock.advance(1_000_000);
+        rtc.poll(clock.now_ns());
+        assert_eq!(*irqs.raised.borrow(), [8]);
+
+        // The line stays asserted until register C is read
+        clock.advance(1_000_000);
+        rtc.poll(clock.now_ns());
+        assert_eq!(irqs.raised.borrow().len(), 1);
+        assert_eq!(read_cmos(&mut rtc, 0x0c), 0xc0);
+        assert_eq!(read_cmos(&mut rtc, 0x0c), 0x00);
+
+        clock.advance(1_000_000);
+        rtc.poll(clock.now_ns());
+        assert_eq!(irqs.raised.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_time_diagnostics() {
+        let clock = Rc::new(FixedClock::new(0));
+        let mut rtc = CmosRtc::with_clock(256, clock.clone(), TEST_TIME);
+        clock.advance(90_500_000_000);
+        assert_eq!(
+            rtc.time_diagnostics(),
+            TimeDiagnostics {
+                clock_ns: 90_500_000_000,
+                device_time: TEST_TIME + 90,
+            }
+        );
+
+        // The guest sets the seconds (from 0 to 5), so the RTC is now five
+        // seconds ahead of the clock
+        rtc.on_port_write(
+            CmosRtc::RTC_ADDRESS,
+            PortWriteRequest::OneByte(&[CmosRegister::Seconds as u8]),
+            define_test_view(),
+        )
+        .unwrap();
+        rtc.on_port_write(
+            CmosRtc::RTC_DATA,
+            PortWriteRequest::OneByte(&[0x05]),
+            define_test_view(),
+        )
+        .unwrap();
+        assert_eq!(rtc.time_diagnostics().device_time, TEST_TIME + 95);
+    }
+}
@@ -0,0 +1,769 @@
+use crate::device::identity::DeviceIdentity;
+use crate::device::interrupt::{IrqLineState, IrqSink};
+use crate::device::{
+    check_isa_irq, port_block, validate_dma_range, DeviceKind, DeviceRegion,
+    EmulatedDevice, Port, PortReadRequest, PortWriteRequest, TriggerMode,
+};
+use crate::error::Result;
+use crate::memory::{
+    GuestAccess, GuestAddressSpaceViewMut, GuestPhysAddr, GuestVirtAddr,
+    PrivilegeLevel,
+};
+use crate::util::checksum;
+use alloc::boxed::Box;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+
+/// The host side of an emulated network interface
+pub trait NetBackend {
+    /// Send a frame transmitted by the guest
+    fn send(&mut self, frame: &[u8]);
+
+    /// Take the next frame to be delivered to the guest, if any
+    fn receive(&mut self) -> Option<Vec<u8>>;
+}
+
+/// A backend that delivers every transmitted frame back to the guest
+#[derive(Default)]
+pub struct LoopbackBackend {
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl NetBackend for LoopbackBackend {
+    fn send(&mut self, frame: &[u8]) {
+        self.frames.push_back(frame.to_vec());
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.frames.pop_front()
+    }
+}
+
+#[allow(non_snake_case)]
+#[allow(dead_code)]
+mod Rtl8139Offset {
+    pub const IDR0: u16 = 0x00;
+    pub const TSD0: u16 = 0x10;
+    pub const TSAD0: u16 = 0x20;
+    pub const RBSTART: u16 = 0x30;
+    pub const CR: u16 = 0x37;
+    pub const CAPR: u16 = 0x38;
+    pub const CBR: u16 = 0x3a;
+    pub const IMR: u16 = 0x3c;
+    pub const ISR: u16 = 0x3e;
+    pub const TCR: u16 = 0x40;
+    pub const RCR: u16 = 0x44;
+}
+
+/// An emulated RTL8139 fast ethernet controller
+///
+/// Only the I/O register window is emulated, at a fixed base port.
+/// Transmitted frames are handed to the `NetBackend` and frames from the
+/// backend are copied in to the guest's receive ring.
+pub struct Rtl8139 {
+    base_port: Port,
+    irq_line: u8,
+    mac: [u8; 6],
+    backend: Box<dyn NetBackend>,
+    irq: Option<Rc<dyn IrqSink>>,
+    irq_raised: bool,
+
+    // Registers without side effects, stored as they appear in the I/O
+    // window (little endian)
+    registers: [u8; Self::REGISTER_SPACE as usize],
+
+    command: u8,
+    tx_status: [u32; 4],
+    rx_read_offset: u16,
+    rx_write_offset: u16,
+    interrupt_mask: u16,
+    interrupt_status: u16,
+}
+
+impl Rtl8139 {
+    const REGISTER_SPACE: u16 = 0x100;
+
+    /// The largest frame that will be sent or received (without the CRC)
+    pub const MAX_FRAME_LEN: usize = 1514;
+    const MIN_FRAME_LEN: usize = 14;
+    const CRC_LEN: usize = 4;
+    const RX_HEADER_LEN: usize = 4;
+
+    const CR_RX_BUFFER_EMPTY: u8 = 1 << 0;
+    const CR_TX_ENABLE: u8 = 1 << 2;
+    const CR_RX_ENABLE: u8 = 1 << 3;
+    const CR_RESET: u8 = 1 << 4;
+
+    const TSD_SIZE_MASK: u32 = 0x1fff;
+    const TSD_OWN: u32 = 1 << 13;
+    const TSD_TOK: u32 = 1 << 15;
+    const TSD_TABT: u32 = 1 << 30;
+
+    const ISR_ROK: u16 = 1 << 0;
+    const ISR_TOK: u16 = 1 << 2;
+    const ISR_TER: u16 = 1 << 3;
+    const ISR_RX_OVERFLOW: u16 = 1 << 4;
+
+    const RCR_ACCEPT_ALL: u32 = 1 << 0;
+    const RCR_ACCEPT_PHYSICAL: u32 = 1 << 1;
+    const RCR_ACCEPT_MULTICAST: u32 = 1 << 2;
+    const RCR_ACCEPT_BROADCAST: u32 = 1 << 3;
+    const RCR_WRAP: u32 = 1 << 7;
+
+    const RX_STATUS_ROK: u16 = 1 << 0;
+    const RX_STATUS_PHYSICAL: u16 = 1 << 12;
+    const RX_STATUS_BROADCAST: u16 = 1 << 13;
+    const RX_STATUS_MULTICAST: u16 = 1 << 15;
+
+    // The guest's read pointer (CAPR) trails the real offset by 16 bytes
+    const CAPR_BIAS: u16 = 16;
+
+    pub fn new(
+        base_port: Port,
+        irq_line: u8,
+        mac: [u8; 6],
+        backend: Box<dyn NetBackend>,
+    ) -> Result<Box<Self>> {
+        port_block(base_port, Self::REGISTER_SPACE)?;
+        check_isa_irq(irq_line)?;
+        let mut nic = Box::new(Self {
+            base_port,
+            irq_line,
+            mac,
+            backend,
+            irq: None,
+            irq_raised: false,
+            registers: [0; Self::REGISTER_SPACE as usize],
+            command: 0,
+            tx_status: [0; 4],
+            rx_read_offset: 0,
+            rx_write_offset: 0,
+            interrupt_mask: 0,
+            interrupt_status: 0,
+        });
+        nic.reset();
+        Ok(nic)
+    }
+
+    /// Create a controller whose MAC address is derived from `identity`
+    /// (and its base port), rather than given explicitly
+    pub fn with_identity(
+        base_port: Port,
+        irq_line: u8,
+        identity: &DeviceIdentity,
+        backend: Box<dyn NetBackend>,
+    ) -> Result<Box<Self>> {
+        let mac = identity.mac_address(base_port as u64);
+        Self::new(base_port, irq_line, mac, backend)
+    }
+
+    /// Set the sink used to raise this device's IRQ
+    pub fn set_irq_sink(&mut self, irq: Rc<dyn IrqSink>) {
+        self.irq = Some(irq);
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    fn reset(&mut self) {
+        self.registers = [0; Self::REGISTER_SPACE as usize];
+        self.registers[..6].copy_from_slice(&self.mac);
+        self.command = 0;
+        self.tx_status = [Self::TSD_OWN; 4];
+        self.rx_read_offset = 0;
+        self.rx_write_offset = 0;
+        self.interrupt_mask = 0;
+        self.interrupt_status = 0;
+        self.update_irq();
+    }
+
+    fn register32(&self, offset: u16) -> u32 {
+        let offset = offset as usize;
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&self.registers[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    // The size of the receive ring, selected by RCR bits 12:11. The
+    // largest (64K) ring still fits the 16-bit ring offsets.
+    fn rx_ring_len(&self) -> usize {
+        let rblen = (self.register32(Rtl8139Offset::RCR) >> 11) & 0b11;
+        (8 * 1024) << rblen
+    }
+
+    fn rx_buffer_empty(&self) -> bool {
+        self.rx_read_offset == self.rx_write_offset
+    }
+
+    fn set_interrupt(&mut self, status: u16) {
+        self.interrupt_status |= status;
+        self.update_irq();
+    }
+
+    fn update_irq(&mut self) {
+        let active = self.interrupt_status & self.interrupt_mask != 0;
+        if active == self.irq_raised {
+            return;
+        }
+        if let Some(irq) = &self.irq {
+            if active {
+                irq.raise_irq(self.irq_line);
+            } else {
+                irq.lower_irq(self.irq_line);
+            }
+            self.irq_raised = active;
+        }
+    }
+
+    // Send the buffer described by transmit descriptor `desc`
+    fn transmit(
+        &mut self,
+        desc: usize,
+        space: &mut GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        if self.command & Self::CR_TX_ENABLE == 0 {
+            return Ok(());
+        }
+
+        let len = (self.tx_status[desc] & Self::TSD_SIZE_MASK) as usize;
+        if len > Self::MAX_FRAME_LEN {
+            warn!("Aborting rtl8139 transmit of {} bytes", len);
+            self.tx_status[desc] |= Self::TSD_OWN | Self::TSD_TABT;
+            self.set_interrupt(Self::ISR_TER);
+            return Ok(());
+        }
+
+        let addr = GuestPhysAddr::new(
+            self.register32(Rtl8139Offset::TSAD0 + 4 * desc as u16) as u64,
+        );
+        let access = GuestAccess::Read(PrivilegeLevel(0));
+        if let Err(e) = validate_dma_range(space, addr, len, access) {
+            self.tx_status[desc] |= Self::TSD_OWN | Self::TSD_TABT;
+            self.set_interrupt(Self::ISR_TER);
+            return Err(e);
+        }
+        let frame =
+            space.read_bytes(GuestVirtAddr::NoPaging(addr), len, access)?;
+        self.backend.send(&frame);
+
+        self.tx_status[desc] |= Self::TSD_OWN | Self::TSD_TOK;
+        self.set_interrupt(Self::ISR_TOK);
+        Ok(())
+    }
+
+    // The receive status for a frame, or None if the frame is filtered
+    fn rx_filter(&self, frame: &[u8]) -> Option<u16> {
+        let rcr = self.register32(Rtl8139Offset::RCR);
+        let dest = &frame[..6];
+        let status = if dest == [0xff; 6] {
+            if rcr & Self::RCR_ACCEPT_BROADCAST == 0 {
+                None
+            } else {
+                Some(Self::RX_STATUS_BROADCAST)
+            }
+        } else if dest[0] & 1 != 0 {
+            if rcr & Self::RCR_ACCEPT_MULTICAST == 0 {
+                None
+            } else {
+                Some(Self::RX_STATUS_MULTICAST)
+            }
+        } else if dest == self.mac {
+            if rcr & Self::RCR_ACCEPT_PHYSICAL == 0 {
+                None
+            } else {
+                Some(Self::RX_STATUS_PHYSICAL)
+            }
+        } else {
+            None
+        };
+
+        match status {
+            Some(status) => Some(status | Self::RX_STATUS_ROK),
+            None if rcr & Self::RCR_ACCEPT_ALL != 0 => {
+                Some(Self::RX_STATUS_ROK)
+            }
+            None => None,
+        }
+    }
+
+    // Copy a frame in to the receive ring. Returns false if the frame was
+    // dropped.
+    fn receive_frame(
+        &mut self,
+        frame: &[u8],
+        space: &mut GuestAddressSpaceViewMut,
+    ) -> Result<bool> {
+        if frame.len() < Self::MIN_FRAME_LEN
+            || frame.len() > Self::MAX_FRAME_LEN
+        {
+            warn!("Dropping rtl8139 frame of {} bytes", frame.len());
+            return Ok(false);
+        }
+        let status = match self.rx_filter(frame) {
+            Some(status) => status,
+            None => return Ok(false),
+        };
+
+        // Each packet is preceded by a status and length header, and
+        // followed by the CRC. Packets start on a dword boundary.
+        let len = frame.len() + Self::CRC_LEN;
+        let mut packet = Vec::with_capacity(Self::RX_HEADER_LEN + len);
+        packet.extend_from_slice(&status.to_le_bytes());
+        packet.extend_from_slice(&(len as u16).to_le_bytes());
+        packet.extend_from_slice(frame);
+        packet.extend_from_slice(&checksum::crc32(frame).to_le_bytes());
+
+        let ring_len = self.rx_ring_len();
+        let used = (self.rx_write_offset as usize + ring_len
+            - self.rx_read_offset as usize)
+            % ring_len;
+        let aligned_len = (packet.len() + 3) & !3;
+        if used + aligned_len >= ring_len {
+            self.set_interrupt(Self::ISR_RX_OVERFLOW);
+            return Ok(false);
+        }
+
+        // Without WRAP, packets that run past the end of the ring continue
+        // at its start. With WRAP, the guest provides space past the end.
+        let start = self.register32(Rtl8139Offset::RBSTART) as u64;
+        let offset = self.rx_write_offset as usize;
+        let (head, tail) =
+            if self.register32(Rtl8139Offset::RCR) & Self::RCR_WRAP == 0
+                && offset + packet.len() > ring_len
+            {
+                packet.split_at(ring_len - offset)
+            } else {
+                (&packet[..], &[][..])
+            };
+
+        let access = GuestAccess::Write(PrivilegeLevel(0));
+        let head_addr = GuestPhysAddr::new(start + offset as u64);
+        validate_dma_range(space, head_addr, head.len(), access)?;
+        validate_dma_range(
+            space,
+            GuestPhysAddr::new(start),
+            tail.len(),
+            access,
+        )?;
+        space.write_bytes(GuestVirtAddr::NoPaging(head_addr), head, access)?;
+        if !tail.is_empty() {
+            space.write_bytes(
+                GuestVirtAddr::NoPaging(GuestPhysAddr::new(start)),
+                tail,
+                access,
+            )?;
+        }
+
+        self.rx_write_offset = ((offset + aligned_len) % ring_len) as u16;
+        self.set_interrupt(Self::ISR_ROK);
+        Ok(true)
+    }
+
+    /// Copy any frames waiting in the backend in to the guest's receive
+    /// ring, raising the receive interrupt
+    ///
+    /// This happens whenever the guest accesses the device, but may also
+    /// be called to deliver frames promptly. Returns the number of frames
+    /// delivered.
+    pub fn receive_frames(
+        &mut self,
+        space: &mut GuestAddressSpaceViewMut,
+    ) -> Result<usize> {
+        let mut delivered = 0;
+        while self.command & Self::CR_RX_ENABLE != 0 {
+            let frame = match self.backend.receive() {
+                Some(frame) => frame,
+                None => break,
+            };
+            if self.receive_frame(&frame, space)? {
+                delivered += 1;
+            }
+        }
+        Ok(delivered)
+    }
+
+    fn read_register(&mut self, offset: u16, len: usize) -> u32 {
+        match offset {
+            Rtl8139Offset::CR => {
+                let mut cr = self.command;
+                if self.rx_buffer_empty() {
+                    cr |= Self::CR_RX_BUFFER_EMPTY;
+                }
+                cr as u32
+            }
+            Rtl8139Offset::CAPR => {
+                self.rx_read_offset.wrapping_sub(Self::CAPR_BIAS) as u32
+            }
+            Rtl8139Offset::CBR => self.rx_write_offset as u32,
+            Rtl8139Offset::IMR => self.interrupt_mask as u32,
+            Rtl8139Offset::ISR => self.interrupt_status as u32,
+            o if o >= Rtl8139Offset::TSD0 && o < Rtl8139Offset::TSAD0 => {
+                self.tx_status[((o - Rtl8139Offset::TSD0) / 4) as usize]
+            }
+            _ => {
+                let offset = offset as usize;
+                let mut bytes = [0u8; 4];
+                for (i, byte) in bytes.iter_mut().enumerate().take(len) {
+                    *byte = self.registers[(offset + i) % self.registers.len()];
+                }
+                u32::from_le_bytes(bytes)
+            }
+        }
+    }
+
+    fn write_register(
+        &mut self,
+        offset: u16,
+        len: usize,
+        val: u32,
+        space: &mut GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        match offset {
+            Rtl8139Offset::CR => {
+                if val as u8 & Self::CR_RESET != 0 {
+                    self.reset();
+                } else {
+                    self.command =
+                        val as u8 & (Self::CR_RX_ENABLE | Self::CR_TX_ENABLE);
+                }
+            }
+            Rtl8139Offset::CAPR => {
+                let ring_len = self.rx_ring_len();
+                self.rx_read_offset =
+                    ((val as u16).wrapping_add(Self::CAPR_BIAS) as usize
+                        % ring_len) as u16;
+            }
+            Rtl8139Offset::CBR => (),
+            Rtl8139Offset::IMR => {
+                self.interrupt_mask = val as u16;
+                self.update_irq();
+            }
+            // Interrupt status bits are cleared by writing 1
+            Rtl8139Offset::ISR => {
+                self.interrupt_status &= !(val as u16);
+                self.update_irq();
+            }
+            o if o >= Rtl8139Offset::TSD0 && o < Rtl8139Offset::TSAD0 => {
+                let desc = ((o - Rtl8139Offset::TSD0) / 4) as usize;
+                self.tx_status[desc] = val & Self::TSD_SIZE_MASK;
+                self.transmit(desc, space)?;
+            }
+            _ => {
+                let offset = offset as usize;
+                for (i, byte) in val.to_le_bytes().iter().enumerate().take(len)
+                {
+                    let idx = (offset + i) % self.registers.len();
+                    self.registers[idx] = *byte;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl IrqLineState for Rtl8139 {
+    fn line_asserted(&self, irq: u8) -> bool {
+        irq == self.irq_line && self.irq_raised
+    }
+}
+
+impl EmulatedDevice for Rtl8139 {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::PortIo(
+            self.base_port..=self.base_port + (Self::REGISTER_SPACE - 1),
+        )]
+    }
+
+    fn depends_on(&self) -> Vec<DeviceKind> {
+        vec![DeviceKind::Pic]
+    }
+
+    fn irq_lines(&self) -> Vec<u8> {
+        vec![self.irq_line]
+    }
+
+    fn reset(&mut self) {
+        Rtl8139::reset(self)
+    }
+
+    // Like all PCI INTx interrupts, the IRQ is level triggered
+    fn irq_trigger_mode(&self, _irq: u8) -> TriggerMode {
+        TriggerMode::Level
+    }
+
+    fn on_port_read(
+        &mut self,
+        port: Port,
+        mut val: PortReadRequest,
+        mut space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.receive_frames(&mut space)?;
+        let len = val.as_slice().len();
+        let reg = self.read_register(port - self.base_port, len);
+        val.copy_from_u32(reg);
+        Ok(())
+    }
+
+    fn on_port_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+        mut space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let len = val.as_slice().len();
+        self.write_register(
+            port - self.base_port,
+            len,
+            val.as_u32(),
+            &mut space,
+        )?;
+        self.receive_frames(&mut space)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::MockIrqs;
+    use crate::memory::GuestAddressSpace;
+    use core::convert::TryFrom;
+
+    const BASE: Port = 0xc000;
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    const TX_BUFFER: u64 = 0x20000;
+    const RX_BUFFER: u64 = 0x21000;
+
+    // Guest memory mapped at 0x20000-0x23fff
+    fn define_test_ram() -> Box<GuestAddressSpace> {
+        let mut space = Box::new(GuestAddressSpace::new().unwrap());
+        for i in 0..4 {
+            space
+                .map_new_frame(GuestPhysAddr::new(0x20000 + i * 4096), false)
+                .unwrap();
+        }
+        space
+    }
+
+    fn view(space: &mut GuestAddressSpace) -> GuestAddressSpaceViewMut {
+        GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space)
+    }
+
+    struct TestNic {
+        nic: Box<Rtl8139>,
+        space: Box<GuestAddressSpace>,
+    }
+
+    impl TestNic {
+        fn try_write(
+            &mut self,
+            offset: u16,
+            val: u32,
+            len: usize,
+        ) -> Result<()> {
+            let bytes = val.to_be_bytes();
+            self.nic.on_port_write(
+                BASE + offset,
+                PortWriteRequest::try_from(&bytes[4 - len..])?,
+                view(&mut self.space),
+            )
+        }
+
+        fn write(&mut self, offset: u16, val: u32, len: usize) {
+            self.try_write(offset, val, len).unwrap();
+        }
+
+        fn read(&mut self, offset: u16, len: usize) -> u32 {
+            let mut bytes = [0u8; 4];
+            self.nic
+                .on_port_read(
+                    BASE + offset,
+                    PortReadRequest::try_from(&mut bytes[4 - len..]).unwrap(),
+                    view(&mut self.space),
+                )
+                .unwrap();
+            u32::from_be_bytes(bytes)
+        }
+
+        fn transmit(&mut self, desc: u16, frame: &[u8]) -> Result<()> {
+            view(&mut self.space).write_bytes(
+                GuestVirtAddr::NoPaging(GuestPhysAddr::new(TX_BUFFER)),
+                frame,
+                GuestAccess::Write(PrivilegeLevel(0)),
+            )?;
+            self.write(Rtl8139Offset::TSAD0 + desc * 4, TX_BUFFER as u32, 4);
+            self.try_write(
+                Rtl8139Offset::TSD0 + desc * 4,
+                frame.len() as u32,
+                4,
+            )
+        }
+    }
+
+    fn test_frame(len: usize) -> Vec<u8> {
+        let mut frame = MAC.to_vec();
+        frame.extend_from_slice(&[0x52, 0x54, 0x00, 0xaa, 0xbb, 0xcc]);
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend((0..len - frame.len()).map(|i| i as u8));
+        frame
+    }
+
+    // Enable the transmitter and receiver the way a driver would
+    fn init_nic(irqs: &Rc<MockIrqs>) -> TestNic {
+        let mut nic =
+            Rtl8139::new(BASE, 11, MAC, Box::new(LoopbackBackend::default()))
+                .unwrap();
+        nic.set_irq_sink(irqs.clone());
+        let mut test = TestNic {
+            nic,
+            space: define_test_ram(),
+        };
+        test.write(Rtl8139Offset::CR, Rtl8139::CR_RESET as u32, 1);
+        test.write(Rtl8139Offset::RBSTART, RX_BUFFER as u32, 4);
+        test.write(
+            Rtl8139Offset::RCR,
+            Rtl8139::RCR_ACCEPT_PHYSICAL | Rtl8139::RCR_ACCEPT_BROADCAST,
+            4,
+        );
+        test.write(
+            Rtl8139Offset::IMR,
+            (Rtl8139::ISR_ROK | Rtl8139::ISR_TOK) as u32,
+            2,
+        );
+        test.write(
+            Rtl8139Offset::CR,
+            (Rtl8139::CR_RX_ENABLE | Rtl8139::CR_TX_ENABLE) as u32,
+            1,
+        );
+        test
+    }
+
+    #[test]
+    fn test_mac_address() {
+        let irqs = Rc::new(MockIrqs::default());
+        let mut nic = init_nic(&irqs);
+        let mac: Vec<u8> = (0..6)
+            .map(|i| nic.read(Rtl8139Offset::IDR0 + i, 1) as u8)
+            .collect();
+        assert_eq!(mac, MAC);
+
+        // A MAC derived from the guest's identity
+        let identity = DeviceIdentity::new(7);
+        let nic = Rtl8139::with_identity(
+            BASE,
+            11,
+            &identity,
+            Box::new(LoopbackBackend::default()),
+        )
+        .unwrap();
+        assert_eq!(nic.mac(), identity.mac_address(BASE as u64));
+        assert_eq!(nic.registers[..6], nic.mac());
+    }
+
+    #[test]
+    fn test_construction_validation() {
+        let new = |base, irq| {
+            Rtl8139::new(base, irq, MAC, Box::new(LoopbackBackend::default()))
+        };
+        assert!(new(0xff00, 15).is_ok());
+        assert!(new(0xff01, 11).is_err());
+        assert!(new(BASE, 16).is_err());
+    }
+
+    #[test]
+    fn test_loopback_transmit_receive() {
+        let irqs = Rc::new(MockIrqs::default());
+        let mut nic = init_nic(&irqs);
+        assert_ne!(nic.read(Rtl8139Offset::CR, 1) as u8 & 1, 0);
+
+        let frame = test_frame(Rtl8139::MAX_FRAME_LEN);
+        nic.transmit(0, &frame).unwrap();
+
+        let status = nic.read(Rtl8139Offset::TSD0, 4);
+        assert_ne!(status & Rtl8139::TSD_OWN, 0);
+        assert_ne!(status & Rtl8139::TSD_TOK, 0);
+        let isr = nic.read(Rtl8139Offset::ISR, 2) as u16;
+        assert_eq!(isr, Rtl8139::ISR_ROK | Rtl8139::ISR_TOK);
+        assert_eq!(*irqs.raised.borrow(), [11]);
+
+        // The frame was looped back in to the receive ring
+        assert_eq!(nic.read(Rtl8139Offset::CR, 1) as u8 & 1, 0);
+        let ring = view(&mut nic.space)
+            .read_bytes(
+                GuestVirtAddr::NoPaging(GuestPhysAddr::new(RX_BUFFER)),
+                frame.len() + 8,
+                GuestAccess::Read(PrivilegeLevel(0)),
+            )
+            .unwrap();
+        let status = u16::from_le_bytes([ring[0], ring[1]]);
+        assert_eq!(
+            status,
+            Rtl8139::RX_STATUS_ROK | Rtl8139::RX_STATUS_PHYSICAL
+        );
+        let len = u16::from_le_bytes([ring[2], ring[3]]) as usize;
+        assert_eq!(len, frame.len() + 4);
+        assert_eq!(&ring[4..4 + frame.len()], &frame[..]);
+        assert_eq!(
+            &ring[4 + frame.len()..],
+            &checksum::crc32(&frame).to_le_bytes()
+        );
+
+        // The driver consumes the packet and acknowledges the interrupts
+        let next = (4 + len as u32 + 3) & !3;
+        assert_eq!(nic.read(Rtl8139Offset::CBR, 2), next);
+        nic.write(Rtl8139Offset::CAPR, next - 16, 2);
+        nic.write(Rtl8139Offset::ISR, isr as u32, 2);
+        assert_eq!(nic.read(Rtl8139Offset::ISR, 2), 0);
+        assert_ne!(nic.read(Rtl8139Offset::CR, 1) as u8 & 1, 0);
+    }
+
+    #[test]
+    fn test_rx_filter_and_length() {
+        let irqs = Rc::new(MockIrqs::default());
+        let mut nic = init_nic(&irqs);
+
+        // Frames for another station are filtered
+        let mut frame = test_frame(64);
+        frame[5] = 0;
+        nic.transmit(1, &frame).unwrap();
+        assert_eq!(nic.read(Rtl8139Offset::ISR, 2) as u16, Rtl8139::ISR_TOK);
+
+        // An oversized frame is aborted rather than sent
+        nic.write(Rtl8139Offset::ISR, 0xffff, 2);
+        let frame = test_frame(Rtl8139::MAX_FRAME_LEN + 1);
+        nic.transmit(2, &frame).unwrap();
+        let status = nic.read(Rtl8139Offset::TSD0 + 8, 4);
+        assert_ne!(status & Rtl8139::TSD_TABT, 0);
+        assert_eq!(nic.read(Rtl8139Offset::ISR, 2) as u16, Rtl8139::ISR_TER);
+
+        // Transmit buffers must be in guest memory
+        nic.write(Rtl8139Offset::TSAD0 + 12, 0x40000, 4);
+        assert!(nic.try_write(Rtl8139Offset::TSD0 + 12, 64, 4).is_err());
+    }
+
+    #[test]
+    fn test_64k_rx_ring() {
+        let irqs = Rc::new(MockIrqs::default());
+        let mut nic = init_nic(&irqs);
+        let rcr = nic.read(Rtl8139Offset::RCR, 4);
+        nic.write(Rtl8139Offset::RCR, rcr | (0b11 << 11), 4);
+        assert_eq!(nic.nic.rx_ring_len(), 64 * 1024);
+
+        // The read offset wraps at the end of the 64K ring
+        nic.write(Rtl8139Offset::CAPR, 0xfff0, 2);
+        assert_eq!(nic.nic.rx_read_offset, 0);
+        nic.write(Rtl8139Offset::CAPR, 0xffe0, 2);
+        assert_eq!(nic.nic.rx_read_offset, 0xfff0);
+        nic.write(Rtl8139Offset::CAPR, 0xfff0, 2);
+
+        let frame = test_frame(64);
+        nic.transmit(0, &frame).unwrap();
+        assert_ne!(
+            nic.read(Rtl8139Offset::ISR, 2) as u16 & Rtl8139::ISR_ROK,
+            0
+        );
+        assert_eq!(nic.read(Rtl8139Offset::CBR, 2), (4 + 64 + 4 + 3) & !3);
+    }
+}
@@ -0,0 +1,230 @@
+use crate::device::validate_dma_range;
+use crate::error::{Error, Result};
+use crate::memory::{
+    GuestAccess, GuestAddressSpaceViewMut, GuestPhysAddr, GuestVirtAddr,
+    PrivilegeLevel,
+};
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// A guest buffer made up of discontiguous segments (e.g., the buffers of
+/// a DMA descriptor chain), accessed as one sequential stream
+///
+/// Each access is checked against the segments it touches before any bytes
+/// are moved, so a failed `read` or `write` has no effect on guest memory
+/// or on the stream position.
+pub struct ScatterGather<'a, 'b> {
+    space: &'a mut GuestAddressSpaceViewMut<'b>,
+    segments: Vec<(GuestPhysAddr, usize)>,
+    len: usize,
+    position: usize,
+}
+
+impl<'a, 'b> ScatterGather<'a, 'b> {
+    /// Create a stream over the (address, length) `segments`, in order
+    ///
+    /// Fails with `Error::InvalidDmaRange` if a segment extends beyond the
+    /// end of the guest physical address space.
+    pub fn new(
+        space: &'a mut GuestAddressSpaceViewMut<'b>,
+        segments: Vec<(GuestPhysAddr, usize)>,
+    ) -> Result<Self> {
+        let mut len = 0;
+        for &(addr, seg_len) in segments.iter() {
+            let invalid = || Error::InvalidDmaRange { addr, len: seg_len };
+            if seg_len > 0 {
+                addr.as_u64()
+                    .checked_add(seg_len as u64 - 1)
+                    .ok_or_else(invalid)?;
+            }
+            len += seg_len;
+        }
+        Ok(Self {
+            space,
+            segments,
+            len,
+            position: 0,
+        })
+    }
+
+    /// The total length of the segments
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The offset in the stream of the next access
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// The number of bytes between the position and the end of the stream
+    pub fn remaining(&self) -> usize {
+        self.len - self.position
+    }
+
+    /// Read from the stream into `buff`, returning the number of bytes read
+    /// (which is less than the length of `buff` at the end of the stream)
+    pub fn read(&mut self, buff: &mut [u8]) -> Result<usize> {
+        let access = GuestAccess::Read(PrivilegeLevel(0));
+        let chunks = self.chunks(buff.len(), access)?;
+        for (addr, range) in chunks.iter().cloned() {
+            let bytes = self.space.read_bytes(
+                GuestVirtAddr::NoPaging(addr),
+                range.len(),
+                access,
+            )?;
+            buff[range].copy_from_slice(&bytes);
+        }
+        Ok(self.advance(&chunks))
+    }
+
+    /// Write `data` to the stream, returning the number of bytes written
+    /// (which is less than the length of `data` at the end of the stream)
+    pub fn write(&mut self, data: &[u8]) -> Result<usize> {
+        let access = GuestAccess::Write(PrivilegeLevel(0));
+        let chunks = self.chunks(data.len(), access)?;
+        for (addr, range) in chunks.iter().cloned() {
+            self.space.write_bytes(
+                GuestVirtAddr::NoPaging(addr),
+                &data[range],
+                access,
+            )?;
+        }
+        Ok(self.advance(&chunks))
+    }
+
+    // The guest address of each piece of an access of up to `len` bytes at
+    // the current position, with the range of the buffer it corresponds to.
+    // Fails if any piece is not accessible.
+    fn chunks(
+        &self,
+        len: usize,
+        access: GuestAccess,
+    ) -> Result<Vec<(GuestPhysAddr, Range<usize>)>> {
+        let mut chunks = vec![];
+        let mut start = 0;
+        let mut done = 0;
+        for &(addr, seg_len) in self.segments.iter() {
+            let seg_end = start + seg_len;
+            let position = self.position + done;
+            if done < len && position < seg_end {
+                let count = (seg_end - position).min(len - done);
+                let chunk_addr = addr + (position - start);
+                validate_dma_range(&self.space, chunk_addr, count, access)?;
+                chunks.push((chunk_addr, done..done + count));
+                done += count;
+            }
+            start = seg_end;
+        }
+        Ok(chunks)
+    }
+
+    fn advance(&mut self, chunks: &[(GuestPhysAddr, Range<usize>)]) -> usize {
+        let count = chunks.iter().map(|(_, range)| range.len()).sum();
+        self.position += count;
+        count
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::GuestAddressSpace;
+    use alloc::boxed::Box;
+
+    // A view with guest memory mapped at 0x20000-0x23fff
+    fn define_test_ram_view() -> GuestAddressSpaceViewMut<'static> {
+        let space: &'static mut GuestAddressSpace =
+            Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
+        for i in 0..4 {
+            space
+                .map_new_frame(GuestPhysAddr::new(0x20000 + i * 4096), false)
+                .unwrap();
+        }
+        GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space)
+    }
+
+    fn poke(space: &mut GuestAddressSpaceViewMut, addr: u64, bytes: &[u8]) {
+        space
+            .write_bytes(
+                GuestVirtAddr::NoPaging(GuestPhysAddr::new(addr)),
+                bytes,
+                GuestAccess::Write(PrivilegeLevel(0)),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_read_across_segments() {
+        let mut space = define_test_ram_view();
+        poke(&mut space, 0x20ffe, &[1, 2, 3, 4]);
+        poke(&mut space, 0x23000, &[5, 6, 7]);
+
+        let segments = vec![
+            (GuestPhysAddr::new(0x20ffe), 4),
+            (GuestPhysAddr::new(0x23000), 3),
+        ];
+        let mut sg = ScatterGather::new(&mut space, segments).unwrap();
+        assert_eq!(sg.len(), 7);
+
+        let mut buff = [0u8; 5];
+        assert_eq!(sg.read(&mut buff).unwrap(), 5);
+        assert_eq!(buff, [1, 2, 3, 4, 5]);
+        assert_eq!(sg.read(&mut buff).unwrap(), 2);
+        assert_eq!(buff[..2], [6, 7]);
+        assert_eq!(sg.read(&mut buff).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_write_across_segments() {
+        let mut space = define_test_ram_view();
+        let segments = vec![
+            (GuestPhysAddr::new(0x21010), 2),
+            (GuestPhysAddr::new(0x22000), 0),
+            (GuestPhysAddr::new(0x21000), 3),
+        ];
+        let mut sg = ScatterGather::new(&mut space, segments).unwrap();
+        assert_eq!(sg.write(&[0xa, 0xb, 0xc, 0xd, 0xe, 0xf]).unwrap(), 5);
+        assert_eq!(sg.remaining(), 0);
+
+        let bytes = space
+            .read_bytes(
+                GuestVirtAddr::NoPaging(GuestPhysAddr::new(0x21000)),
+                0x12,
+                GuestAccess::Read(PrivilegeLevel(0)),
+            )
+            .unwrap();
+        assert_eq!(bytes[..3], [0xc, 0xd, 0xe]);
+        assert_eq!(bytes[0x10..], [0xa, 0xb]);
+    }
+
+    #[test]
+    fn test_invalid_segment() {
+        let mut space = define_test_ram_view();
+        let segments = vec![
+            (GuestPhysAddr::new(0x20000), 4),
+            (GuestPhysAddr::new(0x30000), 4),
+        ];
+        let mut sg = ScatterGather::new(&mut space, segments).unwrap();
+
+        // Nothing is read if any part of the access is invalid
+        let mut buff = [0u8; 8];
+        assert_eq!(
+            sg.read(&mut buff),
+            Err(Error::InvalidDmaRange {
+                addr: GuestPhysAddr::new(0x30000),
+                len: 4
+            })
+        );
+        assert_eq!(sg.position(), 0);
+        assert_eq!(sg.read(&mut buff[..4]).unwrap(), 4);
+        assert!(sg.read(&mut buff).is_err());
+
+        let segments = vec![(GuestPhysAddr::new(!0 - 1), 4)];
+        assert!(ScatterGather::new(&mut space, segments).is_err());
+    }
+}
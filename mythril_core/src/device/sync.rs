@@ -0,0 +1,275 @@
+use crate::device::interrupt::PendingInterrupt;
+use crate::device::{
+    DeviceDescription, DeviceKind, DeviceRegion, EmulatedDevice,
+    MemReadRequest, MemWriteRequest, Port, PortReadRequest, PortWriteRequest,
+    RegionAccess, RegionDelta,
+};
+use crate::error::Result;
+use crate::ioapic::TriggerMode;
+use crate::memory::{GuestAddressSpaceViewMut, GuestPhysAddr};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+use spin::{Mutex, MutexGuard};
+
+/// An `EmulatedDevice` that may be shared between threads
+pub trait SyncEmulatedDevice: EmulatedDevice + Send + Sync {}
+
+impl<T: EmulatedDevice + Send + Sync> SyncEmulatedDevice for T {}
+
+/// A device that can be shared by several vCPUs
+///
+/// Each vCPU holds its own `SyncDevice` handle (see `shared`), and every
+/// access takes a lock on the inner device, so accesses from different
+/// vCPUs are serialized and each one sees the complete effect of the
+/// last.
+///
+/// The lock is a spinlock that is taken on every access, including by
+/// `services` and the other descriptive methods. A vCPU accessing a
+/// contended device will spin until the other vCPU's access completes,
+/// so a device that is accessed often (or whose accesses are slow) is
+/// better placed in a per-vCPU device set when it has no state that must
+/// be shared.
+pub struct SyncDevice<T> {
+    inner: Arc<Mutex<T>>,
+}
+
+impl<T: EmulatedDevice + Send> SyncDevice<T> {
+    pub fn new(device: T) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(device)),
+        }
+    }
+
+    /// Another handle to the same device
+    pub fn shared(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Lock the inner device, waiting for any in-progress access
+    pub fn lock(&self) -> MutexGuard<T> {
+        self.inner.lock()
+    }
+}
+
+impl<T: EmulatedDevice + Send + 'static> EmulatedDevice for SyncDevice<T> {
+    fn services(&self) -> Vec<DeviceRegion> {
+        self.lock().services()
+    }
+
+    fn debug_name(&self) -> &'static str {
+        self.lock().debug_name()
+    }
+
+    fn kind(&self) -> Option<DeviceKind> {
+        self.lock().kind()
+    }
+
+    fn depends_on(&self) -> Vec<DeviceKind> {
+        self.lock().depends_on()
+    }
+
+    fn irq_lines(&self) -> Vec<u8> {
+        self.lock().irq_lines()
+    }
+
+    fn irq_trigger_mode(&self, irq: u8) -> TriggerMode {
+        self.lock().irq_trigger_mode(irq)
+    }
+
+    fn region_changed(&self) -> Option<RegionDelta> {
+        self.lock().region_changed()
+    }
+
+    fn region_access(&self, region: &DeviceRegion) -> RegionAccess {
+        self.lock().region_access(region)
+    }
+
+    fn msr_ranges(&self) -> Vec<RangeInclusive<u32>> {
+        self.lock().msr_ranges()
+    }
+
+    fn describe(&self) -> DeviceDescription {
+        self.lock().describe()
+    }
+
+    fn acknowledge_interrupt(&mut self) -> Option<PendingInterrupt> {
+        self.lock().acknowledge_interrupt()
+    }
+
+    fn on_msr_read(&mut self, msr: u32) -> Result<u64> {
+        self.lock().on_msr_read(msr)
+    }
+
+    fn on_msr_write(&mut self, msr: u32, val: u64) -> Result<()> {
+        self.lock().on_msr_write(msr, val)
+    }
+
+    fn poll(&mut self, now: u64) {
+        self.lock().poll(now)
+    }
+
+    fn reset(&mut self) {
+        self.lock().reset()
+    }
+
+    fn on_mem_read(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemReadRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.lock().on_mem_read(addr, data, space)
+    }
+
+    fn on_mem_write(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemWriteRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.lock().on_mem_write(addr, data, space)
+    }
+
+    fn on_port_read(
+        &mut self,
+        port: Port,
+        val: PortReadRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.lock().on_port_read(port, val, space)
+    }
+
+    fn on_port_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.lock().on_port_write(port, val, space)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::memory::GuestAddressSpace;
+    use alloc::boxed::Box;
+    use core::convert::TryInto;
+
+    // A device whose writes are a non-atomic read-modify-write of two
+    // registers that must always be equal
+    #[derive(Default)]
+    struct PairCounter {
+        low: u32,
+        high: u32,
+    }
+
+    impl EmulatedDevice for PairCounter {
+        fn services(&self) -> Vec<DeviceRegion> {
+            vec![DeviceRegion::PortIo(0x80..=0x80)]
+        }
+
+        fn on_port_read(
+            &mut self,
+            _port: Port,
+            mut val: PortReadRequest,
+            _space: GuestAddressSpaceViewMut,
+        ) -> Result<()> {
+            assert_eq!(self.low, self.high);
+            val.copy_from_u32(self.low);
+            Ok(())
+        }
+
+        fn on_port_write(
+            &mut self,
+            _port: Port,
+            val: PortWriteRequest,
+            _space: GuestAddressSpaceViewMut,
+        ) -> Result<()> {
+            let inc: u8 = val.try_into()?;
+            let low = self.low;
+            core::sync::atomic::spin_loop_hint();
+            self.low = low + inc as u32;
+            let high = self.high;
+            core::sync::atomic::spin_loop_hint();
+            self.high = high + inc as u32;
+            Ok(())
+        }
+    }
+
+    fn read_count(dev: &mut SyncDevice<PairCounter>) -> u32 {
+        let mut space = GuestAddressSpace::new().unwrap();
+        let mut arr = [0u8; 4];
+        dev.on_port_read(
+            0x80,
+            PortReadRequest::FourBytes(&mut arr),
+            GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), &mut space),
+        )
+        .unwrap();
+        u32::from_be_bytes(arr)
+    }
+
+    #[test]
+    fn test_sync_device_forwards() {
+        let mut dev = SyncDevice::new(PairCounter::default());
+        let mut other = dev.shared();
+        assert_eq!(other.services(), dev.lock().services());
+        assert_eq!(other.debug_name(), "PairCounter");
+
+        let mut space = GuestAddressSpace::new().unwrap();
+        for handle in [&mut dev, &mut other].iter_mut() {
+            handle
+                .on_port_write(
+                    0x80,
+                    PortWriteRequest::OneByte(&[3]),
+                    GuestAddressSpaceViewMut::new(
+                        GuestPhysAddr::new(0),
+                        &mut space,
+                    ),
+                )
+                .unwrap();
+        }
+        assert_eq!(read_count(&mut dev), 6);
+        assert_eq!(read_count(&mut other), 6);
+    }
+
+    #[test]
+    fn test_sync_device_serializes_threads() {
+        const THREADS: u32 = 4;
+        const WRITES: u32 = 500;
+
+        let dev = SyncDevice::new(PairCounter::default());
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let mut handle = dev.shared();
+                std::thread::spawn(move || {
+                    let mut space = Box::new(GuestAddressSpace::new().unwrap());
+                    for _ in 0..WRITES {
+                        handle
+                            .on_port_write(
+                                0x80,
+                                PortWriteRequest::OneByte(&[1]),
+                                GuestAddressSpaceViewMut::new(
+                                    GuestPhysAddr::new(0),
+                                    &mut space,
+                                ),
+                            )
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let state = dev.lock();
+        assert_eq!(state.low, THREADS * WRITES);
+        assert_eq!(state.high, THREADS * WRITES);
+    }
+}
@@ -0,0 +1,544 @@
+use crate::device::{
+    require_len, DeviceRegion, EmulatedDevice, MemReadRequest, MemWriteRequest,
+};
+use crate::error::Result;
+use crate::memory::{GuestAddressSpaceViewMut, GuestPhysAddr};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// The host side of an emulated TPM
+pub trait TpmBackend {
+    /// Execute a serialized TPM command, returning the serialized response
+    fn execute(&mut self, command: &[u8]) -> Vec<u8>;
+}
+
+/// A backend without a TPM behind it, which fails every command
+#[derive(Default)]
+pub struct NullTpmBackend;
+
+impl NullTpmBackend {
+    // A TPM_ST_NO_SESSIONS response with TPM_RC_FAILURE
+    const FAILURE_RESPONSE: [u8; 10] =
+        [0x80, 0x01, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x01, 0x01];
+}
+
+impl TpmBackend for NullTpmBackend {
+    fn execute(&mut self, _command: &[u8]) -> Vec<u8> {
+        Self::FAILURE_RESPONSE.to_vec()
+    }
+}
+
+#[allow(non_snake_case)]
+#[allow(dead_code)]
+mod TisOffset {
+    pub const ACCESS: u64 = 0x00;
+    pub const INT_ENABLE: u64 = 0x08;
+    pub const INT_VECTOR: u64 = 0x0c;
+    pub const INT_STATUS: u64 = 0x10;
+    pub const INTF_CAPABILITY: u64 = 0x14;
+    pub const STS: u64 = 0x18;
+    pub const DATA_FIFO: u64 = 0x24;
+    pub const DID_VID: u64 = 0xf00;
+    pub const RID: u64 = 0xf04;
+}
+
+// The progress of a command through the FIFO interface
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TisState {
+    Idle,
+    Ready,
+    Reception,
+    Completion,
+}
+
+/// A TPM 2.0 with the FIFO (TIS) interface
+///
+/// Each of the five localities has a 4KB register window. A locality must
+/// be requested through its access register before it can use the status
+/// register and data FIFO. Commands are executed synchronously by the
+/// `TpmBackend` when the guest sets tpmGo. Interrupts are not supported,
+/// so the guest must poll the status register.
+pub struct TpmTis {
+    base: GuestPhysAddr,
+    backend: Box<dyn TpmBackend>,
+
+    // The locality that owns the interface, and the localities waiting for
+    // it (a bit for each)
+    active_locality: Option<u8>,
+    pending_localities: u8,
+
+    state: TisState,
+    int_enable: u32,
+
+    // The command being received, and the response with the offset of the
+    // next byte to be read
+    command: Vec<u8>,
+    response: Vec<u8>,
+    response_offset: usize,
+}
+
+impl TpmTis {
+    /// The conventional base of the TIS register windows
+    pub const DEFAULT_BASE: u64 = 0xfed4_0000;
+
+    pub const LOCALITY_COUNT: u8 = 5;
+    const LOCALITY_SIZE: u64 = 0x1000;
+
+    /// The largest command that will be received
+    pub const BUFFER_SIZE: usize = 4096;
+
+    // The command header is a tag (2 bytes), then the size (4 bytes)
+    const HEADER_SIZE: usize = 6;
+
+    const ACCESS_REQUEST_USE: u8 = 1 << 1;
+    const ACCESS_PENDING_REQUEST: u8 = 1 << 2;
+    const ACCESS_ACTIVE_LOCALITY: u8 = 1 << 5;
+    const ACCESS_VALID: u8 = 1 << 7;
+
+    const STS_RESPONSE_RETRY: u32 = 1 << 1;
+    const STS_EXPECT: u32 = 1 << 3;
+    const STS_DATA_AVAILABLE: u32 = 1 << 4;
+    const STS_GO: u32 = 1 << 5;
+    const STS_COMMAND_READY: u32 = 1 << 6;
+    const STS_VALID: u32 = 1 << 7;
+    const STS_BURST_COUNT_SHIFT: u32 = 8;
+    const STS_FAMILY_TPM2: u32 = 0b01 << 26;
+
+    // Up to 64 byte transfers, and version 1.3 of the interface for TPM 2.0
+    const INTF_CAPABILITY: u32 = 0b11 << 9 | 0b011 << 28;
+
+    // The vendor and device ID (as used by the QEMU TPM)
+    const DID_VID: u32 = 0x0001_1014;
+    const RID: u32 = 0x01;
+
+    pub fn new(base: GuestPhysAddr, backend: Box<dyn TpmBackend>) -> Box<Self> {
+        Box::new(Self {
+            base,
+            backend,
+            active_locality: None,
+            pending_localities: 0,
+            state: TisState::Idle,
+            int_enable: 0,
+            command: vec![],
+            response: vec![],
+            response_offset: 0,
+        })
+    }
+
+    /// The locality that currently owns the interface, if any
+    pub fn active_locality(&self) -> Option<u8> {
+        self.active_locality
+    }
+
+    // The length of the command being received (once its header is in)
+    fn command_size(&self) -> Option<usize> {
+        let size = self.command.get(2..Self::HEADER_SIZE)?;
+        Some(
+            size.iter()
+                .fold(0usize, |size, byte| size << 8 | *byte as usize),
+        )
+    }
+
+    fn expects_data(&self) -> bool {
+        match self.command_size() {
+            Some(size) => self.command.len() < size,
+            None => true,
+        }
+    }
+
+    fn read_access(&self, locality: u8) -> u8 {
+        let mut access = Self::ACCESS_VALID;
+        match self.active_locality {
+            Some(active) if active == locality => {
+                access |= Self::ACCESS_ACTIVE_LOCALITY;
+                if self.pending_localities != 0 {
+                    access |= Self::ACCESS_PENDING_REQUEST;
+                }
+            }
+            _ => {
+                if self.pending_localities & (1 << locality) != 0 {
+                    access |= Self::ACCESS_REQUEST_USE;
+                }
+            }
+        }
+        access
+    }
+
+    fn write_access(&mut self, locality: u8, val: u8) {
+        if val & Self::ACCESS_REQUEST_USE != 0 {
+            match self.active_locality {
+                None => self.active_locality = Some(locality),
+                Some(active) if active != locality => {
+                    self.pending_localities |= 1 << locality
+                }
+                Some(_) => (),
+            }
+        }
+        if val & Self::ACCESS_ACTIVE_LOCALITY != 0 {
+            self.pending_localities &= !(1 << locality);
+            if self.active_locality == Some(locality) {
+                self.release_locality();
+            }
+        }
+    }
+
+    // Hand the interface to the highest pending locality (if any)
+    fn release_locality(&mut self) {
+        self.state = TisState::Idle;
+        self.active_locality = (0..Self::LOCALITY_COUNT)
+            .rev()
+            .find(|locality| self.pending_localities & (1 << locality) != 0);
+        if let Some(locality) = self.active_locality {
+            self.pending_localities &= !(1 << locality);
+        }
+    }
+
+    fn read_status(&self) -> u32 {
+        let (flags, burst_count) = match self.state {
+            TisState::Idle => (0, 0),
+            TisState::Ready => (Self::STS_COMMAND_READY, Self::BUFFER_SIZE),
+            TisState::Reception => {
+                let expect = if self.expects_data() {
+                    Self::STS_EXPECT
+                } else {
+                    0
+                };
+                (expect, Self::BUFFER_SIZE - self.command.len())
+            }
+            TisState::Completion => {
+                let remaining = self.response.len() - self.response_offset;
+                let available = if remaining > 0 {
+                    Self::STS_DATA_AVAILABLE
+                } else {
+                    0
+                };
+                (available, remaining)
+            }
+        };
+        let burst_count = burst_count.min(0xffff) as u32;
+        Self::STS_VALID
+            | Self::STS_FAMILY_TPM2
+            | flags
+            | burst_count << Self::STS_BURST_COUNT_SHIFT
+    }
+
+    fn write_status(&mut self, val: u32) {
+        if val & Self::STS_COMMAND_READY != 0 {
+            // Abort whatever was in progress
+            self.command.clear();
+            self.response.clear();
+            self.response_offset = 0;
+            self.state = TisState::Ready;
+        }
+        if val & Self::STS_GO != 0 {
+            if self.state == TisState::Reception && !self.expects_data() {
+                self.response = self.backend.execute(&self.command);
+                self.response_offset = 0;
+                self.command.clear();
+                self.state = TisState::Completion;
+            } else {
+                info!("Ignoring TPM go in state {:?}", self.state);
+            }
+        }
+        if val & Self::STS_RESPONSE_RETRY != 0
+            && self.state == TisState::Completion
+        {
+            self.response_offset = 0;
+        }
+    }
+
+    fn write_fifo(&mut self, bytes: &[u8]) {
+        match self.state {
+            TisState::Ready | TisState::Reception => (),
+            state => {
+                info!("Dropping TPM FIFO write in state {:?}", state);
+                return;
+            }
+        }
+        self.state = TisState::Reception;
+        for byte in bytes {
+            if self.command.len() == Self::BUFFER_SIZE {
+                info!("Dropping TPM command bytes beyond the buffer");
+                break;
+            }
+            self.command.push(*byte);
+        }
+    }
+
+    fn read_fifo(&mut self, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| match self.state {
+                TisState::Completion => {
+                    let byte = self.response.get(self.response_offset);
+                    if byte.is_some() {
+                        self.response_offset += 1;
+                    }
+                    byte.copied().unwrap_or(0xff)
+                }
+                _ => 0xff,
+            })
+            .collect()
+    }
+
+    fn read_register(&self, locality: u8, register: u64) -> u32 {
+        let is_active = self.active_locality == Some(locality);
+        match register {
+            TisOffset::ACCESS => self.read_access(locality) as u32,
+            TisOffset::INT_ENABLE => self.int_enable,
+            TisOffset::INTF_CAPABILITY => Self::INTF_CAPABILITY,
+            TisOffset::STS if is_active => self.read_status(),
+            TisOffset::STS => 0xffffffff,
+            TisOffset::DID_VID => Self::DID_VID,
+            TisOffset::RID => Self::RID,
+            _ => 0,
+        }
+    }
+
+    // Split an address into the locality and the offset in its window
+    fn decode(&self, addr: GuestPhysAddr) -> (u8, u64) {
+        let offset = addr.as_u64() - self.base.as_u64();
+        (
+            (offset / Self::LOCALITY_SIZE) as u8,
+            offset % Self::LOCALITY_SIZE,
+        )
+    }
+}
+
+impl EmulatedDevice for TpmTis {
+    fn services(&self) -> Vec<DeviceRegion> {
+        let size = Self::LOCALITY_SIZE * Self::LOCALITY_COUNT as u64;
+        vec![DeviceRegion::MemIo(
+            self.base..=GuestPhysAddr::new(self.base.as_u64() + size - 1),
+        )]
+    }
+
+    fn reset(&mut self) {
+        self.active_locality = None;
+        self.pending_localities = 0;
+        self.state = TisState::Idle;
+        self.int_enable = 0;
+        self.command.clear();
+        self.response.clear();
+        self.response_offset = 0;
+    }
+
+    fn on_mem_read(
+        &mut self,
+        addr: GuestPhysAddr,
+        mut data: MemReadRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let len = data.as_slice().len();
+        require_len(len, &[1, 2, 4])?;
+        let (locality, offset) = self.decode(addr);
+        let is_active = self.active_locality == Some(locality);
+
+        // The first byte read from the FIFO is the least significant
+        let val = if offset & !0b11 == TisOffset::DATA_FIFO {
+            let bytes = if is_active {
+                self.read_fifo(len)
+            } else {
+                vec![0xff; len]
+            };
+            bytes
+                .iter()
+                .rev()
+                .fold(0u32, |val, byte| val << 8 | *byte as u32)
+        } else {
+            let shift = (offset & 0b11) * 8;
+            self.read_register(locality, offset & !0b11) >> shift
+        };
+        data.copy_from_u32(val);
+        Ok(())
+    }
+
+    fn on_mem_write(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemWriteRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let len = data.as_slice().len();
+        require_len(len, &[1, 2, 4])?;
+        let (locality, offset) = self.decode(addr);
+        let val = data
+            .as_slice()
+            .iter()
+            .fold(0u32, |val, byte| val << 8 | *byte as u32);
+
+        if offset == TisOffset::ACCESS {
+            self.write_access(locality, val as u8);
+            return Ok(());
+        }
+        if self.active_locality != Some(locality) {
+            info!(
+                "Ignoring TPM write from inactive locality {} (offset 0x{:x})",
+                locality, offset
+            );
+            return Ok(());
+        }
+
+        let shift = (offset & 0b11) * 8;
+        match offset & !0b11 {
+            TisOffset::INT_ENABLE => self.int_enable = val << shift,
+            TisOffset::STS => self.write_status(val << shift),
+            TisOffset::DATA_FIFO => {
+                self.write_fifo(&val.to_le_bytes()[..len]);
+            }
+            _ => {
+                info!("Ignoring write to TPM register 0x{:x}", offset);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::define_test_view;
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    #[derive(Default)]
+    struct MockBackend {
+        commands: Rc<RefCell<Vec<Vec<u8>>>>,
+    }
+
+    impl TpmBackend for MockBackend {
+        fn execute(&mut self, command: &[u8]) -> Vec<u8> {
+            self.commands.borrow_mut().push(command.to_vec());
+            vec![0x80, 0x01, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x00]
+        }
+    }
+
+    fn tis_addr(locality: u8, offset: u64) -> GuestPhysAddr {
+        GuestPhysAddr::new(
+            TpmTis::DEFAULT_BASE + locality as u64 * 0x1000 + offset,
+        )
+    }
+
+    fn read_tis(tpm: &mut TpmTis, locality: u8, offset: u64) -> u32 {
+        let mut buff = [0u8; 4];
+        tpm.on_mem_read(
+            tis_addr(locality, offset),
+            MemReadRequest::new(&mut buff),
+            define_test_view(),
+        )
+        .unwrap();
+        u32::from_be_bytes(buff)
+    }
+
+    fn read_tis_byte(tpm: &mut TpmTis, locality: u8, offset: u64) -> u8 {
+        let mut buff = [0u8; 1];
+        tpm.on_mem_read(
+            tis_addr(locality, offset),
+            MemReadRequest::new(&mut buff),
+            define_test_view(),
+        )
+        .unwrap();
+        buff[0]
+    }
+
+    fn write_tis(tpm: &mut TpmTis, locality: u8, offset: u64, data: &[u8]) {
+        tpm.on_mem_write(
+            tis_addr(locality, offset),
+            MemWriteRequest::new(data),
+            define_test_view(),
+        )
+        .unwrap();
+    }
+
+    fn new_tpm() -> (Box<TpmTis>, Rc<RefCell<Vec<Vec<u8>>>>) {
+        let backend = MockBackend::default();
+        let commands = backend.commands.clone();
+        let tpm = TpmTis::new(
+            GuestPhysAddr::new(TpmTis::DEFAULT_BASE),
+            Box::new(backend),
+        );
+        (tpm, commands)
+    }
+
+    #[test]
+    fn test_locality_handshake() {
+        let (mut tpm, _) = new_tpm();
+        assert_eq!(read_tis_byte(&mut tpm, 0, TisOffset::ACCESS), 0x80);
+        assert_eq!(read_tis(&mut tpm, 0, TisOffset::DID_VID), 0x0001_1014);
+
+        write_tis(&mut tpm, 0, TisOffset::ACCESS, &[0x02]);
+        assert_eq!(tpm.active_locality(), Some(0));
+        assert_eq!(read_tis_byte(&mut tpm, 0, TisOffset::ACCESS), 0xa0);
+
+        // Another locality waits for the active one to release the TPM
+        write_tis(&mut tpm, 2, TisOffset::ACCESS, &[0x02]);
+        assert_eq!(read_tis_byte(&mut tpm, 2, TisOffset::ACCESS), 0x82);
+        assert_eq!(read_tis_byte(&mut tpm, 0, TisOffset::ACCESS), 0xa4);
+        assert_eq!(read_tis(&mut tpm, 2, TisOffset::STS), 0xffffffff);
+
+        write_tis(&mut tpm, 0, TisOffset::ACCESS, &[0x20]);
+        assert_eq!(tpm.active_locality(), Some(2));
+        assert_eq!(read_tis_byte(&mut tpm, 0, TisOffset::ACCESS), 0x80);
+        assert_eq!(read_tis_byte(&mut tpm, 2, TisOffset::ACCESS), 0xa0);
+
+        write_tis(&mut tpm, 2, TisOffset::ACCESS, &[0x20]);
+        assert_eq!(tpm.active_locality(), None);
+    }
+
+    #[test]
+    fn test_command_response() {
+        let (mut tpm, commands) = new_tpm();
+        write_tis(&mut tpm, 0, TisOffset::ACCESS, &[0x02]);
+
+        // Nothing is accepted until the TPM is made ready
+        write_tis(&mut tpm, 0, TisOffset::DATA_FIFO, &[0x80]);
+        write_tis(&mut tpm, 0, TisOffset::STS, &[0x40]);
+        let sts = read_tis(&mut tpm, 0, TisOffset::STS);
+        assert_ne!(sts & TpmTis::STS_COMMAND_READY, 0);
+        assert_eq!((sts >> 8) & 0xffff, TpmTis::BUFFER_SIZE as u32);
+
+        // TPM2_Startup(TPM_SU_CLEAR), a byte and then a dword at a time
+        let startup = [
+            0x80, 0x01, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x01, 0x44, 0x00,
+            0x00,
+        ];
+        for byte in &startup[..4] {
+            write_tis(&mut tpm, 0, TisOffset::DATA_FIFO, &[*byte]);
+        }
+        let sts = read_tis(&mut tpm, 0, TisOffset::STS);
+        assert_ne!(sts & TpmTis::STS_EXPECT, 0);
+        for chunk in startup[4..].chunks(4) {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(chunk);
+            bytes.reverse();
+            write_tis(&mut tpm, 0, TisOffset::DATA_FIFO, &bytes);
+        }
+        let sts = read_tis(&mut tpm, 0, TisOffset::STS);
+        assert_eq!(sts & (TpmTis::STS_EXPECT | TpmTis::STS_VALID), 0x80);
+
+        write_tis(&mut tpm, 0, TisOffset::STS, &[0x20]);
+        assert_eq!(*commands.borrow(), vec![startup.to_vec()]);
+
+        let sts = read_tis(&mut tpm, 0, TisOffset::STS);
+        assert_ne!(sts & TpmTis::STS_DATA_AVAILABLE, 0);
+        assert_eq!((sts >> 8) & 0xffff, 10);
+
+        let mut response = vec![];
+        for _ in 0..6 {
+            response.push(read_tis_byte(&mut tpm, 0, TisOffset::DATA_FIFO));
+        }
+        let dword = read_tis(&mut tpm, 0, TisOffset::DATA_FIFO);
+        response.extend_from_slice(&dword.to_le_bytes());
+        assert_eq!(
+            response,
+            [0x80, 0x01, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x00]
+        );
+        let sts = read_tis(&mut tpm, 0, TisOffset::STS);
+        assert_eq!(sts & TpmTis::STS_DATA_AVAILABLE, 0);
+
+        // The response can be read again, until the TPM is made ready
+        write_tis(&mut tpm, 0, TisOffset::STS, &[0x02]);
+        assert_eq!(read_tis_byte(&mut tpm, 0, TisOffset::DATA_FIFO), 0x80);
+        write_tis(&mut tpm, 0, TisOffset::STS, &[0x40]);
+        assert_eq!(read_tis_byte(&mut tpm, 0, TisOffset::DATA_FIFO), 0xff);
+    }
+}
@@ -0,0 +1,449 @@
+use crate::device::interrupt::PendingInterrupt;
+use crate::device::{
+    DeviceDescription, DeviceKind, DeviceRegion, EmulatedDevice,
+    MemReadRequest, MemWriteRequest, OwnedPortRead, OwnedPortWrite, Port,
+    PortReadRequest, PortWriteRequest, RegionAccess, RegionDelta,
+};
+use crate::error::{Error, Result};
+use crate::ioapic::TriggerMode;
+use crate::memory::{
+    GuestAddressSpace, GuestAddressSpaceViewMut, GuestPhysAddr,
+};
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt::Write;
+use core::ops::RangeInclusive;
+
+/// A single access to a device, with the value written or read
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoggedAccess {
+    PortRead { port: Port, value: OwnedPortRead },
+    PortWrite { port: Port, value: OwnedPortWrite },
+    MemRead { addr: GuestPhysAddr, data: Vec<u8> },
+    MemWrite { addr: GuestPhysAddr, data: Vec<u8> },
+}
+
+/// The accesses made to a device, in order
+///
+/// A log can be saved as text with one access per line, in the form
+/// `<kind> <address> <bytes>`, where `kind` is one of `in`, `out`, `read`
+/// or `write`, the address is hexadecimal and the bytes are hexadecimal
+/// pairs in request order (e.g., `in 3fd 60`).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AccessLog {
+    accesses: Vec<LoggedAccess>,
+}
+
+impl AccessLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, access: LoggedAccess) {
+        self.accesses.push(access);
+    }
+
+    pub fn accesses(&self) -> &[LoggedAccess] {
+        &self.accesses
+    }
+
+    pub fn len(&self) -> usize {
+        self.accesses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accesses.is_empty()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for access in self.accesses.iter() {
+            let (kind, addr, data) = match access {
+                LoggedAccess::PortRead { port, value } => {
+                    ("in", *port as u64, value.as_slice())
+                }
+                LoggedAccess::PortWrite { port, value } => {
+                    ("out", *port as u64, value.as_request().as_slice())
+                }
+                LoggedAccess::MemRead { addr, data } => {
+                    ("read", addr.as_u64(), &data[..])
+                }
+                LoggedAccess::MemWrite { addr, data } => {
+                    ("write", addr.as_u64(), &data[..])
+                }
+            };
+            // Writing to a String cannot fail
+            let _ = write!(text, "{} {:x} ", kind, addr);
+            for byte in data {
+                let _ = write!(text, "{:02x}", byte);
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    pub fn from_text(text: &str) -> Result<Self> {
+        let mut log = Self::new();
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            log.push(Self::parse_access(line)?);
+        }
+        Ok(log)
+    }
+
+    fn parse_access(line: &str) -> Result<LoggedAccess> {
+        let invalid =
+            || Error::InvalidValue(format!("Invalid access: '{}'", line));
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 3 {
+            return Err(invalid());
+        }
+        let addr = u64::from_str_radix(fields[1], 16).map_err(|_| invalid())?;
+        let data = Self::parse_bytes(fields[2]).ok_or_else(invalid)?;
+
+        let port = || {
+            if addr > Port::MAX as u64 {
+                return Err(invalid());
+            }
+            Ok(addr as Port)
+        };
+        match (fields[0], &data[..]) {
+            ("in", [a]) => Ok(LoggedAccess::PortRead {
+                port: port()?,
+                value: OwnedPortRead::OneByte([*a]),
+            }),
+            ("in", [a, b]) => Ok(LoggedAccess::PortRead {
+                port: port()?,
+                value: OwnedPortRead::TwoBytes([*a, *b]),
+            }),
+            ("in", [a, b, c, d]) => Ok(LoggedAccess::PortRead {
+                port: port()?,
+                value: OwnedPortRead::FourBytes([*a, *b, *c, *d]),
+            }),
+            ("out", [a]) => Ok(LoggedAccess::PortWrite {
+                port: port()?,
+                value: OwnedPortWrite::OneByte([*a]),
+            }),
+            ("out", [a, b]) => Ok(LoggedAccess::PortWrite {
+                port: port()?,
+                value: OwnedPortWrite::TwoBytes([*a, *b]),
+            }),
+            ("out", [a, b, c, d]) => Ok(LoggedAccess::PortWrite {
+                port: port()?,
+                value: OwnedPortWrite::FourBytes([*a, *b, *c, *d]),
+            }),
+            ("read", _) => Ok(LoggedAccess::MemRead {
+                addr: GuestPhysAddr::new(addr),
+                data,
+            }),
+            ("write", _) => Ok(LoggedAccess::MemWrite {
+                addr: GuestPhysAddr::new(addr),
+                data,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn parse_bytes(hex: &str) -> Option<Vec<u8>> {
+        if hex.len() % 2 != 0 || !hex.is_ascii() {
+            return None;
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect()
+    }
+}
+
+/// A wrapper that records every successful access to a device in a shared
+/// `AccessLog`, so an interaction can be saved and later replayed
+pub struct TracedDevice {
+    inner: Box<dyn EmulatedDevice>,
+    log: Rc<RefCell<AccessLog>>,
+}
+
+impl TracedDevice {
+    pub fn new(inner: Box<dyn EmulatedDevice>) -> Box<Self> {
+        Box::new(Self {
+            inner,
+            log: Rc::new(RefCell::new(AccessLog::new())),
+        })
+    }
+
+    /// The log of accesses to the device (which remains available after
+    /// the device is registered)
+    pub fn log(&self) -> Rc<RefCell<AccessLog>> {
+        self.log.clone()
+    }
+}
+
+impl EmulatedDevice for TracedDevice {
+    fn services(&self) -> Vec<DeviceRegion> {
+        self.inner.services()
+    }
+
+    fn debug_name(&self) -> &'static str {
+        self.inner.debug_name()
+    }
+
+    fn kind(&self) -> Option<DeviceKind> {
+        self.inner.kind()
+    }
+
+    fn depends_on(&self) -> Vec<DeviceKind> {
+        self.inner.depends_on()
+    }
+
+    fn irq_lines(&self) -> Vec<u8> {
+        self.inner.irq_lines()
+    }
+
+    fn irq_trigger_mode(&self, irq: u8) -> TriggerMode {
+        self.inner.irq_trigger_mode(irq)
+    }
+
+    fn region_changed(&self) -> Option<RegionDelta> {
+        self.inner.region_changed()
+    }
+
+    fn region_access(&self, region: &DeviceRegion) -> RegionAccess {
+        self.inner.region_access(region)
+    }
+
+    fn msr_ranges(&self) -> Vec<RangeInclusive<u32>> {
+        self.inner.msr_ranges()
+    }
+
+    fn describe(&self) -> DeviceDescription {
+        self.inner.describe()
+    }
+
+    fn acknowledge_interrupt(&mut self) -> Option<PendingInterrupt> {
+        self.inner.acknowledge_interrupt()
+    }
+
+    fn on_msr_read(&mut self, msr: u32) -> Result<u64> {
+        self.inner.on_msr_read(msr)
+    }
+
+    fn on_msr_write(&mut self, msr: u32, val: u64) -> Result<()> {
+        self.inner.on_msr_write(msr, val)
+    }
+
+    fn poll(&mut self, now: u64) {
+        self.inner.poll(now)
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset()
+    }
+
+    fn on_mem_read(
+        &mut self,
+        addr: GuestPhysAddr,
+        mut data: MemReadRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.inner.on_mem_read(
+            addr,
+            MemReadRequest::new(data.as_mut_slice()),
+            space,
+        )?;
+        self.log.borrow_mut().push(LoggedAccess::MemRead {
+            addr,
+            data: data.as_slice().to_vec(),
+        });
+        Ok(())
+    }
+
+    fn on_mem_write(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemWriteRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let logged = LoggedAccess::MemWrite {
+            addr,
+            data: data.as_slice().to_vec(),
+        };
+        self.inner.on_mem_write(addr, data, space)?;
+        self.log.borrow_mut().push(logged);
+        Ok(())
+    }
+
+    fn on_port_read(
+        &mut self,
+        port: Port,
+        mut val: PortReadRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let mut value = OwnedPortRead::from(&val);
+        self.inner.on_port_read(port, value.as_request(), space)?;
+        val.fill_from_slice(value.as_slice())?;
+        self.log
+            .borrow_mut()
+            .push(LoggedAccess::PortRead { port, value });
+        Ok(())
+    }
+
+    fn on_port_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let value = OwnedPortWrite::from(&val);
+        self.inner.on_port_write(port, val, space)?;
+        self.log
+            .borrow_mut()
+            .push(LoggedAccess::PortWrite { port, value });
+        Ok(())
+    }
+}
+
+/// Re-drive `dev` with the accesses in `log`, failing with
+/// `Error::ReplayDivergence` at the first read whose result differs from
+/// the logged value
+pub fn replay(
+    dev: &mut dyn EmulatedDevice,
+    log: &AccessLog,
+    space: &mut GuestAddressSpace,
+) -> Result<()> {
+    for (index, access) in log.accesses().iter().enumerate() {
+        let view = GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space);
+        let (expected, actual) = match access {
+            LoggedAccess::PortRead { port, value } => {
+                let mut actual = *value;
+                dev.on_port_read(*port, actual.as_request(), view)?;
+                (value.as_slice().to_vec(), actual.as_slice().to_vec())
+            }
+            LoggedAccess::PortWrite { port, value } => {
+                dev.on_port_write(*port, value.as_request(), view)?;
+                continue;
+            }
+            LoggedAccess::MemRead { addr, data } => {
+                let mut actual = vec![0u8; data.len()];
+                dev.on_mem_read(*addr, MemReadRequest::new(&mut actual), view)?;
+                (data.clone(), actual)
+            }
+            LoggedAccess::MemWrite { addr, data } => {
+                dev.on_mem_write(*addr, MemWriteRequest::new(data), view)?;
+                continue;
+            }
+        };
+        if expected != actual {
+            return Err(Error::ReplayDivergence {
+                index,
+                expected,
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::com::ComDevice;
+    use crate::device::test_util::define_test_view;
+    use crate::device::DeviceMap;
+
+    // The UART registers used by the tests
+    const DATA: u16 = 0;
+    const IER: u16 = 1;
+    const IIR: u16 = 2;
+    const LSR: u16 = 5;
+    const SCR: u16 = 7;
+
+    fn write_com(map: &mut DeviceMap, reg: u16, val: u8) {
+        map.on_port_write(
+            0x3f8 + reg,
+            PortWriteRequest::OneByte(&[val]),
+            define_test_view(),
+        )
+        .unwrap();
+    }
+
+    fn read_com(map: &mut DeviceMap, reg: u16) -> u8 {
+        let mut arr = [0u8];
+        map.on_port_read(
+            0x3f8 + reg,
+            PortReadRequest::OneByte(&mut arr),
+            define_test_view(),
+        )
+        .unwrap();
+        arr[0]
+    }
+
+    // Capture a short UART interaction
+    fn capture() -> (AccessLog, Vec<u8>) {
+        let com = TracedDevice::new(ComDevice::new(0, 0x3f8));
+        let log = com.log();
+        let mut map = DeviceMap::default();
+        map.register_device(com).unwrap();
+
+        let mut reads = vec![];
+        write_com(&mut map, SCR, 0x5a);
+        reads.push(read_com(&mut map, SCR));
+        write_com(&mut map, IER, 0x02);
+        reads.push(read_com(&mut map, IIR));
+        write_com(&mut map, DATA, b'h');
+        reads.push(read_com(&mut map, LSR));
+        reads.push(read_com(&mut map, IER));
+
+        let log = log.borrow().clone();
+        (log, reads)
+    }
+
+    #[test]
+    fn test_replay_uart() {
+        let (log, reads) = capture();
+        assert_eq!(log.len(), 7);
+        let logged: Vec<u8> = log
+            .accesses()
+            .iter()
+            .filter_map(|access| match access {
+                LoggedAccess::PortRead { value, .. } => {
+                    Some(value.as_u32() as u8)
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(logged, reads);
+
+        // The log survives a round trip through its text form, and
+        // replaying it against a fresh UART reproduces the same reads
+        let log = AccessLog::from_text(&log.to_text()).unwrap();
+        let mut space = GuestAddressSpace::new().unwrap();
+        let mut com = ComDevice::new(0, 0x3f8);
+        replay(&mut *com, &log, &mut space).unwrap();
+    }
+
+    #[test]
+    fn test_replay_divergence() {
+        let (log, _) = capture();
+
+        // A UART with a received byte reports it in the line status
+        let mut space = GuestAddressSpace::new().unwrap();
+        let mut com = ComDevice::new(0, 0x3f8);
+        com.receive(b'x').unwrap();
+        match replay(&mut *com, &log, &mut space) {
+            Err(Error::ReplayDivergence {
+                index,
+                expected,
+                actual,
+            }) => {
+                assert_eq!(index, 5);
+                assert_ne!(expected, actual);
+            }
+            res => panic!("Unexpected replay result: {:?}", res),
+        }
+
+        assert!(AccessLog::from_text("in 3fd 0102ff").is_err());
+        assert!(AccessLog::from_text("peek 3fd 01").is_err());
+        assert!(AccessLog::from_text("in 10000 01").is_err());
+    }
+}
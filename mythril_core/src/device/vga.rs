@@ -1,11 +1,17 @@
 use crate::device::{
-    DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
+    DeviceRegion, EmulatedDevice, MemReadRequest, MemWriteRequest, Port,
+    PortReadRequest, PortWriteRequest, RegionDelta,
 };
 use crate::error::{Error, Result};
-use crate::memory::GuestAddressSpaceViewMut;
+use crate::memory::{
+    GuestAccess, GuestAddressSpaceViewMut, GuestPhysAddr, GuestVirtAddr,
+    PrivilegeLevel,
+};
 use alloc::boxed::Box;
 use alloc::vec::Vec;
+use core::cell::RefCell;
 use core::convert::TryInto;
+use core::ops::RangeInclusive;
 use derive_try_from_primitive::TryFromPrimitive;
 
 #[derive(Clone, Copy, Debug, TryFromPrimitive)]
@@ -27,13 +33,135 @@ pub enum VgaRegister {
     StartAddrLsb = 0x0d,
     CursorAddrMsb = 0x0e,
     CursorAddrLsb = 0x0f,
+    VerticalRetraceStart = 0x10,
+    VerticalRetraceEnd = 0x11,
+    VerticalDisplayEnd = 0x12,
+    Offset = 0x13,
+    UnderlineLocation = 0x14,
+    StartVerticalBlank = 0x15,
+    EndVerticalBlank = 0x16,
+    ModeControl = 0x17,
+    LineCompare = 0x18,
+}
+
+/// A 24-bit color
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+/// The 16 color text mode palette (in attribute color order)
+pub const TEXT_PALETTE: [Rgb; 16] = [
+    Rgb(0x00, 0x00, 0x00), // Black
+    Rgb(0x00, 0x00, 0xaa), // Blue
+    Rgb(0x00, 0xaa, 0x00), // Green
+    Rgb(0x00, 0xaa, 0xaa), // Cyan
+    Rgb(0xaa, 0x00, 0x00), // Red
+    Rgb(0xaa, 0x00, 0xaa), // Magenta
+    Rgb(0xaa, 0x55, 0x00), // Brown
+    Rgb(0xaa, 0xaa, 0xaa), // Light gray
+    Rgb(0x55, 0x55, 0x55), // Dark gray
+    Rgb(0x55, 0x55, 0xff), // Light blue
+    Rgb(0x55, 0xff, 0x55), // Light green
+    Rgb(0x55, 0xff, 0xff), // Light cyan
+    Rgb(0xff, 0x55, 0x55), // Light red
+    Rgb(0xff, 0x55, 0xff), // Light magenta
+    Rgb(0xff, 0xff, 0x55), // Yellow
+    Rgb(0xff, 0xff, 0xff), // White
+];
+
+/// A single rendered character cell
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TextCell {
+    pub character: u8,
+    pub foreground: Rgb,
+    pub background: Rgb,
+}
+
+/// The position and shape of the hardware cursor
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TextCursor {
+    pub row: usize,
+    pub column: usize,
+
+    /// The first and last scanlines of the character cell that are
+    /// covered by the cursor
+    pub start_scanline: u8,
+    pub end_scanline: u8,
+}
+
+/// The visible contents of a graphics mode screen
+#[derive(Clone, Debug, PartialEq)]
+pub struct GraphicsScanout {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Rgb>,
+}
+
+impl GraphicsScanout {
+    pub fn pixel(&self, x: usize, y: usize) -> Option<Rgb> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        self.pixels.get(y * self.width + x).copied()
+    }
+}
+
+/// The visible contents of the screen, in the current display mode
+#[derive(Clone, Debug, PartialEq)]
+pub enum Scanout {
+    Text(TextScanout),
+    Graphics(GraphicsScanout),
+}
+
+/// The visible contents of the text mode screen
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextScanout {
+    pub columns: usize,
+    pub rows: usize,
+    pub cells: Vec<TextCell>,
+    pub cursor: Option<TextCursor>,
+}
+
+impl TextScanout {
+    pub fn cell(&self, row: usize, column: usize) -> Option<&TextCell> {
+        if row >= self.rows || column >= self.columns {
+            return None;
+        }
+        self.cells.get(row * self.columns + column)
+    }
 }
 
 #[derive(Debug)]
 pub struct VgaController {
     index: VgaRegister,
 
-    registers: [u8; 0x10],
+    registers: [u8; 0x19],
+
+    // When set, bit 7 of an attribute selects blinking rather than a
+    // bright background
+    blink_enabled: bool,
+
+    // When set, cursor moves are mirrored to the BIOS data area
+    bios_cursor_sync: bool,
+
+    graphics_index: u8,
+    graphics_registers: [u8; 9],
+
+    sequencer_index: u8,
+    sequencer_registers: [u8; 5],
+
+    // The DAC palette (with 6-bit color components), the entry accessed
+    // next through the data port and the next component of that entry
+    dac_palette: Vec<[u8; 3]>,
+    dac_index: u8,
+    dac_component: usize,
+    dac_reading: bool,
+
+    // The video memory, when the memory window is emulated rather than
+    // backed by guest RAM
+    vram: Option<Vec<u8>>,
+
+    // The memory window last reported to the DeviceMap
+    registered_window: RefCell<Option<RangeInclusive<GuestPhysAddr>>>,
 }
 
 #[allow(dead_code)]
@@ -41,6 +169,66 @@ impl VgaController {
     const VGA_INDEX: Port = 0x03D4;
     const VGA_DATA: Port = 0x03D5;
 
+    const GRAPHICS_INDEX: Port = 0x03CE;
+    const GRAPHICS_DATA: Port = 0x03CF;
+
+    const SEQUENCER_INDEX: Port = 0x03C4;
+    const SEQUENCER_DATA: Port = 0x03C5;
+
+    // Writing either index port selects the palette entry accessed through
+    // the data port. Reading 0x3C7 returns the DAC state (0 after a write
+    // index was set and 3 after a read index).
+    const DAC_READ_INDEX: Port = 0x03C7;
+    const DAC_WRITE_INDEX: Port = 0x03C8;
+    const DAC_DATA: Port = 0x03C9;
+    const DAC_STATE_READ: u8 = 0b11;
+
+    // The sequencer memory mode register, and its chain 4 bit (which
+    // makes the planes appear as one linear buffer)
+    const SEQUENCER_MEMORY_MODE: u8 = 0x04;
+    const MEMORY_MODE_CHAIN4: u8 = 1 << 3;
+
+    // The graphics controller mode register, and its 256 color shift
+    // mode bit
+    const GRAPHICS_MODE: u8 = 0x05;
+    const GRAPHICS_MODE_256_COLOR: u8 = 1 << 6;
+
+    // The graphics controller miscellaneous register, and its memory map
+    // select field (bits 3-2)
+    const GRAPHICS_MISC: u8 = 0x06;
+    const MISC_MEMORY_MAP_SHIFT: u8 = 2;
+    const MISC_MEMORY_MAP_MASK: u8 = 0b11;
+    const MISC_GRAPHICS: u8 = 1 << 0;
+
+    /// The resolution of mode 13h (with one byte per pixel)
+    pub const MODE_13H_WIDTH: usize = 320;
+    pub const MODE_13H_HEIGHT: usize = 200;
+
+    /// The guest physical address of the monochrome text buffer
+    pub const MONO_TEXT_BUFFER: u64 = 0xb0000;
+
+    /// The guest physical address of the graphics mode buffer
+    pub const GRAPHICS_BUFFER: u64 = 0xa0000;
+
+    // The size of the emulated video memory, which is the size of the
+    // largest memory window
+    const VRAM_SIZE: usize = 0x20000;
+
+    /// The guest physical address of the color text buffer
+    pub const TEXT_BUFFER: u64 = 0xb8000;
+
+    // The size of the text buffer, in (two byte) character cells. The
+    // start and cursor addresses wrap around at the end of the buffer.
+    const TEXT_BUFFER_CELLS: usize = 0x4000;
+
+    // The column and row of the cursor on display page 0 in the BIOS
+    // data area (0x40:0x50)
+    const BDA_CURSOR_POSITION: u64 = 0x450;
+
+    const CURSOR_DISABLE: u8 = 1 << 5;
+    const SCANLINE_MASK: u8 = 0x1f;
+    const ATTR_BLINK: u8 = 1 << 7;
+
     pub fn new() -> Box<Self> {
         Box::new(Self {
             index: VgaRegister::HorizontalTotalChars,
@@ -62,17 +250,531 @@ impl VgaController {
                 0x00, // StartAddrLsb
                 0x00, // CursorAddrMsb
                 0x00, // CursorAddrLsb
+                0x9c, // VerticalRetraceStart
+                0x8e, // VerticalRetraceEnd
+                0x8f, // VerticalDisplayEnd
+                0x28, // Offset
+                0x1f, // UnderlineLocation
+                0x96, // StartVerticalBlank
+                0xb9, // EndVerticalBlank
+                0xa3, // ModeControl
+                0xff, // LineCompare
+            ],
+
+            blink_enabled: true,
+            bios_cursor_sync: false,
+
+            graphics_index: 0,
+            graphics_registers: [
+                0x00, // Set/Reset
+                0x00, // Enable Set/Reset
+                0x00, // Color Compare
+                0x00, // Data Rotate
+                0x00, // Read Map Select
+                0x10, // Graphics Mode
+                0x0e, // Miscellaneous (0xb8000 color text window)
+                0x00, // Color Don't Care
+                0xff, // Bit Mask
             ],
+
+            sequencer_index: 0,
+            sequencer_registers: [
+                0x03, // Reset
+                0x00, // Clocking Mode
+                0x03, // Map Mask
+                0x00, // Character Map Select
+                0x02, // Memory Mode
+            ],
+
+            dac_palette: Self::default_palette(),
+            dac_index: 0,
+            dac_component: 0,
+            dac_reading: false,
+
+            vram: None,
+            registered_window: RefCell::new(None),
+        })
+    }
+
+    /// Select whether the VGA emulates its memory window (disabled by
+    /// default, in which case the text buffer is ordinary guest RAM at
+    /// `TEXT_BUFFER`)
+    ///
+    /// When enabled, the VGA services the window selected by the memory
+    /// map select bits of the graphics controller miscellaneous register
+    /// (0xa0000 for 128K or 64K, `MONO_TEXT_BUFFER` or `TEXT_BUFFER` for
+    /// 32K) and accesses to it are routed to the start of the video
+    /// memory, which is also where the text scanout is read from. The
+    /// registered region follows changes to the register.
+    pub fn set_vram_enabled(&mut self, enabled: bool) {
+        self.vram = if enabled {
+            Some(vec![0; Self::VRAM_SIZE])
+        } else {
+            None
+        };
+    }
+
+    /// The memory window that the VGA services, if the memory window is
+    /// emulated
+    pub fn memory_window(&self) -> Option<RangeInclusive<GuestPhysAddr>> {
+        self.vram.as_ref()?;
+        let select = (self.graphics_registers[Self::GRAPHICS_MISC as usize]
+            >> Self::MISC_MEMORY_MAP_SHIFT)
+            & Self::MISC_MEMORY_MAP_MASK;
+        let (start, size) = match select {
+            0b00 => (Self::GRAPHICS_BUFFER, 0x20000),
+            0b01 => (Self::GRAPHICS_BUFFER, 0x10000),
+            0b10 => (Self::MONO_TEXT_BUFFER, 0x8000),
+            _ => (Self::TEXT_BUFFER, 0x8000),
+        };
+        Some(GuestPhysAddr::new(start)..=GuestPhysAddr::new(start + size - 1))
+    }
+
+    // The text mode colors in the first 16 entries, and black in the rest
+    fn default_palette() -> Vec<[u8; 3]> {
+        let mut palette = vec![[0u8; 3]; 256];
+        for (entry, color) in palette.iter_mut().zip(TEXT_PALETTE.iter()) {
+            *entry = [color.0 >> 2, color.1 >> 2, color.2 >> 2];
+        }
+        palette
+    }
+
+    /// The color of an entry of the DAC palette
+    pub fn palette_color(&self, index: u8) -> Rgb {
+        // Scale the 6-bit components to 8 bits
+        let scale = |c: u8| (c << 2) | (c >> 4);
+        let [r, g, b] = self.dac_palette[index as usize];
+        Rgb(scale(r), scale(g), scale(b))
+    }
+
+    /// Returns true if the registers select mode 13h (320x200 with 256
+    /// colors, one byte per pixel in a linear buffer at `GRAPHICS_BUFFER`)
+    pub fn is_mode_13h(&self) -> bool {
+        self.graphics_registers[Self::GRAPHICS_MISC as usize]
+            & Self::MISC_GRAPHICS
+            != 0
+            && self.graphics_registers[Self::GRAPHICS_MODE as usize]
+                & Self::GRAPHICS_MODE_256_COLOR
+                != 0
+            && self.sequencer_registers[Self::SEQUENCER_MEMORY_MODE as usize]
+                & Self::MEMORY_MODE_CHAIN4
+                != 0
+    }
+
+    /// Clear the screen by zeroing the text buffer or mode 13h framebuffer
+    /// (the video memory, or the guest RAM at `TEXT_BUFFER` or
+    /// `GRAPHICS_BUFFER` if the memory window is not emulated)
+    pub fn clear_screen(
+        &mut self,
+        space: &mut GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        match &mut self.vram {
+            Some(vram) => {
+                for byte in vram.iter_mut() {
+                    *byte = 0;
+                }
+                Ok(())
+            }
+            None => {
+                let (addr, len) = if self.is_mode_13h() {
+                    (
+                        Self::GRAPHICS_BUFFER,
+                        Self::MODE_13H_WIDTH * Self::MODE_13H_HEIGHT,
+                    )
+                } else {
+                    (Self::TEXT_BUFFER, 0x8000)
+                };
+                space.fill(
+                    GuestVirtAddr::NoPaging(GuestPhysAddr::new(addr)),
+                    len,
+                    0,
+                    GuestAccess::Write(PrivilegeLevel(0)),
+                )
+            }
+        }
+    }
+
+    // The offset in video memory of a guest address in the memory window
+    fn vram_offset(&self, addr: GuestPhysAddr) -> Result<usize> {
+        match self.memory_window() {
+            Some(window) if window.contains(&addr) => {
+                Ok((addr.as_u64() - window.start().as_u64()) as usize)
+            }
+            _ => Err(Error::InvalidValue(format!(
+                "Address {:?} is outside of the vga memory window",
+                addr
+            ))),
+        }
+    }
+
+    /// Select whether a change to the CRTC cursor location also updates
+    /// the cursor position in the BIOS data area (disabled by default)
+    ///
+    /// Real hardware leaves this to the BIOS, but a guest that moves the
+    /// cursor directly and then calls BIOS services would otherwise see
+    /// a stale position. The update is skipped if the BIOS data area is
+    /// not mapped.
+    pub fn set_bios_cursor_sync(&mut self, enabled: bool) {
+        self.bios_cursor_sync = enabled;
+    }
+
+    /// Select whether bit 7 of an attribute means blink (the default) or
+    /// bright background
+    pub fn set_blink_enabled(&mut self, enabled: bool) {
+        self.blink_enabled = enabled;
+    }
+
+    /// Map a text attribute to its foreground and background colors
+    ///
+    /// Bits 3-0 are the foreground color (bit 3 is intensity) and bits 6-4
+    /// the background. Bit 7 is either blink or background intensity. A
+    /// blinking character is drawn in the background color while
+    /// `blink_phase` is off.
+    pub fn attribute_colors(
+        &self,
+        attribute: u8,
+        blink_phase: bool,
+    ) -> (Rgb, Rgb) {
+        let mut background = (attribute >> 4) & 0x0f;
+        let mut foreground = attribute & 0x0f;
+        if self.blink_enabled {
+            background &= 0x07;
+            if attribute & Self::ATTR_BLINK != 0 && !blink_phase {
+                foreground = background;
+            }
+        }
+        (
+            TEXT_PALETTE[foreground as usize],
+            TEXT_PALETTE[background as usize],
+        )
+    }
+
+    fn register(&self, reg: VgaRegister) -> u8 {
+        self.registers[reg as usize]
+    }
+
+    /// The hardware cursor, if it is visible
+    ///
+    /// The cursor blinks with `blink_phase` and is hidden when it is
+    /// disabled, placed off screen or has an empty scanline range.
+    pub fn cursor(&self, blink_phase: bool) -> Option<TextCursor> {
+        let start = self.register(VgaRegister::CursorStart);
+        if !blink_phase || start & Self::CURSOR_DISABLE != 0 {
+            return None;
+        }
+        let start_scanline = start & Self::SCANLINE_MASK;
+        let max_scanline =
+            self.register(VgaRegister::MaxScanLineAddr) & Self::SCANLINE_MASK;
+        let end_scanline = (self.register(VgaRegister::CursorEnd)
+            & Self::SCANLINE_MASK)
+            .min(max_scanline);
+        if start_scanline > end_scanline {
+            return None;
+        }
+
+        let (row, column) = self.cursor_position()?;
+        Some(TextCursor {
+            row,
+            column,
+            start_scanline,
+            end_scanline,
         })
     }
+
+    // The row and column of the cursor location relative to the start
+    // of the visible window, if it is on screen
+    fn cursor_position(&self) -> Option<(usize, usize)> {
+        let columns = self.columns();
+        let location = (self.register(VgaRegister::CursorAddrMsb) as usize)
+            << 8
+            | self.register(VgaRegister::CursorAddrLsb) as usize;
+        let offset = location.wrapping_sub(self.start_address())
+            % Self::TEXT_BUFFER_CELLS;
+        if columns == 0 || offset >= columns * self.rows() {
+            return None;
+        }
+        Some((offset / columns, offset % columns))
+    }
+
+    /// The character cell of the text buffer that is shown at the top
+    /// left of the screen
+    pub fn start_address(&self) -> usize {
+        ((self.register(VgaRegister::StartAddrMsb) as usize) << 8
+            | self.register(VgaRegister::StartAddrLsb) as usize)
+            % Self::TEXT_BUFFER_CELLS
+    }
+
+    fn columns(&self) -> usize {
+        self.register(VgaRegister::HorizontalCharsPerLine) as usize
+    }
+
+    fn rows(&self) -> usize {
+        self.register(VgaRegister::VirticalDisplayedRows) as usize
+    }
+
+    /// Render the screen in the current display mode
+    ///
+    /// In mode 13h this is the graphics framebuffer, and otherwise the
+    /// text buffer (see `text_scanout`).
+    pub fn scanout(
+        &self,
+        space: &GuestAddressSpaceViewMut,
+        blink_phase: bool,
+    ) -> Result<Scanout> {
+        if self.is_mode_13h() {
+            Ok(Scanout::Graphics(self.graphics_scanout(space)?))
+        } else {
+            Ok(Scanout::Text(self.text_scanout(space, blink_phase)?))
+        }
+    }
+
+    /// Render the mode 13h framebuffer in guest memory (or video memory,
+    /// if the memory window is emulated) using the DAC palette
+    pub fn graphics_scanout(
+        &self,
+        space: &GuestAddressSpaceViewMut,
+    ) -> Result<GraphicsScanout> {
+        let (width, height) = (Self::MODE_13H_WIDTH, Self::MODE_13H_HEIGHT);
+        let bytes = match &self.vram {
+            Some(vram) => vram[..width * height].to_vec(),
+            None => space.read_bytes(
+                GuestVirtAddr::NoPaging(GuestPhysAddr::new(
+                    Self::GRAPHICS_BUFFER,
+                )),
+                width * height,
+                GuestAccess::Read(PrivilegeLevel(0)),
+            )?,
+        };
+        Ok(GraphicsScanout {
+            width,
+            height,
+            pixels: bytes
+                .iter()
+                .map(|index| self.palette_color(*index))
+                .collect(),
+        })
+    }
+
+    /// Render the text buffer in guest memory (or video memory, if the
+    /// memory window is emulated)
+    ///
+    /// The visible window begins at the CRTC start address (so a guest
+    /// scrolls the screen by advancing it) and wraps around to the start
+    /// of the buffer. `blink_phase` selects whether blinking characters
+    /// and the cursor are currently shown.
+    pub fn text_scanout(
+        &self,
+        space: &GuestAddressSpaceViewMut,
+        blink_phase: bool,
+    ) -> Result<TextScanout> {
+        let (columns, rows) = (self.columns(), self.rows());
+        let read_cells = |cell: usize, count: usize| match &self.vram {
+            Some(vram) => Ok(vram[cell * 2..(cell + count) * 2].to_vec()),
+            None => space.read_bytes(
+                GuestVirtAddr::NoPaging(GuestPhysAddr::new(
+                    Self::TEXT_BUFFER + cell as u64 * 2,
+                )),
+                count * 2,
+                GuestAccess::Read(PrivilegeLevel(0)),
+            ),
+        };
+        let start = self.start_address();
+        let count = columns * rows;
+        let before_wrap = count.min(Self::TEXT_BUFFER_CELLS - start);
+        let mut bytes = read_cells(start, before_wrap)?;
+        if before_wrap < count {
+            bytes.extend(read_cells(0, count - before_wrap)?);
+        }
+        let cells = bytes
+            .chunks(2)
+            .map(|cell| {
+                let (foreground, background) =
+                    self.attribute_colors(cell[1], blink_phase);
+                TextCell {
+                    character: cell[0],
+                    foreground,
+                    background,
+                }
+            })
+            .collect();
+        Ok(TextScanout {
+            columns,
+            rows,
+            cells,
+            cursor: self.cursor(blink_phase),
+        })
+    }
+
+    fn write_register(
+        &mut self,
+        val: u8,
+        space: &mut GuestAddressSpaceViewMut,
+    ) {
+        self.registers[self.index as usize] = val;
+        match self.index {
+            VgaRegister::StartAddrMsb
+            | VgaRegister::StartAddrLsb
+            | VgaRegister::CursorAddrMsb
+            | VgaRegister::CursorAddrLsb
+                if self.bios_cursor_sync =>
+            {
+                self.sync_bios_cursor(space)
+            }
+            _ => (),
+        }
+    }
+
+    fn write_graphics_register(&mut self, val: u8) {
+        match self
+            .graphics_registers
+            .get_mut(self.graphics_index as usize)
+        {
+            Some(reg) => *reg = val,
+            None => info!(
+                "Ignoring write to vga graphics register 0x{:x}",
+                self.graphics_index
+            ),
+        }
+    }
+
+    fn write_sequencer_register(&mut self, val: u8) {
+        match self
+            .sequencer_registers
+            .get_mut(self.sequencer_index as usize)
+        {
+            Some(reg) => *reg = val,
+            None => info!(
+                "Ignoring write to vga sequencer register 0x{:x}",
+                self.sequencer_index
+            ),
+        }
+    }
+
+    fn set_dac_index(&mut self, index: u8, reading: bool) {
+        self.dac_index = index;
+        self.dac_component = 0;
+        self.dac_reading = reading;
+    }
+
+    // Advance to the next component (and after the last component, the
+    // next palette entry)
+    fn next_dac_component(&mut self) {
+        self.dac_component += 1;
+        if self.dac_component == 3 {
+            self.dac_component = 0;
+            self.dac_index = self.dac_index.wrapping_add(1);
+        }
+    }
+
+    fn read_dac_data(&mut self) -> u8 {
+        let val = self.dac_palette[self.dac_index as usize][self.dac_component];
+        self.next_dac_component();
+        val
+    }
+
+    fn write_dac_data(&mut self, val: u8) {
+        self.dac_palette[self.dac_index as usize][self.dac_component] =
+            val & 0x3f;
+        self.next_dac_component();
+    }
+
+    fn sync_bios_cursor(&self, space: &mut GuestAddressSpaceViewMut) {
+        let (row, column) = match self.cursor_position() {
+            Some(position) => position,
+            None => return,
+        };
+        // The BIOS data area may not be mapped, in which case there is
+        // nothing to keep in sync
+        let _ = space.write_bytes(
+            GuestVirtAddr::NoPaging(GuestPhysAddr::new(
+                Self::BDA_CURSOR_POSITION,
+            )),
+            &[column as u8, row as u8],
+            GuestAccess::Write(PrivilegeLevel(0)),
+        );
+    }
 }
 
 impl EmulatedDevice for VgaController {
     fn services(&self) -> Vec<DeviceRegion> {
-        vec![
+        let mut services = vec![
             // vga stuff
             DeviceRegion::PortIo(Self::VGA_INDEX..=Self::VGA_DATA),
-        ]
+            DeviceRegion::PortIo(Self::GRAPHICS_INDEX..=Self::GRAPHICS_DATA),
+            DeviceRegion::PortIo(Self::SEQUENCER_INDEX..=Self::SEQUENCER_DATA),
+            DeviceRegion::PortIo(Self::DAC_READ_INDEX..=Self::DAC_DATA),
+        ];
+        let window = self.memory_window();
+        if let Some(window) = window.clone() {
+            services.push(DeviceRegion::MemIo(window));
+        }
+        self.registered_window.replace(window);
+        services
+    }
+
+    fn reset(&mut self) {
+        // The host's configuration is kept (and the registered window, so
+        // the move back to the default window is reported)
+        let vram_enabled = self.vram.is_some();
+        let bios_cursor_sync = self.bios_cursor_sync;
+        let blink_enabled = self.blink_enabled;
+        let registered_window = self.registered_window.replace(None);
+
+        *self = *Self::new();
+        self.set_vram_enabled(vram_enabled);
+        self.bios_cursor_sync = bios_cursor_sync;
+        self.blink_enabled = blink_enabled;
+        self.registered_window.replace(registered_window);
+    }
+
+    fn region_changed(&self) -> Option<RegionDelta> {
+        let window = self.memory_window();
+        let old = self.registered_window.replace(window.clone());
+        match (old, window) {
+            (Some(old), Some(new)) if old != new => Some(RegionDelta::Resize {
+                old: DeviceRegion::MemIo(old),
+                new: DeviceRegion::MemIo(new),
+            }),
+            (None, Some(new)) => {
+                Some(RegionDelta::Add(DeviceRegion::MemIo(new)))
+            }
+            (Some(old), None) => {
+                Some(RegionDelta::Remove(DeviceRegion::MemIo(old)))
+            }
+            _ => None,
+        }
+    }
+
+    fn on_mem_read(
+        &mut self,
+        addr: GuestPhysAddr,
+        mut data: MemReadRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let offset = self.vram_offset(addr)?;
+        if let Some(vram) = &self.vram {
+            for (i, byte) in data.as_mut_slice().iter_mut().enumerate() {
+                *byte = vram.get(offset + i).copied().unwrap_or(0xff);
+            }
+        }
+        Ok(())
+    }
+
+    fn on_mem_write(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemWriteRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let offset = self.vram_offset(addr)?;
+        if let Some(vram) = &mut self.vram {
+            for (i, byte) in data.as_slice().iter().enumerate() {
+                if let Some(dest) = vram.get_mut(offset + i) {
+                    *dest = *byte;
+                }
+            }
+        }
+        Ok(())
     }
 
     fn on_port_read(
@@ -85,6 +787,41 @@ impl EmulatedDevice for VgaController {
             Self::VGA_DATA => {
                 val.copy_from_u32(self.registers[self.index as usize] as u32);
             }
+            Self::GRAPHICS_INDEX => {
+                val.copy_from_u32(self.graphics_index as u32);
+            }
+            Self::GRAPHICS_DATA => {
+                let reg = self
+                    .graphics_registers
+                    .get(self.graphics_index as usize)
+                    .copied()
+                    .unwrap_or(0xff);
+                val.copy_from_u32(reg as u32);
+            }
+            Self::SEQUENCER_INDEX => {
+                val.copy_from_u32(self.sequencer_index as u32);
+            }
+            Self::SEQUENCER_DATA => {
+                let reg = self
+                    .sequencer_registers
+                    .get(self.sequencer_index as usize)
+                    .copied()
+                    .unwrap_or(0xff);
+                val.copy_from_u32(reg as u32);
+            }
+            Self::DAC_READ_INDEX => {
+                let state = if self.dac_reading {
+                    Self::DAC_STATE_READ
+                } else {
+                    0
+                };
+                val.copy_from_u32(state as u32);
+            }
+            Self::DAC_WRITE_INDEX => val.copy_from_u32(self.dac_index as u32),
+            Self::DAC_DATA => {
+                let data = self.read_dac_data();
+                val.copy_from_u32(data as u32);
+            }
             _ => {
                 return Err(Error::NotImplemented(format!(
                     "Unsupported attempt to read from vga port 0x{:x}",
@@ -99,7 +836,7 @@ impl EmulatedDevice for VgaController {
         &mut self,
         port: Port,
         val: PortWriteRequest,
-        _space: GuestAddressSpaceViewMut,
+        mut space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
         match port {
             Self::VGA_INDEX => match val {
@@ -126,7 +863,7 @@ impl EmulatedDevice for VgaController {
                                 index
                             ))
                         })?;
-                    self.registers[self.index as usize] = data;
+                    self.write_register(data, &mut space);
                 }
                 _ => {
                     return Err(Error::InvalidValue(format!(
@@ -136,8 +873,46 @@ impl EmulatedDevice for VgaController {
                 }
             },
             Self::VGA_DATA => {
-                self.registers[self.index as usize] = val.try_into()?;
+                self.write_register(val.try_into()?, &mut space);
+            }
+            Self::GRAPHICS_INDEX => match val {
+                PortWriteRequest::OneByte(b) => self.graphics_index = b[0],
+
+                // As with the CRTC, the index and data may be written in
+                // one operation
+                PortWriteRequest::TwoBytes(bytes) => {
+                    self.graphics_index = bytes[1];
+                    self.write_graphics_register(bytes[0]);
+                }
+                _ => {
+                    return Err(Error::InvalidValue(format!(
+                        "Invalid port write to VGA graphics index: {:?}",
+                        val
+                    )))
+                }
+            },
+            Self::GRAPHICS_DATA => {
+                self.write_graphics_register(val.try_into()?);
+            }
+            Self::SEQUENCER_INDEX => match val {
+                PortWriteRequest::OneByte(b) => self.sequencer_index = b[0],
+                PortWriteRequest::TwoBytes(bytes) => {
+                    self.sequencer_index = bytes[1];
+                    self.write_sequencer_register(bytes[0]);
+                }
+                _ => {
+                    return Err(Error::InvalidValue(format!(
+                        "Invalid port write to VGA sequencer index: {:?}",
+                        val
+                    )))
+                }
+            },
+            Self::SEQUENCER_DATA => {
+                self.write_sequencer_register(val.try_into()?);
             }
+            Self::DAC_READ_INDEX => self.set_dac_index(val.try_into()?, true),
+            Self::DAC_WRITE_INDEX => self.set_dac_index(val.try_into()?, false),
+            Self::DAC_DATA => self.write_dac_data(val.try_into()?),
             _ => {
                 return Err(Error::NotImplemented(format!(
                     "Unsupported attempt to write to vga port 0x{:x}",
@@ -148,3 +923,408 @@ impl EmulatedDevice for VgaController {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::define_test_view;
+    use crate::device::DeviceMap;
+    use crate::memory::GuestAddressSpace;
+    use alloc::rc::Rc;
+
+    fn define_test_text_view() -> GuestAddressSpaceViewMut<'static> {
+        let space: &'static mut GuestAddressSpace =
+            Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
+        for i in 0..8 {
+            space
+                .map_new_frame(
+                    GuestPhysAddr::new(VgaController::TEXT_BUFFER + i * 4096),
+                    false,
+                )
+                .unwrap();
+        }
+        GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space)
+    }
+
+    fn write_register(vga: &mut VgaController, reg: VgaRegister, val: u8) {
+        let bytes = [val, reg as u8];
+        vga.on_port_write(
+            VgaController::VGA_INDEX,
+            PortWriteRequest::TwoBytes(&bytes),
+            define_test_view(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_attribute_colors() {
+        let mut vga = VgaController::new();
+
+        // Yellow (bright brown) on blue
+        assert_eq!(
+            vga.attribute_colors(0x1e, true),
+            (Rgb(0xff, 0xff, 0x55), Rgb(0x00, 0x00, 0xaa))
+        );
+
+        // The intensity bit only brightens the foreground
+        assert_eq!(
+            vga.attribute_colors(0x06, true),
+            (Rgb(0xaa, 0x55, 0x00), Rgb(0x00, 0x00, 0x00))
+        );
+
+        // A blinking light gray on red character disappears in the off phase
+        assert_eq!(
+            vga.attribute_colors(0xc7, true),
+            (Rgb(0xaa, 0xaa, 0xaa), Rgb(0xaa, 0x00, 0x00))
+        );
+        assert_eq!(
+            vga.attribute_colors(0xc7, false),
+            (Rgb(0xaa, 0x00, 0x00), Rgb(0xaa, 0x00, 0x00))
+        );
+
+        // Without blinking, bit 7 is background intensity
+        vga.set_blink_enabled(false);
+        assert_eq!(
+            vga.attribute_colors(0xc7, false),
+            (Rgb(0xaa, 0xaa, 0xaa), Rgb(0xff, 0x55, 0x55))
+        );
+    }
+
+    #[test]
+    fn test_scanout_cursor() {
+        let mut vga = VgaController::new();
+        let mut space = define_test_text_view();
+        space
+            .write_bytes(
+                GuestVirtAddr::NoPaging(GuestPhysAddr::new(
+                    VgaController::TEXT_BUFFER + (80 * 2 + 5) * 2,
+                )),
+                &[b'A', 0x1e],
+                GuestAccess::Write(PrivilegeLevel(0)),
+            )
+            .unwrap();
+
+        // Place the cursor at row 2, column 5
+        let location = 80 * 2 + 5;
+        write_register(&mut vga, VgaRegister::CursorAddrMsb, 0);
+        write_register(&mut vga, VgaRegister::CursorAddrLsb, location);
+
+        let screen = vga.text_scanout(&space, true).unwrap();
+        assert_eq!((screen.columns, screen.rows), (80, 25));
+        assert_eq!(
+            screen.cell(2, 5),
+            Some(&TextCell {
+                character: b'A',
+                foreground: Rgb(0xff, 0xff, 0x55),
+                background: Rgb(0x00, 0x00, 0xaa),
+            })
+        );
+        assert_eq!(
+            screen.cursor,
+            Some(TextCursor {
+                row: 2,
+                column: 5,
+                start_scanline: 0x0b,
+                end_scanline: 0x0c,
+            })
+        );
+
+        // The cursor blinks
+        assert_eq!(vga.text_scanout(&space, false).unwrap().cursor, None);
+
+        // The end scanline is limited to the character height
+        write_register(&mut vga, VgaRegister::CursorStart, 0x00);
+        write_register(&mut vga, VgaRegister::CursorEnd, 0x1f);
+        let cursor = vga.cursor(true).unwrap();
+        assert_eq!((cursor.start_scanline, cursor.end_scanline), (0, 0x0d));
+
+        // An empty scanline range or the disable bit hides the cursor
+        write_register(&mut vga, VgaRegister::CursorStart, 0x0e);
+        assert_eq!(vga.cursor(true), None);
+        write_register(&mut vga, VgaRegister::CursorStart, 0x20);
+        assert_eq!(vga.cursor(true), None);
+    }
+
+    // Write one character to each row of the text buffer, from `first`
+    fn write_row_labels(
+        space: &mut GuestAddressSpaceViewMut,
+        first: usize,
+        rows: usize,
+    ) {
+        for row in first..first + rows {
+            space
+                .write_bytes(
+                    GuestVirtAddr::NoPaging(GuestPhysAddr::new(
+                        VgaController::TEXT_BUFFER + (row * 80) as u64 * 2,
+                    )),
+                    &[b'0' + (row % 64) as u8, 0x07],
+                    GuestAccess::Write(PrivilegeLevel(0)),
+                )
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_clear_screen() {
+        let mut vga = VgaController::new();
+        let mut space = define_test_text_view();
+        write_row_labels(&mut space, 0, 25);
+        vga.clear_screen(&mut space).unwrap();
+        let screen = vga.text_scanout(&space, true).unwrap();
+        for row in 0..25 {
+            assert_eq!(screen.cell(row, 0).unwrap().character, 0);
+        }
+
+        // Without RAM behind the text buffer, the clear fails
+        assert!(vga.clear_screen(&mut define_test_view()).is_err());
+    }
+
+    fn write_port(vga: &mut VgaController, port: Port, val: u8) {
+        vga.on_port_write(
+            port,
+            PortWriteRequest::OneByte(&[val]),
+            define_test_view(),
+        )
+        .unwrap();
+    }
+
+    fn read_port(vga: &mut VgaController, port: Port) -> u8 {
+        let mut arr = [0u8];
+        vga.on_port_read(
+            port,
+            PortReadRequest::OneByte(&mut arr),
+            define_test_view(),
+        )
+        .unwrap();
+        arr[0]
+    }
+
+    // The register writes of a mode 13h mode set that select the mode
+    fn set_mode_13h(vga: &mut VgaController) {
+        for (port, index, val) in [
+            (VgaController::SEQUENCER_INDEX, 0x04, 0x0e),
+            (VgaController::GRAPHICS_INDEX, 0x05, 0x40),
+            (VgaController::GRAPHICS_INDEX, 0x06, 0x05),
+            (VgaController::VGA_INDEX, 0x13, 0x28),
+            (VgaController::VGA_INDEX, 0x17, 0xa3),
+        ]
+        .iter()
+        {
+            write_port(vga, *port, *index);
+            write_port(vga, *port + 1, *val);
+        }
+    }
+
+    #[test]
+    fn test_mode_13h() {
+        let mut vga = VgaController::new();
+        assert!(!vga.is_mode_13h());
+        set_mode_13h(&mut vga);
+        assert!(vga.is_mode_13h());
+
+        // Load palette entry 7, then read it back
+        write_port(&mut vga, VgaController::DAC_WRITE_INDEX, 7);
+        for component in [0x3f, 0x00, 0x20].iter() {
+            write_port(&mut vga, VgaController::DAC_DATA, *component);
+        }
+        assert_eq!(read_port(&mut vga, VgaController::DAC_WRITE_INDEX), 8);
+        write_port(&mut vga, VgaController::DAC_READ_INDEX, 7);
+        let color: Vec<u8> = (0..3)
+            .map(|_| read_port(&mut vga, VgaController::DAC_DATA))
+            .collect();
+        assert_eq!(color, [0x3f, 0x00, 0x20]);
+        assert_eq!(vga.palette_color(7), Rgb(0xff, 0x00, 0x82));
+
+        // Set the pixel at (5, 10) in guest RAM
+        let space: &'static mut GuestAddressSpace =
+            Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
+        for i in 0..16 {
+            space
+                .map_new_frame(
+                    GuestPhysAddr::new(
+                        VgaController::GRAPHICS_BUFFER + i * 4096,
+                    ),
+                    false,
+                )
+                .unwrap();
+        }
+        let mut space =
+            GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space);
+        space
+            .write_bytes(
+                GuestVirtAddr::NoPaging(GuestPhysAddr::new(
+                    VgaController::GRAPHICS_BUFFER + 10 * 320 + 5,
+                )),
+                &[7],
+                GuestAccess::Write(PrivilegeLevel(0)),
+            )
+            .unwrap();
+        let screen = match vga.scanout(&space, true).unwrap() {
+            Scanout::Graphics(screen) => screen,
+            screen => panic!("Unexpected scanout: {:?}", screen),
+        };
+        assert_eq!((screen.width, screen.height), (320, 200));
+        assert_eq!(screen.pixel(5, 10), Some(Rgb(0xff, 0x00, 0x82)));
+        assert_eq!(screen.pixel(6, 10), Some(TEXT_PALETTE[0]));
+        assert_eq!(screen.pixel(320, 0), None);
+
+        // The same pixel written through the emulated memory window
+        vga.set_vram_enabled(true);
+        vga.on_mem_write(
+            GuestPhysAddr::new(VgaController::GRAPHICS_BUFFER + 199 * 320),
+            MemWriteRequest::new(&[7]),
+            define_test_view(),
+        )
+        .unwrap();
+        let screen = vga.graphics_scanout(&space).unwrap();
+        assert_eq!(screen.pixel(0, 199), Some(Rgb(0xff, 0x00, 0x82)));
+        assert_eq!(screen.pixel(5, 10), Some(TEXT_PALETTE[0]));
+    }
+
+    #[test]
+    fn test_start_address_scrolling() {
+        let mut vga = VgaController::new();
+        let mut space = define_test_text_view();
+        write_row_labels(&mut space, 0, 30);
+
+        let screen = vga.text_scanout(&space, true).unwrap();
+        assert_eq!(screen.cell(0, 0).unwrap().character, b'0');
+        assert_eq!(screen.cell(24, 0).unwrap().character, b'0' + 24);
+
+        // Scroll down by five rows
+        write_register(&mut vga, VgaRegister::StartAddrMsb, 0x01);
+        write_register(&mut vga, VgaRegister::StartAddrLsb, 0x90);
+        assert_eq!(vga.start_address(), 80 * 5);
+        let screen = vga.text_scanout(&space, true).unwrap();
+        assert_eq!(screen.cell(0, 0).unwrap().character, b'0' + 5);
+        assert_eq!(screen.cell(24, 0).unwrap().character, b'0' + 29);
+
+        // The window wraps around to the start of the buffer
+        let last_row = VgaController::TEXT_BUFFER_CELLS / 80 - 1;
+        write_row_labels(&mut space, last_row, 1);
+        let start = (last_row * 80) as u16;
+        write_register(&mut vga, VgaRegister::StartAddrMsb, (start >> 8) as u8);
+        write_register(&mut vga, VgaRegister::StartAddrLsb, start as u8);
+        let screen = vga.text_scanout(&space, true).unwrap();
+        assert_eq!(
+            screen.cell(0, 0).unwrap().character,
+            b'0' + (last_row % 64) as u8
+        );
+    }
+
+    #[test]
+    fn test_cursor_tracks_start_address() {
+        let mut vga = VgaController::new();
+        let space: &'static mut GuestAddressSpace =
+            Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
+        space.map_new_frame(GuestPhysAddr::new(0), false).unwrap();
+        vga.set_bios_cursor_sync(true);
+
+        let mut write = |reg: VgaRegister, val: u8| {
+            let bytes = [val, reg as u8];
+            vga.on_port_write(
+                VgaController::VGA_INDEX,
+                PortWriteRequest::TwoBytes(&bytes),
+                GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space),
+            )
+            .unwrap();
+        };
+
+        // Row 10, column 3 of the buffer, then scroll down four rows
+        let location = (80 * 10 + 3) as u16;
+        write(VgaRegister::CursorAddrMsb, (location >> 8) as u8);
+        write(VgaRegister::CursorAddrLsb, location as u8);
+        write(VgaRegister::StartAddrMsb, 0x01);
+        write(VgaRegister::StartAddrLsb, 0x40);
+
+        let cursor = vga.cursor(true).unwrap();
+        assert_eq!((cursor.row, cursor.column), (6, 3));
+        let view = GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space);
+        let bda = view
+            .read_bytes(
+                GuestVirtAddr::NoPaging(GuestPhysAddr::new(
+                    VgaController::BDA_CURSOR_POSITION,
+                )),
+                2,
+                GuestAccess::Read(PrivilegeLevel(0)),
+            )
+            .unwrap();
+        assert_eq!(bda, [3, 6]);
+
+        // A cursor that has scrolled off screen is hidden
+        let mut vga = VgaController::new();
+        write_register(&mut vga, VgaRegister::CursorAddrLsb, 10);
+        write_register(&mut vga, VgaRegister::StartAddrLsb, 80);
+        assert_eq!(vga.cursor(true), None);
+    }
+
+    #[test]
+    fn test_memory_map_select() {
+        let vga = Rc::new(RefCell::new(*VgaController::new()));
+        vga.borrow_mut().set_vram_enabled(true);
+        let mut map = DeviceMap::default();
+        map.register_device(Box::new(vga.clone())).unwrap();
+
+        let write_mem = |map: &mut DeviceMap, addr: u64, data: &[u8]| {
+            map.on_mem_write(
+                GuestPhysAddr::new(addr),
+                MemWriteRequest::new(data),
+                define_test_view(),
+            )
+            .unwrap();
+        };
+
+        // The color window routes to the text buffer
+        write_mem(&mut map, VgaController::TEXT_BUFFER, &[b'C', 0x07]);
+        assert!(map
+            .device_for(GuestPhysAddr::new(VgaController::MONO_TEXT_BUFFER))
+            .is_none());
+        let screen = vga
+            .borrow()
+            .text_scanout(&define_test_view(), true)
+            .unwrap();
+        assert_eq!(screen.cell(0, 0).unwrap().character, b'C');
+
+        // Select the monochrome window
+        let bytes = [0x0a, VgaController::GRAPHICS_MISC];
+        map.on_port_write(
+            VgaController::GRAPHICS_INDEX,
+            PortWriteRequest::TwoBytes(&bytes),
+            define_test_view(),
+        )
+        .unwrap();
+        assert!(map
+            .device_for(GuestPhysAddr::new(VgaController::TEXT_BUFFER))
+            .is_none());
+        write_mem(&mut map, VgaController::MONO_TEXT_BUFFER + 2, &[b'M']);
+        let screen = vga
+            .borrow()
+            .text_scanout(&define_test_view(), true)
+            .unwrap();
+        assert_eq!(screen.cell(0, 0).unwrap().character, b'C');
+        assert_eq!(screen.cell(0, 1).unwrap().character, b'M');
+
+        let mut buff = [0u8; 2];
+        map.on_mem_read(
+            GuestPhysAddr::new(VgaController::MONO_TEXT_BUFFER),
+            MemReadRequest::new(&mut buff),
+            define_test_view(),
+        )
+        .unwrap();
+        assert_eq!(buff, [b'C', 0x07]);
+
+        // The 128K graphics window covers the whole legacy VGA range
+        let bytes = [0x02, VgaController::GRAPHICS_MISC];
+        map.on_port_write(
+            VgaController::GRAPHICS_INDEX,
+            PortWriteRequest::TwoBytes(&bytes),
+            define_test_view(),
+        )
+        .unwrap();
+        assert_eq!(
+            vga.borrow().memory_window(),
+            Some(GuestPhysAddr::new(0xa0000)..=GuestPhysAddr::new(0xbffff))
+        );
+        assert!(map.device_for(GuestPhysAddr::new(0xbffff)).is_some());
+    }
+}
@@ -0,0 +1,301 @@
+use crate::device::reset::{ResetSignal, ResetSource};
+use crate::device::{
+    DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
+};
+use crate::error::Result;
+use crate::memory::GuestAddressSpaceViewMut;
+use crate::time::{ClockSource, SystemClock};
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+const NS_PER_MS: u64 = 1_000_000;
+
+/// A generic watchdog timer that resets the guest unless it is
+/// periodically "pet"
+///
+/// The watchdog occupies six ports starting at its base port:
+///
+///  - `base + 0` (dword): the reload register, the timeout in milliseconds
+///  - `base + 4` (byte): the control register. Bit 0 enables the watchdog
+///    and bit 7 reports (and is cleared by writing one to it) that the
+///    watchdog expired. The expired bit is kept across the reset that the
+///    watchdog requests.
+///  - `base + 5` (byte): the strobe register. Any write pets the watchdog.
+///
+/// While enabled, the countdown restarts from the reload value whenever
+/// the watchdog is enabled, pet, or the reload register is written. When
+/// it reaches zero the watchdog requests a reset with
+/// `ResetSource::Watchdog` and disables itself.
+pub struct Watchdog {
+    base: Port,
+    clock: Rc<dyn ClockSource>,
+    reset: Rc<ResetSignal>,
+    reload_ms: u32,
+    enabled: bool,
+    expired: bool,
+
+    // The clock time at which the watchdog expires, while it is running
+    deadline: Option<u64>,
+}
+
+impl Watchdog {
+    pub const DEFAULT_BASE: Port = 0x0440;
+    pub const DEFAULT_RELOAD_MS: u32 = 1000;
+
+    const RELOAD_OFFSET: Port = 0;
+    const CONTROL_OFFSET: Port = 4;
+    const STROBE_OFFSET: Port = 5;
+
+    pub const CONTROL_ENABLE: u8 = 1 << 0;
+    pub const CONTROL_EXPIRED: u8 = 1 << 7;
+
+    pub fn new(base: Port, reset: Rc<ResetSignal>) -> Box<Self> {
+        Self::with_clock(base, reset, Rc::new(SystemClock))
+    }
+
+    /// Create a watchdog whose countdown is driven by `clock`
+    pub fn with_clock(
+        base: Port,
+        reset: Rc<ResetSignal>,
+        clock: Rc<dyn ClockSource>,
+    ) -> Box<Self> {
+        Box::new(Self {
+            base,
+            clock,
+            reset,
+            reload_ms: Self::DEFAULT_RELOAD_MS,
+            enabled: false,
+            expired: false,
+            deadline: None,
+        })
+    }
+
+    /// Restart the countdown from the reload value, if the watchdog is
+    /// enabled
+    pub fn pet(&mut self) {
+        if self.enabled {
+            let timeout = self.reload_ms as u64 * NS_PER_MS;
+            self.deadline = Some(self.clock.now_ns().saturating_add(timeout));
+        }
+    }
+
+    /// Whether the watchdog is enabled and counting down
+    pub fn is_running(&self) -> bool {
+        self.deadline.is_some()
+    }
+
+    fn control(&self) -> u8 {
+        let mut control = 0;
+        if self.enabled {
+            control |= Self::CONTROL_ENABLE;
+        }
+        if self.expired {
+            control |= Self::CONTROL_EXPIRED;
+        }
+        control
+    }
+
+    fn write_control(&mut self, val: u8) {
+        if val & Self::CONTROL_EXPIRED != 0 {
+            self.expired = false;
+        }
+        let enabled = val & Self::CONTROL_ENABLE != 0;
+        if enabled && !self.enabled {
+            self.enabled = true;
+            self.pet();
+        } else if !enabled {
+            self.enabled = false;
+            self.deadline = None;
+        }
+    }
+}
+
+impl EmulatedDevice for Watchdog {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::PortIo(
+            self.base..=self.base + Self::STROBE_OFFSET,
+        )]
+    }
+
+    fn debug_name(&self) -> &'static str {
+        "watchdog"
+    }
+
+    // The expired flag survives the reset, so the guest can tell that the
+    // watchdog caused it
+    fn reset(&mut self) {
+        self.reload_ms = Self::DEFAULT_RELOAD_MS;
+        self.enabled = false;
+        self.deadline = None;
+    }
+
+    fn poll(&mut self, now: u64) {
+        match self.deadline {
+            Some(deadline) if now >= deadline => {
+                info!("Watchdog expired");
+                self.deadline = None;
+                self.enabled = false;
+                self.expired = true;
+                self.reset.request(ResetSource::Watchdog);
+            }
+            _ => (),
+        }
+    }
+
+    fn on_port_read(
+        &mut self,
+        port: Port,
+        mut val: PortReadRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        match port - self.base {
+            Self::RELOAD_OFFSET => val.copy_from_u32(self.reload_ms),
+            Self::CONTROL_OFFSET => val.copy_from_u32(self.control() as u32),
+            _ => val.copy_from_u32(0),
+        }
+        Ok(())
+    }
+
+    fn on_port_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        match port - self.base {
+            Self::RELOAD_OFFSET => {
+                self.reload_ms = val.try_into()?;
+                self.pet();
+            }
+            Self::CONTROL_OFFSET => {
+                let val: u8 = val.try_into()?;
+                self.write_control(val);
+            }
+            Self::STROBE_OFFSET => self.pet(),
+            offset => {
+                info!("Ignoring write to watchdog offset 0x{:x}", offset);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test_util::define_test_view;
+    use crate::time::FixedClock;
+
+    fn test_watchdog(
+        reload_ms: u32,
+    ) -> (Box<Watchdog>, Rc<FixedClock>, Rc<ResetSignal>) {
+        let clock = Rc::new(FixedClock::new(0));
+        let reset = ResetSignal::new();
+        let mut dog = Watchdog::with_clock(
+            Watchdog::DEFAULT_BASE,
+            reset.clone(),
+            clock.clone(),
+        );
+        let arr = reload_ms.to_be_bytes();
+        dog.on_port_write(
+            Watchdog::DEFAULT_BASE,
+            PortWriteRequest::FourBytes(&arr),
+            define_test_view(),
+        )
+        .unwrap();
+        (dog, clock, reset)
+    }
+
+    fn write_port(dog: &mut Watchdog, offset: Port, val: u8) {
+        let arr = [val];
+        dog.on_port_write(
+            Watchdog::DEFAULT_BASE + offset,
+            PortWriteRequest::OneByte(&arr),
+            define_test_view(),
+        )
+        .unwrap();
+    }
+
+    fn read_control(dog: &mut Watchdog) -> u8 {
+        let mut arr = [0u8];
+        dog.on_port_read(
+            Watchdog::DEFAULT_BASE + Watchdog::CONTROL_OFFSET,
+            PortReadRequest::OneByte(&mut arr),
+            define_test_view(),
+        )
+        .unwrap();
+        arr[0]
+    }
+
+    #[test]
+    fn test_watchdog_expires() {
+        let (mut dog, clock, reset) = test_watchdog(100);
+
+        // Nothing happens while the watchdog is disabled
+        clock.advance(200 * NS_PER_MS);
+        dog.poll(clock.now_ns());
+        assert_eq!(reset.take_reset_request(), None);
+
+        write_port(
+            &mut dog,
+            Watchdog::CONTROL_OFFSET,
+            Watchdog::CONTROL_ENABLE,
+        );
+        clock.advance(99 * NS_PER_MS);
+        dog.poll(clock.now_ns());
+        assert_eq!(reset.take_reset_request(), None);
+
+        clock.advance(NS_PER_MS);
+        dog.poll(clock.now_ns());
+        assert_eq!(reset.take_reset_request(), Some(ResetSource::Watchdog));
+        assert!(!dog.is_running());
+        assert_eq!(read_control(&mut dog), Watchdog::CONTROL_EXPIRED);
+
+        // The expiry fires once
+        clock.advance(200 * NS_PER_MS);
+        dog.poll(clock.now_ns());
+        assert_eq!(reset.take_reset_request(), None);
+
+        // The guest can still see why it was reset
+        dog.reset();
+        assert_eq!(read_control(&mut dog), Watchdog::CONTROL_EXPIRED);
+
+        write_port(
+            &mut dog,
+            Watchdog::CONTROL_OFFSET,
+            Watchdog::CONTROL_EXPIRED,
+        );
+        assert_eq!(read_control(&mut dog), 0);
+    }
+
+    #[test]
+    fn test_watchdog_pet() {
+        let (mut dog, clock, reset) = test_watchdog(100);
+        write_port(
+            &mut dog,
+            Watchdog::CONTROL_OFFSET,
+            Watchdog::CONTROL_ENABLE,
+        );
+
+        // Pet the watchdog through the strobe register and directly
+        for _ in 0..5 {
+            clock.advance(80 * NS_PER_MS);
+            dog.poll(clock.now_ns());
+            write_port(&mut dog, Watchdog::STROBE_OFFSET, 0);
+        }
+        clock.advance(80 * NS_PER_MS);
+        dog.pet();
+        clock.advance(80 * NS_PER_MS);
+        dog.poll(clock.now_ns());
+        assert_eq!(reset.take_reset_request(), None);
+        assert!(dog.is_running());
+
+        // Disabling the watchdog stops the countdown
+        write_port(&mut dog, Watchdog::CONTROL_OFFSET, 0);
+        clock.advance(200 * NS_PER_MS);
+        dog.poll(clock.now_ns());
+        assert_eq!(reset.take_reset_request(), None);
+    }
+}
@@ -1,9 +1,26 @@
-use crate::device::{Port, PortReadRequest, PortWriteRequest};
-use crate::error::Result;
+use crate::device::{OwnedPortWrite, Port, PortReadRequest, PortWriteRequest};
+use crate::error::{Error, Result};
 use crate::memory;
 use crate::{vcpu, vmcs, vmexit};
 use core::convert::TryFrom;
 
+// The number of bytes moved by a string instruction, which must not run
+// past the end of the page at `linear_addr`
+fn string_len(
+    linear_addr: u64,
+    guest_cpu: &vmexit::GuestCpuState,
+    exit: &vmexit::IoInstructionInformation,
+) -> Result<usize> {
+    let page_remaining = 4096 - (linear_addr & 0xfff);
+    match guest_cpu.rcx.checked_mul(exit.size as u64) {
+        Some(len) if len <= page_remaining => Ok(len as usize),
+        _ => Err(Error::InvalidValue(format!(
+            "String I/O of {} {}-byte units at 0x{:x} crosses a page",
+            guest_cpu.rcx, exit.size, linear_addr
+        ))),
+    }
+}
+
 fn emulate_outs(
     vcpu: &mut vcpu::VCpu,
     port: Port,
@@ -29,14 +46,15 @@ fn emulate_outs(
     // FIXME: We should probably only be using some of the lower order bits
     let bytes = view.read_bytes(
         guest_addr,
-        (guest_cpu.rcx * exit.size as u64) as usize,
+        string_len(linear_addr, guest_cpu, &exit)?,
         access,
     )?;
 
     // FIXME: Actually test for REP
     for chunk in bytes.chunks_exact(exit.size as usize) {
-        let request = PortWriteRequest::try_from(chunk)?;
-        vm.on_port_write(vcpu, port, request)?;
+        // Guest memory is little endian, but requests are big endian
+        let request = OwnedPortWrite::from_le_bytes(chunk)?;
+        vm.on_port_write(vcpu, port, request.as_request())?;
     }
 
     guest_cpu.rsi += bytes.len() as u64;
@@ -57,10 +75,13 @@ fn emulate_ins(
     let guest_addr = memory::GuestVirtAddr::new(linear_addr, &vcpu.vmcs)?;
     let access = memory::GuestAccess::Read(memory::PrivilegeLevel(0));
 
-    let mut bytes = vec![0u8; guest_cpu.rcx as usize];
+    let mut bytes = vec![0u8; string_len(linear_addr, guest_cpu, &exit)?];
     for chunk in bytes.chunks_exact_mut(exit.size as usize) {
-        let request = PortReadRequest::try_from(chunk)?;
+        let request = PortReadRequest::try_from(&mut *chunk)?;
         vm.on_port_read(vcpu, port, request)?;
+
+        // Store the (big endian) result in little endian order
+        chunk.reverse();
     }
 
     let mut view = memory::GuestAddressSpaceViewMut::from_vmcs(
@@ -1,5 +1,12 @@
+use crate::device::{
+    AccessKind, DisallowedAccess, InvalidMmioRegion, IrqConflict,
+    MissingDependency, RegionConflict, UnhandledAccess,
+};
+use crate::memory::GuestPhysAddr;
 use crate::vmcs;
 use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
 use derive_try_from_primitive::TryFromPrimitive;
 use x86::bits64::rflags;
 use x86::bits64::rflags::RFlags;
@@ -73,14 +80,63 @@ pub enum Error {
     DuplicateMapping(String),
     AllocError(String),
     MissingDevice(String),
+    MissingDependencies(Vec<MissingDependency>),
+    RegionConflict(RegionConflict),
+    IrqConflict(IrqConflict),
+    InvalidMmioRegion(InvalidMmioRegion),
+    InvalidDmaRange {
+        addr: GuestPhysAddr,
+        len: usize,
+    },
     MissingFile(String),
     NullPtr(String),
     NotSupported,
     NotFound,
     Uefi(String),
     InvalidValue(String),
+    AccessWidth {
+        expected: &'static [usize],
+        actual: usize,
+    },
     InvalidDevice(String),
     NotImplemented(String),
+    UnhandledAccess(UnhandledAccess),
+    DisallowedAccess(DisallowedAccess),
+    InjectedFault {
+        kind: AccessKind,
+        addr: u64,
+    },
+    FifoOverrun {
+        capacity: usize,
+    },
+    ReplayDivergence {
+        index: usize,
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::AccessWidth { expected, actual } => write!(
+                f,
+                "Invalid access width: {} bytes (expected {:?})",
+                actual, expected
+            ),
+            Error::UnhandledAccess(access) => write!(f, "{}", access),
+            Error::ReplayDivergence {
+                index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Replayed access {} read {:02x?} (expected {:02x?})",
+                index, actual, expected
+            ),
+            err => write!(f, "{:?}", err),
+        }
+    }
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
@@ -21,7 +21,7 @@ use core::ptr;
 use spin::Mutex;
 
 const IOREDTBL_KNOWN_BITS_MASK: u64 = 0xff000000_0001ffff;
-const IOREDTBL_RW_MASK: u64 = 0xff000000_0001afff;
+pub(crate) const IOREDTBL_RW_MASK: u64 = 0xff000000_0001afff;
 const IOAPIC_VERSION: u8 = 0x11;
 const IOWIN_OFFSET: isize = 0x10;
 
@@ -460,6 +460,36 @@ impl IoRedTblEntry {
         Ok(entry)
     }
 
+    /// The interrupt vector.
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+
+    /// The action the APIC should take on signal.
+    pub fn delivery_mode(&self) -> DeliveryMode {
+        self.delivery_mode
+    }
+
+    /// The interpretation of the destination field.
+    pub fn destination_mode(&self) -> DestinationMode {
+        self.destination_mode
+    }
+
+    /// Type of signal on interrupt pin.
+    pub fn trigger_mode(&self) -> TriggerMode {
+        self.trigger_mode
+    }
+
+    /// Returns true if the interrupt signal is masked.
+    pub fn masked(&self) -> bool {
+        self.interrupt_mask
+    }
+
+    /// The APIC ID or logical set of processors to deliver to.
+    pub fn destination(&self) -> u8 {
+        self.destination
+    }
+
     /// Perform basic validity checks found in the table from section 3.2.4
     /// in the I/O APIC specification.
     fn validate(&self) -> Result<()> {
@@ -27,8 +27,10 @@ pub mod linux;
 pub mod logger;
 pub mod memory;
 mod registers;
+pub mod rtc;
 pub mod time;
 pub mod tsc;
+pub mod util;
 pub mod vcpu;
 pub mod vm;
 pub mod vmcs;
@@ -6,7 +6,7 @@ use bitflags::bitflags;
 use core::borrow::{Borrow, BorrowMut};
 use core::default::Default;
 use core::fmt;
-use core::ops::{Add, Deref, Index, IndexMut};
+use core::ops::{Add, Deref, Index, IndexMut, RangeInclusive};
 use derive_try_from_primitive::TryFromPrimitive;
 use ux;
 use x86::bits64::paging::*;
@@ -192,6 +192,88 @@ impl fmt::Debug for GuestPhysAddr {
     }
 }
 
+/// The layout of guest physical memory: where RAM is, and the holes where
+/// memory mapped devices may be placed
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryLayout {
+    ram: Vec<RangeInclusive<GuestPhysAddr>>,
+    mmio_holes: Vec<RangeInclusive<GuestPhysAddr>>,
+}
+
+impl MemoryLayout {
+    /// The legacy VGA and option ROM area
+    pub const LEGACY_HOLE_START: u64 = 0xa0000;
+    pub const LEGACY_HOLE_END: u64 = 0xfffff;
+
+    /// The hole below 4GB for the APICs and PCI devices
+    pub const MMIO_HOLE_START: u64 = 0xc000_0000;
+    pub const MMIO_HOLE_END: u64 = 0xffff_ffff;
+
+    pub fn new(
+        ram: Vec<RangeInclusive<GuestPhysAddr>>,
+        mmio_holes: Vec<RangeInclusive<GuestPhysAddr>>,
+    ) -> Self {
+        Self { ram, mmio_holes }
+    }
+
+    /// The standard PC layout for a guest with `memory` MB of RAM
+    ///
+    /// RAM is placed around the legacy hole and the MMIO hole below 4GB,
+    /// with any that does not fit below the MMIO hole placed above 4GB.
+    pub fn for_ram_size(memory: u64) -> Self {
+        let size = memory << 20;
+        let mut ram = vec![];
+        let mut add_ram = |start: u64, end: u64| {
+            if start < end {
+                ram.push(
+                    GuestPhysAddr::new(start)..=GuestPhysAddr::new(end - 1),
+                );
+            }
+        };
+        add_ram(0, size.min(Self::LEGACY_HOLE_START));
+        add_ram(Self::LEGACY_HOLE_END + 1, size.min(Self::MMIO_HOLE_START));
+        if size > Self::MMIO_HOLE_START {
+            let high = Self::MMIO_HOLE_END + 1;
+            add_ram(high, high + (size - Self::MMIO_HOLE_START));
+        }
+
+        Self {
+            ram,
+            mmio_holes: vec![
+                GuestPhysAddr::new(Self::LEGACY_HOLE_START)
+                    ..=GuestPhysAddr::new(Self::LEGACY_HOLE_END),
+                GuestPhysAddr::new(Self::MMIO_HOLE_START)
+                    ..=GuestPhysAddr::new(Self::MMIO_HOLE_END),
+            ],
+        }
+    }
+
+    pub fn ram(&self) -> &[RangeInclusive<GuestPhysAddr>] {
+        &self.ram
+    }
+
+    pub fn mmio_holes(&self) -> &[RangeInclusive<GuestPhysAddr>] {
+        &self.mmio_holes
+    }
+
+    /// The first RAM range that overlaps `region`, if any
+    pub fn overlapping_ram(
+        &self,
+        region: &RangeInclusive<GuestPhysAddr>,
+    ) -> Option<&RangeInclusive<GuestPhysAddr>> {
+        self.ram.iter().find(|ram| {
+            ram.start() <= region.end() && region.start() <= ram.end()
+        })
+    }
+
+    /// Whether `region` lies entirely within one of the MMIO holes
+    pub fn in_mmio_hole(&self, region: &RangeInclusive<GuestPhysAddr>) -> bool {
+        self.mmio_holes.iter().any(|hole| {
+            hole.start() <= region.start() && region.end() <= hole.end()
+        })
+    }
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
 pub struct HostPhysAddr(u64);
 
@@ -353,6 +435,19 @@ impl GuestAddressSpace {
         &self,
         addr: GuestPhysAddr,
     ) -> Result<HostPhysFrame> {
+        let ept_pte = self.find_ept_page_table_entry(addr)?;
+        HostPhysFrame::from_start_address(ept_pte.addr())
+    }
+
+    /// The EPT permissions of the frame mapped at `addr`
+    pub fn frame_flags(&self, addr: GuestPhysAddr) -> Result<EptTableFlags> {
+        Ok(self.find_ept_page_table_entry(addr)?.flags())
+    }
+
+    fn find_ept_page_table_entry(
+        &self,
+        addr: GuestPhysAddr,
+    ) -> Result<&EptPageTableEntry> {
         let ept_pml4e = &self.root[addr.p4_index()];
         if ept_pml4e.is_unused() {
             return Err(Error::InvalidValue(
@@ -381,7 +476,7 @@ impl GuestAddressSpace {
                 "No PT entry for GuestPhysAddr".into(),
             ));
         }
-        HostPhysFrame::from_start_address(ept_pte.addr())
+        Ok(ept_pte)
     }
 
     pub fn frame_iter(
@@ -408,10 +503,11 @@ impl GuestAddressSpace {
         let mut out = vec![];
         let iter = self.frame_iter(cr3, addr, access)?;
 
-        // How many frames this region spans
-        let count = (length + HostPhysFrame::SIZE - 1) / HostPhysFrame::SIZE;
-
         let mut start_offset = addr.as_u64() as usize % HostPhysFrame::SIZE;
+
+        // How many frames this region spans
+        let count = (start_offset + length + HostPhysFrame::SIZE - 1)
+            / HostPhysFrame::SIZE;
         for frame in iter.take(count) {
             let frame = frame?;
             let array = unsafe { frame.as_array() };
@@ -440,11 +536,11 @@ impl GuestAddressSpace {
     ) -> Result<()> {
         let iter = self.frame_iter(cr3, addr, access)?;
 
-        // How many frames this region spans
-        let count =
-            (bytes.len() + HostPhysFrame::SIZE - 1) / HostPhysFrame::SIZE;
-
         let mut start_offset = addr.as_u64() as usize % HostPhysFrame::SIZE;
+
+        // How many frames this region spans
+        let count = (start_offset + bytes.len() + HostPhysFrame::SIZE - 1)
+            / HostPhysFrame::SIZE;
         for frame in iter.take(count) {
             let mut frame = frame?;
             let array = unsafe { frame.as_mut_array() };
@@ -465,6 +561,52 @@ impl GuestAddressSpace {
 
         Ok(())
     }
+
+    /// Set `length` bytes starting at `addr` to `byte`
+    ///
+    /// Every frame in the span is translated before any byte is written,
+    /// so a span that is not entirely mapped fails without modifying
+    /// guest memory.
+    pub fn fill(
+        &mut self,
+        cr3: GuestPhysAddr,
+        addr: GuestVirtAddr,
+        mut length: usize,
+        byte: u8,
+        access: GuestAccess,
+    ) -> Result<()> {
+        if addr.as_u64().checked_add(length as u64).is_none() {
+            return Err(Error::InvalidValue(format!(
+                "Invalid fill of 0x{:x} bytes at 0x{:x}",
+                length,
+                addr.as_u64()
+            )));
+        }
+
+        let mut start_offset = addr.as_u64() as usize % HostPhysFrame::SIZE;
+
+        // How many frames this region spans
+        let count = (start_offset + length + HostPhysFrame::SIZE - 1)
+            / HostPhysFrame::SIZE;
+        let frames = self
+            .frame_iter(cr3, addr, access)?
+            .take(count)
+            .collect::<Result<Vec<_>>>()?;
+        for mut frame in frames {
+            let array = unsafe { frame.as_mut_array() };
+            let end = (start_offset + length).min(HostPhysFrame::SIZE);
+            let span = &mut array[start_offset..end];
+            unsafe {
+                core::ptr::write_bytes(span.as_mut_ptr(), byte, span.len());
+            }
+            length -= span.len();
+
+            // All frames after the first have no start_offset
+            start_offset = 0;
+        }
+
+        Ok(())
+    }
 }
 
 pub type GuestAddressSpaceView<'a> =
@@ -535,6 +677,18 @@ where
             .borrow_mut()
             .write_bytes(self.cr3, addr, bytes, access)
     }
+
+    pub fn fill(
+        &mut self,
+        addr: GuestVirtAddr,
+        length: usize,
+        byte: u8,
+        access: GuestAccess,
+    ) -> Result<()> {
+        self.space
+            .borrow_mut()
+            .fill(self.cr3, addr, length, byte, access)
+    }
 }
 
 impl<T> Deref for GuestAddressSpaceWrapper<T>
@@ -782,3 +936,51 @@ fn map_guest_memory(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn define_test_view(frames: u64) -> GuestAddressSpaceViewMut<'static> {
+        let space: &'static mut GuestAddressSpace =
+            Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
+        for i in 0..frames {
+            space
+                .map_new_frame(GuestPhysAddr::new(i * 4096), false)
+                .unwrap();
+        }
+        GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space)
+    }
+
+    fn addr(addr: u64) -> GuestVirtAddr {
+        GuestVirtAddr::NoPaging(GuestPhysAddr::new(addr))
+    }
+
+    const ACCESS: GuestAccess = GuestAccess::Write(PrivilegeLevel(0));
+
+    #[test]
+    fn test_fill() {
+        let mut view = define_test_view(4);
+        view.fill(addr(0x10), 3 * 4096, 0xaa, ACCESS).unwrap();
+
+        let bytes = view.read_bytes(addr(0), 4 * 4096, ACCESS).unwrap();
+        assert!(bytes[..0x10].iter().all(|b| *b == 0));
+        assert!(bytes[0x10..0x10 + 3 * 4096].iter().all(|b| *b == 0xaa));
+        assert!(bytes[0x10 + 3 * 4096..].iter().all(|b| *b == 0));
+
+        view.fill(addr(0x20), 0, 0x55, ACCESS).unwrap();
+        assert_eq!(view.read_bytes(addr(0x20), 1, ACCESS).unwrap(), [0xaa]);
+    }
+
+    #[test]
+    fn test_fill_out_of_bounds() {
+        let mut view = define_test_view(2);
+
+        // The fill runs past the last mapped frame, so nothing is written
+        assert!(view.fill(addr(0x800), 2 * 4096, 0xaa, ACCESS).is_err());
+        let bytes = view.read_bytes(addr(0), 2 * 4096, ACCESS).unwrap();
+        assert!(bytes.iter().all(|b| *b == 0));
+
+        assert!(view.fill(addr(!0 - 1), 4, 0xaa, ACCESS).is_err());
+    }
+}
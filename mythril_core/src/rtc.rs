@@ -0,0 +1,94 @@
+//! Support for reading the wall clock time from the host's real-time
+//! clock (the MC146818 compatible clock in the CMOS).
+
+use crate::device::rtc::RtcTime;
+use x86::io::{inb, outb};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY_OF_MONTH: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_CENTURY: u8 = 0x32;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+unsafe fn read_register(reg: u8) -> u8 {
+    // Leave NMIs enabled (bit 7 of the address clear)
+    outb(CMOS_ADDRESS, reg & 0x7f);
+    inb(CMOS_DATA)
+}
+
+// The raw time registers, read once the clock is not being updated
+unsafe fn read_raw() -> [u8; 7] {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {}
+    [
+        read_register(REG_SECONDS),
+        read_register(REG_MINUTES),
+        read_register(REG_HOURS),
+        read_register(REG_DAY_OF_MONTH),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+        read_register(REG_CENTURY),
+    ]
+}
+
+/// Read the current time from the host's real-time clock, in seconds
+/// since the unix epoch
+///
+/// The clock is assumed to keep UTC. If the century register is not
+/// implemented (i.e., it does not hold a valid BCD value), the 21st
+/// century is assumed.
+pub unsafe fn read_unix_time() -> u64 {
+    // An update may start between the check and the reads, so read until
+    // two consecutive values agree
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    let status_b = read_register(REG_STATUS_B);
+    let decode = |val: u8| {
+        if status_b & STATUS_B_BINARY != 0 {
+            val
+        } else {
+            (val >> 4) * 10 + (val & 0x0f)
+        }
+    };
+
+    let [second, minute, hour, day_of_month, month, year, century] = raw;
+    let hour = if status_b & STATUS_B_24_HOUR != 0 {
+        decode(hour)
+    } else {
+        let pm = if hour & HOUR_PM != 0 { 12 } else { 0 };
+        decode(hour & !HOUR_PM) % 12 + pm
+    };
+    let century = match decode(century) {
+        century @ 19..=99 => century as u16,
+        _ => 20,
+    };
+
+    let time = RtcTime {
+        second: decode(second),
+        minute: decode(minute),
+        hour,
+        day_of_week: 0,
+        day_of_month: decode(day_of_month),
+        month: decode(month),
+        year: century * 100 + decode(year) as u16,
+    };
+    time.to_unix().max(0) as u64
+}
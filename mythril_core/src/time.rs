@@ -4,21 +4,26 @@
 //!
 //! This module contains types and traits related to time keeping in
 //! Mythril. Note that this does not include _date_ information, only
-//! abstract system clock, counter, and timer information.
+//! abstract system clock, counter, and timer information (and the wall
+//! clock time when the system was started, as a point of reference).
 
 use crate::error::Result;
+use crate::rtc;
 use crate::tsc;
 
+use core::cell::Cell;
 use core::ops::{Add, AddAssign, Sub, SubAssign};
 use core::time::Duration;
 
 static mut TIME_SRC: Option<&'static mut dyn TimeSource> = None;
 static mut START_TIME: Option<Instant> = None;
+static mut START_UNIX_TIME: Option<u64> = None;
 
 /// Determine the best available global system `TimeSource` and calibrate it.
 pub unsafe fn init_global_time() -> Result<()> {
     // Currently we only support using the TSC
     TIME_SRC = Some(tsc::calibrate_tsc()?);
+    START_UNIX_TIME = Some(rtc::read_unix_time());
     START_TIME = Some(now());
     Ok(())
 }
@@ -39,6 +44,13 @@ pub fn system_start_time() -> Instant {
     unsafe { START_TIME.expect("Global time source is not started") }
 }
 
+/// Get the wall clock time (in seconds since the unix epoch, as read from
+/// the host's real-time clock) when the system was started, if the global
+/// system `TimeSource` has been initialized.
+pub fn system_start_unix_time() -> Option<u64> {
+    unsafe { START_UNIX_TIME }
+}
+
 /// Returns whether the global system `TimeSource` has be initialized.
 pub fn is_global_time_ready() -> bool {
     unsafe { TIME_SRC.is_some() }
@@ -110,6 +122,67 @@ pub trait TimeSource {
     fn frequency(&self) -> u64;
 }
 
+/// A source of (guest visible) time for emulated devices.
+///
+/// Unlike a `TimeSource`, a `ClockSource` simply reports the elapsed time
+/// since it started, which allows devices to be driven by a virtual clock
+/// that is independent of the host.
+pub trait ClockSource {
+    /// The number of nanoseconds that have elapsed since the clock started.
+    fn now_ns(&self) -> u64;
+}
+
+/// A device's view of time, along with the `ClockSource` time it was
+/// derived from, for diagnosing divergence between the two.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeDiagnostics {
+    /// The time reported by the device's `ClockSource`, in nanoseconds.
+    pub clock_ns: u64,
+
+    /// The device's current counter or time, in the device's own units
+    /// (e.g., seconds since the unix epoch for the RTC).
+    pub device_time: u64,
+}
+
+/// A `ClockSource` backed by the global system `TimeSource`.
+#[derive(Default, Debug)]
+pub struct SystemClock;
+
+impl ClockSource for SystemClock {
+    fn now_ns(&self) -> u64 {
+        (now() - system_start_time()).as_nanos() as u64
+    }
+}
+
+/// A `ClockSource` that only advances when explicitly told to.
+#[derive(Default, Debug)]
+pub struct FixedClock {
+    ns: Cell<u64>,
+}
+
+impl FixedClock {
+    /// Create a new clock that reports the given time.
+    pub fn new(ns: u64) -> Self {
+        Self { ns: Cell::new(ns) }
+    }
+
+    /// Set the time reported by this clock.
+    pub fn set(&self, ns: u64) {
+        self.ns.set(ns);
+    }
+
+    /// Move the time reported by this clock forward.
+    pub fn advance(&self, ns: u64) {
+        self.ns.set(self.ns.get() + ns);
+    }
+}
+
+impl ClockSource for FixedClock {
+    fn now_ns(&self) -> u64 {
+        self.ns.get()
+    }
+}
+
 enum TimerMode {
     OneShot,
     Periodic,
@@ -0,0 +1,99 @@
+//! Checksum algorithms used by the emulated firmware interfaces
+
+/// Sum up the bytes in the buffer, modulo 256.
+pub fn sum8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |acc, val| acc.wrapping_add(*val))
+}
+
+/// Returns the byte that, when added to `bytes`, makes the sum of the
+/// buffer zero (the checksum used by ACPI, SMBIOS, MP tables, etc).
+///
+/// The buffer should include the checksum byte itself, set to zero.
+pub fn acpi_checksum(bytes: &[u8]) -> u8 {
+    0u8.wrapping_sub(sum8(bytes))
+}
+
+/// Sum up the bytes in the buffer as a 32-bit value, ignoring overflow.
+pub fn additive32(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0u32, |acc, val| acc.wrapping_add(*val as u32))
+}
+
+/// An incremental CRC-32 (the IEEE 802.3 / zlib polynomial)
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    // The reversed representation of the 0x04c11db7 polynomial
+    const POLYNOMIAL: u32 = 0xedb8_8320;
+
+    pub fn new() -> Self {
+        Self { state: 0xffff_ffff }
+    }
+
+    /// Include `bytes` in the checksum
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state ^= *byte as u32;
+            for _ in 0..8 {
+                let mask = 0u32.wrapping_sub(self.state & 1);
+                self.state = (self.state >> 1) ^ (Self::POLYNOMIAL & mask);
+            }
+        }
+    }
+
+    /// The checksum of all bytes added so far
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Calculate the CRC-32 of the given buffer
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_acpi_checksum() {
+        let mut table = [0x12u8, 0x34, 0x00, 0xff, 0x80];
+        table[2] = acpi_checksum(&table);
+        assert_eq!(table[2], 0x3b);
+        assert_eq!(sum8(&table), 0);
+    }
+
+    #[test]
+    fn test_additive32() {
+        assert_eq!(additive32(&[]), 0);
+        assert_eq!(additive32(&[0xff; 4]), 0x3fc);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::device::acpi::SleepState;
 use crate::emulate;
 use crate::error::{self, Error, Result};
 use crate::memory::Raw4kPage;
@@ -8,6 +9,7 @@ use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::mem;
+use core::ops::RangeInclusive;
 use core::pin::Pin;
 use spin::RwLock;
 use x86::controlregs::{cr0, cr3, cr4};
@@ -49,6 +51,12 @@ pub struct VCpu {
 }
 
 impl VCpu {
+    const RFLAGS_IF: u64 = 1 << 9;
+    const INTR_INFO_VALID: u64 = 1 << 31;
+
+    // Blocking by STI or by MOV SS
+    const INTERRUPTIBILITY_BLOCKED: u64 = 0b11;
+
     /// Create a new `VCpu` assocaited with the given `VirtualMachine`
     ///
     /// Note that the result must be `Pin`, as the `VCpu` pushes its own
@@ -85,6 +93,11 @@ impl VCpu {
         Self::initialize_guest_vmcs(&mut vcpu.vmcs)?;
         Self::initialize_ctrl_vmcs(&mut vcpu.vmcs)?;
 
+        // MSRs registered with the `DeviceMap` after this point are not
+        // trapped
+        let msrs = vcpu.vm.write().emulated_msrs();
+        Self::trap_msrs(&mut vcpu.vmcs, &msrs)?;
+
         Ok(vcpu)
     }
 
@@ -237,6 +250,7 @@ impl VCpu {
         vmcs.write_with_fixed(
             vmcs::VmcsField::CpuBasedVmExecControl,
             (vmcs::CpuBasedCtrlFlags::UNCOND_IO_EXITING
+                | vmcs::CpuBasedCtrlFlags::RDTSC_EXITING
                 | vmcs::CpuBasedCtrlFlags::ACTIVATE_MSR_BITMAP
                 | vmcs::CpuBasedCtrlFlags::ACTIVATE_SECONDARY_CONTROLS)
                 .bits(),
@@ -300,6 +314,35 @@ impl VCpu {
         Ok(())
     }
 
+    /// Exit on guest accesses to the given MSRs, rather than letting them
+    /// reach the hardware
+    ///
+    /// Accesses to MSRs outside of the ranges covered by the MSR bitmap
+    /// always exit.
+    fn trap_msrs(
+        vmcs: &mut vmcs::ActiveVmcs,
+        msrs: &[RangeInclusive<u32>],
+    ) -> Result<()> {
+        let addr = vmcs.read_field(vmcs::VmcsField::MsrBitmap)?;
+        let bitmap = unsafe { &mut *(addr as *mut [u8; 4096]) };
+
+        // The low and high MSR read bitmaps are followed by the low and
+        // high MSR write bitmaps
+        let covered = [(0x0000_0000u32, 0usize), (0xc000_0000, 1024)];
+        for range in msrs.iter() {
+            for (first, offset) in covered.iter() {
+                let start = (*range.start()).max(*first);
+                let end = (*range.end()).min(*first + 0x1fff);
+                for msr in start..=end {
+                    let bit = (msr - *first) as usize;
+                    bitmap[offset + bit / 8] |= 1 << (bit % 8);
+                    bitmap[offset + 2048 + bit / 8] |= 1 << (bit % 8);
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn skip_emulated_instruction(&mut self) -> Result<()> {
         let mut rip = self.vmcs.read_field(vmcs::VmcsField::GuestRip)?;
         rip += self
@@ -311,6 +354,120 @@ impl VCpu {
         Ok(())
     }
 
+    fn set_interrupt_window_exiting(&mut self, enabled: bool) -> Result<()> {
+        let flag = vmcs::CpuBasedCtrlFlags::VIRTUAL_INTR_PENDING.bits();
+        let mut ctrl = self
+            .vmcs
+            .read_field(vmcs::VmcsField::CpuBasedVmExecControl)?;
+        if enabled {
+            ctrl |= flag;
+        } else {
+            ctrl &= !flag;
+        }
+        self.vmcs
+            .write_field(vmcs::VmcsField::CpuBasedVmExecControl, ctrl)
+    }
+
+    // Return the guest to the reset vector, with its registers in their
+    // power-on state
+    fn reset(&mut self, guest_cpu: &mut vmexit::GuestCpuState) -> Result<()> {
+        Self::initialize_guest_vmcs(&mut self.vmcs)?;
+        self.vmcs
+            .write_field(vmcs::VmcsField::VmEntryIntrInfoField, 0)?;
+        self.set_interrupt_window_exiting(false)?;
+
+        guest_cpu.cr2 = 0;
+        guest_cpu.r15 = 0;
+        guest_cpu.r14 = 0;
+        guest_cpu.r13 = 0;
+        guest_cpu.r12 = 0;
+        guest_cpu.r11 = 0;
+        guest_cpu.r10 = 0;
+        guest_cpu.r9 = 0;
+        guest_cpu.r8 = 0;
+        guest_cpu.rbp = 0;
+        guest_cpu.rdi = 0;
+        guest_cpu.rsi = 0;
+        guest_cpu.rdx = 0;
+        guest_cpu.rcx = 0;
+        guest_cpu.rbx = 0;
+        guest_cpu.rax = 0;
+        Ok(())
+    }
+
+    // Stop running the guest for good (after it enters S4 or S5)
+    fn power_off() -> ! {
+        info!("The guest powered off");
+        loop {
+            unsafe {
+                llvm_asm!("cli; hlt" :::: "volatile");
+            }
+        }
+    }
+
+    /// Poll the VM's devices and inject the next pending interrupt, if the
+    /// guest can currently take one
+    ///
+    /// This runs after every VMEXIT, so devices only see time pass (and
+    /// interrupts are only injected) when the guest exits. If the guest
+    /// has interrupts blocked, an interrupt-window exit is requested so
+    /// pending interrupts are checked again as soon as it unblocks them.
+    /// A reset requested by the guest is also performed here.
+    fn service_devices(
+        &mut self,
+        guest_cpu: &mut vmexit::GuestCpuState,
+    ) -> Result<()> {
+        let vm = self.vm.clone();
+        let mut vm = vm.write();
+        if let Some(source) = vm.take_reset_request() {
+            info!("Resetting the guest ({:?})", source);
+            vm.reset_devices()?;
+            self.reset(guest_cpu)?;
+        }
+        match vm.take_sleep_request() {
+            Some(SleepState::S4) | Some(SleepState::S5) => {
+                drop(vm);
+                Self::power_off()
+            }
+            // There are no wake events, so the guest wakes immediately
+            Some(state) => {
+                info!("Waking the guest from {:?}", state);
+                vm.resume();
+            }
+            None => (),
+        }
+        vm.poll_devices();
+
+        // An event is already waiting to be injected on the next entry
+        let entry_info = self
+            .vmcs
+            .read_field(vmcs::VmcsField::VmEntryIntrInfoField)?;
+        if entry_info & Self::INTR_INFO_VALID != 0 {
+            return Ok(());
+        }
+
+        let rflags = self.vmcs.read_field(vmcs::VmcsField::GuestRflags)?;
+        let interruptibility = self
+            .vmcs
+            .read_field(vmcs::VmcsField::GuestInterruptibilityInfo)?;
+        if rflags & Self::RFLAGS_IF == 0
+            || interruptibility & Self::INTERRUPTIBILITY_BLOCKED != 0
+        {
+            return self.set_interrupt_window_exiting(true);
+        }
+        self.set_interrupt_window_exiting(false)?;
+
+        // The interruption type of an external interrupt is 0, so only the
+        // valid bit and vector are needed
+        if let Some(vector) = vm.next_interrupt(0) {
+            self.vmcs.write_field(
+                vmcs::VmcsField::VmEntryIntrInfoField,
+                Self::INTR_INFO_VALID | vector as u64,
+            )?;
+        }
+        Ok(())
+    }
+
     /// Handle an arbitrary guest VMEXIT.
     ///
     /// This is the rust 'entry' point when a guest exists.
@@ -389,13 +546,39 @@ impl VCpu {
                 emulate::memio::handle_ept_violation(self, guest_cpu, info)?;
                 self.skip_emulated_instruction()?;
             }
+            vmexit::ExitInformation::InterruptWindow => {
+                // Pending interrupts are injected by `service_devices`
+            }
+            vmexit::ExitInformation::RdMsr => {
+                let msr = guest_cpu.rcx as u32;
+                let val = self.vm.write().on_msr_read(msr)?;
+                guest_cpu.rax = val & 0xffffffff;
+                guest_cpu.rdx = val >> 32;
+                self.skip_emulated_instruction()?;
+            }
+            // The guest TSC is provided by the local APIC, so that it
+            // matches the TSC-deadline timer
+            vmexit::ExitInformation::Rdtsc => {
+                let tsc = self
+                    .vm
+                    .write()
+                    .on_msr_read(msr::IA32_TIME_STAMP_COUNTER)?;
+                guest_cpu.rax = tsc & 0xffffffff;
+                guest_cpu.rdx = tsc >> 32;
+                self.skip_emulated_instruction()?;
+            }
             vmexit::ExitInformation::WrMsr => {
-                info!(
-                    "wrmsr: {:x}:{:x} to register 0x{:x}",
-                    guest_cpu.rdx as u32,
-                    guest_cpu.rax as u32,
-                    guest_cpu.rcx as u32
-                );
+                let msr = guest_cpu.rcx as u32;
+                let val = (guest_cpu.rdx << 32) | (guest_cpu.rax & 0xffffffff);
+                match self.vm.write().on_msr_write(msr, val) {
+                    Ok(()) => (),
+                    Err(Error::MissingDevice(_)) => info!(
+                        "wrmsr: {:x}:{:x} to register 0x{:x}",
+                        guest_cpu.rdx as u32, guest_cpu.rax as u32, msr
+                    ),
+                    Err(e) => return Err(e),
+                }
+                self.skip_emulated_instruction()?;
             }
             _ => {
                 info!("{}", self.vmcs);
@@ -403,6 +586,6 @@ impl VCpu {
             }
         }
 
-        Ok(())
+        self.service_devices(guest_cpu)
     }
 }
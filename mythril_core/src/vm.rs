@@ -1,19 +1,27 @@
 use crate::acpi;
+use crate::device::acpi::{AcpiRuntime, SleepState};
+use crate::device::interrupt::PendingInterrupts;
+use crate::device::reset::{ResetSignal, ResetSource};
 use crate::device::{
-    DeviceMap, MemReadRequest, MemWriteRequest, Port, PortReadRequest,
-    PortWriteRequest,
+    DeviceMap, DeviceRegion, MemReadRequest, MemWriteRequest, Port,
+    PortReadRequest, PortWriteRequest,
 };
 use crate::error::{Error, Result};
+use crate::ioapic::DeliveryMode;
 use crate::memory::{
     self, GuestAddressSpace, GuestPhysAddr, HostPhysAddr, HostPhysFrame,
     Raw4kPage,
 };
+use crate::time::{ClockSource, SystemClock};
 use crate::vcpu;
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
+use alloc::rc::Rc;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::ops::RangeInclusive;
 use spin::RwLock;
 
 pub static mut VM_MAP: Option<BTreeMap<usize, Arc<RwLock<VirtualMachine>>>> =
@@ -35,6 +43,9 @@ pub struct VirtualMachineConfig {
     images: Vec<(String, GuestPhysAddr)>,
     bios: Option<String>,
     devices: DeviceMap,
+    interrupts: Option<Rc<PendingInterrupts>>,
+    reset: Option<Rc<ResetSignal>>,
+    acpi: Option<Rc<RefCell<AcpiRuntime>>>,
     memory: u64, // in MB
 }
 
@@ -50,6 +61,9 @@ impl VirtualMachineConfig {
             _cpus: cpus,
             images: vec![],
             devices: DeviceMap::default(),
+            interrupts: None,
+            reset: None,
+            acpi: None,
             bios: None,
             memory: memory,
         }
@@ -84,6 +98,33 @@ impl VirtualMachineConfig {
     pub fn device_map(&mut self) -> &mut DeviceMap {
         &mut self.devices
     }
+
+    /// Specify the queue the VM's interrupt controllers deliver in to
+    ///
+    /// This should be the same `PendingInterrupts` given to the platform
+    /// with `PlatformBuilder::set_interrupt_sink`, so the interrupts it
+    /// queues are injected in to the guest.
+    pub fn set_pending_interrupts(
+        &mut self,
+        interrupts: Rc<PendingInterrupts>,
+    ) {
+        self.interrupts = Some(interrupts);
+    }
+
+    /// Specify the signal raised when the guest requests a reset
+    ///
+    /// This should be the platform's `PlatformBuilder::reset_signal`.
+    pub fn set_reset_signal(&mut self, reset: Rc<ResetSignal>) {
+        self.reset = Some(reset);
+    }
+
+    /// Specify the ACPI power management device that records the guest's
+    /// sleep (and power off) requests
+    ///
+    /// This should be the platform's `PlatformBuilder::acpi_runtime`.
+    pub fn set_acpi_runtime(&mut self, acpi: Rc<RefCell<AcpiRuntime>>) {
+        self.acpi = Some(acpi);
+    }
 }
 
 /// A virtual machine
@@ -113,27 +154,100 @@ impl VirtualMachine {
         })))
     }
 
+    /// Give every device a chance to act on the passage of time
+    pub fn poll_devices(&mut self) {
+        let now = SystemClock.now_ns();
+        self.config.device_map().poll_all(now);
+    }
+
+    /// Returns the reset requested by the guest, if there is one
+    pub fn take_reset_request(&self) -> Option<ResetSource> {
+        self.config
+            .reset
+            .as_ref()
+            .and_then(|reset| reset.take_reset_request())
+    }
+
+    /// Returns the sleep state requested by the guest, if there is one
+    pub fn take_sleep_request(&self) -> Option<SleepState> {
+        self.config
+            .acpi
+            .as_ref()
+            .and_then(|acpi| acpi.borrow_mut().take_sleep_request())
+    }
+
+    /// Indicate to the guest that it has woken from a sleep state
+    pub fn resume(&self) {
+        if let Some(acpi) = &self.config.acpi {
+            acpi.borrow_mut().resume();
+        }
+    }
+
+    /// Return the devices to their power-on state, discarding any
+    /// interrupts that are pending or in service
+    pub fn reset_devices(&mut self) -> Result<()> {
+        if let Some(interrupts) = &self.config.interrupts {
+            interrupts.clear();
+        }
+        self.config.device_map().reset_all()
+    }
+
+    /// Acknowledge the next interrupt to inject in to the vCPU at `index`
+    ///
+    /// Interrupts delivered through the local APIC take precedence over
+    /// those acknowledged from the legacy PIC. Only fixed and lowest
+    /// priority interrupts can be injected, so any others are dropped.
+    pub fn next_interrupt(&mut self, index: usize) -> Option<u8> {
+        if let Some(interrupts) = &self.config.interrupts {
+            while let Some((mode, vector)) = interrupts.pop(index) {
+                match mode {
+                    DeliveryMode::Fixed | DeliveryMode::LowestPriority => {
+                        return Some(vector)
+                    }
+                    mode => warn!(
+                        "Dropping unsupported {:?} interrupt 0x{:x}",
+                        mode, vector
+                    ),
+                }
+            }
+        }
+        self.config
+            .device_map()
+            .next_pending_interrupt()
+            .map(|interrupt| interrupt.vector)
+    }
+
+    /// The MSR ranges emulated by the VM's devices
+    pub fn emulated_msrs(&mut self) -> Vec<RangeInclusive<u32>> {
+        self.config
+            .device_map()
+            .iter_regions()
+            .filter_map(|(region, _, _)| match region {
+                DeviceRegion::Msr(range) => Some(range),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn on_msr_read(&mut self, msr: u32) -> Result<u64> {
+        self.config.device_map().on_msr_read(msr)
+    }
+
+    pub fn on_msr_write(&mut self, msr: u32, val: u64) -> Result<()> {
+        self.config.device_map().on_msr_write(msr, val)
+    }
+
     pub fn on_mem_read(
         &mut self,
         vcpu: &vcpu::VCpu,
         addr: GuestPhysAddr,
         val: MemReadRequest,
     ) -> Result<()> {
-        let dev =
-            self.config
-                .device_map()
-                .device_for_mut(addr)
-                .ok_or_else(|| {
-                    Error::MissingDevice(format!(
-                        "No device for address {:?}",
-                        addr
-                    ))
-                })?;
         let view = memory::GuestAddressSpaceViewMut::from_vmcs(
             &vcpu.vmcs,
             &mut self.guest_space,
         )?;
-        dev.on_mem_read(addr, val, view)
+        self.config.device_map().on_mem_read(addr, val, view)
     }
 
     pub fn on_mem_write(
@@ -142,21 +256,11 @@ impl VirtualMachine {
         addr: GuestPhysAddr,
         val: MemWriteRequest,
     ) -> Result<()> {
-        let dev =
-            self.config
-                .device_map()
-                .device_for_mut(addr)
-                .ok_or_else(|| {
-                    Error::MissingDevice(format!(
-                        "No device for address {:?}",
-                        addr
-                    ))
-                })?;
         let view = memory::GuestAddressSpaceViewMut::from_vmcs(
             &vcpu.vmcs,
             &mut self.guest_space,
         )?;
-        dev.on_mem_write(addr, val, view)
+        self.config.device_map().on_mem_write(addr, val, view)
     }
 
     pub fn on_port_read(
@@ -165,18 +269,11 @@ impl VirtualMachine {
         port: Port,
         val: PortReadRequest,
     ) -> Result<()> {
-        let dev =
-            self.config
-                .device_map()
-                .device_for_mut(port)
-                .ok_or_else(|| {
-                    Error::MissingDevice(format!("No device for port {}", port))
-                })?;
         let view = memory::GuestAddressSpaceViewMut::from_vmcs(
             &vcpu.vmcs,
             &mut self.guest_space,
         )?;
-        dev.on_port_read(port, val, view)
+        self.config.device_map().on_port_read(port, val, view)
     }
 
     pub fn on_port_write(
@@ -185,18 +282,11 @@ impl VirtualMachine {
         port: Port,
         val: PortWriteRequest,
     ) -> Result<()> {
-        let dev =
-            self.config
-                .device_map()
-                .device_for_mut(port)
-                .ok_or_else(|| {
-                    Error::MissingDevice(format!("No device for port {}", port))
-                })?;
         let view = memory::GuestAddressSpaceViewMut::from_vmcs(
             &vcpu.vmcs,
             &mut self.guest_space,
         )?;
-        dev.on_port_write(port, val, view)
+        self.config.device_map().on_port_write(port, val, view)
     }
 
     fn map_data(
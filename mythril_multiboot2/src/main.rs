@@ -14,6 +14,7 @@ mod allocator;
 mod services;
 
 use alloc::collections::BTreeMap;
+use alloc::rc::Rc;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::convert::TryFrom;
@@ -38,57 +39,14 @@ fn default_vm(
     // FIXME: When `map_bios` may return an error, log the error.
     config.map_bios("seabios.bin".into()).unwrap_or(());
 
-    let device_map = config.device_map();
-    device_map
-        .register_device(device::acpi::AcpiRuntime::new(0xb000).unwrap())
-        .unwrap();
-    device_map
-        .register_device(device::com::ComDevice::new(core as u64, 0x3F8))
-        .unwrap();
-    device_map
-        .register_device(device::com::ComDevice::new(core as u64, 0x2F8))
-        .unwrap();
-    device_map
-        .register_device(device::com::ComDevice::new(core as u64, 0x3E8))
-        .unwrap();
-    device_map
-        .register_device(device::com::ComDevice::new(core as u64, 0x2E8))
-        .unwrap();
-    device_map
-        .register_device(device::debug::DebugPort::new(core as u64, 0x402))
-        .unwrap();
-    device_map
-        .register_device(device::vga::VgaController::new())
-        .unwrap();
-    device_map
-        .register_device(device::dma::Dma8237::new())
-        .unwrap();
-    device_map
-        .register_device(device::ignore::IgnoredDevice::new())
-        .unwrap();
-    device_map
-        .register_device(device::pci::PciRootComplex::new())
-        .unwrap();
-    device_map
-        .register_device(device::pic::Pic8259::new())
-        .unwrap();
-    device_map
-        .register_device(device::keyboard::Keyboard8042::new())
-        .unwrap();
-    device_map
-        .register_device(device::pit::Pit8254::new())
-        .unwrap();
-    device_map
-        .register_device(device::pos::ProgrammableOptionSelect::new())
-        .unwrap();
-    device_map
-        .register_device(device::rtc::CmosRtc::new(mem))
-        .unwrap();
+    let mut platform = device::platform::PlatformBuilder::new(core as u64, mem);
+    platform.enable_legacy_devices();
 
-    //TODO: this should actually be per-vcpu
-    device_map
-        .register_device(device::lapic::LocalApic::new())
-        .unwrap();
+    let interrupts = Rc::new(device::interrupt::PendingInterrupts::new(&[
+        device::interrupt::VcpuApic::new(0),
+    ]));
+    platform.set_interrupt_sink(interrupts.clone());
+    config.set_pending_interrupts(interrupts);
 
     let mut fw_cfg_builder = device::qemu_fw_cfg::QemuFwCfgBuilder::new();
 
@@ -120,7 +78,10 @@ fn default_vm(
         services,
     )
     .unwrap();
-    device_map.register_device(fw_cfg_builder.build()).unwrap();
+    platform.add_device(fw_cfg_builder.build());
+    config.set_reset_signal(platform.reset_signal());
+    config.set_acpi_runtime(platform.acpi_runtime().unwrap());
+    *config.device_map() = platform.build().unwrap();
 
     vm::VirtualMachine::new(config, services).expect("Failed to create vm")
 }